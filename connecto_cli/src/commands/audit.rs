@@ -0,0 +1,81 @@
+//! Audit command - Show and verify the log of security events
+
+use crate::AuditAction;
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use connecto_core::audit::{AuditLog, AuditRecord};
+use connecto_core::clock::{format_utc, UTC_DISPLAY_FORMAT};
+
+use super::table::Table;
+use super::{error, success};
+
+pub fn run(action: Option<AuditAction>, plain: bool) -> Result<()> {
+    let log = AuditLog::new()?;
+
+    match action {
+        None => show(&log, None, plain),
+        Some(AuditAction::Show { last }) => show(&log, last, plain),
+        Some(AuditAction::Verify) => verify(&log),
+    }
+}
+
+fn show(log: &AuditLog, last: Option<usize>, plain: bool) -> Result<()> {
+    let mut records = log.all()?;
+    if let Some(last) = last {
+        records.drain(..records.len().saturating_sub(last));
+    }
+
+    let table = events_table(&records);
+    if plain {
+        table.print(true);
+        return Ok(());
+    }
+
+    if table.is_empty() {
+        println!("{}", "No events recorded.".dimmed());
+        return Ok(());
+    }
+
+    println!("{}", "Audit log:".bold());
+    println!();
+    table.print(false);
+    println!();
+    println!("  Log: {}", log.path().display().to_string().dimmed());
+    println!();
+
+    Ok(())
+}
+
+fn events_table(records: &[AuditRecord]) -> Table {
+    let mut table = Table::new(["#", "TIME", "EVENT", "DETAILS"])
+        .style(0, |s| s.yellow().bold())
+        .style(1, |s| s.dimmed())
+        .style(2, |s| s.cyan());
+    for record in records {
+        table.push_row(vec![
+            record.seq.to_string(),
+            format_utc(record.timestamp, UTC_DISPLAY_FORMAT),
+            record.event.name().to_string(),
+            record.event.to_string(),
+        ]);
+    }
+    table
+}
+
+fn verify(log: &AuditLog) -> Result<()> {
+    match log.verify() {
+        Ok(count) => {
+            success(&format!(
+                "Audit log intact: {} entr{} in {}",
+                count,
+                if count == 1 { "y" } else { "ies" },
+                log.path().display()
+            ));
+            Ok(())
+        }
+        Err(e) => {
+            error(&format!("{}", e));
+            Err(anyhow!("Audit log failed verification"))
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Doctor command - Check what discovery, pairing and SSH need on this machine
+
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use connecto_core::diagnostics::{self, Check, Doctor, Report, Status};
+
+use super::{error, success, warn};
+use crate::output::{banner, mark, Progress};
+
+/// Formats the report can be printed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DoctorFormat {
+    /// One line per check, with fixes under the ones that need them
+    Text,
+    /// The full report as JSON
+    Json,
+}
+
+pub async fn run(port: u16, format: DoctorFormat) -> Result<()> {
+    let doctor = Doctor::new().with_port(port);
+    let report = if format == DoctorFormat::Json {
+        let report = doctor.run().await;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        report
+    } else {
+        let spinner = Progress::spinner("cyan");
+        spinner.set_message("Checking this machine...");
+        spinner.enable_steady_tick();
+        let report = doctor.run().await;
+        spinner.finish_and_clear();
+        print_report(&report);
+        report
+    };
+
+    // Warnings are things that may get in the way; only failures fail, so
+    // scripts can gate on the exit code
+    let failed = report.count(Status::Fail);
+    if failed > 0 {
+        return Err(anyhow!("{} check(s) failed", failed));
+    }
+    Ok(())
+}
+
+/// What a check is about, for people
+fn label(id: &str) -> &str {
+    match id {
+        diagnostics::MDNS_DAEMON => "mDNS daemon",
+        diagnostics::MULTICAST => "Multicast",
+        diagnostics::LISTEN_PORT => "Pairing port",
+        diagnostics::FIREWALL => "Firewall",
+        diagnostics::SSH_SERVER => "SSH server",
+        diagnostics::SSH_PERMISSIONS => "SSH permissions",
+        diagnostics::AUTHORIZED_KEYS => "authorized_keys",
+        diagnostics::CLOCK => "Clock",
+        other => other,
+    }
+}
+
+fn print_report(report: &Report) {
+    println!();
+    banner("CONNECTO DOCTOR", |s| s.on_bright_cyan().black().bold());
+    println!();
+
+    for check in &report.checks {
+        print_check(check);
+    }
+    println!();
+
+    let summary = format!(
+        "{} passed, {} warning(s), {} failed",
+        report.count(Status::Pass),
+        report.count(Status::Warn),
+        report.count(Status::Fail)
+    );
+    match report.worst() {
+        Some(Status::Fail) => error(&summary),
+        Some(Status::Warn) => warn(&summary),
+        _ => success(&summary),
+    }
+    println!();
+}
+
+fn print_check(check: &Check) {
+    let status = match check.status {
+        Status::Pass => mark("✓").green().bold(),
+        Status::Warn => mark("!").yellow().bold(),
+        Status::Fail => mark("✗").red().bold(),
+    };
+    let name = match &check.subject {
+        Some(subject) => format!("{} {}", label(&check.id), subject),
+        None => label(&check.id).to_string(),
+    };
+    println!("{} {}: {}", status, name.bold(), check.detail);
+
+    if let Some(fix) = &check.fix {
+        println!("  {} {}", mark("→").cyan(), fix);
+    }
+    for command in &check.commands {
+        println!("      {}", command.cyan());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(label(diagnostics::LISTEN_PORT), "Pairing port");
+        // Checks added later still show
+        assert_eq!(label("new_check"), "new_check");
+    }
+}
@@ -0,0 +1,184 @@
+//! External commands - Run `connecto-<name>` from PATH for unknown subcommands
+//!
+//! Like git, `connecto foo args...` runs an executable called `connecto-foo`
+//! when `foo` is not built in. It gets the remaining arguments and these
+//! environment variables:
+//!
+//! - `CONNECTO_BIN`: path of the `connecto` executable that started it
+//! - `CONNECTO_VERSION`: version of that executable
+//! - `CONNECTO_CONFIG`: path of the CLI config file (it may not exist yet)
+//! - `CONNECTO_VERBOSE`: `1` if `--verbose` was given, otherwise `0`
+//! - `CONNECTO_CAPABILITIES`: JSON description of what this build supports,
+//!   see [`Capabilities`]
+
+use anyhow::{anyhow, Context, Result};
+use connecto_core::{keys::KeyAlgorithm, PROTOCOL_VERSION};
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::Config;
+
+/// Prefix of external command executables
+pub const PREFIX: &str = "connecto-";
+
+/// What the running `connecto` supports, passed to external commands as JSON
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    pub version: String,
+    pub protocol_version: u32,
+    /// Built-in subcommands
+    pub commands: Vec<String>,
+    /// Values accepted by `--type`
+    pub key_types: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn new(commands: Vec<String>) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            commands,
+            key_types: KeyAlgorithm::ALL
+                .iter()
+                .map(|algorithm| algorithm.name().to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Run the external command for `args`, the unknown subcommand and its
+/// arguments; `builtins` are the names of the built-in subcommands
+pub fn run(args: Vec<String>, builtins: Vec<String>, verbose: bool) -> Result<()> {
+    let (name, args) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("No command given"))?;
+    let search_path = std::env::var_os("PATH").unwrap_or_default();
+    let program = find(name, &search_path).ok_or_else(|| {
+        anyhow!(
+            "'{}' is not a connecto command, and no {}{} was found on PATH. See 'connecto --help'",
+            name,
+            PREFIX,
+            name
+        )
+    })?;
+
+    let capabilities = serde_json::to_string(&Capabilities::new(builtins))?;
+    let mut command = Command::new(&program);
+    command
+        .args(args)
+        .env("CONNECTO_VERSION", env!("CARGO_PKG_VERSION"))
+        .env("CONNECTO_VERBOSE", if verbose { "1" } else { "0" })
+        .env("CONNECTO_CAPABILITIES", capabilities);
+    if let Ok(exe) = std::env::current_exe() {
+        command.env("CONNECTO_BIN", exe);
+    }
+    if let Ok(config) = Config::path() {
+        command.env("CONNECTO_CONFIG", config);
+    }
+
+    // Hand the process over, so signals and the exit code are the command's own
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let e = command.exec();
+        Err(e).with_context(|| format!("Failed to run {}", program.display()))
+    }
+    #[cfg(not(unix))]
+    {
+        let status = command
+            .status()
+            .with_context(|| format!("Failed to run {}", program.display()))?;
+        std::process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Find the executable for external command `name` in `search_path`
+pub fn find(name: &str, search_path: &OsStr) -> Option<PathBuf> {
+    // Names that could escape the search path are never commands
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return None;
+    }
+    let file_name = format!("{}{}", PREFIX, name);
+    std::env::split_paths(search_path)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .flat_map(|dir| candidates(&dir, &file_name))
+        .find(|path| is_executable(path))
+}
+
+/// Paths `file_name` may have in `dir`
+#[cfg(not(windows))]
+fn candidates(dir: &Path, file_name: &str) -> Vec<PathBuf> {
+    vec![dir.join(file_name)]
+}
+
+#[cfg(windows)]
+fn candidates(dir: &Path, file_name: &str) -> Vec<PathBuf> {
+    let extensions = std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+    extensions
+        .split(';')
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| dir.join(format!("{}{}", file_name, ext)))
+        .collect()
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use tempfile::TempDir;
+
+    #[cfg(unix)]
+    fn install(dir: &Path, file_name: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.join(file_name);
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_external_command() {
+        let first = TempDir::new().unwrap();
+        let second = TempDir::new().unwrap();
+        let hello = install(second.path(), "connecto-hello");
+        std::fs::write(first.path().join("connecto-hello"), "not executable").unwrap();
+        let search_path =
+            std::env::join_paths([first.path(), second.path()]).expect("valid search path");
+
+        // A file that is not executable is skipped
+        assert_eq!(find("hello", &search_path), Some(hello));
+        assert_eq!(find("missing", &search_path), None);
+        assert_eq!(find("../connecto-hello", &search_path), None);
+        assert_eq!(find("", &search_path), None);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = Capabilities::new(vec!["listen".to_string(), "pair".to_string()]);
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&capabilities).unwrap()).unwrap();
+
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(json["commands"][1], "pair");
+        assert!(json["key_types"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!("ed25519")));
+    }
+}
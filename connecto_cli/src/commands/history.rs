@@ -0,0 +1,223 @@
+//! History command - Audit the log of pairing decisions
+
+use crate::HistoryAction;
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use connecto_core::audit::{Decision, DecisionLog, DecisionRecord};
+use connecto_core::clock::{format_utc, UTC_DISPLAY_FORMAT};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use super::table::Table;
+use super::{error, success};
+use crate::output::mark;
+
+/// Formats decisions can be exported in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Comma-separated values, one decision per row
+    Csv,
+    /// The log entries as a JSON array
+    Json,
+}
+
+/// Columns of the CSV export
+const CSV_HEADER: [&str; 12] = [
+    "seq",
+    "timestamp",
+    "time_utc",
+    "decision",
+    "peer_name",
+    "address",
+    "fingerprint",
+    "approver",
+    "reason",
+    "policy",
+    "prev_hash",
+    "hash",
+];
+
+pub fn run(action: Option<HistoryAction>, plain: bool) -> Result<()> {
+    let log = DecisionLog::new()?;
+
+    match action {
+        None | Some(HistoryAction::List) => list(&log, plain),
+        Some(HistoryAction::Verify) => verify(&log),
+        Some(HistoryAction::Export { format, output }) => export(&log, format, output.as_deref()),
+    }
+}
+
+fn list(log: &DecisionLog, plain: bool) -> Result<()> {
+    let records = log.all()?;
+
+    let mut table = Table::new(["#", "TIME", "DECISION", "PEER", "ADDRESS", "APPROVER"])
+        .style(0, |s| s.yellow().bold())
+        .style(1, |s| s.dimmed())
+        .style(3, |s| s.cyan())
+        .style(4, |s| s.dimmed());
+    for record in &records {
+        table.push_row(vec![
+            record.seq.to_string(),
+            format_utc(record.timestamp, UTC_DISPLAY_FORMAT),
+            record.decision.to_string(),
+            record.peer_name.clone(),
+            record.address.clone(),
+            approver(record).to_string(),
+        ]);
+    }
+
+    if plain {
+        table.print(true);
+        return Ok(());
+    }
+
+    if table.is_empty() {
+        println!("{}", "No pairing decisions recorded.".dimmed());
+        return Ok(());
+    }
+
+    let accepted = records
+        .iter()
+        .filter(|r| r.decision == Decision::Accepted)
+        .count();
+    println!("{}", "Pairing decisions:".bold());
+    println!();
+    table.print(false);
+    println!();
+    println!(
+        "  {} accepted, {} rejected",
+        accepted.to_string().green(),
+        (records.len() - accepted).to_string().red()
+    );
+    println!();
+
+    Ok(())
+}
+
+fn verify(log: &DecisionLog) -> Result<()> {
+    match log.verify() {
+        Ok(count) => {
+            success(&format!(
+                "Decision log intact: {} entr{} in {}",
+                count,
+                if count == 1 { "y" } else { "ies" },
+                log.path().display()
+            ));
+            Ok(())
+        }
+        Err(e) => {
+            error(&format!("{}", e));
+            Err(anyhow!("Decision log failed verification"))
+        }
+    }
+}
+
+fn export(log: &DecisionLog, format: ExportFormat, output: Option<&str>) -> Result<()> {
+    // Export even a broken log, so auditors can see what was changed
+    if let Err(e) = log.verify() {
+        eprintln!("{} {}", mark("!").yellow().bold(), e);
+    }
+
+    let records = log.all()?;
+    let content = match format {
+        ExportFormat::Csv => to_csv(&records),
+        ExportFormat::Json => serde_json::to_string_pretty(&records)? + "\n",
+    };
+
+    match output {
+        Some(path) => {
+            fs::write(path, content)?;
+            success(&format!(
+                "Exported {} decision(s) to {}",
+                records.len(),
+                path.cyan()
+            ));
+        }
+        None => print!("{}", content),
+    }
+
+    Ok(())
+}
+
+fn approver(record: &DecisionRecord) -> &str {
+    record.approver.as_deref().unwrap_or("automatic")
+}
+
+fn to_csv(records: &[DecisionRecord]) -> String {
+    let mut csv = CSV_HEADER.join(",") + "\n";
+    for record in records {
+        let row = [
+            record.seq.to_string(),
+            record.timestamp.to_string(),
+            format_utc(record.timestamp, UTC_DISPLAY_FORMAT),
+            record.decision.to_string(),
+            record.peer_name.clone(),
+            record.address.clone(),
+            record.fingerprint.clone(),
+            approver(record).to_string(),
+            record.reason.clone().unwrap_or_default(),
+            record.policy.clone().unwrap_or_default(),
+            record.prev_hash.clone(),
+            record.hash.clone(),
+        ];
+        let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quote a CSV field if it contains a separator, quote, or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connecto_core::keys::{KeyAlgorithm, SshKeyPair};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_csv_field_quoting() {
+        assert_eq!(csv_field("desk"), "desk");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("{\"port\":9000}"), "\"{\"\"port\"\":9000}\"");
+    }
+
+    #[test]
+    fn test_csv_export() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+        let log = DecisionLog::with_path(temp_dir.path().join("decisions.jsonl"))
+            .with_policy("{\"port\":9000,\"require_verification\":true}");
+        log.append(
+            DecisionRecord::new(Decision::Rejected, "Desk", "10.0.0.1", &key.public_key)
+                .with_approver(Some("alice"))
+                .with_reason("Rejected by user"),
+        )
+        .unwrap();
+        log.append(DecisionRecord::new(
+            Decision::Accepted,
+            "Laptop",
+            "10.0.0.2",
+            &key.public_key,
+        ))
+        .unwrap();
+
+        let csv = to_csv(&log.all().unwrap());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER.join(","));
+        assert!(lines[1].starts_with("1,"));
+        assert!(lines[1].contains(",rejected,Desk,10.0.0.1,"));
+        assert!(lines[1].contains(",alice,Rejected by user,\"{\"\"port\"\":9000,"));
+        assert!(lines[2].contains(",accepted,Laptop,10.0.0.2,"));
+        assert!(lines[2].contains(",automatic,,"));
+    }
+}
@@ -0,0 +1,181 @@
+//! Keep-warm command - Keep connections to paired hosts open during set hours
+
+use crate::KeepWarmAction;
+use anyhow::Result;
+use colored::Colorize;
+use connecto_core::{
+    keepwarm::{
+        local_time, ControlMaster, HostStatus, KeepWarm, Schedule, SshControlMaster, CHECK_INTERVAL,
+    },
+    ssh_config::SshConfig,
+};
+use std::sync::Arc;
+
+use super::table::Table;
+use super::{error, info, success, warn};
+use crate::output::mark;
+
+pub async fn run(action: Option<KeepWarmAction>, plain: bool) -> Result<()> {
+    match action {
+        Some(KeepWarmAction::Add { host, days, hours }) => {
+            add(&host, Schedule::new(&days, &hours)?)
+        }
+        Some(KeepWarmAction::Remove { host }) => remove(&host),
+        None | Some(KeepWarmAction::Status) => status(plain),
+        Some(KeepWarmAction::Run) => keep_warm().await,
+    }
+}
+
+fn add(host: &str, schedule: Schedule) -> Result<()> {
+    let ssh_config = SshConfig::new()?;
+    let Some(entry) = ssh_config.entries()?.into_iter().find(|e| e.host == host) else {
+        return Err(anyhow::anyhow!("Host '{}' not found in SSH config", host));
+    };
+    if entry.keep_warm == Some(schedule) {
+        println!("{} Nothing to change.", mark("→").yellow());
+        return Ok(());
+    }
+
+    ssh_config.set_keep_warm(host, Some(schedule))?;
+    success(&format!("Keeping {} warm {}", host.cyan(), schedule));
+    if cfg!(windows) {
+        warn("The OpenSSH client for Windows cannot share connections, so nothing is kept open.");
+    } else {
+        info(&format!(
+            "Run {} to open connections on schedule",
+            "connecto keep-warm run".cyan()
+        ));
+    }
+    Ok(())
+}
+
+fn remove(host: &str) -> Result<()> {
+    let ssh_config = SshConfig::new()?;
+    if !ssh_config.entries()?.iter().any(|e| e.host == host) {
+        return Err(anyhow::anyhow!("Host '{}' not found in SSH config", host));
+    }
+    if !ssh_config.set_keep_warm(host, None)? {
+        println!("{} {} is not kept warm.", mark("→").yellow(), host.cyan());
+        return Ok(());
+    }
+
+    // An open connection outlives the schedule otherwise
+    let master = SshControlMaster::new();
+    if master.is_running(host) {
+        master.stop(host)?;
+    }
+    success(&format!("No longer keeping {} warm", host.cyan()));
+    Ok(())
+}
+
+fn status(plain: bool) -> Result<()> {
+    let keeper = KeepWarm::new(SshControlMaster::new(), SshConfig::new()?);
+    let table = status_table(&keeper.status(&local_time())?);
+
+    if plain {
+        table.print(true);
+        return Ok(());
+    }
+
+    if table.is_empty() {
+        println!("{}", "No hosts are kept warm.".dimmed());
+        println!(
+            "  {} Keep one warm with: {}",
+            mark("→").cyan(),
+            "connecto keep-warm add <host>".cyan()
+        );
+        return Ok(());
+    }
+
+    println!("{}", "Kept warm:".bold());
+    println!();
+    table.print(false);
+    println!();
+    Ok(())
+}
+
+/// Open and close connections on schedule until Ctrl+C
+async fn keep_warm() -> Result<()> {
+    let keeper = Arc::new(KeepWarm::new(SshControlMaster::new(), SshConfig::new()?));
+    info(&format!(
+        "Keeping hosts warm on schedule, checking every {}s (Ctrl+C to stop)",
+        CHECK_INTERVAL.as_secs()
+    ));
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut last: Vec<HostStatus> = Vec::new();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        let ticker = keeper.clone();
+        let statuses = tokio::task::spawn_blocking(move || ticker.tick(&local_time())).await??;
+        for status in &statuses {
+            // Only changes are printed
+            if last.contains(status) {
+                continue;
+            }
+            match &status.error {
+                Some(reason) => error(&format!("{}: {}", status.host, reason)),
+                None if status.warm => success(&format!("{} is warm", status.host)),
+                None => info(&format!("{} is off hours", status.host)),
+            }
+        }
+        last = statuses;
+    }
+
+    println!();
+    info("Closing connections once their sessions end...");
+    tokio::task::spawn_blocking(move || keeper.stop_all()).await??;
+    Ok(())
+}
+
+fn status_table(statuses: &[HostStatus]) -> Table {
+    let mut table = Table::new(["HOST", "SCHEDULE", "STATE"])
+        .style(0, |s| s.cyan())
+        .style(1, |s| s.dimmed());
+    for status in statuses {
+        table.push_row(vec![
+            status.host.clone(),
+            status.schedule.clone(),
+            state(status),
+        ]);
+    }
+    table
+}
+
+/// Whether a host is connected, e.g. `cold (should be warm)`
+fn state(status: &HostStatus) -> String {
+    match (status.warm, status.in_hours) {
+        (true, true) => "warm".to_string(),
+        (false, false) => "off hours".to_string(),
+        (true, false) => "warm (off hours)".to_string(),
+        (false, true) => "cold (should be warm)".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_table() {
+        let status = |host: &str, in_hours, warm| HostStatus {
+            host: host.to_string(),
+            schedule: "mon-fri 09:00-18:00".to_string(),
+            in_hours,
+            warm,
+            error: None,
+        };
+        let table = status_table(&[
+            status("desk", true, true),
+            status("nas", true, false),
+            status("lab", false, false),
+        ]);
+        assert_eq!(
+            table.render(true),
+            "desk\tmon-fri 09:00-18:00\twarm\nnas\tmon-fri 09:00-18:00\tcold (should be warm)\nlab\tmon-fri 09:00-18:00\toff hours"
+        );
+    }
+}
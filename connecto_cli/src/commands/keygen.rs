@@ -5,26 +5,20 @@ use colored::Colorize;
 use connecto_core::{
     discovery::get_hostname,
     keys::{KeyAlgorithm, KeyManager, SshKeyPair},
+    tr,
 };
 
-use super::{info, success, warn};
+use super::{announce_algorithm, info, success};
+use crate::config::Config;
+use crate::output::{banner, mark, Progress};
 
-pub async fn run(name: String, comment: Option<String>, rsa: bool) -> Result<()> {
+pub async fn run(name: String, comment: Option<String>, algorithm: KeyAlgorithm) -> Result<()> {
     println!();
-    println!(
-        "{}",
-        "  SSH KEY GENERATOR  ".on_bright_green().black().bold()
-    );
+    banner(&tr!("keygen.title"), |s| s.on_bright_green().black().bold());
     println!();
 
-    // Determine algorithm
-    let algorithm = if rsa {
-        warn("Using RSA-4096 (Ed25519 is recommended for better security and performance)");
-        KeyAlgorithm::Rsa4096
-    } else {
-        info("Using Ed25519 (modern, secure, fast)");
-        KeyAlgorithm::Ed25519
-    };
+    announce_algorithm(algorithm);
+    Config::load()?.check_algorithm(algorithm)?;
 
     // Generate comment
     let key_comment = comment.unwrap_or_else(|| {
@@ -33,50 +27,75 @@ pub async fn run(name: String, comment: Option<String>, rsa: bool) -> Result<()>
         format!("{}@{}", user, hostname)
     });
 
-    info(&format!("Comment: {}", key_comment.cyan()));
+    info(&tr!("keygen.comment", comment = key_comment.cyan()));
     println!();
 
-    // Generate key pair
-    info("Generating key pair...");
-    let key_pair = SshKeyPair::generate(algorithm, &key_comment)?;
+    // Generate key pair; ssh-keygen talks to the user while enrolling a
+    // security key, so only the others get a spinner
+    let key_pair = if algorithm.is_security_key() {
+        info(&tr!("keygen.generating"));
+        SshKeyPair::generate_async(algorithm, &key_comment).await?
+    } else {
+        let spinner = Progress::spinner("green");
+        spinner.set_message(tr!("keygen.generating"));
+        spinner.enable_steady_tick();
+        let key_pair = SshKeyPair::generate_async(algorithm, &key_comment).await;
+        spinner.finish_and_clear();
+        key_pair?
+    };
 
-    // Save key pair
+    // Save key pair, never overwriting a key that may be in use
     let key_manager = KeyManager::new()?;
-    let (private_path, public_path) = key_manager.save_key_pair(&key_pair, &name)?;
+    let (private_path, public_path) = key_manager.save_new_key_pair(&key_pair, &name)?;
 
     println!();
-    success("Key pair generated successfully!");
+    success(&tr!("keygen.done"));
     println!();
 
-    println!("{}", "Files created:".bold());
-    println!(
-        "  {} Private key: {}",
-        "•".green(),
-        private_path.display().to_string().cyan()
-    );
-    println!(
-        "  {} Public key:  {}",
-        "•".green(),
-        public_path.display().to_string().cyan()
-    );
+    // Labels padded to the same width, whatever the language
+    let private_label = tr!("keygen.private-key");
+    let public_label = tr!("keygen.public-key");
+    let width = private_label
+        .chars()
+        .count()
+        .max(public_label.chars().count());
+    println!("{}", tr!("keygen.files").bold());
+    for (label, path) in [
+        (&private_label, &private_path),
+        (&public_label, &public_path),
+    ] {
+        println!(
+            "  {} {:<width$} {}",
+            mark("•").green(),
+            label,
+            path.display().to_string().cyan(),
+            width = width
+        );
+    }
     println!();
 
     // Show public key
-    println!("{}", "Public key:".bold());
+    println!("{}", public_label.bold());
     println!("{}", key_pair.public_key.dimmed());
     println!();
 
     // Show usage hints
-    println!("{}", "Usage:".bold());
+    println!("{}", tr!("keygen.usage").bold());
     println!(
-        "  {} Copy to remote: {}",
-        "→".cyan(),
-        format!("ssh-copy-id -i {} user@host", public_path.display()).dimmed()
+        "  {} {}",
+        mark("→").cyan(),
+        tr!(
+            "keygen.copy",
+            command = format!("ssh-copy-id -i {} user@host", public_path.display()).dimmed()
+        )
     );
     println!(
-        "  {} Or use Connecto: {}",
-        "→".cyan(),
-        "connecto pair <device>".dimmed()
+        "  {} {}",
+        mark("→").cyan(),
+        tr!(
+            "keygen.use-connecto",
+            command = "connecto pair <device>".dimmed()
+        )
     );
     println!();
 
@@ -1,33 +1,95 @@
-//! Keys command - Manage authorized keys
+//! Keys command - Manage authorized keys and local key pairs
 
 use crate::KeysAction;
 use anyhow::{anyhow, Result};
 use colored::Colorize;
-use connecto_core::keys::KeyManager;
-use dialoguer::{theme::ColorfulTheme, Confirm};
+use connecto_core::forges::{self, Forge};
+use connecto_core::keys::{self, split_authorized_key, KeyAudit, KeyIssue, KeyManager, Severity};
+use dialoguer::{Confirm, MultiSelect};
+use std::io::IsTerminal;
 
+use super::table::Table;
 use super::{error, info, success, warn};
+use crate::output::{banner, mark, theme};
 
-pub async fn run(action: Option<KeysAction>) -> Result<()> {
+pub async fn run(action: Option<KeysAction>, plain: bool) -> Result<()> {
     let key_manager = KeyManager::new()?;
 
     match action {
-        None | Some(KeysAction::List) => list_keys(&key_manager).await,
+        None | Some(KeysAction::List) => list_keys(&key_manager, plain).await,
         Some(KeysAction::Remove { target }) => remove_key(&key_manager, &target).await,
+        Some(KeysAction::Delete { name, shred }) => delete_key(&key_manager, &name, shred).await,
+        Some(KeysAction::Audit { format }) => audit_keys(&key_manager, format, plain).await,
+        Some(KeysAction::ImportGithub { user, all }) => {
+            import_keys(&key_manager, Forge::GitHub, &user, all).await
+        }
+        Some(KeysAction::ImportGitlab { user, host, all }) => {
+            import_keys(&key_manager, Forge::GitLab(host), &user, all).await
+        }
     }
 }
 
-async fn list_keys(key_manager: &KeyManager) -> Result<()> {
+/// Formats a key audit can be printed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AuditFormat {
+    /// A table of findings
+    Table,
+    /// The full report as JSON
+    Json,
+}
+
+async fn list_keys(key_manager: &KeyManager, plain: bool) -> Result<()> {
+    let entries = key_manager.list_authorized_key_entries()?;
+    // Windows administrators have keys in two files; say which holds each
+    let show_source = key_manager.authorized_keys_paths().len() > 1;
+
+    let mut headers = vec!["#", "TYPE", "BITS", "FINGERPRINT", "COMMENT", "OPTIONS"];
+    if show_source {
+        headers.push("FILE");
+    }
+    let mut table = Table::new(headers)
+        .style(0, |s| s.yellow().bold())
+        .style(1, |s| s.cyan())
+        .style(2, |s| s.dimmed())
+        .style(3, |s| s.dimmed())
+        .style(4, |s| s.green())
+        .style(5, |s| s.dimmed())
+        .style(6, |s| s.dimmed());
+
+    for (i, entry) in entries.iter().enumerate() {
+        let key = &entry.key;
+        let info = keys::parse_public_key_info(&format!("{} {}", key.key_type, key.blob)).ok();
+        let bits = info.as_ref().and_then(|info| info.bits);
+
+        table.push_row(vec![
+            (i + 1).to_string(),
+            key.key_type.clone(),
+            bits.map_or("-".to_string(), |bits| bits.to_string()),
+            info.map_or("unreadable".to_string(), |info| info.fingerprint),
+            if key.comment.is_empty() {
+                "no comment".to_string()
+            } else {
+                key.comment.clone()
+            },
+            if key.options.is_empty() {
+                "-".to_string()
+            } else {
+                key.options.clone()
+            },
+            entry.source.display().to_string(),
+        ]);
+    }
+
+    if plain {
+        table.print(true);
+        return Ok(());
+    }
+
     println!();
-    println!(
-        "{}",
-        "  AUTHORIZED KEYS  ".on_bright_yellow().black().bold()
-    );
+    banner("AUTHORIZED KEYS", |s| s.on_bright_yellow().black().bold());
     println!();
 
-    let keys = key_manager.list_authorized_keys()?;
-
-    if keys.is_empty() {
+    if table.is_empty() {
         info("No authorized keys found.");
         println!();
         println!(
@@ -38,40 +100,10 @@ async fn list_keys(key_manager: &KeyManager) -> Result<()> {
         return Ok(());
     }
 
-    println!("{} authorized key(s) found:", keys.len());
+    println!("{} authorized key(s) found:", table.len());
     println!();
 
-    for (i, key) in keys.iter().enumerate() {
-        let parts: Vec<&str> = key.split_whitespace().collect();
-
-        let num = format!("[{}]", i + 1).yellow().bold();
-        let key_type = parts.first().unwrap_or(&"unknown");
-        let comment = if parts.len() > 2 {
-            parts[2..].join(" ")
-        } else {
-            "no comment".to_string()
-        };
-
-        // Truncate key data for display
-        let key_preview = if parts.len() > 1 {
-            let key_data = parts[1];
-            if key_data.len() > 20 {
-                format!("{}...{}", &key_data[..10], &key_data[key_data.len() - 10..])
-            } else {
-                key_data.to_string()
-            }
-        } else {
-            "invalid".to_string()
-        };
-
-        println!(
-            "{} {} {} {}",
-            num,
-            key_type.cyan(),
-            key_preview.dimmed(),
-            comment.green()
-        );
-    }
+    table.print(false);
 
     println!();
     println!(
@@ -87,9 +119,111 @@ async fn list_keys(key_manager: &KeyManager) -> Result<()> {
     Ok(())
 }
 
+async fn audit_keys(key_manager: &KeyManager, format: AuditFormat, plain: bool) -> Result<()> {
+    let audit = key_manager.audit()?;
+
+    if format == AuditFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&audit)?);
+    } else if plain {
+        audit_table(&audit).print(true);
+    } else {
+        print_audit(&audit);
+    }
+
+    // Fail on what OpenSSH would refuse or what is easily broken, so
+    // scripts can gate on the exit code
+    let critical = audit
+        .findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Critical)
+        .count();
+    if critical > 0 {
+        return Err(anyhow!("{} critical key issue(s) found", critical));
+    }
+    Ok(())
+}
+
+fn audit_table(audit: &KeyAudit) -> Table {
+    let mut table = Table::new(["SEVERITY", "FILE", "ISSUE", "FINGERPRINT"])
+        .style(0, |s| s.yellow().bold())
+        .style(1, |s| s.cyan())
+        .style(3, |s| s.dimmed());
+
+    for finding in &audit.findings {
+        let path = finding
+            .path
+            .strip_prefix(&audit.ssh_dir)
+            .ok()
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .unwrap_or(&finding.path);
+        let file = match finding.line {
+            Some(line) => format!("{}:{}", path.display(), line),
+            None => path.display().to_string(),
+        };
+        table.push_row(vec![
+            finding.severity.to_string(),
+            file,
+            finding.issue.to_string(),
+            finding
+                .fingerprint
+                .clone()
+                .unwrap_or_else(|| "-".to_string()),
+        ]);
+    }
+    table
+}
+
+fn print_audit(audit: &KeyAudit) {
+    println!();
+    banner("KEY AUDIT", |s| s.on_bright_yellow().black().bold());
+    println!();
+
+    println!(
+        "Checked {} key(s) in {}",
+        audit.keys_checked,
+        audit.ssh_dir.display().to_string().cyan()
+    );
+    println!();
+
+    if audit.findings.is_empty() {
+        success("No issues found.");
+        println!();
+        return;
+    }
+
+    audit_table(audit).print(false);
+    println!();
+
+    let has = |matches: fn(&KeyIssue) -> bool| audit.findings.iter().any(|f| matches(&f.issue));
+    if has(|issue| matches!(issue, KeyIssue::LoosePermissions { .. })) {
+        warn("OpenSSH ignores key files other users can change, and private keys they can read.");
+        println!(
+            "  {} {}",
+            mark("→").cyan(),
+            "chmod 700 ~/.ssh, 600 for private keys and authorized_keys, 644 for .pub files"
+                .dimmed()
+        );
+    }
+    if has(|issue| matches!(issue, KeyIssue::WeakAlgorithm { .. })) {
+        warn(&format!(
+            "Replace DSA keys and RSA keys shorter than {} bits.",
+            keys::MIN_RSA_BITS
+        ));
+        println!(
+            "  {} {}",
+            mark("→").cyan(),
+            format!("For paired hosts: {}", "connecto rotate <host>".cyan()).dimmed()
+        );
+    }
+    if has(|issue| matches!(issue, KeyIssue::DuplicateEntry { .. })) {
+        info("Keep one line of each duplicated key in authorized_keys; 'connecto keys remove' removes every copy.");
+    }
+    println!();
+}
+
 async fn remove_key(key_manager: &KeyManager, target: &str) -> Result<()> {
     println!();
-    println!("{}", "  REMOVE KEY  ".on_bright_red().white().bold());
+    banner("REMOVE KEY", |s| s.on_bright_red().white().bold());
     println!();
 
     let keys = key_manager.list_authorized_keys()?;
@@ -125,7 +259,7 @@ async fn remove_key(key_manager: &KeyManager, target: &str) -> Result<()> {
                     target
                 ));
                 for (i, key) in matches.iter().enumerate() {
-                    let parts: Vec<&str> = key.split_whitespace().collect();
+                    let parts: Vec<&str> = split_authorized_key(key).1.split_whitespace().collect();
                     let comment = if parts.len() > 2 {
                         parts[2..].join(" ")
                     } else {
@@ -139,7 +273,10 @@ async fn remove_key(key_manager: &KeyManager, target: &str) -> Result<()> {
     };
 
     // Show what we're about to remove
-    let parts: Vec<&str> = key_to_remove.split_whitespace().collect();
+    let parts: Vec<&str> = split_authorized_key(&key_to_remove)
+        .1
+        .split_whitespace()
+        .collect();
     let key_type = parts.first().unwrap_or(&"unknown");
     let comment = if parts.len() > 2 {
         parts[2..].join(" ")
@@ -148,11 +285,16 @@ async fn remove_key(key_manager: &KeyManager, target: &str) -> Result<()> {
     };
 
     println!("About to remove:");
-    println!("  {} {} - {}", "•".red(), key_type.cyan(), comment.green());
+    println!(
+        "  {} {} - {}",
+        mark("•").red(),
+        key_type.cyan(),
+        comment.green()
+    );
     println!();
 
     // Confirm
-    let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+    let confirmed = Confirm::with_theme(theme().as_ref())
         .with_prompt("Are you sure you want to remove this key?")
         .default(false)
         .interact()?;
@@ -174,6 +316,140 @@ async fn remove_key(key_manager: &KeyManager, target: &str) -> Result<()> {
     Ok(())
 }
 
+async fn import_keys(key_manager: &KeyManager, forge: Forge, user: &str, all: bool) -> Result<()> {
+    println!();
+    banner("IMPORT KEYS", |s| s.on_bright_yellow().black().bold());
+    println!();
+
+    info(&format!("Fetching {}", forge.keys_url(user).cyan()));
+    let published = forges::fetch_keys(&forge, user)?;
+    if published.is_empty() {
+        warn(&format!(
+            "{} publishes no SSH keys on {}.",
+            user,
+            forge.label()
+        ));
+        return Ok(());
+    }
+
+    let installed: Vec<String> = key_manager
+        .list_authorized_keys()?
+        .iter()
+        .filter_map(|line| keys::fingerprint(split_authorized_key(line).1).ok())
+        .collect();
+    let new: Vec<_> = published
+        .iter()
+        .filter(|key| !installed.contains(&key.info.fingerprint))
+        .collect();
+
+    println!("{} publishes {} key(s):", user.cyan(), published.len());
+    println!();
+    for (i, key) in published.iter().enumerate() {
+        let bits = key
+            .info
+            .bits
+            .map_or(String::new(), |bits| format!(" {}", bits));
+        let note = if installed.contains(&key.info.fingerprint) {
+            " (already authorized)".dimmed().to_string()
+        } else {
+            String::new()
+        };
+        println!(
+            "  {} {}{} {}{}",
+            format!("[{}]", i + 1).yellow(),
+            key.info.algorithm.cyan(),
+            bits.dimmed(),
+            key.info.fingerprint.dimmed(),
+            note
+        );
+    }
+    println!();
+
+    if new.is_empty() {
+        success("All of these keys are already authorized.");
+        return Ok(());
+    }
+
+    // Compare the fingerprints with the ones the user shows you before choosing
+    let selected: Vec<_> = if all {
+        new
+    } else {
+        if !std::io::stdin().is_terminal() {
+            return Err(anyhow!(
+                "Choosing keys needs an interactive terminal; pass --all to import every key"
+            ));
+        }
+        let items: Vec<String> = new
+            .iter()
+            .map(|key| format!("{} {}", key.info.algorithm, key.info.fingerprint))
+            .collect();
+        let chosen = MultiSelect::with_theme(theme().as_ref())
+            .with_prompt("Keys to authorize (space to select, enter to confirm)")
+            .items(&items)
+            .interact()?;
+        chosen.into_iter().map(|i| new[i]).collect()
+    };
+
+    if selected.is_empty() {
+        info("No keys selected.");
+        return Ok(());
+    }
+
+    for key in &selected {
+        key_manager.add_authorized_key(&key.public_key)?;
+    }
+    success(&format!(
+        "Authorized {} key(s) of {}. They can now SSH to this machine.",
+        selected.len(),
+        user.cyan()
+    ));
+    info(&format!(
+        "The keys are marked '{}:{}'; remove them with {}",
+        forge.label(),
+        user,
+        "connecto keys remove <number>".cyan()
+    ));
+
+    Ok(())
+}
+
+async fn delete_key(key_manager: &KeyManager, name: &str, shred: bool) -> Result<()> {
+    println!();
+    banner("DELETE KEY", |s| s.on_bright_red().white().bold());
+    println!();
+
+    let confirmed = Confirm::with_theme(theme().as_ref())
+        .with_prompt(format!(
+            "Delete key pair '{}'{}?",
+            name,
+            if shred {
+                " (shredding the private key)"
+            } else {
+                ""
+            }
+        ))
+        .default(false)
+        .interact()?;
+
+    if !confirmed {
+        info("Operation cancelled.");
+        return Ok(());
+    }
+
+    if !key_manager.delete_key_pair(name, shred)? {
+        return Err(anyhow!("Key '{}' not found", name));
+    }
+
+    if shred {
+        success(&format!("Key '{}' shredded and deleted.", name));
+        info("Copies may survive on copy-on-write filesystems, SSDs, and backups.");
+    } else {
+        success(&format!("Key '{}' deleted.", name));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,89 @@
+//! Known hosts command - Manage host keys in ~/.ssh/known_hosts
+
+use crate::KnownHostsAction;
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use connecto_core::known_hosts::{host_pattern, KnownHostsStore};
+
+use super::success;
+use super::table::Table;
+use crate::output::mark;
+
+pub fn run(action: Option<KnownHostsAction>, plain: bool) -> Result<()> {
+    let store = KnownHostsStore::new()?;
+
+    match action {
+        None | Some(KnownHostsAction::List) => list(&store, plain),
+        Some(KnownHostsAction::Remove { host, port }) => remove(&store, &host, port),
+    }
+}
+
+fn list(store: &KnownHostsStore, plain: bool) -> Result<()> {
+    let mut table = Table::new(["HOST", "TYPE", "FINGERPRINT"])
+        .style(0, |s| s.cyan())
+        .style(2, |s| s.dimmed());
+    for entry in store.list()? {
+        let mut host = if entry.is_hashed() {
+            "(hashed)".to_string()
+        } else {
+            entry.hosts.clone()
+        };
+        if let Some(marker) = &entry.marker {
+            host = format!("@{} {}", marker, host);
+        }
+        table.push_row(vec![
+            host,
+            entry.key_type.clone(),
+            entry.fingerprint().unwrap_or_else(|_| "-".to_string()),
+        ]);
+    }
+
+    if plain {
+        table.print(true);
+        return Ok(());
+    }
+
+    if table.is_empty() {
+        println!("{}", "No known hosts.".dimmed());
+        println!(
+            "  {} Pairing adds the host keys of the device, or ssh asks on first connect",
+            mark("→").cyan()
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        format!("Known hosts ({}):", store.path().display()).bold()
+    );
+    println!();
+    table.print(false);
+    println!();
+    Ok(())
+}
+
+fn remove(store: &KnownHostsStore, host: &str, port: u16) -> Result<()> {
+    let removed = store.remove(host, port)?;
+    if removed.is_empty() {
+        return Err(anyhow!(
+            "No host keys for {} in {}",
+            host_pattern(host, port),
+            store.path().display()
+        ));
+    }
+
+    success(&format!(
+        "Removed {} host key(s) of {}",
+        removed.len(),
+        host.cyan()
+    ));
+    for entry in &removed {
+        println!(
+            "  {} {} {}",
+            mark("•").green(),
+            entry.key_type,
+            entry.fingerprint().unwrap_or_default().dimmed()
+        );
+    }
+    Ok(())
+}
@@ -1,131 +1,269 @@
 //! Listen command - Start listening for pairing requests
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use colored::Colorize;
 #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
 use connecto_core::fallback::AdHocNetwork;
 use connecto_core::{
-    discovery::{get_hostname, get_local_addresses, ServiceAdvertiser},
-    keys::KeyManager,
-    protocol::{HandshakeServer, ServerEvent},
+    access::AccessList,
+    accounts::Account,
+    audit::DecisionLog,
+    clock::{self, format_utc, UTC_DISPLAY_FORMAT},
+    discovery::{generate_pseudonym, get_device_name, get_local_addresses, ServiceAdvertiser},
+    firewall::{self, Firewall, PortAccess},
+    identity::DeviceIdentity,
+    keys::{KeyManager, KeyOptions},
+    known_hosts::local_ssh_port,
+    limits::HandshakeLimits,
+    net::{self, InterfaceAddress},
+    notifications::Notifier,
+    pairings::PairingStore,
+    portmap, ports,
+    power::{PowerEvent, PowerMonitor},
+    protocol::{ApprovalTimeoutAction, HandshakeServer, ServerEvent},
+    relay::PendingChannel,
+    session_log::SessionLog,
+    stdio,
+    trust::TrustStore,
 };
+use serde::{Deserialize, Serialize};
+use std::io::IsTerminal;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc;
 
-#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
-use super::warn;
-use super::{error, info, success};
-
-/// Ensure macOS firewall allows incoming connections to connecto
-#[cfg(target_os = "macos")]
-fn ensure_macos_firewall() {
-    use std::process::Command;
-
-    // Get the path to the current executable first
-    let exe_path = match std::env::current_exe() {
-        Ok(p) => p,
-        Err(_) => return,
-    };
+use crate::config::Config;
 
-    // Resolve symlinks to get the real path (important for Homebrew)
-    let exe_path = exe_path.canonicalize().unwrap_or(exe_path);
-
-    let exe_str = match exe_path.to_str() {
-        Some(s) => s,
-        None => return,
-    };
+use super::prune::describe;
+use super::table::Table;
+use super::{answer_approvals, error, info, port_error, success, warn, warn_clock_skew};
+use crate::output::{banner, mark, theme};
+use dialoguer::Confirm;
 
-    // Check if firewall is enabled
-    let fw_state = Command::new("/usr/libexec/ApplicationFirewall/socketfilterfw")
-        .arg("--getglobalstate")
-        .output();
+/// Warn when other devices probably cannot reach the listener on `port`
+///
+/// Connects to each of `addresses` from a second socket, then asks the
+/// firewall whether it lets the port through, printing the commands that
+/// would. Run as root or Administrator in a terminal, offers to run them.
+async fn check_reachability(bind: IpAddr, addresses: &[IpAddr], port: u16) {
+    match firewall::unreachable_addresses(bind, addresses, port, firewall::SELF_CHECK_TIMEOUT).await
+    {
+        Ok(unreachable) if !unreachable.is_empty() => {
+            let unreachable: Vec<String> = unreachable.iter().map(ToString::to_string).collect();
+            warn(&format!(
+                "This device cannot connect to itself at {} on port {}; other devices will not reach it there either",
+                unreachable.join(", "),
+                port
+            ));
+        }
+        Ok(_) => {}
+        Err(e) => tracing::debug!("Reachability self-check failed: {}", e),
+    }
 
-    let output = fw_state
-        .as_ref()
-        .map(|o| String::from_utf8_lossy(&o.stdout).to_string())
-        .unwrap_or_default();
+    let program = std::env::current_exe()
+        .map(|exe| exe.canonicalize().unwrap_or(exe))
+        .unwrap_or_else(|_| "connecto".into());
+    let Some(check) = firewall::check(port, &program) else {
+        return;
+    };
+    let commands = check.firewall.allow_commands(port, &program);
+    let elevated = super::ssh::is_elevated();
+    match check.access {
+        PortAccess::Allowed => return,
+        PortAccess::Blocked if check.firewall == Firewall::MacOs => warn(&format!(
+            "{} blocks incoming connections to connecto; other devices cannot pair with this one",
+            check.firewall
+        )),
+        PortAccess::Blocked => warn(&format!(
+            "{} blocks port {}; other devices cannot pair with this one",
+            check.firewall, port
+        )),
+        PortAccess::Unknown => info(&format!(
+            "{} is on; if other devices cannot connect, let port {} through",
+            check.firewall, port
+        )),
+    }
+    let prefix = if cfg!(windows) || elevated {
+        ""
+    } else {
+        "sudo "
+    };
+    let run_as = if cfg!(windows) {
+        " (as Administrator)"
+    } else {
+        ""
+    };
+    println!("  {} Allow it with{}:", mark("→").cyan(), run_as);
+    for command in &commands {
+        println!("      {}", format!("{}{}", prefix, command).dimmed());
+    }
 
-    // Debug: print what we got
-    if std::env::var("CONNECTO_DEBUG").is_ok() {
-        eprintln!("[DEBUG] Firewall state output: {}", output);
-        eprintln!("[DEBUG] Executable path: {}", exe_str);
+    if !elevated || !std::io::stdin().is_terminal() {
+        return;
+    }
+    let confirmed = Confirm::with_theme(theme().as_ref())
+        .with_prompt(format!("Add the rule to {} now?", check.firewall))
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+    if !confirmed {
+        return;
+    }
+    match check.firewall.allow(port, &program) {
+        Ok(()) => success(&format!(
+            "{} now lets port {} through",
+            check.firewall, port
+        )),
+        Err(e) => warn(&format!("Could not add the firewall rule: {}", e)),
     }
+}
 
-    // Firewall is enabled if output contains "enabled" (case insensitive) or "State = 1"
-    let firewall_enabled =
-        output.to_lowercase().contains("enabled") || output.contains("State = 1");
+/// What to do with a pairing request nobody answers in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnTimeout {
+    /// Reject the request
+    #[default]
+    Reject,
+    /// Accept the request if the client proved it holds its key
+    AcceptIfVerified,
+}
 
-    if !firewall_enabled {
-        return; // Firewall is off, nothing to do
+impl From<OnTimeout> for ApprovalTimeoutAction {
+    fn from(on_timeout: OnTimeout) -> Self {
+        match on_timeout {
+            OnTimeout::Reject => Self::Reject,
+            OnTimeout::AcceptIfVerified => Self::AcceptIfVerified,
+        }
     }
+}
 
-    info("macOS firewall is enabled - checking access...");
+/// How `--approve` handles pairing requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Approval {
+    /// Seconds to wait for an answer
+    pub timeout_secs: u64,
+    pub on_timeout: OnTimeout,
+}
 
-    // Try to add and unblock using osascript for GUI sudo prompt
-    // Always try this - the command is idempotent (safe to run multiple times)
-    let script = format!(
-        r#"do shell script "/usr/libexec/ApplicationFirewall/socketfilterfw --add '{}' && /usr/libexec/ApplicationFirewall/socketfilterfw --unblockapp '{}'" with administrator privileges"#,
-        exe_str, exe_str
-    );
+/// What the keys a listener installs may be used for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyRestrictions {
+    /// authorized_keys options given with `--key-option`
+    pub options: KeyOptions,
+    /// Only accept each key from the address it was paired from
+    pub restrict_source: bool,
+    /// Other accounts clients may install their key into, given with `--users`
+    pub users: Vec<String>,
+    /// The account keys are installed for instead of ours, given with `--for-user`
+    pub for_user: Option<String>,
+}
 
-    let result = Command::new("osascript").args(["-e", &script]).output();
+/// Which interfaces a listener on the local network uses, and whether the
+/// router forwards to it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Binding {
+    /// Interface names or addresses to advertise on, given with `--interface`
+    pub interfaces: Vec<String>,
+    /// The only address to listen on, given with `--bind`
+    pub bind: Option<IpAddr>,
+    /// Map the port on the router with NAT-PMP or UPnP, given with `--upnp`
+    pub upnp: bool,
+}
 
-    match result {
-        Ok(output) if output.status.success() => {
-            success("Firewall exception added for connecto");
-        }
-        Ok(output) => {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            if stderr.contains("User canceled") || stderr.contains("(-128)") {
-                warn("Firewall setup canceled - incoming connections may be blocked");
-            } else {
-                warn("Could not add firewall exception automatically");
+impl Binding {
+    /// The interface addresses among `available` to advertise on, or none
+    /// to advertise on all of them
+    ///
+    /// Without `--interface`, a `--bind` address limits advertising to its
+    /// own interface.
+    fn selected(
+        &self,
+        available: &[InterfaceAddress],
+    ) -> connecto_core::Result<Vec<InterfaceAddress>> {
+        match self.bind.filter(|_| self.interfaces.is_empty()) {
+            Some(bind) if !bind.is_unspecified() => {
+                net::select_interfaces(available, &[bind.to_string()])
             }
-            println!(
-                "  {} Run manually: {}",
-                "→".cyan(),
-                format!(
-                    "sudo /usr/libexec/ApplicationFirewall/socketfilterfw --add '{}' --unblockapp '{}'",
-                    exe_str, exe_str
-                )
-                .dimmed()
-            );
-        }
-        Err(_) => {
-            warn("Could not add firewall exception automatically");
-            println!(
-                "  {} Run manually: {}",
-                "→".cyan(),
-                format!(
-                    "sudo /usr/libexec/ApplicationFirewall/socketfilterfw --add '{}' --unblockapp '{}'",
-                    exe_str, exe_str
-                )
-                .dimmed()
-            );
+            _ => net::select_interfaces(available, &self.interfaces),
         }
     }
 }
 
-#[cfg(not(target_os = "macos"))]
-fn ensure_macos_firewall() {
-    // No-op on other platforms
+/// How clients reach the listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reach {
+    /// Over the local network, found through mDNS or by address
+    Network(Binding),
+    /// Over an ad-hoc WiFi network created by this machine
+    AdHoc,
+    /// Through the relay at this address, with a code
+    Relay(String),
 }
 
+/// How often `--prune` looks for expired keys
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3_600);
+
+/// How long the event handler gets to catch up once the server stops
+const EVENT_DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_with_adhoc(
     port: u16,
+    ssh_port: Option<u16>,
     name: Option<String>,
     verify: bool,
+    private: bool,
+    approval: Option<Approval>,
     continuous: bool,
-    force_adhoc: bool,
+    reach: Reach,
+    prune: bool,
+    restrictions: KeyRestrictions,
+    access: AccessList,
+    limits: HandshakeLimits,
+    notify: bool,
 ) -> Result<()> {
-    let device_name = name.unwrap_or_else(get_hostname);
+    if approval.is_some() && !std::io::stdin().is_terminal() {
+        bail!("--approve needs an interactive terminal to answer pairing requests");
+    }
+    let force_adhoc = reach == Reach::AdHoc;
+    let (relay, binding) = match reach {
+        Reach::Relay(relay) => (Some(relay), Binding::default()),
+        Reach::Network(binding) => (None, binding),
+        Reach::AdHoc => (None, Binding::default()),
+    };
+
+    let config = Config::load().unwrap_or_default();
+
+    // A private listener never falls back to the OS device name
+    let device_name = match name.or_else(|| config.device_name.clone()) {
+        Some(name) => name,
+        None if private => generate_pseudonym(),
+        None => get_device_name(),
+    };
     let key_manager = KeyManager::new()?;
 
+    // The machine policy can make verification and key proof mandatory
+    let machine_policy = config.policy;
+    let policy = machine_policy.clone().unwrap_or_default();
+    let verify = verify || policy.require_verification;
+    let ssh_port = ssh_port.unwrap_or_else(local_ssh_port);
+    // Rules from the command line add to the saved ones
+    let mut access_list = config.listen_access.clone();
+    access_list.merge(&access);
+
+    // Find a taken port before creating networks or advertising it
+    if relay.is_none() {
+        ports::check_available(port).map_err(|e| port_error(e, "connecto listen"))?;
+    }
+    let interfaces = binding.selected(&net::interface_addresses())?;
+
+    let account = install_account(&restrictions)?;
+
     // Print header
     println!();
-    println!(
-        "{}",
-        "  CONNECTO LISTENER  ".on_bright_blue().white().bold()
-    );
+    banner("CONNECTO LISTENER", |s| s.on_bright_blue().white().bold());
     println!();
 
     // Track if we should try ad-hoc as fallback
@@ -214,50 +352,272 @@ pub async fn run_with_adhoc(
 
     // Show local addresses
     let addresses = get_local_addresses();
-    if addresses.is_empty() && !force_adhoc {
+    if addresses.is_empty() && !force_adhoc && relay.is_none() {
         error("No network interfaces found");
         return Ok(());
     }
 
     info(&format!("Device name: {}", device_name.cyan()));
-    info(&format!("Port: {}", port.to_string().cyan()));
+    match &relay {
+        Some(relay) => info(&format!("Relay: {}", relay.cyan())),
+        None => info(&format!("Port: {}", port.to_string().cyan())),
+    }
+    info(&format!("SSH port: {}", ssh_port.to_string().cyan()));
+    if private {
+        info(&format!(
+            "Privacy: {}",
+            "hostname revealed only after pairing".magenta()
+        ));
+    }
     if force_adhoc {
         info(&format!("Mode: {}", "Ad-hoc (direct connection)".magenta()));
     }
+    if restrictions.restrict_source || !restrictions.options.is_empty() {
+        let mut options = Vec::new();
+        if restrictions.restrict_source {
+            options.push("from=\"<pairing address>\"".to_string());
+        }
+        if !restrictions.options.is_empty() {
+            options.push(restrictions.options.to_string());
+        }
+        info(&format!("Key options: {}", options.join(",").dimmed()));
+    }
+    if let Some(account) = &account {
+        info(&format!(
+            "Installing keys for: {}",
+            account.ssh_dir().join("authorized_keys").display()
+        ));
+        if account.uid == 0 {
+            warn("Devices that pair will log in as root, with full control of this machine");
+        } else {
+            warn(&format!(
+                "Devices that pair will log in as {}, not as you",
+                account.name
+            ));
+        }
+    }
+    if !restrictions.users.is_empty() {
+        info(&format!(
+            "Other accounts: {}",
+            restrictions.users.join(", ").dimmed()
+        ));
+    }
+    if !access_list.is_empty() {
+        let rules: Vec<String> = access_list
+            .allow
+            .iter()
+            .map(|network| format!("allow {}", network))
+            .chain(
+                access_list
+                    .deny
+                    .iter()
+                    .map(|network| format!("deny {}", network)),
+            )
+            .chain(
+                access_list
+                    .allow_names
+                    .iter()
+                    .map(|pattern| format!("allow name {}", pattern)),
+            )
+            .collect();
+        info(&format!("Access: {}", rules.join(", ").dimmed()));
+    }
+    let trust = match TrustStore::new() {
+        Ok(store) => {
+            let mut rules = if verify {
+                "every device enters a code".to_string()
+            } else {
+                "devices enter a code unless trusted".to_string()
+            };
+            if approval.is_some() {
+                rules.push_str(", unknown devices also need approval");
+            }
+            info(&format!("Trust: {}", rules.dimmed()));
+            Some(store)
+        }
+        Err(e) => {
+            warn(&format!("Trust levels unavailable: {}", e));
+            None
+        }
+    };
     println!();
 
-    if !addresses.is_empty() {
+    if !interfaces.is_empty() {
+        println!("{}", "Advertised on:".bold());
+        for iface in &interfaces {
+            println!("  {} {}", mark("•").green(), iface);
+        }
+        println!();
+    } else if !addresses.is_empty() {
         println!("{}", "Local IP addresses:".bold());
         for addr in &addresses {
             if addr.is_ipv4() {
-                println!("  {} {}", "•".green(), addr);
+                println!("  {} {}", mark("•").green(), addr);
             }
         }
         println!();
     }
 
-    // Ensure firewall allows connecto (macOS)
-    ensure_macos_firewall();
+    // Catch a firewall or binding that keeps clients out before they time out
+    if relay.is_none() {
+        let bind = binding.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let addresses: Vec<IpAddr> = match &interfaces[..] {
+            [] => net::interface_addresses(),
+            selected => selected.to_vec(),
+        }
+        .into_iter()
+        .map(|iface| iface.address)
+        .collect();
+        check_reachability(bind, &addresses, port).await;
+    }
 
-    // Start mDNS advertising
-    let mut advertiser = ServiceAdvertiser::new()?;
-    advertiser.advertise(&device_name, port)?;
-    success("mDNS service registered - device is now discoverable");
+    // Load the identity peers use to recognise this device at any address
+    let identity = match DeviceIdentity::load_or_create() {
+        Ok(identity) => Some(identity),
+        Err(e) => {
+            warn(&format!("Could not load device identity: {}", e));
+            None
+        }
+    };
 
-    // Start handshake server
-    let mut server = HandshakeServer::new(key_manager, &device_name).with_verification(verify);
-    let addr = server.listen(port).await?;
+    // Let devices on other segments in through the router
+    let mapping = if binding.upnp {
+        match portmap::map_port(port, portmap::MAPPING_LIFETIME).await {
+            Ok(mapping) => {
+                success(&format!(
+                    "The router forwards {} to this device ({})",
+                    mapping.external, mapping.protocol
+                ));
+                println!(
+                    "  {} Devices on other networks behind it can run: {}",
+                    mark("→").cyan(),
+                    format!("connecto pair {}", mapping.external).cyan()
+                );
+                Some(mapping.keep_renewed())
+            }
+            Err(e) => {
+                warn(&e.to_string());
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    println!();
-    println!(
-        "{}",
-        format!("Listening for pairing requests on port {}...", addr.port())
-            .green()
-            .bold()
-    );
+    // Start mDNS advertising; through a relay, the code is how the device is found
+    let mut advertiser = ServiceAdvertiser::new()?
+        .with_privacy(private)
+        .with_interfaces(interfaces);
+    if let Some(identity) = &identity {
+        advertiser = advertiser.with_identity(identity.fingerprint());
+    }
+    if let Some(mapping) = &mapping {
+        advertiser = advertiser.with_external_address(mapping.external());
+    }
+    if relay.is_none() {
+        advertiser.advertise(&device_name, port)?;
+        success("mDNS service registered - device is now discoverable");
+    }
+
+    // Start handshake server
+    let mut server = HandshakeServer::new(key_manager, &device_name)
+        .with_verification(verify)
+        .with_privacy(private)
+        .with_key_options(restrictions.options)
+        .with_source_restriction(restrictions.restrict_source)
+        .with_users(restrictions.users)
+        .with_ssh_port(ssh_port)
+        .with_access_list(access_list)
+        .with_limits(limits);
+    if let Some(identity) = &identity {
+        server = server.with_identity(identity);
+    }
+    if let Some(bind) = binding.bind {
+        server = server.with_bind_address(bind);
+    }
+    if let Some(account) = &account {
+        server = server.with_account(account);
+    }
+    if let Some(trust) = trust {
+        server = server.with_trust_levels(trust);
+    }
+    match PairingStore::new() {
+        Ok(store) => server = server.with_pairing_store(store),
+        Err(e) => warn(&format!("Pairings will not be recorded: {}", e)),
+    }
+    match DecisionLog::new() {
+        Ok(mut log) => {
+            if let Some(policy) = &machine_policy {
+                log = log.with_policy(&serde_json::to_string(policy)?);
+            }
+            server = server.with_decision_log(log);
+        }
+        Err(e) => warn(&format!("Pairing decisions will not be recorded: {}", e)),
+    }
+    let approver = if let Some(approval) = approval {
+        let (approval_tx, approval_rx) = mpsc::channel(1);
+        server = server.with_approval(approval_tx).with_approval_timeout(
+            Duration::from_secs(approval.timeout_secs),
+            approval.on_timeout.into(),
+        );
+        Some(tokio::spawn(answer_approvals(
+            approval_rx,
+            "Pairing request",
+            |request| {
+                format!(
+                    "Allow {} to SSH into this machine?",
+                    request.device_name.bold()
+                )
+            },
+        )))
+    } else {
+        None
+    };
+    let pending = match &relay {
+        Some(relay) => {
+            let pending = PendingChannel::open(relay).await?;
+            println!();
+            println!(
+                "{}",
+                format!("Waiting on {} for the other device...", relay)
+                    .green()
+                    .bold()
+            );
+            info(&format!(
+                "Pairing code: {}",
+                pending.code().to_string().yellow().bold()
+            ));
+            println!(
+                "  {} On the other device run: {}",
+                mark("→").cyan(),
+                format!("connecto pair --relay {} --code {}", relay, pending.code()).cyan()
+            );
+            Some(pending)
+        }
+        None => {
+            let addr = server
+                .listen(port)
+                .await
+                .map_err(|e| port_error(e, "connecto listen"))?;
+            println!();
+            let on = match binding.bind {
+                Some(_) => addr.to_string(),
+                None => format!("port {}", addr.port()),
+            };
+            println!(
+                "{}",
+                format!("Listening for pairing requests on {}...", on)
+                    .green()
+                    .bold()
+            );
+            None
+        }
+    };
     println!("{}", "Press Ctrl+C to stop".dimmed());
     println!();
 
+    let pruner = prune.then(|| tokio::spawn(prune_periodically(account.clone())));
+
     // Create event channel
     let (event_tx, mut event_rx) = mpsc::channel(10);
 
@@ -275,10 +635,20 @@ pub async fn run_with_adhoc(
         .collect();
 
     // Handle events in a separate task
-    let event_handler = tokio::spawn(async move {
+    let relayed = pending.is_some();
+    let notifier = if notify {
+        Notifier::new()
+    } else {
+        Notifier::disabled()
+    };
+    let session = Arc::new(Mutex::new(SessionLog::new()));
+    let session_log = Arc::clone(&session);
+    let mut event_handler = tokio::spawn(async move {
         let mut last_client_ip: Option<String> = None;
 
         while let Some(event) = event_rx.recv().await {
+            notifier.notify_server_event(&event);
+            session_log.lock().unwrap().record(&event);
             match event {
                 ServerEvent::Started { address } => {
                     info(&format!("Server started on {}", address));
@@ -298,8 +668,53 @@ pub async fn run_with_adhoc(
                         address
                     ));
                 }
-                ServerEvent::KeyReceived { comment } => {
+                ServerEvent::VerificationCode { device_name, code } => {
+                    info(&format!(
+                        "Verification code for {}: {}",
+                        device_name.cyan(),
+                        code.yellow().bold()
+                    ));
+                    println!(
+                        "  {} {}",
+                        mark("→").cyan(),
+                        "Enter this code on the other device to continue".dimmed()
+                    );
+                }
+                ServerEvent::KeyReceived {
+                    comment,
+                    fingerprint,
+                    ..
+                } => {
                     info(&format!("Received key: {}", comment.dimmed()));
+                    println!("  {} Fingerprint: {}", mark("•").cyan(), fingerprint.cyan());
+                    println!(
+                        "  {} {}",
+                        mark("→").cyan(),
+                        "Compare it with the one shown on the other device".dimmed()
+                    );
+                }
+                ServerEvent::PairingRejected { device_name } => {
+                    warn(&format!("Rejected pairing request from {}", device_name));
+                }
+                ServerEvent::ApprovalTimedOut {
+                    device_name,
+                    accepted,
+                } => {
+                    warn(&format!(
+                        "No answer to the request from {} in time; {}",
+                        device_name,
+                        if accepted {
+                            "accepting its verified key"
+                        } else {
+                            "rejecting it"
+                        }
+                    ));
+                }
+                ServerEvent::ClockSkew {
+                    device_name,
+                    skew_secs,
+                } => {
+                    warn_clock_skew(&device_name, Some(skew_secs));
                 }
                 ServerEvent::PairingComplete { device_name } => {
                     println!();
@@ -307,10 +722,11 @@ pub async fn run_with_adhoc(
                         "Successfully paired with {}!",
                         device_name.green().bold()
                     ));
-                    println!("  {} They can now SSH to this machine.", "→".cyan());
+                    println!("  {} They can now SSH to this machine.", mark("→").cyan());
 
-                    // Check if client is from a different subnet (VPN scenario)
-                    if let Some(ref client_ip) = last_client_ip {
+                    // Check if client is from a different subnet (VPN scenario);
+                    // a relayed client is expected to be
+                    if let Some(client_ip) = last_client_ip.as_ref().filter(|_| !relayed) {
                         let client_subnet: String =
                             client_ip.split('.').take(3).collect::<Vec<_>>().join(".");
 
@@ -322,7 +738,7 @@ pub async fn run_with_adhoc(
                             );
                             println!(
                                 "  {} Tell {} to save your subnet for future scans:",
-                                "→".cyan(),
+                                mark("→").cyan(),
                                 device_name.cyan()
                             );
                             println!(
@@ -334,6 +750,32 @@ pub async fn run_with_adhoc(
                     }
                     println!();
                 }
+                ServerEvent::AccessDenied {
+                    device_name,
+                    address,
+                    reason,
+                } => {
+                    warn(&format!(
+                        "Refused {} ({}): {}",
+                        device_name,
+                        address.ip(),
+                        reason
+                    ));
+                }
+                ServerEvent::KeyRevoked {
+                    device_name,
+                    address,
+                    fingerprint,
+                } => {
+                    info(&format!(
+                        "{} ({}) revoked its key {}",
+                        device_name.cyan(),
+                        address.ip(),
+                        fingerprint.dimmed()
+                    ));
+                }
+                // Only the session summary reports these; most are scanner probes
+                ServerEvent::PairingFailed { .. } => {}
                 ServerEvent::Error { message } => {
                     error(&format!("Error: {}", message));
                 }
@@ -342,15 +784,33 @@ pub async fn run_with_adhoc(
     });
 
     // Run server
-    if continuous {
+    let mut server_error = None;
+    if let Some(pending) = pending {
+        // Pair with the device that joins the room, then exit
+        tokio::select! {
+            channel = pending.connect() => {
+                let channel = channel?;
+                let peer = channel.peer_addr();
+                server.handle_stream(channel.into_stream(), peer, event_tx).await?;
+                // The server dropped its sender; let the handler report the outcome
+                let _ = (&mut event_handler).await;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!();
+                info("Shutting down...");
+            }
+        }
+    } else if continuous {
         // Run continuously until Ctrl+C
         info("Running in continuous mode (Ctrl+C to stop)...");
         tokio::select! {
             result = server.run(event_tx) => {
                 if let Err(e) = result {
                     error(&format!("Server error: {}", e));
+                    server_error = Some(e);
                 }
             }
+            _ = follow_power_events(&mut advertiser) => {}
             _ = tokio::signal::ctrl_c() => {
                 println!();
                 info("Shutting down...");
@@ -358,20 +818,376 @@ pub async fn run_with_adhoc(
         }
     } else {
         // Default: handle one pairing and exit
-        server.handle_one(event_tx).await?;
+        tokio::select! {
+            result = server.handle_one(event_tx) => {
+                if let Err(e) = result {
+                    server_error = Some(e);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!();
+                info("Shutting down...");
+            }
+        }
     }
 
-    // Clean up
+    // Clean up, letting the handler record the last events first
     advertiser.stop()?;
+    if let Some(mapping) = mapping {
+        match mapping.remove().await {
+            Ok(()) => info("Removed the port mapping from the router"),
+            Err(e) => warn(&format!("Could not remove the port mapping: {}", e)),
+        }
+    }
+    if !event_handler.is_finished() {
+        let _ = tokio::time::timeout(EVENT_DRAIN_TIMEOUT, &mut event_handler).await;
+    }
     event_handler.abort();
+    print_session(&session.lock().unwrap());
+    if let Some(approver) = approver {
+        approver.abort();
+    }
+    if let Some(pruner) = pruner {
+        pruner.abort();
+    }
 
+    if let Some(e) = server_error {
+        return Err(e.into());
+    }
     success("Connecto listener stopped");
     Ok(())
 }
 
+/// Answer one pairing request arriving on stdin, replying on stdout
+///
+/// For channels Connecto does not open itself, such as
+/// `connecto listen --accept-stdin` run over `ssh`, or a person pasting the
+/// client's messages. Nothing is advertised and no port is opened, and
+/// everything meant for the user goes to stderr. The channel stands in for
+/// verification codes and approval, so `verify` and `approve` (set in the
+/// config) are refused rather than skipped.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_stdin(
+    ssh_port: Option<u16>,
+    name: Option<String>,
+    verify: bool,
+    private: bool,
+    approve: bool,
+    restrictions: KeyRestrictions,
+    access: AccessList,
+    limits: HandshakeLimits,
+) -> Result<()> {
+    let config = Config::load().unwrap_or_default();
+    let device_name = match name.or_else(|| config.device_name.clone()) {
+        Some(name) => name,
+        None if private => generate_pseudonym(),
+        None => get_device_name(),
+    };
+    let machine_policy = config.policy;
+    let policy = machine_policy.clone().unwrap_or_default();
+    if policy.require_verification {
+        bail!("The machine policy requires verification codes, which cannot be entered over stdin");
+    }
+    if verify {
+        bail!("Verification codes cannot be entered over stdin; turn off listen.verify, or pair over the network");
+    }
+    if approve {
+        bail!("Pairing requests cannot be approved over stdin; turn off listen.approve, or pair over the network");
+    }
+    let peer = stdio::peer_address();
+    if restrictions.restrict_source && !stdio::peer_known(peer) {
+        bail!("--restrict-source needs the client's address, which only sshd gives over stdin");
+    }
+    let account = install_account(&restrictions)?;
+    let mut access_list = config.listen_access.clone();
+    access_list.merge(&access);
+
+    let mut server = HandshakeServer::new(KeyManager::new()?, &device_name)
+        .with_privacy(private)
+        .with_key_options(restrictions.options)
+        .with_source_restriction(restrictions.restrict_source)
+        .with_users(restrictions.users)
+        .with_ssh_port(ssh_port.unwrap_or_else(local_ssh_port))
+        .with_access_list(access_list)
+        .with_limits(limits);
+    match DeviceIdentity::load_or_create() {
+        Ok(identity) => server = server.with_identity(&identity),
+        Err(e) => report(&format!("Could not load device identity: {}", e)),
+    }
+    if let Some(account) = &account {
+        server = server.with_account(account);
+    }
+    match PairingStore::new() {
+        Ok(store) => server = server.with_pairing_store(store),
+        Err(e) => report(&format!("Pairings will not be recorded: {}", e)),
+    }
+    match DecisionLog::new() {
+        Ok(mut log) => {
+            if let Some(policy) = &machine_policy {
+                log = log.with_policy(&serde_json::to_string(policy)?);
+            }
+            server = server.with_decision_log(log);
+        }
+        Err(e) => report(&format!("Pairing decisions will not be recorded: {}", e)),
+    }
+
+    eprintln!(
+        "{} {} is waiting for a pairing request on stdin...",
+        mark("→").cyan().bold(),
+        device_name.cyan()
+    );
+    if std::io::stdin().is_terminal() {
+        eprintln!(
+            "  {} Paste each line `connecto pair --print-authorized-line` prints, and each line printed here into it",
+            mark("→").cyan()
+        );
+    }
+
+    let (event_tx, mut event_rx) = mpsc::channel(10);
+    let reporter = tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            report_stdin_event(event);
+        }
+    });
+    let result = tokio::select! {
+        result = server.handle_stream(stdio::stream(), peer, event_tx) => result,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!();
+            report("Stopped before a device paired");
+            Ok(())
+        }
+    };
+    // The server dropped its sender; let the reporter catch up
+    let _ = tokio::time::timeout(EVENT_DRAIN_TIMEOUT, reporter).await;
+    result.map_err(Into::into)
+}
+
+/// Tell the user on stderr about `event` of a pairing over stdin
+fn report_stdin_event(event: ServerEvent) {
+    match event {
+        ServerEvent::PairingRequest { device_name, .. } => eprintln!(
+            "{} Pairing request from {}",
+            mark("→").cyan().bold(),
+            device_name.cyan().bold()
+        ),
+        ServerEvent::KeyReceived {
+            comment,
+            fingerprint,
+            ..
+        } => {
+            eprintln!(
+                "{} Received key: {}",
+                mark("→").cyan().bold(),
+                comment.dimmed()
+            );
+            eprintln!("  {} Fingerprint: {}", mark("•").cyan(), fingerprint.cyan());
+        }
+        ServerEvent::ClockSkew {
+            device_name,
+            skew_secs,
+        } if clock::is_large(skew_secs) => report(&format!(
+            "The clock of {} is {} compared to this machine's; check that both have the right time",
+            device_name,
+            clock::describe(skew_secs)
+        )),
+        ServerEvent::PairingComplete { device_name } => eprintln!(
+            "{} Successfully paired with {}; it can now SSH to this machine",
+            mark("✓").green().bold(),
+            device_name.green().bold()
+        ),
+        ServerEvent::PairingRejected { device_name } => {
+            report(&format!("Rejected pairing request from {}", device_name))
+        }
+        ServerEvent::AccessDenied {
+            device_name,
+            reason,
+            ..
+        } => report(&format!("Refused {}: {}", device_name, reason)),
+        ServerEvent::Error { message } => error(&format!("Error: {}", message)),
+        _ => {}
+    }
+}
+
+/// Print a warning on stderr, leaving stdout to the handshake
+fn report(msg: &str) {
+    eprintln!("{} {}", mark("!").yellow().bold(), msg);
+}
+
+/// Print the pairing attempts of the session, if there were any
+fn print_session(log: &SessionLog) {
+    if log.is_empty() {
+        return;
+    }
+    let mut table = Table::new([
+        "TIME",
+        "DEVICE",
+        "ADDRESS",
+        "FINGERPRINT",
+        "RESULT",
+        "REASON",
+    ])
+    .style(0, |s| s.dimmed())
+    .style(1, |s| s.cyan())
+    .style(2, |s| s.dimmed())
+    .style(3, |s| s.dimmed());
+    for attempt in log.attempts() {
+        table.push_row(vec![
+            format_utc(attempt.started_at, UTC_DISPLAY_FORMAT),
+            attempt.device_name.clone(),
+            attempt.address.ip().to_string(),
+            attempt.fingerprint.clone().unwrap_or_default(),
+            attempt.outcome.to_string(),
+            attempt.outcome.reason().unwrap_or_default().to_string(),
+        ]);
+    }
+
+    let summary = log.summary();
+    println!();
+    println!("{}", "Pairing requests this session:".bold());
+    table.print(false);
+    println!();
+    println!(
+        "{} accepted, {} rejected, {} failed{}",
+        summary.accepted.to_string().green(),
+        summary.rejected.to_string().yellow(),
+        summary.failed.to_string().red(),
+        if summary.pending > 0 {
+            format!(", {} still pending", summary.pending)
+        } else {
+            String::new()
+        }
+    );
+}
+
+/// Remove expired keys from authorized_keys, of `account` if given, now and
+/// every [`PRUNE_INTERVAL`]
+async fn prune_periodically(account: Option<Account>) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = clock::unix_now().max(0) as u64;
+        let keys = KeyManager::new().map(|keys| match &account {
+            Some(account) => match keys.audit_log() {
+                Some(log) => KeyManager::for_account(account).with_audit_log(log.clone()),
+                None => KeyManager::for_account(account),
+            },
+            None => keys,
+        });
+        match keys.and_then(|m| m.prune_expired_keys(now)) {
+            Ok(expired) => {
+                for line in expired {
+                    info(&format!("Removed expired key: {}", describe(&line)));
+                }
+            }
+            Err(e) => warn(&format!("Could not remove expired keys: {}", e)),
+        }
+    }
+}
+
+/// The account given with `--for-user`, after checking that it and every
+/// account in `--users` exist and that we may install keys for it
+fn install_account(restrictions: &KeyRestrictions) -> Result<Option<Account>> {
+    // Offer only accounts that exist
+    for user in &restrictions.users {
+        if let Err(e) = Account::lookup(user) {
+            bail!("Cannot offer the account {}: {}", user, e);
+        }
+    }
+    let account = match &restrictions.for_user {
+        Some(user) => match Account::lookup(user) {
+            Ok(account) => Some(account),
+            Err(e) => bail!("Cannot install keys for {}: {}", user, e),
+        },
+        None => None,
+    };
+    if let Some(account) = &account {
+        if !can_install_for(account) {
+            bail!(
+                "Installing keys for {} needs root; run `sudo connecto listen --for-user {}`",
+                account.name,
+                account.name
+            );
+        }
+    }
+    Ok(account)
+}
+
+/// Whether keys can be installed for `account`: it is ours, or we are root
+fn can_install_for(account: &Account) -> bool {
+    #[cfg(unix)]
+    {
+        let euid = unsafe { libc::geteuid() };
+        euid == 0 || euid == account.uid
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = account;
+        false
+    }
+}
+
+/// Withdraw the advertisement while the machine sleeps
+///
+/// Runs until the listener stops; the server itself keeps its socket, so
+/// clients that still know the address can pair once the machine is awake.
+async fn follow_power_events(advertiser: &mut ServiceAdvertiser) {
+    let mut power_rx = PowerMonitor::new().watch();
+    while let Some(event) = power_rx.recv().await {
+        match event {
+            PowerEvent::Sleeping => match advertiser.suspend() {
+                Ok(()) => info("Going to sleep: stopped advertising"),
+                Err(e) => warn(&format!("Could not stop advertising: {}", e)),
+            },
+            PowerEvent::Woke => match advertiser.resume() {
+                Ok(()) => success("Awake again: advertising resumed"),
+                Err(e) => warn(&format!("Could not resume advertising: {}", e)),
+            },
+        }
+    }
+    std::future::pending::<()>().await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use connecto_core::discovery::get_hostname;
+
+    #[test]
+    fn test_binding_selects_interfaces() {
+        let iface = |name: &str, address: &str| InterfaceAddress {
+            name: name.to_string(),
+            address: address.parse().unwrap(),
+        };
+        let available = [iface("en0", "192.168.1.20"), iface("utun3", "10.8.0.2")];
+
+        assert!(Binding::default().selected(&available).unwrap().is_empty());
+        let vpn = Binding {
+            interfaces: vec!["utun3".to_string()],
+            bind: None,
+            ..Binding::default()
+        };
+        assert_eq!(vpn.selected(&available).unwrap(), [available[1].clone()]);
+
+        // Binding to one address advertises only that one
+        let bound = Binding {
+            interfaces: vec![],
+            bind: Some("192.168.1.20".parse().unwrap()),
+            ..Binding::default()
+        };
+        assert_eq!(bound.selected(&available).unwrap(), [available[0].clone()]);
+        let everywhere = Binding {
+            interfaces: vec![],
+            bind: Some("0.0.0.0".parse().unwrap()),
+            ..Binding::default()
+        };
+        assert!(everywhere.selected(&available).unwrap().is_empty());
+        let elsewhere = Binding {
+            interfaces: vec![],
+            bind: Some("172.16.0.9".parse().unwrap()),
+            ..Binding::default()
+        };
+        assert!(elsewhere.selected(&available).is_err());
+    }
 
     #[test]
     fn test_module_compiles() {
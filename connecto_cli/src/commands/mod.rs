@@ -1,40 +1,234 @@
 //! CLI command implementations
 
+pub mod audit;
+pub mod doctor;
+pub mod export;
+pub mod external;
+pub mod history;
+pub mod keep_warm;
 pub mod keygen;
 pub mod keys;
+pub mod known_hosts;
 pub mod listen;
 pub mod pair;
+pub mod prune;
+pub mod relay;
+pub mod repair;
+pub mod restore_config;
+pub mod revoke;
+pub mod rotate;
+pub mod run;
 pub mod scan;
+pub mod service;
 pub mod ssh;
 pub mod sync;
+pub mod table;
+pub mod target;
+pub mod test;
+pub mod transfer;
+pub mod trust;
 
+use crate::output::mark;
 use colored::Colorize;
+use connecto_core::{
+    clock, keys::KeyAlgorithm, next_steps::NextStep, ports, protocol::ApprovalRequest, tr,
+    ConnectoError,
+};
+use std::io::{BufRead, Write};
+use tokio::sync::mpsc;
 
 /// Print a success message
 pub fn success(msg: &str) {
-    println!("{} {}", "✓".green().bold(), msg);
+    println!("{} {}", mark("✓").green().bold(), msg);
 }
 
 /// Print an error message
 pub fn error(msg: &str) {
-    eprintln!("{} {}", "✗".red().bold(), msg);
+    eprintln!("{} {}", mark("✗").red().bold(), msg);
 }
 
 /// Print an info message
 pub fn info(msg: &str) {
-    println!("{} {}", "→".cyan().bold(), msg);
+    println!("{} {}", mark("→").cyan().bold(), msg);
 }
 
 /// Print a warning message
 pub fn warn(msg: &str) {
-    println!("{} {}", "!".yellow().bold(), msg);
+    println!("{} {}", mark("!").yellow().bold(), msg);
+}
+
+/// Warn if the clock of `peer_name` is too far from ours
+///
+/// Nothing depends on the clocks agreeing yet, but expiring keys will.
+pub fn warn_clock_skew(peer_name: &str, skew: Option<i64>) {
+    if let Some(skew) = skew.filter(|s| clock::is_large(*s)) {
+        warn(&format!(
+            "The clock of {} is {} compared to this machine's; check that both have the right time",
+            peer_name,
+            clock::describe(skew)
+        ));
+    }
+}
+
+/// Print the suggestions of [`connecto_core::next_steps::recommend`]
+pub fn print_next_steps(steps: &[NextStep]) {
+    if steps.is_empty() {
+        return;
+    }
+    println!("{}", tr!("next-steps").bold());
+    for step in steps {
+        match &step.command {
+            Some(command) => println!(
+                "  {} {}: {}",
+                mark("→").cyan(),
+                step.title,
+                command.cyan().bold()
+            ),
+            None => println!("  {} {}", mark("→").cyan(), step.title),
+        }
+    }
+    println!();
+}
+
+/// Ways past a port that is already in use, for `command` (e.g.
+/// `connecto listen`); empty for any other error
+pub fn port_in_use_hints(e: &ConnectoError, command: &str) -> Vec<String> {
+    let ConnectoError::PortInUse { port, owner } = e else {
+        return Vec::new();
+    };
+    let mut hints = Vec::new();
+    match owner {
+        Some(owner) if owner.is_connecto() => hints.push(format!(
+            "Another Connecto is still running; stop it with: {}",
+            kill_command(owner.pid)
+        )),
+        Some(owner) => hints.push(format!("Stop {} or use another port", owner)),
+        None => {}
+    }
+    if let Some(free) = ports::suggest_free_port(*port) {
+        hints.push(format!("Use a free port: {} --port {}", command, free));
+    }
+    hints
+}
+
+/// Error for a port that could not be bound, with the hints appended
+pub fn port_error(e: ConnectoError, command: &str) -> anyhow::Error {
+    let hints = port_in_use_hints(&e, command);
+    let mut message = e.to_string();
+    for hint in hints {
+        message.push_str(&format!("\n  {} {}", mark("→").cyan(), hint));
+    }
+    anyhow::anyhow!(message)
+}
+
+#[cfg(not(windows))]
+fn kill_command(pid: u32) -> String {
+    format!("kill {}", pid)
+}
+
+#[cfg(windows)]
+fn kill_command(pid: u32) -> String {
+    format!("taskkill /PID {}", pid)
+}
+
+/// Tell the user which kind of key is about to be generated
+pub fn announce_algorithm(algorithm: KeyAlgorithm) {
+    match algorithm {
+        KeyAlgorithm::Ed25519 => info(&tr!("algorithm.ed25519")),
+        KeyAlgorithm::Rsa4096 => warn(&tr!("algorithm.rsa")),
+        KeyAlgorithm::Ed25519Sk => info(&tr!("algorithm.security-key")),
+        other => info(&tr!("algorithm.other", algorithm = other)),
+    }
+}
+
+/// Lines typed on stdin, read on their own thread
+///
+/// A prompt whose request times out can then be abandoned without
+/// swallowing the answer to the next one.
+pub fn stdin_lines() -> mpsc::UnboundedReceiver<String> {
+    let (line_tx, lines) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    lines
+}
+
+/// Prompt the user to accept or reject each approval request, showing it
+/// under `title` and asking `question`
+pub async fn answer_approvals(
+    mut approval_rx: mpsc::Receiver<ApprovalRequest>,
+    title: &'static str,
+    question: fn(&ApprovalRequest) -> String,
+) {
+    let mut lines = stdin_lines();
+    while let Some(mut request) = approval_rx.recv().await {
+        // Ignore anything typed while no prompt was shown
+        while lines.try_recv().is_ok() {}
+
+        println!();
+        println!("{}", title.yellow().bold());
+        println!(
+            "  {} Device:      {}",
+            mark("•").cyan(),
+            request.device_name.bold()
+        );
+        println!(
+            "  {} IP:          {}",
+            mark("•").cyan(),
+            request.address.ip()
+        );
+        println!(
+            "  {} Key:         {}",
+            mark("•").cyan(),
+            request.comment.dimmed()
+        );
+        println!(
+            "  {} Fingerprint: {}",
+            mark("•").cyan(),
+            request.fingerprint
+        );
+
+        print!("{} {} [y/N] ", "?".yellow().bold(), question(&request));
+        let _ = std::io::stdout().flush();
+
+        let answer = tokio::select! {
+            line = lines.recv() => line,
+            // Timed out, or the peer gave up
+            _ = request.closed() => {
+                println!();
+                continue;
+            }
+        };
+        request.respond(answer.as_deref().is_some_and(is_yes));
+    }
+}
+
+/// Whether a prompt answer means yes
+pub fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_module_exists() {
         // Just verify the module compiles
         assert!(true);
     }
+
+    #[test]
+    fn test_is_yes() {
+        assert!(is_yes("y"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes(""));
+        assert!(!is_yes("n"));
+        assert!(!is_yes("yep"));
+    }
 }
@@ -3,104 +3,593 @@
 use anyhow::{anyhow, Result};
 use colored::Colorize;
 use connecto_core::{
+    attempts::{PairingAttempt, PairingAttempts},
+    batch::{BatchPairing, BatchProgress, BatchResult, PairingStatus},
+    clock::{format_utc, UTC_DISPLAY_FORMAT},
+    connectivity::SSH_PORT,
     discovery::get_hostname,
+    identity::{DeviceIdentity, VerifiedIdentity},
     keys::{KeyAlgorithm, KeyManager, SshKeyPair},
-    protocol::HandshakeClient,
-    DEFAULT_PORT,
+    known_hosts::{KnownHostsStore, Recorded},
+    net,
+    next_steps::{self, Situation},
+    pairings::{PairingDirection, PairingRecord, PairingStore},
+    protocol::{ClientEvent, HandshakeClient, PairingResult, PinPrompt, PIN_ATTEMPTS},
+    relay::{PendingChannel, RelayChannel, RelayCode},
+    retry::RetryPolicy,
+    ssh_config::{self, HostEntry, SshConfig, TagTemplates},
+    stdio,
+    trust::{self, TrustMode, TrustStore},
+    ConnectoError,
 };
-use indicatif::{ProgressBar, ProgressStyle};
-use std::fs::OpenOptions;
-use std::io::Write;
+use dialoguer::Confirm;
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-use super::scan::load_cached_devices;
-use super::{error, info, success, warn};
+use super::{announce_algorithm, error, info, print_next_steps, success, warn, warn_clock_skew};
+use super::{scan, target};
 use crate::config::Config;
+use crate::device_cache::DeviceCache;
+use crate::output::{banner, mark, theme, Progress};
 
+/// How long to look for a device named on the command line when there is no
+/// recent scan
+const NAME_SCAN_TIMEOUT_SECS: u64 = 3;
+
+/// What pins, prompts and errors call the listener on the other end of stdio
+const STDIO_ADDRESS: &str = "stdin";
+
+/// The devices to pair with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Targets {
+    /// Device numbers from the last scan, device names, or addresses
+    Listed(Vec<String>),
+    /// Every device from the last scan
+    All,
+    /// The device waiting on a relay with this code
+    Relay { relay: String, code: RelayCode },
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
-    target: String,
+    targets: Targets,
     comment: Option<String>,
-    rsa: bool,
+    algorithm: KeyAlgorithm,
     key_path: Option<String>,
+    accept_new_identity: bool,
+    tags: Vec<String>,
+    lifetime: Option<Duration>,
+    alias: Option<String>,
+    mdns: bool,
+    user: Option<String>,
+    timeout: Option<Duration>,
+    retries: Option<u32>,
 ) -> Result<()> {
     println!();
-    println!(
-        "{}",
-        "  CONNECTO PAIRING  ".on_bright_magenta().white().bold()
-    );
+    banner("CONNECTO PAIRING", |s| s.on_bright_magenta().white().bold());
     println!();
 
-    // Resolve target to address
-    let address = resolve_target(&target)?;
+    let config = Config::load().unwrap_or_default();
+    let cache = DeviceCache::new(&config);
 
-    info(&format!("Connecting to {}...", address.cyan()));
-    println!();
+    // Resolve targets to addresses
+    let mut channel = None;
+    let addresses = match targets {
+        Targets::All => all_cached_addresses(&cache)?,
+        Targets::Listed(targets) => {
+            let mut addresses = Vec::new();
+            for target in &targets {
+                let address = if is_device_name(target) {
+                    resolve_name(target, &config, &cache).await?
+                } else {
+                    resolve_target(target, config.default_port(), &cache)?
+                };
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+            addresses
+        }
+        Targets::Relay { relay, code } => {
+            let relayed = join_relay(&relay, &code).await?;
+            // The device is known by its address as the relay sees it
+            let address = relayed.peer_addr().to_string();
+            channel = Some(relayed);
+            vec![address]
+        }
+    };
 
-    // Determine which key to use
-    // Priority: 1. --key flag, 2. config default_key, 3. generate new key
-    let effective_key_path =
-        key_path.or_else(|| Config::load().ok().and_then(|cfg| cfg.default_key.clone()));
+    if alias.is_some() && addresses.len() > 1 {
+        return Err(anyhow!(
+            "--alias names a single host; pair with one device at a time to use it"
+        ));
+    }
 
-    // Create spinner
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_style(
-        ProgressStyle::default_spinner()
-            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
-            .template("{spinner:.magenta} {msg}")
-            .unwrap(),
-    );
+    // Held until pairing ends, so a retry in another terminal can't race us
+    let (addresses, attempts) = begin_attempts(addresses)?;
 
-    let (key_pair, using_existing_key, existing_key_path) =
-        if let Some(key_file) = effective_key_path {
-            // Use existing key
-            let expanded_path = expand_path(&key_file)?;
-            let pub_key_path = format!("{}.pub", expanded_path);
+    if let [address] = addresses.as_slice() {
+        info(&format!("Connecting to {}...", address.cyan()));
+    } else {
+        info(&format!(
+            "Pairing with {} devices: {}",
+            addresses.len(),
+            addresses.join(", ").cyan()
+        ));
+    }
+    println!();
 
-            if !std::path::Path::new(&expanded_path).exists() {
-                return Err(anyhow!("Key file not found: {}", expanded_path));
-            }
-            if !std::path::Path::new(&pub_key_path).exists() {
-                return Err(anyhow!("Public key not found: {}", pub_key_path));
+    // Create spinner
+    let spinner = Progress::spinner("magenta");
+
+    // Every device receives the same key
+    let (key_pair, existing_key_path) =
+        match prepare_key(&config, key_path, comment, algorithm, &attempts, &spinner).await {
+            Ok(key) => key,
+            Err(e) => {
+                spinner.finish_and_clear();
+                return Err(e);
             }
+        };
 
-            info(&format!("Using existing key: {}", expanded_path.cyan()));
+    if key_pair.algorithm.is_security_key() {
+        info("Touch your security key to prove you hold the key");
+    }
 
-            spinner.set_message("Loading existing SSH key...");
-            spinner.enable_steady_tick(Duration::from_millis(80));
+    let mut client = handshake_client(&config.device_name(), accept_new_identity);
+    if let Some(lifetime) = lifetime {
+        client = client.with_key_lifetime(lifetime);
+    }
+    if let Some(user) = &user {
+        client = client.with_user(user);
+    }
+    if let Some(timeout) = timeout {
+        client = client.with_timeout(timeout);
+    }
+    if let Some(retries) = retries {
+        client = client.with_retry(RetryPolicy::new(retries.saturating_add(1)));
+    }
+    let options = InstallOptions {
+        tags,
+        templates: config.ssh_templates,
+        accept_new_identity,
+        lifetime,
+        alias,
+        mdns,
+    };
+    match addresses.as_slice() {
+        [address] => {
+            pair_one(
+                client,
+                address,
+                channel,
+                &key_pair,
+                existing_key_path.as_deref(),
+                &options,
+                spinner,
+            )
+            .await
+        }
+        _ => {
+            pair_many(
+                client,
+                addresses,
+                &key_pair,
+                existing_key_path.as_deref(),
+                &options,
+                spinner,
+            )
+            .await
+        }
+    }
+}
 
-            let key_pair = SshKeyPair::load_from_file(&expanded_path)?;
-            (key_pair, true, Some(expanded_path))
-        } else {
-            // Generate new key
-            let algorithm = if rsa {
-                warn("Using RSA-4096 (Ed25519 is recommended for better security)");
-                KeyAlgorithm::Rsa4096
-            } else {
-                info("Using Ed25519 key (modern, secure, fast)");
-                KeyAlgorithm::Ed25519
-            };
+/// Pair with `connecto listen --accept-stdin` over stdin and stdout, then
+/// print the authorized_keys line it installed and an SSH config entry for it
+///
+/// The messages travel over whatever joins the two, such as `ssh` or a person
+/// pasting each line, so everything meant for the user goes to stderr. The
+/// address of the device is unknown here, so `~/.ssh/config` is left alone:
+/// the entry printed names the device by its hostname, to be corrected.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_stdio(
+    comment: Option<String>,
+    algorithm: KeyAlgorithm,
+    key_path: Option<String>,
+    accept_new_identity: bool,
+    tags: Vec<String>,
+    lifetime: Option<Duration>,
+    alias: Option<String>,
+    user: Option<String>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let config = Config::load().unwrap_or_default();
 
+    // ssh-keygen would talk over stdout while enrolling a security key
+    let (key_pair, existing_key_path) = match key_path.or_else(|| config.default_key.clone()) {
+        Some(key_file) => {
+            let expanded_path = expand_path(&key_file)?;
+            (
+                SshKeyPair::load_from_file(&expanded_path)?,
+                Some(expanded_path),
+            )
+        }
+        None if algorithm.is_security_key() => {
+            return Err(anyhow!(
+                "Security keys cannot be enrolled while stdout carries the pairing; create one with `connecto keygen -t ed25519-sk` and pass it with --key"
+            ));
+        }
+        None => {
             let key_comment = comment.unwrap_or_else(|| {
                 let user = std::env::var("USER")
                     .or_else(|_| std::env::var("USERNAME"))
                     .unwrap_or_else(|_| "user".to_string());
-                let hostname = get_hostname();
-                format!("{}@{}", user, hostname)
+                format!("{}@{}", user, get_hostname())
             });
+            (
+                SshKeyPair::generate_async(algorithm, &key_comment).await?,
+                None,
+            )
+        }
+    };
+    config.check_algorithm(key_pair.algorithm)?;
 
-            spinner.set_message("Generating SSH key pair...");
-            spinner.enable_steady_tick(Duration::from_millis(80));
+    let mut client =
+        HandshakeClient::new(&config.device_name()).with_step_timeout(stdio::STEP_TIMEOUT);
+    if let Ok(store) = TrustStore::new() {
+        client = client.with_trust_store(store);
+    }
+    if accept_new_identity {
+        client = client.with_trust_mode(TrustMode::Warn);
+    }
+    if let Ok(identity) = DeviceIdentity::load_or_create() {
+        client = client.with_identity(&identity);
+    }
+    if let Some(lifetime) = lifetime {
+        client = client.with_key_lifetime(lifetime);
+    }
+    if let Some(user) = &user {
+        client = client.with_user(user);
+    }
+    if let Some(timeout) = timeout {
+        client = client.with_timeout(timeout);
+    }
 
-            let key_pair = SshKeyPair::generate(algorithm, &key_comment)?;
-            (key_pair, false, None)
-        };
+    eprintln!(
+        "{} Pairing over stdin and stdout with `connecto listen --accept-stdin`...",
+        mark("→").cyan().bold()
+    );
+    if std::io::stdin().is_terminal() {
+        eprintln!(
+            "  {} Paste each line printed here into the listener, and each line it prints here",
+            mark("→").cyan()
+        );
+    }
+    let result = client
+        .pair_over_lines(stdio::stream(), STDIO_ADDRESS, &key_pair)
+        .await;
+    let pairing_result = match result {
+        Ok(pairing_result) => pairing_result,
+        Err(e) => {
+            eprintln!("{} Pairing failed: {}", mark("✗").red().bold(), e);
+            return Err(e.into());
+        }
+    };
+    let peer = pairing_result.peer_name();
+    eprintln!(
+        "{} Paired with {}",
+        mark("✓").green().bold(),
+        peer.green().bold()
+    );
+    eprintln!(
+        "  {} Key fingerprint: {}",
+        mark("•").cyan(),
+        pairing_result.key_fingerprint.cyan()
+    );
+    if let Some(expires_at) = pairing_result.expires_at {
+        eprintln!(
+            "  {} {} accepts the key until {}",
+            mark("•").cyan(),
+            peer,
+            format_utc(expires_at, UTC_DISPLAY_FORMAT)
+        );
+    }
 
+    let host = alias.unwrap_or_else(|| ssh_config::host_alias(peer));
+    let private_path = match existing_key_path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            KeyManager::new()?
+                .save_key_pair(&key_pair, &format!("connecto_{}", host))?
+                .0
+        }
+    };
+    let entry = HostEntry {
+        host,
+        hostname: peer.to_string(),
+        user: pairing_result.ssh_user.clone(),
+        port: (pairing_result.ssh_port != SSH_PORT).then_some(pairing_result.ssh_port),
+        identity_file: private_path.display().to_string(),
+        identity: pairing_result
+            .server_identity
+            .as_ref()
+            .map(|identity| identity.fingerprint().to_string()),
+        ..Default::default()
+    }
+    .with_tags(&tags, &config.ssh_templates);
+
+    eprintln!();
+    eprintln!(
+        "{}",
+        format!("Authorized for {} on {}:", pairing_result.ssh_user, peer).bold()
+    );
+    eprintln!("{}", key_pair.public_key.trim_end());
+    eprintln!();
+    eprintln!(
+        "{}",
+        "Add this to ~/.ssh/config, with the device's address as HostName:".bold()
+    );
+    eprint!("{}", entry.to_block().trim_start());
+    Ok(())
+}
+
+/// Meet the device showing `code` on `relay`
+async fn join_relay(relay: &str, code: &RelayCode) -> Result<RelayChannel> {
+    info(&format!(
+        "Joining room {} on {}...",
+        code.room(),
+        relay.cyan()
+    ));
+    let channel = PendingChannel::join(relay, code).await?.connect().await?;
+    success("Connected end to end; the relay cannot read the pairing");
+    Ok(channel)
+}
+
+/// Start an attempt on every address, dropping those already being paired
+///
+/// Fails if no address is left.
+fn begin_attempts(addresses: Vec<String>) -> Result<(Vec<String>, Vec<PairingAttempt>)> {
+    let attempts = match PairingAttempts::new() {
+        Ok(attempts) => attempts,
+        Err(e) => {
+            warn(&format!(
+                "Duplicate pairing attempts will not be detected: {}",
+                e
+            ));
+            return Ok((addresses, Vec::new()));
+        }
+    };
+
+    let mut free = Vec::new();
+    let mut running = Vec::new();
+    let mut busy = Vec::new();
+    for address in addresses {
+        match attempts.begin(&address) {
+            Ok(attempt) => {
+                running.push(attempt);
+                free.push(address);
+            }
+            Err(ConnectoError::PairingInProgress(_)) => busy.push(address),
+            Err(e) => {
+                warn(&format!(
+                    "Could not track the pairing with {}: {}",
+                    address, e
+                ));
+                free.push(address);
+            }
+        }
+    }
+
+    for address in &busy {
+        error(&format!(
+            "Already pairing with {} in another window; wait for it to finish",
+            address
+        ));
+    }
+    if free.is_empty() {
+        println!();
+        return Err(anyhow!("Pairing already in progress"));
+    }
+    if !busy.is_empty() {
+        println!();
+    }
+    Ok((free, running))
+}
+
+/// The key a recent attempt sent to these targets, if they all got the same
+/// one
+fn recent_key(attempts: &[PairingAttempt], algorithm: KeyAlgorithm) -> Option<SshKeyPair> {
+    let mut keys = attempts
+        .iter()
+        .filter_map(|attempt| attempt.recent_key(algorithm));
+    let key_pair = keys.next()?;
+    keys.all(|other| other.public_key == key_pair.public_key)
+        .then_some(key_pair)
+}
+
+/// Load the key to send, or generate a new one
+///
+/// A newly generated key is kept with each attempt, and sent again if the
+/// user retries soon after. Returns the key pair and, for an existing key,
+/// the path of its private key.
+async fn prepare_key(
+    config: &Config,
+    key_path: Option<String>,
+    comment: Option<String>,
+    algorithm: KeyAlgorithm,
+    attempts: &[PairingAttempt],
+    spinner: &Progress,
+) -> Result<(SshKeyPair, Option<String>)> {
+    // Determine which key to use
+    // Priority: 1. --key flag, 2. config default_key, 3. generate new key
+    let effective_key_path = key_path.or_else(|| config.default_key.clone());
+
+    let (key_pair, existing_key_path) = if let Some(key_file) = effective_key_path {
+        // Use existing key
+        let expanded_path = expand_path(&key_file)?;
+        let pub_key_path = format!("{}.pub", expanded_path);
+
+        if !std::path::Path::new(&expanded_path).exists() {
+            return Err(anyhow!("Key file not found: {}", expanded_path));
+        }
+        if !std::path::Path::new(&pub_key_path).exists() {
+            return Err(anyhow!("Public key not found: {}", pub_key_path));
+        }
+
+        info(&format!("Using existing key: {}", expanded_path.cyan()));
+
+        spinner.set_message("Loading existing SSH key...");
+        spinner.enable_steady_tick();
+
+        let key_pair = SshKeyPair::load_from_file(&expanded_path)?;
+        (key_pair, Some(expanded_path))
+    } else {
+        let key_comment = comment.unwrap_or_else(|| {
+            let user = std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .unwrap_or_else(|_| "user".to_string());
+            let hostname = get_hostname();
+            format!("{}@{}", user, hostname)
+        });
+
+        match recent_key(attempts, algorithm).filter(|key| key.comment == key_comment) {
+            Some(key_pair) => {
+                info("Retrying with the key from the last attempt");
+                (key_pair, None)
+            }
+            None => {
+                // Generate new key
+                announce_algorithm(algorithm);
+
+                // ssh-keygen talks to the user while enrolling a security key
+                if !algorithm.is_security_key() {
+                    spinner.set_message("Generating SSH key pair...");
+                    spinner.enable_steady_tick();
+                }
+
+                let key_pair = SshKeyPair::generate_async(algorithm, &key_comment).await?;
+                for attempt in attempts {
+                    if let Err(e) = attempt.remember_key(&key_pair) {
+                        warn(&format!("A retry will need a new key: {}", e));
+                    }
+                }
+                (key_pair, None)
+            }
+        }
+    };
+
+    config.check_algorithm(key_pair.algorithm)?;
+    Ok((key_pair, existing_key_path))
+}
+
+fn handshake_client(device_name: &str, accept_new_identity: bool) -> HandshakeClient {
+    let mut client = HandshakeClient::new(device_name);
+    match TrustStore::new() {
+        Ok(store) => client = client.with_trust_store(store),
+        Err(e) => warn(&format!("Device identities will not be checked: {}", e)),
+    }
+    // Devices at addresses from ~/.ssh/config must still be the same device
+    match SshConfig::new().and_then(|config| config.identity_pins()) {
+        Ok(pins) => client = client.with_address_pins(pins),
+        Err(e) => warn(&format!("Pinned addresses will not be checked: {}", e)),
+    }
+    if accept_new_identity {
+        client = client.with_trust_mode(TrustMode::Warn);
+    }
+    match DeviceIdentity::load_or_create() {
+        Ok(identity) => client = client.with_identity(&identity),
+        Err(e) => warn(&format!("Could not load device identity: {}", e)),
+    }
+    client
+}
+
+/// Check the server against the identity pinned in the SSH config entry it
+/// would use, before anything is installed
+///
+/// An alias chosen with `--alias` is not checked: the user is asked before
+/// an entry for another device is replaced.
+fn check_host_pin(
+    pairing_result: &PairingResult,
+    options: &InstallOptions,
+) -> connecto_core::Result<()> {
+    if options.alias.is_some() {
+        return Ok(());
+    }
+    let host_alias = ssh_config::host_alias(pairing_result.peer_name());
+    let entry = SshConfig::new()?
+        .entries()?
+        .into_iter()
+        .find(|e| e.host == host_alias);
+    let Some(pinned) = entry.and_then(|e| e.identity) else {
+        return Ok(());
+    };
+    let mode = if options.accept_new_identity {
+        TrustMode::Warn
+    } else {
+        TrustMode::Enforce
+    };
+    trust::verify_identity(
+        &format!("The device paired as '{}'", host_alias),
+        &pinned,
+        pairing_result.server_identity.as_ref(),
+        mode,
+    )
+}
+
+async fn pair_one(
+    mut client: HandshakeClient,
+    address: &str,
+    channel: Option<RelayChannel>,
+    key_pair: &SshKeyPair,
+    existing_key_path: Option<&str>,
+    options: &InstallOptions,
+    spinner: Progress,
+) -> Result<()> {
     spinner.set_message("Connecting and exchanging keys...");
 
-    // Create client and pair
-    let client = HandshakeClient::new(&get_hostname());
-    let result = client.pair(&address, &key_pair).await;
+    // Listeners running with --approve tell us while they wait for an answer
+    let (event_tx, mut event_rx) = mpsc::channel(4);
+    client = client.with_events(event_tx);
+    let waiting = spinner.clone();
+    let notices = tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            waiting.set_message(match event {
+                ClientEvent::AwaitingApproval { remaining_secs } => format!(
+                    "Waiting for the device's owner to approve ({}s left)...",
+                    remaining_secs
+                ),
+                ClientEvent::Retrying {
+                    attempt,
+                    attempts,
+                    delay,
+                    ..
+                } => format!(
+                    "Attempt {}/{} failed, trying again in {:.1}s...",
+                    attempt,
+                    attempts,
+                    delay.as_secs_f64()
+                ),
+            });
+        }
+    });
+
+    let (pin_tx, prompter) = prompt_for_pins(&spinner);
+    client = client.with_pin_prompt(pin_tx);
+
+    let result = match channel {
+        Some(channel) => {
+            client
+                .pair_over(channel.into_stream(), address, key_pair)
+                .await
+        }
+        None => client.pair(address, key_pair).await,
+    };
+    notices.abort();
+    prompter.abort();
 
     spinner.finish_and_clear();
 
@@ -108,92 +597,81 @@ pub async fn run(
         Ok(pairing_result) => {
             println!();
             success("Pairing successful!");
+            report_fingerprint(&pairing_result);
+            warn_clock_skew(pairing_result.peer_name(), pairing_result.clock_skew);
+            report_expiry(&pairing_result, options);
             println!();
 
-            // Determine the key path to use in SSH config
-            let private_path = if using_existing_key {
-                // Use the existing key path
-                let path = existing_key_path.unwrap();
-                println!("{}", "Using existing key:".bold());
-                println!("  {} {}", "•".green(), path.dimmed());
-                println!();
-                PathBuf::from(path)
-            } else {
-                // Save the new key locally
-                let key_manager = KeyManager::new()?;
-                let key_name = format!("connecto_{}", sanitize_name(&pairing_result.server_name));
-                let (private_path, public_path) =
-                    key_manager.save_key_pair(&key_pair, &key_name)?;
-
-                println!("{}", "Key saved:".bold());
-                println!(
-                    "  {} Private: {}",
-                    "•".green(),
-                    private_path.display().to_string().dimmed()
-                );
-                println!(
-                    "  {} Public:  {}",
-                    "•".green(),
-                    public_path.display().to_string().dimmed()
-                );
+            if let Err(e) = check_host_pin(&pairing_result, options) {
+                error(&format!("Not installing the key: {}", e));
                 println!();
-                private_path
-            };
-
-            // Auto-configure SSH config
-            let primary_ip = extract_ip_from_address(&address);
-            let host_alias = sanitize_name(&pairing_result.server_name);
-
-            match add_to_ssh_config(
-                &host_alias,
-                &primary_ip,
-                &pairing_result.ssh_user,
-                &private_path,
-            ) {
-                Ok(true) => {
-                    success(&format!("Added to ~/.ssh/config as '{}'", host_alias));
-                    println!();
-                    println!("{}", "You can now connect with:".bold());
-                    println!();
-                    println!("  {}", format!("ssh {}", host_alias).cyan().bold());
-                }
-                Ok(false) => {
-                    info(&format!("Host '{}' already in ~/.ssh/config", host_alias));
-                    println!();
-                    println!("{}", "You can connect with:".bold());
-                    println!();
-                    println!("  {}", format!("ssh {}", host_alias).cyan().bold());
+                print_identity_hint(&[address.to_string()]);
+                return Err(e.into());
+            }
+
+            let installed = install(
+                &pairing_result,
+                address,
+                key_pair,
+                existing_key_path,
+                options,
+            )?;
+
+            match &installed.public_path {
+                None => {
+                    println!("{}", "Using existing key:".bold());
+                    println!(
+                        "  {} {}",
+                        mark("•").green(),
+                        installed.private_path.display().to_string().dimmed()
+                    );
                 }
-                Err(e) => {
-                    warn(&format!("Could not update ~/.ssh/config: {}", e));
-                    println!();
-                    println!("{}", "You can connect with:".bold());
-                    println!();
+                Some(public_path) => {
+                    println!("{}", "Key saved:".bold());
+                    println!(
+                        "  {} Private: {}",
+                        mark("•").green(),
+                        installed.private_path.display().to_string().dimmed()
+                    );
                     println!(
-                        "  {}",
-                        format!(
-                            "ssh -i {} {}@{}",
-                            private_path.display(),
-                            pairing_result.ssh_user,
-                            primary_ip
-                        )
-                        .cyan()
-                        .bold()
+                        "  {} Public:  {}",
+                        mark("•").green(),
+                        public_path.display().to_string().dimmed()
                     );
                 }
             }
             println!();
+
+            let host_alias = &installed.host_alias;
+            match &installed.ssh_config {
+                Ok(ConfigEntry::Added) => {
+                    success(&format!("Added to ~/.ssh/config as '{}'", host_alias))
+                }
+                Ok(ConfigEntry::Replaced) => {
+                    success(&format!("Replaced '{}' in ~/.ssh/config", host_alias))
+                }
+                Ok(ConfigEntry::Exists) => {
+                    info(&format!("Host '{}' already in ~/.ssh/config", host_alias))
+                }
+                Err(e) => warn(&format!("Could not update ~/.ssh/config: {}", e)),
+            }
+            report_known_hosts(pairing_result.peer_name(), &installed.known_hosts);
+            println!();
+
+            let key_in_agent = next_steps::key_in_agent(key_pair);
+            print_next_steps(&next_steps::recommend(&situation(&installed, key_in_agent)));
+        }
+        Err(e @ ConnectoError::IdentityMismatch(_)) => {
+            error(&format!("Pairing aborted: {}", e));
+            println!();
+            print_identity_hint(&[address.to_string()]);
+            return Err(e.into());
         }
         Err(e) => {
+            forget_unreachable(address, &e);
             error(&format!("Pairing failed: {}", e));
             println!();
-            println!("{}", "Troubleshooting:".bold());
-            println!(
-                "  {} Make sure the target is running 'connecto listen'",
-                "•".dimmed()
-            );
-            println!("  {} Check that the address is correct", "•".dimmed());
-            println!("  {} Verify firewall allows the connection", "•".dimmed());
+            print_troubleshooting();
             println!();
             return Err(e.into());
         }
@@ -202,14 +680,531 @@ pub async fn run(
     Ok(())
 }
 
-fn resolve_target(target: &str) -> Result<String> {
+/// Ask for the verification code of each listener running with `--verify`
+///
+/// Prompts are answered one at a time, with the spinner paused.
+fn prompt_for_pins(spinner: &Progress) -> (mpsc::Sender<PinPrompt>, JoinHandle<()>) {
+    let (pin_tx, mut pin_rx) = mpsc::channel::<PinPrompt>(4);
+    let spinner = spinner.clone();
+    let prompter = tokio::spawn(async move {
+        while let Some(prompt) = pin_rx.recv().await {
+            let spinner = spinner.clone();
+            let question = (
+                prompt.server_name.clone(),
+                prompt.address.clone(),
+                prompt.attempts_left,
+            );
+            let pin = tokio::task::spawn_blocking(move || {
+                let (server_name, address, attempts_left) = question;
+                spinner.suspend(|| read_pin(&server_name, &address, attempts_left))
+            })
+            .await;
+            // Dropping the prompt gives up on that pairing
+            if let Ok(Some(pin)) = pin {
+                prompt.respond(&pin);
+            }
+        }
+    });
+    (pin_tx, prompter)
+}
+
+/// Read a verification code from stdin, or `None` at end of input
+fn read_pin(server_name: &str, address: &str, attempts_left: u32) -> Option<String> {
+    if attempts_left < PIN_ATTEMPTS {
+        warn(&format!(
+            "Wrong code, {} {} left",
+            attempts_left,
+            if attempts_left == 1 { "try" } else { "tries" }
+        ));
+    }
+    print!(
+        "{} Enter the verification code shown on {} ({}): ",
+        "?".yellow().bold(),
+        server_name.bold(),
+        address
+    );
+    let _ = std::io::stdout().flush();
+
+    let mut line = String::new();
+    match std::io::stdin().lock().read_line(&mut line) {
+        Ok(n) if n > 0 => Some(line.trim().to_string()),
+        _ => None,
+    }
+}
+
+/// Pair with several devices concurrently, reporting each one's outcome
+async fn pair_many(
+    mut client: HandshakeClient,
+    addresses: Vec<String>,
+    key_pair: &SshKeyPair,
+    existing_key_path: Option<&str>,
+    options: &InstallOptions,
+    spinner: Progress,
+) -> Result<()> {
+    let total = addresses.len();
+    spinner.set_message(format!("Exchanging keys (0/{} done)...", total));
+    spinner.enable_steady_tick();
+
+    let (progress_tx, mut progress_rx) = mpsc::channel(16);
+    let counter = spinner.clone();
+    let progress = tokio::spawn(async move {
+        let mut done = 0;
+        while let Some(BatchProgress { status, .. }) = progress_rx.recv().await {
+            if matches!(status, PairingStatus::Paired | PairingStatus::Failed { .. }) {
+                done += 1;
+                counter.set_message(format!("Exchanging keys ({}/{} done)...", done, total));
+            }
+        }
+    });
+
+    let mut batch = BatchPairing::new().with_progress(progress_tx);
+    // Each key proof needs its own touch, so take them one at a time
+    if key_pair.algorithm.is_security_key() {
+        batch = batch.with_max_concurrent(1);
+    }
+    let (pin_tx, prompter) = prompt_for_pins(&spinner);
+    client = client.with_pin_prompt(pin_tx);
+    let results = batch.pair(&client, addresses, key_pair).await;
+    drop(batch);
+    let _ = progress.await;
+    prompter.abort();
+
+    spinner.finish_and_clear();
+    println!();
+
+    let mut paired = Vec::new();
+    let mut failed = 0;
+    let mut identity_changed = Vec::new();
+    for BatchResult { address, result } in results {
+        let pairing_result = match result {
+            Ok(pairing_result) => pairing_result,
+            Err(e) => {
+                if matches!(e, ConnectoError::IdentityMismatch(_)) {
+                    identity_changed.push(address.clone());
+                }
+                forget_unreachable(&address, &e);
+                error(&format!("{}: {}", address, e));
+                failed += 1;
+                continue;
+            }
+        };
+
+        if let Err(e) = check_host_pin(&pairing_result, options) {
+            if matches!(e, ConnectoError::IdentityMismatch(_)) {
+                identity_changed.push(address.clone());
+            }
+            error(&format!("{}: not installing the key: {}", address, e));
+            failed += 1;
+            continue;
+        }
+
+        match install(
+            &pairing_result,
+            &address,
+            key_pair,
+            existing_key_path,
+            options,
+        ) {
+            Ok(installed) => {
+                success(&format!(
+                    "Paired with {} ({})",
+                    pairing_result.peer_name().bold(),
+                    address
+                ));
+                if let Err(e) = &installed.ssh_config {
+                    warn(&format!("Could not update ~/.ssh/config: {}", e));
+                }
+                if let Err(e) = &installed.known_hosts {
+                    warn(&format!("Could not update ~/.ssh/known_hosts: {}", e));
+                }
+                warn_clock_skew(pairing_result.peer_name(), pairing_result.clock_skew);
+                report_expiry(&pairing_result, options);
+                paired.push(installed);
+            }
+            Err(e) => {
+                error(&format!(
+                    "{}: paired, but the key could not be saved: {}",
+                    address, e
+                ));
+                failed += 1;
+            }
+        }
+    }
+    println!();
+
+    if !paired.is_empty() {
+        info(&format!(
+            "Key fingerprint: {}",
+            key_pair.fingerprint()?.cyan()
+        ));
+        println!(
+            "  {} {}",
+            mark("→").cyan(),
+            "Check that each device shows the same fingerprint".dimmed()
+        );
+        println!();
+    }
+
+    // Every device shares the key, so suggestions about it are only shown once
+    let key_in_agent = next_steps::key_in_agent(key_pair);
+    let mut steps = Vec::new();
+    for installed in &paired {
+        for step in next_steps::recommend(&situation(installed, key_in_agent)) {
+            if !steps.contains(&step) {
+                steps.push(step);
+            }
+        }
+    }
+    print_next_steps(&steps);
+
+    if !identity_changed.is_empty() {
+        print_identity_hint(&identity_changed);
+    }
+
+    if failed == 0 {
+        success(&format!("Paired with all {} devices", total));
+        println!();
+        return Ok(());
+    }
+
+    println!(
+        "  {} paired, {} failed",
+        paired.len().to_string().green(),
+        failed.to_string().red()
+    );
+    println!();
+    Err(anyhow!("{} of {} pairings failed", failed, total))
+}
+
+/// How paired hosts are added to `~/.ssh/config`
+struct InstallOptions {
+    /// Tags for the new entries
+    tags: Vec<String>,
+    templates: TagTemplates,
+    /// Rebind existing entries to a device's new identity
+    accept_new_identity: bool,
+    /// How long the devices should accept the key, if not for good
+    lifetime: Option<Duration>,
+    /// Name of the host in `~/.ssh/config`, instead of the device's name
+    alias: Option<String>,
+    /// Write the devices' mDNS hostnames to `~/.ssh/config` instead of their
+    /// addresses
+    mdns: bool,
+}
+
+/// The name a device gets in `~/.ssh/config`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Alias {
+    name: String,
+    /// Whether the entry of the name is another device's, to be replaced
+    replace: bool,
+}
+
+/// What became of the device's entry in `~/.ssh/config`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigEntry {
+    /// A new entry was added
+    Added,
+    /// The device already had the entry
+    Exists,
+    /// An entry for another device or address was replaced, as confirmed
+    Replaced,
+}
+
+/// What was set up locally after a successful pairing
+struct Installed {
+    private_path: PathBuf,
+    /// Only set when a new key was saved
+    public_path: Option<PathBuf>,
+    host_alias: String,
+    ssh_config: Result<ConfigEntry>,
+    /// What changed in `~/.ssh/known_hosts`
+    known_hosts: Result<Recorded>,
+    /// Command that connects without the SSH config entry
+    ssh_command: String,
+}
+
+/// Save the key, add the peer to `~/.ssh/config`, and record the pairing
+fn install(
+    pairing_result: &PairingResult,
+    address: &str,
+    key_pair: &SshKeyPair,
+    existing_key_path: Option<&str>,
+    options: &InstallOptions,
+) -> Result<Installed> {
+    let primary_ip = extract_ip_from_address(address);
+    let hostname = config_hostname(&primary_ip, options.mdns);
+    let wanted = options
+        .alias
+        .clone()
+        .unwrap_or_else(|| ssh_config::host_alias(pairing_result.peer_name()));
+    // Settled before the key is saved, as the key is named after it
+    let alias = choose_alias(
+        wanted,
+        &hostname,
+        pairing_result
+            .server_identity
+            .as_ref()
+            .map(VerifiedIdentity::fingerprint),
+        options,
+    );
+    let host_alias = alias.name.clone();
+
+    // Determine the key path to use in SSH config
+    let (private_path, public_path) = match existing_key_path {
+        Some(path) => (PathBuf::from(path), None),
+        None => {
+            // Save the new key locally
+            let key_manager = KeyManager::new()?;
+            let key_name = format!("connecto_{}", host_alias);
+            let (private_path, public_path) = key_manager.save_key_pair(key_pair, &key_name)?;
+            (private_path, Some(public_path))
+        }
+    };
+
+    // Auto-configure SSH config
+    let ssh_config = add_to_ssh_config(
+        &alias,
+        &hostname,
+        &pairing_result.ssh_user,
+        pairing_result.ssh_port,
+        &private_path,
+        pairing_result
+            .server_identity
+            .as_ref()
+            .map(VerifiedIdentity::fingerprint),
+        options,
+    );
+
+    // Remember when and with whom we paired
+    let recorded = PairingRecord::new(
+        pairing_result.peer_name(),
+        &key_pair.public_key,
+        &primary_ip,
+        PairingDirection::Outgoing,
+    )
+    .and_then(|record| {
+        PairingStore::new()?.record(
+            record
+                .with_host(&host_alias)
+                .with_ssh_port(pairing_result.ssh_port)
+                .with_key_path(&private_path.to_string_lossy())
+                .with_peer_identity(
+                    pairing_result
+                        .server_identity
+                        .as_ref()
+                        .map(VerifiedIdentity::fingerprint),
+                )
+                .with_clock_skew(pairing_result.clock_skew)
+                .with_expires_at(pairing_result.expires_at),
+        )
+    });
+    if let Err(e) = recorded {
+        warn(&format!("Could not record pairing: {}", e));
+    }
+
+    // Trust the host keys the listener sent, so the first `ssh` doesn't ask
+    let known_hosts = if pairing_result.host_keys.is_empty() {
+        Ok(Recorded::default())
+    } else {
+        KnownHostsStore::new()
+            .and_then(|store| {
+                store.record(
+                    &hostname,
+                    pairing_result.ssh_port,
+                    &pairing_result.host_keys,
+                )
+            })
+            .map_err(Into::into)
+    };
+
+    let port_arg = match pairing_result.ssh_port {
+        SSH_PORT => String::new(),
+        port => format!(" -p {}", port),
+    };
+    let ssh_command = format!(
+        "ssh -i {}{} {}@{}",
+        private_path.display(),
+        port_arg,
+        pairing_result.ssh_user,
+        hostname
+    );
+
+    Ok(Installed {
+        private_path,
+        public_path,
+        host_alias,
+        ssh_config,
+        known_hosts,
+        ssh_command,
+    })
+}
+
+/// Say which host keys were added to `~/.ssh/known_hosts`, and warn about
+/// any they replaced
+fn report_known_hosts(peer: &str, known_hosts: &Result<Recorded>) {
+    match known_hosts {
+        Ok(recorded) => {
+            if recorded.added > 0 {
+                success(&format!(
+                    "Added {} host key(s) of {} to ~/.ssh/known_hosts",
+                    recorded.added, peer
+                ));
+            }
+            if !recorded.replaced.is_empty() {
+                warn(&format!(
+                    "Replaced {} old host key(s) of {} in ~/.ssh/known_hosts",
+                    recorded.replaced.len(),
+                    peer
+                ));
+                for old in &recorded.replaced {
+                    println!(
+                        "  {} {} {}",
+                        mark("•").yellow(),
+                        old.key_type,
+                        old.fingerprint().unwrap_or_default().dimmed()
+                    );
+                }
+            }
+        }
+        Err(e) => warn(&format!("Could not update ~/.ssh/known_hosts: {}", e)),
+    }
+}
+
+/// Show the fingerprint of the key sent, to compare with the one the
+/// listener shows
+fn report_fingerprint(pairing_result: &PairingResult) {
+    info(&format!(
+        "Key fingerprint: {}",
+        pairing_result.key_fingerprint.cyan()
+    ));
+    let hint = if pairing_result.fingerprint_confirmed {
+        format!(
+            "{} installed the key with this fingerprint and shows it too",
+            pairing_result.peer_name()
+        )
+    } else {
+        format!(
+            "Check that {} shows the same fingerprint",
+            pairing_result.peer_name()
+        )
+    };
+    println!("  {} {}", mark("→").cyan(), hint.dimmed());
+}
+
+/// Say when the device stops accepting the key, or that it never will
+fn report_expiry(pairing_result: &PairingResult, options: &InstallOptions) {
+    match pairing_result.expires_at {
+        Some(expires_at) => info(&format!(
+            "{} accepts the key until {}",
+            pairing_result.peer_name(),
+            format_utc(expires_at, UTC_DISPLAY_FORMAT)
+        )),
+        None if options.lifetime.is_some() => warn(&format!(
+            "{} does not support expiring keys; it accepts the key until you unpair",
+            pairing_result.peer_name()
+        )),
+        None => {}
+    }
+}
+
+/// What to do about devices at `addresses` whose identity changed
+fn print_identity_hint(addresses: &[String]) {
+    println!(
+        "  {} Another device may be impersonating it. It may also have been reinstalled,",
+        mark("!").yellow()
+    );
+    println!("    had its identity reset, or lost its address to another device.");
+    println!();
+    let steps: Vec<_> = addresses
+        .iter()
+        .flat_map(|address| {
+            next_steps::recommend(&Situation {
+                address: Some(address.clone()),
+                identity_changed: true,
+                ..Default::default()
+            })
+        })
+        .collect();
+    print_next_steps(&steps);
+}
+
+/// What is known once a device's key is installed
+fn situation(installed: &Installed, key_in_agent: Option<bool>) -> Situation {
+    Situation {
+        host: installed
+            .ssh_config
+            .is_ok()
+            .then(|| installed.host_alias.clone()),
+        ssh_command: Some(installed.ssh_command.clone()),
+        ssh_config_failed: installed.ssh_config.is_err(),
+        key_path: Some(installed.private_path.display().to_string()),
+        key_in_agent,
+        ..Default::default()
+    }
+}
+
+fn print_troubleshooting() {
+    println!("{}", "Troubleshooting:".bold());
+    println!(
+        "  {} Make sure the target is running 'connecto listen'",
+        mark("•").dimmed()
+    );
+    println!("  {} Check that the address is correct", mark("•").dimmed());
+    println!(
+        "  {} Verify firewall allows the connection",
+        mark("•").dimmed()
+    );
+}
+
+/// Mark `address` unreachable in the last scan's results when `e` says
+/// nothing answered there, so it is not paired with by number again
+fn forget_unreachable(address: &str, e: &ConnectoError) {
+    if !matches!(
+        e,
+        ConnectoError::ConnectionRefused(_) | ConnectoError::Network(_)
+    ) {
+        return;
+    }
+    let cache = DeviceCache::new(&Config::load().unwrap_or_default());
+    if let Err(e) = cache.invalidate(address) {
+        tracing::debug!("Could not update the cached scan results: {}", e);
+    }
+}
+
+/// Addresses of every device from the last scan
+fn all_cached_addresses(cache: &DeviceCache) -> Result<Vec<String>> {
+    let scan = cache
+        .fresh()
+        .map_err(|e| anyhow!("{}, or provide IP:port addresses.", e))?;
+
+    let mut addresses = Vec::new();
+    for device in &scan.devices {
+        match device.connection_string() {
+            Some(address) if scan.is_unreachable(device) => warn(&format!(
+                "Skipping {}: it could not be reached before",
+                address
+            )),
+            Some(address) if !addresses.contains(&address) => addresses.push(address),
+            Some(_) => {}
+            None => warn(&format!("Skipping {}: no IP address", device.name)),
+        }
+    }
+    if addresses.is_empty() {
+        return Err(anyhow!(
+            "The last scan found no devices that can be reached. Run 'connecto scan' again."
+        ));
+    }
+    Ok(addresses)
+}
+
+fn resolve_target(target: &str, default_port: u16, cache: &DeviceCache) -> Result<String> {
     // First, check if it's a number (device index from scan, 0-based)
     if let Ok(index) = target.parse::<usize>() {
-        let devices = load_cached_devices().map_err(|_| {
-            anyhow!(
-                "No cached devices found. Run 'connecto scan' first, or provide an IP:port address."
-            )
-        })?;
+        let scan = cache
+            .fresh()
+            .map_err(|e| anyhow!("{}, or provide an IP:port address.", e))?;
+        let devices = &scan.devices;
 
         if index >= devices.len() {
             return Err(anyhow!(
@@ -220,142 +1215,470 @@ fn resolve_target(target: &str) -> Result<String> {
         }
 
         let device = &devices[index];
-        device
+        let address = device
             .connection_string()
-            .ok_or_else(|| anyhow!("Device {} has no IP address", device.name))
-    } else if target.contains(':') {
+            .ok_or_else(|| anyhow!("Device {} has no IP address", device.name))?;
+        if scan.is_unreachable(device) {
+            // Its router may still forward to it (`listen --upnp`)
+            if let Some(external) = device.external {
+                info(&format!(
+                    "Device {} could not be reached at {}; trying its router's address {}",
+                    index, address, external
+                ));
+                return Ok(external.to_string());
+            }
+            return Err(anyhow!(
+                "Device {} could not be reached at {} since the last scan. Run 'connecto scan' again, or provide its new address.",
+                index,
+                address
+            ));
+        }
+        Ok(address)
+    } else if connecto_core::net::host_of(target) != target {
         // It's an address with port
         Ok(target.to_string())
     } else {
         // It's just an IP, add default port
-        Ok(format!("{}:{}", target, DEFAULT_PORT))
+        Ok(connecto_core::net::join_host_port(target, default_port))
     }
 }
 
-fn sanitize_name(name: &str) -> String {
-    name.chars()
-        .map(|c| {
-            if c.is_alphanumeric() || c == '-' || c == '_' {
-                c
-            } else {
-                '_'
+/// Whether `target` names a device, rather than giving its number or address
+fn is_device_name(target: &str) -> bool {
+    target.parse::<usize>().is_err()
+        && net::host_of(target) == target
+        && !target::is_ip_address(target)
+}
+
+/// The address of the device called `name`, see [`target::candidates`]
+///
+/// Without a recent scan to look in, one is run first. A name that matches
+/// no device is used as a host name if it resolves.
+async fn resolve_name(name: &str, config: &Config, cache: &DeviceCache) -> Result<String> {
+    let hosts = SshConfig::new()
+        .and_then(|ssh_config| ssh_config.entries())
+        .unwrap_or_default();
+    let port = config.default_port();
+    let devices = match cache.fresh() {
+        Ok(scan) => scan.devices,
+        Err(_) => {
+            info(&format!("Looking for '{}' on the network...", name));
+            scan::discover(config, Duration::from_secs(NAME_SCAN_TIMEOUT_SECS)).await?
+        }
+    };
+
+    let found = target::candidates(name, &devices, &hosts, port);
+    match found.as_slice() {
+        [candidate] => {
+            info(&format!("'{}' is {}", name, candidate));
+            Ok(candidate.address.clone())
+        }
+        [] => {
+            let address = net::join_host_port(name, port);
+            if net::resolve(&address)
+                .await
+                .is_ok_and(|addrs| !addrs.is_empty())
+            {
+                return Ok(address);
             }
-        })
-        .collect::<String>()
-        .to_lowercase()
+            Err(anyhow!(
+                "No device called '{}' was found. Run 'connecto scan' to see the devices nearby, or provide an IP:port address.",
+                name
+            ))
+        }
+        _ => {
+            let list: String = found
+                .iter()
+                .map(|candidate| format!("\n  {} {}", mark("•"), candidate))
+                .collect();
+            Err(anyhow!(
+                "'{}' matches {} devices:{}\nGive more of its name, its device number or its address.",
+                name,
+                found.len(),
+                list
+            ))
+        }
+    }
 }
 
 fn extract_ip_from_address(address: &str) -> String {
-    address.split(':').next().unwrap_or(address).to_string()
+    connecto_core::net::host_of(address).to_string()
+}
+
+/// The name the device at `ip` gets as `HostName` in `~/.ssh/config`
+///
+/// With `--mdns` this is the device's mDNS hostname from the last scan,
+/// provided it resolves to `ip` here, so the entry keeps working when the
+/// device gets a new DHCP lease. Otherwise, with a warning, it is `ip`.
+fn config_hostname(ip: &str, mdns: bool) -> String {
+    if !mdns {
+        return ip.to_string();
+    }
+    // Link-local addresses carry their scope
+    let addr = ip
+        .split('%')
+        .next()
+        .and_then(|ip| ip.parse::<IpAddr>().ok());
+    let device = addr.and_then(|addr| {
+        DeviceCache::new(&Config::load().unwrap_or_default())
+            .load()
+            .ok()??
+            .devices
+            .into_iter()
+            .find(|device| device.addresses.contains(&addr))
+    });
+    let Some(name) = device.as_ref().and_then(|device| device.mdns_hostname()) else {
+        warn(&format!(
+            "No mDNS hostname known for {}; using its address. Pair by number after 'connecto scan' to use it",
+            ip
+        ));
+        return ip.to_string();
+    };
+    if !addr.is_some_and(|addr| net::resolves_to(name, addr)) {
+        warn(&format!(
+            "{} does not resolve to {} here; using the address instead",
+            name, ip
+        ));
+        return ip.to_string();
+    }
+    name.to_string()
 }
 
 /// Expand ~ to home directory in path
 fn expand_path(path: &str) -> Result<String> {
-    if path.starts_with("~/") {
-        let home = std::env::var("HOME")
-            .or_else(|_| std::env::var("USERPROFILE"))
-            .map_err(|_| anyhow!("HOME/USERPROFILE not set"))?;
-        Ok(path.replacen("~", &home, 1))
-    } else {
-        Ok(path.to_string())
+    Ok(connecto_core::paths::expand_home(path)?
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Pick the name for a device in `~/.ssh/config`, starting from `wanted`
+///
+/// `wanted` is kept when it is free or already the device's. When it names
+/// another device or address, the user is asked whether to replace that
+/// entry; otherwise, and for hosts written by hand, the first free name of
+/// `wanted-2`, `wanted-3`, ... is used instead.
+fn choose_alias(
+    wanted: String,
+    hostname: &str,
+    identity: Option<&str>,
+    options: &InstallOptions,
+) -> Alias {
+    let keep = |name: String| Alias {
+        name,
+        replace: false,
+    };
+    let Ok(ssh_config) = SshConfig::new() else {
+        return keep(wanted);
+    };
+    if !ssh_config.has_host(&wanted).unwrap_or(false) {
+        return keep(wanted);
     }
+
+    let existing = ssh_config
+        .entries()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|e| e.host == wanted);
+    if let Some(existing) = existing {
+        let entry = HostEntry {
+            hostname: hostname.to_string(),
+            identity: identity.map(str::to_string),
+            ..Default::default()
+        };
+        if is_same_device(&existing, &entry, options.accept_new_identity) {
+            return keep(wanted);
+        }
+        match confirm_replace(&existing) {
+            Ok(true) => {
+                return Alias {
+                    name: wanted,
+                    replace: true,
+                }
+            }
+            Ok(false) => {}
+            Err(e) => warn(&format!(
+                "Could not ask whether to replace '{}': {}",
+                wanted, e
+            )),
+        }
+    }
+
+    let free = (2..)
+        .map(|n| format!("{}-{}", wanted, n))
+        .find(|name| !ssh_config.has_host(name).unwrap_or(true))
+        .unwrap_or_else(|| wanted.clone());
+    warn(&format!(
+        "'{}' in ~/.ssh/config is another host; adding this device as '{}'",
+        wanted, free
+    ));
+    println!();
+    keep(free)
 }
 
 /// Add a host entry to ~/.ssh/config
-/// Returns Ok(true) if added, Ok(false) if already exists, Err on failure
+///
+/// When the server announced an identity, the entry is bound to it so later
+/// scans can follow the device to a new address. Tags are added to an
+/// existing entry of the device as well, and it is bound to the identity if
+/// it had none or the user accepted a new one.
 fn add_to_ssh_config(
-    host: &str,
+    alias: &Alias,
     hostname: &str,
     user: &str,
+    port: u16,
     identity_file: &std::path::Path,
-) -> Result<bool> {
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| anyhow!("HOME/USERPROFILE not set"))?;
-    let ssh_dir = PathBuf::from(&home).join(".ssh");
-    let config_path = ssh_dir.join("config");
-
-    // Create .ssh directory if it doesn't exist
-    if !ssh_dir.exists() {
-        std::fs::create_dir_all(&ssh_dir)?;
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            std::fs::set_permissions(&ssh_dir, std::fs::Permissions::from_mode(0o700))?;
-        }
-    }
-
-    // Check if host already exists in config
-    if config_path.exists() {
-        let content = std::fs::read_to_string(&config_path)?;
-        let host_pattern = format!("Host {}", host);
-        if content
-            .lines()
-            .any(|line| line.trim() == host_pattern || line.trim() == format!("Host {}", host))
-        {
-            return Ok(false); // Already exists
-        }
-    }
-
-    // Append to config
-    let entry = format!(
-        "\n# Added by connecto\nHost {}\n    HostName {}\n    User {}\n    IdentityFile {}\n",
-        host,
-        hostname,
-        user,
-        identity_file.display()
-    );
+    identity: Option<&str>,
+    options: &InstallOptions,
+) -> Result<ConfigEntry> {
+    let host = alias.name.as_str();
+    let ssh_config = SshConfig::new()?;
+    let entry = HostEntry {
+        host: host.to_string(),
+        hostname: hostname.to_string(),
+        user: user.to_string(),
+        port: Some(port),
+        identity_file: identity_file.display().to_string(),
+        identity: identity.map(str::to_string),
+        ..Default::default()
+    }
+    .with_tags(&options.tags, &options.templates);
+    if ssh_config.add_entry(&entry)? {
+        return Ok(ConfigEntry::Added);
+    }
 
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&config_path)?;
+    let Some(existing) = ssh_config.entries()?.into_iter().find(|e| e.host == host) else {
+        return Ok(ConfigEntry::Exists);
+    };
+    let mut merged = existing.tags.clone();
+    for tag in &options.tags {
+        if !merged.contains(tag) {
+            merged.push(tag.clone());
+        }
+    }
 
-    file.write_all(entry.as_bytes())?;
+    if alias.replace {
+        ssh_config.replace_entry(&entry.with_tags(&merged, &options.templates))?;
+        return Ok(ConfigEntry::Replaced);
+    }
 
-    // Set proper permissions on config file
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        std::fs::set_permissions(&config_path, std::fs::Permissions::from_mode(0o600))?;
+    // The SSH server may have moved to another port since
+    if existing.port.unwrap_or(SSH_PORT) != port {
+        ssh_config.replace_entry(&HostEntry {
+            port: Some(port),
+            ..existing.clone()
+        })?;
     }
 
-    Ok(true)
+    // Entries from before identities were recorded are bound on first use
+    let rebind = existing.identity.is_none() || options.accept_new_identity;
+    if let Some(identity) = identity.filter(|_| rebind) {
+        ssh_config.set_identity(host, identity, hostname)?;
+    }
+    if !options.tags.is_empty() {
+        ssh_config.set_tags(host, &merged, &options.templates)?;
+    }
+    Ok(ConfigEntry::Exists)
+}
+
+/// Whether `existing` is an entry for the device `entry` was made for
+///
+/// Entries bound to an identity are the device's when the identity is the
+/// same, or the user accepted a new one; others when the address is.
+fn is_same_device(existing: &HostEntry, entry: &HostEntry, accept_new_identity: bool) -> bool {
+    match (&existing.identity, &entry.identity) {
+        (Some(pinned), Some(identity)) => pinned == identity || accept_new_identity,
+        _ => existing.hostname == entry.hostname,
+    }
+}
+
+/// Ask whether to replace `existing` with an entry for the new device
+///
+/// Without a terminal to ask on, the entry is kept.
+fn confirm_replace(existing: &HostEntry) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    let confirmed = Confirm::with_theme(theme().as_ref())
+        .with_prompt(format!(
+            "'{}' in ~/.ssh/config is {}@{}. Replace it with the new device?",
+            existing.host, existing.user, existing.hostname
+        ))
+        .default(false)
+        .interact()?;
+    println!();
+    Ok(confirmed)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use connecto_core::DEFAULT_PORT;
+    use tempfile::TempDir;
 
     #[test]
-    fn test_sanitize_name() {
-        assert_eq!(sanitize_name("My Device"), "my_device");
-        assert_eq!(sanitize_name("test-host"), "test-host");
-        assert_eq!(sanitize_name("Host (123)"), "host__123_");
+    fn test_recent_key_must_agree() {
+        let temp_dir = TempDir::new().unwrap();
+        let attempts = PairingAttempts::with_dir(temp_dir.path().to_path_buf());
+        let first = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+        let second = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+
+        let desk = attempts.begin("10.0.0.5:8099").unwrap();
+        let laptop = attempts.begin("10.0.0.6:8099").unwrap();
+        let phone = attempts.begin("10.0.0.7:8099").unwrap();
+        assert!(recent_key(&[desk], KeyAlgorithm::Ed25519).is_none());
+
+        let desk = attempts.begin("10.0.0.5:8099").unwrap();
+        desk.remember_key(&first).unwrap();
+        laptop.remember_key(&first).unwrap();
+        let both = [desk, laptop];
+        let reused = recent_key(&both, KeyAlgorithm::Ed25519).unwrap();
+        assert_eq!(reused.public_key, first.public_key);
+
+        // A target that got a different key means none is reused
+        phone.remember_key(&second).unwrap();
+        let [desk, laptop] = both;
+        assert!(recent_key(&[desk, laptop, phone], KeyAlgorithm::Ed25519).is_none());
     }
 
     #[test]
     fn test_extract_ip_from_address() {
         assert_eq!(extract_ip_from_address("192.168.1.1:8099"), "192.168.1.1");
         assert_eq!(extract_ip_from_address("10.0.0.1"), "10.0.0.1");
+        assert_eq!(extract_ip_from_address("[fe80::1%en0]:8099"), "fe80::1%en0");
+    }
+
+    /// A cache no scan has written to
+    fn empty_cache(temp_dir: &TempDir) -> DeviceCache {
+        DeviceCache::with_path(
+            temp_dir.path().join("devices.json"),
+            crate::device_cache::DEFAULT_TTL,
+        )
     }
 
     #[test]
     fn test_resolve_target_with_port() {
-        let result = resolve_target("192.168.1.1:8080").unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let cache = empty_cache(&temp_dir);
+        let result = resolve_target("192.168.1.1:8080", DEFAULT_PORT, &cache).unwrap();
         assert_eq!(result, "192.168.1.1:8080");
     }
 
     #[test]
     fn test_resolve_target_without_port() {
-        let result = resolve_target("192.168.1.1").unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let cache = empty_cache(&temp_dir);
+        let result = resolve_target("192.168.1.1", DEFAULT_PORT, &cache).unwrap();
         assert_eq!(result, format!("192.168.1.1:{}", DEFAULT_PORT));
+
+        // A policy port replaces the default, but not an explicit one
+        let result = resolve_target("192.168.1.1", 9000, &cache).unwrap();
+        assert_eq!(result, "192.168.1.1:9000");
+        let result = resolve_target("192.168.1.1:8080", 9000, &cache).unwrap();
+        assert_eq!(result, "192.168.1.1:8080");
+
+        // Bare IPv6 addresses get brackets
+        let result = resolve_target("fe80::1%en0", DEFAULT_PORT, &cache).unwrap();
+        assert_eq!(result, format!("[fe80::1%en0]:{}", DEFAULT_PORT));
+        let result = resolve_target("[fe80::1%en0]:8080", DEFAULT_PORT, &cache).unwrap();
+        assert_eq!(result, "[fe80::1%en0]:8080");
+    }
+
+    #[test]
+    fn test_is_same_device() {
+        let entry = HostEntry {
+            host: "desk".to_string(),
+            hostname: "192.168.1.10".to_string(),
+            identity: Some("SHA256:aaa".to_string()),
+            ..Default::default()
+        };
+        let moved = HostEntry {
+            hostname: "192.168.1.20".to_string(),
+            ..entry.clone()
+        };
+        // The identity follows the device to its new address
+        assert!(is_same_device(&entry, &moved, false));
+
+        let other = HostEntry {
+            identity: Some("SHA256:bbb".to_string()),
+            ..entry.clone()
+        };
+        assert!(!is_same_device(&entry, &other, false));
+        assert!(is_same_device(&entry, &other, true));
+
+        // Without identities, only the address tells
+        let legacy = HostEntry {
+            identity: None,
+            ..entry.clone()
+        };
+        assert!(is_same_device(&legacy, &entry, false));
+        assert!(!is_same_device(&legacy, &moved, false));
     }
 
     #[test]
     fn test_resolve_target_invalid_index() {
         // Should fail because there's no cache
-        let result = resolve_target("999");
+        let temp_dir = TempDir::new().unwrap();
+        let cache = empty_cache(&temp_dir);
+        let result = resolve_target("999", DEFAULT_PORT, &cache);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_is_device_name() {
+        assert!(is_device_name("my-laptop"));
+        assert!(is_device_name("desk-pc.local"));
+        assert!(!is_device_name("3"));
+        assert!(!is_device_name("192.168.1.1"));
+        assert!(!is_device_name("fe80::1%en0"));
+        assert!(!is_device_name("my-laptop:9000"));
+    }
+
+    #[test]
+    fn test_resolve_target_unreachable() {
+        let temp_dir = TempDir::new().unwrap();
+        let cache = empty_cache(&temp_dir);
+        let device = |ip: [u8; 4]| connecto_core::discovery::DiscoveredDevice {
+            name: "Desk._connecto._tcp.local.".to_string(),
+            hostname: "desk.local.".to_string(),
+            addresses: vec![IpAddr::from(ip)],
+            port: DEFAULT_PORT,
+            instance_name: "Desk._connecto._tcp.local.".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        };
+        cache
+            .save(&[device([10, 0, 0, 5]), device([10, 0, 0, 6])])
+            .unwrap();
+        let first = format!("10.0.0.5:{}", DEFAULT_PORT);
+        assert_eq!(resolve_target("0", DEFAULT_PORT, &cache).unwrap(), first);
+
+        // A failed pairing marks the address, and its number is refused
+        assert!(cache.invalidate(&first).unwrap());
+        let error = resolve_target("0", DEFAULT_PORT, &cache).unwrap_err();
+        assert!(error.to_string().contains("could not be reached"));
+        assert_eq!(
+            resolve_target("1", DEFAULT_PORT, &cache).unwrap(),
+            format!("10.0.0.6:{}", DEFAULT_PORT)
+        );
+        assert_eq!(
+            all_cached_addresses(&cache).unwrap(),
+            [format!("10.0.0.6:{}", DEFAULT_PORT)]
+        );
+
+        // Unless its router forwards a port to it
+        let mut forwarded = device([10, 0, 0, 7]);
+        forwarded.external = Some("203.0.113.5:8100".parse().unwrap());
+        cache.save(&[forwarded]).unwrap();
+        assert!(cache
+            .invalidate(&format!("10.0.0.7:{}", DEFAULT_PORT))
+            .unwrap());
+        assert_eq!(
+            resolve_target("0", DEFAULT_PORT, &cache).unwrap(),
+            "203.0.113.5:8100"
+        );
+    }
 }
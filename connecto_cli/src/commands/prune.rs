@@ -0,0 +1,118 @@
+//! Prune command - Remove expired keys
+
+use anyhow::Result;
+use colored::Colorize;
+use connecto_core::{
+    clock::{self, format_utc, UTC_DISPLAY_FORMAT},
+    keys::{authorized_key_expiry, split_authorized_key, KeyManager, EXPIRY_MARKER},
+    pairings::{PairingRecord, PairingStore},
+    ssh_config::SshConfig,
+};
+use std::collections::BTreeSet;
+
+use super::{info, success, warn};
+use crate::output::mark;
+
+/// Remove expired keys from authorized_keys, then warn about local keys the
+/// paired hosts no longer accept
+pub fn run(dry_run: bool) -> Result<()> {
+    let now = clock::unix_now().max(0) as u64;
+    let key_manager = KeyManager::new()?;
+    let expired = if dry_run {
+        key_manager.expired_authorized_keys(now)?
+    } else {
+        key_manager.prune_expired_keys(now)?
+    };
+
+    if expired.is_empty() {
+        info("No expired keys in authorized_keys.");
+    } else {
+        for line in &expired {
+            println!("  {} {}", mark("•").dimmed(), describe(line));
+        }
+        if dry_run {
+            info(&format!(
+                "{} expired key(s) would be removed from authorized_keys.",
+                expired.len()
+            ));
+        } else {
+            success(&format!(
+                "Removed {} expired key(s) from authorized_keys.",
+                expired.len()
+            ));
+        }
+    }
+
+    for record in expired_local_keys(now)? {
+        let host = record.host.as_deref().unwrap_or(&record.peer_name);
+        warn(&format!(
+            "The key for '{}' expired on {}; pair again or run 'connecto unpair {}'",
+            host,
+            format_utc(record.expires_at.unwrap_or_default(), UTC_DISPLAY_FORMAT),
+            host
+        ));
+    }
+    Ok(())
+}
+
+/// A key line as its comment and when it expired
+pub fn describe(line: &str) -> String {
+    let comment: Vec<&str> = split_authorized_key(line)
+        .1
+        .split_whitespace()
+        .skip(2)
+        .filter(|t| !t.starts_with(EXPIRY_MARKER))
+        .collect();
+    let comment = if comment.is_empty() {
+        "no comment".to_string()
+    } else {
+        comment.join(" ")
+    };
+    match authorized_key_expiry(line) {
+        Some(expires_at) => format!(
+            "{} {}",
+            comment,
+            format!("(expired {})", format_utc(expires_at, UTC_DISPLAY_FORMAT)).dimmed()
+        ),
+        None => comment,
+    }
+}
+
+/// The latest pairing of each SSH config host, if its key expired by `now`
+fn expired_local_keys(now: u64) -> Result<Vec<PairingRecord>> {
+    let store = PairingStore::new()?;
+    let hosts: BTreeSet<String> = SshConfig::new()?
+        .entries()?
+        .into_iter()
+        .map(|e| e.host)
+        .collect();
+
+    let mut expired = Vec::new();
+    for host in &hosts {
+        if let Some(record) = store.latest_for_host(host)? {
+            if record.is_expired(now) {
+                expired.push(record);
+            }
+        }
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        colored::control::set_override(false);
+        assert_eq!(
+            describe("ssh-ed25519 AAAA me@laptop connecto-expires=1970-01-01T00:16:40Z"),
+            "me@laptop (expired 1970-01-01 00:16 UTC)"
+        );
+        assert_eq!(describe("ssh-ed25519 AAAA"), "no comment");
+        assert_eq!(
+            describe(r#"from="10.0.0.5" ssh-ed25519 AAAA me@laptop"#),
+            "me@laptop"
+        );
+    }
+}
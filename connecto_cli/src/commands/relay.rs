@@ -0,0 +1,79 @@
+//! Relay command - Run a rendezvous server for devices on different networks
+
+use anyhow::Result;
+use colored::Colorize;
+use connecto_core::relay::{RelayEvent, RelayServer};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::{info, port_error, success, warn};
+use crate::output::{banner, mark};
+
+pub async fn serve(port: u16, wait_secs: u64) -> Result<()> {
+    println!();
+    banner("CONNECTO RELAY", |s| s.on_bright_blue().white().bold());
+    println!();
+
+    let mut server = RelayServer::new().with_wait(Duration::from_secs(wait_secs));
+    let addr = server
+        .listen(port)
+        .await
+        .map_err(|e| port_error(e, "connecto relay serve"))?;
+    info(&format!("Port: {}", addr.port().to_string().cyan()));
+    info(&format!(
+        "Rooms close after {}s without a second device",
+        wait_secs
+    ));
+    println!();
+    println!(
+        "  {} Devices pair through this relay with {}",
+        mark("→").cyan(),
+        format!("connecto listen --relay <this-host>:{}", addr.port()).cyan()
+    );
+    println!("{}", "Press Ctrl+C to stop".dimmed());
+    println!();
+
+    let (event_tx, mut event_rx) = mpsc::channel(32);
+    let event_handler = tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                RelayEvent::Started { address } => {
+                    success(&format!("Relay started on {}", address));
+                }
+                RelayEvent::Waiting { room, address } => {
+                    info(&format!("{} waiting in room {}", address, room.yellow()));
+                }
+                RelayEvent::Matched {
+                    room,
+                    listener,
+                    client,
+                } => {
+                    success(&format!(
+                        "Room {}: relaying between {} and {}",
+                        room.yellow(),
+                        listener,
+                        client
+                    ));
+                }
+                RelayEvent::Closed { room } => {
+                    info(&format!("Room {} closed", room.yellow()));
+                }
+                RelayEvent::Refused { address, reason } => {
+                    warn(&format!("Turned away {}: {}", address, reason));
+                }
+            }
+        }
+    });
+
+    tokio::select! {
+        result = server.run(event_tx) => result?,
+        _ = tokio::signal::ctrl_c() => {
+            println!();
+            info("Shutting down...");
+        }
+    }
+
+    event_handler.abort();
+    success("Connecto relay stopped");
+    Ok(())
+}
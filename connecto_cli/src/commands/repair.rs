@@ -0,0 +1,233 @@
+//! Repair command - Find paired hosts that moved and point their SSH config entries at them
+
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use connecto_core::connectivity::{self, ProbeTarget};
+use connecto_core::discovery::{DiscoveredDevice, ServiceBrowser, SubnetScanner};
+use connecto_core::known_hosts::KnownHostsStore;
+use connecto_core::pairings::PairingStore;
+use connecto_core::protocol::HandshakeClient;
+use connecto_core::relocation::{self, PairedHost};
+use connecto_core::ssh_config::SshConfig;
+use connecto_core::ConnectoError;
+use std::time::Duration;
+
+use super::scan::follow_renames;
+use super::{error, info, success, warn};
+use crate::config::Config;
+use crate::output::{mark, Progress};
+
+/// How long each host's SSH server gets to answer, matching ssh's ConnectTimeout
+const PROBE_TIMEOUT_SECS: u64 = 5;
+
+/// How long to look for the hosts over mDNS before scanning subnets
+const REDISCOVER_TIMEOUT_SECS: u64 = 5;
+
+/// How long each subnet scan probe waits
+const SUBNET_PROBE_TIMEOUT_MS: u64 = 500;
+
+/// How long a found device has to prove it holds the host's identity
+const IDENTITY_PROOF_TIMEOUT_SECS: u64 = 5;
+
+pub async fn run(host: Option<String>) -> Result<()> {
+    let ssh_config = SshConfig::new()?;
+    let mut hosts = PairedHost::all(&ssh_config, &PairingStore::new()?)?;
+    if let Some(host) = &host {
+        hosts.retain(|paired| paired.host() == host);
+        if hosts.is_empty() {
+            return Err(anyhow!(
+                "Host '{}' is not a paired host in ~/.ssh/config",
+                host
+            ));
+        }
+    }
+    if hosts.is_empty() {
+        println!("{}", "No paired hosts.".dimmed());
+        println!(
+            "  {} Pair with a device first: connecto pair <device>",
+            mark("→").cyan()
+        );
+        return Ok(());
+    }
+
+    let total = hosts.len();
+    let stale = find_stale(hosts).await;
+    if stale.is_empty() {
+        success(&match host {
+            Some(host) => format!("'{}' answers at its address", host),
+            None => format!("All {} paired host(s) answer at their addresses", total),
+        });
+        return Ok(());
+    }
+    for paired in &stale {
+        info(&format!(
+            "'{}' does not answer at {}",
+            paired.host(),
+            paired.entry.hostname
+        ));
+    }
+    println!();
+
+    let devices = discover(&stale).await;
+    let known_hosts = KnownHostsStore::new()?;
+    let client = HandshakeClient::new(&Config::load().unwrap_or_default().device_name())
+        .with_timeout(Duration::from_secs(IDENTITY_PROOF_TIMEOUT_SECS));
+    let mut unrepaired = Vec::new();
+    for paired in &stale {
+        let found = paired.find_device_in(&devices);
+        match found.and_then(DiscoveredDevice::primary_host) {
+            None => {
+                error(&format!(
+                    "Could not find '{}' on the network. Is it running 'connecto listen'?",
+                    paired.host()
+                ));
+                unrepaired.push(paired.host());
+            }
+            Some(address) if address == paired.entry.hostname => {
+                warn(&format!(
+                    "'{}' is still at {}, but its SSH server does not answer on port {}",
+                    paired.host(),
+                    address,
+                    paired.port()
+                ));
+                unrepaired.push(paired.host());
+            }
+            Some(address) => {
+                // The device has to prove it is the one the entry is bound to
+                let proven = match (
+                    &paired.entry.identity,
+                    found.and_then(|d| d.connection_string()),
+                ) {
+                    (Some(_), Some(target)) => match client.verify_identity(&target).await {
+                        Ok(proven) => Some(proven),
+                        Err(e) => {
+                            error(&format!(
+                                "The device at {} could not prove it is '{}': {}",
+                                address,
+                                paired.host(),
+                                e
+                            ));
+                            unrepaired.push(paired.host());
+                            continue;
+                        }
+                    },
+                    _ => None,
+                };
+                if let Err(e) = relocation::relocate(
+                    &ssh_config,
+                    &known_hosts,
+                    paired,
+                    &address,
+                    proven.as_ref(),
+                ) {
+                    error(&e.to_string());
+                    unrepaired.push(paired.host());
+                    continue;
+                }
+                success(&format!(
+                    "'{}' moved to {}, updated ~/.ssh/config",
+                    paired.host().cyan(),
+                    address.cyan().bold()
+                ));
+            }
+        }
+    }
+    if let Err(e) = follow_renames(&devices, false) {
+        warn(&format!("Failed to record device names: {}", e));
+    }
+
+    if unrepaired.is_empty() {
+        return Ok(());
+    }
+    println!();
+    println!(
+        "  {} Check that the device is on and running 'connecto listen', or run: connecto test {}",
+        mark("→").cyan(),
+        unrepaired[0]
+    );
+    Err(ConnectoError::DeviceNotFound(unrepaired.join(", ")).into())
+}
+
+/// The hosts whose SSH server does not answer at the address in their entry
+///
+/// Hosts reached through a bastion are not on the local network, so they are
+/// reported but not looked for.
+async fn find_stale(hosts: Vec<PairedHost>) -> Vec<PairedHost> {
+    let spinner = Progress::spinner("cyan");
+    spinner.set_message(format!("Checking {} paired host(s)...", hosts.len()));
+    spinner.enable_steady_tick();
+
+    // Probed at the same time, so one host down doesn't hold up the rest
+    let checks: Vec<_> = hosts
+        .iter()
+        .map(|paired| {
+            let host = paired.host().to_string();
+            let direct = ProbeTarget::new(&paired.entry.hostname, paired.port());
+            tokio::spawn(async move {
+                // Follow the route ssh would take, bastions included
+                let target = ProbeTarget::resolve(&host).await.unwrap_or(direct);
+                let timeout = Duration::from_secs(PROBE_TIMEOUT_SECS);
+                let answered = connectivity::probe(&target, timeout).await.is_ok();
+                (target, answered)
+            })
+        })
+        .collect();
+
+    let mut stale = Vec::new();
+    for (paired, check) in hosts.into_iter().zip(checks) {
+        let Ok((target, answered)) = check.await else {
+            continue;
+        };
+        if answered {
+            continue;
+        }
+        if target.route.is_proxied() {
+            spinner.suspend(|| {
+                warn(&format!(
+                    "'{}' does not answer {}; check that the bastion is up",
+                    paired.host(),
+                    target.route.describe()
+                ))
+            });
+            continue;
+        }
+        stale.push(paired);
+    }
+    spinner.finish_and_clear();
+    stale
+}
+
+/// Look for the devices of `stale` over mDNS, scanning subnets for any not
+/// found that way
+async fn discover(stale: &[PairedHost]) -> Vec<DiscoveredDevice> {
+    let spinner = Progress::spinner("cyan");
+    spinner.set_message("Looking for the host(s) on the network...");
+    spinner.enable_steady_tick();
+
+    let mut devices = match ServiceBrowser::new() {
+        Ok(browser) => browser
+            .scan_for_duration(Duration::from_secs(REDISCOVER_TIMEOUT_SECS))
+            .await
+            .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+
+    if stale
+        .iter()
+        .any(|paired| paired.find_in(&devices).is_none())
+    {
+        spinner.set_message("Scanning subnets...");
+        let config = Config::load().unwrap_or_default();
+        let scanner = SubnetScanner::new(
+            config.default_port(),
+            Duration::from_millis(SUBNET_PROBE_TIMEOUT_MS),
+        );
+        devices.extend(scanner.scan().await);
+        if !config.subnets.is_empty() {
+            devices.extend(scanner.scan_subnets(&config.subnets).await);
+        }
+    }
+
+    spinner.finish_and_clear();
+    devices
+}
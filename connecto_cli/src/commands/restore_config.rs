@@ -0,0 +1,69 @@
+//! Restore-config command - Roll back the last change to the SSH files
+
+use anyhow::Result;
+use colored::Colorize;
+use connecto_core::{
+    backups,
+    clock::{format_utc, UTC_DISPLAY_FORMAT},
+    keys::KeyManager,
+    paths,
+};
+use std::path::PathBuf;
+
+use super::{info, success};
+use crate::output::mark;
+
+/// Put back the newest backup of `~/.ssh/config` or authorized_keys, or with
+/// `list` only show the backups
+pub fn run(list: bool) -> Result<()> {
+    let files = backed_up_files()?;
+
+    if list {
+        let mut all = Vec::new();
+        for file in &files {
+            all.extend(backups::backups_of(file)?);
+        }
+        all.sort_by_key(|backup| std::cmp::Reverse(backup.taken_at_ms));
+        if all.is_empty() {
+            info("No backups yet.");
+        }
+        for backup in &all {
+            println!(
+                "  {} {}  {}  {}",
+                mark("•").dimmed(),
+                format_utc(backup.taken_at_ms / 1000, UTC_DISPLAY_FORMAT),
+                backup.original.display(),
+                backup.path.display().to_string().dimmed()
+            );
+        }
+        return Ok(());
+    }
+
+    let Some(backup) = backups::latest(files.iter().map(PathBuf::as_path))? else {
+        info("No backups to restore; Connecto has not changed the SSH config or authorized_keys.");
+        return Ok(());
+    };
+    backup.restore()?;
+    success(&format!(
+        "Restored {} to how it was at {}.",
+        backup.original.display().to_string().cyan(),
+        format_utc(backup.taken_at_ms / 1000, UTC_DISPLAY_FORMAT)
+    ));
+
+    let left = backups::backups_of(&backup.original)?.len();
+    if left > 0 {
+        info(&format!(
+            "{} older backup(s) left; run {} again to go further back.",
+            left,
+            "connecto restore-config".cyan()
+        ));
+    }
+    Ok(())
+}
+
+/// The files Connecto backs up before changing them
+fn backed_up_files() -> Result<Vec<PathBuf>> {
+    let mut files = vec![paths::ssh_config_file()?];
+    files.extend(KeyManager::new()?.authorized_keys_paths());
+    Ok(files)
+}
@@ -0,0 +1,43 @@
+//! Revoke command - Have a paired host's listener forget our key
+
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use connecto_core::{
+    keys::SshKeyPair, net, paths, protocol::HandshakeClient, ssh_config::SshConfig,
+};
+
+use super::{info, success};
+use crate::config::Config;
+
+/// Ask the listener on `host` to remove our key from its authorized_keys
+///
+/// The listener checks that we hold the key before removing it, so this works
+/// even when SSH to the host no longer does.
+pub async fn run(host: &str, port: u16) -> Result<()> {
+    let entry = SshConfig::new()?
+        .entries()?
+        .into_iter()
+        .find(|entry| entry.host == host)
+        .ok_or_else(|| anyhow!("Host '{}' not found in SSH config", host))?;
+
+    let key_path = paths::expand_home(&entry.identity_file)?;
+    let key_pair = SshKeyPair::load_from_file(&key_path.to_string_lossy())
+        .map_err(|e| anyhow!("Cannot read the key for '{}': {}", host, e))?;
+    let address = net::join_host_port(&entry.hostname, port);
+
+    info(&format!(
+        "Asking {} to remove key {}...",
+        address.cyan(),
+        key_pair.fingerprint()?.dimmed()
+    ));
+    HandshakeClient::new(&Config::load()?.device_name())
+        .revoke(&address, &key_pair)
+        .await?;
+
+    success(&format!("{} no longer accepts your key.", host.cyan()));
+    info(&format!(
+        "Run {} to remove the host and key here too.",
+        format!("connecto unpair {}", host).cyan()
+    ));
+    Ok(())
+}
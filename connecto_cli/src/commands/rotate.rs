@@ -0,0 +1,103 @@
+//! Rotate command - Replace the keys of paired hosts
+
+use anyhow::Result;
+use colored::Colorize;
+use connecto_core::{
+    keys::KeyAlgorithm,
+    rotation::{KeyRotator, SshCommand},
+    ssh_config::SshConfig,
+};
+
+use super::{announce_algorithm, error, info, success, warn};
+use crate::config::Config;
+use crate::output::{arrow, mark};
+
+/// Rotate the key of `host`, or of every paired host without one
+pub fn run(host: Option<&str>, algorithm: KeyAlgorithm, shred: bool) -> Result<()> {
+    Config::load()?.check_algorithm(algorithm)?;
+
+    let ssh_config = SshConfig::new()?;
+    let entries = ssh_config.entries()?;
+    let hosts: Vec<String> = match host {
+        Some(host) => {
+            if !entries.iter().any(|e| e.host == host) {
+                return Err(anyhow::anyhow!("Host '{}' not found in SSH config", host));
+            }
+            vec![host.to_string()]
+        }
+        None => entries.into_iter().map(|e| e.host).collect(),
+    };
+    if hosts.is_empty() {
+        info("No paired hosts to rotate.");
+        return Ok(());
+    }
+
+    announce_algorithm(algorithm);
+    info(&format!("Rotating the keys of {} host(s)...", hosts.len()));
+    println!();
+
+    let rotations = KeyRotator::new(SshCommand, ssh_config)
+        .with_algorithm(algorithm)
+        .with_shred(shred)
+        .rotate(&hosts)?;
+
+    let mut rotated = 0;
+    let mut failed = 0;
+    for rotation in &rotations {
+        match &rotation.new_key {
+            Some(new_key) => println!(
+                "{} {} {}",
+                rotation.old_key.dimmed(),
+                arrow().cyan(),
+                new_key.display().to_string().cyan()
+            ),
+            None => println!("{}", rotation.old_key.dimmed()),
+        }
+        for host in &rotation.hosts {
+            match &host.result {
+                Ok(true) => {
+                    rotated += 1;
+                    println!("  {} {}", mark("✓").green().bold(), host.host.bold());
+                }
+                Ok(false) => {
+                    rotated += 1;
+                    println!(
+                        "  {} {} {}",
+                        mark("✓").green().bold(),
+                        host.host.bold(),
+                        "(old key still authorized there)".yellow()
+                    );
+                }
+                Err(reason) => {
+                    failed += 1;
+                    println!(
+                        "  {} {}: {}",
+                        mark("✗").red().bold(),
+                        host.host.bold(),
+                        reason.red()
+                    );
+                }
+            }
+        }
+        if rotation.old_key_deleted {
+            println!("  {} {}", mark("•").dimmed(), "Old key deleted".dimmed());
+        }
+        println!();
+    }
+
+    if rotated > 0 {
+        success(&format!("Rotated the keys of {} host(s).", rotated));
+    }
+    if rotations
+        .iter()
+        .flat_map(|r| &r.hosts)
+        .any(|h| h.result == Ok(false))
+    {
+        warn("Remove old keys the hosts still accept from their authorized_keys by hand.");
+    }
+    if failed > 0 {
+        error("Hosts that failed keep working with their old key.");
+        return Err(anyhow::anyhow!("{} host(s) could not be rotated", failed));
+    }
+    Ok(())
+}
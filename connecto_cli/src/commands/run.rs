@@ -0,0 +1,242 @@
+//! Run command - Run a command on a paired host over SSH
+
+use anyhow::{anyhow, Context, Result};
+use colored::Colorize;
+use connecto_core::net;
+use connecto_core::pairings::{PairingDirection, PairingRecord, PairingStore};
+use connecto_core::ssh_config::{host_alias, HostEntry, SshConfig};
+use std::process::Command;
+
+use crate::output::mark;
+
+/// Exit status ssh uses for its own errors, as opposed to the command's
+const SSH_ERROR_STATUS: i32 = 255;
+
+/// How ssh reaches a paired host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// Host alias from ~/.ssh/config, or the address the host was paired at
+    pub destination: String,
+    pub port: Option<u16>,
+    /// Private key the host accepts
+    pub identity_file: Option<String>,
+}
+
+pub fn run(host: &str, command: &[String], timeout: u64) -> Result<()> {
+    let entries = SshConfig::new()
+        .and_then(|config| config.entries())
+        .unwrap_or_default();
+    let records = PairingStore::new()
+        .and_then(|store| store.all())
+        .unwrap_or_default();
+    let target = resolve(host, &entries, &records).ok_or_else(|| {
+        anyhow!(
+            "'{}' is not a paired host. See 'connecto hosts' for the hosts you can reach",
+            host
+        )
+    })?;
+
+    let status = Command::new("ssh")
+        .args(ssh_args(&target, timeout, command))
+        .status()
+        .context("Failed to run ssh")?;
+    let code = status.code().unwrap_or(1);
+    // The command itself may exit with 255 too, so this is only a hint
+    let paired_alias = entries.iter().any(|entry| entry.host == target.destination);
+    if code == SSH_ERROR_STATUS && paired_alias {
+        eprintln!(
+            "{} If {} could not be reached, 'connecto test {}' can find out why",
+            mark("→").cyan().bold(),
+            host,
+            target.destination
+        );
+    }
+    std::process::exit(code);
+}
+
+/// Find how to reach `host`, a host alias or the name of a paired device
+///
+/// Hosts in ~/.ssh/config are used through their alias, so their tags'
+/// options and any bastion apply. A device paired without one is reached
+/// at the address it was paired at, with the key sent to it.
+pub fn resolve(host: &str, entries: &[HostEntry], records: &[PairingRecord]) -> Option<Target> {
+    let alias = records
+        .iter()
+        .filter(|record| record.peer_name == host)
+        .max_by_key(|record| record.paired_at)
+        .and_then(|record| record.host.clone());
+    let entry = entries
+        .iter()
+        .find(|entry| entry.host == host)
+        .or_else(|| {
+            entries
+                .iter()
+                .find(|entry| Some(&entry.host) == alias.as_ref())
+        })
+        .or_else(|| entries.iter().find(|entry| entry.host == host_alias(host)));
+    if let Some(entry) = entry {
+        return Some(Target {
+            destination: entry.host.clone(),
+            port: None,
+            identity_file: Some(entry.identity_file.clone()).filter(|path| !path.is_empty()),
+        });
+    }
+
+    // Only keys this device sent can log in to the peer
+    records
+        .iter()
+        .filter(|record| record.direction != PairingDirection::Incoming)
+        .filter(|record| record.host.as_deref() == Some(host) || record.peer_name == host)
+        .max_by_key(|record| record.paired_at)
+        .map(|record| Target {
+            destination: net::host_of(&record.address).to_string(),
+            port: record.ssh_port,
+            identity_file: record.key_path.clone(),
+        })
+}
+
+/// Arguments for `ssh` to run `command` on `target`
+///
+/// Only the paired key is offered, so a full agent cannot exhaust the
+/// server's authentication attempts first, and ssh never stops to ask for
+/// a password.
+pub fn ssh_args(target: &Target, timeout: u64, command: &[String]) -> Vec<String> {
+    let mut args = vec![
+        "-o".to_string(),
+        "BatchMode=yes".to_string(),
+        "-o".to_string(),
+        format!("ConnectTimeout={}", timeout.max(1)),
+    ];
+    if let Some(identity_file) = &target.identity_file {
+        args.extend([
+            "-i".to_string(),
+            identity_file.clone(),
+            "-o".to_string(),
+            "IdentitiesOnly=yes".to_string(),
+        ]);
+    }
+    if let Some(port) = target.port {
+        args.extend(["-p".to_string(), port.to_string()]);
+    }
+    // Nothing after the destination is taken as an ssh option
+    args.push("--".to_string());
+    args.push(target.destination.clone());
+    args.extend(command.iter().cloned());
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connecto_core::keys::{KeyAlgorithm, SshKeyPair};
+
+    fn entry(host: &str) -> HostEntry {
+        HostEntry {
+            host: host.to_string(),
+            hostname: "192.168.1.10".to_string(),
+            user: "alice".to_string(),
+            identity_file: format!("~/.ssh/connecto_{}", host),
+            ..Default::default()
+        }
+    }
+
+    fn record(peer_name: &str, direction: PairingDirection) -> PairingRecord {
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+        PairingRecord::new(peer_name, &key.public_key, "192.168.1.20", direction)
+            .unwrap()
+            .with_ssh_port(2222)
+            .with_key_path("/home/alice/.ssh/connecto_desk")
+    }
+
+    #[test]
+    fn test_resolve_host_alias() {
+        let entries = vec![entry("desk"), entry("my_mac")];
+        let target = resolve("my_mac", &entries, &[]).unwrap();
+        assert_eq!(target.destination, "my_mac");
+        assert_eq!(target.port, None);
+        assert_eq!(
+            target.identity_file.as_deref(),
+            Some("~/.ssh/connecto_my_mac")
+        );
+
+        // Device names lead to their alias
+        assert_eq!(
+            resolve("My Mac", &entries, &[]).unwrap().destination,
+            "my_mac"
+        );
+        let records = vec![record("Office PC", PairingDirection::Outgoing).with_host("desk")];
+        assert_eq!(
+            resolve("Office PC", &entries, &records)
+                .unwrap()
+                .destination,
+            "desk"
+        );
+        assert!(resolve("laptop", &entries, &records).is_none());
+    }
+
+    #[test]
+    fn test_resolve_from_pairings() {
+        let records = vec![
+            record("Desk", PairingDirection::Incoming),
+            record("Laptop", PairingDirection::Outgoing),
+        ];
+        let target = resolve("Laptop", &[], &records).unwrap();
+        assert_eq!(target.destination, "192.168.1.20");
+        assert_eq!(target.port, Some(2222));
+        assert_eq!(
+            target.identity_file.as_deref(),
+            Some("/home/alice/.ssh/connecto_desk")
+        );
+
+        // Devices that only sent us their key cannot be logged in to
+        assert!(resolve("Desk", &[], &records).is_none());
+    }
+
+    #[test]
+    fn test_ssh_args() {
+        let target = Target {
+            destination: "192.168.1.20".to_string(),
+            port: Some(2222),
+            identity_file: Some("/home/alice/.ssh/connecto_desk".to_string()),
+        };
+        let command = vec!["ls".to_string(), "-la".to_string()];
+        assert_eq!(
+            ssh_args(&target, 10, &command),
+            vec![
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ConnectTimeout=10",
+                "-i",
+                "/home/alice/.ssh/connecto_desk",
+                "-o",
+                "IdentitiesOnly=yes",
+                "-p",
+                "2222",
+                "--",
+                "192.168.1.20",
+                "ls",
+                "-la",
+            ]
+        );
+
+        let target = Target {
+            destination: "desk".to_string(),
+            port: None,
+            identity_file: None,
+        };
+        assert_eq!(
+            ssh_args(&target, 0, &command),
+            vec![
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ConnectTimeout=1",
+                "--",
+                "desk",
+                "ls",
+                "-la"
+            ]
+        );
+    }
+}
@@ -1,18 +1,29 @@
 //! Scan command - Discover devices on the local network
 
 use anyhow::Result;
-use colored::Colorize;
-use connecto_core::discovery::{DiscoveredDevice, ServiceBrowser, SubnetScanner, DEFAULT_PORT};
+use colored::{ColoredString, Colorize};
+use connecto_core::discovery::{
+    merge_devices, DiscoveredDevice, EarlyExit, ScanProgress, ServiceBrowser, SubnetScanner,
+    DEFAULT_SCAN_CONCURRENCY,
+};
 #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
 use connecto_core::fallback::{AdHocNetwork, FallbackHandler};
-use indicatif::{ProgressBar, ProgressStyle};
-use std::fs;
-use std::io::Write;
+use connecto_core::identity::VerifiedIdentity;
+use connecto_core::pairings::PairingStore;
+use connecto_core::protocol::HandshakeClient;
+use connecto_core::renames;
+use connecto_core::retry::RetryPolicy;
+use connecto_core::ssh_config::{SshConfig, TagTemplates};
+use dialoguer::Confirm;
+use std::io::IsTerminal;
 #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
 use std::net::IpAddr;
 use std::time::Duration;
 
-use super::{info, success};
+use super::table::Table;
+use super::{info, success, warn};
+use crate::output::{banner, mark, theme, Progress};
+use serde::{Deserialize, Serialize};
 use std::process::Command as StdCommand;
 
 /// Check if the network appears to be isolated (router blocking device-to-device traffic)
@@ -53,101 +64,312 @@ async fn check_network_isolation() -> bool {
     gateway_responds && !other_device_responds
 }
 use crate::config::Config;
+use crate::device_cache::{format_age, DeviceCache};
 
-/// File to cache discovered devices for the pair command
-const CACHE_FILE: &str = "/tmp/connecto_devices.json";
+/// Columns that can be shown in scan output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanColumn {
+    /// Friendly device name
+    Name,
+    /// Primary IP address
+    Ip,
+    /// Connecto port
+    Port,
+    /// mDNS hostname
+    Hostname,
+    /// All known addresses
+    Addresses,
+    /// Address the device's router forwards to it (`listen --upnp`)
+    External,
+    /// Operating system the device announces
+    Os,
+    /// Connecto version the device announces
+    Version,
+    /// Round trip to devices found by a subnet scan
+    Latency,
+}
+
+impl ScanColumn {
+    /// Columns shown when nothing is configured
+    pub fn defaults() -> Vec<ScanColumn> {
+        vec![ScanColumn::Name, ScanColumn::Ip, ScanColumn::Port]
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            ScanColumn::Name => "NAME",
+            ScanColumn::Ip => "IP",
+            ScanColumn::Port => "PORT",
+            ScanColumn::Hostname => "HOSTNAME",
+            ScanColumn::Addresses => "ADDRESSES",
+            ScanColumn::External => "EXTERNAL",
+            ScanColumn::Os => "OS",
+            ScanColumn::Version => "VERSION",
+            ScanColumn::Latency => "LATENCY",
+        }
+    }
+
+    fn value(self, device: &DiscoveredDevice) -> String {
+        match self {
+            ScanColumn::Name => extract_friendly_name(&device.name),
+            ScanColumn::Ip => device.primary_host().unwrap_or_else(|| "-".to_string()),
+            ScanColumn::Port => device.port.to_string(),
+            // Private listeners keep their hostname out of the advertisement
+            ScanColumn::Hostname => match device.hostname.trim_end_matches('.') {
+                "" => "-".to_string(),
+                hostname => hostname.to_string(),
+            },
+            ScanColumn::Addresses => device
+                .addresses
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            ScanColumn::External => device
+                .external
+                .map_or_else(|| "-".to_string(), |external| external.to_string()),
+            // Private listeners and older versions announce neither
+            ScanColumn::Os => device.os.clone().unwrap_or_else(|| "-".to_string()),
+            ScanColumn::Version => device.version.clone().unwrap_or_else(|| "-".to_string()),
+            // Only subnet scans connect to the devices they find
+            ScanColumn::Latency => device.latency.map_or_else(
+                || "-".to_string(),
+                |latency| format!("{:.1}ms", latency.as_secs_f64() * 1000.0),
+            ),
+        }
+    }
+}
+
+/// Sort order for scan output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanSort {
+    /// Keep the order in which devices were discovered
+    #[default]
+    Discovery,
+    /// Sort by friendly name (case-insensitive)
+    Name,
+    /// Sort by primary IP address
+    Ip,
+    /// Sort by port
+    Port,
+}
+
+/// How scan results are presented
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanOutput {
+    pub columns: Vec<ScanColumn>,
+    pub sort: ScanSort,
+    /// Print only tab-separated rows (no banner, header, colors, or hints)
+    pub plain: bool,
+}
+
+impl Default for ScanOutput {
+    fn default() -> Self {
+        Self {
+            columns: ScanColumn::defaults(),
+            sort: ScanSort::default(),
+            plain: false,
+        }
+    }
+}
+
+impl ScanOutput {
+    /// Resolve output options: command-line flags take precedence over config
+    pub fn resolve(
+        columns: Vec<ScanColumn>,
+        sort: Option<ScanSort>,
+        plain: bool,
+        config: &Config,
+    ) -> Self {
+        let columns = if !columns.is_empty() {
+            columns
+        } else if !config.scan.columns.is_empty() {
+            config.scan.columns.clone()
+        } else {
+            ScanColumn::defaults()
+        };
+
+        Self {
+            columns,
+            sort: sort.or(config.scan.sort).unwrap_or_default(),
+            plain,
+        }
+    }
+}
+
+/// How subnet scans probe hosts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeOptions {
+    /// Hosts probed at the same time
+    pub concurrency: usize,
+    /// Most probes started per second, if limited
+    pub rate: Option<u32>,
+    /// How long to wait for each host
+    pub timeout: Duration,
+}
+
+/// Default time to wait for each host during a subnet scan
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_SCAN_CONCURRENCY,
+            rate: None,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        }
+    }
+}
+
+impl ProbeOptions {
+    /// Resolve probe options: command-line flags take precedence over config
+    pub fn resolve(
+        concurrency: Option<usize>,
+        rate: Option<u32>,
+        timeout_ms: Option<u64>,
+        config: &Config,
+    ) -> Self {
+        Self {
+            concurrency: concurrency
+                .or(config.scan.concurrency)
+                .unwrap_or(DEFAULT_SCAN_CONCURRENCY),
+            rate: rate.or(config.scan.rate),
+            timeout: timeout_ms
+                .or(config.scan.probe_timeout_ms)
+                .map_or(DEFAULT_PROBE_TIMEOUT, Duration::from_millis),
+        }
+    }
+
+    fn scanner(&self, port: u16) -> SubnetScanner {
+        let scanner = SubnetScanner::new(port, self.timeout).with_concurrency(self.concurrency);
+        match self.rate {
+            Some(rate) => scanner.with_rate_limit(rate),
+            None => scanner,
+        }
+    }
+}
+
+/// When the mDNS search may stop early: command-line flags take precedence
+/// over config
+pub fn early_exit(
+    min_devices: Option<usize>,
+    quiet_period_ms: Option<u64>,
+    config: &Config,
+) -> EarlyExit {
+    let mut early_exit = EarlyExit::default();
+    if let Some(count) = min_devices.or(config.scan.min_devices) {
+        early_exit = early_exit.with_min_devices(count);
+    }
+    if let Some(ms) = quiet_period_ms.or(config.scan.quiet_period_ms) {
+        early_exit = early_exit.with_quiet_period(Duration::from_millis(ms));
+    }
+    early_exit
+}
 
 #[allow(dead_code)]
 pub async fn run(timeout: u64) -> Result<()> {
-    run_with_options(timeout, false, vec![]).await
+    run_with_options(
+        timeout,
+        false,
+        vec![],
+        ScanOutput::default(),
+        ProbeOptions::default(),
+        EarlyExit::default(),
+    )
+    .await
 }
 
 #[allow(dead_code)]
 pub async fn run_with_fallback(timeout: u64, fallback: bool) -> Result<()> {
-    run_with_options(timeout, fallback, vec![]).await
+    run_with_options(
+        timeout,
+        fallback,
+        vec![],
+        ScanOutput::default(),
+        ProbeOptions::default(),
+        EarlyExit::default(),
+    )
+    .await
 }
 
 pub async fn run_with_options(
     timeout: u64,
     _fallback: bool,
     cli_subnets: Vec<String>,
+    output: ScanOutput,
+    probe: ProbeOptions,
+    early_exit: EarlyExit,
 ) -> Result<()> {
-    println!();
-    println!("{}", "  CONNECTO SCANNER  ".on_bright_cyan().white().bold());
-    println!();
+    if !output.plain {
+        println!();
+        banner("CONNECTO SCANNER", |s| s.on_bright_cyan().white().bold());
+        println!();
+    }
 
-    // Load saved subnets from config
+    // Load saved subnets and the machine policy's trusted subnets
     let config = Config::load().unwrap_or_default();
     let mut all_subnets = cli_subnets;
-    for subnet in config.subnets {
+    for subnet in config.scan_subnets() {
         if !all_subnets.contains(&subnet) {
             all_subnets.push(subnet);
         }
     }
 
-    info("Scanning for devices...");
-    println!();
+    if !output.plain {
+        info("Scanning for devices...");
+        println!();
+    }
 
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_style(
-        ProgressStyle::default_spinner()
-            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
-            .template("{spinner:.cyan} {msg}")
-            .unwrap(),
-    );
+    let spinner = Progress::spinner("cyan");
 
     // Try mDNS first
     spinner.set_message("Searching via mDNS...");
-    spinner.enable_steady_tick(Duration::from_millis(80));
+    spinner.enable_steady_tick();
 
     let browser = ServiceBrowser::new()?;
     let mut devices = browser
-        .scan_for_duration(Duration::from_secs(timeout))
+        .scan_until(Duration::from_secs(timeout), early_exit)
         .await?;
 
     spinner.finish_and_clear();
 
-    // If mDNS found nothing, try subnet scanning (local + configured subnets)
-    if devices.is_empty() {
-        let spinner = ProgressBar::new_spinner();
-        spinner.set_style(
-            ProgressStyle::default_spinner()
-                .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
-                .template("{spinner:.yellow} {msg}")
-                .unwrap(),
-        );
-        spinner.set_message("Scanning subnets...");
-        spinner.enable_steady_tick(Duration::from_millis(80));
-
-        let scanner = SubnetScanner::new(DEFAULT_PORT, Duration::from_millis(500));
+    // Probe the local subnets if mDNS found nothing, and the given and
+    // configured subnets in any case
+    let scan_local = devices.is_empty();
+    if scan_local || !all_subnets.is_empty() {
+        let bar = Progress::bar("yellow", "hosts");
+        bar.set_message("Scanning subnets...");
+        bar.enable_steady_tick();
 
-        // Scan local subnets
-        devices = scanner.scan().await;
+        let (progress_tx, progress_rx) = tokio::sync::mpsc::channel(256);
+        let progress = tokio::spawn(show_scan_progress(bar.clone(), progress_rx));
+        let scanner = probe
+            .scanner(config.default_port())
+            .with_progress(progress_tx);
 
-        // Also scan configured subnets
+        let mut probed = Vec::new();
+        if scan_local {
+            probed = scanner.scan().await;
+        }
         if !all_subnets.is_empty() {
-            let extra = scanner.scan_subnets(&all_subnets).await;
-            for device in extra {
-                if !devices.iter().any(|d| d.addresses == device.addresses) {
-                    devices.push(device);
-                }
-            }
+            probed.extend(scanner.scan_subnets(&all_subnets).await);
         }
 
-        spinner.finish_and_clear();
+        drop(scanner);
+        let _ = progress.await;
+        bar.finish_and_clear();
+
+        // Devices mDNS found as well are listed once, under their mDNS name
+        devices = merge_devices(devices.into_iter().chain(probed));
     };
 
     // If still no devices, try fallback: scan for ad-hoc networks
     if devices.is_empty() {
-        let spinner = ProgressBar::new_spinner();
-        spinner.set_style(
-            ProgressStyle::default_spinner()
-                .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
-                .template("{spinner:.magenta} {msg}")
-                .unwrap(),
-        );
+        let spinner = Progress::spinner("magenta");
         spinner.set_message("Scanning for Connecto ad-hoc networks...");
-        spinner.enable_steady_tick(Duration::from_millis(80));
+        spinner.enable_steady_tick();
 
         // Look for connecto ad-hoc networks
         #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
@@ -155,7 +377,7 @@ pub async fn run_with_options(
             if let Ok(adhoc_networks) = AdHocNetwork::scan_for_networks() {
                 spinner.finish_and_clear();
 
-                if !adhoc_networks.is_empty() {
+                if !adhoc_networks.is_empty() && !output.plain {
                     info(&format!(
                         "Found {} Connecto ad-hoc network(s)",
                         adhoc_networks.len()
@@ -179,7 +401,7 @@ pub async fn run_with_options(
                     );
                     println!(
                         "  {} connecto scan --join {}",
-                        "→".cyan(),
+                        mark("→").cyan(),
                         adhoc_networks.first().unwrap_or(&String::new())
                     );
                     println!();
@@ -199,8 +421,14 @@ pub async fn run_with_options(
                             addresses: vec![host_ip
                                 .parse::<IpAddr>()
                                 .unwrap_or(IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 73, 1)))],
-                            port: DEFAULT_PORT,
+                            port: config.default_port(),
                             instance_name: "adhoc._connecto._tcp.local.".to_string(),
+                            identity: None,
+                            scope: None,
+                            external: None,
+                            os: None,
+                            version: None,
+                            latency: None,
                         };
                         devices.push(device);
                     }
@@ -217,6 +445,10 @@ pub async fn run_with_options(
     }
 
     if devices.is_empty() {
+        if output.plain {
+            return Ok(());
+        }
+
         // Check if network might be isolated (can't reach other devices)
         let network_isolated = check_network_isolation().await;
 
@@ -225,8 +457,9 @@ pub async fn run_with_options(
 
         if network_isolated {
             println!(
-                "{}",
-                "⚠ Network isolation detected - your router is blocking device-to-device traffic."
+                "{} {}",
+                mark("⚠").yellow().bold(),
+                "Network isolation detected - your router is blocking device-to-device traffic."
                     .yellow()
                     .bold()
             );
@@ -240,20 +473,20 @@ pub async fn run_with_options(
             );
             println!(
                 "     {} Hold {} + click WiFi icon in menu bar",
-                "•".dimmed(),
+                mark("•").dimmed(),
                 "Option".cyan()
             );
             println!(
                 "     {} Click '{}'",
-                "•".dimmed(),
+                mark("•").dimmed(),
                 "Create Network...".cyan()
             );
             println!(
                 "     {} Name it: {} (or any name)",
-                "•".dimmed(),
+                mark("•").dimmed(),
                 "Connecto".cyan()
             );
-            println!("     {} Click {}", "•".dimmed(), "Create".cyan());
+            println!("     {} Click {}", mark("•").dimmed(), "Create".cyan());
             println!();
             println!(
                 "  {} On {} Mac (this one):",
@@ -261,12 +494,12 @@ pub async fn run_with_options(
                 "THIS".green().bold()
             );
             println!(
-                "     {} Click WiFi icon → join the network you just created",
-                "•".dimmed()
+                "     {} Click the WiFi icon and join the network you just created",
+                mark("•").dimmed()
             );
             println!(
                 "     {} Run '{}' again",
-                "•".dimmed(),
+                mark("•").dimmed(),
                 "connecto scan".cyan()
             );
             println!();
@@ -274,35 +507,77 @@ pub async fn run_with_options(
             println!("{}", "Make sure:".dimmed());
             println!(
                 "  {} The target device is running 'connecto listen'",
-                "•".dimmed()
+                mark("•").dimmed()
             );
             println!(
                 "  {} Your firewall allows connections on port 8099",
-                "•".dimmed()
+                mark("•").dimmed()
             );
             println!();
             println!(
                 "{}",
                 "If devices are on different subnets (e.g., VPN):".dimmed()
             );
-            println!("  {} connecto config add-subnet 10.x.x.0/24", "→".cyan());
+            println!(
+                "  {} connecto config add-subnet 10.x.x.0/24",
+                mark("→").cyan()
+            );
             println!();
         }
 
         println!("{}", "Or pair directly if you know the IP:".dimmed());
-        println!("  {} connecto pair <ip>:8099", "→".cyan());
+        println!("  {} connecto pair <ip>:8099", mark("→").cyan());
         println!();
         return Ok(());
     }
 
+    sort_devices(&mut devices, output.sort);
+
+    // Cache devices for pair command (after sorting so indices match the output)
+    DeviceCache::new(&config).save(&devices)?;
+
+    // Follow paired devices that came back at a different address
+    let updated = match SshConfig::new() {
+        Ok(ssh_config) => {
+            refresh_ssh_config(
+                &ssh_config,
+                &devices,
+                &config.ssh_templates,
+                &config.device_name(),
+            )
+            .await
+        }
+        Err(_) => Ok(Vec::new()),
+    }
+    .unwrap_or_default();
+
+    if output.plain {
+        device_table(&devices, &output.columns).print(true);
+        if let Err(e) = follow_renames(&devices, true) {
+            eprintln!("Failed to record device names: {}", e);
+        }
+        return Ok(());
+    }
+
     // Display found devices
     success(&format!("Found {} device(s):", devices.len()));
     println!();
 
-    display_devices(&devices);
+    device_table(&devices, &output.columns).print(false);
 
-    // Cache devices for pair command
-    cache_devices(&devices)?;
+    if !updated.is_empty() {
+        println!();
+        for (host, address) in &updated {
+            info(&format!(
+                "'{}' moved to {}, updated ~/.ssh/config",
+                host.cyan(),
+                address
+            ));
+        }
+    }
+    if let Err(e) = follow_renames(&devices, false) {
+        warn(&format!("Failed to record device names: {}", e));
+    }
 
     println!();
     println!(
@@ -326,28 +601,59 @@ pub async fn run_with_options(
     Ok(())
 }
 
-fn display_devices(devices: &[DiscoveredDevice]) {
-    for (i, device) in devices.iter().enumerate() {
-        let num = format!("[{}]", i).green().bold();
-        let name = extract_friendly_name(&device.name);
+/// Look for devices without printing them, for commands that need a scan
+///
+/// Subnets are probed only when mDNS finds nothing, as `connecto scan` does.
+/// The results are sorted as configured and cached, so their numbers are
+/// those `connecto scan --cached` shows.
+pub async fn discover(config: &Config, timeout: Duration) -> Result<Vec<DiscoveredDevice>> {
+    let mut devices = ServiceBrowser::new()?
+        .scan_until(timeout, early_exit(None, None, config))
+        .await?;
+    if devices.is_empty() {
+        let scanner =
+            ProbeOptions::resolve(None, None, None, config).scanner(config.default_port());
+        devices = scanner.scan().await;
+        devices.extend(scanner.scan_subnets(&config.scan_subnets()).await);
+        devices = merge_devices(devices);
+    }
+    sort_devices(&mut devices, config.scan.sort.unwrap_or_default());
+    DeviceCache::new(config).save(&devices)?;
+    Ok(devices)
+}
 
-        print!("{} {} ", num, name.cyan().bold());
+/// Order devices in place according to the requested sort
+fn sort_devices(devices: &mut [DiscoveredDevice], sort: ScanSort) {
+    match sort {
+        ScanSort::Discovery => {}
+        ScanSort::Name => devices.sort_by_key(|d| extract_friendly_name(&d.name).to_lowercase()),
+        ScanSort::Ip => devices.sort_by_key(|d| d.primary_address()),
+        ScanSort::Port => devices.sort_by_key(|d| d.port),
+    }
+}
 
-        if let Some(addr) = device.primary_address() {
-            print!("({}:{})", addr.to_string().yellow(), device.port);
-        }
+/// Build the scan output table; the first column is always the pair index
+fn device_table(devices: &[DiscoveredDevice], columns: &[ScanColumn]) -> Table {
+    let headers = std::iter::once("#").chain(columns.iter().map(|c| c.header()));
+    let mut table = Table::new(headers).style(0, |s| s.green().bold());
 
-        println!();
+    for (i, column) in columns.iter().enumerate() {
+        let style: fn(&str) -> ColoredString = match column {
+            ScanColumn::Name => |s| s.cyan().bold(),
+            ScanColumn::Ip => |s| s.yellow(),
+            _ => |s| s.normal(),
+        };
+        table = table.style(i + 1, style);
+    }
 
-        // Show additional addresses if any
-        if device.addresses.len() > 1 {
-            for addr in &device.addresses {
-                if Some(*addr) != device.primary_address() {
-                    println!("    {} {}", "└".dimmed(), addr.to_string().dimmed());
-                }
-            }
-        }
+    for (i, device) in devices.iter().enumerate() {
+        let row = std::iter::once(i.to_string())
+            .chain(columns.iter().map(|c| c.value(device)))
+            .collect();
+        table.push_row(row);
     }
+
+    table
 }
 
 /// Extract a friendly name from the full service name
@@ -360,23 +666,228 @@ fn extract_friendly_name(full_name: &str) -> String {
         .to_string()
 }
 
-fn cache_devices(devices: &[DiscoveredDevice]) -> Result<()> {
-    let json = serde_json::to_string(devices)?;
-    let mut file = fs::File::create(CACHE_FILE)?;
-    file.write_all(json.as_bytes())?;
+/// Draw subnet scan progress on `bar` until the scanner is dropped
+///
+/// Each subnet is scanned separately, so the bar restarts for every one while
+/// the found count keeps adding up.
+async fn show_scan_progress(
+    bar: Progress,
+    mut progress_rx: tokio::sync::mpsc::Receiver<ScanProgress>,
+) {
+    let mut found_before = 0;
+    let mut found = 0;
+    while let Some(progress) = progress_rx.recv().await {
+        if progress.scanned == 0 {
+            found_before += found;
+        }
+        found = progress.found;
+        bar.set_length(progress.total as u64);
+        bar.set_position(progress.scanned as u64);
+        bar.set_message(format!("Scanning subnets, {} found", found_before + found));
+    }
+}
+
+/// How long a device announcing a moved host's identity has to prove it
+const IDENTITY_PROOF_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Update the SSH config entries bound to the identity of a device that
+/// turned up at a different address
+///
+/// A device is only followed once it proves the identity by signing a
+/// challenge; one that merely announces it is left alone. Returns the host
+/// aliases that were changed along with their new address.
+async fn refresh_ssh_config(
+    config: &SshConfig,
+    devices: &[DiscoveredDevice],
+    templates: &TagTemplates,
+    device_name: &str,
+) -> connecto_core::Result<Vec<(String, String)>> {
+    let entries = config.entries()?;
+    let client = HandshakeClient::new(device_name)
+        .with_timeout(IDENTITY_PROOF_TIMEOUT)
+        .with_retry(RetryPolicy::none());
+    let mut moved = Vec::new();
+    for device in devices {
+        let (Some(identity), Some(host), Some(address)) = (
+            &device.identity,
+            device.primary_host(),
+            device.connection_string(),
+        ) else {
+            continue;
+        };
+        let elsewhere = entries
+            .iter()
+            .any(|entry| entry.identity.as_ref() == Some(identity) && entry.hostname != host);
+        if !elsewhere {
+            continue;
+        }
+        match client.verify_identity(&address).await {
+            Ok(proven) if proven.fingerprint() == identity => moved.push((proven, host)),
+            Ok(proven) => tracing::warn!(
+                "{} announced identity {} but proved {}; not following it",
+                address,
+                identity,
+                proven
+            ),
+            Err(e) => tracing::warn!(
+                "{} announced identity {} but did not prove it: {}",
+                address,
+                identity,
+                e
+            ),
+        }
+    }
+    update_addresses(config, &moved, templates)
+}
+
+/// Point the SSH config entries bound to each proven identity at its new
+/// address, returning the host aliases changed along with that address
+fn update_addresses(
+    config: &SshConfig,
+    moved: &[(VerifiedIdentity, String)],
+    templates: &TagTemplates,
+) -> connecto_core::Result<Vec<(String, String)>> {
+    let mut updated = Vec::new();
+    for (identity, address) in moved {
+        for host in config.update_address(identity.fingerprint(), address)? {
+            updated.push((host, address.clone()));
+        }
+    }
+    // Tagged hosts pick up template changes made by hand in the config file
+    config.apply_templates(templates)?;
+    Ok(updated)
+}
+
+/// Record the new names of paired devices among `devices`, offering to
+/// rename the SSH config aliases made from their old names
+///
+/// Aliases are only renamed once the user agrees, so they stay as they are
+/// when stdin is not a terminal. `quiet` records the names without a word.
+pub fn follow_renames(devices: &[DiscoveredDevice], quiet: bool) -> Result<()> {
+    let store = PairingStore::new()?;
+    let ssh_config = SshConfig::new()?;
+    let renames = renames::refresh(&store, &ssh_config, devices)?;
+    if quiet {
+        return Ok(());
+    }
+
+    for rename in renames {
+        println!();
+        info(&format!(
+            "'{}' is now called '{}'",
+            rename.old_name,
+            rename.new_name.cyan()
+        ));
+        for alias in &rename.aliases {
+            if !std::io::stdin().is_terminal() {
+                println!(
+                    "  {} Run {} in a terminal to rename host '{}' to '{}'",
+                    mark("→").dimmed(),
+                    "connecto scan".cyan(),
+                    alias.host,
+                    alias.new_host
+                );
+                continue;
+            }
+            let confirmed = Confirm::with_theme(theme().as_ref())
+                .with_prompt(format!(
+                    "Rename host '{}' to '{}' in ~/.ssh/config?",
+                    alias.host, alias.new_host
+                ))
+                .default(true)
+                .interact()?;
+            if !confirmed {
+                continue;
+            }
+            if renames::rename_alias(&store, &ssh_config, &alias.host, &alias.new_host)? {
+                success(&format!(
+                    "Renamed host '{}' to '{}'; connect with: ssh {}",
+                    alias.host, alias.new_host, alias.new_host
+                ));
+            } else {
+                warn(&format!(
+                    "Host '{}' is already taken; '{}' keeps its name",
+                    alias.new_host, alias.host
+                ));
+            }
+        }
+    }
     Ok(())
 }
 
-/// Load cached devices from the scan command
-pub fn load_cached_devices() -> Result<Vec<DiscoveredDevice>> {
-    let content = fs::read_to_string(CACHE_FILE)?;
-    let devices: Vec<DiscoveredDevice> = serde_json::from_str(&content)?;
-    Ok(devices)
+/// Print the devices of the last scan without scanning again
+///
+/// The numbers are those `connecto pair` takes, as long as the results have
+/// not expired.
+pub fn show_cached(columns: &[ScanColumn], plain: bool, cache: &DeviceCache) -> Result<()> {
+    let Some(scan) = cache.load()? else {
+        if !plain {
+            println!(
+                "{}",
+                "No cached devices. Run 'connecto scan' first.".yellow()
+            );
+        }
+        return Ok(());
+    };
+    if plain {
+        device_table(&scan.devices, columns).print(true);
+        return Ok(());
+    }
+
+    if scan.is_stale(cache.ttl()) {
+        warn(&format!(
+            "Found {} device(s) {}; these results have expired",
+            scan.devices.len(),
+            format_age(scan.age())
+        ));
+    } else {
+        success(&format!(
+            "Found {} device(s) {}:",
+            scan.devices.len(),
+            format_age(scan.age())
+        ));
+    }
+    println!();
+    device_table(&scan.devices, columns).print(false);
+
+    let unreachable: Vec<_> = scan
+        .devices
+        .iter()
+        .enumerate()
+        .filter(|(_, device)| scan.is_unreachable(device))
+        .collect();
+    if !unreachable.is_empty() {
+        println!();
+        for (i, device) in unreachable {
+            warn(&format!(
+                "{} ({}) could not be reached when pairing; it may have moved",
+                i,
+                extract_friendly_name(&device.name)
+            ));
+        }
+    }
+
+    println!();
+    let hint = if scan.is_stale(cache.ttl()) {
+        format!("Scan again to pair by number: {}", "connecto scan".cyan())
+    } else {
+        format!(
+            "To pair with a device, run: {}",
+            "connecto pair <number>".cyan()
+        )
+    };
+    println!("{}", hint.dimmed());
+    println!();
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use connecto_core::identity::DeviceIdentity;
+    use connecto_core::protocol::{Message, PROTOCOL_VERSION};
+    use std::fs;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 
     #[test]
     fn test_extract_friendly_name() {
@@ -387,8 +898,223 @@ mod tests {
         assert_eq!(extract_friendly_name(simple), "Test");
     }
 
+    fn device(name: &str, ip: [u8; 4], port: u16) -> DiscoveredDevice {
+        DiscoveredDevice {
+            name: format!("{}._connecto._tcp.local.", name),
+            hostname: format!("{}.local.", name.to_lowercase()),
+            addresses: vec![IpAddr::from(ip)],
+            port,
+            instance_name: format!("{}._connecto._tcp.local.", name),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        }
+    }
+
+    /// An SSH config with one entry bound to a fresh identity, which is
+    /// returned proven
+    fn desk_config(temp_dir: &tempfile::TempDir) -> (SshConfig, VerifiedIdentity) {
+        let identity =
+            DeviceIdentity::load_or_create_at(&temp_dir.path().join("identity")).unwrap();
+        let proven = identity.prove("nonce").unwrap().verify("nonce").unwrap();
+        let path = temp_dir.path().join("config");
+        fs::write(
+            &path,
+            format!(
+                "# Added by connecto\nHost desk\n    HostName 10.0.0.5\n    User me\n    # connecto-identity {}\n    # connecto-tags lab\n    IdentityFile ~/.ssh/connecto_desk\n",
+                proven
+            ),
+        )
+        .unwrap();
+        (SshConfig::with_path(path), proven)
+    }
+
+    #[test]
+    fn test_update_addresses() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let (config, proven) = desk_config(&temp_dir);
+        let mut templates = TagTemplates::new();
+        templates
+            .entry("lab".to_string())
+            .or_default()
+            .insert("ForwardAgent".to_string(), "yes".to_string());
+
+        let moved = vec![(proven, "10.0.0.9".to_string())];
+        let updated = update_addresses(&config, &moved, &templates).unwrap();
+        assert_eq!(updated, vec![("desk".to_string(), "10.0.0.9".to_string())]);
+        let desk = &config.entries().unwrap()[0];
+        assert_eq!(desk.hostname, "10.0.0.9");
+        // The tag's template was applied along the way
+        assert_eq!(
+            desk.options,
+            vec![("ForwardAgent".to_string(), "yes".to_string())]
+        );
+
+        // Nothing left to change on a second scan
+        assert!(update_addresses(&config, &moved, &templates)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_ssh_config_needs_proof() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let (config, proven) = desk_config(&temp_dir);
+
+        // A device repeating the identity without holding its key is not
+        // followed
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let announced = proven.to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.split();
+            let mut hello = String::new();
+            BufReader::new(reader).read_line(&mut hello).await.unwrap();
+            let ack = Message::HelloAck {
+                version: PROTOCOL_VERSION,
+                device_name: "Desk".to_string(),
+                verification_code: None,
+                identity: Some(announced),
+                pin_required: false,
+                timestamp: None,
+                capabilities: None,
+                users: Vec::new(),
+                identity_proof: None,
+                nonce: None,
+                key_share: None,
+            };
+            let line = ack.to_json().unwrap();
+            writer.write_all(line.as_bytes()).await.unwrap();
+        });
+        let mut impostor = device("Desk", [127, 0, 0, 1], port);
+        impostor.identity = Some(proven.to_string());
+
+        let updated = refresh_ssh_config(&config, &[impostor], &TagTemplates::new(), "me")
+            .await
+            .unwrap();
+        assert!(updated.is_empty());
+        assert_eq!(config.entries().unwrap()[0].hostname, "10.0.0.5");
+    }
+
     #[test]
-    fn test_cache_file_path() {
-        assert_eq!(CACHE_FILE, "/tmp/connecto_devices.json");
+    fn test_sort_devices() {
+        let mut devices = vec![
+            device("beta", [10, 0, 0, 2], 9000),
+            device("Alpha", [10, 0, 0, 10], 8099),
+            device("gamma", [10, 0, 0, 1], 22),
+        ];
+
+        sort_devices(&mut devices, ScanSort::Discovery);
+        assert!(devices[0].name.starts_with("beta"));
+
+        sort_devices(&mut devices, ScanSort::Name);
+        assert!(devices[0].name.starts_with("Alpha"));
+        assert!(devices[2].name.starts_with("gamma"));
+
+        sort_devices(&mut devices, ScanSort::Ip);
+        assert!(devices[0].name.starts_with("gamma"));
+        assert!(devices[2].name.starts_with("Alpha"));
+
+        sort_devices(&mut devices, ScanSort::Port);
+        assert_eq!(devices[0].port, 22);
+        assert_eq!(devices[2].port, 9000);
+    }
+
+    #[test]
+    fn test_device_table_plain() {
+        let devices = vec![device("Desk", [192, 168, 1, 5], 8099)];
+        let columns = [ScanColumn::Name, ScanColumn::Ip, ScanColumn::Hostname];
+        assert_eq!(
+            device_table(&devices, &columns).render(true),
+            "0\tDesk\t192.168.1.5\tdesk.local"
+        );
+
+        // What the device announced, or a dash for devices that do not say
+        let mut announced = device("Laptop", [192, 168, 1, 6], 8099);
+        announced.os = Some("linux".to_string());
+        announced.version = Some("0.4.0".to_string());
+        let devices = vec![announced, device("Desk", [192, 168, 1, 5], 8099)];
+        let columns = [ScanColumn::Name, ScanColumn::Os, ScanColumn::Version];
+        assert_eq!(
+            device_table(&devices, &columns).render(true),
+            "0\tLaptop\tlinux\t0.4.0\n1\tDesk\t-\t-"
+        );
+
+        // The round trip of devices a subnet scan connected to
+        let mut probed = device("Laptop", [192, 168, 1, 6], 8099);
+        probed.latency = Some(Duration::from_micros(2_350));
+        let devices = vec![probed, device("Desk", [192, 168, 1, 5], 8099)];
+        let columns = [ScanColumn::Name, ScanColumn::Latency];
+        assert_eq!(
+            device_table(&devices, &columns).render(true),
+            "0\tLaptop\t2.4ms\n1\tDesk\t-"
+        );
+    }
+
+    #[test]
+    fn test_scan_output_resolve() {
+        let mut config = Config::default();
+        let output = ScanOutput::resolve(vec![], None, false, &config);
+        assert_eq!(output.columns, ScanColumn::defaults());
+        assert_eq!(output.sort, ScanSort::Discovery);
+
+        config.scan.columns = vec![ScanColumn::Ip];
+        config.scan.sort = Some(ScanSort::Name);
+        let output = ScanOutput::resolve(vec![], None, true, &config);
+        assert_eq!(output.columns, vec![ScanColumn::Ip]);
+        assert_eq!(output.sort, ScanSort::Name);
+        assert!(output.plain);
+
+        let output =
+            ScanOutput::resolve(vec![ScanColumn::Port], Some(ScanSort::Port), false, &config);
+        assert_eq!(output.columns, vec![ScanColumn::Port]);
+        assert_eq!(output.sort, ScanSort::Port);
+    }
+
+    #[test]
+    fn test_probe_options_resolve() {
+        let mut config = Config::default();
+        assert_eq!(
+            ProbeOptions::resolve(None, None, None, &config),
+            ProbeOptions::default()
+        );
+
+        config.scan.concurrency = Some(500);
+        config.scan.rate = Some(2000);
+        config.scan.probe_timeout_ms = Some(300);
+        let probe = ProbeOptions::resolve(None, None, None, &config);
+        assert_eq!(probe.concurrency, 500);
+        assert_eq!(probe.rate, Some(2000));
+        assert_eq!(probe.timeout, Duration::from_millis(300));
+
+        let probe = ProbeOptions::resolve(Some(50), Some(100), Some(1000), &config);
+        assert_eq!(probe.concurrency, 50);
+        assert_eq!(probe.rate, Some(100));
+        assert_eq!(probe.timeout, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_early_exit_resolve() {
+        let mut config = Config::default();
+        assert_eq!(early_exit(None, None, &config), EarlyExit::default());
+
+        config.scan.min_devices = Some(3);
+        config.scan.quiet_period_ms = Some(1500);
+        assert_eq!(
+            early_exit(None, None, &config),
+            EarlyExit::default()
+                .with_min_devices(3)
+                .with_quiet_period(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            early_exit(Some(1), Some(500), &config),
+            EarlyExit::default()
+                .with_min_devices(1)
+                .with_quiet_period(Duration::from_millis(500))
+        );
     }
 }
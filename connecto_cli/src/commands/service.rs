@@ -0,0 +1,545 @@
+//! Service command - Keep a listener running in the background
+//!
+//! The listener is registered with the service manager of the platform: a
+//! launchd agent on macOS, a systemd user unit on Linux, and a scheduled task
+//! run at logon on Windows. A Windows service proper would run outside the
+//! user's session and install keys for the wrong account. Each runs
+//! `connecto listen --continuous` with the options given to `install`, and
+//! appends its output to a log file.
+
+use crate::{Cli, Commands, ServiceAction};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use colored::Colorize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::{info, success, warn};
+use crate::output::mark;
+
+/// Name of the launchd agent
+const LAUNCHD_LABEL: &str = "com.connecto.listener";
+
+/// Lines of the log `status` shows
+const LOG_TAIL_LINES: usize = 5;
+
+/// What the service runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceSpec {
+    /// The connecto executable
+    pub program: PathBuf,
+    /// Its arguments, starting with `listen`
+    pub args: Vec<String>,
+    /// File the listener's output is appended to
+    pub log: PathBuf,
+}
+
+pub fn run(action: ServiceAction) -> Result<()> {
+    match action {
+        ServiceAction::Install { options } => install(&options),
+        ServiceAction::Uninstall => uninstall(),
+        ServiceAction::Status => status(),
+    }
+}
+
+fn install(options: &[String]) -> Result<()> {
+    let spec = ServiceSpec {
+        program: std::env::current_exe().context("Could not find the connecto executable")?,
+        args: listen_args(options)?,
+        log: log_path()?,
+    };
+    let path = platform::definition_path()?;
+
+    // Installing again replaces the running listener
+    if path.exists() {
+        platform::unregister(&path)?;
+    }
+    for dir in [path.parent(), spec.log.parent()].into_iter().flatten() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    fs::write(&path, platform::definition(&spec))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    if let Err(e) = platform::register(&path) {
+        // A definition the service manager never took is not installed
+        let _ = fs::remove_file(&path);
+        return Err(e);
+    }
+
+    success(&format!(
+        "Listener service installed: runs {} at login",
+        command_line(&spec.args).cyan()
+    ));
+    info(&format!("Definition: {}", path.display()));
+    info(&format!("Log: {}", spec.log.display()));
+    Ok(())
+}
+
+fn uninstall() -> Result<()> {
+    let path = platform::definition_path()?;
+    if !path.exists() {
+        println!(
+            "{} The listener service is not installed.",
+            mark("→").yellow()
+        );
+        return Ok(());
+    }
+    platform::unregister(&path)?;
+    fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    success("Listener service uninstalled");
+    Ok(())
+}
+
+fn status() -> Result<()> {
+    let path = platform::definition_path()?;
+    if !path.exists() {
+        println!(
+            "{} The listener service is not installed.",
+            mark("→").yellow()
+        );
+        println!(
+            "  Install it with {}",
+            "connecto service install [LISTEN OPTIONS]".cyan()
+        );
+        return Ok(());
+    }
+
+    println!("{}", "Listener service:".bold());
+    println!();
+    let state = platform::state();
+    println!("  State:      {}", state.cyan());
+    println!("  Definition: {}", path.display());
+    let log = log_path()?;
+    println!("  Log:        {}", log.display());
+
+    if let Ok(content) = fs::read_to_string(&log) {
+        let lines: Vec<_> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+        if !lines.is_empty() {
+            println!();
+            for line in &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..] {
+                println!("  {}", line.dimmed());
+            }
+        }
+    }
+    println!();
+    if state != "running" {
+        warn("The listener is not running; see the log for why");
+    }
+    Ok(())
+}
+
+/// The arguments of `connecto listen --continuous` with `options`
+///
+/// Options that need someone to answer at a terminal are refused, since
+/// nobody watches a background listener.
+fn listen_args(options: &[String]) -> Result<Vec<String>> {
+    let argv = ["connecto", "listen"]
+        .into_iter()
+        .map(String::from)
+        .chain(options.iter().cloned());
+    let cli = Cli::try_parse_from(argv).map_err(|e| anyhow!("Invalid listen options: {}", e))?;
+    let Commands::Listen {
+        continuous,
+        approve,
+        relay,
+        ..
+    } = cli.command
+    else {
+        unreachable!("parsed as listen");
+    };
+    if approve {
+        bail!("--approve needs someone at a terminal; use --verify to check devices instead");
+    }
+    if relay.is_some() {
+        bail!("--relay waits for a single device and cannot run as a service");
+    }
+
+    let mut args = vec!["listen".to_string()];
+    if !continuous {
+        args.push("--continuous".to_string());
+    }
+    args.extend(options.iter().cloned());
+    Ok(args)
+}
+
+/// Where the listener service writes its output
+fn log_path() -> Result<PathBuf> {
+    Ok(connecto_core::paths::config_file("listener.log")?)
+}
+
+/// `args` as the user would type them after `connecto`
+fn command_line(args: &[String]) -> String {
+    let mut line = String::from("connecto");
+    for arg in args {
+        line.push(' ');
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "'\"\\$".contains(c)) {
+            line.push_str(&format!("'{}'", arg.replace('\'', r"'\''")));
+        } else {
+            line.push_str(arg);
+        }
+    }
+    line
+}
+
+/// Run a service manager command, failing with its error output
+#[cfg_attr(
+    not(any(target_os = "macos", target_os = "linux", target_os = "windows")),
+    allow(dead_code)
+)]
+fn run_tool(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{} {} failed: {}", program, args.join(" "), stderr.trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A launchd agent that starts at login and restarts after a crash
+#[cfg_attr(not(any(target_os = "macos", test)), allow(dead_code))]
+fn launchd_plist(spec: &ServiceSpec) -> String {
+    let arguments: String = std::iter::once(spec.program.to_string_lossy().into_owned())
+        .chain(spec.args.iter().cloned())
+        .map(|arg| format!("        <string>{}</string>\n", xml_escape(&arg)))
+        .collect();
+    let log = xml_escape(&spec.log.to_string_lossy());
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{LAUNCHD_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#
+    )
+}
+
+#[cfg_attr(not(any(target_os = "macos", test)), allow(dead_code))]
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// A systemd user unit that starts with the user's session and restarts
+/// after a crash
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn systemd_unit(spec: &ServiceSpec) -> String {
+    let exec: Vec<String> = std::iter::once(spec.program.to_string_lossy().into_owned())
+        .chain(spec.args.iter().cloned())
+        .map(|arg| systemd_quote(&arg))
+        .collect();
+    let log = spec.log.to_string_lossy().replace('%', "%%");
+    format!(
+        "[Unit]\n\
+         Description=Connecto pairing listener\n\
+         \n\
+         [Service]\n\
+         ExecStart={}\n\
+         Restart=on-failure\n\
+         RestartSec=10\n\
+         StandardOutput=append:{log}\n\
+         StandardError=append:{log}\n\
+         \n\
+         [Install]\n\
+         WantedBy=default.target\n",
+        exec.join(" ")
+    )
+}
+
+/// `arg` as one word of an `ExecStart=` line
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn systemd_quote(arg: &str) -> String {
+    // Specifiers and variables are expanded even inside quotes
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c)) {
+        return arg;
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A batch script that runs the listener with its output appended to the log
+#[cfg_attr(not(any(target_os = "windows", test)), allow(dead_code))]
+fn windows_script(spec: &ServiceSpec) -> String {
+    let command: Vec<String> = std::iter::once(spec.program.to_string_lossy().into_owned())
+        .chain(spec.args.iter().cloned())
+        .map(|arg| windows_quote(&arg))
+        .collect();
+    format!(
+        "@echo off\r\n{} >> {} 2>&1\r\n",
+        command.join(" "),
+        windows_quote(&spec.log.to_string_lossy())
+    )
+}
+
+/// `arg` as one argument of a batch script line
+#[cfg_attr(not(any(target_os = "windows", test)), allow(dead_code))]
+fn windows_quote(arg: &str) -> String {
+    // cmd expands variables even inside quotes
+    let arg = arg.replace('%', "%%");
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"&|<>^".contains(c)) {
+        return arg;
+    }
+    format!("\"{}\"", arg.replace('"', "\\\""))
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::*;
+
+    pub fn definition_path() -> Result<PathBuf> {
+        let base = directories::BaseDirs::new().context("Could not find home directory")?;
+        Ok(base
+            .home_dir()
+            .join("Library/LaunchAgents")
+            .join(format!("{}.plist", LAUNCHD_LABEL)))
+    }
+
+    pub fn definition(spec: &ServiceSpec) -> String {
+        launchd_plist(spec)
+    }
+
+    pub fn register(path: &Path) -> Result<()> {
+        run_tool("launchctl", &["load", "-w", &path.to_string_lossy()]).map(drop)
+    }
+
+    pub fn unregister(path: &Path) -> Result<()> {
+        // An agent that is not loaded has nothing to stop
+        let _ = run_tool("launchctl", &["unload", "-w", &path.to_string_lossy()]);
+        Ok(())
+    }
+
+    pub fn state() -> String {
+        match run_tool("launchctl", &["list", LAUNCHD_LABEL]) {
+            Ok(listing) if listing.contains("\"PID\"") => "running".to_string(),
+            Ok(_) => "stopped".to_string(),
+            Err(_) => "not loaded".to_string(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+
+    /// Name of the systemd user unit
+    const SYSTEMD_UNIT: &str = "connecto-listener.service";
+
+    pub fn definition_path() -> Result<PathBuf> {
+        let base = directories::BaseDirs::new().context("Could not find home directory")?;
+        Ok(base.config_dir().join("systemd/user").join(SYSTEMD_UNIT))
+    }
+
+    pub fn definition(spec: &ServiceSpec) -> String {
+        systemd_unit(spec)
+    }
+
+    pub fn register(_path: &Path) -> Result<()> {
+        run_tool("systemctl", &["--user", "daemon-reload"])?;
+        run_tool("systemctl", &["--user", "enable", "--now", SYSTEMD_UNIT]).map(drop)
+    }
+
+    pub fn unregister(_path: &Path) -> Result<()> {
+        // A unit that never started has nothing to stop
+        let _ = run_tool("systemctl", &["--user", "disable", "--now", SYSTEMD_UNIT]);
+        Ok(())
+    }
+
+    pub fn state() -> String {
+        // is-active fails for every state but active, yet still prints it
+        let output = Command::new("systemctl")
+            .args(["--user", "is-active", SYSTEMD_UNIT])
+            .output();
+        match output {
+            Ok(output) => match String::from_utf8_lossy(&output.stdout).trim() {
+                "active" => "running".to_string(),
+                "" => "unknown".to_string(),
+                other => other.to_string(),
+            },
+            Err(_) => "unknown".to_string(),
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+
+    /// Name of the scheduled task
+    const WINDOWS_TASK: &str = "Connecto Listener";
+
+    pub fn definition_path() -> Result<PathBuf> {
+        Ok(log_path()?.with_file_name("listener.cmd"))
+    }
+
+    pub fn definition(spec: &ServiceSpec) -> String {
+        windows_script(spec)
+    }
+
+    pub fn register(path: &Path) -> Result<()> {
+        // Run hidden through PowerShell, so no console window stays open
+        let action = format!(
+            "powershell.exe -NoProfile -WindowStyle Hidden -Command & '{}'",
+            path.to_string_lossy().replace('\'', "''")
+        );
+        run_tool(
+            "schtasks",
+            &[
+                "/Create",
+                "/F",
+                "/SC",
+                "ONLOGON",
+                "/RL",
+                "LIMITED",
+                "/TN",
+                WINDOWS_TASK,
+                "/TR",
+                &action,
+            ],
+        )?;
+        run_tool("schtasks", &["/Run", "/TN", WINDOWS_TASK]).map(drop)
+    }
+
+    pub fn unregister(_path: &Path) -> Result<()> {
+        // A task that is not running has nothing to end
+        let _ = run_tool("schtasks", &["/End", "/TN", WINDOWS_TASK]);
+        let _ = run_tool("schtasks", &["/Delete", "/F", "/TN", WINDOWS_TASK]);
+        Ok(())
+    }
+
+    pub fn state() -> String {
+        // CSV rows are "name","next run","status"
+        match run_tool(
+            "schtasks",
+            &["/Query", "/TN", WINDOWS_TASK, "/FO", "CSV", "/NH"],
+        ) {
+            Ok(row) => match row.trim().rsplit("\",\"").next() {
+                Some(status) if status.trim_matches('"') == "Running" => "running".to_string(),
+                Some(status) => status.trim_matches('"').to_lowercase(),
+                None => "unknown".to_string(),
+            },
+            Err(_) => "not registered".to_string(),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+mod platform {
+    use super::*;
+
+    pub fn definition_path() -> Result<PathBuf> {
+        bail!("Services are not supported on this platform; run `connecto listen --continuous` from your init system")
+    }
+
+    pub fn definition(_spec: &ServiceSpec) -> String {
+        String::new()
+    }
+
+    pub fn register(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn unregister(_path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn state() -> String {
+        "unknown".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> ServiceSpec {
+        ServiceSpec {
+            program: PathBuf::from("/opt/connecto/bin/connecto"),
+            args: listen_args(&["--name".to_string(), "Office Mac".to_string()]).unwrap(),
+            log: PathBuf::from("/home/me/.config/connecto/listener.log"),
+        }
+    }
+
+    #[test]
+    fn test_listen_args() {
+        assert_eq!(
+            listen_args(&["--port".to_string(), "9000".to_string()]).unwrap(),
+            ["listen", "--continuous", "--port", "9000"]
+        );
+        // Given again, --continuous is not repeated
+        assert_eq!(
+            listen_args(&["-c".to_string(), "--verify".to_string()]).unwrap(),
+            ["listen", "-c", "--verify"]
+        );
+
+        let error = listen_args(&["--approve".to_string()]).unwrap_err();
+        assert!(error.to_string().contains("--approve"));
+        assert!(listen_args(&["--relay".to_string(), "10.0.0.5".to_string()]).is_err());
+        assert!(listen_args(&["--no-such-option".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_command_line() {
+        assert_eq!(
+            command_line(&spec().args),
+            "connecto listen --continuous --name 'Office Mac'"
+        );
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let unit = systemd_unit(&spec());
+        assert!(unit.contains(
+            "ExecStart=/opt/connecto/bin/connecto listen --continuous --name \"Office Mac\"\n"
+        ));
+        assert!(unit.contains("StandardOutput=append:/home/me/.config/connecto/listener.log\n"));
+        assert!(unit.contains("WantedBy=default.target"));
+        assert_eq!(systemd_quote("100%"), "100%%");
+        assert_eq!(systemd_quote(r#"say "hi""#), r#""say \"hi\"""#);
+    }
+
+    #[test]
+    fn test_launchd_plist() {
+        let mut spec = spec();
+        spec.args.push("--allow-name".to_string());
+        spec.args.push("R&D <lab>".to_string());
+        let plist = launchd_plist(&spec);
+        assert!(plist.contains("<string>com.connecto.listener</string>"));
+        assert!(plist.contains("        <string>Office Mac</string>\n"));
+        assert!(plist.contains("<string>R&amp;D &lt;lab&gt;</string>"));
+        assert!(plist.contains(
+            "<key>StandardOutPath</key>\n    <string>/home/me/.config/connecto/listener.log</string>"
+        ));
+    }
+
+    #[test]
+    fn test_windows_script() {
+        let mut spec = spec();
+        spec.program = PathBuf::from(r"C:\Program Files\Connecto\connecto.exe");
+        spec.log = PathBuf::from(r"C:\Users\me\AppData\connecto\listener.log");
+        assert_eq!(
+            windows_script(&spec),
+            "@echo off\r\n\"C:\\Program Files\\Connecto\\connecto.exe\" listen --continuous --name \"Office Mac\" >> C:\\Users\\me\\AppData\\connecto\\listener.log 2>&1\r\n"
+        );
+        assert_eq!(windows_quote("50%"), "50%%");
+    }
+}
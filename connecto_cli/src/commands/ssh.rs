@@ -2,12 +2,14 @@
 //!
 //! Enables/disables SSH server on Windows, macOS, and Linux
 
-use anyhow::Result;
+use crate::output::{banner, mark};
+use anyhow::{anyhow, Result};
 use colored::Colorize;
+use connecto_core::ConnectoError;
 use std::process::Command;
 
 /// Check if running as root/administrator
-fn is_elevated() -> bool {
+pub(crate) fn is_elevated() -> bool {
     #[cfg(target_os = "windows")]
     {
         let output = Command::new("powershell")
@@ -27,6 +29,12 @@ fn is_elevated() -> bool {
     }
 }
 
+/// The error for running a command that needs root or Administrator without it
+fn not_elevated() -> anyhow::Error {
+    ConnectoError::PermissionDenied("managing the SSH server needs elevated privileges".into())
+        .into()
+}
+
 /// Get the current platform
 fn get_platform() -> &'static str {
     if cfg!(target_os = "windows") {
@@ -41,54 +49,42 @@ fn get_platform() -> &'static str {
 /// Enable SSH server
 pub async fn enable() -> Result<()> {
     println!();
-    println!(
-        "{}",
-        "  CONNECTO SSH SETUP  ".on_bright_blue().white().bold()
-    );
+    banner("CONNECTO SSH SETUP", |s| s.on_bright_blue().white().bold());
     println!();
 
     match get_platform() {
         "windows" => enable_windows().await,
         "macos" => enable_macos().await,
         "linux" => enable_linux().await,
-        _ => {
-            println!("{} Unsupported platform.", "✗".red());
-            Ok(())
-        }
+        platform => Err(anyhow!("Unsupported platform: {}", platform)),
     }
 }
 
 /// Disable SSH server
 pub async fn disable() -> Result<()> {
     println!();
-    println!("{}", "  CONNECTO SSH  ".on_bright_blue().white().bold());
+    banner("CONNECTO SSH", |s| s.on_bright_blue().white().bold());
     println!();
 
     match get_platform() {
         "windows" => disable_windows().await,
         "macos" => disable_macos().await,
         "linux" => disable_linux().await,
-        _ => {
-            println!("{} Unsupported platform.", "✗".red());
-            Ok(())
-        }
+        platform => Err(anyhow!("Unsupported platform: {}", platform)),
     }
 }
 
 /// Show SSH server status
 pub async fn status() -> Result<()> {
     println!();
-    println!("{}", "  SSH STATUS  ".on_bright_blue().white().bold());
+    banner("SSH STATUS", |s| s.on_bright_blue().white().bold());
     println!();
 
     match get_platform() {
         "windows" => status_windows().await,
         "macos" => status_macos().await,
         "linux" => status_linux().await,
-        _ => {
-            println!("{} Unsupported platform.", "✗".red());
-            Ok(())
-        }
+        platform => Err(anyhow!("Unsupported platform: {}", platform)),
     }
 }
 
@@ -100,19 +96,22 @@ async fn enable_windows() -> Result<()> {
     if !is_elevated() {
         println!(
             "{} This command requires Administrator privileges.",
-            "✗".red()
+            mark("✗").red()
         );
         println!();
         println!("Please run PowerShell as Administrator and try again:");
         println!("  {}", "connecto ssh on".cyan());
-        return Ok(());
+        return Err(not_elevated());
     }
 
-    println!("{} Enabling OpenSSH Server...", "→".cyan());
+    println!("{} Enabling OpenSSH Server...", mark("→").cyan());
     println!();
 
     // Check if OpenSSH Server is installed
-    println!("{} Checking OpenSSH Server installation...", "→".cyan());
+    println!(
+        "{} Checking OpenSSH Server installation...",
+        mark("→").cyan()
+    );
 
     // First check if sshd service already exists (works on all Windows versions)
     let service_check = Command::new("powershell")
@@ -127,7 +126,7 @@ async fn enable_windows() -> Result<()> {
         .is_empty();
 
     if sshd_exists {
-        println!("{} OpenSSH Server already installed.", "✓".green());
+        println!("{} OpenSSH Server already installed.", mark("✓").green());
     } else {
         // Try Windows 10/Server 2016+ method first (Add-WindowsCapability)
         let capability_check = Command::new("powershell")
@@ -143,7 +142,7 @@ async fn enable_windows() -> Result<()> {
                 .is_empty()
         {
             // Modern Windows - use Add-WindowsCapability
-            println!("{} Installing OpenSSH Server...", "→".cyan());
+            println!("{} Installing OpenSSH Server...", mark("→").cyan());
 
             let install_output = Command::new("powershell")
                 .args([
@@ -154,17 +153,17 @@ async fn enable_windows() -> Result<()> {
 
             if !install_output.status.success() {
                 let stderr = String::from_utf8_lossy(&install_output.stderr);
-                println!("{} Failed to install OpenSSH Server.", "✗".red());
+                println!("{} Failed to install OpenSSH Server.", mark("✗").red());
                 if !stderr.is_empty() {
                     println!("{}", stderr.dimmed());
                 }
-                return Ok(());
+                return Err(anyhow!("Failed to install OpenSSH Server"));
             }
 
-            println!("{} OpenSSH Server installed.", "✓".green());
+            println!("{} OpenSSH Server installed.", mark("✓").green());
         } else {
             // Older Windows (Server 2012 R2, etc.) - OpenSSH must be installed manually
-            println!("{} OpenSSH Server is not installed.", "✗".red());
+            println!("{} OpenSSH Server is not installed.", mark("✗").red());
             println!();
             println!("Your Windows version requires manual OpenSSH installation:");
             println!();
@@ -180,12 +179,12 @@ async fn enable_windows() -> Result<()> {
             println!("     {}", "powershell -ExecutionPolicy Bypass -File \"C:\\Program Files\\OpenSSH\\install-sshd.ps1\"".dimmed());
             println!();
             println!("  4. Then run {} again.", "connecto ssh on".cyan());
-            return Ok(());
+            return Err(anyhow!("OpenSSH Server is not installed"));
         }
     }
 
     // Start the sshd service
-    println!("{} Starting SSH service...", "→".cyan());
+    println!("{} Starting SSH service...", mark("→").cyan());
 
     let start_output = Command::new("powershell")
         .args(["-Command", "Start-Service sshd"])
@@ -194,18 +193,18 @@ async fn enable_windows() -> Result<()> {
     if !start_output.status.success() {
         let stderr = String::from_utf8_lossy(&start_output.stderr);
         if !stderr.contains("already") {
-            println!("{} Failed to start SSH service.", "✗".red());
+            println!("{} Failed to start SSH service.", mark("✗").red());
             if !stderr.is_empty() {
                 println!("{}", stderr.dimmed());
             }
-            return Ok(());
+            return Err(anyhow!("Failed to start SSH service"));
         }
     }
 
-    println!("{} SSH service started.", "✓".green());
+    println!("{} SSH service started.", mark("✓").green());
 
     // Set to automatic startup
-    println!("{} Configuring automatic startup...", "→".cyan());
+    println!("{} Configuring automatic startup...", mark("→").cyan());
 
     let auto_output = Command::new("powershell")
         .args([
@@ -215,13 +214,19 @@ async fn enable_windows() -> Result<()> {
         .output()?;
 
     if !auto_output.status.success() {
-        println!("{} Warning: Could not set automatic startup.", "⚠".yellow());
+        println!(
+            "{} Warning: Could not set automatic startup.",
+            mark("⚠").yellow()
+        );
     } else {
-        println!("{} SSH will start automatically on boot.", "✓".green());
+        println!(
+            "{} SSH will start automatically on boot.",
+            mark("✓").green()
+        );
     }
 
     // Configure firewall rule
-    println!("{} Configuring firewall...", "→".cyan());
+    println!("{} Configuring firewall...", mark("→").cyan());
 
     let firewall_output = Command::new("powershell")
         .args([
@@ -240,9 +245,15 @@ async fn enable_windows() -> Result<()> {
         .output()?;
 
     if firewall_output.status.success() {
-        println!("{} Firewall configured for SSH (port 22).", "✓".green());
+        println!(
+            "{} Firewall configured for SSH (port 22).",
+            mark("✓").green()
+        );
     } else {
-        println!("{} Warning: Could not configure firewall.", "⚠".yellow());
+        println!(
+            "{} Warning: Could not configure firewall.",
+            mark("⚠").yellow()
+        );
     }
 
     print_success_message();
@@ -253,15 +264,15 @@ async fn disable_windows() -> Result<()> {
     if !is_elevated() {
         println!(
             "{} This command requires Administrator privileges.",
-            "✗".red()
+            mark("✗").red()
         );
         println!();
         println!("Please run PowerShell as Administrator and try again:");
         println!("  {}", "connecto ssh off".cyan());
-        return Ok(());
+        return Err(not_elevated());
     }
 
-    println!("{} Disabling OpenSSH Server...", "→".cyan());
+    println!("{} Disabling OpenSSH Server...", mark("→").cyan());
 
     // Stop the service
     let stop_output = Command::new("powershell")
@@ -272,7 +283,7 @@ async fn disable_windows() -> Result<()> {
         .output()?;
 
     if stop_output.status.success() {
-        println!("{} SSH service stopped.", "✓".green());
+        println!("{} SSH service stopped.", mark("✓").green());
     }
 
     // Disable automatic startup
@@ -284,7 +295,7 @@ async fn disable_windows() -> Result<()> {
         .output()?;
 
     if disable_output.status.success() {
-        println!("{} SSH automatic startup disabled.", "✓".green());
+        println!("{} SSH automatic startup disabled.", mark("✓").green());
     }
 
     println!();
@@ -309,7 +320,7 @@ async fn status_windows() -> Result<()> {
     if status.is_empty() {
         println!(
             "{} OpenSSH Server is {}",
-            "•".red(),
+            mark("•").red(),
             "not installed".red().bold()
         );
         println!();
@@ -319,17 +330,21 @@ async fn status_windows() -> Result<()> {
 
     match status.as_str() {
         "Running" => {
-            println!("{} SSH server is {}", "•".green(), "running".green().bold());
+            println!(
+                "{} SSH server is {}",
+                mark("•").green(),
+                "running".green().bold()
+            );
         }
         "Stopped" => {
             println!(
                 "{} SSH server is {}",
-                "•".yellow(),
+                mark("•").yellow(),
                 "stopped".yellow().bold()
             );
         }
         _ => {
-            println!("{} SSH server status: {}", "•".dimmed(), status);
+            println!("{} SSH server status: {}", mark("•").dimmed(), status);
         }
     }
 
@@ -347,17 +362,17 @@ async fn status_windows() -> Result<()> {
 
     match startup.as_str() {
         "Automatic" => {
-            println!("{} Starts automatically on boot", "•".green());
+            println!("{} Starts automatically on boot", mark("•").green());
         }
         "Disabled" => {
             println!(
                 "{} Automatic startup is {}",
-                "•".yellow(),
+                mark("•").yellow(),
                 "disabled".yellow()
             );
         }
         _ => {
-            println!("{} Startup type: {}", "•".dimmed(), startup);
+            println!("{} Startup type: {}", mark("•").dimmed(), startup);
         }
     }
 
@@ -371,9 +386,9 @@ async fn status_windows() -> Result<()> {
         .to_string();
 
     if firewall == "True" {
-        println!("{} Firewall allows SSH (port 22)", "•".green());
+        println!("{} Firewall allows SSH (port 22)", mark("•").green());
     } else {
-        println!("{} Firewall rule not configured", "•".yellow());
+        println!("{} Firewall rule not configured", mark("•").yellow());
     }
 
     println!();
@@ -386,14 +401,14 @@ async fn status_windows() -> Result<()> {
 
 async fn enable_macos() -> Result<()> {
     if !is_elevated() {
-        println!("{} This command requires root privileges.", "✗".red());
+        println!("{} This command requires root privileges.", mark("✗").red());
         println!();
         println!("Please run with sudo:");
         println!("  {}", "sudo connecto ssh on".cyan());
-        return Ok(());
+        return Err(not_elevated());
     }
 
-    println!("{} Enabling Remote Login (SSH)...", "→".cyan());
+    println!("{} Enabling Remote Login (SSH)...", mark("→").cyan());
     println!();
 
     // Enable Remote Login using systemsetup
@@ -402,14 +417,17 @@ async fn enable_macos() -> Result<()> {
         .output()?;
 
     if output.status.success() {
-        println!("{} Remote Login (SSH) enabled.", "✓".green());
+        println!("{} Remote Login (SSH) enabled.", mark("✓").green());
     } else {
         let stderr = String::from_utf8_lossy(&output.stderr);
         // Check if it's already enabled
         if stderr.contains("already") || stderr.contains("Remote Login") {
-            println!("{} Remote Login (SSH) is already enabled.", "✓".green());
+            println!(
+                "{} Remote Login (SSH) is already enabled.",
+                mark("✓").green()
+            );
         } else {
-            println!("{} Failed to enable Remote Login.", "✗".red());
+            println!("{} Failed to enable Remote Login.", mark("✗").red());
             if !stderr.is_empty() {
                 println!("{}", stderr.dimmed());
             }
@@ -421,7 +439,7 @@ async fn enable_macos() -> Result<()> {
                 "Sharing".cyan(),
                 "Remote Login".cyan()
             );
-            return Ok(());
+            return Err(anyhow!("Failed to enable Remote Login"));
         }
     }
 
@@ -431,27 +449,28 @@ async fn enable_macos() -> Result<()> {
 
 async fn disable_macos() -> Result<()> {
     if !is_elevated() {
-        println!("{} This command requires root privileges.", "✗".red());
+        println!("{} This command requires root privileges.", mark("✗").red());
         println!();
         println!("Please run with sudo:");
         println!("  {}", "sudo connecto ssh off".cyan());
-        return Ok(());
+        return Err(not_elevated());
     }
 
-    println!("{} Disabling Remote Login (SSH)...", "→".cyan());
+    println!("{} Disabling Remote Login (SSH)...", mark("→").cyan());
 
     let output = Command::new("systemsetup")
         .args(["-setremotelogin", "off"])
         .output()?;
 
     if output.status.success() {
-        println!("{} Remote Login (SSH) disabled.", "✓".green());
+        println!("{} Remote Login (SSH) disabled.", mark("✓").green());
     } else {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        println!("{} Failed to disable Remote Login.", "✗".red());
+        println!("{} Failed to disable Remote Login.", mark("✗").red());
         if !stderr.is_empty() {
             println!("{}", stderr.dimmed());
         }
+        return Err(anyhow!("Failed to disable Remote Login"));
     }
 
     println!();
@@ -468,11 +487,15 @@ async fn status_macos() -> Result<()> {
     let sshd_running = pgrep_output.map(|o| o.status.success()).unwrap_or(false);
 
     if sshd_running {
-        println!("{} SSH server is {}", "•".green(), "running".green().bold());
+        println!(
+            "{} SSH server is {}",
+            mark("•").green(),
+            "running".green().bold()
+        );
     } else {
         println!(
             "{} SSH server is {}",
-            "•".yellow(),
+            mark("•").yellow(),
             "not running".yellow().bold()
         );
         println!();
@@ -494,7 +517,7 @@ async fn status_macos() -> Result<()> {
 
     if let Ok(out) = lsof_output {
         if out.status.success() && !out.stdout.is_empty() {
-            println!("{} Listening on port {}", "•".green(), "22".cyan());
+            println!("{} Listening on port {}", mark("•").green(), "22".cyan());
         }
     }
 
@@ -508,14 +531,14 @@ async fn status_macos() -> Result<()> {
 
 async fn enable_linux() -> Result<()> {
     if !is_elevated() {
-        println!("{} This command requires root privileges.", "✗".red());
+        println!("{} This command requires root privileges.", mark("✗").red());
         println!();
         println!("Please run with sudo:");
         println!("  {}", "sudo connecto ssh on".cyan());
-        return Ok(());
+        return Err(not_elevated());
     }
 
-    println!("{} Enabling SSH server...", "→".cyan());
+    println!("{} Enabling SSH server...", mark("→").cyan());
     println!();
 
     // Check if openssh-server is installed
@@ -524,7 +547,7 @@ async fn enable_linux() -> Result<()> {
     let sshd_installed = which_output.map(|o| o.status.success()).unwrap_or(false);
 
     if !sshd_installed {
-        println!("{} OpenSSH server not found.", "✗".red());
+        println!("{} OpenSSH server not found.", mark("✗").red());
         println!();
         println!("Install it with your package manager:");
         println!(
@@ -536,7 +559,7 @@ async fn enable_linux() -> Result<()> {
             "sudo dnf install openssh-server".cyan()
         );
         println!("  {} (Arch)", "sudo pacman -S openssh".cyan());
-        return Ok(());
+        return Err(anyhow!("OpenSSH server is not installed"));
     }
 
     // Try systemctl first (most modern distros)
@@ -548,7 +571,7 @@ async fn enable_linux() -> Result<()> {
 
     if systemctl_exists {
         // Start sshd
-        println!("{} Starting SSH service...", "→".cyan());
+        println!("{} Starting SSH service...", mark("→").cyan());
         let start_output = Command::new("systemctl").args(["start", "sshd"]).output();
 
         // Try 'ssh' service name if 'sshd' fails (Ubuntu uses 'ssh')
@@ -562,14 +585,14 @@ async fn enable_linux() -> Result<()> {
         };
 
         if start_ok {
-            println!("{} SSH service started.", "✓".green());
+            println!("{} SSH service started.", mark("✓").green());
         } else {
-            println!("{} Failed to start SSH service.", "✗".red());
-            return Ok(());
+            println!("{} Failed to start SSH service.", mark("✗").red());
+            return Err(anyhow!("Failed to start SSH service"));
         }
 
         // Enable on boot
-        println!("{} Configuring automatic startup...", "→".cyan());
+        println!("{} Configuring automatic startup...", mark("→").cyan());
         let enable_output = Command::new("systemctl").args(["enable", "sshd"]).output();
 
         let enable_ok = match enable_output {
@@ -582,31 +605,34 @@ async fn enable_linux() -> Result<()> {
         };
 
         if enable_ok {
-            println!("{} SSH will start automatically on boot.", "✓".green());
+            println!(
+                "{} SSH will start automatically on boot.",
+                mark("✓").green()
+            );
         } else {
             println!(
                 "{} Warning: Could not enable automatic startup.",
-                "⚠".yellow()
+                mark("⚠").yellow()
             );
         }
     } else {
         // Fallback for non-systemd systems
-        println!("{} Starting SSH service...", "→".cyan());
+        println!("{} Starting SSH service...", mark("→").cyan());
         let output = Command::new("service").args(["sshd", "start"]).output();
 
         match output {
             Ok(o) if o.status.success() => {
-                println!("{} SSH service started.", "✓".green());
+                println!("{} SSH service started.", mark("✓").green());
             }
             _ => {
                 // Try 'ssh' service name
                 let output2 = Command::new("service").args(["ssh", "start"]).output();
 
                 if output2.map(|o| o.status.success()).unwrap_or(false) {
-                    println!("{} SSH service started.", "✓".green());
+                    println!("{} SSH service started.", mark("✓").green());
                 } else {
-                    println!("{} Failed to start SSH service.", "✗".red());
-                    return Ok(());
+                    println!("{} Failed to start SSH service.", mark("✗").red());
+                    return Err(anyhow!("Failed to start SSH service"));
                 }
             }
         }
@@ -618,14 +644,14 @@ async fn enable_linux() -> Result<()> {
 
 async fn disable_linux() -> Result<()> {
     if !is_elevated() {
-        println!("{} This command requires root privileges.", "✗".red());
+        println!("{} This command requires root privileges.", mark("✗").red());
         println!();
         println!("Please run with sudo:");
         println!("  {}", "sudo connecto ssh off".cyan());
-        return Ok(());
+        return Err(not_elevated());
     }
 
-    println!("{} Disabling SSH server...", "→".cyan());
+    println!("{} Disabling SSH server...", mark("→").cyan());
 
     let systemctl_exists = Command::new("which")
         .arg("systemctl")
@@ -638,18 +664,18 @@ async fn disable_linux() -> Result<()> {
         let _ = Command::new("systemctl").args(["stop", "sshd"]).output();
         let _ = Command::new("systemctl").args(["stop", "ssh"]).output();
 
-        println!("{} SSH service stopped.", "✓".green());
+        println!("{} SSH service stopped.", mark("✓").green());
 
         // Disable on boot
         let _ = Command::new("systemctl").args(["disable", "sshd"]).output();
         let _ = Command::new("systemctl").args(["disable", "ssh"]).output();
 
-        println!("{} SSH automatic startup disabled.", "✓".green());
+        println!("{} SSH automatic startup disabled.", mark("✓").green());
     } else {
         let _ = Command::new("service").args(["sshd", "stop"]).output();
         let _ = Command::new("service").args(["ssh", "stop"]).output();
 
-        println!("{} SSH service stopped.", "✓".green());
+        println!("{} SSH service stopped.", mark("✓").green());
     }
 
     println!();
@@ -685,11 +711,15 @@ async fn status_linux() -> Result<()> {
         };
 
         if is_active {
-            println!("{} SSH server is {}", "•".green(), "running".green().bold());
+            println!(
+                "{} SSH server is {}",
+                mark("•").green(),
+                "running".green().bold()
+            );
         } else {
             println!(
                 "{} SSH server is {}",
-                "•".yellow(),
+                mark("•").yellow(),
                 "stopped".yellow().bold()
             );
         }
@@ -709,11 +739,11 @@ async fn status_linux() -> Result<()> {
         };
 
         if is_enabled {
-            println!("{} Starts automatically on boot", "•".green());
+            println!("{} Starts automatically on boot", mark("•").green());
         } else {
             println!(
                 "{} Automatic startup is {}",
-                "•".yellow(),
+                mark("•").yellow(),
                 "disabled".yellow()
             );
         }
@@ -723,12 +753,16 @@ async fn status_linux() -> Result<()> {
 
         match pgrep_output {
             Ok(out) if out.status.success() => {
-                println!("{} SSH server is {}", "•".green(), "running".green().bold());
+                println!(
+                    "{} SSH server is {}",
+                    mark("•").green(),
+                    "running".green().bold()
+                );
             }
             _ => {
                 println!(
                     "{} SSH server is {}",
-                    "•".yellow(),
+                    mark("•").yellow(),
                     "not running".yellow().bold()
                 );
             }
@@ -741,7 +775,7 @@ async fn status_linux() -> Result<()> {
     if let Ok(out) = ss_output {
         let output = String::from_utf8_lossy(&out.stdout);
         if output.contains(":22 ") || output.contains(":22\t") {
-            println!("{} Listening on port {}", "•".green(), "22".cyan());
+            println!("{} Listening on port {}", mark("•").green(), "22".cyan());
         }
     }
 
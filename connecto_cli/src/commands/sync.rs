@@ -1,30 +1,65 @@
-//! Sync command - Bidirectional SSH key pairing between two devices
+//! Sync command - Bidirectional SSH key pairing between two devices, once or
+//! continuously as a daemon
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use colored::Colorize;
 use connecto_core::{
-    discovery::{get_hostname, get_local_addresses},
-    keys::{KeyAlgorithm, KeyManager, SshKeyPair},
-    sync::{SyncEvent, SyncHandler},
+    audit::DecisionLog,
+    discovery::get_local_addresses,
+    identity::{DeviceIdentity, VerifiedIdentity},
+    keys::{self, KeyAlgorithm, KeyManager, SshKeyPair},
+    next_steps::{self, Event, Situation},
+    pairings::{PairingDirection, PairingRecord, PairingStore},
+    ssh_config::{parse_entries, HostEntry, SshConfig},
+    sync::{KeyOutcome, ReceivedKey, SyncEvent, SyncHandler, SyncResult},
+    sync_keys::SyncKeyStore,
+    trust::{TrustMode, TrustStore},
+    ConnectoError,
 };
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::time::Duration;
 use tokio::sync::mpsc;
 
-use super::{error, info, success, warn};
+use super::{
+    answer_approvals, error, info, port_in_use_hints, print_next_steps, success, warn,
+    warn_clock_skew,
+};
+use crate::config::Config;
+use crate::output::{banner, mark};
+
+/// Which of the peer's keys a sync accepts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AcceptPolicy {
+    /// With `--approve`, how long to wait for an answer
+    pub approval: Option<Duration>,
+    /// Device names and key fingerprints given with `--deny`
+    pub deny: Vec<String>,
+}
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     port: u16,
     name: Option<String>,
     timeout_secs: u64,
-    use_rsa: bool,
+    algorithm: KeyAlgorithm,
     key_path: Option<String>,
+    share: Vec<String>,
+    share_all: bool,
+    accept_new_identity: bool,
+    policy: AcceptPolicy,
+    daemon: Option<Duration>,
 ) -> Result<()> {
-    let device_name = name.unwrap_or_else(get_hostname);
+    if policy.approval.is_some() && !std::io::stdin().is_terminal() {
+        bail!("--approve needs an interactive terminal to answer sync requests");
+    }
+    let config = Config::load().unwrap_or_default();
+    let device_name = name.unwrap_or_else(|| config.device_name());
     let key_manager = KeyManager::new()?;
 
     // Print header
     println!();
-    println!("{}", "  CONNECTO SYNC  ".on_bright_magenta().white().bold());
+    banner("CONNECTO SYNC", |s| s.on_bright_magenta().white().bold());
     println!();
 
     // Show local addresses
@@ -36,27 +71,34 @@ pub async fn run(
 
     info(&format!("Device name: {}", device_name.cyan()));
     info(&format!("Port: {}", port.to_string().cyan()));
-    info(&format!("Timeout: {}s", timeout_secs.to_string().cyan()));
+    match daemon {
+        Some(interval) => info(&format!(
+            "Key lists exchanged every {}s",
+            interval.as_secs().to_string().cyan()
+        )),
+        None => info(&format!("Timeout: {}s", timeout_secs.to_string().cyan())),
+    }
     println!();
 
     println!("{}", "Local IP addresses:".bold());
     for addr in &addresses {
         if addr.is_ipv4() {
-            println!("  {} {}", "•".green(), addr);
+            println!("  {} {}", mark("•").green(), addr);
         }
     }
     println!();
 
+    // Further keys for the peer to authorize
+    let mut shared_keys = shared_keys(&key_manager, &share, share_all)?;
+
     // Get or generate key pair
-    let key_pair = if let Some(key_path) = key_path {
+    let (key_pair, key_file) = if let Some(key_path) = key_path {
         info(&format!("Using existing key: {}", key_path.dimmed()));
-        SshKeyPair::load_from_file(&key_path)?
+        let key_pair = SshKeyPair::load_from_file(&key_path)?;
+        config.check_algorithm(key_pair.algorithm)?;
+        (key_pair, key_path)
     } else {
-        let algorithm = if use_rsa {
-            KeyAlgorithm::Rsa4096
-        } else {
-            KeyAlgorithm::Ed25519
-        };
+        config.check_algorithm(algorithm)?;
 
         // Generate key for this sync
         let user = std::env::var("USER")
@@ -64,12 +106,12 @@ pub async fn run(
             .unwrap_or_else(|_| "user".to_string());
         let comment = format!("{}@{}", user, device_name);
 
-        info(&format!(
-            "Generating {} key for sync...",
-            if use_rsa { "RSA-4096" } else { "Ed25519" }
-        ));
+        info(&format!("Generating {} key for sync...", algorithm));
+        if algorithm.is_security_key() {
+            info("Touch your security key when it blinks");
+        }
 
-        let key_pair = SshKeyPair::generate(algorithm, &comment)?;
+        let key_pair = SshKeyPair::generate_async(algorithm, &comment).await?;
 
         // Save the key
         let key_name = format!("connecto_sync_{}", sanitize_hostname(&device_name));
@@ -80,26 +122,98 @@ pub async fn run(
             priv_path.display().to_string().dimmed()
         ));
 
-        key_pair
+        (key_pair, priv_path.display().to_string())
     };
 
+    // The sync key is always sent, and each key only once
+    let key_id = |key: &str| key.split_whitespace().take(2).collect::<Vec<_>>().join(" ");
+    let mut seen = vec![key_id(&key_pair.public_key)];
+    shared_keys.retain(|(_, key)| {
+        let id = key_id(key);
+        let new = !seen.contains(&id);
+        seen.push(id);
+        new
+    });
+    for (path, _) in &shared_keys {
+        info(&format!("Sharing key: {}", path.dimmed()));
+    }
+
     println!();
-    println!("{}", "Waiting for sync peer...".magenta().bold());
-    println!(
-        "{}",
-        "Run 'connecto sync' on another device on the same network".dimmed()
-    );
-    println!("{}", "Press Ctrl+C to cancel".dimmed());
+    if daemon.is_some() {
+        println!("{}", "Running as a sync daemon...".magenta().bold());
+        println!(
+            "{}",
+            "Devices running 'connecto sync' on the same network are synced and kept up to date"
+                .dimmed()
+        );
+        println!("{}", "Press Ctrl+C to stop".dimmed());
+    } else {
+        println!("{}", "Waiting for sync peer...".magenta().bold());
+        println!(
+            "{}",
+            "Run 'connecto sync' on another device on the same network".dimmed()
+        );
+        println!("{}", "Press Ctrl+C to cancel".dimmed());
+    }
     println!();
 
     // Create sync handler
     let sync_key_manager = KeyManager::new()?;
-    let handler = SyncHandler::new(sync_key_manager, &device_name, key_pair.clone());
+    let mut handler = SyncHandler::new(sync_key_manager, &device_name, key_pair.clone())
+        .with_shared_keys(shared_keys.into_iter().map(|(_, key)| key).collect());
+    match DeviceIdentity::load_or_create() {
+        Ok(identity) => handler = handler.with_identity(&identity),
+        Err(e) => warn(&format!("Could not load device identity: {}", e)),
+    }
+    match TrustStore::new() {
+        Ok(store) => handler = handler.with_trust_store(store),
+        Err(e) => warn(&format!("Device identities will not be checked: {}", e)),
+    }
+    if accept_new_identity {
+        handler = handler.with_trust_mode(TrustMode::Warn);
+    }
+    match DecisionLog::new() {
+        Ok(mut log) => {
+            if let Some(policy) = &config.policy {
+                log = log.with_policy(&serde_json::to_string(policy)?);
+            }
+            handler = handler.with_decision_log(log);
+        }
+        Err(e) => warn(&format!("Sync decisions will not be recorded: {}", e)),
+    }
+    if !policy.deny.is_empty() {
+        handler = handler.with_deny_list(policy.deny);
+    }
+    let approver = if let Some(timeout) = policy.approval {
+        let (approval_tx, approval_rx) = mpsc::channel(1);
+        handler = handler
+            .with_approval(approval_tx)
+            .with_approval_timeout(timeout);
+        Some(tokio::spawn(answer_approvals(
+            approval_rx,
+            "Sync request",
+            |request| {
+                format!(
+                    "Accept key from {} ({})?",
+                    request.device_name.bold(),
+                    request.fingerprint
+                )
+            },
+        )))
+    } else {
+        None
+    };
+    // Lets a daemon on either side keep the keys up to date later
+    match SyncKeyStore::new() {
+        Ok(store) => handler = handler.with_key_store(store),
+        Err(e) => warn(&format!("Synced keys will not be kept up to date: {}", e)),
+    }
 
     // Create event channel
     let (event_tx, mut event_rx) = mpsc::channel(10);
 
     // Handle events in a separate task
+    let daemon_key = (key_pair.clone(), key_file.clone());
     let event_handler = tokio::spawn(async move {
         while let Some(event) = event_rx.recv().await {
             match event {
@@ -145,19 +259,77 @@ pub async fn run(
                         "Sync completed with {}!",
                         peer_name.green().bold()
                     ));
-                    println!("  {} Bidirectional SSH access established.", "→".cyan());
+                    println!(
+                        "  {} Bidirectional SSH access established.",
+                        mark("→").cyan()
+                    );
                     println!(
                         "  {} You can SSH to them, and they can SSH to you.",
-                        "→".cyan()
+                        mark("→").cyan()
                     );
                 }
                 SyncEvent::Failed { message } => {
                     error(&format!("Sync failed: {}", message));
                 }
+                SyncEvent::PeerSynced { result } => {
+                    for key in &result.received_keys {
+                        if let KeyOutcome::Refused(reason) | KeyOutcome::Failed(reason) =
+                            &key.outcome
+                        {
+                            warn(&format!(
+                                "Did not authorize {} from {}: {}",
+                                key_label(key),
+                                result.peer_name,
+                                reason
+                            ));
+                        }
+                    }
+                    let (key_pair, key_file) = &daemon_key;
+                    if let Err(e) = remember_peer(&result, key_pair, key_file) {
+                        warn(&format!(
+                            "Could not add {} to SSH config: {}",
+                            result.peer_name, e
+                        ));
+                    }
+                }
+                SyncEvent::KeysChanged {
+                    device_name,
+                    added,
+                    removed,
+                } => {
+                    success(&format!(
+                        "Updated keys of {}: {} added, {} removed",
+                        device_name.cyan().bold(),
+                        added.len(),
+                        removed.len()
+                    ));
+                }
             }
         }
     });
 
+    if let Some(interval) = daemon {
+        let shutdown = handler.shutdown_handle();
+        let result = tokio::select! {
+            result = handler.run_daemon(port, interval, event_tx) => result,
+            _ = tokio::signal::ctrl_c() => {
+                shutdown.shutdown();
+                println!();
+                info("Sync daemon stopped");
+                Ok(())
+            }
+        };
+        event_handler.abort();
+        if let Err(e) = result {
+            error(&format!("Sync daemon failed: {}", e));
+            for hint in port_in_use_hints(&e, "connecto sync --daemon") {
+                println!("  {} {}", mark("→").cyan(), hint);
+            }
+            return Err(e.into());
+        }
+        return Ok(());
+    }
+
     // Run sync with Ctrl+C handling
     let result = tokio::select! {
         result = handler.run(port, timeout_secs, event_tx) => result,
@@ -170,64 +342,197 @@ pub async fn run(
     };
 
     event_handler.abort();
+    if let Some(approver) = approver {
+        approver.abort();
+    }
 
     match result {
         Ok(sync_result) => {
             println!();
             println!("{}", "Sync Summary:".bold());
-            println!("  {} Peer: {}", "•".green(), sync_result.peer_name.cyan());
-            println!("  {} User: {}", "•".green(), sync_result.peer_user);
+            println!(
+                "  {} Peer: {}",
+                mark("•").green(),
+                sync_result.peer_name.cyan()
+            );
+            println!("  {} User: {}", mark("•").green(), sync_result.peer_user);
             println!(
                 "  {} Address: {}:{}",
-                "•".green(),
+                mark("•").green(),
                 sync_result.peer_address,
                 sync_result.peer_port
             );
             println!();
+            // A single key was already reported as it arrived
+            if sync_result.received_keys.len() > 1 {
+                print_received_keys(&sync_result.received_keys);
+            }
+            warn_clock_skew(&sync_result.peer_name, sync_result.clock_skew);
 
-            // Add to SSH config
-            add_to_ssh_config(
-                &sync_result.peer_name,
-                &sync_result.peer_address.to_string(),
-                &sync_result.peer_user,
-                &key_pair,
-                &key_manager,
-            )?;
-
-            println!("{}", "Next steps:".bold());
-            let host_alias = sanitize_hostname(&sync_result.peer_name);
-            println!(
-                "  {} SSH to peer: {}",
-                "→".cyan(),
-                format!("ssh {}", host_alias).green()
-            );
-            println!();
+            let host_alias = remember_peer(&sync_result, &key_pair, &key_file)?;
+
+            // The peer connects back, which needs our SSH server
+            print_next_steps(&next_steps::recommend(&Situation {
+                event: Event::Synced,
+                host: Some(host_alias),
+                key_path: Some(key_file.to_string()),
+                key_in_agent: next_steps::key_in_agent(&key_pair),
+                sshd_running: Some(next_steps::ssh_server_running().await),
+                ..Default::default()
+            }));
 
             success("Sync successful!");
         }
         Err(e) => {
             println!();
             error(&format!("Sync failed: {}", e));
+            // A refusal reached the peer, so there is nothing to troubleshoot
+            if matches!(e, ConnectoError::SyncRejected(_)) {
+                return Err(e.into());
+            }
+
+            let hints = port_in_use_hints(&e, "connecto sync");
+            if !hints.is_empty() {
+                for hint in hints {
+                    println!("  {} {}", mark("→").cyan(), hint);
+                }
+                return Err(e.into());
+            }
 
             // Provide helpful suggestions
             println!();
             println!("{}", "Troubleshooting:".bold());
             println!(
                 "  {} Make sure both devices are on the same network",
-                "•".dimmed()
+                mark("•").dimmed()
+            );
+            println!(
+                "  {} Check that mDNS/Bonjour is not blocked",
+                mark("•").dimmed()
             );
-            println!("  {} Check that mDNS/Bonjour is not blocked", "•".dimmed());
             println!(
                 "  {} Try increasing timeout: {}",
-                "•".dimmed(),
+                mark("•").dimmed(),
                 format!("connecto sync --timeout {}", timeout_secs * 2).cyan()
             );
+            return Err(e.into());
         }
     }
 
     Ok(())
 }
 
+/// Public keys to share besides the sync key, with the files they came from:
+/// the `--share` files, then every connecto_* key with `--share-all`
+fn shared_keys(
+    key_manager: &KeyManager,
+    share: &[String],
+    share_all: bool,
+) -> Result<Vec<(String, String)>> {
+    let mut shared = Vec::new();
+    for path in share {
+        let key = keys::read_public_key(Path::new(path))
+            .map_err(|e| anyhow::anyhow!("Could not read the key to share ({}): {}", path, e))?;
+        shared.push((path.clone(), key));
+    }
+    if share_all {
+        for (path, key) in key_manager.connecto_public_keys()? {
+            shared.push((path.display().to_string(), key));
+        }
+    }
+    Ok(shared)
+}
+
+/// A received key's comment, or its fingerprint if it has none
+fn key_label(key: &ReceivedKey) -> String {
+    if !key.comment.is_empty() {
+        return key.comment.clone();
+    }
+    keys::fingerprint(&key.public_key).unwrap_or_else(|_| "a key".to_string())
+}
+
+/// List the keys a peer shared and what became of each
+fn print_received_keys(received_keys: &[ReceivedKey]) {
+    println!("{}", "Keys received:".bold());
+    for key in received_keys {
+        match &key.outcome {
+            KeyOutcome::Added => println!(
+                "  {} {} {}",
+                mark("✓").green(),
+                key_label(key).cyan(),
+                "added".dimmed()
+            ),
+            KeyOutcome::AlreadyAuthorized => println!(
+                "  {} {} {}",
+                mark("✓").green(),
+                key_label(key).cyan(),
+                "already authorized".dimmed()
+            ),
+            KeyOutcome::Refused(reason) => println!(
+                "  {} {} {}",
+                mark("✗").yellow(),
+                key_label(key).cyan(),
+                format!("refused: {}", reason).yellow()
+            ),
+            KeyOutcome::Failed(reason) => println!(
+                "  {} {} {}",
+                mark("✗").red(),
+                key_label(key).cyan(),
+                format!("not authorized: {}", reason).red()
+            ),
+        }
+    }
+    println!();
+}
+
+/// Add a synced peer to SSH config and the pairing database, returning its
+/// host alias
+fn remember_peer(
+    sync_result: &SyncResult,
+    key_pair: &SshKeyPair,
+    key_file: &str,
+) -> Result<String> {
+    add_to_ssh_config(
+        &sync_result.peer_name,
+        &sync_result.peer_address.to_string(),
+        &sync_result.peer_user,
+        sync_result
+            .peer_identity
+            .as_ref()
+            .map(VerifiedIdentity::fingerprint),
+        key_pair,
+        &KeyManager::new()?,
+    )?;
+
+    let host_alias = sanitize_hostname(&sync_result.peer_name);
+
+    // Remember when and with whom we synced
+    let recorded = PairingRecord::new(
+        &sync_result.peer_name,
+        &key_pair.public_key,
+        &sync_result.peer_address.to_string(),
+        PairingDirection::Sync,
+    )
+    .and_then(|record| {
+        PairingStore::new()?.record(
+            record
+                .with_host(&host_alias)
+                .with_key_path(key_file)
+                .with_peer_identity(
+                    sync_result
+                        .peer_identity
+                        .as_ref()
+                        .map(VerifiedIdentity::fingerprint),
+                )
+                .with_clock_skew(sync_result.clock_skew),
+        )
+    });
+    if let Err(e) = recorded {
+        warn(&format!("Could not record pairing: {}", e));
+    }
+    Ok(host_alias)
+}
+
 /// Sanitize hostname for use as SSH host alias
 fn sanitize_hostname(hostname: &str) -> String {
     hostname
@@ -250,17 +555,14 @@ fn add_to_ssh_config(
     peer_name: &str,
     peer_ip: &str,
     peer_user: &str,
+    peer_identity: Option<&str>,
     _key_pair: &SshKeyPair,
     _key_manager: &KeyManager,
 ) -> Result<()> {
-    use std::fs::{self, OpenOptions};
-    use std::io::Write;
-
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| anyhow::anyhow!("HOME/USERPROFILE not set"))?;
+    use connecto_core::backups;
+    use std::fs;
 
-    let ssh_dir = PathBuf::from(&home).join(".ssh");
+    let ssh_dir = connecto_core::paths::ssh_dir()?;
     let config_path = ssh_dir.join("config");
 
     // Ensure .ssh directory exists
@@ -276,16 +578,25 @@ fn add_to_ssh_config(
     let host_alias = sanitize_hostname(peer_name);
 
     // Check if host already exists
+    let mut tags = Vec::new();
     if config_path.exists() {
+        let _lock = backups::lock(&config_path)?;
         let content = fs::read_to_string(&config_path)?;
         if content.contains(&format!("Host {}", host_alias)) {
+            // The new entry keeps the old one's tags
+            if let Some(entry) = parse_entries(&content)
+                .into_iter()
+                .find(|e| e.host == host_alias)
+            {
+                tags = entry.tags;
+            }
             warn(&format!(
                 "Host '{}' already exists in SSH config, updating...",
                 host_alias
             ));
             // Remove existing entry and re-add
             let new_content = remove_host_from_config(&content, &host_alias);
-            fs::write(&config_path, new_content)?;
+            backups::replace(&config_path, &new_content)?;
         }
     }
 
@@ -293,21 +604,33 @@ fn add_to_ssh_config(
     let key_name = format!("connecto_sync_{}", host_alias);
     let identity_file = ssh_dir.join(&key_name);
 
+    // Other entries for the same device (e.g. from an earlier pairing) follow it too
+    if let Some(identity) = peer_identity {
+        for host in SshConfig::with_path(config_path.clone()).update_address(identity, peer_ip)? {
+            info(&format!("Updated '{}' to {}", host.cyan(), peer_ip));
+        }
+    }
+
     // Write SSH config entry
-    let entry = format!(
-        "\n# Added by connecto\nHost {}\n    HostName {}\n    User {}\n    IdentityFile {}\n",
-        host_alias,
-        peer_ip,
-        peer_user,
-        identity_file.display()
-    );
-
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&config_path)?;
-
-    file.write_all(entry.as_bytes())?;
+    let entry = HostEntry {
+        host: host_alias.clone(),
+        hostname: peer_ip.to_string(),
+        user: peer_user.to_string(),
+        identity_file: identity_file.display().to_string(),
+        identity: peer_identity.map(str::to_string),
+        ..Default::default()
+    }
+    .with_tags(&tags, &Config::load().unwrap_or_default().ssh_templates)
+    .to_block();
+
+    let _lock = backups::lock(&config_path)?;
+    let mut content = if config_path.exists() {
+        fs::read_to_string(&config_path)?
+    } else {
+        String::new()
+    };
+    content.push_str(&entry);
+    backups::replace(&config_path, &content)?;
 
     info(&format!("Added '{}' to SSH config", host_alias.cyan()));
 
@@ -0,0 +1,176 @@
+//! Table rendering shared by list-style commands (scan, hosts, keys)
+
+use colored::{ColoredString, Colorize};
+
+/// Styling applied to the cells of a single column in pretty mode
+type CellStyle = fn(&str) -> ColoredString;
+
+/// A simple column-aligned table
+///
+/// In pretty mode the table is rendered with a bold header and padded
+/// columns. In plain mode only the rows are printed, tab-separated and
+/// without colors, so the output can be piped into `awk -F'\t'` or `cut`.
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    styles: Vec<Option<CellStyle>>,
+}
+
+impl Table {
+    /// Create a table with the given column headers
+    pub fn new<S: Into<String>>(headers: impl IntoIterator<Item = S>) -> Self {
+        let headers: Vec<String> = headers.into_iter().map(Into::into).collect();
+        let styles = vec![None; headers.len()];
+        Self {
+            headers,
+            rows: Vec::new(),
+            styles,
+        }
+    }
+
+    /// Style the cells of a column in pretty mode
+    pub fn style(mut self, column: usize, style: CellStyle) -> Self {
+        if let Some(slot) = self.styles.get_mut(column) {
+            *slot = Some(style);
+        }
+        self
+    }
+
+    /// Append a row; missing cells are rendered empty and extra cells are dropped
+    pub fn push_row(&mut self, row: Vec<String>) {
+        let mut row = row;
+        row.resize(self.headers.len(), String::new());
+        self.rows.push(row);
+    }
+
+    /// Number of rows in the table
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Whether the table has no rows
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Render the table to a string (without a trailing newline)
+    pub fn render(&self, plain: bool) -> String {
+        if plain {
+            return self
+                .rows
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|cell| cell.replace(['\t', '\n'], " "))
+                        .collect::<Vec<_>>()
+                        .join("\t")
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+
+        let widths: Vec<usize> = (0..self.headers.len())
+            .map(|i| {
+                self.rows
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(std::iter::once(self.headers[i].chars().count()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let last = self.headers.len().saturating_sub(1);
+        let mut lines = Vec::with_capacity(self.rows.len() + 1);
+
+        let header = self
+            .headers
+            .iter()
+            .enumerate()
+            .map(|(i, h)| pad(h, widths[i], i == last).bold().to_string())
+            .collect::<Vec<_>>()
+            .join("  ");
+        lines.push(header);
+
+        for row in &self.rows {
+            let line = row
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    let padded = pad(cell, widths[i], i == last);
+                    match self.styles[i] {
+                        Some(style) => style(&padded).to_string(),
+                        None => padded,
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("  ");
+            lines.push(line);
+        }
+
+        lines.join("\n")
+    }
+
+    /// Print the table to stdout
+    pub fn print(&self, plain: bool) {
+        let rendered = self.render(plain);
+        if !rendered.is_empty() {
+            println!("{}", rendered);
+        }
+    }
+}
+
+/// Left-align a cell to the column width (the last column is never padded)
+fn pad(cell: &str, width: usize, last: bool) -> String {
+    if last {
+        cell.to_string()
+    } else {
+        format!("{:<width$}", cell, width = width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_render_is_tab_separated() {
+        let mut table = Table::new(["#", "NAME", "IP"]);
+        table.push_row(vec!["0".into(), "My Device".into(), "10.0.0.1".into()]);
+        table.push_row(vec!["1".into(), "Other".into(), "10.0.0.2".into()]);
+
+        assert_eq!(
+            table.render(true),
+            "0\tMy Device\t10.0.0.1\n1\tOther\t10.0.0.2"
+        );
+    }
+
+    #[test]
+    fn test_plain_render_strips_separators_from_cells() {
+        let mut table = Table::new(["NAME"]);
+        table.push_row(vec!["a\tb\nc".into()]);
+        assert_eq!(table.render(true), "a b c");
+    }
+
+    #[test]
+    fn test_pretty_render_aligns_columns() {
+        colored::control::set_override(false);
+        let mut table = Table::new(["#", "NAME", "PORT"]);
+        table.push_row(vec!["0".into(), "alpha".into(), "8099".into()]);
+        table.push_row(vec!["10".into(), "b".into(), "22".into()]);
+
+        let rendered = table.render(false);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[0], "#   NAME   PORT");
+        assert_eq!(lines[1], "0   alpha  8099");
+        assert_eq!(lines[2], "10  b      22");
+    }
+
+    #[test]
+    fn test_push_row_pads_missing_cells() {
+        let mut table = Table::new(["A", "B"]);
+        table.push_row(vec!["x".into()]);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.render(true), "x\t");
+    }
+}
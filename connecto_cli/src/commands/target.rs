@@ -0,0 +1,219 @@
+//! Target names - Find the device a name given to `connecto pair` refers to
+//!
+//! Besides device numbers and addresses, `pair` takes a name: the device
+//! name or mDNS hostname of a device from the last scan, or the alias of a
+//! paired host in `~/.ssh/config`. Names are compared regardless of case. A
+//! name that matches nothing exactly matches the names starting with it, and
+//! failing those, the names containing it.
+
+use connecto_core::discovery::DiscoveredDevice;
+use connecto_core::net;
+use connecto_core::ssh_config::{host_alias, HostEntry};
+use std::fmt;
+
+/// A device a name may refer to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// How the user knows the device, e.g. `Study (desk-pc.local)`
+    pub label: String,
+    /// Where its listener is reached
+    pub address: String,
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.label, self.address)
+    }
+}
+
+/// How closely a name matches, worst first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Fit {
+    Contains,
+    Prefix,
+    Exact,
+}
+
+fn fit(query: &str, name: &str) -> Option<Fit> {
+    let name = name.to_lowercase();
+    if name == query {
+        Some(Fit::Exact)
+    } else if name.starts_with(query) {
+        Some(Fit::Prefix)
+    } else if name.contains(query) {
+        Some(Fit::Contains)
+    } else {
+        None
+    }
+}
+
+/// The devices `name` may refer to, among `devices` from the last scan and
+/// the paired `hosts`
+///
+/// Only the closest matches are returned, so an exact match wins over any
+/// number of partial ones. Paired hosts that are also among `devices` are
+/// left to them, since the scan has the current address.
+pub fn candidates(
+    name: &str,
+    devices: &[DiscoveredDevice],
+    hosts: &[HostEntry],
+    default_port: u16,
+) -> Vec<Candidate> {
+    let query = name.to_lowercase();
+    let mut matches: Vec<(Fit, Candidate)> = Vec::new();
+
+    for (index, device) in devices.iter().enumerate() {
+        let Some(address) = device.connection_string() else {
+            continue;
+        };
+        let device_name = device.device_name();
+        let mut names = vec![device_name.to_string(), host_alias(device_name)];
+        if let Some(hostname) = device.mdns_hostname() {
+            names.push(hostname.to_string());
+            names.push(hostname.trim_end_matches(".local").to_string());
+        }
+        if let Some(best) = names.iter().filter_map(|n| fit(&query, n)).max() {
+            let label = match device.mdns_hostname() {
+                Some(hostname) => format!("{} ({}), device {}", device_name, hostname, index),
+                None => format!("{}, device {}", device_name, index),
+            };
+            matches.push((best, Candidate { label, address }));
+        }
+    }
+
+    for entry in hosts {
+        let scanned = devices.iter().any(|device| {
+            (entry.identity.is_some() && device.identity == entry.identity)
+                || host_alias(device.device_name()) == entry.host
+        });
+        if scanned {
+            continue;
+        }
+        if let Some(best) = fit(&query, &entry.host) {
+            matches.push((
+                best,
+                Candidate {
+                    label: format!("paired host '{}'", entry.host),
+                    address: net::join_host_port(&entry.hostname, default_port),
+                },
+            ));
+        }
+    }
+
+    let Some(best) = matches.iter().map(|(fit, _)| *fit).max() else {
+        return Vec::new();
+    };
+    let mut found: Vec<Candidate> = Vec::new();
+    for (fit, candidate) in matches {
+        if fit == best && !found.iter().any(|c| c.address == candidate.address) {
+            found.push(candidate);
+        }
+    }
+    found
+}
+
+/// Whether `target` is an IP address, with or without scope, rather than a
+/// name
+pub fn is_ip_address(target: &str) -> bool {
+    let ip = target.split('%').next().unwrap_or(target);
+    ip.parse::<std::net::IpAddr>().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connecto_core::DEFAULT_PORT;
+    use std::net::IpAddr;
+
+    fn device(name: &str, hostname: &str, ip: [u8; 4]) -> DiscoveredDevice {
+        DiscoveredDevice {
+            name: format!("{} ({})._connecto._tcp.local.", name, hostname),
+            hostname: format!("{}.local.", hostname),
+            addresses: vec![IpAddr::from(ip)],
+            port: DEFAULT_PORT,
+            instance_name: format!("{} ({})._connecto._tcp.local.", name, hostname),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        }
+    }
+
+    fn host(alias: &str, hostname: &str) -> HostEntry {
+        HostEntry {
+            host: alias.to_string(),
+            hostname: hostname.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn addresses(found: &[Candidate]) -> Vec<&str> {
+        found.iter().map(|c| c.address.as_str()).collect()
+    }
+
+    #[test]
+    fn test_candidates_by_name() {
+        let devices = [
+            device("My Laptop", "alice-mbp", [10, 0, 0, 5]),
+            device("Study", "desk-pc", [10, 0, 0, 6]),
+        ];
+
+        // Device name, its host alias form, and mDNS hostname with or without .local
+        for name in ["my laptop", "my_laptop", "alice-mbp", "Alice-MBP.local"] {
+            let found = candidates(name, &devices, &[], DEFAULT_PORT);
+            assert_eq!(addresses(&found), ["10.0.0.5:8099"], "{}", name);
+        }
+        let found = candidates("desk", &devices, &[], DEFAULT_PORT);
+        assert_eq!(found[0].label, "Study (desk-pc.local), device 1");
+        assert!(candidates("kitchen", &devices, &[], DEFAULT_PORT).is_empty());
+    }
+
+    #[test]
+    fn test_closest_matches_win() {
+        let devices = [
+            device("lab", "lab-1", [10, 0, 0, 5]),
+            device("lab-2", "lab-2", [10, 0, 0, 6]),
+            device("old lab", "old", [10, 0, 0, 7]),
+        ];
+        // An exact match beats the prefix matches
+        assert_eq!(
+            addresses(&candidates("LAB", &devices, &[], DEFAULT_PORT)),
+            ["10.0.0.5:8099"]
+        );
+        // Prefix matches beat containing ones, and all of them are returned
+        assert_eq!(
+            addresses(&candidates("lab-", &devices, &[], DEFAULT_PORT)),
+            ["10.0.0.5:8099", "10.0.0.6:8099"]
+        );
+    }
+
+    #[test]
+    fn test_candidates_from_paired_hosts() {
+        let devices = [device("Study", "desk-pc", [10, 0, 0, 6])];
+        let hosts = [host("nas", "10.0.0.20"), host("study", "10.0.0.2")];
+
+        let found = candidates("nas", &devices, &hosts, 9000);
+        assert_eq!(
+            found,
+            [Candidate {
+                label: "paired host 'nas'".to_string(),
+                address: "10.0.0.20:9000".to_string(),
+            }]
+        );
+        // The scan knows where the paired study is now
+        assert_eq!(
+            addresses(&candidates("study", &devices, &hosts, DEFAULT_PORT)),
+            ["10.0.0.6:8099"]
+        );
+    }
+
+    #[test]
+    fn test_is_ip_address() {
+        assert!(is_ip_address("192.168.1.1"));
+        assert!(is_ip_address("fe80::1%en0"));
+        assert!(!is_ip_address("my-laptop"));
+        assert!(!is_ip_address("desk.local"));
+    }
+}
@@ -0,0 +1,707 @@
+//! Test command - Check SSH connectivity to a paired host and repair common failures
+
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use connecto_core::connectivity::{self, ProbeTarget};
+use connecto_core::discovery::{DiscoveredDevice, ServiceBrowser, SubnetScanner, DEFAULT_PORT};
+use connecto_core::next_steps::{self, ConnectionIssue, Event, Situation};
+use connecto_core::paths;
+use connecto_core::ssh_client::{CheckFailure, SshCheck};
+use connecto_core::ssh_config::{self, host_alias, SshConfig, IDENTITY_MARKER};
+use connecto_core::ConnectoError;
+use dialoguer::Confirm;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use super::{error, info, print_next_steps, success, warn};
+use crate::output::{mark, theme, Progress};
+
+/// How long to look for a host on the network when its address looks stale
+const REDISCOVER_TIMEOUT_SECS: u64 = 5;
+
+/// How long the reachability pre-check waits, matching ssh's ConnectTimeout
+const PROBE_TIMEOUT_SECS: u64 = 5;
+
+/// A known cause of a failed connection test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Issue {
+    /// The host could not be reached at its configured address
+    StaleAddress,
+    /// The private key (or ~/.ssh) has permissions ssh refuses to use
+    KeyPermissions,
+    /// The server rejected the key, which may not be loaded in the agent
+    KeyNotLoaded,
+}
+
+impl Issue {
+    fn description(self) -> &'static str {
+        match self {
+            Issue::StaleAddress => "The host is unreachable at its saved address",
+            Issue::KeyPermissions => "The private key has insecure permissions",
+            Issue::KeyNotLoaded => "The server rejected the key",
+        }
+    }
+
+    fn fix_description(self) -> &'static str {
+        match self {
+            Issue::StaleAddress => "Rediscover the host on the network and update its IP",
+            Issue::KeyPermissions => "Restrict permissions on the private key and ~/.ssh",
+            Issue::KeyNotLoaded => "Add the private key to the SSH agent",
+        }
+    }
+}
+
+impl From<Issue> for ConnectionIssue {
+    fn from(issue: Issue) -> Self {
+        match issue {
+            Issue::StaleAddress => ConnectionIssue::Unreachable,
+            Issue::KeyPermissions => ConnectionIssue::KeyPermissions,
+            Issue::KeyNotLoaded => ConnectionIssue::KeyRejected,
+        }
+    }
+}
+
+/// The parts of a host's ~/.ssh/config entry needed for repairs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostEntry {
+    pub hostname: Option<String>,
+    pub identity_file: Option<String>,
+    /// Identity fingerprint of the paired device, if the entry is bound to one
+    pub identity: Option<String>,
+}
+
+/// Result of a single connection attempt
+enum Outcome {
+    Success,
+    Unexpected,
+    Failed(String),
+}
+
+/// Result of a connection test, with the likely cause of a failure
+enum Checked {
+    Passed,
+    Failed(Option<Issue>),
+}
+
+pub async fn run(host: &str, fix: bool, builtin: bool) -> Result<()> {
+    let builtin = builtin || !ssh_installed();
+    if builtin {
+        info("Testing with the built-in SSH client");
+    }
+
+    // Check reachability along the route ssh will take, bastions included.
+    // The built-in client reports unreachable hosts itself
+    let target = if builtin {
+        None
+    } else {
+        ProbeTarget::resolve(host).await.ok()
+    };
+    let reachable = match &target {
+        Some(target) => check_reachable(host, target).await,
+        None => true,
+    };
+
+    let issue = if reachable {
+        match check(host, builtin).await? {
+            Checked::Passed => return Ok(()),
+            Checked::Failed(issue) => issue,
+        }
+    } else {
+        Some(Issue::StaleAddress)
+    };
+
+    let Some(issue) = issue else {
+        println!();
+        print_next(host, None, false);
+        return Err(still_failing(host, None));
+    };
+
+    // The built-in client never asks the agent, so loading the key won't help
+    if builtin && issue == Issue::KeyNotLoaded {
+        println!();
+        print_next(host, Some(issue), false);
+        return Err(still_failing(host, Some(issue)));
+    }
+
+    // A host behind a bastion is not on the local network, so rediscovering it won't help
+    if let Some(target) = target.as_ref().filter(|t| t.route.is_proxied()) {
+        if issue == Issue::StaleAddress {
+            println!();
+            warn(&format!(
+                "'{}' is reached {}. Check that the bastion is up and can reach {}.",
+                host,
+                target.route.describe(),
+                target.hostname
+            ));
+            // Rediscovering the host on the local network won't help
+            println!();
+            print_next(host, None, false);
+            return Err(still_failing(host, Some(issue)));
+        }
+    }
+
+    println!();
+    warn(&format!("Likely cause: {}", issue.description()));
+    println!(
+        "  {} {}",
+        mark("→").dimmed(),
+        issue.fix_description().dimmed()
+    );
+    println!();
+
+    if !fix {
+        if !std::io::stdin().is_terminal() {
+            print_next(host, Some(issue), true);
+            return Err(still_failing(host, Some(issue)));
+        }
+
+        let confirmed = Confirm::with_theme(theme().as_ref())
+            .with_prompt("Attempt fix?")
+            .default(true)
+            .interact()?;
+
+        if !confirmed {
+            println!();
+            print_next(host, Some(issue), false);
+            return Err(still_failing(host, Some(issue)));
+        }
+    }
+
+    let entry = load_host_entry(host)?;
+    let repaired = match issue {
+        Issue::StaleAddress => refresh_address(host, &entry).await?,
+        Issue::KeyPermissions => fix_key_permissions(&entry)?,
+        Issue::KeyNotLoaded => add_key_to_agent(&entry)?,
+    };
+
+    if !repaired {
+        println!();
+        print_next(host, Some(issue), false);
+        return Err(still_failing(host, Some(issue)));
+    }
+
+    println!();
+    info("Retesting...");
+    match check(host, builtin).await? {
+        Checked::Failed(issue) => {
+            println!();
+            print_next(host, issue, false);
+            Err(still_failing(host, issue))
+        }
+        Checked::Passed => Ok(()),
+    }
+}
+
+/// Whether the `ssh` command can be run
+fn ssh_installed() -> bool {
+    Command::new("ssh").arg("-V").output().is_ok()
+}
+
+/// Test the connection to `host` with ssh, or with the built-in client
+async fn check(host: &str, builtin: bool) -> Result<Checked> {
+    if builtin {
+        return check_builtin(host).await;
+    }
+    Ok(match test_connection(host)? {
+        Outcome::Success | Outcome::Unexpected => Checked::Passed,
+        Outcome::Failed(stderr) => Checked::Failed(diagnose(&stderr)),
+    })
+}
+
+/// Log in to `host` with the built-in client, which reports exactly why
+/// a login failed instead of leaving it to be read from ssh's output
+async fn check_builtin(host: &str) -> Result<Checked> {
+    let entry = SshConfig::new()?
+        .entries()?
+        .into_iter()
+        .find(|entry| entry.host == host)
+        .ok_or_else(|| anyhow!("Host '{}' not found in SSH config", host))?;
+    let ssh_check = SshCheck::for_entry(&entry)?;
+
+    println!(
+        "{} Testing connection to {}...",
+        mark("→").cyan(),
+        host.cyan().bold()
+    );
+    match ssh_check.run().await {
+        Ok(report) => {
+            println!("{} Connection successful!", mark("✓").green());
+            println!(
+                "  {} {}, host key {}",
+                mark("→").dimmed(),
+                report.server_version.dimmed(),
+                report.host_key.dimmed()
+            );
+            Ok(Checked::Passed)
+        }
+        Err(failure) => {
+            println!("{} Connection failed.", mark("✗").red());
+            println!("{}", failure.to_string().dimmed());
+            Ok(Checked::Failed(classify(&failure)))
+        }
+    }
+}
+
+/// The repairable cause behind a failure of the built-in client
+pub fn classify(failure: &CheckFailure) -> Option<Issue> {
+    match failure {
+        CheckFailure::Resolve(_) | CheckFailure::Connect(_) | CheckFailure::Timeout(_) => {
+            Some(Issue::StaleAddress)
+        }
+        CheckFailure::KeyPermissions(_) => Some(Issue::KeyPermissions),
+        CheckFailure::Rejected { .. } => Some(Issue::KeyNotLoaded),
+        CheckFailure::Protocol(_)
+        | CheckFailure::UnknownHostKey { .. }
+        | CheckFailure::HostKeyMismatch { .. }
+        | CheckFailure::Key(_) => None,
+    }
+}
+
+/// The error to exit with while `host` still cannot be reached over SSH
+fn still_failing(host: &str, issue: Option<Issue>) -> anyhow::Error {
+    match issue {
+        Some(Issue::KeyPermissions | Issue::KeyNotLoaded) => {
+            ConnectoError::PermissionDenied(format!("the key for {} was refused", host)).into()
+        }
+        _ => anyhow!("Could not connect to {}", host),
+    }
+}
+
+/// Probe the host's SSH server the way ssh would reach it
+///
+/// Only reports when something is notable: a failure, or success through a
+/// bastion (so a direct connect failing is not mistaken for the host being down).
+async fn check_reachable(host: &str, target: &ProbeTarget) -> bool {
+    match connectivity::probe(target, Duration::from_secs(PROBE_TIMEOUT_SECS)).await {
+        Ok(()) => {
+            if target.route.is_proxied() {
+                println!(
+                    "{} {} is reachable {}",
+                    mark("✓").green(),
+                    host.cyan().bold(),
+                    target.route.describe()
+                );
+            }
+            true
+        }
+        Err(e) => {
+            println!(
+                "{} {} is unreachable {}: {}",
+                mark("✗").red(),
+                host.cyan().bold(),
+                target.route.describe(),
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Run `ssh <host> echo connecto-ok` and report the result
+fn test_connection(host: &str) -> Result<Outcome> {
+    println!(
+        "{} Testing connection to {}...",
+        mark("→").cyan(),
+        host.cyan().bold()
+    );
+
+    let output = Command::new("ssh")
+        .args([
+            "-o",
+            "ConnectTimeout=5",
+            "-o",
+            "BatchMode=yes",
+            host,
+            "echo",
+            "connecto-ok",
+        ])
+        .output();
+
+    match output {
+        Ok(result) => {
+            if result.status.success() {
+                let stdout = String::from_utf8_lossy(&result.stdout);
+                if stdout.trim() == "connecto-ok" {
+                    println!("{} Connection successful!", mark("✓").green());
+                    Ok(Outcome::Success)
+                } else {
+                    println!(
+                        "{} Connection established but unexpected response.",
+                        mark("⚠").yellow()
+                    );
+                    Ok(Outcome::Unexpected)
+                }
+            } else {
+                let stderr = String::from_utf8_lossy(&result.stderr).to_string();
+                println!("{} Connection failed.", mark("✗").red());
+                if !stderr.is_empty() {
+                    println!("{}", stderr.dimmed());
+                }
+                Ok(Outcome::Failed(stderr))
+            }
+        }
+        Err(e) => Err(anyhow!("Failed to run ssh: {}", e)),
+    }
+}
+
+/// Print what to try while `host` still fails with `issue`
+///
+/// `offer_repair` suggests `connecto test --fix`, which is pointless once
+/// the repair was tried or declined.
+fn print_next(host: &str, issue: Option<Issue>, offer_repair: bool) {
+    print_next_steps(&next_steps::recommend(&Situation {
+        event: Event::Tested,
+        host: Some(host.to_string()),
+        key_path: load_host_entry(host).ok().and_then(|e| e.identity_file),
+        issue: Some(issue.map_or(ConnectionIssue::Unknown, ConnectionIssue::from)),
+        repair_attempted: !offer_repair,
+        ..Default::default()
+    }));
+}
+
+/// Map ssh's error output to a known, repairable cause
+pub fn diagnose(stderr: &str) -> Option<Issue> {
+    let stderr = stderr.to_lowercase();
+
+    if stderr.contains("unprotected private key file") || stderr.contains("bad permissions") {
+        Some(Issue::KeyPermissions)
+    } else if stderr.contains("permission denied (publickey") {
+        Some(Issue::KeyNotLoaded)
+    } else if stderr.contains("connection timed out")
+        || stderr.contains("operation timed out")
+        || stderr.contains("no route to host")
+        || stderr.contains("connection refused")
+        || stderr.contains("could not resolve hostname")
+        || stderr.contains("network is unreachable")
+    {
+        Some(Issue::StaleAddress)
+    } else {
+        None
+    }
+}
+
+/// Find the HostName and IdentityFile for a host in ssh config content
+pub fn parse_host_entry(content: &str, host: &str) -> Option<HostEntry> {
+    let mut in_target_block = false;
+    let mut entry: Option<HostEntry> = None;
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+
+        if let Some(name) = trimmed.strip_prefix("Host ") {
+            if entry.is_some() {
+                break;
+            }
+            in_target_block = name.trim() == host;
+            if in_target_block {
+                entry = Some(HostEntry {
+                    hostname: None,
+                    identity_file: None,
+                    identity: None,
+                });
+            }
+            continue;
+        }
+
+        if !in_target_block {
+            continue;
+        }
+
+        if let Some(entry) = entry.as_mut() {
+            if let Some(value) = trimmed.strip_prefix("HostName ") {
+                entry.hostname = Some(ssh_config::parse_arg(value));
+            } else if let Some(value) = trimmed.strip_prefix("IdentityFile ") {
+                entry.identity_file = Some(ssh_config::parse_arg(value));
+            } else if let Some(value) = trimmed.strip_prefix(IDENTITY_MARKER) {
+                entry.identity = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    entry
+}
+
+fn ssh_config_path() -> Result<PathBuf> {
+    Ok(paths::ssh_config_file()?)
+}
+
+fn load_host_entry(host: &str) -> Result<HostEntry> {
+    let config_path = ssh_config_path()?;
+    let content = std::fs::read_to_string(&config_path).unwrap_or_default();
+    parse_host_entry(&content, host)
+        .ok_or_else(|| anyhow!("Host '{}' not found in SSH config", host))
+}
+
+/// Whether a discovered device is the one paired under `host`
+///
+/// Host aliases are the sanitized device name; mDNS names also carry the
+/// advertiser's hostname in parentheses, which is ignored here.
+pub fn matches_host(device: &DiscoveredDevice, host: &str) -> bool {
+    let full = device
+        .name
+        .split("._connecto")
+        .next()
+        .unwrap_or(&device.name);
+    let short = full.rsplit_once(" (").map(|(name, _)| name).unwrap_or(full);
+    host_alias(short) == host || host_alias(full) == host
+}
+
+/// Whether a discovered device is the one behind `entry`
+///
+/// Entries bound to an identity are matched on it, so a renamed device is
+/// still found; otherwise the device name is compared with the alias.
+fn is_paired_device(device: &DiscoveredDevice, host: &str, entry: &HostEntry) -> bool {
+    match (&entry.identity, &device.identity) {
+        (Some(expected), Some(found)) => expected == found,
+        _ => matches_host(device, host),
+    }
+}
+
+/// Look for the host on the network and point its SSH config entry at the new address
+async fn refresh_address(host: &str, entry: &HostEntry) -> Result<bool> {
+    let spinner = Progress::spinner("cyan");
+    spinner.set_message(format!("Looking for {} on the network...", host));
+    spinner.enable_steady_tick();
+
+    let mut devices = match ServiceBrowser::new() {
+        Ok(browser) => browser
+            .scan_for_duration(Duration::from_secs(REDISCOVER_TIMEOUT_SECS))
+            .await
+            .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+
+    if !devices.iter().any(|d| is_paired_device(d, host, entry)) {
+        let scanner = SubnetScanner::new(DEFAULT_PORT, Duration::from_millis(500));
+        devices = scanner.scan().await;
+    }
+
+    spinner.finish_and_clear();
+
+    let new_ip = devices
+        .iter()
+        .find(|d| is_paired_device(d, host, entry))
+        .and_then(|d| d.primary_host());
+
+    let Some(new_ip) = new_ip else {
+        error(&format!(
+            "Could not find '{}' on the network. Is it running 'connecto listen'?",
+            host
+        ));
+        return Ok(false);
+    };
+
+    if entry.hostname.as_deref() == Some(new_ip.as_str()) {
+        info(&format!(
+            "'{}' is still at {}; its address is not the problem.",
+            host, new_ip
+        ));
+        return Ok(false);
+    }
+
+    crate::run_update_ip(host, &new_ip)?;
+    if let Err(e) = super::scan::follow_renames(&devices, false) {
+        warn(&format!("Failed to record device names: {}", e));
+    }
+    Ok(true)
+}
+
+/// Restrict permissions on the host's private key and the ~/.ssh directory
+#[cfg(unix)]
+fn fix_key_permissions(entry: &HostEntry) -> Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Some(identity) = entry.identity_file.as_deref() else {
+        error("No IdentityFile configured for this host.");
+        return Ok(false);
+    };
+    let key_path = paths::expand_home(identity)?;
+
+    if let Some(ssh_dir) = key_path.parent() {
+        std::fs::set_permissions(ssh_dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o600))?;
+    let public_path = PathBuf::from(format!("{}.pub", key_path.display()));
+    if public_path.exists() {
+        std::fs::set_permissions(&public_path, std::fs::Permissions::from_mode(0o644))?;
+    }
+
+    success(&format!(
+        "Fixed permissions on {}",
+        key_path.display().to_string().cyan()
+    ));
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn fix_key_permissions(_entry: &HostEntry) -> Result<bool> {
+    warn("Automatic permission repair is only supported on macOS and Linux.");
+    Ok(false)
+}
+
+/// Load the host's private key into the running SSH agent
+fn add_key_to_agent(entry: &HostEntry) -> Result<bool> {
+    let Some(identity) = entry.identity_file.as_deref() else {
+        error("No IdentityFile configured for this host.");
+        return Ok(false);
+    };
+    let key_path = paths::expand_home(identity)?;
+
+    if !Path::new(&key_path).exists() {
+        error(&format!(
+            "Key file not found: {}. Re-pair with 'connecto pair'.",
+            key_path.display()
+        ));
+        return Ok(false);
+    }
+
+    let status = Command::new("ssh-add").arg(&key_path).status();
+    match status {
+        Ok(status) if status.success() => {
+            success("Key added to the SSH agent.");
+            Ok(true)
+        }
+        Ok(_) => {
+            error("ssh-add failed. Is the SSH agent running?");
+            Ok(false)
+        }
+        Err(e) => {
+            error(&format!("Failed to run ssh-add: {}", e));
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_diagnose_stale_address() {
+        let stderr = "ssh: connect to host 192.168.1.5 port 22: Connection timed out";
+        assert_eq!(diagnose(stderr), Some(Issue::StaleAddress));
+        let stderr = "ssh: connect to host 192.168.1.5 port 22: No route to host";
+        assert_eq!(diagnose(stderr), Some(Issue::StaleAddress));
+    }
+
+    #[test]
+    fn test_diagnose_key_permissions() {
+        let stderr = "@ WARNING: UNPROTECTED PRIVATE KEY FILE! @\n\
+                      Permissions 0644 for '/home/me/.ssh/connecto_desk' are too open.";
+        assert_eq!(diagnose(stderr), Some(Issue::KeyPermissions));
+    }
+
+    #[test]
+    fn test_diagnose_key_rejected() {
+        let stderr = "me@192.168.1.5: Permission denied (publickey,password).";
+        assert_eq!(diagnose(stderr), Some(Issue::KeyNotLoaded));
+    }
+
+    #[test]
+    fn test_diagnose_unknown() {
+        assert_eq!(diagnose("Host key verification failed."), None);
+        assert_eq!(diagnose(""), None);
+    }
+
+    #[test]
+    fn test_classify() {
+        let failure = CheckFailure::Connect("Connection refused".to_string());
+        assert_eq!(classify(&failure), Some(Issue::StaleAddress));
+        let failure = CheckFailure::KeyPermissions(PathBuf::from("/home/me/.ssh/connecto_desk"));
+        assert_eq!(classify(&failure), Some(Issue::KeyPermissions));
+        let failure = CheckFailure::Rejected {
+            methods: vec!["publickey".to_string()],
+        };
+        assert_eq!(classify(&failure), Some(Issue::KeyNotLoaded));
+
+        let failure = CheckFailure::HostKeyMismatch {
+            presented: "SHA256:new".to_string(),
+            known: vec!["SHA256:old".to_string()],
+        };
+        assert_eq!(classify(&failure), None);
+    }
+
+    #[test]
+    fn test_parse_host_entry() {
+        let content = "\
+Host *
+    ServerAliveInterval 60
+
+# Added by connecto
+Host desk
+    HostName 192.168.1.5
+    User me
+    # connecto-identity SHA256:desk
+    IdentityFile ~/.ssh/connecto_desk
+
+# Added by connecto
+Host laptop
+    HostName 192.168.1.6
+    User me
+    IdentityFile ~/.ssh/connecto_laptop
+";
+        let entry = parse_host_entry(content, "desk").unwrap();
+        assert_eq!(entry.hostname.as_deref(), Some("192.168.1.5"));
+        assert_eq!(entry.identity_file.as_deref(), Some("~/.ssh/connecto_desk"));
+        assert_eq!(entry.identity.as_deref(), Some("SHA256:desk"));
+
+        let entry = parse_host_entry(content, "laptop").unwrap();
+        assert_eq!(entry.hostname.as_deref(), Some("192.168.1.6"));
+        assert_eq!(entry.identity, None);
+
+        assert!(parse_host_entry(content, "missing").is_none());
+    }
+
+    #[test]
+    fn test_matches_host() {
+        let device = DiscoveredDevice {
+            name: "My Desk (desk-host)._connecto._tcp.local.".to_string(),
+            hostname: "desk-host.local.".to_string(),
+            addresses: vec![IpAddr::from([192, 168, 1, 5])],
+            port: DEFAULT_PORT,
+            instance_name: "My Desk (desk-host)._connecto._tcp.local.".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        };
+        assert!(matches_host(&device, "my_desk"));
+        assert!(matches_host(&device, "my_desk__desk-host_"));
+        assert!(!matches_host(&device, "laptop"));
+    }
+
+    #[test]
+    fn test_is_paired_device_prefers_identity() {
+        let device = DiscoveredDevice {
+            name: "Renamed Desk (desk-host)._connecto._tcp.local.".to_string(),
+            hostname: "desk-host.local.".to_string(),
+            addresses: vec![IpAddr::from([192, 168, 1, 9])],
+            port: DEFAULT_PORT,
+            instance_name: "Renamed Desk (desk-host)._connecto._tcp.local.".to_string(),
+            identity: Some("SHA256:desk".to_string()),
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        };
+        let mut entry = HostEntry {
+            hostname: Some("192.168.1.5".to_string()),
+            identity_file: None,
+            identity: Some("SHA256:desk".to_string()),
+        };
+        assert!(is_paired_device(&device, "my_desk", &entry));
+
+        entry.identity = Some("SHA256:other".to_string());
+        assert!(!is_paired_device(&device, "renamed_desk", &entry));
+
+        // Unbound entries fall back to the name
+        entry.identity = None;
+        assert!(is_paired_device(&device, "renamed_desk", &entry));
+    }
+}
@@ -0,0 +1,405 @@
+//! Send and receive commands - Transfer files between paired devices
+
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use connecto_core::{
+    identity::{DeviceIdentity, VerifiedIdentity},
+    net,
+    ssh_config::SshConfig,
+    transfer::{FileReceiver, FileSender, TransferEvent, TransferRequest},
+    trust::{TrustMode, TrustStore},
+};
+use directories::UserDirs;
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::{error, info, is_yes, port_error, stdin_lines, success, warn};
+use crate::config::Config;
+use crate::device_cache::DeviceCache;
+use crate::output::{banner, mark, Progress};
+
+pub async fn send(
+    device: String,
+    files: Vec<PathBuf>,
+    port: u16,
+    accept_new_identity: bool,
+) -> Result<()> {
+    let config = Config::load().unwrap_or_default();
+    let address = transfer_address(&device, port)?;
+
+    let mut sender = FileSender::new(&config.device_name());
+    match DeviceIdentity::load_or_create() {
+        Ok(identity) => sender = sender.with_identity(&identity),
+        Err(e) => warn(&format!("Could not load device identity: {}", e)),
+    }
+    match TrustStore::new() {
+        Ok(store) => sender = sender.with_trust_store(store),
+        Err(e) => warn(&format!("Device identities will not be checked: {}", e)),
+    }
+    if accept_new_identity {
+        sender = sender.with_trust_mode(TrustMode::Warn);
+    }
+
+    let total: u64 = connecto_core::transfer::offer_files(&files)?
+        .iter()
+        .map(|file| file.size)
+        .sum();
+    info(&format!(
+        "Offering {} ({}) to {}...",
+        count_files(files.len()),
+        format_size(total),
+        address.cyan()
+    ));
+
+    let (event_tx, mut event_rx) = mpsc::channel(32);
+    let event_handler = tokio::spawn(async move {
+        let mut progress = None;
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                TransferEvent::Accepted { device_name } => {
+                    info(&format!("{} accepted, sending...", device_name.cyan()));
+                }
+                TransferEvent::Progress { name, bytes, size } => {
+                    show_progress(&mut progress, "cyan", &name, bytes, size);
+                }
+                TransferEvent::FileCompleted { file } => {
+                    clear_progress(&mut progress);
+                    success(&format!("Sent {} ({})", file.name, format_size(file.size)));
+                }
+                _ => {}
+            }
+        }
+        clear_progress(&mut progress);
+    });
+
+    let result = tokio::select! {
+        result = sender.send(&address, &files, event_tx) => result,
+        _ = tokio::signal::ctrl_c() => {
+            println!();
+            info("Transfer cancelled");
+            return Ok(());
+        }
+    };
+    let _ = event_handler.await;
+
+    let result = result?;
+    println!();
+    success(&format!(
+        "Sent {} ({}) to {}",
+        count_files(result.files.len()),
+        format_size(result.total_bytes()),
+        result.peer_name.green().bold()
+    ));
+    Ok(())
+}
+
+pub async fn receive(
+    port: u16,
+    dir: Option<PathBuf>,
+    continuous: bool,
+    approval_timeout: u64,
+) -> Result<()> {
+    let config = Config::load().unwrap_or_default();
+    let device_name = config.device_name();
+    let dir = dir.unwrap_or_else(download_dir);
+
+    println!();
+    banner("CONNECTO RECEIVE", |s| s.on_bright_green().white().bold());
+    println!();
+
+    let mut receiver = FileReceiver::new(&device_name, &dir);
+    match DeviceIdentity::load_or_create() {
+        Ok(identity) => receiver = receiver.with_identity(&identity),
+        Err(e) => warn(&format!("Could not load device identity: {}", e)),
+    }
+    match TrustStore::new() {
+        Ok(store) => receiver = receiver.with_trust_store(store),
+        Err(e) => warn(&format!("Device identities will not be checked: {}", e)),
+    }
+    // Without a terminal to ask on, only trusted devices get through
+    let approver = if std::io::stdin().is_terminal() {
+        let (approval_tx, approval_rx) = mpsc::channel(1);
+        receiver = receiver
+            .with_approval(approval_tx)
+            .with_approval_timeout(Duration::from_secs(approval_timeout));
+        Some(tokio::spawn(answer_transfers(approval_rx)))
+    } else {
+        None
+    };
+
+    let addr = receiver
+        .listen(port)
+        .await
+        .map_err(|e| port_error(e, "connecto receive"))?;
+    info(&format!("Device name: {}", device_name.cyan()));
+    info(&format!("Port: {}", addr.port().to_string().cyan()));
+    info(&format!(
+        "Saving to: {}",
+        dir.display().to_string().dimmed()
+    ));
+    if approver.is_none() {
+        info("Accepting files from trusted devices only");
+    }
+    println!();
+    println!(
+        "  {} Send files here with {}",
+        mark("→").cyan(),
+        format!("connecto send {} <FILE>...", device_name).cyan()
+    );
+    println!("{}", "Press Ctrl+C to stop".dimmed());
+    println!();
+
+    let asks = approver.is_some();
+    let (event_tx, mut event_rx) = mpsc::channel(32);
+    let event_handler = tokio::spawn(async move {
+        let mut progress = None;
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                TransferEvent::Started { address } => {
+                    info(&format!("Waiting for files on {}", address));
+                }
+                TransferEvent::Offered {
+                    device_name,
+                    address,
+                    files,
+                } => {
+                    let total = files.iter().map(|file| file.size).sum();
+                    info(&format!(
+                        "{} ({}) offers {} ({})",
+                        device_name.cyan().bold(),
+                        address.ip(),
+                        count_files(files.len()),
+                        format_size(total)
+                    ));
+                }
+                TransferEvent::Progress { name, bytes, size } => {
+                    show_progress(&mut progress, "green", &name, bytes, size);
+                }
+                TransferEvent::FileCompleted { file } => {
+                    clear_progress(&mut progress);
+                    success(&format!(
+                        "Received {} ({})",
+                        file.path.display(),
+                        format_size(file.size)
+                    ));
+                }
+                TransferEvent::Completed { result } => {
+                    success(&format!(
+                        "Received {} from {}",
+                        count_files(result.files.len()),
+                        result.peer_name.green().bold()
+                    ));
+                }
+                TransferEvent::Declined {
+                    device_name,
+                    reason,
+                } => {
+                    warn(&format!("Declined files from {}: {}", device_name, reason));
+                    if !asks {
+                        println!(
+                            "  {} Accept files from it without asking: {}",
+                            mark("→").cyan(),
+                            format!("connecto trust set \"{}\" trusted", device_name).cyan()
+                        );
+                    }
+                }
+                TransferEvent::Failed { message } => {
+                    clear_progress(&mut progress);
+                    error(&format!("Transfer failed: {}", message));
+                }
+                TransferEvent::Accepted { .. } => {}
+            }
+        }
+    });
+
+    let result = tokio::select! {
+        result = async {
+            if continuous {
+                receiver.run(event_tx).await
+            } else {
+                receiver.handle_one(event_tx).await
+            }
+        } => result,
+        _ = tokio::signal::ctrl_c() => {
+            println!();
+            info("Shutting down...");
+            Ok(())
+        }
+    };
+    // Let the last events through before returning
+    let _ = event_handler.await;
+    if let Some(approver) = approver {
+        approver.abort();
+    }
+    result?;
+    Ok(())
+}
+
+/// Where `device` receives files: a paired host, a device number from the
+/// last scan, or a HOST[:PORT] address
+fn transfer_address(device: &str, port: u16) -> Result<String> {
+    if let Ok(index) = device.parse::<usize>() {
+        let scan = DeviceCache::new(&Config::load().unwrap_or_default())
+            .fresh()
+            .map_err(|e| anyhow!("{}, or give a paired host or address.", e))?;
+        let device = scan
+            .devices
+            .get(index)
+            .ok_or_else(|| anyhow!("Invalid device number {}", index))?;
+        let addr = device
+            .primary_address()
+            .ok_or_else(|| anyhow!("Device {} has no IP address", device.name))?;
+        return Ok(net::format_address(addr, port, device.scope.as_deref()));
+    }
+    if let Ok(ssh_config) = SshConfig::new() {
+        if let Some(entry) = ssh_config
+            .entries()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|entry| entry.host == device)
+        {
+            return Ok(net::join_host_port(&entry.hostname, port));
+        }
+    }
+    Ok(address_with_port(device, port))
+}
+
+/// `address`, with `port` unless it already names one
+fn address_with_port(address: &str, port: u16) -> String {
+    if net::host_of(address) != address {
+        address.to_string()
+    } else {
+        net::join_host_port(address, port)
+    }
+}
+
+/// The user's downloads folder, or the current directory
+fn download_dir() -> PathBuf {
+    UserDirs::new()
+        .and_then(|dirs| dirs.download_dir().map(|dir| dir.to_path_buf()))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Ask the user about each offer from a device that is not trusted
+async fn answer_transfers(mut approval_rx: mpsc::Receiver<TransferRequest>) {
+    let mut lines = stdin_lines();
+    while let Some(mut request) = approval_rx.recv().await {
+        // Ignore anything typed while no prompt was shown
+        while lines.try_recv().is_ok() {}
+
+        println!();
+        println!("{}", "Incoming files".yellow().bold());
+        println!(
+            "  {} Device:   {}",
+            mark("•").cyan(),
+            request.device_name.bold()
+        );
+        println!("  {} IP:       {}", mark("•").cyan(), request.address.ip());
+        println!(
+            "  {} Identity: {}",
+            mark("•").cyan(),
+            request
+                .identity
+                .as_ref()
+                .map_or("(none)", VerifiedIdentity::fingerprint)
+        );
+        for file in &request.files {
+            println!(
+                "  {} {} ({})",
+                mark("•").cyan(),
+                file.name,
+                format_size(file.size).dimmed()
+            );
+        }
+        print!(
+            "{} Accept {} from {}? [y/N] ",
+            "?".yellow().bold(),
+            count_files(request.files.len()),
+            request.device_name.bold()
+        );
+        let _ = std::io::stdout().flush();
+
+        let answer = tokio::select! {
+            line = lines.recv() => line,
+            // Timed out, or the sender gave up
+            _ = request.closed() => {
+                println!();
+                continue;
+            }
+        };
+        request.respond(answer.as_deref().is_some_and(is_yes));
+    }
+}
+
+/// Show how much of the file `name` is done, on a bar started for its
+/// first chunk
+fn show_progress(progress: &mut Option<Progress>, color: &str, name: &str, bytes: u64, size: u64) {
+    let progress = progress.get_or_insert_with(|| {
+        let bar = Progress::bar(color, "bytes");
+        bar.set_message(name.to_string());
+        bar.set_length(size);
+        bar
+    });
+    progress.set_position(bytes);
+}
+
+fn clear_progress(progress: &mut Option<Progress>) {
+    if let Some(progress) = progress.take() {
+        progress.finish_and_clear();
+    }
+}
+
+/// "1 file" or "3 files"
+fn count_files(count: usize) -> String {
+    match count {
+        1 => "1 file".to_string(),
+        n => format!("{} files", n),
+    }
+}
+
+/// A size in bytes for people, e.g. "3.4 MB"
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(1500), "1.5 KB");
+        assert_eq!(format_size(3_400_000), "3.4 MB");
+        assert_eq!(format_size(2_000_000_000_000), "2.0 TB");
+    }
+
+    #[test]
+    fn test_address_with_port() {
+        assert_eq!(address_with_port("192.168.1.20", 8097), "192.168.1.20:8097");
+        assert_eq!(
+            address_with_port("192.168.1.20:9000", 8097),
+            "192.168.1.20:9000"
+        );
+        assert_eq!(address_with_port("fe80::1", 8097), "[fe80::1]:8097");
+    }
+
+    #[test]
+    fn test_count_files() {
+        assert_eq!(count_files(1), "1 file");
+        assert_eq!(count_files(2), "2 files");
+    }
+}
@@ -0,0 +1,95 @@
+//! Trust command - Decide what listeners ask of each device
+
+use crate::TrustAction;
+use anyhow::Result;
+use colored::Colorize;
+use connecto_core::clock::{format_utc, UTC_DISPLAY_FORMAT};
+use connecto_core::trust::{TrustLevel, TrustStore};
+use serde::{Deserialize, Serialize};
+
+use super::success;
+use super::table::Table;
+use crate::output::mark;
+
+/// Trust levels that can be set for a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// Pair without a verification code or approval
+    Trusted,
+    /// Enter a verification code
+    Known,
+    /// Enter a verification code and be approved (with `listen --approve`)
+    Unknown,
+}
+
+impl From<Level> for TrustLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Trusted => Self::Trusted,
+            Level::Known => Self::Known,
+            Level::Unknown => Self::Unknown,
+        }
+    }
+}
+
+pub fn run(action: Option<TrustAction>, plain: bool) -> Result<()> {
+    let store = TrustStore::new()?;
+
+    match action {
+        None | Some(TrustAction::List) => list(&store, plain),
+        Some(TrustAction::Set { device, level }) => set(&store, &device, level.into()),
+    }
+}
+
+fn list(store: &TrustStore, plain: bool) -> Result<()> {
+    let mut table = Table::new(["DEVICE", "LEVEL", "FINGERPRINT", "LAST SEEN"])
+        .style(0, |s| s.cyan())
+        .style(2, |s| s.dimmed())
+        .style(3, |s| s.dimmed());
+    for (name, peer) in store.all()? {
+        table.push_row(vec![
+            name,
+            peer.trust_level().to_string(),
+            peer.fingerprint.unwrap_or_else(|| "-".to_string()),
+            format_utc(peer.last_seen, UTC_DISPLAY_FORMAT),
+        ]);
+    }
+
+    if plain {
+        table.print(true);
+        return Ok(());
+    }
+
+    if table.is_empty() {
+        println!("{}", "No known devices.".dimmed());
+        println!(
+            "  {} Devices become known when you pair with them, or run: {}",
+            mark("→").cyan(),
+            "connecto trust set <device> trusted".cyan()
+        );
+        return Ok(());
+    }
+
+    println!("{}", "Known devices:".bold());
+    println!();
+    table.print(false);
+    println!();
+    Ok(())
+}
+
+fn set(store: &TrustStore, device: &str, level: TrustLevel) -> Result<()> {
+    store.set_level(device, level)?;
+    let effect = match level {
+        TrustLevel::Trusted => "pairs without a verification code or approval",
+        TrustLevel::Known => "enters a verification code to pair",
+        TrustLevel::Unknown => "enters a verification code and needs approval to pair",
+    };
+    success(&format!(
+        "{} is now {}: it {}",
+        device.cyan(),
+        level.to_string().bold(),
+        effect
+    ));
+    Ok(())
+}
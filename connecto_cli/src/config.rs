@@ -1,13 +1,64 @@
 //! Configuration management for Connecto CLI
 
-use anyhow::{Context, Result};
-use directories::ProjectDirs;
+use crate::commands::scan::{ScanColumn, ScanSort};
+use crate::policy::{self, Policy};
+use anyhow::{bail, Context, Result};
+use connecto_core::{
+    access::AccessList, discovery::get_device_name, keys::KeyAlgorithm, paths,
+    ssh_config::TagTemplates, DEFAULT_PORT,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Environment variable that picks a profile, like `--profile`
+pub const PROFILE_ENV: &str = "CONNECTO_PROFILE";
+
+/// Name that stands for the settings outside any profile
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Profile picked for this run with `--profile`, over the one in use
+static PROFILE_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+/// Use the profile `name` for this run, whatever `config use-profile` chose
+pub fn set_profile_override(name: &str) {
+    let _ = PROFILE_OVERRIDE.set(name.to_string());
+}
+
+/// Settings that belong to a network, kept apart per profile
+///
+/// Everything else in the config is shared by all profiles.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub subnets: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_key: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "AccessList::is_empty")]
+    pub listen_access: AccessList,
+}
+
+/// The profile whose settings are in a loaded [`Config`]'s own fields
+#[derive(Debug, Clone)]
+struct ActiveProfile {
+    name: String,
+    /// The settings outside any profile, set aside while it is in use
+    default: Profile,
+}
 
 /// Connecto CLI configuration
-#[derive(Debug, Default, Serialize, Deserialize)]
+///
+/// While a profile is in use, its settings take the place of
+/// `subnets`, `default_key`, `port` and `listen_access`, so everything
+/// reading those follows the profile; [`Config::save`] puts them back.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Config {
     /// Additional subnets to scan (in CIDR notation)
     #[serde(default)]
@@ -16,31 +67,189 @@ pub struct Config {
     /// Default SSH key to use for pairing (path to private key)
     #[serde(default)]
     pub default_key: Option<String>,
+
+    /// Port to use for listen, pair, scan and sync when none is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// Name to announce to other devices instead of the OS device name
+    #[serde(default)]
+    pub device_name: Option<String>,
+
+    /// Scan output preferences
+    #[serde(default)]
+    pub scan: ScanConfig,
+
+    /// Defaults for `connecto listen`
+    #[serde(default)]
+    pub listen: ListenConfig,
+
+    /// Defaults for `connecto pair`
+    #[serde(default)]
+    pub pair: PairConfig,
+
+    /// SSH options added to paired hosts by tag
+    #[serde(default, skip_serializing_if = "TagTemplates::is_empty")]
+    pub ssh_templates: TagTemplates,
+
+    /// Which clients `connecto listen` answers, on top of its flags
+    #[serde(default, skip_serializing_if = "AccessList::is_empty")]
+    pub listen_access: AccessList,
+
+    /// Profile picked with `connecto config use-profile`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+
+    /// Named profiles, e.g. home, office and lab
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+
+    /// Policy from the machine-level config layer, if one is installed
+    #[serde(skip)]
+    pub policy: Option<Policy>,
+
+    #[serde(skip)]
+    active: Option<ActiveProfile>,
+}
+
+/// Default output and subnet probe settings for `connecto scan`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScanConfig {
+    /// Columns to display (empty means the built-in default)
+    #[serde(default)]
+    pub columns: Vec<ScanColumn>,
+
+    /// Sort order for discovered devices
+    #[serde(default)]
+    pub sort: Option<ScanSort>,
+
+    /// Hosts a subnet scan probes at the same time
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+
+    /// Most subnet scan probes started per second
+    #[serde(default)]
+    pub rate: Option<u32>,
+
+    /// How long to wait for each host during a subnet scan, in milliseconds
+    #[serde(default)]
+    pub probe_timeout_ms: Option<u64>,
+
+    /// How long scan results can be paired with by number, in seconds
+    #[serde(default)]
+    pub cache_ttl_secs: Option<u64>,
+
+    /// How long to scan, in seconds
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+
+    /// Devices after which the mDNS search stops
+    #[serde(default)]
+    pub min_devices: Option<usize>,
+
+    /// How long the mDNS search waits for another device after the last
+    /// new one, in milliseconds
+    #[serde(default)]
+    pub quiet_period_ms: Option<u64>,
+}
+
+/// Defaults for `connecto listen`; its flags override them
+///
+/// A setting of `true` turns the option on as if its flag were always given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListenConfig {
+    /// Port to listen on, over the config's own port
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// Name to announce while listening, over the device name
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Require a verification code
+    #[serde(default)]
+    pub verify: bool,
+
+    /// Keep listening after the first pairing
+    #[serde(default)]
+    pub continuous: bool,
+
+    /// Ask before accepting pairing requests from unknown devices
+    #[serde(default)]
+    pub approve: bool,
+
+    /// Advertise only the name, not the hostname
+    #[serde(default)]
+    pub private: bool,
+
+    /// Remove expired keys while listening
+    #[serde(default)]
+    pub prune: bool,
+}
+
+/// Defaults for `connecto pair`; its flags override them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PairConfig {
+    /// Type of key to generate, e.g. `ecdsa-p256`
+    #[serde(default)]
+    pub key_type: Option<String>,
+
+    /// Have devices accept keys only for this long, e.g. `30d`
+    #[serde(default)]
+    pub expires: Option<String>,
+
+    /// Tags for new hosts, when `--tag` is not given
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Write mDNS hostnames to `~/.ssh/config` instead of IPs
+    #[serde(default)]
+    pub mdns: bool,
+}
+
+impl PairConfig {
+    /// The configured key type, if any
+    pub fn key_type(&self) -> Result<Option<KeyAlgorithm>> {
+        self.key_type
+            .as_deref()
+            .map(|name| {
+                name.parse()
+                    .with_context(|| format!("Invalid pair.key_type in the config: {}", name))
+            })
+            .transpose()
+    }
 }
 
 impl Config {
     /// Get the config file path
     pub fn path() -> Result<PathBuf> {
-        let proj_dirs = ProjectDirs::from("com", "connecto", "connecto")
-            .context("Could not determine config directory")?;
-
-        Ok(proj_dirs.config_dir().join("config.json"))
+        Ok(paths::config_file("config.json")?)
     }
 
     /// Load config from file, or return default if not exists
+    ///
+    /// The profile picked with `--profile`, [`PROFILE_ENV`] or
+    /// `config use-profile` is put in use, and the machine-level policy, if
+    /// installed, is layered on top.
     pub fn load() -> Result<Self> {
         let path = Self::path()?;
 
-        if !path.exists() {
-            return Ok(Self::default());
-        }
-
-        let content = fs::read_to_string(&path)
-            .with_context(|| format!("Failed to read config from {}", path.display()))?;
-
-        let config: Config =
-            serde_json::from_str(&content).with_context(|| "Failed to parse config file")?;
+        let mut config: Config = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config from {}", path.display()))?;
+            serde_json::from_str(&content).with_context(|| "Failed to parse config file")?
+        } else {
+            Self::default()
+        };
 
+        let profile = PROFILE_OVERRIDE
+            .get()
+            .cloned()
+            .or_else(|| config.profile.clone());
+        if let Some(name) = profile {
+            config.activate(&name)?;
+        }
+        config.policy = policy::load_machine_policy();
         Ok(config)
     }
 
@@ -55,7 +264,8 @@ impl Config {
             })?;
         }
 
-        let content = serde_json::to_string_pretty(self).context("Failed to serialize config")?;
+        let content =
+            serde_json::to_string_pretty(&self.stored()).context("Failed to serialize config")?;
 
         fs::write(&path, content)
             .with_context(|| format!("Failed to write config to {}", path.display()))?;
@@ -63,6 +273,104 @@ impl Config {
         Ok(())
     }
 
+    /// Put the profile `name` in use
+    ///
+    /// [`DEFAULT_PROFILE`] stands for the settings outside any profile.
+    pub fn activate(&mut self, name: &str) -> Result<()> {
+        if self.active_profile() == Some(name) {
+            return Ok(());
+        }
+        self.deactivate();
+        if name == DEFAULT_PROFILE {
+            return Ok(());
+        }
+        let Some(profile) = self.profiles.remove(name) else {
+            bail!(
+                "No profile named '{}'; create it with `connecto config add-profile {}`",
+                name,
+                name
+            );
+        };
+        let default = self.swap_profile(profile);
+        self.active = Some(ActiveProfile {
+            name: name.to_string(),
+            default,
+        });
+        Ok(())
+    }
+
+    /// Name of the profile in use, if any
+    pub fn active_profile(&self) -> Option<&str> {
+        self.active.as_ref().map(|active| active.name.as_str())
+    }
+
+    /// Names of every profile, sorted
+    pub fn profile_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .profiles
+            .keys()
+            .map(String::as_str)
+            .chain(self.active_profile())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Create the empty profile `name`; returns false if it exists
+    pub fn add_profile(&mut self, name: &str) -> Result<bool> {
+        validate_profile_name(name)?;
+        if self.profile_names().contains(&name) {
+            return Ok(false);
+        }
+        self.profiles.insert(name.to_string(), Profile::default());
+        Ok(true)
+    }
+
+    /// Delete the profile `name`; returns false if there is none
+    pub fn remove_profile(&mut self, name: &str) -> Result<bool> {
+        if self.active_profile() == Some(name) {
+            bail!("Profile '{}' is in use; switch to another one first", name);
+        }
+        if self.profile.as_deref() == Some(name) {
+            self.profile = None;
+        }
+        Ok(self.profiles.remove(name).is_some())
+    }
+
+    /// Make `name` the profile every command uses
+    pub fn use_profile(&mut self, name: &str) -> Result<()> {
+        if name != DEFAULT_PROFILE && !self.profile_names().contains(&name) {
+            bail!("No profile named '{}'", name);
+        }
+        self.profile = (name != DEFAULT_PROFILE).then(|| name.to_string());
+        Ok(())
+    }
+
+    /// Put `profile`'s settings in place, returning the ones replaced
+    fn swap_profile(&mut self, profile: Profile) -> Profile {
+        Profile {
+            subnets: std::mem::replace(&mut self.subnets, profile.subnets),
+            default_key: std::mem::replace(&mut self.default_key, profile.default_key),
+            port: std::mem::replace(&mut self.port, profile.port),
+            listen_access: std::mem::replace(&mut self.listen_access, profile.listen_access),
+        }
+    }
+
+    /// Put the profile in use back among the others
+    fn deactivate(&mut self) {
+        if let Some(active) = self.active.take() {
+            let profile = self.swap_profile(active.default);
+            self.profiles.insert(active.name, profile);
+        }
+    }
+
+    /// The config as it is written to the file
+    fn stored(&self) -> Self {
+        let mut stored = self.clone();
+        stored.deactivate();
+        stored
+    }
+
     /// Add a subnet to the config
     pub fn add_subnet(&mut self, subnet: &str) -> bool {
         let subnet = subnet.to_string();
@@ -89,6 +397,141 @@ impl Config {
     pub fn clear_default_key(&mut self) {
         self.default_key = None;
     }
+
+    /// Set the port used when none is given on the command line
+    pub fn set_port(&mut self, port: u16) {
+        self.port = Some(port);
+    }
+
+    /// Go back to the built-in port
+    pub fn clear_port(&mut self) {
+        self.port = None;
+    }
+
+    /// Set the name announced to other devices
+    pub fn set_device_name(&mut self, name: &str) {
+        self.device_name = Some(name.to_string());
+    }
+
+    /// Go back to announcing the OS device name
+    pub fn clear_device_name(&mut self) {
+        self.device_name = None;
+    }
+
+    /// Make hosts tagged `tag` use `value` for the SSH option `option`
+    pub fn set_template(&mut self, tag: &str, option: &str, value: &str) {
+        let template = self.ssh_templates.entry(tag.to_string()).or_default();
+        // SSH option names are case-insensitive
+        template.retain(|name, _| !name.eq_ignore_ascii_case(option));
+        template.insert(option.to_string(), value.to_string());
+    }
+
+    /// Remove `option` from the template for `tag`, or the whole template
+    ///
+    /// Returns whether anything was removed.
+    pub fn remove_template(&mut self, tag: &str, option: Option<&str>) -> bool {
+        let Some(template) = self.ssh_templates.get_mut(tag) else {
+            return false;
+        };
+        let removed = match option {
+            Some(option) => {
+                let len_before = template.len();
+                template.retain(|name, _| !name.eq_ignore_ascii_case(option));
+                template.len() < len_before
+            }
+            None => {
+                template.clear();
+                true
+            }
+        };
+        if template.is_empty() {
+            self.ssh_templates.remove(tag);
+        }
+        removed
+    }
+
+    /// Add listener access rules; returns whether any were new
+    pub fn add_listen_access(&mut self, rules: &AccessList) -> bool {
+        let before = self.listen_access.clone();
+        self.listen_access.merge(rules);
+        self.listen_access != before
+    }
+
+    /// Remove the listener access rule written as `rule` from every list
+    /// that has it
+    pub fn remove_listen_access(&mut self, rule: &str) -> bool {
+        let access = &mut self.listen_access;
+        let before = access.clone();
+        access.allow.retain(|network| network.to_string() != rule);
+        access.deny.retain(|network| network.to_string() != rule);
+        access.allow_names.retain(|pattern| pattern != rule);
+        *access != before
+    }
+
+    /// Name to announce when none is given on the command line
+    pub fn device_name(&self) -> String {
+        self.device_name.clone().unwrap_or_else(get_device_name)
+    }
+
+    /// Port to use when none is given on the command line
+    ///
+    /// The machine policy's port wins, so a fleet keeps finding itself.
+    pub fn default_port(&self) -> u16 {
+        self.port_over(None)
+    }
+
+    /// Port for `connecto listen` when none is given on the command line
+    pub fn listen_port(&self) -> u16 {
+        self.port_over(self.listen.port)
+    }
+
+    fn port_over(&self, command_port: Option<u16>) -> u16 {
+        self.policy
+            .as_ref()
+            .and_then(|p| p.port)
+            .or(command_port)
+            .or(self.port)
+            .unwrap_or(DEFAULT_PORT)
+    }
+
+    /// Subnets to scan: the user's own plus those trusted by the machine policy
+    pub fn scan_subnets(&self) -> Vec<String> {
+        let mut subnets = self.subnets.clone();
+        if let Some(policy) = &self.policy {
+            for subnet in &policy.trusted_subnets {
+                if !subnets.contains(subnet) {
+                    subnets.push(subnet.clone());
+                }
+            }
+        }
+        subnets
+    }
+
+    /// Fail if the machine policy forbids keys of `algorithm`
+    pub fn check_algorithm(&self, algorithm: KeyAlgorithm) -> Result<()> {
+        match &self.policy {
+            Some(policy) => policy.check_algorithm(algorithm),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Check that `name` can name a profile
+fn validate_profile_name(name: &str) -> Result<()> {
+    if name == DEFAULT_PROFILE {
+        bail!("'{}' stands for the settings outside any profile", name);
+    }
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "'{}' is not a valid profile name; use letters, digits, - and _",
+            name
+        );
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -129,4 +572,199 @@ mod tests {
 
         assert_eq!(loaded.subnets, config.subnets);
     }
+
+    #[test]
+    fn test_device_name() {
+        let mut config = Config::default();
+        assert_eq!(config.device_name(), get_device_name());
+
+        config.set_device_name("Meeting room");
+        assert_eq!(config.device_name(), "Meeting room");
+        let json = serde_json::to_string(&config).unwrap();
+        let loaded: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.device_name.as_deref(), Some("Meeting room"));
+
+        config.clear_device_name();
+        assert_eq!(config.device_name(), get_device_name());
+    }
+
+    #[test]
+    fn test_ssh_templates() {
+        let mut config = Config::default();
+        config.set_template("prod", "StrictHostKeyChecking", "yes");
+        config.set_template("prod", "ForwardAgent", "yes");
+        config.set_template("prod", "forwardagent", "no");
+        config.set_template("lab", "ForwardAgent", "yes");
+
+        let prod = &config.ssh_templates["prod"];
+        assert_eq!(prod.len(), 2);
+        assert_eq!(prod["forwardagent"], "no");
+
+        let json = serde_json::to_string(&config).unwrap();
+        let loaded: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.ssh_templates, config.ssh_templates);
+
+        assert!(config.remove_template("prod", Some("FORWARDAGENT")));
+        assert!(!config.remove_template("prod", Some("ForwardAgent")));
+        assert!(config.remove_template("lab", None));
+        assert!(!config.remove_template("lab", None));
+        assert_eq!(config.ssh_templates.keys().collect::<Vec<_>>(), ["prod"]);
+
+        // The last option takes the template with it
+        assert!(config.remove_template("prod", Some("StrictHostKeyChecking")));
+        assert!(config.ssh_templates.is_empty());
+    }
+
+    #[test]
+    fn test_listen_access() {
+        let mut config = Config::default();
+        let rules = AccessList {
+            allow: vec!["10.0.0.0/24".parse().unwrap()],
+            allow_names: vec!["alice-*".to_string()],
+            ..Default::default()
+        };
+        assert!(config.add_listen_access(&rules));
+        assert!(!config.add_listen_access(&rules));
+
+        let json = serde_json::to_string(&config).unwrap();
+        let loaded: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.listen_access, rules);
+
+        assert!(config.remove_listen_access("10.0.0.0/24"));
+        assert!(!config.remove_listen_access("10.0.0.0/24"));
+        assert!(config.remove_listen_access("alice-*"));
+        assert!(config.listen_access.is_empty());
+        assert!(!serde_json::to_string(&config)
+            .unwrap()
+            .contains("listen_access"));
+    }
+
+    #[test]
+    fn test_policy_layer() {
+        let mut config = Config::default();
+        config.add_subnet("10.0.0.0/24");
+        assert_eq!(config.default_port(), DEFAULT_PORT);
+        assert!(config.check_algorithm(KeyAlgorithm::Rsa4096).is_ok());
+
+        config.policy = Some(Policy {
+            port: Some(9000),
+            allowed_algorithms: vec![policy::PolicyAlgorithm::Ed25519],
+            trusted_subnets: vec!["10.0.0.0/24".to_string(), "10.0.2.0/24".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(config.default_port(), 9000);
+        assert_eq!(config.scan_subnets(), vec!["10.0.0.0/24", "10.0.2.0/24"]);
+        assert!(config.check_algorithm(KeyAlgorithm::Rsa4096).is_err());
+
+        // The policy is never written to the user's config file
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(!json.contains("10.0.2.0/24"));
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut config = Config::default();
+        config.add_subnet("192.168.1.0/24");
+        assert!(config.add_profile("office").unwrap());
+        assert!(!config.add_profile("office").unwrap());
+        assert!(config.add_profile(DEFAULT_PROFILE).is_err());
+        assert!(config.add_profile("my office").is_err());
+        assert!(config.activate("lab").is_err());
+
+        config.activate("office").unwrap();
+        assert_eq!(config.active_profile(), Some("office"));
+        assert!(config.subnets.is_empty());
+        config.add_subnet("10.1.0.0/16");
+        config.set_port(9000);
+        assert_eq!(config.default_port(), 9000);
+        assert!(config.remove_profile("office").is_err());
+
+        // The file keeps the profile apart from the settings outside it
+        let stored = config.stored();
+        assert_eq!(stored.subnets, vec!["192.168.1.0/24"]);
+        assert_eq!(stored.port, None);
+        assert_eq!(stored.profiles["office"].subnets, vec!["10.1.0.0/16"]);
+        assert_eq!(stored.profiles["office"].port, Some(9000));
+
+        let json = serde_json::to_string(&stored).unwrap();
+        let mut loaded: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.profile_names(), vec!["office"]);
+        assert_eq!(loaded.default_port(), DEFAULT_PORT);
+        loaded.activate("office").unwrap();
+        assert_eq!(loaded.scan_subnets(), vec!["10.1.0.0/16"]);
+        loaded.activate(DEFAULT_PROFILE).unwrap();
+        assert_eq!(loaded.active_profile(), None);
+        assert_eq!(loaded.subnets, vec!["192.168.1.0/24"]);
+    }
+
+    #[test]
+    fn test_use_profile() {
+        let mut config = Config::default();
+        config.add_profile("home").unwrap();
+        assert!(config.use_profile("work").is_err());
+
+        config.use_profile("home").unwrap();
+        assert_eq!(config.profile.as_deref(), Some("home"));
+        config.use_profile(DEFAULT_PROFILE).unwrap();
+        assert!(config.profile.is_none());
+
+        config.use_profile("home").unwrap();
+        assert!(config.remove_profile("home").unwrap());
+        assert!(config.profile.is_none());
+        assert!(!config.remove_profile("home").unwrap());
+    }
+
+    #[test]
+    fn test_scan_config_serialization() {
+        let json = r#"{"scan": {"columns": ["name", "hostname"], "sort": "ip"}}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            config.scan.columns,
+            vec![ScanColumn::Name, ScanColumn::Hostname]
+        );
+        assert_eq!(config.scan.sort, Some(ScanSort::Ip));
+        assert!(config.scan.concurrency.is_none());
+
+        let json = r#"{"scan": {"concurrency": 500, "rate": 2000, "probe_timeout_ms": 300}}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(config.scan.concurrency, Some(500));
+        assert_eq!(config.scan.rate, Some(2000));
+        assert_eq!(config.scan.probe_timeout_ms, Some(300));
+
+        // Older config files without a scan section still load
+        let config: Config = serde_json::from_str(r#"{"subnets": []}"#).unwrap();
+        assert!(config.scan.columns.is_empty());
+        assert!(config.scan.sort.is_none());
+    }
+
+    #[test]
+    fn test_command_sections() {
+        let json = r#"{
+            "port": 9000,
+            "listen": {"port": 9100, "name": "Lab bench", "verify": true, "continuous": true},
+            "pair": {"key_type": "ecdsa-p256", "tags": ["lab"], "mdns": true},
+            "scan": {"timeout_secs": 10}
+        }"#;
+        let mut config: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(config.listen_port(), 9100);
+        assert_eq!(config.default_port(), 9000);
+        assert_eq!(config.listen.name.as_deref(), Some("Lab bench"));
+        assert!(config.listen.verify && config.listen.continuous && !config.listen.approve);
+        assert_eq!(
+            config.pair.key_type().unwrap(),
+            Some(KeyAlgorithm::EcdsaP256)
+        );
+        assert_eq!(config.pair.tags, vec!["lab"]);
+        assert_eq!(config.scan.timeout_secs, Some(10));
+
+        config.pair.key_type = Some("dsa".to_string());
+        assert!(config.pair.key_type().is_err());
+
+        // The machine policy's port still wins
+        config.policy = Some(Policy {
+            port: Some(8200),
+            ..Default::default()
+        });
+        assert_eq!(config.listen_port(), 8200);
+    }
 }
@@ -0,0 +1,293 @@
+//! Devices found by the last scan
+//!
+//! `connecto scan` numbers the devices it finds, and `pair`, `transfer` and
+//! `pair --all` refer to them by those numbers in later invocations. The
+//! results are kept together with the time of the scan and used only while
+//! they are fresh: devices move and leave, and a number from yesterday's scan
+//! may no longer name the machine the user remembers.
+//!
+//! A device that could not be reached when pairing is marked unreachable
+//! rather than dropped, so the numbers of the others stay as printed.
+
+use anyhow::{anyhow, Context, Result};
+use connecto_core::discovery::DiscoveredDevice;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+
+/// File to cache discovered devices for the pair command
+pub const CACHE_FILE: &str = "/tmp/connecto_devices.json";
+
+/// How long scan results are used when the config does not say
+pub const DEFAULT_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// The results of a scan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedScan {
+    /// When the scan ran, in seconds since the Unix epoch
+    pub scanned_at: u64,
+    /// The devices, in the order they were numbered
+    pub devices: Vec<DiscoveredDevice>,
+    /// Addresses a pairing failed to reach since the scan
+    #[serde(default)]
+    pub unreachable: Vec<String>,
+}
+
+impl CachedScan {
+    pub fn new(devices: Vec<DiscoveredDevice>) -> Self {
+        Self {
+            scanned_at: unix_now(),
+            devices,
+            unreachable: Vec::new(),
+        }
+    }
+
+    /// How long ago the scan ran
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.scanned_at))
+    }
+
+    /// Whether the results are older than `ttl`
+    pub fn is_stale(&self, ttl: Duration) -> bool {
+        self.age() > ttl
+    }
+
+    /// Whether a pairing failed to reach `device` since the scan
+    pub fn is_unreachable(&self, device: &DiscoveredDevice) -> bool {
+        device
+            .connection_string()
+            .is_some_and(|address| self.unreachable.contains(&address))
+    }
+}
+
+/// Where scan results are kept, and for how long they are used
+#[derive(Debug, Clone)]
+pub struct DeviceCache {
+    path: PathBuf,
+    ttl: Duration,
+}
+
+impl DeviceCache {
+    /// The cache shared by all commands, with the TTL from `config`
+    pub fn new(config: &Config) -> Self {
+        let ttl = config
+            .scan
+            .cache_ttl_secs
+            .map_or(DEFAULT_TTL, Duration::from_secs);
+        Self::with_path(PathBuf::from(CACHE_FILE), ttl)
+    }
+
+    /// Use a specific cache file
+    pub fn with_path(path: PathBuf, ttl: Duration) -> Self {
+        Self { path, ttl }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Replace the cache with the results of a new scan
+    pub fn save(&self, devices: &[DiscoveredDevice]) -> Result<()> {
+        self.write(&CachedScan::new(devices.to_vec()))
+    }
+
+    fn write(&self, scan: &CachedScan) -> Result<()> {
+        let json = serde_json::to_string(scan)?;
+        fs::write(&self.path, json)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    /// The last scan, fresh or not, or `None` if there was none
+    pub fn load(&self) -> Result<Option<CachedScan>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.path.display()))
+            }
+        };
+        if let Ok(scan) = serde_json::from_str(&content) {
+            return Ok(Some(scan));
+        }
+        // Older versions kept the bare list; the file's age is the scan's
+        let devices: Vec<DiscoveredDevice> = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", self.path.display()))?;
+        let scanned_at = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |age| age.as_secs());
+        Ok(Some(CachedScan {
+            scanned_at,
+            devices,
+            unreachable: Vec::new(),
+        }))
+    }
+
+    /// The last scan, if it is recent enough to pair by number
+    pub fn fresh(&self) -> Result<CachedScan> {
+        let scan = self
+            .load()
+            .ok()
+            .flatten()
+            .ok_or_else(|| anyhow!("No cached devices found. Run 'connecto scan' first"))?;
+        if scan.is_stale(self.ttl) {
+            return Err(anyhow!(
+                "The last scan ran {} and its results have expired. Run 'connecto scan' again",
+                format_age(scan.age())
+            ));
+        }
+        Ok(scan)
+    }
+
+    /// Mark `address` as unreachable in the cached results
+    ///
+    /// Returns whether a cached device has that address.
+    pub fn invalidate(&self, address: &str) -> Result<bool> {
+        let Some(mut scan) = self.load()? else {
+            return Ok(false);
+        };
+        let cached = scan
+            .devices
+            .iter()
+            .any(|device| device.connection_string().as_deref() == Some(address));
+        if !cached || scan.unreachable.iter().any(|a| a == address) {
+            return Ok(cached);
+        }
+        scan.unreachable.push(address.to_string());
+        self.write(&scan)?;
+        Ok(true)
+    }
+}
+
+/// How long ago something happened, e.g. `3 minutes ago`
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (amount, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3_599 => (secs / 60, "minute"),
+        3_600..=86_399 => (secs / 3_600, "hour"),
+        _ => (secs / 86_400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        amount,
+        unit,
+        if amount == 1 { "" } else { "s" }
+    )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use tempfile::TempDir;
+
+    fn device(name: &str, ip: [u8; 4]) -> DiscoveredDevice {
+        DiscoveredDevice {
+            name: format!("{}._connecto._tcp.local.", name),
+            hostname: format!("{}.local.", name),
+            addresses: vec![IpAddr::from(ip)],
+            port: 8099,
+            instance_name: format!("{}._connecto._tcp.local.", name),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        }
+    }
+
+    fn cache(temp_dir: &TempDir) -> DeviceCache {
+        DeviceCache::with_path(temp_dir.path().join("devices.json"), DEFAULT_TTL)
+    }
+
+    #[test]
+    fn test_cache_file_path() {
+        assert_eq!(CACHE_FILE, "/tmp/connecto_devices.json");
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let temp_dir = TempDir::new().unwrap();
+        let cache = cache(&temp_dir);
+        assert_eq!(cache.load().unwrap(), None);
+        assert!(cache.fresh().is_err());
+
+        cache
+            .save(&[device("desk", [10, 0, 0, 5]), device("lab", [10, 0, 0, 6])])
+            .unwrap();
+        let scan = cache.fresh().unwrap();
+        assert_eq!(scan.devices.len(), 2);
+        assert!(scan.age() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_expired_scan() {
+        let temp_dir = TempDir::new().unwrap();
+        let cache = cache(&temp_dir);
+        let mut scan = CachedScan::new(vec![device("desk", [10, 0, 0, 5])]);
+        scan.scanned_at -= 20 * 60;
+        cache.write(&scan).unwrap();
+
+        // Still shown by `scan --cached`, but no longer paired with by number
+        assert!(cache.load().unwrap().unwrap().is_stale(cache.ttl()));
+        let error = cache.fresh().unwrap_err().to_string();
+        assert!(error.contains("20 minutes ago"), "{}", error);
+
+        let patient = DeviceCache::with_path(cache.path.clone(), Duration::from_secs(3_600));
+        assert!(patient.fresh().is_ok());
+    }
+
+    #[test]
+    fn test_legacy_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let cache = cache(&temp_dir);
+        let devices = vec![device("desk", [10, 0, 0, 5])];
+        fs::write(&cache.path, serde_json::to_string(&devices).unwrap()).unwrap();
+
+        let scan = cache.fresh().unwrap();
+        assert_eq!(scan.devices, devices);
+        assert!(scan.unreachable.is_empty());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let temp_dir = TempDir::new().unwrap();
+        let cache = cache(&temp_dir);
+        assert!(!cache.invalidate("10.0.0.5:8099").unwrap());
+
+        cache
+            .save(&[device("desk", [10, 0, 0, 5]), device("lab", [10, 0, 0, 6])])
+            .unwrap();
+        assert!(cache.invalidate("10.0.0.5:8099").unwrap());
+        assert!(cache.invalidate("10.0.0.5:8099").unwrap());
+        assert!(!cache.invalidate("10.0.0.9:8099").unwrap());
+
+        // The others keep their numbers
+        let scan = cache.fresh().unwrap();
+        assert_eq!(scan.devices.len(), 2);
+        assert_eq!(scan.unreachable, ["10.0.0.5:8099"]);
+        assert!(scan.is_unreachable(&scan.devices[0]));
+        assert!(!scan.is_unreachable(&scan.devices[1]));
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_secs(12)), "just now");
+        assert_eq!(format_age(Duration::from_secs(60)), "1 minute ago");
+        assert_eq!(format_age(Duration::from_secs(20 * 60)), "20 minutes ago");
+        assert_eq!(format_age(Duration::from_secs(2 * 3_600)), "2 hours ago");
+        assert_eq!(format_age(Duration::from_secs(86_400)), "1 day ago");
+    }
+}
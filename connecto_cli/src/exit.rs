@@ -0,0 +1,107 @@
+//! Exit codes, so scripts can tell failures apart
+
+use connecto_core::ConnectoError;
+use std::io;
+
+/// Exit code of a failed command
+///
+/// Code 2 is left to clap, which uses it for invalid arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    /// Any failure without a code of its own
+    Failure = 1,
+    /// No device found, or mDNS discovery failed
+    Discovery = 3,
+    /// Nothing is listening at the peer's address
+    ConnectionRefused = 4,
+    /// Wrong verification code, or the peer's identity changed
+    VerificationFailed = 5,
+    /// A key with that name already exists
+    KeyExists = 6,
+    /// The file system or the SSH server refused access
+    PermissionDenied = 7,
+    /// The peer or the user did not answer in time
+    Timeout = 8,
+    /// The listener's user turned the pairing down
+    Rejected = 9,
+    /// The listener is busy, or got too many connections from this address
+    RateLimited = 10,
+    /// The two devices share no protocol version
+    VersionMismatch = 11,
+}
+
+impl Code {
+    /// The code for `error`, from the first cause that has one
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| {
+                if let Some(e) = cause.downcast_ref::<ConnectoError>() {
+                    Self::of_connecto(e)
+                } else {
+                    cause.downcast_ref::<io::Error>().and_then(Self::of_io)
+                }
+            })
+            .unwrap_or(Self::Failure)
+    }
+
+    fn of_connecto(error: &ConnectoError) -> Option<Self> {
+        match error {
+            ConnectoError::Discovery(_) | ConnectoError::DeviceNotFound(_) => Some(Self::Discovery),
+            ConnectoError::ConnectionRefused(_) => Some(Self::ConnectionRefused),
+            ConnectoError::VerificationFailed(_) | ConnectoError::IdentityMismatch(_) => {
+                Some(Self::VerificationFailed)
+            }
+            ConnectoError::KeyExists(_) => Some(Self::KeyExists),
+            ConnectoError::PermissionDenied(_) => Some(Self::PermissionDenied),
+            ConnectoError::Timeout(_) => Some(Self::Timeout),
+            ConnectoError::Rejected(_) => Some(Self::Rejected),
+            ConnectoError::RateLimited(_) => Some(Self::RateLimited),
+            ConnectoError::VersionMismatch(_) => Some(Self::VersionMismatch),
+            ConnectoError::Io(e) => Self::of_io(e),
+            _ => None,
+        }
+    }
+
+    fn of_io(error: &io::Error) -> Option<Self> {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Some(Self::ConnectionRefused),
+            io::ErrorKind::PermissionDenied => Some(Self::PermissionDenied),
+            io::ErrorKind::TimedOut => Some(Self::Timeout),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_codes() {
+        let code = |e: ConnectoError| Code::of(&e.into()) as i32;
+        assert_eq!(code(ConnectoError::DeviceNotFound("desk".into())), 3);
+        assert_eq!(code(ConnectoError::ConnectionRefused("desk".into())), 4);
+        assert_eq!(code(ConnectoError::VerificationFailed("wrong".into())), 5);
+        assert_eq!(code(ConnectoError::IdentityMismatch("desk".into())), 5);
+        assert_eq!(code(ConnectoError::KeyExists("id".into())), 6);
+        assert_eq!(code(ConnectoError::Timeout("peer".into())), 8);
+        assert_eq!(code(ConnectoError::Rejected("no".into())), 9);
+        assert_eq!(code(ConnectoError::RateLimited("busy".into())), 10);
+        assert_eq!(code(ConnectoError::VersionMismatch("1-7".into())), 11);
+        assert_eq!(code(ConnectoError::Handshake("oops".into())), 1);
+
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert_eq!(code(ConnectoError::Io(denied)), 7);
+
+        // Context added on top keeps the code of the cause
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        let error = Err::<(), _>(refused)
+            .context("Failed to reach desk")
+            .unwrap_err();
+        assert_eq!(Code::of(&error), Code::ConnectionRefused);
+
+        assert_eq!(Code::of(&anyhow::anyhow!("Host not found")), Code::Failure);
+    }
+}
@@ -4,13 +4,30 @@
 //!   connecto listen    - Start listening for pairing requests
 //!   connecto scan      - Scan for available devices
 //!   connecto pair <n>  - Pair with device number n
+//!   connecto relay serve - Run a relay for devices on other networks
+//!   connecto <name>    - Run `connecto-<name>` from PATH
 
 mod commands;
 mod config;
+mod device_cache;
+mod exit;
+mod output;
+mod policy;
 
 use anyhow::Result;
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use clap_complete::{generate, Shell};
+use connecto_core::{
+    access::{AccessList, Network},
+    clock::{format_utc, UTC_DISPLAY_FORMAT},
+    keys::{KeyAlgorithm, KeyOptions},
+    limits::{self, HandshakeLimits},
+    relay::RelayCode,
+};
+use output::mark;
+use std::path::PathBuf;
+use std::time::Duration;
 use tracing_subscriber::EnvFilter;
 
 /// Connecto - AirDrop-like SSH key pairing for your terminal
@@ -36,6 +53,16 @@ struct Cli {
     #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Plain text output for screen readers: words instead of symbols, no
+    /// colors or spinners (also set by CONNECTO_ACCESSIBLE)
+    #[arg(long, global = true)]
+    accessible: bool,
+
+    /// Config profile to use instead of the one picked with `config
+    /// use-profile` (also set by CONNECTO_PROFILE)
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -48,7 +75,11 @@ enum Commands {
         #[arg(short, long, default_value_t = connecto_core::DEFAULT_PORT)]
         port: u16,
 
-        /// Custom device name (defaults to hostname)
+        /// Port the SSH server listens on (defaults to the Port in sshd_config, or 22)
+        #[arg(long, value_name = "PORT")]
+        ssh_port: Option<u16>,
+
+        /// Custom device name (defaults to the configured or OS device name)
         #[arg(short, long)]
         name: Option<String>,
 
@@ -56,6 +87,28 @@ enum Commands {
         #[arg(long)]
         verify: bool,
 
+        /// Advertise only --name (or a random name); reveal the hostname only after pairing
+        #[arg(long)]
+        private: bool,
+
+        /// Ask before accepting pairing requests from unknown devices
+        #[arg(long)]
+        approve: bool,
+
+        /// Seconds to wait for an answer before applying --on-timeout
+        #[arg(long, value_name = "SECS", default_value_t = connecto_core::protocol::APPROVAL_TIMEOUT_SECS, requires = "approve")]
+        approval_timeout: u64,
+
+        /// What to do with a request nobody answers in time
+        #[arg(
+            long,
+            value_enum,
+            value_name = "ACTION",
+            default_value_t,
+            requires = "approve"
+        )]
+        on_timeout: commands::listen::OnTimeout,
+
         /// Keep listening after first pairing (default: exit after one)
         #[arg(short, long)]
         continuous: bool,
@@ -63,6 +116,74 @@ enum Commands {
         /// Create an ad-hoc WiFi network (bypasses router, for isolated networks)
         #[arg(long)]
         adhoc: bool,
+
+        /// Wait on the relay at HOST[:PORT] for a device on another network
+        #[arg(long, value_name = "HOST[:PORT]", conflicts_with_all = ["continuous", "adhoc"])]
+        relay: Option<String>,
+
+        /// Remove expired keys from authorized_keys now and every hour
+        #[arg(long)]
+        prune: bool,
+
+        /// Only accept installed keys from the address they were paired from
+        #[arg(long)]
+        restrict_source: bool,
+
+        /// authorized_keys option for installed keys, e.g. no-port-forwarding or command="..."
+        #[arg(long = "key-option", value_name = "OPTION", value_parser = parse_key_option)]
+        key_options: Vec<String>,
+
+        /// Other accounts clients may pair into, comma-separated (installing for them needs root)
+        #[arg(long, value_name = "USER", value_delimiter = ',')]
+        users: Vec<String>,
+
+        /// Install keys for this account instead of your own, e.g. root (needs root)
+        #[arg(long, value_name = "USER")]
+        for_user: Option<String>,
+
+        /// Only answer clients from this address or subnet (e.g., 10.0.0.0/24). Can be specified multiple times
+        #[arg(long = "allow", value_name = "CIDR")]
+        allow: Vec<Network>,
+
+        /// Refuse clients from this address or subnet. Can be specified multiple times
+        #[arg(long = "deny", value_name = "CIDR")]
+        deny: Vec<Network>,
+
+        /// Only answer devices whose name matches this pattern (e.g., "alice-*"). Can be specified multiple times
+        #[arg(long = "allow-name", value_name = "PATTERN")]
+        allow_names: Vec<String>,
+
+        /// Most pairing handshakes to run at the same time
+        #[arg(long, value_name = "N", default_value_t = limits::DEFAULT_MAX_HANDSHAKES, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        max_handshakes: usize,
+
+        /// Most connections one address may open per minute (0 for no limit)
+        #[arg(long, value_name = "PER_MINUTE", default_value_t = limits::DEFAULT_CONNECTIONS_PER_MINUTE)]
+        rate_limit: u32,
+
+        /// Seconds to wait for each message of a client before dropping it
+        #[arg(long, value_name = "SECS", default_value_t = limits::DEFAULT_STEP_TIMEOUT_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+        step_timeout: u64,
+
+        /// Do not show desktop notifications for pairing requests and results
+        #[arg(long)]
+        no_notify: bool,
+
+        /// Advertise only on this interface (e.g. en0) or address. Can be specified multiple times
+        #[arg(long = "interface", value_name = "NAME|ADDR", conflicts_with_all = ["relay", "adhoc"])]
+        interfaces: Vec<String>,
+
+        /// Listen on this address only, instead of every IPv4 address
+        #[arg(long, value_name = "ADDR", conflicts_with_all = ["relay", "adhoc"])]
+        bind: Option<std::net::IpAddr>,
+
+        /// Forward the port on the router with NAT-PMP or UPnP, for devices on guest networks or behind another NAT
+        #[arg(long, conflicts_with_all = ["relay", "adhoc"])]
+        upnp: bool,
+
+        /// Answer one pairing request arriving on stdin, replying on stdout (e.g. over ssh)
+        #[arg(long, conflicts_with_all = ["port", "verify", "approve", "continuous", "adhoc", "relay", "prune", "interfaces", "bind", "upnp"])]
+        accept_stdin: bool,
     },
 
     /// Scan the local network for devices running Connecto
@@ -74,12 +195,61 @@ enum Commands {
         /// Subnet to scan (e.g., 10.105.225.0/24). Can be specified multiple times.
         #[arg(short, long)]
         subnet: Vec<String>,
+
+        /// Columns to show, comma-separated (overrides config)
+        #[arg(long, value_enum, value_delimiter = ',')]
+        columns: Vec<commands::scan::ScanColumn>,
+
+        /// Sort order for results (overrides config)
+        #[arg(long, value_enum)]
+        sort: Option<commands::scan::ScanSort>,
+
+        /// Print tab-separated rows only (for scripts and awk)
+        #[arg(long)]
+        plain: bool,
+
+        /// Hosts to probe at the same time during subnet scans (overrides config)
+        #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        concurrency: Option<usize>,
+
+        /// Most subnet scan probes to start per second (overrides config)
+        #[arg(long, value_name = "PER_SECOND", value_parser = clap::value_parser!(u32).range(1..))]
+        rate: Option<u32>,
+
+        /// How long to wait for each host during subnet scans, in milliseconds (overrides config)
+        #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+        probe_timeout: Option<u64>,
+
+        /// Stop the mDNS search once N devices were found (overrides config)
+        #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        min_devices: Option<usize>,
+
+        /// Stop the mDNS search when no new device appeared for MS milliseconds after the first (overrides config)
+        #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+        quiet_period: Option<u64>,
+
+        /// Print the devices of the last scan, and how old they are, instead of scanning
+        #[arg(long, conflicts_with_all = ["timeout", "subnet", "sort", "concurrency", "rate", "probe_timeout", "min_devices", "quiet_period"])]
+        cached: bool,
     },
 
     /// Pair with a discovered device
     Pair {
-        /// Device number from scan results, or IP:port address
-        target: String,
+        /// Device numbers from scan results, device names, or IP:port addresses
+        #[arg(required_unless_present_any = ["all", "relay", "print_authorized_line"])]
+        targets: Vec<String>,
+
+        /// Pair with every device from the last scan
+        #[arg(long, conflicts_with = "targets")]
+        all: bool,
+
+        /// Pair through the relay at HOST[:PORT] with the device showing --code
+        #[arg(long, value_name = "HOST[:PORT]", requires = "code", conflicts_with_all = ["targets", "all"])]
+        relay: Option<String>,
+
+        /// Code shown by `connecto listen --relay` on the other device
+        #[arg(long, requires = "relay")]
+        code: Option<RelayCode>,
 
         /// Custom key comment (defaults to user@hostname)
         #[arg(short, long)]
@@ -89,15 +259,60 @@ enum Commands {
         #[arg(long)]
         rsa: bool,
 
+        /// Type of key to generate (defaults to ed25519)
+        #[arg(short = 't', long = "type", value_name = "TYPE", value_parser = key_type_parser(), conflicts_with = "rsa")]
+        key_type: Option<KeyAlgorithm>,
+
         /// Use existing SSH key instead of generating a new one
         #[arg(short, long, value_name = "PATH")]
         key: Option<String>,
+
+        /// Pair even if the device's identity changed since the last pairing
+        #[arg(long)]
+        accept_new_identity: bool,
+
+        /// Tag the new host, applying the SSH options templated for the tag.
+        /// Can be specified multiple times
+        #[arg(long = "tag", value_name = "TAG", value_parser = parse_tag)]
+        tags: Vec<String>,
+
+        /// Have the device accept the key only for DURATION (e.g. 12h, 30d, 2w)
+        #[arg(long, value_name = "DURATION", value_parser = parse_lifetime)]
+        expires: Option<Duration>,
+
+        /// Name of the host in ~/.ssh/config (defaults to the device's name)
+        #[arg(long, value_name = "ALIAS", value_parser = parse_alias, conflicts_with = "all")]
+        alias: Option<String>,
+
+        /// Write the device's mDNS hostname (e.g. desk-pc.local) to ~/.ssh/config instead of its IP
+        #[arg(long, conflicts_with = "relay")]
+        mdns: bool,
+
+        /// Account on the device to pair into, one it offers with `listen --users`
+        #[arg(long, value_name = "NAME")]
+        user: Option<String>,
+
+        /// Give up on a device if pairing with it takes longer than SECS
+        #[arg(long, value_name = "SECS")]
+        timeout: Option<u64>,
+
+        /// Try connecting to a device up to N more times, waiting longer each time [default: 2]
+        #[arg(long, value_name = "N")]
+        retries: Option<u32>,
+
+        /// Pair over stdin and stdout with `connecto listen --accept-stdin` (e.g. over ssh), then print the authorized_keys line
+        #[arg(long, conflicts_with_all = ["targets", "all", "relay", "mdns", "retries"])]
+        print_authorized_line: bool,
     },
 
     /// List authorized keys on this machine
     Keys {
         #[command(subcommand)]
         action: Option<KeysAction>,
+
+        /// Print tab-separated rows only (for scripts and awk)
+        #[arg(long, global = true)]
+        plain: bool,
     },
 
     /// Generate a new SSH key pair
@@ -113,6 +328,10 @@ enum Commands {
         /// Generate RSA key instead of Ed25519
         #[arg(long)]
         rsa: bool,
+
+        /// Type of key to generate (defaults to ed25519)
+        #[arg(short = 't', long = "type", value_name = "TYPE", value_parser = key_type_parser(), conflicts_with = "rsa")]
+        key_type: Option<KeyAlgorithm>,
     },
 
     /// Manage configuration (saved subnets, etc.)
@@ -121,19 +340,145 @@ enum Commands {
         action: ConfigAction,
     },
 
-    /// List paired hosts (from ~/.ssh/config)
-    Hosts,
+    /// List paired hosts (from ~/.ssh/config); with --verbose, also when and how they were paired
+    Hosts {
+        /// Print tab-separated rows only (for scripts and awk)
+        #[arg(long)]
+        plain: bool,
+    },
+
+    /// Show the tamper-evident log of accepted and rejected pairing requests
+    History {
+        #[command(subcommand)]
+        action: Option<HistoryAction>,
+
+        /// Print tab-separated rows only (for scripts and awk)
+        #[arg(long, global = true)]
+        plain: bool,
+    },
+
+    /// Show the tamper-evident log of key, pairing and sync events
+    Audit {
+        #[command(subcommand)]
+        action: Option<AuditAction>,
+
+        /// Print tab-separated rows only (for scripts and awk)
+        #[arg(long, global = true)]
+        plain: bool,
+    },
+
+    /// Tag a paired host; tags pick the SSH option templates it gets
+    Tag {
+        /// Host name to tag
+        host: String,
+
+        /// Tags to add (none lists the host's tags)
+        #[arg(value_parser = parse_tag)]
+        tags: Vec<String>,
+
+        /// Remove the tags instead of adding them
+        #[arg(short, long, requires = "tags")]
+        remove: bool,
+    },
 
     /// Remove a paired host and delete its keys
     Unpair {
         /// Host name to unpair
         host: String,
+
+        /// Overwrite the private key before deleting it (best effort)
+        #[arg(long)]
+        shred: bool,
+
+        /// First remove your key from the host's authorized_keys, through its
+        /// listener or else over SSH
+        #[arg(long)]
+        remote: bool,
+
+        /// Port the host's listener runs on, for --remote
+        #[arg(short, long, default_value_t = connecto_core::DEFAULT_PORT)]
+        port: u16,
+    },
+
+    /// Ask a paired host's listener to remove your key from its authorized_keys
+    Revoke {
+        /// Host name whose listener should forget your key
+        host: String,
+
+        /// Port the host listens on
+        #[arg(short, long, default_value_t = connecto_core::DEFAULT_PORT)]
+        port: u16,
+    },
+
+    /// Replace the key of paired hosts with a freshly generated one
+    Rotate {
+        /// Host name whose key to rotate
+        #[arg(required_unless_present = "all")]
+        host: Option<String>,
+
+        /// Rotate the keys of every paired host
+        #[arg(long, conflicts_with = "host")]
+        all: bool,
+
+        /// Type of the new key (defaults to ed25519)
+        #[arg(short = 't', long = "type", value_name = "TYPE", value_parser = key_type_parser())]
+        key_type: Option<KeyAlgorithm>,
+
+        /// Overwrite old private keys before deleting them (best effort)
+        #[arg(long)]
+        shred: bool,
+    },
+
+    /// Remove expired keys from authorized_keys and report expired local keys
+    Prune {
+        /// Only list what would be removed
+        #[arg(long)]
+        dry_run: bool,
     },
 
     /// Test SSH connection to a paired host
     Test {
         /// Host name to test
         host: String,
+
+        /// Attempt to repair known failures without prompting
+        #[arg(long)]
+        fix: bool,
+
+        /// Test with the built-in SSH client instead of ssh (used anyway when ssh is not installed)
+        #[arg(long)]
+        builtin: bool,
+    },
+
+    /// Run a command on a paired host over SSH, exiting with its status
+    Run {
+        /// Host alias or device name of the paired host
+        host: String,
+
+        /// Command to run, after `--`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+
+        /// Seconds to wait for the connection
+        #[arg(long, value_name = "SECS", default_value_t = 10)]
+        timeout: u64,
+    },
+
+    /// Find paired hosts that no longer answer and point them at their new address
+    Repair {
+        /// Host to repair (default: every paired host)
+        host: Option<String>,
+    },
+
+    /// Check what discovery, pairing and SSH need on this machine, with fixes
+    Doctor {
+        /// Pairing port to check
+        #[arg(short, long, default_value_t = connecto_core::DEFAULT_PORT)]
+        port: u16,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = commands::doctor::DoctorFormat::Text)]
+        format: commands::doctor::DoctorFormat,
     },
 
     /// Update IP address for a paired host
@@ -145,17 +490,41 @@ enum Commands {
         ip: String,
     },
 
-    /// Export paired hosts configuration
+    /// Undo the last change Connecto made to ~/.ssh/config or authorized_keys
+    RestoreConfig {
+        /// Only list the backups, newest first
+        #[arg(long)]
+        list: bool,
+    },
+
+    /// Export paired hosts, keys, config and pairing history
     Export {
         /// Output file (default: stdout)
         #[arg(short, long)]
         output: Option<String>,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = commands::export::ExportFormat::Json)]
+        format: commands::export::ExportFormat,
+
+        #[command(flatten)]
+        sections: commands::export::Sections,
+
+        #[command(flatten)]
+        protection: commands::export::Protection,
     },
 
-    /// Import paired hosts configuration
+    /// Import an export, previewing what changes first
     Import {
         /// Input file
         file: String,
+
+        #[command(flatten)]
+        sections: commands::export::Sections,
+
+        /// Only show what would change
+        #[arg(long)]
+        dry_run: bool,
     },
 
     /// Generate shell completions
@@ -171,7 +540,7 @@ enum Commands {
         #[arg(short, long, default_value_t = connecto_core::DEFAULT_PORT)]
         port: u16,
 
-        /// Custom device name (defaults to hostname)
+        /// Custom device name (defaults to the configured or OS device name)
         #[arg(short, long)]
         name: Option<String>,
 
@@ -183,9 +552,125 @@ enum Commands {
         #[arg(long)]
         rsa: bool,
 
+        /// Type of key to generate (defaults to ed25519)
+        #[arg(long = "type", value_name = "TYPE", value_parser = key_type_parser(), conflicts_with = "rsa")]
+        key_type: Option<KeyAlgorithm>,
+
         /// Use existing SSH key instead of generating a new one
         #[arg(short, long, value_name = "PATH")]
         key: Option<String>,
+
+        /// Also share this public key with the peer (a key pair's private key
+        /// or .pub file; repeatable)
+        #[arg(long, value_name = "PATH")]
+        share: Vec<String>,
+
+        /// Also share every connecto_* key in ~/.ssh with the peer
+        #[arg(long)]
+        share_all: bool,
+
+        /// Pair even if the device's identity changed since the last pairing
+        #[arg(long)]
+        accept_new_identity: bool,
+
+        /// Ask before accepting keys from unknown devices
+        #[arg(long, conflicts_with = "daemon")]
+        approve: bool,
+
+        /// Seconds to wait for an answer before refusing the key
+        #[arg(long, value_name = "SECS", default_value_t = connecto_core::protocol::APPROVAL_TIMEOUT_SECS, requires = "approve")]
+        approval_timeout: u64,
+
+        /// Refuse this device, by name or key fingerprint (repeatable)
+        #[arg(long, value_name = "NAME|FINGERPRINT")]
+        deny: Vec<String>,
+
+        /// Keep running: sync with new peers and keep synced peers' keys up to date
+        #[arg(long, conflicts_with = "timeout")]
+        daemon: bool,
+
+        /// Seconds between key list exchanges with synced peers (with --daemon)
+        #[arg(
+            long,
+            value_name = "SECS",
+            default_value_t = connecto_core::DEFAULT_SYNC_INTERVAL_SECS,
+            value_parser = clap::value_parser!(u64).range(1..),
+            requires = "daemon"
+        )]
+        interval: u64,
+    },
+
+    /// Send files to a device running `connecto receive`
+    Send {
+        /// Paired host, device number from scan results, or IP[:port] address
+        device: String,
+
+        /// Files to send
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<PathBuf>,
+
+        /// Port the receiver listens on, unless the address names one
+        #[arg(short, long, default_value_t = connecto_core::DEFAULT_TRANSFER_PORT)]
+        port: u16,
+
+        /// Send even if the device's identity changed since the last pairing
+        #[arg(long)]
+        accept_new_identity: bool,
+    },
+
+    /// Receive files sent with `connecto send`
+    Receive {
+        /// Port to listen on
+        #[arg(short, long, default_value_t = connecto_core::DEFAULT_TRANSFER_PORT)]
+        port: u16,
+
+        /// Directory to save files to (default: Downloads)
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Keep receiving after the first transfer
+        #[arg(short, long)]
+        continuous: bool,
+
+        /// Seconds to wait for an answer before declining the files
+        #[arg(long, value_name = "SECS", default_value_t = connecto_core::protocol::APPROVAL_TIMEOUT_SECS)]
+        approval_timeout: u64,
+    },
+
+    /// Set how far listeners trust each device
+    Trust {
+        #[command(subcommand)]
+        action: Option<TrustAction>,
+
+        /// Print tab-separated rows only (for scripts and awk)
+        #[arg(long, global = true)]
+        plain: bool,
+    },
+
+    /// List and remove host keys in ~/.ssh/known_hosts
+    KnownHosts {
+        #[command(subcommand)]
+        action: Option<KnownHostsAction>,
+
+        /// Print tab-separated rows only (for scripts and awk)
+        #[arg(long, global = true)]
+        plain: bool,
+    },
+
+    /// Run a relay that pairs devices on different networks
+    Relay {
+        #[command(subcommand)]
+        action: RelayAction,
+    },
+
+    /// Keep connections to paired hosts open during set hours
+    KeepWarm {
+        #[command(subcommand)]
+        action: Option<KeepWarmAction>,
+
+        /// Print tab-separated rows only (for scripts and awk)
+        #[arg(long, global = true)]
+        plain: bool,
     },
 
     /// Manage SSH server (Windows: enable/disable OpenSSH Server)
@@ -193,6 +678,16 @@ enum Commands {
         #[command(subcommand)]
         action: SshAction,
     },
+
+    /// Run the listener in the background from login (launchd, systemd or a scheduled task)
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+
+    /// Run `connecto-<name>` from PATH for any other command
+    #[command(external_subcommand)]
+    External(Vec<String>),
 }
 
 #[derive(Subcommand)]
@@ -204,6 +699,129 @@ enum KeysAction {
         /// Key number or search pattern
         target: String,
     },
+    /// Delete a local key pair from ~/.ssh
+    Delete {
+        /// Key file name (e.g. connecto_mydesktop)
+        name: String,
+
+        /// Overwrite the private key before deleting it (best effort)
+        #[arg(long)]
+        shred: bool,
+    },
+    /// Check local keys and authorized_keys for weak or misconfigured keys
+    Audit {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = commands::keys::AuditFormat::Table)]
+        format: commands::keys::AuditFormat,
+    },
+    /// Authorize public keys a user publishes on GitHub
+    ImportGithub {
+        /// GitHub user name
+        user: String,
+
+        /// Install every key without asking which
+        #[arg(long)]
+        all: bool,
+    },
+    /// Authorize public keys a user publishes on GitLab
+    ImportGitlab {
+        /// GitLab user name
+        user: String,
+
+        /// Host of a self-hosted GitLab instance
+        #[arg(long, default_value = connecto_core::forges::GITLAB_HOST)]
+        host: String,
+
+        /// Install every key without asking which
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryAction {
+    /// List recorded decisions
+    List,
+    /// Check that no decision was modified or removed
+    Verify,
+    /// Export decisions for auditors
+    Export {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = commands::history::ExportFormat::Csv)]
+        format: commands::history::ExportFormat,
+
+        /// Output file (default: stdout)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuditAction {
+    /// List recorded events
+    Show {
+        /// Only the last N events
+        #[arg(short = 'n', long)]
+        last: Option<usize>,
+    },
+    /// Check that no event was modified or removed
+    Verify,
+}
+
+#[derive(Subcommand)]
+enum TrustAction {
+    /// List known devices and their trust levels
+    List,
+    /// Set the trust level of a device
+    Set {
+        /// Device name, as it announces itself when pairing
+        device: String,
+
+        /// Trust level
+        #[arg(value_enum)]
+        level: commands::trust::Level,
+    },
+}
+
+#[derive(Subcommand)]
+enum KnownHostsAction {
+    /// List known hosts and their key fingerprints
+    List,
+    /// Remove every host key of a host
+    Remove {
+        /// Host name or IP address, as ssh connects to it
+        host: String,
+
+        /// SSH port of the host
+        #[arg(short, long, default_value_t = connecto_core::connectivity::SSH_PORT)]
+        port: u16,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeepWarmAction {
+    /// Keep a connection to a host open during set hours
+    Add {
+        /// Host alias from the SSH config
+        host: String,
+
+        /// Days to keep it open (e.g. mon-fri, sat,sun or daily)
+        #[arg(long, default_value = "mon-fri")]
+        days: String,
+
+        /// Hours to keep it open, in local time
+        #[arg(long, value_name = "HH:MM-HH:MM", default_value = "09:00-18:00")]
+        hours: String,
+    },
+    /// Stop keeping a connection to a host open
+    Remove {
+        /// Host alias from the SSH config
+        host: String,
+    },
+    /// Show the hosts kept warm and whether they are connected
+    Status,
+    /// Open and close connections on schedule until stopped
+    Run,
 }
 
 #[derive(Subcommand)]
@@ -216,6 +834,42 @@ enum SshAction {
     Status,
 }
 
+#[derive(Subcommand)]
+enum ServiceAction {
+    /// Register `connecto listen --continuous` to start at login, and start it now
+    ///
+    /// On Windows this is a scheduled task run at logon, not a Windows
+    /// service: a service runs outside your session, and the keys it
+    /// installed would go to its own account instead of yours.
+    Install {
+        /// Options for `connecto listen`, e.g. --name office --verify
+        #[arg(
+            value_name = "LISTEN OPTIONS",
+            trailing_var_arg = true,
+            allow_hyphen_values = true
+        )]
+        options: Vec<String>,
+    },
+    /// Stop the listener service and remove it
+    Uninstall,
+    /// Show whether the listener service is installed and running
+    Status,
+}
+
+#[derive(Subcommand)]
+enum RelayAction {
+    /// Accept devices and forward between those that join with the same code
+    Serve {
+        /// Port to listen on
+        #[arg(short, long, default_value_t = connecto_core::relay::DEFAULT_RELAY_PORT)]
+        port: u16,
+
+        /// Seconds a device waits for the other one before its room closes
+        #[arg(long, value_name = "SECS", default_value_t = connecto_core::relay::RELAY_WAIT_SECS)]
+        wait: u64,
+    },
+}
+
 #[derive(Subcommand)]
 enum ConfigAction {
     /// Add a subnet to always scan (e.g., 10.105.225.0/24)
@@ -235,27 +889,153 @@ enum ConfigAction {
     },
     /// Clear the default SSH key
     ClearDefaultKey,
+    /// Set the port listen, pair, scan and sync use by default
+    SetPort {
+        /// Port number
+        port: u16,
+    },
+    /// Go back to the built-in default port
+    ClearPort,
+    /// Set the name other devices see (defaults to the OS device name)
+    SetName {
+        /// Device name, e.g. "Meeting room"
+        name: String,
+    },
+    /// Go back to announcing the OS device name
+    ClearName,
+    /// Add an SSH option to every host with a tag (e.g., prod StrictHostKeyChecking yes)
+    SetTemplate {
+        /// Tag the option applies to
+        #[arg(value_parser = parse_tag)]
+        tag: String,
+        /// SSH option name (e.g., ForwardAgent)
+        option: String,
+        /// Option value
+        value: String,
+    },
+    /// Remove an SSH option from a tag's template, or the whole template
+    RemoveTemplate {
+        /// Tag whose template to change
+        tag: String,
+        /// Option to remove (default: all of them)
+        option: Option<String>,
+    },
+    /// Only answer listener clients from an address or subnet (e.g., 10.0.0.0/24)
+    Allow {
+        /// IP address or subnet in CIDR notation
+        address: Network,
+    },
+    /// Refuse listener clients from an address or subnet
+    Deny {
+        /// IP address or subnet in CIDR notation
+        address: Network,
+    },
+    /// Only answer listener clients whose device name matches a pattern (e.g., "alice-*")
+    AllowName {
+        /// Device name, with * and ? wildcards
+        pattern: String,
+    },
+    /// Remove a saved allow, deny or allow-name rule
+    RemoveAccess {
+        /// The address, subnet or pattern as it was added
+        rule: String,
+    },
+    /// Create a profile, e.g. home, office or lab
+    AddProfile {
+        /// Profile name
+        name: String,
+    },
+    /// Delete a profile and its settings
+    RemoveProfile {
+        /// Profile name
+        name: String,
+    },
+    /// Switch every command to a profile ("default" for the settings outside profiles)
+    UseProfile {
+        /// Profile name
+        name: String,
+    },
+    /// List profiles, marking the one in use
+    Profiles,
     /// List current configuration
     List,
     /// Show config file path
     Path,
-}
+    /// Export a signed policy bundle for fleet rollout
+    ExportPolicy {
+        /// Private key to sign the bundle with (e.g., ~/.ssh/id_ed25519)
+        #[arg(short, long, value_name = "PATH")]
+        key: String,
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Enable ANSI color support on Windows
-    #[cfg(windows)]
-    {
-        // ANSI escape codes require Windows 10 v1511+ (build 10586+)
-        // On older Windows, disable colors entirely to avoid garbage output
-        if !supports_ansi_colors_windows() {
-            colored::control::set_override(false);
-        } else {
-            let _ = colored::control::set_virtual_terminal(true);
-        }
-    }
+        /// Default port for listen, pair, scan and sync
+        #[arg(long)]
+        port: Option<u16>,
+
+        /// Require a verification code on every listener
+        #[arg(long)]
+        require_verification: bool,
+
+        /// Allowed key algorithm. Can be specified multiple times (default: all)
+        #[arg(long = "allow-algorithm", value_enum, value_name = "ALGORITHM")]
+        allow_algorithms: Vec<policy::PolicyAlgorithm>,
+
+        /// Trusted subnet to always scan. Can be specified multiple times
+        /// (default: the subnets in your config)
+        #[arg(long = "subnet", value_name = "CIDR")]
+        subnets: Vec<String>,
+
+        /// Output file (default: stdout)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Verify a policy bundle and install it machine-wide (needs admin rights)
+    ApplyPolicy {
+        /// Policy bundle file
+        file: String,
+
+        /// Admin public key (file or key text) the bundle must be signed with.
+        /// Required for the first install; later bundles must match the installed signer.
+        #[arg(long, value_name = "KEY")]
+        signer: Option<String>,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{} {:?}", connecto_core::i18n::text("label.error"), e);
+        std::process::exit(exit::Code::of(&e) as i32);
+    }
+}
+
+async fn run() -> Result<()> {
+    // Enable ANSI color support on Windows
+    #[cfg(windows)]
+    {
+        // ANSI escape codes require Windows 10 v1511+ (build 10586+)
+        // On older Windows, disable colors entirely to avoid garbage output
+        if !supports_ansi_colors_windows() {
+            colored::control::set_override(false);
+        } else {
+            let _ = colored::control::set_virtual_terminal(true);
+        }
+    }
 
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    output::set_accessible(
+        cli.accessible || std::env::var_os(output::ACCESSIBLE_ENV).is_some_and(|v| !v.is_empty()),
+    );
+    let profile = cli.profile.clone().or_else(|| {
+        std::env::var(config::PROFILE_ENV)
+            .ok()
+            .filter(|v| !v.is_empty())
+    });
+    if let Some(profile) = profile {
+        config::set_profile_override(&profile);
+        // Fail now rather than fall back to defaults where the config is optional
+        config::Config::load()?;
+    }
 
     // Set up logging
     let filter = if cli.verbose {
@@ -264,38 +1044,293 @@ async fn main() -> Result<()> {
         EnvFilter::new("info")
     };
 
-    tracing_subscriber::fmt()
+    let logs = tracing_subscriber::fmt()
         .with_env_filter(filter)
         .without_time()
-        .with_target(false)
-        .init();
+        .with_target(false);
+    // Over stdio, stdout carries the pairing
+    let over_stdio = matches!(
+        cli.command,
+        Commands::Listen {
+            accept_stdin: true,
+            ..
+        } | Commands::Pair {
+            print_authorized_line: true,
+            ..
+        }
+    );
+    if over_stdio {
+        logs.with_writer(std::io::stderr).init();
+    } else {
+        logs.init();
+    }
 
     match cli.command {
         Commands::Listen {
             port,
+            ssh_port,
             name,
             verify,
+            private,
+            approve,
+            approval_timeout,
+            on_timeout,
             continuous,
             adhoc,
-        } => commands::listen::run_with_adhoc(port, name, verify, continuous, adhoc).await,
-        Commands::Scan { timeout, subnet } => {
-            commands::scan::run_with_options(timeout, false, subnet).await
+            relay,
+            prune,
+            restrict_source,
+            key_options,
+            users,
+            for_user,
+            allow,
+            deny,
+            allow_names,
+            max_handshakes,
+            rate_limit,
+            step_timeout,
+            no_notify,
+            interfaces,
+            bind,
+            upnp,
+            accept_stdin,
+        } => {
+            let cfg = config::Config::load().unwrap_or_default();
+            let port = policy_port(&matches, "listen", port, config::Config::listen_port);
+            let name = name.or_else(|| cfg.listen.name.clone());
+            let verify = verify || cfg.listen.verify;
+            let private = private || cfg.listen.private;
+            let approve = approve || cfg.listen.approve;
+            let continuous = continuous || cfg.listen.continuous;
+            let prune = prune || cfg.listen.prune;
+            let restrictions = commands::listen::KeyRestrictions {
+                options: key_options.join(",").parse()?,
+                restrict_source,
+                users,
+                for_user,
+            };
+            let access = AccessList {
+                allow,
+                deny,
+                allow_names,
+            };
+            let limits = HandshakeLimits {
+                max_handshakes,
+                connections_per_minute: rate_limit,
+                step_timeout: Duration::from_secs(step_timeout),
+                ..Default::default()
+            };
+            if accept_stdin {
+                // Leave time to paste each message unless told otherwise
+                let limits = if given(&matches, "listen", "step_timeout") {
+                    limits
+                } else {
+                    HandshakeLimits {
+                        step_timeout: connecto_core::stdio::STEP_TIMEOUT,
+                        ..limits
+                    }
+                };
+                return commands::listen::run_stdin(
+                    ssh_port,
+                    name,
+                    verify,
+                    private,
+                    approve,
+                    restrictions,
+                    access,
+                    limits,
+                )
+                .await;
+            }
+            let approval = approve.then_some(commands::listen::Approval {
+                timeout_secs: approval_timeout,
+                on_timeout,
+            });
+            let reach = match relay {
+                Some(relay) => commands::listen::Reach::Relay(relay),
+                None if adhoc => commands::listen::Reach::AdHoc,
+                None => commands::listen::Reach::Network(commands::listen::Binding {
+                    interfaces,
+                    bind,
+                    upnp,
+                }),
+            };
+            commands::listen::run_with_adhoc(
+                port,
+                ssh_port,
+                name,
+                verify,
+                private,
+                approval,
+                continuous,
+                reach,
+                prune,
+                restrictions,
+                access,
+                limits,
+                !no_notify,
+            )
+            .await
+        }
+        Commands::Scan {
+            timeout,
+            subnet,
+            columns,
+            sort,
+            plain,
+            concurrency,
+            rate,
+            probe_timeout,
+            min_devices,
+            quiet_period,
+            cached,
+        } => {
+            let cfg = config::Config::load().unwrap_or_default();
+            let timeout = match cfg.scan.timeout_secs {
+                Some(secs) if !given(&matches, "scan", "timeout") => secs,
+                _ => timeout,
+            };
+            let output = commands::scan::ScanOutput::resolve(columns, sort, plain, &cfg);
+            if cached {
+                let cache = device_cache::DeviceCache::new(&cfg);
+                commands::scan::show_cached(&output.columns, output.plain, &cache)
+            } else {
+                let probe =
+                    commands::scan::ProbeOptions::resolve(concurrency, rate, probe_timeout, &cfg);
+                let early_exit = commands::scan::early_exit(min_devices, quiet_period, &cfg);
+                commands::scan::run_with_options(timeout, false, subnet, output, probe, early_exit)
+                    .await
+            }
         }
         Commands::Pair {
-            target,
+            targets,
+            all,
+            relay,
+            code,
             comment,
             rsa,
+            key_type,
             key,
-        } => commands::pair::run(target, comment, rsa, key).await,
-        Commands::Keys { action } => commands::keys::run(action).await,
-        Commands::Keygen { name, comment, rsa } => commands::keygen::run(name, comment, rsa).await,
+            accept_new_identity,
+            tags,
+            expires,
+            alias,
+            mdns,
+            user,
+            timeout,
+            retries,
+            print_authorized_line,
+        } => {
+            let cfg = config::Config::load()?;
+            let key_type = match key_type {
+                None if !rsa => cfg.pair.key_type()?,
+                key_type => key_type,
+            };
+            let expires =
+                match (expires, &cfg.pair.expires) {
+                    (None, Some(lifetime)) => Some(parse_lifetime(lifetime).map_err(|e| {
+                        anyhow::anyhow!("Invalid pair.expires in the config: {}", e)
+                    })?),
+                    (expires, _) => expires,
+                };
+            let tags = if tags.is_empty() {
+                cfg.pair.tags.clone()
+            } else {
+                tags
+            };
+            let mdns = mdns || (cfg.pair.mdns && relay.is_none());
+            let algorithm = key_algorithm(rsa, key_type);
+            if print_authorized_line {
+                return commands::pair::run_stdio(
+                    comment,
+                    algorithm,
+                    key,
+                    accept_new_identity,
+                    tags,
+                    expires,
+                    alias,
+                    user,
+                    timeout.map(Duration::from_secs),
+                )
+                .await;
+            }
+            let targets = match (relay, code) {
+                (Some(relay), Some(code)) => commands::pair::Targets::Relay { relay, code },
+                _ if all => commands::pair::Targets::All,
+                _ => commands::pair::Targets::Listed(targets),
+            };
+            commands::pair::run(
+                targets,
+                comment,
+                algorithm,
+                key,
+                accept_new_identity,
+                tags,
+                expires,
+                alias,
+                mdns,
+                user,
+                timeout.map(Duration::from_secs),
+                retries,
+            )
+            .await
+        }
+        Commands::Keys { action, plain } => commands::keys::run(action, plain).await,
+        Commands::Keygen {
+            name,
+            comment,
+            rsa,
+            key_type,
+        } => commands::keygen::run(name, comment, key_algorithm(rsa, key_type)).await,
         Commands::Config { action } => run_config(action),
-        Commands::Hosts => run_hosts(),
-        Commands::Unpair { host } => run_unpair(&host),
-        Commands::Test { host } => run_test(&host),
+        Commands::Hosts { plain } => run_hosts(plain, cli.verbose),
+        Commands::History { action, plain } => commands::history::run(action, plain),
+        Commands::Audit { action, plain } => commands::audit::run(action, plain),
+        Commands::Tag { host, tags, remove } => run_tag(&host, tags, remove),
+        Commands::Unpair {
+            host,
+            shred,
+            remote,
+            port,
+        } => {
+            let port = policy_port(&matches, "unpair", port, config::Config::default_port);
+            run_unpair(&host, shred, remote, port).await
+        }
+        Commands::Revoke { host, port } => {
+            let port = policy_port(&matches, "revoke", port, config::Config::default_port);
+            commands::revoke::run(&host, port).await
+        }
+        Commands::Rotate {
+            host,
+            all: _,
+            key_type,
+            shred,
+        } => commands::rotate::run(host.as_deref(), key_type.unwrap_or_default(), shred),
+        Commands::Prune { dry_run } => commands::prune::run(dry_run),
+        Commands::Test { host, fix, builtin } => commands::test::run(&host, fix, builtin).await,
+        Commands::Run {
+            host,
+            command,
+            timeout,
+        } => commands::run::run(&host, &command, timeout),
+        Commands::Repair { host } => commands::repair::run(host).await,
+        Commands::Doctor { port, format } => {
+            let port = policy_port(&matches, "doctor", port, config::Config::listen_port);
+            commands::doctor::run(port, format).await
+        }
         Commands::UpdateIp { host, ip } => run_update_ip(&host, &ip),
-        Commands::Export { output } => run_export(output.as_deref()),
-        Commands::Import { file } => run_import(&file),
+        Commands::RestoreConfig { list } => commands::restore_config::run(list),
+        Commands::Export {
+            output,
+            format,
+            sections,
+            protection,
+        } => commands::export::export(sections, format, output.as_deref(), protection),
+        Commands::Import {
+            file,
+            sections,
+            dry_run,
+        } => commands::export::import(&file, sections, dry_run),
         Commands::Completions { shell } => {
             generate(
                 shell,
@@ -310,27 +1345,175 @@ async fn main() -> Result<()> {
             name,
             timeout,
             rsa,
+            key_type,
             key,
-        } => commands::sync::run(port, name, timeout, rsa, key).await,
+            share,
+            share_all,
+            accept_new_identity,
+            approve,
+            approval_timeout,
+            deny,
+            daemon,
+            interval,
+        } => {
+            let port = policy_port(&matches, "sync", port, config::Config::default_port);
+            let algorithm = key_algorithm(rsa, key_type);
+            let daemon = daemon.then(|| Duration::from_secs(interval));
+            let policy = commands::sync::AcceptPolicy {
+                approval: approve.then(|| Duration::from_secs(approval_timeout)),
+                deny,
+            };
+            commands::sync::run(
+                port,
+                name,
+                timeout,
+                algorithm,
+                key,
+                share,
+                share_all,
+                accept_new_identity,
+                policy,
+                daemon,
+            )
+            .await
+        }
+        Commands::Trust { action, plain } => commands::trust::run(action, plain),
+        Commands::KnownHosts { action, plain } => commands::known_hosts::run(action, plain),
+        Commands::Send {
+            device,
+            files,
+            port,
+            accept_new_identity,
+        } => commands::transfer::send(device, files, port, accept_new_identity).await,
+        Commands::Receive {
+            port,
+            dir,
+            continuous,
+            approval_timeout,
+        } => commands::transfer::receive(port, dir, continuous, approval_timeout).await,
+        Commands::Relay { action } => match action {
+            RelayAction::Serve { port, wait } => commands::relay::serve(port, wait).await,
+        },
+        Commands::KeepWarm { action, plain } => commands::keep_warm::run(action, plain).await,
         Commands::Ssh { action } => match action {
             SshAction::On => commands::ssh::enable().await,
             SshAction::Off => commands::ssh::disable().await,
             SshAction::Status => commands::ssh::status().await,
         },
+        Commands::Service { action } => commands::service::run(action),
+        Commands::External(args) => {
+            let builtins = Cli::command()
+                .get_subcommands()
+                .map(|command| command.get_name().to_string())
+                .collect();
+            commands::external::run(args, builtins, cli.verbose)
+        }
+    }
+}
+
+/// Use the machine policy's port unless one was given on the command line
+/// Parser for `--type`, accepting the short algorithm names
+fn key_type_parser() -> impl TypedValueParser<Value = KeyAlgorithm> {
+    PossibleValuesParser::new(KeyAlgorithm::ALL.map(KeyAlgorithm::name)).map(|name| {
+        name.parse()
+            .expect("possible values are valid algorithm names")
+    })
+}
+
+/// Algorithm to generate keys with, from `--type` or the legacy `--rsa` flag
+fn key_algorithm(rsa: bool, key_type: Option<KeyAlgorithm>) -> KeyAlgorithm {
+    key_type.unwrap_or(if rsa {
+        KeyAlgorithm::Rsa4096
+    } else {
+        KeyAlgorithm::default()
+    })
+}
+
+/// Parser for tags: letters, digits, `-`, `_` and `.`
+fn parse_tag(tag: &str) -> std::result::Result<String, String> {
+    if !tag.is_empty()
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        Ok(tag.to_string())
+    } else {
+        Err("tags may only contain letters, digits, '-', '_' and '.'".to_string())
     }
 }
 
-fn run_hosts() -> Result<()> {
+/// Parser for SSH host aliases, which must not be patterns
+fn parse_alias(alias: &str) -> std::result::Result<String, String> {
+    if !alias.is_empty()
+        && alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        Ok(alias.to_string())
+    } else {
+        Err("aliases may only contain letters, digits, '-', '_' and '.'".to_string())
+    }
+}
+
+/// Parser for key lifetimes: a number and a unit, `m`, `h`, `d` or `w`
+fn parse_lifetime(lifetime: &str) -> std::result::Result<Duration, String> {
+    let unit_secs = match lifetime.chars().last() {
+        Some('m') => 60,
+        Some('h') => 3_600,
+        Some('d') => 86_400,
+        Some('w') => 604_800,
+        _ => return Err("expected a number and a unit: m, h, d or w (e.g. 30d)".to_string()),
+    };
+    match lifetime[..lifetime.len() - 1].parse::<u64>() {
+        Ok(count) if count > 0 => count
+            .checked_mul(unit_secs)
+            .map(Duration::from_secs)
+            .ok_or_else(|| "lifetime is too long".to_string()),
+        _ => Err("expected a positive number before the unit (e.g. 30d)".to_string()),
+    }
+}
+
+/// Parser for authorized_keys options, e.g. `no-pty` or `from="10.0.0.*"`
+fn parse_key_option(option: &str) -> std::result::Result<String, String> {
+    option
+        .parse::<KeyOptions>()
+        .map(|_| option.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Whether `arg` of `subcommand` was given rather than left at its default
+fn given(matches: &ArgMatches, subcommand: &str, arg: &str) -> bool {
+    matches
+        .subcommand_matches(subcommand)
+        .and_then(|m| m.value_source(arg))
+        .is_some_and(|source| source != ValueSource::DefaultValue)
+}
+
+/// `port` if it was given, otherwise the port `configured` picks from the
+/// config and machine policy
+fn policy_port(
+    matches: &ArgMatches,
+    subcommand: &str,
+    port: u16,
+    configured: fn(&config::Config) -> u16,
+) -> u16 {
+    if given(matches, subcommand, "port") {
+        port
+    } else {
+        configured(&config::Config::load().unwrap_or_default())
+    }
+}
+
+fn run_hosts(plain: bool, verbose: bool) -> Result<()> {
     use colored::Colorize;
     use std::fs;
 
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| anyhow::anyhow!("HOME/USERPROFILE not set"))?;
-    let config_path = std::path::PathBuf::from(&home).join(".ssh").join("config");
+    let config_path = connecto_core::paths::ssh_config_file()?;
 
     if !config_path.exists() {
-        println!("{}", "No SSH config file found.".dimmed());
+        if !plain {
+            println!("{}", "No SSH config file found.".dimmed());
+        }
         return Ok(());
     }
 
@@ -363,7 +1546,9 @@ fn run_hosts() -> Result<()> {
                 }
                 current_host = Some(trimmed.strip_prefix("Host ").unwrap().to_string());
             } else if trimmed.starts_with("HostName ") {
-                current_hostname = Some(trimmed.strip_prefix("HostName ").unwrap().to_string());
+                current_hostname = Some(connecto_core::ssh_config::parse_arg(
+                    trimmed.strip_prefix("HostName ").unwrap(),
+                ));
             } else if trimmed.starts_with("User ") {
                 current_user = Some(trimmed.strip_prefix("User ").unwrap().to_string());
             } else if trimmed.starts_with("IdentityFile ") {
@@ -387,7 +1572,94 @@ fn run_hosts() -> Result<()> {
         connecto_hosts.push((h, hn, u));
     }
 
-    if connecto_hosts.is_empty() {
+    // The pairing database adds when and how each host was paired
+    let history = if verbose {
+        connecto_core::pairings::PairingStore::new()?.all()?
+    } else {
+        Vec::new()
+    };
+
+    // Tags only show in the table for people, keeping --plain columns stable
+    let tags: std::collections::HashMap<String, Vec<String>> =
+        connecto_core::ssh_config::parse_entries(&content)
+            .into_iter()
+            .filter(|e| !e.tags.is_empty())
+            .map(|e| (e.host, e.tags))
+            .collect();
+    let show_tags = !plain && !tags.is_empty();
+
+    let mut headers = vec!["HOST", "USER", "HOSTNAME"];
+    if show_tags {
+        headers.push("TAGS");
+    }
+    if verbose {
+        headers.extend(["PAIRED", "DIRECTION", "FINGERPRINT"]);
+    }
+    let mut table = commands::table::Table::new(headers)
+        .style(0, |s| s.cyan().bold())
+        .style(1, |s| s.dimmed())
+        .style(2, |s| s.dimmed())
+        .style(5 + usize::from(show_tags), |s| s.dimmed());
+    for (host, hostname, user) in connecto_hosts {
+        let mut row = vec![host, user, hostname];
+        if show_tags {
+            row.push(tags.get(&row[0]).map(|t| t.join(" ")).unwrap_or_default());
+        }
+        if verbose {
+            let latest = history
+                .iter()
+                .filter(|r| r.host.as_deref() == Some(row[0].as_str()))
+                .max_by_key(|r| r.paired_at);
+            if let Some(record) = latest {
+                row.extend([
+                    format_utc(record.paired_at, UTC_DISPLAY_FORMAT),
+                    record.direction.to_string(),
+                    record.fingerprint.clone(),
+                ]);
+            }
+        }
+        table.push_row(row);
+    }
+
+    if plain {
+        table.print(true);
+        return Ok(());
+    }
+
+    if verbose && !history.is_empty() {
+        let mut log = commands::table::Table::new([
+            "PAIRED",
+            "DIRECTION",
+            "PEER",
+            "ADDRESS",
+            "CLOCK",
+            "FINGERPRINT",
+        ])
+        .style(0, |s| s.dimmed())
+        .style(2, |s| s.cyan())
+        .style(5, |s| s.dimmed());
+        for record in history.iter().rev() {
+            // How far the peer's clock was from ours when pairing
+            let clock = record
+                .clock_skew
+                .map(connecto_core::clock::describe)
+                .unwrap_or_default();
+            log.push_row(vec![
+                format_utc(record.paired_at, UTC_DISPLAY_FORMAT),
+                record.direction.to_string(),
+                record.peer_name.clone(),
+                record.address.clone(),
+                clock,
+                record.fingerprint.clone(),
+            ]);
+        }
+        println!("{}", "Pairing history:".bold());
+        println!();
+        log.print(false);
+        println!();
+    }
+
+    if table.is_empty() {
         println!("{}", "No paired hosts found.".dimmed());
         println!();
         println!(
@@ -399,24 +1671,43 @@ fn run_hosts() -> Result<()> {
 
     println!("{}", "Paired hosts:".bold());
     println!();
-    for (host, hostname, user) in &connecto_hosts {
-        println!(
-            "  {} {} → {}@{}",
-            "•".green(),
-            host.cyan().bold(),
-            user.dimmed(),
-            hostname.dimmed()
-        );
-    }
+    table.print(false);
     println!();
     println!("{}", "Connect with:".dimmed());
-    println!("  {} ssh <hostname>", "→".cyan());
+    println!("  {} ssh <hostname>", mark("→").cyan());
     println!();
 
     Ok(())
 }
 
+/// Save listener access rules to the config
+fn add_listen_access(rules: AccessList) -> Result<()> {
+    use colored::Colorize;
+
+    let mut cfg = config::Config::load()?;
+    let rule = rules
+        .allow
+        .iter()
+        .chain(&rules.deny)
+        .map(|network| network.to_string())
+        .chain(rules.allow_names.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if cfg.add_listen_access(&rules) {
+        cfg.save()?;
+        println!("{} Added access rule: {}", mark("✓").green(), rule.cyan());
+    } else {
+        println!(
+            "{} Access rule already exists: {}",
+            mark("→").yellow(),
+            rule
+        );
+    }
+    Ok(())
+}
+
 fn run_config(action: ConfigAction) -> Result<()> {
+    use clap::ValueEnum;
     use colored::Colorize;
 
     match action {
@@ -424,36 +1715,30 @@ fn run_config(action: ConfigAction) -> Result<()> {
             let mut cfg = config::Config::load()?;
             if cfg.add_subnet(&subnet) {
                 cfg.save()?;
-                println!("{} Added subnet: {}", "✓".green(), subnet.cyan());
+                println!("{} Added subnet: {}", mark("✓").green(), subnet.cyan());
             } else {
-                println!("{} Subnet already exists: {}", "→".yellow(), subnet);
+                println!("{} Subnet already exists: {}", mark("→").yellow(), subnet);
             }
         }
         ConfigAction::RemoveSubnet { subnet } => {
             let mut cfg = config::Config::load()?;
             if cfg.remove_subnet(&subnet) {
                 cfg.save()?;
-                println!("{} Removed subnet: {}", "✓".green(), subnet);
+                println!("{} Removed subnet: {}", mark("✓").green(), subnet);
             } else {
-                println!("{} Subnet not found: {}", "✗".red(), subnet);
+                println!("{} Subnet not found: {}", mark("✗").red(), subnet);
             }
         }
         ConfigAction::SetDefaultKey { key_path } => {
             // Expand ~ to home directory
-            let expanded_path = if key_path.starts_with("~/") {
-                let home = std::env::var("HOME")
-                    .or_else(|_| std::env::var("USERPROFILE"))
-                    .map_err(|_| anyhow::anyhow!("HOME/USERPROFILE not set"))?;
-                key_path.replacen("~", &home, 1)
-            } else {
-                key_path.clone()
-            };
+            let expanded_path = connecto_core::paths::expand_home(&key_path)?
+                .to_string_lossy()
+                .to_string();
 
             // Verify the key exists
             let key_file = std::path::Path::new(&expanded_path);
             if !key_file.exists() {
-                println!("{} Key file not found: {}", "✗".red(), expanded_path);
-                return Ok(());
+                return Err(anyhow::anyhow!("Key file not found: {}", expanded_path));
             }
 
             // Verify it's a valid SSH key (check for public key too)
@@ -461,12 +1746,12 @@ fn run_config(action: ConfigAction) -> Result<()> {
             if !std::path::Path::new(&pub_key_path).exists() {
                 println!(
                     "{} Public key not found: {}",
-                    "✗".red(),
+                    mark("✗").red(),
                     pub_key_path.dimmed()
                 );
                 println!(
                     "  {} Both private and public key files are required.",
-                    "→".yellow()
+                    mark("→").yellow()
                 );
                 return Ok(());
             }
@@ -474,29 +1759,209 @@ fn run_config(action: ConfigAction) -> Result<()> {
             let mut cfg = config::Config::load()?;
             cfg.set_default_key(&expanded_path);
             cfg.save()?;
-            println!("{} Default key set: {}", "✓".green(), expanded_path.cyan());
-            println!("  {} All future pairings will use this key.", "→".dimmed());
+            println!(
+                "{} Default key set: {}",
+                mark("✓").green(),
+                expanded_path.cyan()
+            );
+            println!(
+                "  {} All future pairings will use this key.",
+                mark("→").dimmed()
+            );
         }
         ConfigAction::ClearDefaultKey => {
             let mut cfg = config::Config::load()?;
             if cfg.default_key.is_some() {
                 cfg.clear_default_key();
                 cfg.save()?;
-                println!("{} Default key cleared.", "✓".green());
-                println!("  {} Pairings will generate new keys again.", "→".dimmed());
+                println!("{} Default key cleared.", mark("✓").green());
+                println!(
+                    "  {} Pairings will generate new keys again.",
+                    mark("→").dimmed()
+                );
+            } else {
+                println!("{} No default key was set.", mark("→").yellow());
+            }
+        }
+        ConfigAction::SetPort { port } => {
+            if port == 0 {
+                return Err(anyhow::anyhow!("Port must be between 1 and 65535"));
+            }
+            let mut cfg = config::Config::load()?;
+            cfg.set_port(port);
+            cfg.save()?;
+            println!(
+                "{} Default port set: {}",
+                mark("✓").green(),
+                port.to_string().cyan()
+            );
+            if let Some(policy_port) = cfg.policy.as_ref().and_then(|p| p.port) {
+                println!(
+                    "  {} The machine policy's port {} still wins.",
+                    mark("→").yellow(),
+                    policy_port
+                );
+            }
+        }
+        ConfigAction::ClearPort => {
+            let mut cfg = config::Config::load()?;
+            if cfg.port.is_some() {
+                cfg.clear_port();
+                cfg.save()?;
+                println!(
+                    "{} Default port cleared, using {}.",
+                    mark("✓").green(),
+                    cfg.default_port().to_string().cyan()
+                );
+            } else {
+                println!("{} No default port was set.", mark("→").yellow());
+            }
+        }
+        ConfigAction::AddProfile { name } => {
+            let mut cfg = config::Config::load()?;
+            if cfg.add_profile(&name)? {
+                cfg.save()?;
+                println!("{} Added profile: {}", mark("✓").green(), name.cyan());
+                println!(
+                    "  {} Fill it with: {}",
+                    mark("→").dimmed(),
+                    format!("connecto --profile {} config add-subnet <cidr>", name).cyan()
+                );
+            } else {
+                println!("{} Profile already exists: {}", mark("→").yellow(), name);
+            }
+        }
+        ConfigAction::RemoveProfile { name } => {
+            let mut cfg = config::Config::load()?;
+            if cfg.remove_profile(&name)? {
+                cfg.save()?;
+                println!("{} Removed profile: {}", mark("✓").green(), name);
+            } else {
+                println!("{} Profile not found: {}", mark("✗").red(), name);
+            }
+        }
+        ConfigAction::UseProfile { name } => {
+            let mut cfg = config::Config::load()?;
+            cfg.use_profile(&name)?;
+            cfg.save()?;
+            println!("{} Using profile: {}", mark("✓").green(), name.cyan());
+        }
+        ConfigAction::Profiles => {
+            let cfg = config::Config::load()?;
+            let active = cfg.active_profile().unwrap_or(config::DEFAULT_PROFILE);
+            for name in std::iter::once(config::DEFAULT_PROFILE).chain(cfg.profile_names()) {
+                if name == active {
+                    println!("  {} {}", mark("•").green(), name.cyan());
+                } else {
+                    println!("    {}", name);
+                }
+            }
+        }
+        ConfigAction::SetName { name } => {
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(anyhow::anyhow!("Device name cannot be empty"));
+            }
+            let mut cfg = config::Config::load()?;
+            cfg.set_device_name(name);
+            cfg.save()?;
+            println!("{} Device name set: {}", mark("✓").green(), name.cyan());
+            println!(
+                "  {} listen, sync and pair will announce this name.",
+                mark("→").dimmed()
+            );
+        }
+        ConfigAction::ClearName => {
+            let mut cfg = config::Config::load()?;
+            if cfg.device_name.is_some() {
+                cfg.clear_device_name();
+                cfg.save()?;
+                println!(
+                    "{} Device name cleared, using {}.",
+                    mark("✓").green(),
+                    cfg.device_name().cyan()
+                );
+            } else {
+                println!("{} No device name was set.", mark("→").yellow());
+            }
+        }
+        ConfigAction::SetTemplate { tag, option, value } => {
+            let reserved = ["Host", "Match", "HostName", "User", "IdentityFile"];
+            if reserved.iter().any(|r| r.eq_ignore_ascii_case(&option)) {
+                anyhow::bail!("{} is set by Connecto and cannot be templated", option);
+            }
+            if option.is_empty() || !option.chars().all(|c| c.is_ascii_alphanumeric()) {
+                anyhow::bail!("'{}' is not an SSH option name", option);
+            }
+            if value.trim().is_empty() || value.contains('\n') {
+                anyhow::bail!("The value must be a single non-empty line");
+            }
+            let mut cfg = config::Config::load()?;
+            cfg.set_template(&tag, &option, value.trim());
+            cfg.save()?;
+            println!(
+                "{} Hosts tagged {} get {} {}",
+                mark("✓").green(),
+                tag.cyan(),
+                option,
+                value.trim()
+            );
+            apply_ssh_templates(&cfg)?;
+        }
+        ConfigAction::RemoveTemplate { tag, option } => {
+            let mut cfg = config::Config::load()?;
+            if cfg.remove_template(&tag, option.as_deref()) {
+                cfg.save()?;
+                match &option {
+                    Some(option) => println!(
+                        "{} Removed {} from the {} template",
+                        mark("✓").green(),
+                        option,
+                        tag.cyan()
+                    ),
+                    None => println!("{} Removed the {} template", mark("✓").green(), tag.cyan()),
+                }
+                apply_ssh_templates(&cfg)?;
+            } else {
+                println!("{} Nothing to remove.", mark("→").yellow());
+            }
+        }
+        ConfigAction::Allow { address } => add_listen_access(AccessList {
+            allow: vec![address],
+            ..Default::default()
+        })?,
+        ConfigAction::Deny { address } => add_listen_access(AccessList {
+            deny: vec![address],
+            ..Default::default()
+        })?,
+        ConfigAction::AllowName { pattern } => add_listen_access(AccessList {
+            allow_names: vec![pattern],
+            ..Default::default()
+        })?,
+        ConfigAction::RemoveAccess { rule } => {
+            let mut cfg = config::Config::load()?;
+            if cfg.remove_listen_access(&rule) {
+                cfg.save()?;
+                println!("{} Removed access rule: {}", mark("✓").green(), rule);
             } else {
-                println!("{} No default key was set.", "→".yellow());
+                println!("{} Access rule not found: {}", mark("✗").red(), rule);
             }
         }
         ConfigAction::List => {
             let cfg = config::Config::load()?;
             let mut has_config = false;
 
+            if let Some(profile) = cfg.active_profile() {
+                has_config = true;
+                println!("{} {}", "Profile:".bold(), profile.cyan());
+                println!();
+            }
+
             if !cfg.subnets.is_empty() {
                 has_config = true;
                 println!("{}", "Configured subnets:".bold());
                 for subnet in &cfg.subnets {
-                    println!("  {} {}", "•".cyan(), subnet);
+                    println!("  {} {}", mark("•").cyan(), subnet);
                 }
             }
 
@@ -504,51 +1969,276 @@ fn run_config(action: ConfigAction) -> Result<()> {
                 has_config = true;
                 println!();
                 println!("{}", "Default SSH key:".bold());
-                println!("  {} {}", "•".cyan(), key);
+                println!("  {} {}", mark("•").cyan(), key);
             }
 
-            if !has_config {
-                println!("{}", "No configuration set.".dimmed());
+            if let Some(port) = cfg.port {
+                has_config = true;
                 println!();
-                println!(
-                    "Add subnets to scan with: {}",
-                    "connecto config add-subnet <cidr>".cyan()
-                );
-                println!(
-                    "Set default key with: {}",
-                    "connecto config set-default-key <path>".cyan()
-                );
+                println!("{}", "Default port:".bold());
+                println!("  {} {}", mark("•").cyan(), port);
             }
-        }
-        ConfigAction::Path => {
-            let path = config::Config::path()?;
-            println!("{}", path.display());
-        }
-    }
-    Ok(())
-}
-
-/// Remove a paired host from SSH config and delete its keys
-fn run_unpair(host: &str) -> Result<()> {
-    use colored::Colorize;
-    use std::fs;
 
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| anyhow::anyhow!("HOME/USERPROFILE not set"))?;
-    let ssh_dir = std::path::PathBuf::from(&home).join(".ssh");
-    let config_path = ssh_dir.join("config");
+            if let Some(name) = &cfg.device_name {
+                has_config = true;
+                println!();
+                println!("{}", "Device name:".bold());
+                println!("  {} {}", mark("•").cyan(), name);
+            }
 
-    if !config_path.exists() {
-        println!("{} No SSH config file found.", "✗".red());
+            if !cfg.scan.columns.is_empty() || cfg.scan.sort.is_some() {
+                has_config = true;
+                println!();
+                println!("{}", "Scan output:".bold());
+                if !cfg.scan.columns.is_empty() {
+                    let columns: Vec<String> = cfg
+                        .scan
+                        .columns
+                        .iter()
+                        .filter_map(|c| c.to_possible_value())
+                        .map(|v| v.get_name().to_string())
+                        .collect();
+                    println!("  {} columns: {}", mark("•").cyan(), columns.join(","));
+                }
+                if let Some(sort) = cfg.scan.sort.and_then(|s| s.to_possible_value()) {
+                    println!("  {} sort: {}", mark("•").cyan(), sort.get_name());
+                }
+            }
+
+            if !cfg.ssh_templates.is_empty() {
+                has_config = true;
+                println!();
+                println!("{}", "SSH templates:".bold());
+                for (tag, template) in &cfg.ssh_templates {
+                    for (option, value) in template {
+                        println!("  {} {}: {} {}", mark("•").cyan(), tag, option, value);
+                    }
+                }
+            }
+
+            let access = &cfg.listen_access;
+            if !access.is_empty() {
+                has_config = true;
+                println!();
+                println!("{}", "Listener access:".bold());
+                for network in &access.allow {
+                    println!("  {} allow {}", mark("•").cyan(), network);
+                }
+                for network in &access.deny {
+                    println!("  {} deny {}", mark("•").cyan(), network);
+                }
+                for pattern in &access.allow_names {
+                    println!("  {} allow name {}", mark("•").cyan(), pattern);
+                }
+            }
+
+            if let Some(policy) = &cfg.policy {
+                has_config = true;
+                println!();
+                println!(
+                    "{} {}",
+                    "Machine policy:".bold(),
+                    policy::machine_policy_path().display().to_string().dimmed()
+                );
+                if let Some(port) = policy.port {
+                    println!("  {} port: {}", mark("•").cyan(), port);
+                }
+                if policy.require_verification {
+                    println!("  {} verification code required", mark("•").cyan());
+                }
+                if !policy.allowed_algorithms.is_empty() {
+                    let algorithms: Vec<String> = policy
+                        .allowed_algorithms
+                        .iter()
+                        .filter_map(|a| a.to_possible_value())
+                        .map(|v| v.get_name().to_string())
+                        .collect();
+                    println!(
+                        "  {} algorithms: {}",
+                        mark("•").cyan(),
+                        algorithms.join(",")
+                    );
+                }
+                for subnet in &policy.trusted_subnets {
+                    println!("  {} trusted subnet: {}", mark("•").cyan(), subnet);
+                }
+            }
+
+            if !has_config {
+                println!("{}", "No configuration set.".dimmed());
+                println!();
+                println!(
+                    "Add subnets to scan with: {}",
+                    "connecto config add-subnet <cidr>".cyan()
+                );
+                println!(
+                    "Set default key with: {}",
+                    "connecto config set-default-key <path>".cyan()
+                );
+            }
+        }
+        ConfigAction::Path => {
+            let path = config::Config::path()?;
+            println!("{}", path.display());
+        }
+        ConfigAction::ExportPolicy {
+            key,
+            port,
+            require_verification,
+            allow_algorithms,
+            subnets,
+            output,
+        } => {
+            let key_pair = connecto_core::keys::SshKeyPair::load_from_file(&key)?;
+            let subnets = if subnets.is_empty() {
+                config::Config::load()?.subnets
+            } else {
+                subnets
+            };
+            let policy = policy::Policy {
+                port,
+                require_verification,
+                allowed_algorithms: allow_algorithms,
+                trusted_subnets: subnets,
+            };
+            let bundle = policy::PolicyBundle::sign(&policy, &key_pair)?;
+            let json = serde_json::to_string_pretty(&bundle)?;
+
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, json)?;
+                    eprintln!("{} Exported policy to {}", mark("✓").green(), path.cyan());
+                    eprintln!(
+                        "  {} Signed by {}",
+                        mark("→").dimmed(),
+                        bundle.signer_fingerprint()?.cyan()
+                    );
+                }
+                None => println!("{}", json),
+            }
+        }
+        ConfigAction::ApplyPolicy { file, signer } => {
+            let content = std::fs::read_to_string(&file)?;
+            let bundle: policy::PolicyBundle = serde_json::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("Invalid policy bundle {}: {}", file, e))?;
+
+            // Accept either a public key file or the key itself
+            let signer = match signer {
+                Some(s) if std::path::Path::new(&s).is_file() => {
+                    Some(std::fs::read_to_string(&s)?.trim().to_string())
+                }
+                other => other,
+            };
+
+            let path = policy::machine_policy_path();
+            policy::apply(&path, &bundle, signer.as_deref())?;
+            println!("{} Applied policy to {}", mark("✓").green(), path.display());
+            println!(
+                "  {} Signed by {}",
+                mark("→").dimmed(),
+                bundle.signer_fingerprint()?.cyan()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Remove a paired host from SSH config and delete its keys
+/// Bring the options of tagged hosts in ~/.ssh/config in line with the templates
+fn apply_ssh_templates(cfg: &config::Config) -> Result<()> {
+    use colored::Colorize;
+
+    let updated =
+        connecto_core::ssh_config::SshConfig::new()?.apply_templates(&cfg.ssh_templates)?;
+    for host in updated {
+        println!("  {} Updated {}", mark("→").cyan(), host.cyan());
+    }
+    Ok(())
+}
+
+fn run_tag(host: &str, tags: Vec<String>, remove: bool) -> Result<()> {
+    use colored::Colorize;
+
+    let ssh_config = connecto_core::ssh_config::SshConfig::new()?;
+    let entry = ssh_config
+        .entries()?
+        .into_iter()
+        .find(|e| e.host == host)
+        .ok_or_else(|| anyhow::anyhow!("Host '{}' not found in SSH config", host))?;
+
+    if tags.is_empty() {
+        if entry.tags.is_empty() {
+            println!("{} {} has no tags.", mark("→").yellow(), host.cyan());
+        } else {
+            println!("{}", entry.tags.join(" "));
+        }
         return Ok(());
     }
 
+    let mut new_tags = entry.tags.clone();
+    if remove {
+        new_tags.retain(|t| !tags.contains(t));
+    } else {
+        for tag in tags {
+            if !new_tags.contains(&tag) {
+                new_tags.push(tag);
+            }
+        }
+    }
+
+    let cfg = config::Config::load().unwrap_or_default();
+    if !ssh_config.set_tags(host, &new_tags, &cfg.ssh_templates)? {
+        println!("{} Nothing to change.", mark("→").yellow());
+        return Ok(());
+    }
+
+    if new_tags.is_empty() {
+        println!(
+            "{} Removed all tags from {}",
+            mark("✓").green(),
+            host.cyan()
+        );
+    } else {
+        println!(
+            "{} {} is tagged {}",
+            mark("✓").green(),
+            host.cyan(),
+            new_tags.join(" ")
+        );
+    }
+    for tag in new_tags
+        .iter()
+        .filter(|t| !cfg.ssh_templates.contains_key(*t))
+    {
+        println!(
+            "  {} No template for {} yet; add one with {}",
+            mark("→").yellow(),
+            tag,
+            format!("connecto config set-template {} <option> <value>", tag).cyan()
+        );
+    }
+    Ok(())
+}
+
+async fn run_unpair(host: &str, shred: bool, remote: bool, port: u16) -> Result<()> {
+    use colored::Colorize;
+    use connecto_core::backups;
+    use std::fs;
+
+    let ssh_dir = connecto_core::paths::ssh_dir()?;
+    let config_path = ssh_dir.join("config");
+
+    if !config_path.exists() {
+        return Err(anyhow::anyhow!("No SSH config file found"));
+    }
+
+    let _lock = backups::lock(&config_path)?;
     let content = fs::read_to_string(&config_path)?;
     let mut new_lines: Vec<&str> = Vec::new();
     let mut skip_block = false;
     let mut found = false;
     let mut identity_file: Option<String> = None;
+    let mut hostname: Option<String> = None;
 
     for line in content.lines() {
         let trimmed = line.trim();
@@ -575,13 +2265,14 @@ fn run_unpair(host: &str) -> Result<()> {
 
         if skip_block {
             if trimmed.starts_with("IdentityFile ") {
-                identity_file = Some(
-                    trimmed
-                        .strip_prefix("IdentityFile ")
-                        .unwrap()
-                        .trim()
-                        .to_string(),
-                );
+                identity_file = Some(connecto_core::ssh_config::parse_arg(
+                    trimmed.strip_prefix("IdentityFile ").unwrap(),
+                ));
+            }
+            if trimmed.starts_with("HostName ") {
+                hostname = Some(connecto_core::ssh_config::parse_arg(
+                    trimmed.strip_prefix("HostName ").unwrap(),
+                ));
             }
             if trimmed.is_empty()
                 || (trimmed.starts_with("Host ") && !trimmed.starts_with("HostName"))
@@ -598,13 +2289,65 @@ fn run_unpair(host: &str) -> Result<()> {
     }
 
     if !found {
-        println!("{} Host '{}' not found in SSH config.", "✗".red(), host);
-        return Ok(());
+        return Err(anyhow::anyhow!("Host '{}' not found in SSH config", host));
+    }
+
+    // Revoke remotely while the host entry and key still exist
+    if remote {
+        match identity_file.as_deref() {
+            Some(key_path) => match revoke_on_host(
+                host,
+                &connecto_core::net::join_host_port(hostname.as_deref().unwrap_or(host), port),
+                key_path,
+                revoke_remote_key,
+            )
+            .await
+            {
+                Ok(RemoteRevocation::Listener) => println!(
+                    "{} {}'s listener removed your key from its authorized_keys.",
+                    mark("✓").green(),
+                    host.cyan()
+                ),
+                Ok(RemoteRevocation::Ssh(0)) => println!(
+                    "{} Your key was not authorized on {}.",
+                    mark("→").cyan(),
+                    host.cyan()
+                ),
+                Ok(RemoteRevocation::Ssh(_)) => println!(
+                    "{} Removed your key from {}'s authorized_keys.",
+                    mark("✓").green(),
+                    host.cyan()
+                ),
+                Err(e) => {
+                    println!(
+                        "{} Could not remove your key from {}: {}",
+                        mark("✗").red(),
+                        host.cyan(),
+                        e
+                    );
+                    println!(
+                        "  {} {} still trusts the key; remove it there with {}",
+                        mark("→").cyan(),
+                        host,
+                        "connecto keys remove".cyan()
+                    );
+                }
+            },
+            None => println!(
+                "{} No IdentityFile for '{}'; skipping remote removal.",
+                mark("!").yellow(),
+                host
+            ),
+        }
     }
 
     // Write updated config
-    fs::write(&config_path, new_lines.join("\n") + "\n")?;
-    println!("{} Removed '{}' from SSH config.", "✓".green(), host.cyan());
+    backups::replace(&config_path, &(new_lines.join("\n") + "\n"))?;
+    println!(
+        "{} Removed '{}' from SSH config.",
+        mark("✓").green(),
+        host.cyan()
+    );
 
     // Delete key files
     if let Some(key_path) = identity_file {
@@ -612,10 +2355,15 @@ fn run_unpair(host: &str) -> Result<()> {
         let pub_path = key_path.with_extension("pub");
 
         if key_path.exists() {
-            fs::remove_file(&key_path)?;
+            if shred {
+                connecto_core::KeyManager::secure_delete(&key_path)?;
+            } else {
+                fs::remove_file(&key_path)?;
+            }
             println!(
-                "{} Deleted private key: {}",
-                "✓".green(),
+                "{} {} private key: {}",
+                mark("✓").green(),
+                if shred { "Shredded" } else { "Deleted" },
                 key_path.display().to_string().dimmed()
             );
         }
@@ -623,7 +2371,7 @@ fn run_unpair(host: &str) -> Result<()> {
             fs::remove_file(&pub_path)?;
             println!(
                 "{} Deleted public key: {}",
-                "✓".green(),
+                mark("✓").green(),
                 pub_path.display().to_string().dimmed()
             );
         }
@@ -632,87 +2380,78 @@ fn run_unpair(host: &str) -> Result<()> {
     Ok(())
 }
 
-/// Test SSH connection to a paired host
-fn run_test(host: &str) -> Result<()> {
-    use colored::Colorize;
-    use std::process::Command;
-
-    println!(
-        "{} Testing connection to {}...",
-        "→".cyan(),
-        host.cyan().bold()
-    );
-
-    let output = Command::new("ssh")
-        .args([
-            "-o",
-            "ConnectTimeout=5",
-            "-o",
-            "BatchMode=yes",
-            host,
-            "echo",
-            "connecto-ok",
-        ])
-        .output();
+/// How `unpair --remote` removed the key from the host
+#[derive(Debug, PartialEq, Eq)]
+enum RemoteRevocation {
+    /// The host's listener removed it
+    Listener,
+    /// Over SSH, removing this many authorized_keys entries
+    Ssh(usize),
+}
 
-    match output {
-        Ok(result) => {
-            if result.status.success() {
-                let stdout = String::from_utf8_lossy(&result.stdout);
-                if stdout.trim() == "connecto-ok" {
-                    println!("{} Connection successful!", "✓".green());
-                    Ok(())
-                } else {
-                    println!(
-                        "{} Connection established but unexpected response.",
-                        "⚠".yellow()
-                    );
-                    Ok(())
-                }
-            } else {
-                let stderr = String::from_utf8_lossy(&result.stderr);
-                println!("{} Connection failed.", "✗".red());
-                if !stderr.is_empty() {
-                    println!("{}", stderr.dimmed());
-                }
-                println!();
-                println!("{}", "Troubleshooting:".bold());
-                println!("  {} Check if the host is online", "•".dimmed());
-                println!(
-                    "  {} Verify the IP is correct: {}",
-                    "•".dimmed(),
-                    "connecto hosts".cyan()
-                );
-                println!(
-                    "  {} Update IP if changed: {}",
-                    "•".dimmed(),
-                    format!("connecto update-ip {} <new-ip>", host).cyan()
-                );
-                Ok(())
+/// Have `host` forget the key at `key_path`: through its listener at
+/// `address`, which works without SSH and on Windows hosts, or else with `ssh`
+async fn revoke_on_host(
+    host: &str,
+    address: &str,
+    key_path: &str,
+    ssh: impl FnOnce(&str, &str) -> std::result::Result<usize, String>,
+) -> std::result::Result<RemoteRevocation, String> {
+    use connecto_core::{keys::SshKeyPair, protocol::HandshakeClient};
+
+    let listener_error = match SshKeyPair::load_from_file(key_path) {
+        Ok(key_pair) => {
+            let device_name = config::Config::load().unwrap_or_default().device_name();
+            match HandshakeClient::new(&device_name)
+                .revoke(address, &key_pair)
+                .await
+            {
+                Ok(()) => return Ok(RemoteRevocation::Listener),
+                Err(e) => e.to_string(),
             }
         }
-        Err(e) => {
-            println!("{} Failed to run ssh: {}", "✗".red(), e);
-            Ok(())
-        }
-    }
+        Err(e) => format!("cannot read the key: {}", e),
+    };
+    tracing::debug!("Listener at {} did not revoke: {}", address, listener_error);
+    ssh(host, key_path).map(RemoteRevocation::Ssh).map_err(|e| {
+        format!(
+            "the listener failed ({}), and so did SSH ({})",
+            listener_error, e
+        )
+    })
+}
+
+/// Log in to `host` with the key at `key_path` and remove that key from the
+/// host's authorized_keys, returning how many entries were removed
+fn revoke_remote_key(host: &str, key_path: &str) -> std::result::Result<usize, String> {
+    use connecto_core::rotation::{self, RemoteShell, SshCommand};
+
+    let key_path = std::path::Path::new(key_path);
+    let public_key =
+        rotation::public_key_of(key_path).map_err(|e| format!("cannot read the key: {}", e))?;
+    let command = rotation::revoke_command(&public_key).map_err(|e| e.to_string())?;
+    let output = SshCommand
+        .run(host, key_path, &command)
+        .map_err(|e| e.to_string())?;
+    rotation::parse_revoked(&output).ok_or_else(|| "the remote command failed".to_string())
 }
 
 /// Update IP address for a paired host
 fn run_update_ip(host: &str, new_ip: &str) -> Result<()> {
     use colored::Colorize;
+    use connecto_core::backups;
+    use connecto_core::ssh_config;
+    use connecto_core::tr;
     use std::fs;
 
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| anyhow::anyhow!("HOME/USERPROFILE not set"))?;
-    let config_path = std::path::PathBuf::from(&home).join(".ssh").join("config");
+    ssh_config::validate_hostname(new_ip)?;
+    let config_path = connecto_core::paths::ssh_config_file()?;
 
     if !config_path.exists() {
-        println!("{} No SSH config file found.", "✗".red());
-        return Ok(());
+        return Err(anyhow::anyhow!(tr!("update-ip.no-config")));
     }
 
+    let _lock = backups::lock(&config_path)?;
     let content = fs::read_to_string(&config_path)?;
     let mut new_content = String::new();
     let mut in_target_block = false;
@@ -728,12 +2467,11 @@ fn run_update_ip(host: &str, new_ip: &str) -> Result<()> {
         }
 
         if in_target_block && trimmed.starts_with("HostName ") {
-            old_ip = trimmed
-                .strip_prefix("HostName ")
-                .unwrap()
-                .trim()
-                .to_string();
-            new_content.push_str(&format!("    HostName {}\n", new_ip));
+            old_ip = ssh_config::parse_arg(trimmed.strip_prefix("HostName ").unwrap());
+            new_content.push_str(&format!(
+                "    HostName {}\n",
+                ssh_config::format_arg(new_ip)
+            ));
             found = true;
             continue;
         }
@@ -743,215 +2481,25 @@ fn run_update_ip(host: &str, new_ip: &str) -> Result<()> {
     }
 
     if !found {
-        println!("{} Host '{}' not found in SSH config.", "✗".red(), host);
-        return Ok(());
+        return Err(anyhow::anyhow!(tr!("update-ip.not-found", host = host)));
     }
 
-    fs::write(&config_path, new_content)?;
+    backups::replace(&config_path, &new_content)?;
     println!(
-        "{} Updated '{}' IP: {} → {}",
-        "✓".green(),
-        host.cyan(),
-        old_ip.dimmed(),
-        new_ip.cyan().bold()
+        "{} {}",
+        mark("✓").green(),
+        tr!(
+            "update-ip.updated",
+            host = host.cyan(),
+            old = old_ip.dimmed(),
+            arrow = output::arrow(),
+            new = new_ip.cyan().bold()
+        )
     );
 
     Ok(())
 }
 
-/// Export paired hosts configuration
-fn run_export(output: Option<&str>) -> Result<()> {
-    use colored::Colorize;
-    use serde::{Deserialize, Serialize};
-    use std::fs;
-
-    #[derive(Serialize, Deserialize)]
-    struct ExportedHost {
-        host: String,
-        hostname: String,
-        user: String,
-        identity_file: String,
-    }
-
-    #[derive(Serialize, Deserialize)]
-    struct ExportData {
-        version: u32,
-        hosts: Vec<ExportedHost>,
-        subnets: Vec<String>,
-    }
-
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| anyhow::anyhow!("HOME/USERPROFILE not set"))?;
-    let config_path = std::path::PathBuf::from(&home).join(".ssh").join("config");
-
-    let mut hosts = Vec::new();
-
-    if config_path.exists() {
-        let content = fs::read_to_string(&config_path)?;
-        let mut in_connecto_block = false;
-        let mut current = ExportedHost {
-            host: String::new(),
-            hostname: String::new(),
-            user: String::new(),
-            identity_file: String::new(),
-        };
-
-        for line in content.lines() {
-            let trimmed = line.trim();
-
-            if trimmed == "# Added by connecto" {
-                in_connecto_block = true;
-                continue;
-            }
-
-            if in_connecto_block {
-                if trimmed.starts_with("Host ") && !trimmed.contains('*') {
-                    if !current.host.is_empty() {
-                        hosts.push(current);
-                        current = ExportedHost {
-                            host: String::new(),
-                            hostname: String::new(),
-                            user: String::new(),
-                            identity_file: String::new(),
-                        };
-                    }
-                    current.host = trimmed.strip_prefix("Host ").unwrap().to_string();
-                } else if trimmed.starts_with("HostName ") {
-                    current.hostname = trimmed.strip_prefix("HostName ").unwrap().to_string();
-                } else if trimmed.starts_with("User ") {
-                    current.user = trimmed.strip_prefix("User ").unwrap().to_string();
-                } else if trimmed.starts_with("IdentityFile ") {
-                    current.identity_file =
-                        trimmed.strip_prefix("IdentityFile ").unwrap().to_string();
-                    hosts.push(current);
-                    current = ExportedHost {
-                        host: String::new(),
-                        hostname: String::new(),
-                        user: String::new(),
-                        identity_file: String::new(),
-                    };
-                    in_connecto_block = false;
-                }
-            }
-        }
-    }
-
-    let cfg = config::Config::load().unwrap_or_default();
-
-    let export_data = ExportData {
-        version: 1,
-        hosts,
-        subnets: cfg.subnets,
-    };
-
-    let json = serde_json::to_string_pretty(&export_data)?;
-
-    if let Some(path) = output {
-        fs::write(path, &json)?;
-        println!(
-            "{} Exported {} host(s) to {}",
-            "✓".green(),
-            export_data.hosts.len(),
-            path.cyan()
-        );
-    } else {
-        println!("{}", json);
-    }
-
-    Ok(())
-}
-
-/// Import paired hosts configuration
-fn run_import(file: &str) -> Result<()> {
-    use colored::Colorize;
-    use serde::{Deserialize, Serialize};
-    use std::fs;
-
-    #[derive(Serialize, Deserialize)]
-    struct ExportedHost {
-        host: String,
-        hostname: String,
-        user: String,
-        identity_file: String,
-    }
-
-    #[derive(Serialize, Deserialize)]
-    struct ExportData {
-        version: u32,
-        hosts: Vec<ExportedHost>,
-        subnets: Vec<String>,
-    }
-
-    let content = fs::read_to_string(file)?;
-    let data: ExportData = serde_json::from_str(&content)?;
-
-    if data.version != 1 {
-        return Err(anyhow::anyhow!(
-            "Unsupported export version: {}",
-            data.version
-        ));
-    }
-
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| anyhow::anyhow!("HOME/USERPROFILE not set"))?;
-    let ssh_dir = std::path::PathBuf::from(&home).join(".ssh");
-    let config_path = ssh_dir.join("config");
-
-    // Create .ssh directory if needed
-    if !ssh_dir.exists() {
-        fs::create_dir_all(&ssh_dir)?;
-    }
-
-    // Read existing config
-    let mut existing = String::new();
-    if config_path.exists() {
-        existing = fs::read_to_string(&config_path)?;
-    }
-
-    // Add hosts that don't already exist
-    let mut added = 0;
-    for host in &data.hosts {
-        let host_pattern = format!("Host {}", host.host);
-        if !existing.contains(&host_pattern) {
-            let entry = format!(
-                "\n# Added by connecto\nHost {}\n    HostName {}\n    User {}\n    IdentityFile {}\n",
-                host.host, host.hostname, host.user, host.identity_file
-            );
-            existing.push_str(&entry);
-            added += 1;
-        }
-    }
-
-    if added > 0 {
-        fs::write(&config_path, &existing)?;
-        println!("{} Imported {} host(s) to SSH config.", "✓".green(), added);
-    } else {
-        println!("{} All hosts already exist in SSH config.", "→".yellow());
-    }
-
-    // Import subnets
-    let mut cfg = config::Config::load().unwrap_or_default();
-    let mut subnet_added = 0;
-    for subnet in &data.subnets {
-        if cfg.add_subnet(subnet) {
-            subnet_added += 1;
-        }
-    }
-
-    if subnet_added > 0 {
-        cfg.save()?;
-        println!(
-            "{} Imported {} subnet(s) to config.",
-            "✓".green(),
-            subnet_added
-        );
-    }
-
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -968,107 +2516,1110 @@ mod tests {
         match cli.command {
             Commands::Listen {
                 port,
+                ssh_port,
                 name,
                 verify,
+                private,
+                approve,
+                approval_timeout,
+                on_timeout,
                 continuous,
                 adhoc,
+                relay,
+                prune,
+                restrict_source,
+                key_options,
+                users,
+                for_user,
+                allow,
+                deny,
+                allow_names,
+                max_handshakes,
+                rate_limit,
+                step_timeout,
+                no_notify,
+                interfaces,
+                bind,
+                upnp,
+                accept_stdin,
             } => {
                 assert_eq!(port, connecto_core::DEFAULT_PORT);
+                assert!(ssh_port.is_none());
                 assert!(name.is_none());
                 assert!(!verify);
+                assert!(!private);
+                assert!(!approve);
+                assert_eq!(
+                    approval_timeout,
+                    connecto_core::protocol::APPROVAL_TIMEOUT_SECS
+                );
+                assert_eq!(on_timeout, commands::listen::OnTimeout::Reject);
                 assert!(!continuous);
                 assert!(!adhoc);
+                assert!(relay.is_none());
+                assert!(!prune);
+                assert!(!restrict_source);
+                assert!(key_options.is_empty());
+                assert!(users.is_empty());
+                assert!(for_user.is_none());
+                assert!(allow.is_empty());
+                assert!(deny.is_empty());
+                assert!(allow_names.is_empty());
+                assert_eq!(max_handshakes, limits::DEFAULT_MAX_HANDSHAKES);
+                assert_eq!(rate_limit, limits::DEFAULT_CONNECTIONS_PER_MINUTE);
+                assert_eq!(step_timeout, limits::DEFAULT_STEP_TIMEOUT_SECS);
+                assert!(!no_notify);
+                assert!(interfaces.is_empty());
+                assert!(bind.is_none());
+                assert!(!upnp);
+                assert!(!accept_stdin);
             }
             _ => panic!("Expected Listen command"),
         }
     }
 
     #[test]
-    fn test_scan_defaults() {
-        let cli = Cli::try_parse_from(["connecto", "scan"]).unwrap();
-        match cli.command {
-            Commands::Scan { timeout, subnet } => {
-                assert_eq!(timeout, 5);
-                assert!(subnet.is_empty());
+    fn test_listen_accept_stdin() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "listen",
+            "--accept-stdin",
+            "--restrict-source",
+            "--step-timeout",
+            "60",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Listen {
+                accept_stdin: true,
+                restrict_source: true,
+                step_timeout: 60,
+                ..
             }
-            _ => panic!("Expected Scan command"),
+        ));
+
+        // Nothing is advertised or answered on the network
+        for other in [
+            &["--port", "9000"][..],
+            &["--relay", "relay.example.com"],
+            &["--continuous"],
+            &["--upnp"],
+            &["--bind", "192.168.1.20"],
+            &["--verify"],
+            &["--approve"],
+        ] {
+            let args = ["connecto", "listen", "--accept-stdin"]
+                .iter()
+                .chain(other)
+                .copied();
+            assert!(Cli::try_parse_from(args).is_err(), "{:?}", other);
         }
     }
 
     #[test]
-    fn test_scan_with_subnet() {
-        let cli = Cli::try_parse_from(["connecto", "scan", "--subnet", "10.0.0.0/24"]).unwrap();
+    fn test_listen_access() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "listen",
+            "--allow",
+            "10.0.0.0/24",
+            "--deny",
+            "10.0.0.13",
+            "--allow-name",
+            "alice-*",
+        ])
+        .unwrap();
         match cli.command {
-            Commands::Scan { timeout, subnet } => {
-                assert_eq!(timeout, 5);
+            Commands::Listen {
+                allow,
+                deny,
+                allow_names,
+                ..
+            } => {
+                assert_eq!(allow, vec!["10.0.0.0/24".parse::<Network>().unwrap()]);
+                assert_eq!(deny, vec!["10.0.0.13".parse::<Network>().unwrap()]);
+                assert_eq!(allow_names, vec!["alice-*"]);
+            }
+            _ => panic!("Expected Listen command"),
+        }
+        assert!(Cli::try_parse_from(["connecto", "listen", "--allow", "10.0.0.0/33"]).is_err());
+    }
+
+    #[test]
+    fn test_listen_ssh_port() {
+        let cli = Cli::try_parse_from(["connecto", "listen", "--ssh-port", "2222"]).unwrap();
+        match cli.command {
+            Commands::Listen { ssh_port, .. } => assert_eq!(ssh_port, Some(2222)),
+            _ => panic!("Expected Listen command"),
+        }
+        assert!(Cli::try_parse_from(["connecto", "listen", "--ssh-port", "70000"]).is_err());
+    }
+
+    #[test]
+    fn test_listen_key_options() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "listen",
+            "--restrict-source",
+            "--key-option",
+            "no-port-forwarding",
+            "--key-option",
+            "command=\"uptime\"",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Listen {
+                restrict_source,
+                key_options,
+                ..
+            } => {
+                assert!(restrict_source);
+                let options: KeyOptions = key_options.join(",").parse().unwrap();
+                assert_eq!(options.to_string(), "command=\"uptime\",no-port-forwarding");
+            }
+            _ => panic!("Expected Listen command"),
+        }
+        assert!(Cli::try_parse_from(["connecto", "listen", "--key-option", "tunnel=0"]).is_err());
+    }
+
+    #[test]
+    fn test_listen_approval_timeout() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "listen",
+            "--approve",
+            "--approval-timeout",
+            "30",
+            "--on-timeout",
+            "accept-if-verified",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Listen {
+                approval_timeout,
+                on_timeout,
+                ..
+            } => {
+                assert_eq!(approval_timeout, 30);
+                assert_eq!(on_timeout, commands::listen::OnTimeout::AcceptIfVerified);
+            }
+            _ => panic!("Expected Listen command"),
+        }
+
+        // Timeout settings only make sense when approving
+        assert!(Cli::try_parse_from(["connecto", "listen", "--on-timeout", "reject"]).is_err());
+    }
+
+    #[test]
+    fn test_scan_defaults() {
+        let cli = Cli::try_parse_from(["connecto", "scan"]).unwrap();
+        match cli.command {
+            Commands::Scan {
+                timeout, subnet, ..
+            } => {
+                assert_eq!(timeout, 5);
+                assert!(subnet.is_empty());
+            }
+            _ => panic!("Expected Scan command"),
+        }
+    }
+
+    #[test]
+    fn test_scan_with_subnet() {
+        let cli = Cli::try_parse_from(["connecto", "scan", "--subnet", "10.0.0.0/24"]).unwrap();
+        match cli.command {
+            Commands::Scan {
+                timeout, subnet, ..
+            } => {
+                assert_eq!(timeout, 5);
                 assert_eq!(subnet, vec!["10.0.0.0/24"]);
             }
-            _ => panic!("Expected Scan command"),
+            _ => panic!("Expected Scan command"),
+        }
+    }
+
+    #[test]
+    fn test_scan_with_multiple_subnets() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "scan",
+            "--subnet",
+            "10.0.0.0/24",
+            "--subnet",
+            "192.168.1.0/24",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Scan { subnet, .. } => {
+                assert_eq!(subnet.len(), 2);
+                assert_eq!(subnet[0], "10.0.0.0/24");
+                assert_eq!(subnet[1], "192.168.1.0/24");
+            }
+            _ => panic!("Expected Scan command"),
+        }
+    }
+
+    #[test]
+    fn test_scan_probe_flags() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "scan",
+            "--concurrency",
+            "500",
+            "--rate",
+            "2000",
+            "--probe-timeout",
+            "250",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Scan {
+                concurrency,
+                rate,
+                probe_timeout,
+                ..
+            } => {
+                assert_eq!(concurrency, Some(500));
+                assert_eq!(rate, Some(2000));
+                assert_eq!(probe_timeout, Some(250));
+            }
+            _ => panic!("Expected Scan command"),
+        }
+        assert!(Cli::try_parse_from(["connecto", "scan", "--concurrency", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_scan_early_exit_flags() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "scan",
+            "--min-devices",
+            "2",
+            "--quiet-period",
+            "800",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Scan {
+                min_devices,
+                quiet_period,
+                ..
+            } => {
+                assert_eq!(min_devices, Some(2));
+                assert_eq!(quiet_period, Some(800));
+            }
+            _ => panic!("Expected Scan command"),
+        }
+        assert!(Cli::try_parse_from(["connecto", "scan", "--min-devices", "0"]).is_err());
+        assert!(
+            Cli::try_parse_from(["connecto", "scan", "--cached", "--quiet-period", "800"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_scan_output_flags() {
+        use commands::scan::{ScanColumn, ScanSort};
+
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "scan",
+            "--columns",
+            "name,hostname",
+            "--sort",
+            "ip",
+            "--plain",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Scan {
+                columns,
+                sort,
+                plain,
+                ..
+            } => {
+                assert_eq!(columns, vec![ScanColumn::Name, ScanColumn::Hostname]);
+                assert_eq!(sort, Some(ScanSort::Ip));
+                assert!(plain);
+            }
+            _ => panic!("Expected Scan command"),
+        }
+    }
+
+    #[test]
+    fn test_scan_cached() {
+        let cli = Cli::try_parse_from(["connecto", "scan", "--cached", "--plain"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Scan {
+                cached: true,
+                plain: true,
+                ..
+            }
+        ));
+        // Nothing is scanned, and the numbers keep their order
+        assert!(
+            Cli::try_parse_from(["connecto", "scan", "--cached", "--subnet", "10.0.0.0/24"])
+                .is_err()
+        );
+        assert!(Cli::try_parse_from(["connecto", "scan", "--cached", "--sort", "name"]).is_err());
+    }
+
+    #[test]
+    fn test_pair_target() {
+        let cli = Cli::try_parse_from(["connecto", "pair", "1"]).unwrap();
+        match cli.command {
+            Commands::Pair {
+                targets,
+                all,
+                relay,
+                code,
+                comment,
+                rsa,
+                key_type,
+                key,
+                accept_new_identity,
+                tags,
+                expires,
+                alias,
+                mdns,
+                user,
+                timeout,
+                retries,
+                print_authorized_line,
+            } => {
+                assert_eq!(targets, ["1"]);
+                assert!(!all);
+                assert!(relay.is_none());
+                assert!(code.is_none());
+                assert!(comment.is_none());
+                assert!(!rsa);
+                assert!(key_type.is_none());
+                assert!(key.is_none());
+                assert!(!accept_new_identity);
+                assert!(tags.is_empty());
+                assert!(expires.is_none());
+                assert!(alias.is_none());
+                assert!(!mdns);
+                assert!(user.is_none());
+                assert!(timeout.is_none());
+                assert!(retries.is_none());
+                assert!(!print_authorized_line);
+            }
+            _ => panic!("Expected Pair command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "pair", "1", "--timeout", "45"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Pair {
+                timeout: Some(45),
+                ..
+            }
+        ));
+
+        let cli = Cli::try_parse_from(["connecto", "pair", "1", "--retries", "0"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Pair {
+                retries: Some(0),
+                ..
+            }
+        ));
+        assert!(Cli::try_parse_from(["connecto", "pair", "1", "--retries", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_pair_print_authorized_line() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "pair",
+            "--print-authorized-line",
+            "--alias",
+            "desk",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Pair {
+                targets,
+                alias,
+                print_authorized_line,
+                ..
+            } => {
+                assert!(targets.is_empty());
+                assert_eq!(alias.as_deref(), Some("desk"));
+                assert!(print_authorized_line);
+            }
+            _ => panic!("Expected Pair command"),
+        }
+
+        // The device is on the other end of stdin, not found by address
+        assert!(Cli::try_parse_from(["connecto", "pair", "1", "--print-authorized-line"]).is_err());
+        assert!(
+            Cli::try_parse_from(["connecto", "pair", "--all", "--print-authorized-line"]).is_err()
+        );
+        assert!(
+            Cli::try_parse_from(["connecto", "pair", "--print-authorized-line", "--mdns"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_pair_alias() {
+        let cli = Cli::try_parse_from(["connecto", "pair", "1", "--alias", "desk.home"]).unwrap();
+        match cli.command {
+            Commands::Pair { alias, .. } => assert_eq!(alias.as_deref(), Some("desk.home")),
+            _ => panic!("Expected Pair command"),
+        }
+
+        assert!(Cli::try_parse_from(["connecto", "pair", "1", "--alias", "desk*"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "pair", "1", "--alias", "my desk"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "pair", "--all", "--alias", "desk"]).is_err());
+    }
+
+    #[test]
+    fn test_pair_mdns() {
+        let cli = Cli::try_parse_from(["connecto", "pair", "0", "1", "--mdns"]).unwrap();
+        assert!(matches!(cli.command, Commands::Pair { mdns: true, .. }));
+
+        assert!(Cli::try_parse_from([
+            "connecto",
+            "pair",
+            "--relay",
+            "relay.example",
+            "--code",
+            "4821-7305",
+            "--mdns"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_user_selection() {
+        let cli = Cli::try_parse_from(["connecto", "listen", "--users", "deploy,backup"]).unwrap();
+        match cli.command {
+            Commands::Listen { users, .. } => assert_eq!(users, ["deploy", "backup"]),
+            _ => panic!("Expected Listen command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "listen", "--for-user", "root"]).unwrap();
+        match cli.command {
+            Commands::Listen { for_user, .. } => assert_eq!(for_user.as_deref(), Some("root")),
+            _ => panic!("Expected Listen command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "pair", "1", "--user", "deploy"]).unwrap();
+        match cli.command {
+            Commands::Pair { user, .. } => assert_eq!(user.as_deref(), Some("deploy")),
+            _ => panic!("Expected Pair command"),
+        }
+    }
+
+    #[test]
+    fn test_pair_expires() {
+        let cli = Cli::try_parse_from(["connecto", "pair", "1", "--expires", "30d"]).unwrap();
+        match cli.command {
+            Commands::Pair { expires, .. } => {
+                assert_eq!(expires, Some(Duration::from_secs(30 * 86_400)))
+            }
+            _ => panic!("Expected Pair command"),
+        }
+        assert_eq!(parse_lifetime("90m"), Ok(Duration::from_secs(5_400)));
+        assert_eq!(parse_lifetime("12h"), Ok(Duration::from_secs(43_200)));
+        assert_eq!(parse_lifetime("2w"), Ok(Duration::from_secs(1_209_600)));
+        for invalid in ["", "30", "d", "0d", "-1d", "1.5h", "3y"] {
+            assert!(parse_lifetime(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_doctor_command() {
+        let cli = Cli::try_parse_from(["connecto", "doctor"]).unwrap();
+        match cli.command {
+            Commands::Doctor { port, format } => {
+                assert_eq!(port, connecto_core::DEFAULT_PORT);
+                assert_eq!(format, commands::doctor::DoctorFormat::Text);
+            }
+            _ => panic!("Expected Doctor command"),
+        }
+        let cli = Cli::try_parse_from(["connecto", "doctor", "--port", "9100", "--format", "json"])
+            .unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Doctor {
+                port: 9100,
+                format: commands::doctor::DoctorFormat::Json
+            }
+        ));
+    }
+
+    #[test]
+    fn test_prune_command() {
+        let cli = Cli::try_parse_from(["connecto", "prune", "--dry-run"]).unwrap();
+        assert!(matches!(cli.command, Commands::Prune { dry_run: true }));
+        let cli = Cli::try_parse_from(["connecto", "listen", "--prune"]).unwrap();
+        assert!(matches!(cli.command, Commands::Listen { prune: true, .. }));
+    }
+
+    #[test]
+    fn test_restore_config_command() {
+        let cli = Cli::try_parse_from(["connecto", "restore-config"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::RestoreConfig { list: false }
+        ));
+        let cli = Cli::try_parse_from(["connecto", "restore-config", "--list"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::RestoreConfig { list: true }
+        ));
+    }
+
+    #[test]
+    fn test_revoke_command() {
+        let cli = Cli::try_parse_from(["connecto", "revoke", "desk"]).unwrap();
+        match cli.command {
+            Commands::Revoke { host, port } => {
+                assert_eq!(host, "desk");
+                assert_eq!(port, connecto_core::DEFAULT_PORT);
+            }
+            _ => panic!("Expected Revoke command"),
+        }
+        let cli = Cli::try_parse_from(["connecto", "revoke", "desk", "-p", "9000"]).unwrap();
+        assert!(matches!(cli.command, Commands::Revoke { port: 9000, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_unpair_remote_asks_the_listener_first() {
+        use connecto_core::keys::SshKeyPair;
+        use connecto_core::protocol::HandshakeServer;
+        use connecto_core::KeyManager;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "laptop@connecto").unwrap();
+        let (key_path, _) = KeyManager::with_dir(temp_dir.path().join("local"))
+            .save_key_pair(&key_pair, "connecto_desk")
+            .unwrap();
+        let key_path = key_path.to_string_lossy().to_string();
+        let host_keys = KeyManager::with_dir(temp_dir.path().join("host"));
+        host_keys.add_authorized_key(&key_pair.public_key).unwrap();
+
+        let mut server =
+            HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join("host")), "Desk");
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, _event_rx) = tokio::sync::mpsc::channel(10);
+        let handle = tokio::spawn(async move { server.handle_one(event_tx).await });
+
+        // The listener removes the key, so SSH is never tried
+        let address = format!("127.0.0.1:{}", addr.port());
+        let revoked = revoke_on_host("desk", &address, &key_path, |_, _| {
+            panic!("SSH should not be needed")
+        })
+        .await;
+        assert_eq!(revoked, Ok(RemoteRevocation::Listener));
+        assert!(host_keys.list_authorized_keys().unwrap().is_empty());
+        handle.abort();
+        let _ = handle.await;
+
+        // Without a listener, SSH does it
+        let revoked = revoke_on_host("desk", &address, &key_path, |host, path| {
+            assert_eq!((host, path), ("desk", key_path.as_str()));
+            Ok(1)
+        })
+        .await;
+        assert_eq!(revoked, Ok(RemoteRevocation::Ssh(1)));
+
+        let error = revoke_on_host("desk", &address, &key_path, |_, _| {
+            Err("connection refused".to_string())
+        })
+        .await
+        .unwrap_err();
+        assert!(error.contains("connection refused"), "{}", error);
+    }
+
+    #[test]
+    fn test_service_command() {
+        let cli = Cli::try_parse_from([
+            "connecto", "service", "install", "--port", "9000", "--verify",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Service {
+                action: ServiceAction::Install { options },
+            } => assert_eq!(options, ["--port", "9000", "--verify"]),
+            _ => panic!("Expected service install"),
+        }
+        let cli = Cli::try_parse_from(["connecto", "service", "status"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Service {
+                action: ServiceAction::Status
+            }
+        ));
+    }
+
+    #[test]
+    fn test_keep_warm_command() {
+        let cli = Cli::try_parse_from(["connecto", "keep-warm", "add", "desk"]).unwrap();
+        match cli.command {
+            Commands::KeepWarm {
+                action: Some(KeepWarmAction::Add { host, days, hours }),
+                ..
+            } => {
+                assert_eq!(host, "desk");
+                assert_eq!(days, "mon-fri");
+                assert_eq!(hours, "09:00-18:00");
+            }
+            _ => panic!("Expected keep-warm add"),
+        }
+        let cli = Cli::try_parse_from(["connecto", "keep-warm", "status", "--plain"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::KeepWarm {
+                action: Some(KeepWarmAction::Status),
+                plain: true
+            }
+        ));
+    }
+
+    #[test]
+    fn test_tags() {
+        let cli =
+            Cli::try_parse_from(["connecto", "pair", "1", "--tag", "prod", "--tag", "eu-west"])
+                .unwrap();
+        match cli.command {
+            Commands::Pair { tags, .. } => assert_eq!(tags, ["prod", "eu-west"]),
+            _ => panic!("Expected Pair command"),
+        }
+        assert!(Cli::try_parse_from(["connecto", "pair", "1", "--tag", "a b"]).is_err());
+
+        let cli = Cli::try_parse_from(["connecto", "tag", "desk", "lab", "-r"]).unwrap();
+        match cli.command {
+            Commands::Tag { host, tags, remove } => {
+                assert_eq!(host, "desk");
+                assert_eq!(tags, ["lab"]);
+                assert!(remove);
+            }
+            _ => panic!("Expected Tag command"),
+        }
+        // Removing needs something to remove
+        assert!(Cli::try_parse_from(["connecto", "tag", "desk", "--remove"]).is_err());
+
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "config",
+            "set-template",
+            "prod",
+            "StrictHostKeyChecking",
+            "yes",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Config {
+                action: ConfigAction::SetTemplate { tag, option, value },
+            } => {
+                assert_eq!(tag, "prod");
+                assert_eq!(option, "StrictHostKeyChecking");
+                assert_eq!(value, "yes");
+            }
+            _ => panic!("Expected config set-template"),
+        }
+    }
+
+    #[test]
+    fn test_profile_option() {
+        let cli = Cli::try_parse_from(["connecto", "scan", "--profile", "office"]).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("office"));
+        assert!(matches!(cli.command, Commands::Scan { .. }));
+
+        let cli = Cli::try_parse_from(["connecto", "--profile", "lab", "listen"]).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("lab"));
+
+        let cli = Cli::try_parse_from(["connecto", "config", "use-profile", "home"]).unwrap();
+        match cli.command {
+            Commands::Config {
+                action: ConfigAction::UseProfile { name },
+            } => assert_eq!(name, "home"),
+            _ => panic!("Expected config use-profile"),
+        }
+        assert!(cli.profile.is_none());
+    }
+
+    #[test]
+    fn test_pair_several_targets() {
+        let cli = Cli::try_parse_from(["connecto", "pair", "1", "2", "10.0.0.5"]).unwrap();
+        match cli.command {
+            Commands::Pair { targets, all, .. } => {
+                assert_eq!(targets, ["1", "2", "10.0.0.5"]);
+                assert!(!all);
+            }
+            _ => panic!("Expected Pair command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "pair", "--all"]).unwrap();
+        match cli.command {
+            Commands::Pair { targets, all, .. } => {
+                assert!(targets.is_empty());
+                assert!(all);
+            }
+            _ => panic!("Expected Pair command"),
+        }
+
+        // A target or --all is required, but not both
+        assert!(Cli::try_parse_from(["connecto", "pair"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "pair", "1", "--all"]).is_err());
+    }
+
+    #[test]
+    fn test_relay() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "pair",
+            "--relay",
+            "relay.example.com",
+            "--code",
+            "4821-K7PQ2M",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Pair { relay, code, .. } => {
+                assert_eq!(relay.as_deref(), Some("relay.example.com"));
+                assert_eq!(code.unwrap().to_string(), "4821-K7PQ2M");
+            }
+            _ => panic!("Expected Pair command"),
+        }
+        // The relay and code go together, and replace the targets
+        assert!(Cli::try_parse_from(["connecto", "pair", "--relay", "r"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "pair", "--code", "4821-K7PQ2M"]).is_err());
+        assert!(
+            Cli::try_parse_from(["connecto", "pair", "--relay", "r", "--code", "oops"]).is_err()
+        );
+        assert!(Cli::try_parse_from([
+            "connecto",
+            "pair",
+            "1",
+            "--relay",
+            "r",
+            "--code",
+            "4821-K7PQ2M"
+        ])
+        .is_err());
+
+        let cli = Cli::try_parse_from(["connecto", "listen", "--relay", "10.0.0.5:9000"]).unwrap();
+        match cli.command {
+            Commands::Listen { relay, .. } => assert_eq!(relay.as_deref(), Some("10.0.0.5:9000")),
+            _ => panic!("Expected Listen command"),
+        }
+        assert!(
+            Cli::try_parse_from(["connecto", "listen", "--relay", "r", "--continuous"]).is_err()
+        );
+
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "listen",
+            "--interface",
+            "en0",
+            "--interface",
+            "10.8.0.2",
+            "--bind",
+            "192.168.1.20",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Listen {
+                interfaces, bind, ..
+            } => {
+                assert_eq!(interfaces, ["en0", "10.8.0.2"]);
+                assert_eq!(bind, Some("192.168.1.20".parse().unwrap()));
+            }
+            _ => panic!("Expected Listen command"),
+        }
+        assert!(
+            Cli::try_parse_from(["connecto", "listen", "--relay", "r", "--interface", "en0"])
+                .is_err()
+        );
+        assert!(Cli::try_parse_from(["connecto", "listen", "--adhoc", "--upnp"]).is_err());
+
+        let cli = Cli::try_parse_from(["connecto", "relay", "serve", "-p", "9000"]).unwrap();
+        match cli.command {
+            Commands::Relay {
+                action: RelayAction::Serve { port, wait },
+            } => {
+                assert_eq!(port, 9000);
+                assert_eq!(wait, connecto_core::relay::RELAY_WAIT_SECS);
+            }
+            _ => panic!("Expected Relay command"),
+        }
+    }
+
+    #[test]
+    fn test_key_type() {
+        let cli = Cli::try_parse_from(["connecto", "keygen", "-t", "ecdsa-p256"]).unwrap();
+        match cli.command {
+            Commands::Keygen { rsa, key_type, .. } => {
+                assert_eq!(key_algorithm(rsa, key_type), KeyAlgorithm::EcdsaP256);
+            }
+            _ => panic!("Expected Keygen command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "pair", "1", "--type", "ed25519-sk"]).unwrap();
+        match cli.command {
+            Commands::Pair { key_type, .. } => assert_eq!(key_type, Some(KeyAlgorithm::Ed25519Sk)),
+            _ => panic!("Expected Pair command"),
+        }
+
+        assert_eq!(key_algorithm(true, None), KeyAlgorithm::Rsa4096);
+        assert_eq!(key_algorithm(false, None), KeyAlgorithm::Ed25519);
+        assert!(Cli::try_parse_from(["connecto", "keygen", "-t", "dsa"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "keygen", "--rsa", "-t", "rsa"]).is_err());
+    }
+
+    #[test]
+    fn test_verbose_flag() {
+        let cli = Cli::try_parse_from(["connecto", "-v", "scan"]).unwrap();
+        assert!(cli.verbose);
+    }
+
+    #[test]
+    fn test_accessible_flag() {
+        let cli = Cli::try_parse_from(["connecto", "scan"]).unwrap();
+        assert!(!cli.accessible);
+        let cli = Cli::try_parse_from(["connecto", "scan", "--accessible"]).unwrap();
+        assert!(cli.accessible);
+    }
+
+    #[test]
+    fn test_external_command() {
+        let cli = Cli::try_parse_from(["connecto", "-v", "backup", "--to", "nas"]).unwrap();
+        assert!(cli.verbose);
+        match cli.command {
+            Commands::External(args) => assert_eq!(args, ["backup", "--to", "nas"]),
+            _ => panic!("Expected External command"),
+        }
+
+        // Built-in commands are never handed out
+        let cli = Cli::try_parse_from(["connecto", "scan"]).unwrap();
+        assert!(matches!(cli.command, Commands::Scan { .. }));
+    }
+
+    #[test]
+    fn test_sync_defaults() {
+        let cli = Cli::try_parse_from(["connecto", "sync"]).unwrap();
+        match cli.command {
+            Commands::Sync {
+                port,
+                name,
+                timeout,
+                rsa,
+                key_type,
+                key,
+                share,
+                share_all,
+                accept_new_identity,
+                approve,
+                approval_timeout,
+                deny,
+                daemon,
+                interval,
+            } => {
+                assert_eq!(port, connecto_core::DEFAULT_PORT);
+                assert!(share.is_empty());
+                assert!(!share_all);
+                assert!(!approve);
+                assert_eq!(
+                    approval_timeout,
+                    connecto_core::protocol::APPROVAL_TIMEOUT_SECS
+                );
+                assert!(deny.is_empty());
+                assert!(key_type.is_none());
+                assert!(name.is_none());
+                assert_eq!(timeout, connecto_core::DEFAULT_SYNC_TIMEOUT_SECS);
+                assert!(!rsa);
+                assert!(key.is_none());
+                assert!(!accept_new_identity);
+                assert!(!daemon);
+                assert_eq!(interval, connecto_core::DEFAULT_SYNC_INTERVAL_SECS);
+            }
+            _ => panic!("Expected Sync command"),
+        }
+    }
+
+    #[test]
+    fn test_sync_daemon() {
+        let cli =
+            Cli::try_parse_from(["connecto", "sync", "--daemon", "--interval", "60"]).unwrap();
+        match cli.command {
+            Commands::Sync {
+                daemon, interval, ..
+            } => {
+                assert!(daemon);
+                assert_eq!(interval, 60);
+            }
+            _ => panic!("Expected Sync command"),
         }
+
+        // A daemon has no discovery timeout, and only a daemon has an interval
+        assert!(Cli::try_parse_from(["connecto", "sync", "--daemon", "--timeout", "5"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "sync", "--interval", "60"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "sync", "--daemon", "--interval", "0"]).is_err());
     }
 
     #[test]
-    fn test_scan_with_multiple_subnets() {
+    fn test_sync_accept_policy() {
         let cli = Cli::try_parse_from([
             "connecto",
-            "scan",
-            "--subnet",
-            "10.0.0.0/24",
-            "--subnet",
-            "192.168.1.0/24",
+            "sync",
+            "--approve",
+            "--approval-timeout",
+            "30",
+            "--deny",
+            "Old Laptop",
+            "--deny",
+            "SHA256:abc",
         ])
         .unwrap();
         match cli.command {
-            Commands::Scan { subnet, .. } => {
-                assert_eq!(subnet.len(), 2);
-                assert_eq!(subnet[0], "10.0.0.0/24");
-                assert_eq!(subnet[1], "192.168.1.0/24");
+            Commands::Sync {
+                approve,
+                approval_timeout,
+                deny,
+                ..
+            } => {
+                assert!(approve);
+                assert_eq!(approval_timeout, 30);
+                assert_eq!(deny, vec!["Old Laptop", "SHA256:abc"]);
             }
-            _ => panic!("Expected Scan command"),
+            _ => panic!("Expected Sync command"),
         }
+
+        // Nobody is there to answer a daemon's prompts
+        assert!(Cli::try_parse_from(["connecto", "sync", "--approve", "--daemon"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "sync", "--approval-timeout", "30"]).is_err());
     }
 
     #[test]
-    fn test_pair_target() {
-        let cli = Cli::try_parse_from(["connecto", "pair", "1"]).unwrap();
+    fn test_test_command() {
+        let cli = Cli::try_parse_from(["connecto", "test", "desk", "--builtin"]).unwrap();
         match cli.command {
-            Commands::Pair {
-                target,
-                comment,
-                rsa,
-                key,
+            Commands::Test { host, fix, builtin } => {
+                assert_eq!(host, "desk");
+                assert!(!fix);
+                assert!(builtin);
+            }
+            _ => panic!("Expected Test command"),
+        }
+    }
+
+    #[test]
+    fn test_run_command() {
+        let cli =
+            Cli::try_parse_from(["connecto", "run", "desk", "--", "ls", "-la", "/tmp"]).unwrap();
+        match cli.command {
+            Commands::Run {
+                host,
+                command,
+                timeout,
             } => {
-                assert_eq!(target, "1");
-                assert!(comment.is_none());
-                assert!(!rsa);
-                assert!(key.is_none());
+                assert_eq!(host, "desk");
+                assert_eq!(command, vec!["ls", "-la", "/tmp"]);
+                assert_eq!(timeout, 10);
             }
-            _ => panic!("Expected Pair command"),
+            _ => panic!("Expected Run command"),
+        }
+
+        let cli =
+            Cli::try_parse_from(["connecto", "run", "--timeout", "3", "desk", "uptime"]).unwrap();
+        match cli.command {
+            Commands::Run {
+                command, timeout, ..
+            } => {
+                assert_eq!(command, vec!["uptime"]);
+                assert_eq!(timeout, 3);
+            }
+            _ => panic!("Expected Run command"),
         }
+
+        // Nothing to run
+        assert!(Cli::try_parse_from(["connecto", "run", "desk"]).is_err());
     }
 
     #[test]
-    fn test_verbose_flag() {
-        let cli = Cli::try_parse_from(["connecto", "-v", "scan"]).unwrap();
-        assert!(cli.verbose);
+    fn test_send_command() {
+        let cli = Cli::try_parse_from(["connecto", "send", "laptop", "a.txt", "b.png"]).unwrap();
+        match cli.command {
+            Commands::Send {
+                device,
+                files,
+                port,
+                accept_new_identity,
+            } => {
+                assert_eq!(device, "laptop");
+                assert_eq!(files, vec![PathBuf::from("a.txt"), PathBuf::from("b.png")]);
+                assert_eq!(port, connecto_core::DEFAULT_TRANSFER_PORT);
+                assert!(!accept_new_identity);
+            }
+            _ => panic!("Expected Send command"),
+        }
+
+        // Nothing to send
+        assert!(Cli::try_parse_from(["connecto", "send", "laptop"]).is_err());
     }
 
     #[test]
-    fn test_sync_defaults() {
-        let cli = Cli::try_parse_from(["connecto", "sync"]).unwrap();
+    fn test_receive_command() {
+        let cli = Cli::try_parse_from(["connecto", "receive"]).unwrap();
         match cli.command {
-            Commands::Sync {
+            Commands::Receive {
                 port,
-                name,
-                timeout,
-                rsa,
-                key,
+                dir,
+                continuous,
+                approval_timeout,
             } => {
-                assert_eq!(port, connecto_core::DEFAULT_PORT);
-                assert!(name.is_none());
-                assert_eq!(timeout, connecto_core::DEFAULT_SYNC_TIMEOUT_SECS);
-                assert!(!rsa);
-                assert!(key.is_none());
+                assert_eq!(port, connecto_core::DEFAULT_TRANSFER_PORT);
+                assert!(dir.is_none());
+                assert!(!continuous);
+                assert_eq!(
+                    approval_timeout,
+                    connecto_core::protocol::APPROVAL_TIMEOUT_SECS
+                );
+            }
+            _ => panic!("Expected Receive command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "receive", "-d", "/tmp/in", "-c", "-p", "9000"])
+            .unwrap();
+        match cli.command {
+            Commands::Receive {
+                port,
+                dir,
+                continuous,
+                ..
+            } => {
+                assert_eq!(port, 9000);
+                assert_eq!(dir, Some(PathBuf::from("/tmp/in")));
+                assert!(continuous);
+            }
+            _ => panic!("Expected Receive command"),
+        }
+    }
+
+    #[test]
+    fn test_sync_shared_keys() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "sync",
+            "--share",
+            "/home/me/.ssh/connecto_work",
+            "--share",
+            "id_ed25519.pub",
+            "--share-all",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Sync {
+                share, share_all, ..
+            } => {
+                assert_eq!(share, vec!["/home/me/.ssh/connecto_work", "id_ed25519.pub"]);
+                assert!(share_all);
             }
             _ => panic!("Expected Sync command"),
         }
@@ -1086,6 +3637,7 @@ mod tests {
             "--timeout",
             "120",
             "--rsa",
+            "--accept-new-identity",
         ])
         .unwrap();
         match cli.command {
@@ -1094,17 +3646,459 @@ mod tests {
                 name,
                 timeout,
                 rsa,
+                key_type: _,
                 key,
+                accept_new_identity,
+                ..
             } => {
                 assert_eq!(port, 9000);
                 assert_eq!(name, Some("MyDevice".to_string()));
                 assert_eq!(timeout, 120);
                 assert!(rsa);
                 assert!(key.is_none());
+                assert!(accept_new_identity);
             }
             _ => panic!("Expected Sync command"),
         }
     }
+
+    #[test]
+    fn test_shred_flags() {
+        let cli = Cli::try_parse_from(["connecto", "unpair", "desk", "--shred"]).unwrap();
+        match cli.command {
+            Commands::Unpair {
+                host,
+                shred,
+                remote,
+                ..
+            } => {
+                assert_eq!(host, "desk");
+                assert!(shred);
+                assert!(!remote);
+            }
+            _ => panic!("Expected Unpair command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "keys", "delete", "connecto_desk"]).unwrap();
+        match cli.command {
+            Commands::Keys {
+                action: Some(KeysAction::Delete { name, shred }),
+                ..
+            } => {
+                assert_eq!(name, "connecto_desk");
+                assert!(!shred);
+            }
+            _ => panic!("Expected keys delete command"),
+        }
+    }
+
+    #[test]
+    fn test_keys_import_commands() {
+        let cli = Cli::try_parse_from(["connecto", "keys", "import-github", "octocat"]).unwrap();
+        match cli.command {
+            Commands::Keys {
+                action: Some(KeysAction::ImportGithub { user, all }),
+                ..
+            } => {
+                assert_eq!(user, "octocat");
+                assert!(!all);
+            }
+            _ => panic!("Expected keys import-github command"),
+        }
+
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "keys",
+            "import-gitlab",
+            "jane",
+            "--host",
+            "git.example.org",
+            "--all",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Keys {
+                action: Some(KeysAction::ImportGitlab { user, host, all }),
+                ..
+            } => {
+                assert_eq!(user, "jane");
+                assert_eq!(host, "git.example.org");
+                assert!(all);
+            }
+            _ => panic!("Expected keys import-gitlab command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "keys", "import-gitlab", "jane"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Keys {
+                action: Some(KeysAction::ImportGitlab { host, .. }),
+                ..
+            } if host == "gitlab.com"
+        ));
+    }
+
+    #[test]
+    fn test_keys_audit_command() {
+        let cli = Cli::try_parse_from(["connecto", "keys", "audit"]).unwrap();
+        match cli.command {
+            Commands::Keys {
+                action: Some(KeysAction::Audit { format }),
+                plain,
+            } => {
+                assert_eq!(format, commands::keys::AuditFormat::Table);
+                assert!(!plain);
+            }
+            _ => panic!("Expected keys audit command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "keys", "audit", "-f", "json"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Keys {
+                action: Some(KeysAction::Audit {
+                    format: commands::keys::AuditFormat::Json
+                }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_rotate_command() {
+        let cli = Cli::try_parse_from(["connecto", "rotate", "desk"]).unwrap();
+        match cli.command {
+            Commands::Rotate {
+                host,
+                all,
+                key_type,
+                shred,
+            } => {
+                assert_eq!(host.as_deref(), Some("desk"));
+                assert!(!all);
+                assert_eq!(key_type, None);
+                assert!(!shred);
+            }
+            _ => panic!("Expected Rotate command"),
+        }
+
+        let cli =
+            Cli::try_parse_from(["connecto", "rotate", "--all", "-t", "rsa", "--shred"]).unwrap();
+        match cli.command {
+            Commands::Rotate {
+                host,
+                all,
+                key_type,
+                shred,
+            } => {
+                assert_eq!(host, None);
+                assert!(all);
+                assert_eq!(key_type, Some(KeyAlgorithm::Rsa4096));
+                assert!(shred);
+            }
+            _ => panic!("Expected Rotate command"),
+        }
+
+        // A host or --all, never both
+        assert!(Cli::try_parse_from(["connecto", "rotate"]).is_err());
+        assert!(Cli::try_parse_from(["connecto", "rotate", "desk", "--all"]).is_err());
+    }
+
+    #[test]
+    fn test_trust_commands() {
+        let cli = Cli::try_parse_from(["connecto", "trust"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Trust {
+                action: None,
+                plain: false
+            }
+        ));
+
+        let cli = Cli::try_parse_from(["connecto", "trust", "set", "desk", "trusted"]).unwrap();
+        match cli.command {
+            Commands::Trust {
+                action: Some(TrustAction::Set { device, level }),
+                ..
+            } => {
+                assert_eq!(device, "desk");
+                assert_eq!(level, commands::trust::Level::Trusted);
+            }
+            _ => panic!("Expected trust set command"),
+        }
+
+        assert!(Cli::try_parse_from(["connecto", "trust", "set", "desk", "friendly"]).is_err());
+    }
+
+    #[test]
+    fn test_repair_command() {
+        let cli = Cli::try_parse_from(["connecto", "repair"]).unwrap();
+        assert!(matches!(cli.command, Commands::Repair { host: None }));
+
+        let cli = Cli::try_parse_from(["connecto", "repair", "desk"]).unwrap();
+        match cli.command {
+            Commands::Repair { host } => assert_eq!(host.as_deref(), Some("desk")),
+            _ => panic!("Expected Repair command"),
+        }
+    }
+
+    #[test]
+    fn test_known_hosts_commands() {
+        let cli = Cli::try_parse_from(["connecto", "known-hosts", "--plain"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::KnownHosts {
+                action: None,
+                plain: true
+            }
+        ));
+
+        let cli =
+            Cli::try_parse_from(["connecto", "known-hosts", "remove", "192.168.1.10"]).unwrap();
+        match cli.command {
+            Commands::KnownHosts {
+                action: Some(KnownHostsAction::Remove { host, port }),
+                ..
+            } => {
+                assert_eq!(host, "192.168.1.10");
+                assert_eq!(port, 22);
+            }
+            _ => panic!("Expected known-hosts remove command"),
+        }
+
+        let cli = Cli::try_parse_from(["connecto", "known-hosts", "remove", "desk", "-p", "2222"])
+            .unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::KnownHosts {
+                action: Some(KnownHostsAction::Remove { port: 2222, .. }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_history_commands() {
+        let cli = Cli::try_parse_from(["connecto", "history"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::History {
+                action: None,
+                plain: false
+            }
+        ));
+
+        let cli = Cli::try_parse_from(["connecto", "history", "export"]).unwrap();
+        match cli.command {
+            Commands::History {
+                action: Some(HistoryAction::Export { format, output }),
+                ..
+            } => {
+                assert_eq!(format, commands::history::ExportFormat::Csv);
+                assert!(output.is_none());
+            }
+            _ => panic!("Expected history export command"),
+        }
+
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "history",
+            "export",
+            "--format",
+            "json",
+            "-o",
+            "audit.json",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::History {
+                action: Some(HistoryAction::Export { format, output }),
+                ..
+            } => {
+                assert_eq!(format, commands::history::ExportFormat::Json);
+                assert_eq!(output.as_deref(), Some("audit.json"));
+            }
+            _ => panic!("Expected history export command"),
+        }
+
+        assert!(Cli::try_parse_from(["connecto", "history", "verify"]).is_ok());
+    }
+
+    #[test]
+    fn test_audit_commands() {
+        let cli = Cli::try_parse_from(["connecto", "audit"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Audit {
+                action: None,
+                plain: false
+            }
+        ));
+
+        let cli = Cli::try_parse_from(["connecto", "audit", "show", "-n", "5", "--plain"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Audit {
+                action: Some(AuditAction::Show { last: Some(5) }),
+                plain: true
+            }
+        ));
+
+        let cli = Cli::try_parse_from(["connecto", "audit", "verify"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Audit {
+                action: Some(AuditAction::Verify),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_export_import_commands() {
+        use commands::export::{ExportFormat, Protection, Sections};
+
+        let cli = Cli::try_parse_from(["connecto", "export"]).unwrap();
+        match cli.command {
+            Commands::Export {
+                output,
+                format,
+                sections,
+                protection,
+            } => {
+                assert!(output.is_none());
+                assert_eq!(format, ExportFormat::Json);
+                assert_eq!(sections.or_all(), Sections::ALL);
+                assert_eq!(protection, Protection::default());
+            }
+            _ => panic!("Expected export command"),
+        }
+
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "export",
+            "--hosts",
+            "--history",
+            "--format",
+            "ssh-config",
+            "-o",
+            "hosts.conf",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Export {
+                output,
+                format,
+                sections,
+                ..
+            } => {
+                assert_eq!(output.as_deref(), Some("hosts.conf"));
+                assert_eq!(format, ExportFormat::SshConfig);
+                assert!(sections.hosts && sections.history);
+                assert!(!sections.keys && !sections.config);
+            }
+            _ => panic!("Expected export command"),
+        }
+
+        let cli =
+            Cli::try_parse_from(["connecto", "export", "--include-keys", "--encrypt"]).unwrap();
+        match cli.command {
+            Commands::Export { protection, .. } => {
+                assert!(protection.include_keys && protection.encrypt);
+            }
+            _ => panic!("Expected export command"),
+        }
+        // Private keys only leave encrypted
+        assert!(Cli::try_parse_from(["connecto", "export", "--include-keys"]).is_err());
+
+        let cli = Cli::try_parse_from(["connecto", "import", "backup.json", "--keys", "--dry-run"])
+            .unwrap();
+        match cli.command {
+            Commands::Import {
+                file,
+                sections,
+                dry_run,
+            } => {
+                assert_eq!(file, "backup.json");
+                assert!(sections.keys && !sections.hosts);
+                assert!(dry_run);
+            }
+            _ => panic!("Expected import command"),
+        }
+    }
+
+    #[test]
+    fn test_policy_commands() {
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "config",
+            "export-policy",
+            "--key",
+            "admin_key",
+            "--port",
+            "9000",
+            "--require-verification",
+            "--allow-algorithm",
+            "ed25519",
+            "--subnet",
+            "10.0.2.0/24",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Config {
+                action:
+                    ConfigAction::ExportPolicy {
+                        key,
+                        port,
+                        require_verification,
+                        allow_algorithms,
+                        subnets,
+                        output,
+                    },
+            } => {
+                assert_eq!(key, "admin_key");
+                assert_eq!(port, Some(9000));
+                assert!(require_verification);
+                assert_eq!(allow_algorithms, vec![policy::PolicyAlgorithm::Ed25519]);
+                assert_eq!(subnets, vec!["10.0.2.0/24"]);
+                assert!(output.is_none());
+            }
+            _ => panic!("Expected config export-policy command"),
+        }
+
+        let cli = Cli::try_parse_from([
+            "connecto",
+            "config",
+            "apply-policy",
+            "policy.json",
+            "--signer",
+            "admin_key.pub",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Config {
+                action: ConfigAction::ApplyPolicy { file, signer },
+            } => {
+                assert_eq!(file, "policy.json");
+                assert_eq!(signer.as_deref(), Some("admin_key.pub"));
+            }
+            _ => panic!("Expected config apply-policy command"),
+        }
+    }
+
+    #[test]
+    fn test_policy_port_respects_explicit_port() {
+        let matches = Cli::command()
+            .try_get_matches_from(["connecto", "listen", "--port", "9100"])
+            .unwrap();
+        assert_eq!(
+            policy_port(&matches, "listen", 9100, config::Config::listen_port),
+            9100
+        );
+        assert!(given(&matches, "listen", "port"));
+        assert!(!given(&matches, "listen", "approval_timeout"));
+        assert!(!given(&matches, "scan", "port"));
+    }
 }
 
 /// Check if the Windows version supports ANSI escape codes
@@ -0,0 +1,258 @@
+//! How output is rendered: glyphs, colors and live progress, or plain text
+//!
+//! The accessible mode (`--accessible`) is for screen readers. Glyphs become
+//! words, colors are turned off, and spinners and progress bars become lines
+//! of text printed as the state changes, instead of redrawing one line.
+
+use colored::ColoredString;
+use connecto_core::i18n;
+use dialoguer::theme::{ColorfulTheme, SimpleTheme, Theme};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Environment variable turning on the accessible mode, like `--accessible`
+pub const ACCESSIBLE_ENV: &str = "CONNECTO_ACCESSIBLE";
+
+static ACCESSIBLE: AtomicBool = AtomicBool::new(false);
+
+const TICK_CHARS: &str = "⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏";
+
+/// Switch to the accessible mode for the rest of the run
+pub fn set_accessible(accessible: bool) {
+    ACCESSIBLE.store(accessible, Ordering::Relaxed);
+    if accessible {
+        colored::control::set_override(false);
+    }
+}
+
+/// Whether output is rendered for screen readers
+pub fn is_accessible() -> bool {
+    ACCESSIBLE.load(Ordering::Relaxed)
+}
+
+/// The status glyph `glyph`, or the word standing in for it
+pub fn mark(glyph: &'static str) -> &'static str {
+    if is_accessible() {
+        word(glyph)
+    } else {
+        glyph
+    }
+}
+
+fn word(glyph: &'static str) -> &'static str {
+    match glyph {
+        "✓" => i18n::text("label.done"),
+        "✗" => i18n::text("label.error"),
+        "!" | "⚠" => i18n::text("label.warning"),
+        "→" => i18n::text("label.note"),
+        "•" => "-",
+        other => other,
+    }
+}
+
+/// Arrow between an old and a new value, e.g. `10.0.0.5 → 10.0.0.9`
+pub fn arrow() -> &'static str {
+    if is_accessible() {
+        i18n::text("label.to")
+    } else {
+        "→"
+    }
+}
+
+/// A spinner or progress bar, printed as plain lines in the accessible mode
+///
+/// Clones share the same display.
+#[derive(Clone)]
+pub struct Progress {
+    inner: Inner,
+}
+
+#[derive(Clone)]
+enum Inner {
+    Live(ProgressBar),
+    Plain(Arc<Mutex<PlainState>>),
+}
+
+#[derive(Default)]
+struct PlainState {
+    message: String,
+    unit: Option<&'static str>,
+    length: u64,
+    /// Quarters of the length already reported
+    reported: u64,
+}
+
+impl Progress {
+    /// A spinner in `color` (e.g. `cyan`) showing a message
+    pub fn spinner(color: &str) -> Self {
+        if is_accessible() {
+            return Self::plain(None);
+        }
+        let spinner = ProgressBar::new_spinner();
+        spinner.set_style(
+            ProgressStyle::default_spinner()
+                .tick_chars(TICK_CHARS)
+                .template(&format!("{{spinner:.{}}} {{msg}}", color))
+                .unwrap(),
+        );
+        Self {
+            inner: Inner::Live(spinner),
+        }
+    }
+
+    /// A progress bar in `color` counting `unit`s (e.g. `hosts`)
+    pub fn bar(color: &str, unit: &'static str) -> Self {
+        if is_accessible() {
+            return Self::plain(Some(unit));
+        }
+        let bar = ProgressBar::new(0);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .tick_chars(TICK_CHARS)
+                .template(&format!(
+                    "{{spinner:.{c}}} {{msg}} [{{bar:30.{c}/dim}}] {{pos}}/{{len}} {}",
+                    unit,
+                    c = color
+                ))
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        Self {
+            inner: Inner::Live(bar),
+        }
+    }
+
+    fn plain(unit: Option<&'static str>) -> Self {
+        Self {
+            inner: Inner::Plain(Arc::new(Mutex::new(PlainState {
+                unit,
+                ..Default::default()
+            }))),
+        }
+    }
+
+    /// Show `message`; printed on a line of its own when it changes
+    pub fn set_message(&self, message: impl Into<String>) {
+        let message = message.into();
+        match &self.inner {
+            Inner::Live(bar) => bar.set_message(message),
+            Inner::Plain(state) => {
+                let mut state = state.lock().unwrap();
+                if state.message != message {
+                    eprintln!("{}", message);
+                    state.message = message;
+                }
+            }
+        }
+    }
+
+    /// Keep the spinner moving while nothing else changes
+    pub fn enable_steady_tick(&self) {
+        if let Inner::Live(bar) = &self.inner {
+            bar.enable_steady_tick(Duration::from_millis(80));
+        }
+    }
+
+    /// Set how many units there are; a new length starts over
+    pub fn set_length(&self, length: u64) {
+        match &self.inner {
+            Inner::Live(bar) => bar.set_length(length),
+            Inner::Plain(state) => {
+                let mut state = state.lock().unwrap();
+                if state.length != length {
+                    state.length = length;
+                    state.reported = 0;
+                }
+            }
+        }
+    }
+
+    /// Set how many units are done, reported at every quarter
+    pub fn set_position(&self, position: u64) {
+        match &self.inner {
+            Inner::Live(bar) => bar.set_position(position),
+            Inner::Plain(state) => {
+                let mut state = state.lock().unwrap();
+                if state.length == 0 {
+                    return;
+                }
+                let quarters = position.min(state.length) * 4 / state.length;
+                if quarters < state.reported {
+                    state.reported = 0;
+                }
+                if quarters > state.reported {
+                    state.reported = quarters;
+                    eprintln!(
+                        "{} of {} {} done",
+                        position,
+                        state.length,
+                        state.unit.unwrap_or("")
+                    );
+                }
+            }
+        }
+    }
+
+    /// Run `f` with the spinner out of the way, e.g. to prompt
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.inner {
+            Inner::Live(bar) => bar.suspend(f),
+            Inner::Plain(_) => f(),
+        }
+    }
+
+    /// Remove the spinner; nothing was drawn in the accessible mode
+    pub fn finish_and_clear(&self) {
+        if let Inner::Live(bar) = &self.inner {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// Theme for prompts; the accessible one has no symbols or colors
+pub fn theme() -> Box<dyn Theme> {
+    if is_accessible() {
+        Box::new(SimpleTheme)
+    } else {
+        Box::new(ColorfulTheme::default())
+    }
+}
+
+/// A banner naming the command, e.g. `CONNECTO SCANNER`
+pub fn banner(title: &str, paint: fn(&str) -> ColoredString) {
+    if is_accessible() {
+        println!("{}", title);
+    } else {
+        println!("{}", paint(&format!("  {}  ", title)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accessible_output() {
+        assert_eq!(word("✓"), "Done:");
+        assert_eq!(word("✗"), "Error:");
+        assert_eq!(word("!"), "Warning:");
+        assert_eq!(word("⚠"), "Warning:");
+        assert_eq!(word("→"), "Note:");
+        assert_eq!(word("•"), "-");
+
+        // Plain progress reports every quarter once, starting over when the
+        // length changes
+        let progress = Progress::plain(Some("hosts"));
+        progress.set_length(8);
+        progress.set_position(3);
+        progress.set_position(8);
+        let Inner::Plain(state) = &progress.inner else {
+            panic!("Expected plain progress");
+        };
+        assert_eq!(state.lock().unwrap().reported, 4);
+        progress.set_length(4);
+        assert_eq!(state.lock().unwrap().reported, 0);
+    }
+}
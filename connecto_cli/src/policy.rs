@@ -0,0 +1,349 @@
+//! Fleet policies for Connecto CLI
+//!
+//! Admins export a policy bundle signed with their SSH key and distribute it
+//! to a fleet. Applying a bundle verifies the signature and installs it into
+//! the machine-level config layer, which every user's config inherits from.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use connecto_core::keys::{KeyAlgorithm, SshKeyPair};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// SSH signature namespace for policy bundles
+pub const POLICY_NAMESPACE: &str = "connecto-policy";
+
+/// Current policy bundle format version
+pub const POLICY_BUNDLE_VERSION: u32 = 1;
+
+/// Environment variable overriding the machine-level config directory
+pub const MACHINE_CONFIG_DIR_ENV: &str = "CONNECTO_MACHINE_CONFIG_DIR";
+
+/// Key algorithms a policy can allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolicyAlgorithm {
+    Ed25519,
+    Rsa,
+    /// ECDSA on either NIST curve
+    Ecdsa,
+    /// Ed25519 on a FIDO2 security key
+    Ed25519Sk,
+}
+
+impl PolicyAlgorithm {
+    fn matches(self, algorithm: KeyAlgorithm) -> bool {
+        matches!(
+            (self, algorithm),
+            (Self::Ed25519, KeyAlgorithm::Ed25519)
+                | (Self::Rsa, KeyAlgorithm::Rsa4096)
+                | (
+                    Self::Ecdsa,
+                    KeyAlgorithm::EcdsaP256 | KeyAlgorithm::EcdsaP384
+                )
+                | (Self::Ed25519Sk, KeyAlgorithm::Ed25519Sk)
+        )
+    }
+}
+
+/// Settings an admin enforces across a fleet
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    /// Default port for listen, pair, scan and sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// Listeners always require a verification code
+    #[serde(default)]
+    pub require_verification: bool,
+
+    /// Key algorithms allowed for new and existing keys (empty allows all)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_algorithms: Vec<PolicyAlgorithm>,
+
+    /// Subnets always included in scans
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_subnets: Vec<String>,
+}
+
+impl Policy {
+    /// Whether keys of `algorithm` may be used
+    pub fn allows(&self, algorithm: KeyAlgorithm) -> bool {
+        self.allowed_algorithms.is_empty()
+            || self.allowed_algorithms.iter().any(|a| a.matches(algorithm))
+    }
+
+    /// Fail if keys of `algorithm` are not allowed
+    pub fn check_algorithm(&self, algorithm: KeyAlgorithm) -> Result<()> {
+        if self.allows(algorithm) {
+            return Ok(());
+        }
+        let allowed: Vec<String> = self
+            .allowed_algorithms
+            .iter()
+            .filter_map(|a| a.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect();
+        bail!(
+            "{} keys are not allowed by the machine policy (allowed: {})",
+            algorithm,
+            allowed.join(", ")
+        )
+    }
+}
+
+/// A policy signed by an admin's SSH key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyBundle {
+    pub version: u32,
+    /// The policy as JSON; the signature covers exactly these bytes
+    pub policy: String,
+    /// Signer's public key in OpenSSH format
+    pub signer: String,
+    /// Armored SSH signature over `policy`
+    pub signature: String,
+}
+
+impl PolicyBundle {
+    /// Sign a policy with the given key pair
+    pub fn sign(policy: &Policy, key_pair: &SshKeyPair) -> Result<Self> {
+        let policy = serde_json::to_string(policy)?;
+        let signature = key_pair
+            .sign(POLICY_NAMESPACE, policy.as_bytes())
+            .context("Failed to sign policy")?;
+
+        Ok(Self {
+            version: POLICY_BUNDLE_VERSION,
+            policy,
+            signer: key_pair.public_key.clone(),
+            signature,
+        })
+    }
+
+    /// Check the signature and return the policy it covers
+    pub fn verify(&self) -> Result<Policy> {
+        if self.version != POLICY_BUNDLE_VERSION {
+            bail!("Unsupported policy bundle version {}", self.version);
+        }
+        SshKeyPair::verify_signature(
+            &self.signer,
+            POLICY_NAMESPACE,
+            self.policy.as_bytes(),
+            &self.signature,
+        )
+        .context("Policy signature is invalid")?;
+
+        serde_json::from_str(&self.policy).context("Failed to parse signed policy")
+    }
+
+    /// Fingerprint of the signing key
+    pub fn signer_fingerprint(&self) -> Result<String> {
+        Ok(signer_fingerprint(&self.signer)?)
+    }
+}
+
+fn signer_fingerprint(public_key: &str) -> connecto_core::Result<String> {
+    let key = SshKeyPair::parse_public_key(public_key)?;
+    Ok(key.fingerprint(Default::default()).to_string())
+}
+
+/// Directory holding the machine-level config layer
+pub fn machine_config_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var(MACHINE_CONFIG_DIR_ENV) {
+        return PathBuf::from(dir);
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let base = std::env::var("PROGRAMDATA").unwrap_or_else(|_| r"C:\ProgramData".to_string());
+        PathBuf::from(base).join("connecto")
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        PathBuf::from("/Library/Application Support/connecto")
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        PathBuf::from("/etc/connecto")
+    }
+}
+
+/// Path of the installed policy bundle
+pub fn machine_policy_path() -> PathBuf {
+    machine_config_dir().join("policy.json")
+}
+
+/// Load the policy bundle installed at `path`, if any
+pub fn load_bundle(path: &Path) -> Result<Option<PolicyBundle>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read policy from {}", path.display()))?;
+    let bundle = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse policy at {}", path.display()))?;
+    Ok(Some(bundle))
+}
+
+/// Load and verify the policy installed in the machine-level config layer
+///
+/// A missing, unreadable, or tampered bundle yields no policy.
+pub fn load_machine_policy() -> Option<Policy> {
+    load_policy(&machine_policy_path())
+}
+
+/// Load and verify the policy installed at `path`
+fn load_policy(path: &Path) -> Option<Policy> {
+    let bundle = match load_bundle(path) {
+        Ok(bundle) => bundle?,
+        Err(e) => {
+            warn!("Ignoring machine policy: {:#}", e);
+            return None;
+        }
+    };
+    match bundle.verify() {
+        Ok(policy) => Some(policy),
+        Err(e) => {
+            warn!("Ignoring machine policy: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Verify a bundle and install it at `path`
+///
+/// The bundle must be signed by `trusted_signer` or, when none is given, by
+/// the signer of the currently installed bundle.
+pub fn apply(path: &Path, bundle: &PolicyBundle, trusted_signer: Option<&str>) -> Result<Policy> {
+    let policy = bundle.verify()?;
+
+    let installed = load_bundle(path).ok().flatten();
+    let trusted = match (trusted_signer, &installed) {
+        (Some(key), _) => key.to_string(),
+        (None, Some(installed)) => installed.signer.clone(),
+        (None, None) => {
+            return Err(anyhow!(
+                "No trusted policy signer yet; pass --signer with the admin's public key"
+            ))
+        }
+    };
+
+    let trusted = signer_fingerprint(&trusted).context("Invalid trusted signer key")?;
+    let signer = bundle.signer_fingerprint()?;
+    if trusted != signer {
+        bail!(
+            "Policy is signed by {}, but the trusted signer is {}",
+            signer,
+            trusted
+        );
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(path, serde_json::to_string_pretty(bundle)?).with_context(|| {
+        format!(
+            "Failed to write {} (applying a policy needs administrator rights)",
+            path.display()
+        )
+    })?;
+
+    Ok(policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin_key() -> SshKeyPair {
+        SshKeyPair::generate(KeyAlgorithm::Ed25519, "admin@fleet").unwrap()
+    }
+
+    fn sample_policy() -> Policy {
+        Policy {
+            port: Some(9000),
+            require_verification: true,
+            allowed_algorithms: vec![PolicyAlgorithm::Ed25519],
+            trusted_subnets: vec!["10.0.2.0/24".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_policy_algorithms() {
+        let policy = sample_policy();
+        assert!(policy.allows(KeyAlgorithm::Ed25519));
+        assert!(!policy.allows(KeyAlgorithm::Rsa4096));
+        assert!(policy.check_algorithm(KeyAlgorithm::Rsa4096).is_err());
+
+        // No restriction allows everything
+        assert!(Policy::default().allows(KeyAlgorithm::Rsa4096));
+    }
+
+    #[test]
+    fn test_bundle_sign_and_verify() {
+        let bundle = PolicyBundle::sign(&sample_policy(), &admin_key()).unwrap();
+        assert_eq!(bundle.verify().unwrap(), sample_policy());
+
+        // Round-trips through the file format
+        let json = serde_json::to_string(&bundle).unwrap();
+        let loaded: PolicyBundle = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.verify().unwrap(), sample_policy());
+    }
+
+    #[test]
+    fn test_bundle_tampering_detected() {
+        let mut bundle = PolicyBundle::sign(&sample_policy(), &admin_key()).unwrap();
+        bundle.policy = bundle.policy.replace("9000", "22");
+        assert!(bundle.verify().is_err());
+
+        // Re-signing with another key does not match the original signer
+        let original = PolicyBundle::sign(&sample_policy(), &admin_key()).unwrap();
+        let forged = PolicyBundle::sign(&sample_policy(), &admin_key()).unwrap();
+        assert_ne!(
+            original.signer_fingerprint().unwrap(),
+            forged.signer_fingerprint().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_apply_requires_trusted_signer() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("connecto").join("policy.json");
+
+        let admin = admin_key();
+        let bundle = PolicyBundle::sign(&sample_policy(), &admin).unwrap();
+
+        // First install needs an explicit signer
+        assert!(apply(&path, &bundle, None).is_err());
+        assert!(apply(&path, &bundle, Some(&admin_key().public_key)).is_err());
+        assert_eq!(
+            apply(&path, &bundle, Some(&admin.public_key)).unwrap(),
+            sample_policy()
+        );
+        assert_eq!(load_policy(&path), Some(sample_policy()));
+
+        // Updates from the same signer are accepted, others are not
+        let update = PolicyBundle::sign(&Policy::default(), &admin).unwrap();
+        assert!(apply(&path, &update, None).is_ok());
+        let intruder = PolicyBundle::sign(&Policy::default(), &admin_key()).unwrap();
+        assert!(apply(&path, &intruder, None).is_err());
+        assert_eq!(load_policy(&path), Some(Policy::default()));
+    }
+
+    #[test]
+    fn test_tampered_install_ignored() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("policy.json");
+
+        let mut bundle = PolicyBundle::sign(&sample_policy(), &admin_key()).unwrap();
+        bundle.policy = bundle.policy.replace("true", "false");
+        fs::write(&path, serde_json::to_string(&bundle).unwrap()).unwrap();
+
+        assert_eq!(load_policy(&path), None);
+    }
+}
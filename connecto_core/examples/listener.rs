@@ -0,0 +1,61 @@
+//! Minimal pairing listener
+//!
+//! Advertises this device over mDNS and accepts one pairing request, adding
+//! the client's key to `~/.ssh/authorized_keys`.
+//!
+//! ```bash
+//! cargo run -p connecto_core --example listener -- [PORT]
+//! ```
+
+use connecto_core::{
+    discovery::{ServiceAdvertiser, DEFAULT_PORT},
+    keys::KeyManager,
+    protocol::{HandshakeServer, ServerEvent},
+    DeviceIdentity,
+};
+use tokio::sync::mpsc;
+
+#[tokio::main]
+async fn main() -> connecto_core::Result<()> {
+    let port = std::env::args()
+        .nth(1)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(DEFAULT_PORT);
+    let device_name = connecto_core::device_name();
+    let identity = DeviceIdentity::load_or_create()?;
+
+    let mut server =
+        HandshakeServer::new(KeyManager::new()?, &device_name).with_identity(&identity);
+    let address = server.listen(port).await?;
+
+    let mut advertiser = ServiceAdvertiser::new()?.with_identity(identity.fingerprint());
+    advertiser.advertise(&device_name, address.port())?;
+    println!("{} is listening on {}", device_name, address);
+
+    let (event_tx, mut event_rx) = mpsc::channel(32);
+    let events = tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                ServerEvent::PairingRequest {
+                    device_name,
+                    address,
+                } => println!("Pairing request from {} ({})", device_name, address),
+                ServerEvent::KeyReceived {
+                    comment,
+                    fingerprint,
+                    ..
+                } => println!("Received key {} ({})", comment, fingerprint),
+                ServerEvent::PairingComplete { device_name } => {
+                    println!("Paired with {}", device_name)
+                }
+                ServerEvent::Error { message } => eprintln!("Error: {}", message),
+                _ => {}
+            }
+        }
+    });
+
+    let result = server.handle_one(event_tx).await;
+    advertiser.stop()?;
+    events.await.ok();
+    result
+}
@@ -0,0 +1,54 @@
+//! Minimal pairing client
+//!
+//! Discovers a listener over mDNS (or uses the given address), generates a
+//! new Ed25519 key, and sends it to the listener. The key is saved to
+//! `~/.ssh/connecto_example` once the listener accepts it.
+//!
+//! ```bash
+//! cargo run -p connecto_core --example pair -- [HOST:PORT]
+//! ```
+
+use connecto_core::{
+    discovery::ServiceBrowser,
+    keys::{KeyAlgorithm, KeyManager, SshKeyPair},
+    protocol::HandshakeClient,
+    ConnectoError,
+};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> connecto_core::Result<()> {
+    let address = match std::env::args().nth(1) {
+        Some(address) => address,
+        None => {
+            println!("Searching for devices...");
+            let browser = ServiceBrowser::new()?;
+            let devices = browser.scan_for_duration(Duration::from_secs(5)).await?;
+            let device = devices
+                .first()
+                .ok_or_else(|| ConnectoError::Discovery("No devices found".to_string()))?;
+            println!("Found {}", device.name);
+            device
+                .connection_string()
+                .ok_or_else(|| ConnectoError::Discovery("Device has no address".to_string()))?
+        }
+    };
+
+    let device_name = connecto_core::device_name();
+    let key_pair = SshKeyPair::generate_async(KeyAlgorithm::Ed25519, &device_name).await?;
+
+    let client = HandshakeClient::new(&device_name);
+    let result = client.pair(&address, &key_pair).await?;
+
+    let (private_key, _) = KeyManager::new()?.save_key_pair(&key_pair, "connecto_example")?;
+    println!(
+        "Paired with {}: ssh -i {} {}@{}",
+        result.server_name,
+        private_key.display(),
+        result.ssh_user,
+        address
+            .rsplit_once(':')
+            .map_or(address.as_str(), |(host, _)| host)
+    );
+    Ok(())
+}
@@ -0,0 +1,37 @@
+//! Subnet scan demo
+//!
+//! Finds Connecto listeners by probing every address in the given subnets,
+//! for networks where mDNS is blocked. Without arguments, scans the local
+//! subnets.
+//!
+//! ```bash
+//! cargo run -p connecto_core --example scan -- [CIDR...]
+//! ```
+
+use connecto_core::discovery::{SubnetScanner, DEFAULT_PORT};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    let subnets: Vec<String> = std::env::args().skip(1).collect();
+    let scanner = SubnetScanner::new(DEFAULT_PORT, Duration::from_millis(500));
+
+    let devices = if subnets.is_empty() {
+        println!("Scanning local subnets...");
+        scanner.scan().await
+    } else {
+        println!("Scanning {}...", subnets.join(", "));
+        scanner.scan_subnets(&subnets).await
+    };
+
+    if devices.is_empty() {
+        println!("No devices found");
+    }
+    for device in devices {
+        println!(
+            "{} at {}",
+            device.name,
+            device.connection_string().unwrap_or_default()
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Bidirectional sync demo
+//!
+//! Run this on two machines at the same time. Each one advertises itself,
+//! finds the other, and both exchange keys, so either can SSH to the other.
+//!
+//! ```bash
+//! cargo run -p connecto_core --example sync
+//! ```
+
+use connecto_core::{
+    discovery::DEFAULT_PORT,
+    keys::{KeyAlgorithm, KeyManager, SshKeyPair},
+    sync::{SyncEvent, SyncHandler, DEFAULT_SYNC_TIMEOUT_SECS},
+    DeviceIdentity,
+};
+use tokio::sync::mpsc;
+
+#[tokio::main]
+async fn main() -> connecto_core::Result<()> {
+    let device_name = connecto_core::device_name();
+    let identity = DeviceIdentity::load_or_create()?;
+    let key_manager = KeyManager::new()?;
+
+    let key_pair = SshKeyPair::generate_async(KeyAlgorithm::Ed25519, &device_name).await?;
+    let (private_key, _) = key_manager.save_key_pair(&key_pair, "connecto_sync_example")?;
+
+    let handler = SyncHandler::new(key_manager, &device_name, key_pair).with_identity(&identity);
+
+    let (event_tx, mut event_rx) = mpsc::channel(32);
+    let events = tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                SyncEvent::Started { address } => println!("Listening on {}", address),
+                SyncEvent::Searching => println!("Searching for a sync peer..."),
+                SyncEvent::PeerFound {
+                    device_name,
+                    address,
+                } => println!("Found {} at {}", device_name, address),
+                SyncEvent::KeyReceived { key_comment, .. } => {
+                    println!("Received key {}", key_comment)
+                }
+                SyncEvent::Failed { message } => eprintln!("Sync failed: {}", message),
+                _ => {}
+            }
+        }
+    });
+
+    let result = handler
+        .run(DEFAULT_PORT, DEFAULT_SYNC_TIMEOUT_SECS, event_tx)
+        .await?;
+    events.await.ok();
+
+    println!(
+        "Synced with {}: ssh -i {} {}@{}",
+        result.peer_name,
+        private_key.display(),
+        result.peer_user,
+        result.peer_address
+    );
+    Ok(())
+}
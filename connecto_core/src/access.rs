@@ -0,0 +1,249 @@
+//! Access control for listeners
+//!
+//! Allow and deny lists a listener checks before it answers a pairing
+//! request: client addresses by IP or subnet, and device names by pattern.
+//! Denied addresses are always refused; when allow lists are given, a client
+//! has to match each of them.
+
+use crate::error::{ConnectoError, Result};
+use crate::known_hosts::wildcard_match;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IP address or a subnet in CIDR notation, e.g. `10.0.0.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// Whether `ip` is in the network
+    ///
+    /// IPv4 clients of a dual-stack listener, which arrive as IPv4-mapped
+    /// IPv6 addresses, are matched as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix(address: IpAddr) -> u8 {
+        match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix == Self::max_prefix(self.address) {
+            write!(f, "{}", self.address)
+        } else {
+            write!(f, "{}/{}", self.address, self.prefix)
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = ConnectoError;
+
+    /// Parse `IP` or `IP/PREFIX`
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            ConnectoError::Access(format!(
+                "Invalid address '{}': expected an IP address or a subnet like 10.0.0.0/24",
+                s
+            ))
+        };
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max = Self::max_prefix(address);
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = ConnectoError;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Network> for String {
+    fn from(network: Network) -> Self {
+        network.to_string()
+    }
+}
+
+/// Which clients a listener answers
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessList {
+    /// Addresses clients must come from; empty allows every address
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Network>,
+    /// Addresses that are always refused
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Network>,
+    /// Device name patterns with `*` and `?`, matched ignoring case; empty
+    /// allows every name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_names: Vec<String>,
+}
+
+impl AccessList {
+    /// Whether the list lets every client through
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty() && self.allow_names.is_empty()
+    }
+
+    /// Add the rules of `other` that this list does not have yet
+    pub fn merge(&mut self, other: &AccessList) {
+        fn extend<T: Clone + PartialEq>(list: &mut Vec<T>, items: &[T]) {
+            for item in items {
+                if !list.contains(item) {
+                    list.push(item.clone());
+                }
+            }
+        }
+        extend(&mut self.allow, &other.allow);
+        extend(&mut self.deny, &other.deny);
+        extend(&mut self.allow_names, &other.allow_names);
+    }
+
+    /// Why the client `device_name` at `ip` is refused, or `None` if it
+    /// may pair
+    pub fn refusal(&self, ip: IpAddr, device_name: &str) -> Option<String> {
+        if let Some(network) = self.deny.iter().find(|network| network.contains(ip)) {
+            return Some(format!("{} is denied by the rule {}", ip, network));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|network| network.contains(ip)) {
+            return Some(format!("{} is not in the allowed addresses", ip));
+        }
+        let name = device_name.to_lowercase();
+        if !self.allow_names.is_empty()
+            && !self
+                .allow_names
+                .iter()
+                .any(|pattern| wildcard_match(&pattern.to_lowercase(), &name))
+        {
+            return Some(format!("'{}' is not an allowed device name", device_name));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_network_parse() {
+        let network: Network = "10.0.0.0/24".parse().unwrap();
+        assert!(network.contains(ip("10.0.0.13")));
+        assert!(!network.contains(ip("10.0.1.13")));
+        assert_eq!(network.to_string(), "10.0.0.0/24");
+
+        let host: Network = "10.0.0.13".parse().unwrap();
+        assert!(host.contains(ip("10.0.0.13")));
+        assert!(!host.contains(ip("10.0.0.14")));
+        assert_eq!(host.to_string(), "10.0.0.13");
+
+        let any: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("192.168.1.5")));
+        assert!(!any.contains(ip("fe80::1")));
+
+        let v6: Network = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("laptop".parse::<Network>().is_err());
+        assert!("10.0.0.0/".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_network_matches_mapped_ipv4() {
+        let network: Network = "10.0.0.0/24".parse().unwrap();
+        assert!(network.contains(ip("::ffff:10.0.0.13")));
+    }
+
+    #[test]
+    fn test_refusal() {
+        let access = AccessList {
+            allow: vec!["10.0.0.0/24".parse().unwrap()],
+            deny: vec!["10.0.0.13".parse().unwrap()],
+            allow_names: vec!["alice-*".to_string()],
+        };
+        assert_eq!(access.refusal(ip("10.0.0.5"), "alice-laptop"), None);
+        assert_eq!(access.refusal(ip("10.0.0.5"), "Alice-Desk"), None);
+        assert_eq!(
+            access.refusal(ip("10.0.0.13"), "alice-laptop").as_deref(),
+            Some("10.0.0.13 is denied by the rule 10.0.0.13")
+        );
+        assert_eq!(
+            access.refusal(ip("192.168.1.5"), "alice-laptop").as_deref(),
+            Some("192.168.1.5 is not in the allowed addresses")
+        );
+        assert_eq!(
+            access.refusal(ip("10.0.0.5"), "bob-laptop").as_deref(),
+            Some("'bob-laptop' is not an allowed device name")
+        );
+
+        assert_eq!(AccessList::default().refusal(ip("10.0.0.13"), "bob"), None);
+    }
+
+    #[test]
+    fn test_access_list_serialization() {
+        let mut access = AccessList::default();
+        access.merge(&AccessList {
+            deny: vec!["10.0.0.13".parse().unwrap()],
+            allow_names: vec!["alice-*".to_string()],
+            ..Default::default()
+        });
+        access.merge(&AccessList {
+            deny: vec!["10.0.0.13".parse().unwrap()],
+            ..Default::default()
+        });
+        assert_eq!(access.deny.len(), 1);
+
+        let json = serde_json::to_string(&access).unwrap();
+        assert_eq!(json, r#"{"deny":["10.0.0.13"],"allow_names":["alice-*"]}"#);
+        let loaded: AccessList = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, access);
+
+        assert!(serde_json::from_str::<AccessList>(r#"{"allow":["nope"]}"#).is_err());
+    }
+}
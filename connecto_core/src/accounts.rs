@@ -0,0 +1,129 @@
+//! Local accounts a listener installs keys for
+//!
+//! A listener normally installs keys into the authorized_keys of the account
+//! it runs as. With [`HandshakeServer::with_users`] it offers clients other
+//! accounts too, and the client picks one. Writing another account's
+//! `~/.ssh` needs the privileges to do so, in practice running as root; the
+//! files written are handed to the account, since sshd ignores an
+//! authorized_keys its owner cannot have written.
+//!
+//! [`HandshakeServer::with_users`]: crate::protocol::HandshakeServer::with_users
+
+use crate::error::{ConnectoError, Result};
+use std::path::PathBuf;
+
+/// The user database consulted by [`Account::lookup`]
+#[cfg(unix)]
+const PASSWD_FILE: &str = "/etc/passwd";
+
+/// A local user account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub name: String,
+    pub home: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Account {
+    /// Find the account called `name`
+    ///
+    /// macOS keeps its users in Directory Services rather than the passwd
+    /// file, so there an account is also found by its home in `/Users`.
+    #[cfg(unix)]
+    pub fn lookup(name: &str) -> Result<Self> {
+        let passwd = std::fs::read_to_string(PASSWD_FILE)?;
+        if let Some(account) = Self::from_passwd(&passwd, name) {
+            return Ok(account);
+        }
+        #[cfg(target_os = "macos")]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let home = PathBuf::from("/Users").join(name);
+            if let Ok(metadata) = std::fs::metadata(&home) {
+                return Ok(Self {
+                    name: name.to_string(),
+                    home,
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                });
+            }
+        }
+        Err(no_account(name))
+    }
+
+    #[cfg(not(unix))]
+    pub fn lookup(name: &str) -> Result<Self> {
+        Err(ConnectoError::PermissionDenied(format!(
+            "Keys can only be installed for the current user on this platform, not {}",
+            name
+        )))
+    }
+
+    /// The entry for `name` in the contents of a passwd file
+    #[cfg_attr(not(unix), allow(dead_code))]
+    fn from_passwd(passwd: &str, name: &str) -> Option<Self> {
+        passwd.lines().find_map(|line| {
+            // name:password:uid:gid:gecos:home:shell
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 7 || fields[0] != name {
+                return None;
+            }
+            Some(Self {
+                name: name.to_string(),
+                uid: fields[2].parse().ok()?,
+                gid: fields[3].parse().ok()?,
+                home: PathBuf::from(fields[5]),
+            })
+        })
+    }
+
+    /// The account's SSH directory
+    pub fn ssh_dir(&self) -> PathBuf {
+        self.home.join(".ssh")
+    }
+}
+
+#[cfg(unix)]
+fn no_account(name: &str) -> ConnectoError {
+    ConnectoError::Io(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("No account named {}", name),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str = "\
+root:x:0:0:root:/root:/bin/bash
+# a comment
+alice:x:1000:1000:Alice,,,:/home/alice:/bin/zsh
+deploy:x:1001:100::/srv/deploy:/usr/sbin/nologin
+broken:x:notanumber:100::/home/broken:/bin/sh
+";
+
+    #[test]
+    fn test_from_passwd() {
+        let alice = Account::from_passwd(PASSWD, "alice").unwrap();
+        assert_eq!(alice.uid, 1000);
+        assert_eq!(alice.gid, 1000);
+        assert_eq!(alice.ssh_dir(), PathBuf::from("/home/alice/.ssh"));
+
+        let deploy = Account::from_passwd(PASSWD, "deploy").unwrap();
+        assert_eq!(deploy.home, PathBuf::from("/srv/deploy"));
+        assert_eq!(deploy.gid, 100);
+
+        assert_eq!(Account::from_passwd(PASSWD, "ali"), None);
+        assert_eq!(Account::from_passwd(PASSWD, "broken"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lookup() {
+        assert_eq!(Account::lookup("root").unwrap().uid, 0);
+        assert!(Account::lookup("no-such-connecto-user").is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Pairing attempt module
+//!
+//! Keeps impatient retries from piling up keys and SSH config entries. While
+//! a pairing with a target runs, a lock file for it is held in the `attempts`
+//! directory of the Connecto config directory, so a second attempt on the
+//! same target — from another `connecto pair` or the GUI — fails at once
+//! instead of racing the first. The lock is released when the attempt ends,
+//! even if the process is killed.
+//!
+//! The key generated for an attempt is kept next to its lock for a short
+//! window. Retrying within that window sends the same key again, so the peer
+//! ends up with one key no matter how often the user pressed retry.
+
+use crate::error::{ConnectoError, Result};
+use crate::keys::{KeyAlgorithm, KeyManager, SshKeyPair};
+use crate::paths;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Name of the attempts directory inside the config directory
+const ATTEMPTS_DIR: &str = "attempts";
+
+/// How long the key of an attempt is offered to retries
+pub const DEFAULT_REUSE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Pairing attempts on this device
+#[derive(Debug, Clone)]
+pub struct PairingAttempts {
+    dir: PathBuf,
+    reuse_window: Duration,
+}
+
+impl PairingAttempts {
+    /// Default location of the attempts directory
+    pub fn default_dir() -> Result<PathBuf> {
+        paths::config_file(ATTEMPTS_DIR)
+    }
+
+    /// Use the default attempts directory in the Connecto config directory
+    pub fn new() -> Result<Self> {
+        Ok(Self::with_dir(Self::default_dir()?))
+    }
+
+    /// Use a specific attempts directory
+    pub fn with_dir(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            reuse_window: DEFAULT_REUSE_WINDOW,
+        }
+    }
+
+    /// Offer an attempt's key to retries for `window` instead of the default
+    pub fn with_reuse_window(mut self, window: Duration) -> Self {
+        self.reuse_window = window;
+        self
+    }
+
+    /// Start an attempt on `target`
+    ///
+    /// Fails with [`ConnectoError::PairingInProgress`] while another attempt
+    /// on the same target is running.
+    pub fn begin(&self, target: &str) -> Result<PairingAttempt> {
+        // Created private, since it will also hold private keys
+        KeyManager::with_dir(self.dir.clone()).ensure_ssh_dir()?;
+
+        let name = file_name(target);
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.dir.join(format!("{}.lock", name)))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(ConnectoError::PairingInProgress(target.to_string()))
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        Ok(PairingAttempt {
+            target: target.to_string(),
+            dir: self.dir.clone(),
+            name,
+            reuse_window: self.reuse_window,
+            _lock: lock,
+        })
+    }
+}
+
+/// A running pairing attempt; the target is unlocked when it is dropped
+#[derive(Debug)]
+pub struct PairingAttempt {
+    target: String,
+    dir: PathBuf,
+    /// File name for this target's lock and key
+    name: String,
+    reuse_window: Duration,
+    _lock: File,
+}
+
+impl PairingAttempt {
+    /// Address the attempt pairs with
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The key a recent attempt on this target sent, if it is an `algorithm`
+    /// key
+    ///
+    /// Keys older than the reuse window are deleted instead.
+    pub fn recent_key(&self, algorithm: KeyAlgorithm) -> Option<SshKeyPair> {
+        let path = self.key_path();
+        let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+        if age > self.reuse_window {
+            self.forget_key();
+            return None;
+        }
+        SshKeyPair::load_from_file(&path.to_string_lossy())
+            .ok()
+            .filter(|key_pair| key_pair.algorithm == algorithm)
+    }
+
+    /// Keep `key_pair` so a retry within the reuse window can send it again
+    pub fn remember_key(&self, key_pair: &SshKeyPair) -> Result<()> {
+        KeyManager::with_dir(self.dir.clone()).save_key_pair(key_pair, &self.name)?;
+        Ok(())
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join(&self.name)
+    }
+
+    fn forget_key(&self) {
+        let path = self.key_path();
+        let _ = KeyManager::secure_delete(&path);
+        let _ = fs::remove_file(pub_path(&path));
+    }
+}
+
+fn pub_path(private_path: &Path) -> PathBuf {
+    let mut path = private_path.as_os_str().to_owned();
+    path.push(".pub");
+    PathBuf::from(path)
+}
+
+/// A file name for `target`, e.g. `10.0.0.5_8099`
+fn file_name(target: &str) -> String {
+    target
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_duplicate_attempt_is_blocked() {
+        let temp_dir = TempDir::new().unwrap();
+        let attempts = PairingAttempts::with_dir(temp_dir.path().join(ATTEMPTS_DIR));
+
+        let attempt = attempts.begin("10.0.0.5:8099").unwrap();
+        assert_eq!(attempt.target(), "10.0.0.5:8099");
+        assert!(matches!(
+            attempts.begin("10.0.0.5:8099"),
+            Err(ConnectoError::PairingInProgress(target)) if target == "10.0.0.5:8099"
+        ));
+
+        // Other targets are unaffected
+        let _other = attempts.begin("10.0.0.6:8099").unwrap();
+
+        drop(attempt);
+        assert!(attempts.begin("10.0.0.5:8099").is_ok());
+    }
+
+    #[test]
+    fn test_retry_reuses_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let attempts = PairingAttempts::with_dir(temp_dir.path().join(ATTEMPTS_DIR));
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+
+        let attempt = attempts.begin("[fe80::1%en0]:8099").unwrap();
+        assert!(attempt.recent_key(KeyAlgorithm::Ed25519).is_none());
+        attempt.remember_key(&key_pair).unwrap();
+        drop(attempt);
+
+        let retry = attempts.begin("[fe80::1%en0]:8099").unwrap();
+        let reused = retry.recent_key(KeyAlgorithm::Ed25519).unwrap();
+        assert_eq!(reused.public_key, key_pair.public_key);
+        assert_eq!(reused.private_key, key_pair.private_key);
+
+        // Asking for a different key type means a new key
+        assert!(retry.recent_key(KeyAlgorithm::Rsa4096).is_none());
+
+        // Other targets don't see it
+        let other = attempts.begin("10.0.0.5:8099").unwrap();
+        assert!(other.recent_key(KeyAlgorithm::Ed25519).is_none());
+    }
+
+    #[test]
+    fn test_expired_key_is_deleted() {
+        let temp_dir = TempDir::new().unwrap();
+        let attempts = PairingAttempts::with_dir(temp_dir.path().join(ATTEMPTS_DIR))
+            .with_reuse_window(Duration::ZERO);
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+
+        let attempt = attempts.begin("10.0.0.5:8099").unwrap();
+        attempt.remember_key(&key_pair).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert!(attempt.recent_key(KeyAlgorithm::Ed25519).is_none());
+        assert!(!attempt.key_path().exists());
+        assert!(!pub_path(&attempt.key_path()).exists());
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("10.0.0.5:8099"), "10.0.0.5_8099");
+        assert_eq!(file_name("[fe80::1%en0]:8099"), "_fe80__1_en0__8099");
+        assert_eq!(file_name("desk.local:8099"), "desk.local_8099");
+    }
+}
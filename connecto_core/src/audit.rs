@@ -0,0 +1,689 @@
+//! Decision and audit logs
+//!
+//! Two logs in the Connecto config directory keep a record for auditors:
+//!
+//! - `decisions.jsonl` holds every accept/reject decision on an incoming key,
+//!   with who made it and the policy in effect
+//! - `audit.jsonl` holds every security-relevant event: keys generated,
+//!   installed in or removed from `authorized_keys`, pairings accepted or
+//!   rejected, and syncs completed
+//!
+//! Both are append-only and hash-chained: each entry carries the hash of the
+//! one before it, so editing or removing an entry breaks the chain for every
+//! entry after it.
+
+use crate::authorized_keys::AuthorizedKey;
+use crate::error::{ConnectoError, Result};
+use crate::keys::SshKeyPair;
+use crate::paths;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File name of the decision log inside the config directory
+const DECISIONS_FILE: &str = "decisions.jsonl";
+
+/// File name of the audit log inside the config directory
+const AUDIT_FILE: &str = "audit.jsonl";
+
+/// Serializes appends to audit logs, which every key manager of the process
+/// writes to
+static AUDIT_LOCK: Mutex<()> = Mutex::new(());
+
+/// `prev_hash` of the first entry in a log
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Whether a key was installed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Accepted,
+    Rejected,
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Accepted => write!(f, "accepted"),
+            Self::Rejected => write!(f, "rejected"),
+        }
+    }
+}
+
+/// A single decision in the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecisionRecord {
+    /// Position in the log, starting at 1
+    pub seq: u64,
+    /// Unix timestamp (seconds) of the decision
+    pub timestamp: u64,
+    pub decision: Decision,
+    /// Device name the peer announced
+    pub peer_name: String,
+    /// Peer IP address
+    pub address: String,
+    /// Fingerprint of the key the peer sent
+    pub fingerprint: String,
+    /// Local user who approved or rejected the request, if a person decided
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approver: Option<String>,
+    /// Why the request was rejected, or accepted without anyone answering
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Machine policy in effect, as JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    /// Hash of the previous entry
+    pub prev_hash: String,
+    /// SHA-256 over this entry with `hash` left empty
+    pub hash: String,
+}
+
+impl DecisionRecord {
+    /// Create an unsealed record for a key sent from `address`
+    ///
+    /// The sequence number, timestamp, policy, and hashes are filled in by
+    /// [`DecisionLog::append`].
+    pub fn new(decision: Decision, peer_name: &str, address: &str, public_key: &str) -> Self {
+        Self {
+            seq: 0,
+            timestamp: 0,
+            decision,
+            peer_name: peer_name.to_string(),
+            address: address.to_string(),
+            fingerprint: fingerprint_of(public_key),
+            approver: None,
+            reason: None,
+            policy: None,
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    /// Set the local user who made the decision
+    pub fn with_approver(mut self, approver: Option<&str>) -> Self {
+        self.approver = approver.map(str::to_string);
+        self
+    }
+
+    /// Set why the request was rejected, or accepted without anyone answering
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    /// Hash of this record's contents, excluding `hash` itself
+    pub fn compute_hash(&self) -> Result<String> {
+        let mut unsealed = self.clone();
+        unsealed.hash.clear();
+        sha256_json(&unsealed)
+    }
+}
+
+impl Chained for DecisionRecord {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn prev_hash(&self) -> &str {
+        &self.prev_hash
+    }
+
+    fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    fn compute_hash(&self) -> Result<String> {
+        DecisionRecord::compute_hash(self)
+    }
+}
+
+/// The decision log
+#[derive(Debug, Clone)]
+pub struct DecisionLog {
+    path: PathBuf,
+    policy: Option<String>,
+    // Serializes appends from concurrently handled clients
+    lock: Arc<Mutex<()>>,
+}
+
+impl DecisionLog {
+    /// Default location of the decision log
+    pub fn default_path() -> Result<PathBuf> {
+        paths::config_file(DECISIONS_FILE)
+    }
+
+    /// Use the default log in the Connecto config directory
+    pub fn new() -> Result<Self> {
+        Ok(Self::with_path(Self::default_path()?))
+    }
+
+    /// Use a specific log file
+    pub fn with_path(path: PathBuf) -> Self {
+        Self {
+            path,
+            policy: None,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Stamp new entries with the machine policy in effect
+    pub fn with_policy(mut self, policy: &str) -> Self {
+        self.policy = Some(policy.to_string());
+        self
+    }
+
+    /// Path of the log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All entries, oldest first
+    pub fn all(&self) -> Result<Vec<DecisionRecord>> {
+        read_entries(&self.path)
+    }
+
+    /// Seal `record` onto the end of the chain and append it to the log
+    pub fn append(&self, mut record: DecisionRecord) -> Result<DecisionRecord> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let last = self.all()?.pop();
+        record.seq = last.as_ref().map_or(1, |r| r.seq + 1);
+        record.prev_hash = last.map_or_else(|| GENESIS_HASH.to_string(), |r| r.hash);
+        record.timestamp = unix_now();
+        record.policy = self.policy.clone();
+        record.hash = record.compute_hash()?;
+
+        append_entry(&self.path, &record)?;
+        Ok(record)
+    }
+
+    /// Check the hash chain, returning the number of entries
+    pub fn verify(&self) -> Result<usize> {
+        verify_entries::<DecisionRecord>(&self.path).map_err(ConnectoError::DecisionLog)
+    }
+}
+
+/// Something that happened to this device's keys or pairings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A key pair was generated and saved to the SSH directory
+    KeyGenerated { name: String, fingerprint: String },
+    /// A key was added to `authorized_keys`, or its entry rewritten with new
+    /// options or expiry
+    KeyInstalled {
+        fingerprint: String,
+        comment: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        options: String,
+    },
+    /// A key was removed from `authorized_keys`
+    KeyRemoved {
+        fingerprint: String,
+        comment: String,
+        /// Why, if it was not removed by hand, e.g. because it expired
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A listener accepted a pairing request
+    PairingAccepted {
+        peer_name: String,
+        address: String,
+        fingerprint: String,
+    },
+    /// A listener rejected a pairing request
+    PairingRejected {
+        peer_name: String,
+        address: String,
+        fingerprint: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A sync with a peer finished
+    SyncCompleted {
+        peer_name: String,
+        address: String,
+        /// Keys of the peer added to `authorized_keys`
+        keys_added: usize,
+    },
+}
+
+impl AuditEvent {
+    /// A key pair named `name` was generated
+    pub fn key_generated(name: &str, public_key: &str) -> Self {
+        Self::KeyGenerated {
+            name: name.to_string(),
+            fingerprint: fingerprint_of(public_key),
+        }
+    }
+
+    /// `key` was written to `authorized_keys`
+    pub fn key_installed(key: &AuthorizedKey) -> Self {
+        Self::KeyInstalled {
+            fingerprint: fingerprint_of(&format!("{} {}", key.key_type, key.blob)),
+            comment: key.comment.clone(),
+            options: key.options.clone(),
+        }
+    }
+
+    /// `key` was removed from `authorized_keys`, for `reason` if given
+    pub fn key_removed(key: &AuthorizedKey, reason: Option<&str>) -> Self {
+        Self::KeyRemoved {
+            fingerprint: fingerprint_of(&format!("{} {}", key.key_type, key.blob)),
+            comment: key.comment.clone(),
+            reason: reason.map(str::to_string),
+        }
+    }
+
+    /// The pairing event matching a listener's decision
+    pub fn from_decision(record: &DecisionRecord) -> Self {
+        match record.decision {
+            Decision::Accepted => Self::PairingAccepted {
+                peer_name: record.peer_name.clone(),
+                address: record.address.clone(),
+                fingerprint: record.fingerprint.clone(),
+            },
+            Decision::Rejected => Self::PairingRejected {
+                peer_name: record.peer_name.clone(),
+                address: record.address.clone(),
+                fingerprint: record.fingerprint.clone(),
+                reason: record.reason.clone(),
+            },
+        }
+    }
+
+    /// A sync with `peer_name` at `address` added `keys_added` keys
+    pub fn sync_completed(peer_name: &str, address: IpAddr, keys_added: usize) -> Self {
+        Self::SyncCompleted {
+            peer_name: peer_name.to_string(),
+            address: address.to_string(),
+            keys_added,
+        }
+    }
+
+    /// The event's name as written to the log, e.g. `key_installed`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::KeyGenerated { .. } => "key_generated",
+            Self::KeyInstalled { .. } => "key_installed",
+            Self::KeyRemoved { .. } => "key_removed",
+            Self::PairingAccepted { .. } => "pairing_accepted",
+            Self::PairingRejected { .. } => "pairing_rejected",
+            Self::SyncCompleted { .. } => "sync_completed",
+        }
+    }
+}
+
+impl fmt::Display for AuditEvent {
+    /// What happened in a few words, without the event name
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let commented = |fingerprint: &str, comment: &str| {
+            if comment.is_empty() {
+                fingerprint.to_string()
+            } else {
+                format!("{} ({})", fingerprint, comment)
+            }
+        };
+        match self {
+            Self::KeyGenerated { name, fingerprint } => write!(f, "{} {}", name, fingerprint),
+            Self::KeyInstalled {
+                fingerprint,
+                comment,
+                ..
+            } => write!(f, "{}", commented(fingerprint, comment)),
+            Self::KeyRemoved {
+                fingerprint,
+                comment,
+                reason,
+            } => {
+                write!(f, "{}", commented(fingerprint, comment))?;
+                match reason {
+                    Some(reason) => write!(f, ": {}", reason),
+                    None => Ok(()),
+                }
+            }
+            Self::PairingAccepted {
+                peer_name,
+                address,
+                fingerprint,
+            } => write!(f, "{} at {}, key {}", peer_name, address, fingerprint),
+            Self::PairingRejected {
+                peer_name,
+                address,
+                fingerprint,
+                reason,
+            } => {
+                write!(f, "{} at {}, key {}", peer_name, address, fingerprint)?;
+                match reason {
+                    Some(reason) => write!(f, ": {}", reason),
+                    None => Ok(()),
+                }
+            }
+            Self::SyncCompleted {
+                peer_name,
+                address,
+                keys_added,
+            } => write!(
+                f,
+                "{} at {}, {} key(s) added",
+                peer_name, address, keys_added
+            ),
+        }
+    }
+}
+
+/// A single event in the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Position in the log, starting at 1
+    pub seq: u64,
+    /// Unix timestamp (seconds) of the event
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    /// Hash of the previous entry
+    pub prev_hash: String,
+    /// SHA-256 over this entry with `hash` left empty
+    pub hash: String,
+}
+
+impl AuditRecord {
+    /// Hash of this record's contents, excluding `hash` itself
+    pub fn compute_hash(&self) -> Result<String> {
+        let mut unsealed = self.clone();
+        unsealed.hash.clear();
+        sha256_json(&unsealed)
+    }
+}
+
+impl Chained for AuditRecord {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn prev_hash(&self) -> &str {
+        &self.prev_hash
+    }
+
+    fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    fn compute_hash(&self) -> Result<String> {
+        AuditRecord::compute_hash(self)
+    }
+}
+
+/// The audit log
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Default location of the audit log
+    pub fn default_path() -> Result<PathBuf> {
+        DecisionLog::default_path().map(|path| path.with_file_name(AUDIT_FILE))
+    }
+
+    /// Use the default log in the Connecto config directory
+    pub fn new() -> Result<Self> {
+        Ok(Self::with_path(Self::default_path()?))
+    }
+
+    /// Use a specific log file
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Path of the log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All entries, oldest first
+    pub fn all(&self) -> Result<Vec<AuditRecord>> {
+        read_entries(&self.path)
+    }
+
+    /// Seal `event` onto the end of the chain and append it to the log
+    pub fn append(&self, event: AuditEvent) -> Result<AuditRecord> {
+        let _guard = AUDIT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let last = self.all()?.pop();
+        let mut record = AuditRecord {
+            seq: last.as_ref().map_or(1, |r| r.seq + 1),
+            timestamp: unix_now(),
+            event,
+            prev_hash: last.map_or_else(|| GENESIS_HASH.to_string(), |r| r.hash),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash()?;
+
+        append_entry(&self.path, &record)?;
+        Ok(record)
+    }
+
+    /// Check the hash chain, returning the number of entries
+    pub fn verify(&self) -> Result<usize> {
+        verify_entries::<AuditRecord>(&self.path).map_err(ConnectoError::AuditLog)
+    }
+}
+
+/// An entry of a hash-chained log
+trait Chained: Serialize + DeserializeOwned {
+    fn seq(&self) -> u64;
+    fn prev_hash(&self) -> &str;
+    fn hash(&self) -> &str;
+    fn compute_hash(&self) -> Result<String>;
+}
+
+fn read_entries<T: Chained>(path: &Path) -> Result<Vec<T>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+fn append_entry<T: Chained>(path: &Path, entry: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// Check the chain of the log at `path`, returning the number of entries or
+/// where it breaks
+fn verify_entries<T: Chained>(path: &Path) -> std::result::Result<usize, String> {
+    let entries: Vec<T> = read_entries(path).map_err(|e| format!("Unreadable entry: {}", e))?;
+
+    let mut prev_hash = GENESIS_HASH;
+    for (i, entry) in entries.iter().enumerate() {
+        let expected_seq = i as u64 + 1;
+        if entry.seq() != expected_seq {
+            return Err(format!(
+                "Entry {} is missing or out of order (found entry {})",
+                expected_seq,
+                entry.seq()
+            ));
+        }
+        if entry.prev_hash() != prev_hash {
+            return Err(format!(
+                "Entry {} does not follow entry {}",
+                entry.seq(),
+                entry.seq() - 1
+            ));
+        }
+        if entry.compute_hash().map_err(|e| e.to_string())? != entry.hash() {
+            return Err(format!("Entry {} was modified", entry.seq()));
+        }
+        prev_hash = entry.hash();
+    }
+    Ok(entries.len())
+}
+
+fn sha256_json(value: &impl Serialize) -> Result<String> {
+    let digest = Sha256::digest(serde_json::to_string(value)?.as_bytes());
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn fingerprint_of(public_key: &str) -> String {
+    SshKeyPair::public_key_fingerprint(public_key).unwrap_or_else(|_| "(invalid key)".to_string())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyAlgorithm;
+    use tempfile::TempDir;
+
+    fn log_with_entries(temp_dir: &TempDir) -> DecisionLog {
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+        let log = DecisionLog::with_path(temp_dir.path().join("connecto").join(DECISIONS_FILE))
+            .with_policy("{\"require_verification\":true}");
+
+        log.append(
+            DecisionRecord::new(Decision::Accepted, "Desk", "10.0.0.1", &key.public_key)
+                .with_approver(Some("alice")),
+        )
+        .unwrap();
+        log.append(
+            DecisionRecord::new(Decision::Rejected, "Laptop", "10.0.0.2", &key.public_key)
+                .with_approver(Some("alice"))
+                .with_reason("Rejected by user"),
+        )
+        .unwrap();
+        log.append(DecisionRecord::new(
+            Decision::Accepted,
+            "Phone",
+            "10.0.0.3",
+            &key.public_key,
+        ))
+        .unwrap();
+        log
+    }
+
+    #[test]
+    fn test_append_chains_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = log_with_entries(&temp_dir);
+
+        let records = log.all().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].prev_hash, GENESIS_HASH);
+        assert_eq!(records[1].prev_hash, records[0].hash);
+        assert_eq!(records[2].seq, 3);
+        assert_eq!(records[1].decision, Decision::Rejected);
+        assert_eq!(
+            records[0].policy.as_deref(),
+            Some("{\"require_verification\":true}")
+        );
+        assert_eq!(log.verify().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_verify_detects_tampering() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = log_with_entries(&temp_dir);
+        let original = fs::read_to_string(log.path()).unwrap();
+
+        // Rewriting a decision
+        fs::write(log.path(), original.replacen("rejected", "accepted", 1)).unwrap();
+        assert!(log.verify().unwrap_err().to_string().contains("Entry 2"));
+
+        // Deleting an entry
+        let lines: Vec<&str> = original.lines().collect();
+        fs::write(log.path(), format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(log.verify().is_err());
+
+        // Re-sealing an edited entry still breaks the link to the next one
+        let mut records = log_with_entries(&TempDir::new().unwrap()).all().unwrap();
+        records[0].peer_name = "Impostor".to_string();
+        records[0].hash = records[0].compute_hash().unwrap();
+        let resealed: Vec<String> = records
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap())
+            .collect();
+        fs::write(log.path(), resealed.join("\n")).unwrap();
+        assert!(log.verify().unwrap_err().to_string().contains("Entry 2"));
+    }
+
+    #[test]
+    fn test_audit_log_chains_events() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+        let log = AuditLog::with_path(temp_dir.path().join("connecto").join(AUDIT_FILE));
+
+        log.append(AuditEvent::key_generated("connecto_desk", &key.public_key))
+            .unwrap();
+        let rejected = DecisionRecord::new(Decision::Rejected, "Laptop", "10.0.0.2", "garbage")
+            .with_reason("Rejected by user");
+        log.append(AuditEvent::from_decision(&rejected)).unwrap();
+        log.append(AuditEvent::sync_completed(
+            "Desk",
+            "10.0.0.1".parse().unwrap(),
+            2,
+        ))
+        .unwrap();
+
+        let records = log.all().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].prev_hash, GENESIS_HASH);
+        assert_eq!(records[2].prev_hash, records[1].hash);
+        assert_eq!(
+            records[1].event.to_string(),
+            "Laptop at 10.0.0.2, key (invalid key): Rejected by user"
+        );
+        assert_eq!(
+            records[2].event.to_string(),
+            "Desk at 10.0.0.1, 2 key(s) added"
+        );
+        assert_eq!(log.verify().unwrap(), 3);
+
+        // Events are tagged by name next to the chain fields
+        let lines = fs::read_to_string(log.path()).unwrap();
+        let first: serde_json::Value = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!(first["event"], "key_generated");
+        assert_eq!(first["name"], "connecto_desk");
+        assert_eq!(first["seq"], 1);
+
+        fs::write(
+            log.path(),
+            lines.replacen("\"keys_added\":2", "\"keys_added\":0", 1),
+        )
+        .unwrap();
+        assert_eq!(
+            log.verify().unwrap_err().to_string(),
+            "Audit log error: Entry 3 was modified"
+        );
+    }
+
+    #[test]
+    fn test_empty_log_verifies() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = DecisionLog::with_path(temp_dir.path().join(DECISIONS_FILE));
+        assert!(log.all().unwrap().is_empty());
+        assert_eq!(log.verify().unwrap(), 0);
+    }
+}
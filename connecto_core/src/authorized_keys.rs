@@ -0,0 +1,433 @@
+//! Authorized keys module
+//!
+//! Reads `authorized_keys` into structured entries and writes it back.
+//! Entries are matched by their decoded key data, so the same key with
+//! another comment, other options or different whitespace is still
+//! recognised as a duplicate. Lines that are not keys are kept as they are.
+
+use crate::backups::{self, Backup};
+use crate::clock;
+use crate::error::{ConnectoError, Result};
+use crate::keys::{
+    authorized_key_expiry, split_authorized_key, KeyOptions, EXPIRY_MARKER, KEY_TYPE_PREFIXES,
+};
+use serde::{Deserialize, Serialize};
+use ssh_key::public::KeyData;
+use ssh_key::PublicKey;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// One key in authorized_keys
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorizedKey {
+    /// Options before the key as written, empty if it has none, e.g.
+    /// `from="10.0.0.5",no-pty`
+    pub options: String,
+    /// Key type, e.g. `ssh-ed25519`
+    pub key_type: String,
+    /// Base64 key data
+    pub blob: String,
+    /// Text after the key, empty if there is none
+    pub comment: String,
+}
+
+impl AuthorizedKey {
+    /// The options, parsed; fails for options Connecto does not write
+    pub fn key_options(&self) -> Result<KeyOptions> {
+        self.options.parse()
+    }
+
+    /// Replace the options
+    pub fn with_options(mut self, options: &KeyOptions) -> Self {
+        self.options = options.to_string();
+        self
+    }
+
+    /// When the key expires (Unix seconds), if it was given a lifetime
+    pub fn expires_at(&self) -> Option<u64> {
+        authorized_key_expiry(&self.comment)
+    }
+
+    /// Expire at `expires_at` (Unix seconds), or never with `None`
+    pub fn with_expiry(mut self, expires_at: Option<u64>) -> Self {
+        let mut comment: Vec<String> = self
+            .comment
+            .split_whitespace()
+            .filter(|t| !t.starts_with(EXPIRY_MARKER))
+            .map(String::from)
+            .collect();
+        if let Some(expires_at) = expires_at {
+            comment.push(format!(
+                "{}{}",
+                EXPIRY_MARKER,
+                clock::format_utc(expires_at, clock::UTC_FILE_FORMAT)
+            ));
+        }
+        self.comment = comment.join(" ");
+        self
+    }
+
+    /// Whether `other` holds the same key, whatever its options and comment
+    pub fn same_key(&self, other: &AuthorizedKey) -> bool {
+        match (self.key_data(), other.key_data()) {
+            (Some(a), Some(b)) => a == b,
+            // Key types ssh-key cannot decode are compared as written
+            _ => self.key_type == other.key_type && self.blob == other.blob,
+        }
+    }
+
+    fn key_data(&self) -> Option<KeyData> {
+        PublicKey::from_openssh(&format!("{} {}", self.key_type, self.blob))
+            .ok()
+            .map(|key| key.key_data().clone())
+    }
+}
+
+impl fmt::Display for AuthorizedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.options.is_empty() {
+            write!(f, "{} ", self.options)?;
+        }
+        write!(f, "{} {}", self.key_type, self.blob)?;
+        if !self.comment.is_empty() {
+            write!(f, " {}", self.comment)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AuthorizedKey {
+    type Err = ConnectoError;
+
+    /// Parse a line of authorized_keys, or a public key in OpenSSH format
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid =
+            || ConnectoError::KeyParsing(format!("Invalid authorized key '{}'", s.trim()));
+        let (options, key) = split_authorized_key(s.trim());
+        let mut parts = key.split_whitespace();
+        let (Some(key_type), Some(blob)) = (parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        if !KEY_TYPE_PREFIXES.iter().any(|p| key_type.starts_with(p)) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            options: options.to_string(),
+            key_type: key_type.to_string(),
+            blob: blob.to_string(),
+            comment: parts.collect::<Vec<_>>().join(" "),
+        })
+    }
+}
+
+/// What [`AuthorizedKeysFile::merge`] did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Merge {
+    /// The key was new
+    Added,
+    /// The key was there with other options or another comment, or more
+    /// than once
+    Replaced,
+    /// The key was there exactly as given
+    Unchanged,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    /// A key, with the text it was read from while unchanged
+    Key(AuthorizedKey, Option<String>),
+    /// A comment, blank line, or anything else that is not a key
+    Other(String),
+}
+
+/// The contents of an authorized_keys file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthorizedKeysFile {
+    lines: Vec<Line>,
+}
+
+impl AuthorizedKeysFile {
+    /// Parse the contents of an authorized_keys file
+    pub fn parse(content: &str) -> Self {
+        let lines = content
+            .lines()
+            .map(|line| {
+                if is_comment_or_blank(line) {
+                    return Line::Other(line.to_string());
+                }
+                match line.trim().parse() {
+                    Ok(key) => Line::Key(key, Some(line.to_string())),
+                    Err(_) => Line::Other(line.to_string()),
+                }
+            })
+            .collect();
+        Self { lines }
+    }
+
+    /// Read the file at `path`; a missing file has no keys
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write to `path`, only readable by its owner on Unix
+    ///
+    /// The file is replaced atomically after a backup of the old one, which
+    /// is returned; see [`backups`].
+    pub fn save(&self, path: &Path) -> Result<Option<Backup>> {
+        let backup = backups::replace(path, &self.to_string())?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(backup)
+    }
+
+    /// The keys, in file order
+    pub fn keys(&self) -> impl Iterator<Item = &AuthorizedKey> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Key(key, _) => Some(key),
+            Line::Other(_) => None,
+        })
+    }
+
+    /// The keys with their line numbers, counting from 1
+    pub fn numbered_keys(&self) -> impl Iterator<Item = (usize, &AuthorizedKey)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| match line {
+                Line::Key(key, _) => Some((i + 1, key)),
+                Line::Other(_) => None,
+            })
+    }
+
+    /// Line numbers, counting from 1, of lines that are neither keys,
+    /// comments nor blank; sshd skips them
+    pub fn invalid_lines(&self) -> impl Iterator<Item = usize> + '_ {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| match line {
+                Line::Other(text) if !is_comment_or_blank(text) => Some(i + 1),
+                _ => None,
+            })
+    }
+
+    /// The entry holding the same key as `key`
+    pub fn find(&self, key: &AuthorizedKey) -> Option<&AuthorizedKey> {
+        self.keys().find(|k| k.same_key(key))
+    }
+
+    /// Add `key`, or put it in place of the entries holding the same key
+    pub fn merge(&mut self, key: AuthorizedKey) -> Merge {
+        let mut matches = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| matches!(line, Line::Key(k, _) if k.same_key(&key)))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>()
+            .into_iter();
+        let Some(first) = matches.next() else {
+            self.lines.push(Line::Key(key, None));
+            return Merge::Added;
+        };
+
+        let duplicates: Vec<usize> = matches.collect();
+        let unchanged = matches!(&self.lines[first], Line::Key(k, _) if *k == key);
+        if unchanged && duplicates.is_empty() {
+            return Merge::Unchanged;
+        }
+        if !unchanged {
+            self.lines[first] = Line::Key(key, None);
+        }
+        for i in duplicates.into_iter().rev() {
+            self.lines.remove(i);
+        }
+        Merge::Replaced
+    }
+
+    /// Remove every entry holding the same key as `key`, returning whether
+    /// there was one
+    pub fn remove(&mut self, key: &AuthorizedKey) -> bool {
+        !self.retain(|k| !k.same_key(key)).is_empty()
+    }
+
+    /// Keep only the keys `keep` accepts, returning the others
+    pub fn retain(&mut self, mut keep: impl FnMut(&AuthorizedKey) -> bool) -> Vec<AuthorizedKey> {
+        let mut removed = Vec::new();
+        self.lines.retain(|line| match line {
+            Line::Key(key, _) if !keep(key) => {
+                removed.push(key.clone());
+                false
+            }
+            _ => true,
+        });
+        removed
+    }
+}
+
+impl fmt::Display for AuthorizedKeysFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Key(_, Some(text)) | Line::Other(text) => writeln!(f, "{}", text)?,
+                Line::Key(key, None) => writeln!(f, "{}", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_comment_or_blank(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.is_empty() || trimmed.starts_with('#')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+    fn key(line: &str) -> AuthorizedKey {
+        line.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_authorized_key() {
+        let parsed = key(r#"from="10.0.0.5",command="echo a b" ssh-ed25519 AAAAC3 alice@laptop x"#);
+        assert_eq!(parsed.options, r#"from="10.0.0.5",command="echo a b""#);
+        assert_eq!(parsed.key_type, "ssh-ed25519");
+        assert_eq!(parsed.blob, "AAAAC3");
+        assert_eq!(parsed.comment, "alice@laptop x");
+        assert_eq!(
+            parsed.key_options().unwrap().command.as_deref(),
+            Some("echo a b")
+        );
+        assert_eq!(
+            parsed.to_string(),
+            r#"from="10.0.0.5",command="echo a b" ssh-ed25519 AAAAC3 alice@laptop x"#
+        );
+
+        // Tabs, trailing carriage returns and a missing comment
+        let parsed = key("ssh-ed25519\tAAAAC3\r");
+        assert_eq!(
+            (parsed.blob.as_str(), parsed.comment.as_str()),
+            ("AAAAC3", "")
+        );
+
+        assert!("ssh-ed25519".parse::<AuthorizedKey>().is_err());
+        assert!("".parse::<AuthorizedKey>().is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let parsed = key("ssh-ed25519 AAAAC3 bob").with_expiry(Some(1_000));
+        assert_eq!(parsed.comment, "bob connecto-expires=1970-01-01T00:16:40Z");
+        assert_eq!(parsed.expires_at(), Some(1_000));
+
+        let renewed = parsed.with_expiry(Some(2_000));
+        assert_eq!(renewed.expires_at(), Some(2_000));
+        assert_eq!(renewed.with_expiry(None).comment, "bob");
+    }
+
+    #[test]
+    fn test_same_key_compares_decoded_data() {
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@host").unwrap();
+        let other = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@host").unwrap();
+        let plain = key(&key_pair.public_key);
+
+        let relabelled = key(&format!("no-pty {} {}", plain.key_type, plain.blob));
+        assert!(plain.same_key(&relabelled));
+        assert!(!plain.same_key(&key(&other.public_key)));
+
+        // A comment quoting another key's data is not that key
+        let quoting = key(&format!("{} {}", other.public_key, plain.blob));
+        assert!(!plain.same_key(&quoting));
+    }
+
+    #[test]
+    fn test_merge() {
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@host").unwrap();
+        let other = SshKeyPair::generate(KeyAlgorithm::Ed25519, "b@host").unwrap();
+        let content = format!(
+            "# managed by hand\n{}\n\n{}\nnot a key\n",
+            other.public_key, key_pair.public_key
+        );
+        let mut file = AuthorizedKeysFile::parse(&content);
+        assert_eq!(file.keys().count(), 2);
+
+        assert_eq!(file.merge(key(&key_pair.public_key)), Merge::Unchanged);
+        assert_eq!(file.to_string(), content);
+
+        let restricted = key(&format!("no-pty {}", key_pair.public_key));
+        assert_eq!(file.merge(restricted.clone()), Merge::Replaced);
+        assert_eq!(file.keys().count(), 2);
+        assert_eq!(file.find(&key(&key_pair.public_key)), Some(&restricted));
+        // Other lines are left alone
+        assert!(file.to_string().starts_with("# managed by hand\n"));
+        assert!(file.to_string().ends_with("not a key\n"));
+
+        let third = SshKeyPair::generate(KeyAlgorithm::Ed25519, "c@host").unwrap();
+        assert_eq!(file.merge(key(&third.public_key)), Merge::Added);
+        assert_eq!(file.keys().count(), 3);
+    }
+
+    #[test]
+    fn test_merge_removes_duplicates() {
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@host").unwrap();
+        let plain = key(&key_pair.public_key);
+        let mut file = AuthorizedKeysFile::parse(&format!(
+            "{}\n{} {} other comment\n",
+            key_pair.public_key, plain.key_type, plain.blob
+        ));
+
+        assert_eq!(file.merge(plain.clone()), Merge::Replaced);
+        assert_eq!(file.keys().collect::<Vec<_>>(), [&plain]);
+
+        assert!(file.remove(&plain));
+        assert!(!file.remove(&plain));
+        assert_eq!(file.to_string(), "");
+    }
+
+    #[test]
+    fn test_invalid_lines() {
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@host").unwrap();
+        let file = AuthorizedKeysFile::parse(&format!(
+            "# comment\n\n{}\nssh-ed25519\nnot a key\n",
+            key_pair.public_key
+        ));
+        assert_eq!(file.invalid_lines().collect::<Vec<_>>(), vec![4, 5]);
+    }
+
+    #[test]
+    fn test_load_and_save() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("authorized_keys");
+        assert_eq!(AuthorizedKeysFile::load(&path).unwrap().keys().count(), 0);
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@host").unwrap();
+        let mut file = AuthorizedKeysFile::default();
+        file.merge(key(&key_pair.public_key));
+        file.save(&path).unwrap();
+
+        assert_eq!(
+            AuthorizedKeysFile::load(&path).unwrap(),
+            AuthorizedKeysFile::parse(&format!("{}\n", key_pair.public_key))
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
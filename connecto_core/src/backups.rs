@@ -0,0 +1,646 @@
+//! Safe rewrites of the SSH files Connecto edits
+//!
+//! `~/.ssh/config` and `authorized_keys` are written through [`replace`]:
+//! the old contents are copied to a timestamped backup, then the new
+//! contents go to a temporary file in the same directory that is renamed
+//! over the original, so a crash leaves either the old file or the new one,
+//! never a truncated one.
+//!
+//! Backups sit next to the file, in [`BACKUP_DIR`], named after it and the
+//! time in milliseconds since the Unix epoch, e.g. `config.1760616000123`.
+//! The newest [`BACKUPS_KEPT`] of each file are kept.
+//!
+//! Reading a file, changing it and writing it back happens under [`lock`],
+//! so two pairings finishing at once cannot both start from the old
+//! contents and drop each other's change.
+//!
+//! Another account's `~/.ssh`, written as root, goes through [`OwnedDir`]
+//! instead: the account can put anything in it, so nothing there is
+//! followed through a symlink or trusted unless the account owns it.
+
+#[cfg(unix)]
+use crate::error::ConnectoError;
+use crate::error::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory next to a file that holds its backups
+pub const BACKUP_DIR: &str = "connecto-backups";
+
+/// Backups kept of each file, the oldest go first
+pub const BACKUPS_KEPT: usize = 20;
+
+/// A copy of a file taken before Connecto changed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    /// The file that was backed up
+    pub original: PathBuf,
+    /// The copy
+    pub path: PathBuf,
+    /// When the copy was taken, in milliseconds since the Unix epoch
+    pub taken_at_ms: u64,
+}
+
+impl Backup {
+    /// Put the copy back in place of the original, then delete it
+    ///
+    /// The backup is used up, so restoring again goes one change further
+    /// back.
+    pub fn restore(&self) -> Result<()> {
+        let contents = fs::read(&self.path)?;
+        write_atomic(&self.original, &contents)?;
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// An exclusive lock on a file Connecto rewrites, released when dropped
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+/// Lock `path` against other Connecto processes and threads rewriting it
+///
+/// Blocks until the lock is free. The lock is advisory, `flock` on Unix and
+/// `LockFileEx` on Windows, and is taken on [`lock_path`] rather than the
+/// file itself, which [`replace`] swaps for a new one. The directory of
+/// `path` must exist.
+pub fn lock(path: &Path) -> Result<FileLock> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(path))?;
+    file.lock()?;
+    Ok(FileLock { _file: file })
+}
+
+/// The file [`lock`] locks for `path`, e.g. `.config.lock` next to `config`
+pub fn lock_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(".{}.lock", file_name(path)))
+}
+
+/// Back up `path`, then replace its contents with `contents`
+///
+/// Nothing happens if the file already holds `contents`. Returns the backup
+/// taken, if the file existed.
+pub fn replace(path: &Path, contents: &str) -> Result<Option<Backup>> {
+    if fs::read(path).is_ok_and(|old| old == contents.as_bytes()) {
+        return Ok(None);
+    }
+    let backup = back_up(path)?;
+    write_atomic(path, contents.as_bytes())?;
+    Ok(backup)
+}
+
+/// Write `contents` to `path` through a temporary file that is renamed over it
+///
+/// The file keeps its permissions; a new file is only readable by its owner
+/// on Unix. A symlink at `path` is never followed: the link itself is
+/// replaced by the new file, so a link planted in `~/.ssh` cannot send the
+/// write anywhere else.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    // Unique, so concurrent writers never share a temporary file
+    let temp = dir.join(format!(
+        ".{}.connecto-{}-{:08x}.tmp",
+        file_name(path),
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+
+    let result = (|| {
+        // A new name that nothing, not even a link, may already have
+        let mut file = create_new(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                fs::set_permissions(&temp, metadata.permissions())?
+            }
+            _ => owner_only(&temp)?,
+        }
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    Ok(())
+}
+
+/// Create `path`, failing if anything is there, only readable by its owner
+/// on Unix
+fn create_new(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Copy `path` into its backup directory
+///
+/// Returns `None` if there is no file to back up. Older backups beyond
+/// [`BACKUPS_KEPT`] are deleted.
+pub fn back_up(path: &Path) -> Result<Option<Backup>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let dir = backup_dir(path);
+    if !dir.exists() {
+        fs::create_dir_all(&dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        }
+    }
+
+    let name = file_name(path);
+    let mut taken_at_ms = now_ms();
+    let mut backup_path = dir.join(format!("{}.{}", name, taken_at_ms));
+    // Two changes within a millisecond still get a backup each
+    while backup_path.exists() {
+        taken_at_ms += 1;
+        backup_path = dir.join(format!("{}.{}", name, taken_at_ms));
+    }
+    fs::copy(path, &backup_path)?;
+    owner_only(&backup_path)?;
+
+    for old in backups_of(path)?.into_iter().skip(BACKUPS_KEPT) {
+        let _ = fs::remove_file(&old.path);
+    }
+    Ok(Some(Backup {
+        original: path.to_path_buf(),
+        path: backup_path,
+        taken_at_ms,
+    }))
+}
+
+/// The backups of `path`, newest first
+pub fn backups_of(path: &Path) -> Result<Vec<Backup>> {
+    let dir = backup_dir(path);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let prefix = format!("{}.", file_name(path));
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(Ok(taken_at_ms)) = name.strip_prefix(&prefix).map(str::parse::<u64>) else {
+            continue;
+        };
+        backups.push(Backup {
+            original: path.to_path_buf(),
+            path: entry.path(),
+            taken_at_ms,
+        });
+    }
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.taken_at_ms));
+    Ok(backups)
+}
+
+/// The newest backup of any of `paths`, i.e. the last change Connecto made
+/// to them
+pub fn latest<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Result<Option<Backup>> {
+    let mut newest: Option<Backup> = None;
+    for path in paths {
+        if let Some(backup) = backups_of(path)?.into_iter().next() {
+            if newest
+                .as_ref()
+                .is_none_or(|n| backup.taken_at_ms > n.taken_at_ms)
+            {
+                newest = Some(backup);
+            }
+        }
+    }
+    Ok(newest)
+}
+
+/// The directory holding the backups of `path`
+pub fn backup_dir(path: &Path) -> PathBuf {
+    path.parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(BACKUP_DIR)
+}
+
+pub(crate) fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn owner_only(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Another account's directory, such as its `~/.ssh`, written with the
+/// privileges the account does not have
+///
+/// Paths are never resolved again once the directory is open: every file is
+/// opened relative to it without following symlinks, has to be a regular
+/// file the account owns, and what is created is handed to the account on
+/// the open file. A link the account planted to `/etc/shadow` is refused
+/// rather than read or written.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct OwnedDir {
+    path: PathBuf,
+    fd: std::os::fd::OwnedFd,
+    uid: u32,
+    gid: u32,
+}
+
+#[cfg(unix)]
+impl OwnedDir {
+    /// Open the directory at `path`, which `uid` has to own, if it exists
+    pub fn open(path: &Path, uid: u32, gid: u32) -> Result<Option<Self>> {
+        use rustix::fs::{Mode, OFlags};
+
+        let parent = rustix::fs::open(
+            path.parent().unwrap_or_else(|| Path::new(".")),
+            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        Self::open_at(&parent, path, uid, gid)
+    }
+
+    /// Open the directory at `path` like [`open`](Self::open), first
+    /// creating it for `uid` and `gid`, only accessible to them
+    pub fn create(path: &Path, uid: u32, gid: u32) -> Result<Self> {
+        use rustix::fs::{Mode, OFlags};
+
+        let parent = rustix::fs::open(
+            path.parent().unwrap_or_else(|| Path::new(".")),
+            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        Self::create_at(&parent, path, uid, gid)
+    }
+
+    fn create_at(parent: &std::os::fd::OwnedFd, path: &Path, uid: u32, gid: u32) -> Result<Self> {
+        use rustix::fs::{Gid, Mode, Uid};
+
+        match rustix::fs::mkdirat(parent, file_name(path), Mode::from_raw_mode(0o700)) {
+            Ok(()) => {
+                let dir =
+                    rustix::fs::openat(parent, file_name(path), Self::DIR_FLAGS, Mode::empty())
+                        .map_err(|e| Self::refuse(path, e))?;
+                rustix::fs::fchown(&dir, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))?;
+            }
+            Err(rustix::io::Errno::EXIST) => {}
+            Err(e) => return Err(e.into()),
+        }
+        Self::open_at(parent, path, uid, gid)?.ok_or_else(|| {
+            ConnectoError::PermissionDenied(format!("{} disappeared", path.display()))
+        })
+    }
+
+    fn open_at(
+        parent: &std::os::fd::OwnedFd,
+        path: &Path,
+        uid: u32,
+        gid: u32,
+    ) -> Result<Option<Self>> {
+        use rustix::fs::{FileType, Mode};
+
+        let fd = match rustix::fs::openat(parent, file_name(path), Self::DIR_FLAGS, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(rustix::io::Errno::NOENT) => return Ok(None),
+            Err(e) => return Err(Self::refuse(path, e)),
+        };
+        let stat = rustix::fs::fstat(&fd)?;
+        if FileType::from_raw_mode(stat.st_mode) != FileType::Directory || stat.st_uid != uid {
+            return Err(ConnectoError::PermissionDenied(format!(
+                "{} is not a directory of its account",
+                path.display()
+            )));
+        }
+        Ok(Some(Self {
+            path: path.to_path_buf(),
+            fd,
+            uid,
+            gid,
+        }))
+    }
+
+    const DIR_FLAGS: rustix::fs::OFlags = rustix::fs::OFlags::RDONLY
+        .union(rustix::fs::OFlags::DIRECTORY)
+        .union(rustix::fs::OFlags::NOFOLLOW)
+        .union(rustix::fs::OFlags::CLOEXEC);
+
+    /// The path of `name` in the directory
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// The contents of `name`, if it exists
+    pub fn read(&self, name: &str) -> Result<Option<String>> {
+        use std::io::Read;
+
+        let Some(mut file) = self.open_file(name)? else {
+            return Ok(None);
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(Some(contents))
+    }
+
+    /// Lock `name` like [`lock`] does, on a lock file the account owns
+    pub fn lock(&self, name: &str) -> Result<FileLock> {
+        let lock_name = format!(".{}.lock", name);
+        let file = match self.create_file(&lock_name, 0o600) {
+            Ok(file) => file,
+            Err(ConnectoError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                self.open_file(&lock_name)?.ok_or_else(|| {
+                    ConnectoError::PermissionDenied(format!(
+                        "{} disappeared",
+                        self.path(&lock_name).display()
+                    ))
+                })?
+            }
+            Err(e) => return Err(e),
+        };
+        file.lock()?;
+        Ok(FileLock { _file: file })
+    }
+
+    /// Back up `name`, then replace its contents with `contents`, readable
+    /// as `mode` allows
+    ///
+    /// Like [`replace`], nothing happens if the file already holds
+    /// `contents`, and the backup taken is returned.
+    pub fn replace(&self, name: &str, contents: &str, mode: u32) -> Result<Option<Backup>> {
+        let old = self.read(name)?;
+        if old.as_deref() == Some(contents) {
+            return Ok(None);
+        }
+        let backup = match old {
+            Some(old) => Some(self.back_up(name, old.as_bytes())?),
+            None => None,
+        };
+
+        let temp = format!(
+            ".{}.connecto-{}-{:08x}.tmp",
+            name,
+            std::process::id(),
+            rand::random::<u32>()
+        );
+        let result = (|| {
+            let mut file = self.create_file(&temp, mode)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            rustix::fs::renameat(&self.fd, temp.as_str(), &self.fd, name)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = rustix::fs::unlinkat(&self.fd, temp.as_str(), rustix::fs::AtFlags::empty());
+        }
+        result.map(|()| backup)
+    }
+
+    /// Copy `contents` of `name` into its backup directory, dropping the
+    /// oldest backups beyond [`BACKUPS_KEPT`]
+    fn back_up(&self, name: &str, contents: &[u8]) -> Result<Backup> {
+        let dir = Self::create_at(&self.fd, &self.path(BACKUP_DIR), self.uid, self.gid)?;
+        let mut taken_at_ms = now_ms();
+        // Two changes within a millisecond still get a backup each
+        let mut file = loop {
+            match dir.create_file(&format!("{}.{}", name, taken_at_ms), 0o600) {
+                Err(ConnectoError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    taken_at_ms += 1
+                }
+                result => break result?,
+            }
+        };
+        file.write_all(contents)?;
+
+        let prefix = format!("{}.", name);
+        let mut kept: Vec<(u64, std::ffi::CString)> = Vec::new();
+        for entry in rustix::fs::Dir::read_from(&dir.fd)? {
+            let entry = entry?;
+            let Some(Ok(taken_at_ms)) = entry
+                .file_name()
+                .to_str()
+                .ok()
+                .and_then(|n| n.strip_prefix(&prefix))
+                .map(str::parse::<u64>)
+            else {
+                continue;
+            };
+            kept.push((taken_at_ms, entry.file_name().to_owned()));
+        }
+        kept.sort_by_key(|(taken_at_ms, _)| std::cmp::Reverse(*taken_at_ms));
+        for (_, old) in kept.into_iter().skip(BACKUPS_KEPT) {
+            let _ = rustix::fs::unlinkat(&dir.fd, old.as_c_str(), rustix::fs::AtFlags::empty());
+        }
+
+        Ok(Backup {
+            original: self.path(name),
+            path: dir.path(&format!("{}.{}", name, taken_at_ms)),
+            taken_at_ms,
+        })
+    }
+
+    /// Open `name` if it exists and is a regular file of the account's
+    fn open_file(&self, name: &str) -> Result<Option<File>> {
+        use rustix::fs::{FileType, Mode, OFlags};
+
+        // Non-blocking, so a FIFO put in place of the file cannot hang us
+        let flags = OFlags::RDWR | OFlags::NOFOLLOW | OFlags::NONBLOCK | OFlags::CLOEXEC;
+        let fd = match rustix::fs::openat(&self.fd, name, flags, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(rustix::io::Errno::NOENT) => return Ok(None),
+            Err(e) => return Err(Self::refuse(&self.path(name), e)),
+        };
+        let stat = rustix::fs::fstat(&fd)?;
+        if FileType::from_raw_mode(stat.st_mode) != FileType::RegularFile
+            || stat.st_nlink != 1
+            || stat.st_uid != self.uid
+        {
+            return Err(ConnectoError::PermissionDenied(format!(
+                "{} is not a file of its account",
+                self.path(name).display()
+            )));
+        }
+        rustix::fs::fcntl_setfl(&fd, OFlags::empty())?;
+        Ok(Some(File::from(fd)))
+    }
+
+    /// Create `name`, failing if anything is there, and hand it to the
+    /// account
+    fn create_file(&self, name: &str, mode: u32) -> Result<File> {
+        use rustix::fs::{Gid, Mode, OFlags, Uid};
+
+        let flags =
+            OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+        let fd = rustix::fs::openat(&self.fd, name, flags, Mode::from_raw_mode(0o600))?;
+        rustix::fs::fchown(
+            &fd,
+            Some(Uid::from_raw(self.uid)),
+            Some(Gid::from_raw(self.gid)),
+        )?;
+        rustix::fs::fchmod(&fd, Mode::from_raw_mode(mode))?;
+        Ok(File::from(fd))
+    }
+
+    /// The error for failing to open `path`, naming a symlink as the reason
+    /// where one was refused
+    fn refuse(path: &Path, e: rustix::io::Errno) -> ConnectoError {
+        // Linux reports a refused link as ELOOP, FreeBSD as EMLINK
+        if e == rustix::io::Errno::LOOP || e == rustix::io::Errno::MLINK {
+            ConnectoError::PermissionDenied(format!(
+                "{} is a symbolic link, which is not followed",
+                path.display()
+            ))
+        } else {
+            e.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_backs_up_and_restores() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+
+        // A new file has nothing to back up
+        assert!(replace(&path, "Host desk\n").unwrap().is_none());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Host desk\n");
+
+        let first = replace(&path, "Host desk\nHost nas\n").unwrap().unwrap();
+        assert_eq!(first.original, path);
+        assert_eq!(fs::read_to_string(&first.path).unwrap(), "Host desk\n");
+
+        // Unchanged contents are not written or backed up
+        assert!(replace(&path, "Host desk\nHost nas\n").unwrap().is_none());
+
+        let second = replace(&path, "").unwrap().unwrap();
+        assert!(second.taken_at_ms > first.taken_at_ms);
+        assert_eq!(backups_of(&path).unwrap(), vec![second.clone(), first]);
+
+        // Each restore goes one change further back
+        latest([path.as_path()])
+            .unwrap()
+            .unwrap()
+            .restore()
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Host desk\nHost nas\n");
+        latest([path.as_path()])
+            .unwrap()
+            .unwrap()
+            .restore()
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Host desk\n");
+        assert!(latest([path.as_path()]).unwrap().is_none());
+
+        // No temporary files are left behind
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+    }
+
+    #[test]
+    fn test_latest_across_files_and_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config");
+        let keys = dir.path().join("authorized_keys");
+        fs::write(&config, "a").unwrap();
+        fs::write(&keys, "b").unwrap();
+
+        back_up(&config).unwrap();
+        // Backups of different files can share a millisecond
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let newest = back_up(&keys).unwrap().unwrap();
+        let paths = [config.as_path(), keys.as_path()];
+        assert_eq!(latest(paths).unwrap(), Some(newest));
+
+        for _ in 0..BACKUPS_KEPT + 3 {
+            back_up(&config).unwrap();
+        }
+        assert_eq!(backups_of(&config).unwrap().len(), BACKUPS_KEPT);
+        assert_eq!(backups_of(&keys).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_lock_serializes_rewrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+        assert_eq!(lock_path(&path), dir.path().join(".authorized_keys.lock"));
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let _lock = lock(&path).unwrap();
+                    let mut content = fs::read_to_string(&path).unwrap_or_default();
+                    std::thread::sleep(std::time::Duration::from_millis(2));
+                    content.push_str(&format!("key{}\n", i));
+                    replace(&path, &content).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // No thread started from contents another one was about to change
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 8);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_keeps_permissions_and_replaces_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        write_atomic(&path, b"one").unwrap();
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_atomic(&path, b"two").unwrap();
+        assert_eq!(mode(&path), 0o644);
+
+        // The link is replaced; what it pointed at is left alone
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        write_atomic(&link, b"three").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().is_file());
+        assert_eq!(mode(&link), 0o600);
+        assert_eq!(fs::read_to_string(&link).unwrap(), "three");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two");
+    }
+}
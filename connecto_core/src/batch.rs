@@ -0,0 +1,316 @@
+//! Batch pairing module
+//!
+//! Runs pairings with several devices at once. Each pairing is its own
+//! handshake; progress is reported per device so a UI can show a status for
+//! every one of them, and results come back in the order the devices were
+//! given.
+
+use crate::error::Result;
+use crate::keys::SshKeyPair;
+use crate::protocol::{HandshakeClient, PairingResult};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use tokio::sync::mpsc;
+
+/// Default number of pairings run at the same time
+pub const DEFAULT_MAX_CONCURRENT: usize = 4;
+
+/// Where a single pairing in a batch stands
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum PairingStatus {
+    /// Waiting for a free slot
+    Queued,
+    /// The handshake is running
+    Pairing,
+    /// The pairing succeeded
+    Paired,
+    /// The pairing failed
+    Failed { error: String },
+}
+
+/// A status change for one device in a batch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchProgress {
+    /// Position of the device in the batch
+    pub target: usize,
+    /// Address being paired with
+    pub address: String,
+    #[serde(flatten)]
+    pub status: PairingStatus,
+}
+
+/// The outcome of pairing with one device in a batch
+#[derive(Debug)]
+pub struct BatchResult {
+    /// Address that was paired with
+    pub address: String,
+    pub result: Result<PairingResult>,
+}
+
+/// Pairs with several devices concurrently
+#[derive(Debug, Clone)]
+pub struct BatchPairing {
+    max_concurrent: usize,
+    progress: Option<mpsc::Sender<BatchProgress>>,
+}
+
+impl BatchPairing {
+    pub fn new() -> Self {
+        Self {
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            progress: None,
+        }
+    }
+
+    /// Limit how many pairings run at the same time (at least one)
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = max_concurrent.max(1);
+        self
+    }
+
+    /// Report every status change on `progress`
+    pub fn with_progress(mut self, progress: mpsc::Sender<BatchProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Pair with every address using `pair`, returning one result per address
+    /// in the order given
+    ///
+    /// A failed pairing does not stop the others.
+    pub async fn run<T, E, F, Fut>(
+        &self,
+        addresses: Vec<String>,
+        pair: F,
+    ) -> Vec<std::result::Result<T, E>>
+    where
+        E: std::fmt::Display,
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = std::result::Result<T, E>>,
+    {
+        for (target, address) in addresses.iter().enumerate() {
+            self.report(target, address, PairingStatus::Queued).await;
+        }
+
+        let pair = &pair;
+        stream::iter(addresses.into_iter().enumerate())
+            .map(|(target, address)| async move {
+                self.report(target, &address, PairingStatus::Pairing).await;
+                let result = pair(address.clone()).await;
+                let status = match &result {
+                    Ok(_) => PairingStatus::Paired,
+                    Err(e) => PairingStatus::Failed {
+                        error: e.to_string(),
+                    },
+                };
+                self.report(target, &address, status).await;
+                result
+            })
+            .buffered(self.max_concurrent)
+            .collect()
+            .await
+    }
+
+    /// Send `key_pair` to every address with `client`
+    ///
+    /// Every device receives the same key, so it only needs to be generated
+    /// once.
+    pub async fn pair(
+        &self,
+        client: &HandshakeClient,
+        addresses: Vec<String>,
+        key_pair: &SshKeyPair,
+    ) -> Vec<BatchResult> {
+        let results = self
+            .run(addresses.clone(), |address| async move {
+                client.pair(&address, key_pair).await
+            })
+            .await;
+        addresses
+            .into_iter()
+            .zip(results)
+            .map(|(address, result)| BatchResult { address, result })
+            .collect()
+    }
+
+    async fn report(&self, target: usize, address: &str, status: PairingStatus) {
+        if let Some(progress) = &self.progress {
+            let _ = progress
+                .send(BatchProgress {
+                    target,
+                    address: address.to_string(),
+                    status,
+                })
+                .await;
+        }
+    }
+}
+
+impl Default for BatchPairing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{KeyAlgorithm, KeyManager};
+    use crate::protocol::HandshakeServer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn addresses(count: usize) -> Vec<String> {
+        (1..=count).map(|i| format!("10.0.0.{}:8099", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_results_keep_order() {
+        let results = BatchPairing::new()
+            .run(addresses(3), |address| async move {
+                // Finish in reverse order
+                let last: u64 = address[7..8].parse().unwrap();
+                tokio::time::sleep(Duration::from_millis(30 / last)).await;
+                if address.starts_with("10.0.0.2") {
+                    Err(format!("{} refused", address))
+                } else {
+                    Ok(address)
+                }
+            })
+            .await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_deref(), Ok("10.0.0.1:8099"));
+        assert_eq!(results[1], Err("10.0.0.2:8099 refused".to_string()));
+        assert_eq!(results[2].as_deref(), Ok("10.0.0.3:8099"));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+
+        let results = BatchPairing::new()
+            .with_max_concurrent(2)
+            .run(addresses(6), |_| async {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, String>(())
+            })
+            .await;
+
+        assert_eq!(results.len(), 6);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_progress_events() {
+        let (tx, mut rx) = mpsc::channel(16);
+        BatchPairing::new()
+            .with_progress(tx)
+            .run(addresses(2), |address| async move {
+                if address.starts_with("10.0.0.1") {
+                    Ok(())
+                } else {
+                    Err("timed out")
+                }
+            })
+            .await;
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+
+        let statuses = |target: usize| -> Vec<PairingStatus> {
+            events
+                .iter()
+                .filter(|e| e.target == target)
+                .map(|e| e.status.clone())
+                .collect()
+        };
+        assert_eq!(
+            statuses(0),
+            [
+                PairingStatus::Queued,
+                PairingStatus::Pairing,
+                PairingStatus::Paired
+            ]
+        );
+        assert_eq!(
+            statuses(1),
+            [
+                PairingStatus::Queued,
+                PairingStatus::Pairing,
+                PairingStatus::Failed {
+                    error: "timed out".to_string()
+                }
+            ]
+        );
+        assert_eq!(events[1].address, "10.0.0.2:8099");
+    }
+
+    #[tokio::test]
+    async fn test_pair_sends_one_key_to_every_device() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut addresses = Vec::new();
+        let mut handles = Vec::new();
+        for name in ["desk", "laptop"] {
+            let key_manager = KeyManager::with_dir(temp_dir.path().join(name));
+            let mut server = HandshakeServer::new(key_manager, name);
+            let addr = server.listen(0).await.unwrap();
+            addresses.push(format!("127.0.0.1:{}", addr.port()));
+            handles.push(tokio::spawn(async move {
+                let (event_tx, _event_rx) = mpsc::channel(10);
+                server.handle_one(event_tx).await
+            }));
+        }
+        // Nothing listens on port 1
+        addresses.push("127.0.0.1:1".to_string());
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let results = BatchPairing::new()
+            .pair(&HandshakeClient::new("Client"), addresses, &key_pair)
+            .await;
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].result.as_ref().unwrap().server_name, "desk");
+        assert_eq!(results[1].result.as_ref().unwrap().server_name, "laptop");
+        assert_eq!(results[2].address, "127.0.0.1:1");
+        assert!(results[2].result.is_err());
+        for name in ["desk", "laptop"] {
+            let authorized = KeyManager::with_dir(temp_dir.path().join(name))
+                .list_authorized_keys()
+                .unwrap();
+            assert!(authorized[0].contains(key_pair.public_key.trim()));
+        }
+    }
+
+    #[test]
+    fn test_progress_serialization() {
+        let progress = BatchProgress {
+            target: 1,
+            address: "10.0.0.2:8099".to_string(),
+            status: PairingStatus::Failed {
+                error: "refused".to_string(),
+            },
+        };
+        let json = serde_json::to_string(&progress).unwrap();
+        assert_eq!(
+            json,
+            r#"{"target":1,"address":"10.0.0.2:8099","status":"failed","error":"refused"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<BatchProgress>(&json).unwrap(),
+            progress
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Capabilities peers announce in the pairing handshake
+//!
+//! Besides the range of protocol versions it speaks, a client lists the
+//! features it supports in `Hello`, and the listener answers with the ones
+//! both sides have in `HelloAck`. Features can then roll out without a new
+//! protocol version, and a peer that lacks one is told so instead of failing
+//! halfway through. Peers that predate capabilities send none; what they
+//! support is judged by their protocol version.
+
+use crate::protocol::PIN_VERSION;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{BitAnd, BitOr};
+
+/// A set of features, sent as a bitset
+///
+/// Bits this build does not know are kept when deserializing, so a set can
+/// be intersected with a newer peer's without losing track of what the
+/// peer meant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// No features
+    pub const NONE: Self = Self(0);
+    /// Pairing over an encrypted [`channel`](crate::channel); only counts
+    /// once the channel is set up, since peers before
+    /// [`ENCRYPTED_VERSION`](crate::protocol::ENCRYPTED_VERSION) announced it
+    /// without encrypting
+    pub const ENCRYPTION: Self = Self(1);
+    /// Entering and showing verification codes
+    pub const VERIFICATION: Self = Self(1 << 1);
+    /// Keeping keys in sync with `connecto sync`
+    pub const SYNC: Self = Self(1 << 2);
+    /// Receiving files with `connecto receive`
+    pub const TRANSFER: Self = Self(1 << 3);
+
+    /// Everything this build supports
+    pub const SUPPORTED: Self =
+        Self(Self::ENCRYPTION.0 | Self::VERIFICATION.0 | Self::SYNC.0 | Self::TRANSFER.0);
+
+    const NAMES: [(Self, &'static str); 4] = [
+        (Self::ENCRYPTION, "encryption"),
+        (Self::VERIFICATION, "verification"),
+        (Self::SYNC, "sync"),
+        (Self::TRANSFER, "transfer"),
+    ];
+
+    /// The raw bitset
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether every feature of `other` is in the set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The set without the features of `other`
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// What a peer that sends no capabilities supports, judged by the
+    /// protocol version it speaks
+    pub fn implied_by(version: u32) -> Self {
+        if version >= PIN_VERSION {
+            Self::VERIFICATION
+        } else {
+            Self::NONE
+        }
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    /// The known features by name, e.g. `verification, sync`, or `none`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let ours = Capabilities::SUPPORTED;
+        let theirs = Capabilities::VERIFICATION | Capabilities::SYNC;
+        let common = ours & theirs;
+        assert!(common.contains(Capabilities::SYNC));
+        assert!(!common.contains(Capabilities::TRANSFER));
+        assert_eq!(common.to_string(), "verification, sync");
+        assert_eq!(Capabilities::NONE.to_string(), "none");
+        assert!(Capabilities::NONE.contains(Capabilities::NONE));
+        assert_eq!(
+            ours.without(Capabilities::ENCRYPTION | Capabilities::TRANSFER)
+                .to_string(),
+            "verification, sync"
+        );
+
+        assert_eq!(Capabilities::implied_by(1), Capabilities::NONE);
+        assert_eq!(
+            Capabilities::implied_by(PIN_VERSION),
+            Capabilities::VERIFICATION
+        );
+    }
+
+    #[test]
+    fn test_unknown_bits_survive() {
+        // A newer peer's feature this build does not know about
+        let theirs: Capabilities = serde_json::from_str("48").unwrap();
+        assert_eq!(theirs.bits(), 48);
+        assert_eq!(serde_json::to_string(&theirs).unwrap(), "48");
+        assert_eq!(theirs & Capabilities::SUPPORTED, Capabilities::NONE);
+        assert_eq!(
+            (theirs | Capabilities::SYNC).to_string(),
+            "sync",
+            "unknown bits are not named"
+        );
+    }
+}
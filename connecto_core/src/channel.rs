@@ -0,0 +1,565 @@
+//! Encrypted channels between devices
+//!
+//! From [`ENCRYPTED_VERSION`](crate::protocol::ENCRYPTED_VERSION) on, the
+//! client sends an ephemeral X25519 key share in `Hello` and the listener
+//! answers with its own in `HelloAck`. Both derive a secret from the
+//! Diffie-Hellman result and the two shares, and seal every message after
+//! `HelloAck` with ChaCha20-Poly1305: a separate key for each direction and
+//! the frame's sequence number as nonce, so frames cannot be read, changed,
+//! replayed, dropped or reordered.
+//!
+//! An ephemeral key exchange alone does not stop a man in the middle, who
+//! can run one with each side. Each channel therefore has a binding, a value
+//! derived from its secret that differs between the two halves of such an
+//! attack. Identity proofs sign it along with the nonce, and the
+//! verification code is checked with SPAKE2 over the binding instead of
+//! being sent: a device in the middle sees neither the code nor anything it
+//! could test guesses against offline, and a proof made for one channel
+//! fails in the other.
+//!
+//! The same frames carry relay channels, keyed by the relay's own SPAKE2
+//! exchange.
+
+use crate::error::{ConnectoError, Result};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Most plaintext bytes in one encrypted frame
+pub(crate) const MAX_FRAME: usize = 16 * 1024;
+
+/// Length of a frame's authentication tag
+pub(crate) const TAG_LEN: usize = 16;
+
+/// Bytes of the length that starts a frame
+const HEADER_LEN: usize = 4;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Which end of a pairing channel a device is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    /// The device that connected and sends its key
+    Client,
+    /// The listener that installs the key
+    Server,
+}
+
+impl Side {
+    fn label(self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Server => "server",
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        }
+    }
+}
+
+/// An ephemeral X25519 key pair, used for one channel
+pub(crate) struct KeyShare {
+    secret: [u8; 32],
+    public: [u8; 32],
+}
+
+impl KeyShare {
+    /// Generate a fresh key share
+    pub(crate) fn generate() -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let public = MontgomeryPoint::mul_base_clamped(secret).to_bytes();
+        Self { secret, public }
+    }
+
+    /// The public half, hex-encoded as it is sent
+    pub(crate) fn public(&self) -> String {
+        to_hex(&self.public)
+    }
+
+    /// Agree on the channel's keys with the peer's public share
+    ///
+    /// Fails if the share is malformed or a low-order point, which would
+    /// let the peer choose the shared secret.
+    pub(crate) fn agree(self, side: Side, peer: &str) -> Result<ChannelKeys> {
+        let invalid = || ConnectoError::Handshake("Invalid key share".to_string());
+        let peer: [u8; 32] = from_hex(peer)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(invalid)?;
+        let shared = MontgomeryPoint(peer).mul_clamped(self.secret).to_bytes();
+        if shared == [0; 32] {
+            return Err(invalid());
+        }
+
+        let (client, server) = match side {
+            Side::Client => (self.public, peer),
+            Side::Server => (peer, self.public),
+        };
+        let mut mac = HmacSha256::new_from_slice(&shared).expect("HMAC takes any key length");
+        mac.update(b"connecto pairing");
+        mac.update(&client);
+        mac.update(&server);
+        let secret: [u8; 32] = mac.finalize().into_bytes().into();
+
+        let mut binding = HmacSha256::new_from_slice(&secret).expect("HMAC takes any key length");
+        binding.update(b"binding");
+        Ok(ChannelKeys {
+            sending: FrameKeys::derive(&secret, &format!("{} cipher", side.label())),
+            receiving: FrameKeys::derive(&secret, &format!("{} cipher", side.other().label())),
+            binding: to_hex(&binding.finalize().into_bytes()),
+        })
+    }
+}
+
+/// Keys both ends of a channel agreed on
+pub(crate) struct ChannelKeys {
+    sending: FrameKeys,
+    receiving: FrameKeys,
+    binding: String,
+}
+
+impl ChannelKeys {
+    /// A value unique to this channel, known only to its two ends
+    pub(crate) fn binding(&self) -> &str {
+        &self.binding
+    }
+}
+
+/// Wrap the two halves of a connection, sealing what is written and opening
+/// what is read with `keys`, or passing it through unchanged without keys
+pub(crate) fn wrap<R, W>(
+    reader: R,
+    writer: W,
+    keys: Option<ChannelKeys>,
+) -> (ChannelReader<R>, ChannelWriter<W>) {
+    let (sending, receiving) = match keys {
+        Some(keys) => (Some(keys.sending), Some(keys.receiving)),
+        None => (None, None),
+    };
+    let reader = ChannelReader {
+        inner: reader,
+        keys: receiving,
+        frame: Vec::new(),
+        filled: 0,
+        plaintext: Vec::new(),
+        consumed: 0,
+    };
+    let writer = ChannelWriter {
+        inner: writer,
+        keys: sending,
+        frame: Vec::new(),
+        written: 0,
+        accepted: 0,
+    };
+    (reader, writer)
+}
+
+/// The reading half of a channel
+///
+/// A frame that fails authentication is an [`ErrorKind::InvalidData`] error.
+pub(crate) struct ChannelReader<R> {
+    inner: R,
+    keys: Option<FrameKeys>,
+    /// The frame being read and how much of it has arrived
+    frame: Vec<u8>,
+    filled: usize,
+    /// The last frame opened and how much of it was read
+    plaintext: Vec<u8>,
+    consumed: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ChannelReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let Some(keys) = &mut this.keys else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        loop {
+            if this.consumed < this.plaintext.len() {
+                let n = buf.remaining().min(this.plaintext.len() - this.consumed);
+                buf.put_slice(&this.plaintext[this.consumed..this.consumed + n]);
+                this.consumed += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let target = if this.filled < HEADER_LEN {
+                HEADER_LEN
+            } else {
+                let header: [u8; HEADER_LEN] = this.frame[..HEADER_LEN].try_into().unwrap();
+                let len = u32::from_be_bytes(header) as usize;
+                if len > MAX_FRAME {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Frame of {} bytes is too long", len),
+                    )));
+                }
+                HEADER_LEN + len + TAG_LEN
+            };
+
+            if this.filled == target {
+                let header: [u8; HEADER_LEN] = this.frame[..HEADER_LEN].try_into().unwrap();
+                this.plaintext = keys
+                    .open(&header, &this.frame[HEADER_LEN..target])
+                    .ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidData, "A frame failed authentication")
+                    })?;
+                this.consumed = 0;
+                this.filled = 0;
+                continue;
+            }
+
+            this.frame.resize(target, 0);
+            let mut read = ReadBuf::new(&mut this.frame[this.filled..target]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            let n = read.filled().len();
+            if n == 0 {
+                // A clean close between frames is the end of the stream
+                if this.filled == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+            this.filled += n;
+        }
+    }
+}
+
+/// The writing half of a channel
+///
+/// Each write is sealed into one frame of at most [`MAX_FRAME`] bytes and
+/// completes once the whole frame is written, so writes must be retried
+/// with the same data until they do, as `write_all` does.
+pub(crate) struct ChannelWriter<W> {
+    inner: W,
+    keys: Option<FrameKeys>,
+    /// The frame being written and how much of it has been
+    frame: Vec<u8>,
+    written: usize,
+    /// Plaintext bytes the frame holds
+    accepted: usize,
+}
+
+impl<W: AsyncWrite + Unpin> ChannelWriter<W> {
+    /// Write out the rest of the current frame
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.frame.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.frame[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.frame.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ChannelWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(keys) = &mut this.keys else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        if this.frame.is_empty() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            this.accepted = buf.len().min(MAX_FRAME);
+            this.frame = keys.seal(&buf[..this.accepted]);
+        }
+        ready!(this.poll_frame(cx))?;
+        Poll::Ready(Ok(this.accepted))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_frame(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_frame(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Key and frame counter for one direction of a channel
+pub(crate) struct FrameKeys {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl FrameKeys {
+    /// Key for the direction `label` names, derived from `key`
+    pub(crate) fn derive(key: &[u8; 32], label: &str) -> Self {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key length");
+        mac.update(label.as_bytes());
+        Self {
+            cipher: <ChaCha20Poly1305 as chacha20poly1305::KeyInit>::new(
+                &mac.finalize().into_bytes(),
+            ),
+            counter: 0,
+        }
+    }
+
+    /// Nonce of the current frame; the counter makes replayed, dropped or
+    /// reordered frames fail
+    fn nonce(&self) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        nonce
+    }
+
+    /// Encrypt `plaintext` into a frame: length, ciphertext, tag
+    pub(crate) fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let header = (plaintext.len() as u32).to_be_bytes();
+        let sealed = self
+            .cipher
+            .encrypt(
+                &self.nonce(),
+                Payload {
+                    msg: plaintext,
+                    aad: &header,
+                },
+            )
+            .expect("frames are far below ChaCha20-Poly1305's limit");
+        self.counter += 1;
+
+        let mut frame = header.to_vec();
+        frame.extend_from_slice(&sealed);
+        frame
+    }
+
+    /// Check and decrypt a frame's body, ciphertext followed by tag, or
+    /// `None` if it fails authentication
+    pub(crate) fn open(&mut self, header: &[u8; 4], body: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = self
+            .cipher
+            .decrypt(
+                &self.nonce(),
+                Payload {
+                    msg: body,
+                    aad: header,
+                },
+            )
+            .ok()?;
+        self.counter += 1;
+        Some(plaintext)
+    }
+}
+
+/// One side of a SPAKE2 exchange over a verification code, tied to the
+/// channel it runs in; the listener is side A
+pub(crate) struct CodeExchange {
+    state: Spake2<Ed25519Group>,
+    message: Vec<u8>,
+}
+
+impl CodeExchange {
+    /// Start the exchange for `code` in the channel with `binding`
+    pub(crate) fn start(side: Side, code: &str, binding: &str) -> Self {
+        let password = Password::new(code.as_bytes());
+        let server = Identity::new(format!("connecto pairing server {}", binding).as_bytes());
+        let client = Identity::new(format!("connecto pairing client {}", binding).as_bytes());
+        let (state, message) = match side {
+            Side::Server => Spake2::start_a(&password, &server, &client),
+            Side::Client => Spake2::start_b(&password, &server, &client),
+        };
+        Self { state, message }
+    }
+
+    /// Our message, hex-encoded as it is sent
+    pub(crate) fn message(&self) -> String {
+        to_hex(&self.message)
+    }
+
+    /// The key both sides derive if they used the same code in the same
+    /// channel, given the other side's message
+    pub(crate) fn finish(self, peer_message: &str) -> Result<CodeKey> {
+        let invalid = || ConnectoError::Handshake("Invalid verification message".to_string());
+        let peer_message = from_hex(peer_message).ok_or_else(invalid)?;
+        let key = self.state.finish(&peer_message).map_err(|_| invalid())?;
+        key.try_into().map(CodeKey).map_err(|_| invalid())
+    }
+}
+
+/// The result of a [`CodeExchange`], which each side proves it holds
+pub(crate) struct CodeKey([u8; 32]);
+
+impl CodeKey {
+    /// The MAC `sender` proves it derived the key with, hex-encoded
+    pub(crate) fn confirmation(&self, sender: Side) -> String {
+        to_hex(&self.mac(sender).finalize().into_bytes())
+    }
+
+    /// Whether `mac` is the confirmation of `sender`, compared in constant time
+    pub(crate) fn confirms(&self, sender: Side, mac: &str) -> bool {
+        from_hex(mac).is_some_and(|mac| self.mac(sender).verify_slice(&mac).is_ok())
+    }
+
+    fn mac(&self, sender: Side) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC takes any key length");
+        mac.update(format!("{} confirm", sender.label()).as_bytes());
+        mac
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Keys of a client and a server that exchanged shares
+    fn agree() -> (ChannelKeys, ChannelKeys) {
+        let client = KeyShare::generate();
+        let server = KeyShare::generate();
+        let (client_public, server_public) = (client.public(), server.public());
+        (
+            client.agree(Side::Client, &server_public).unwrap(),
+            server.agree(Side::Server, &client_public).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_key_agreement() {
+        let (client, server) = agree();
+        assert_eq!(client.binding(), server.binding());
+        assert_eq!(client.binding().len(), 64);
+
+        // A device in the middle ends up with a channel of its own
+        let (other, _) = agree();
+        assert_ne!(other.binding(), client.binding());
+
+        // Malformed shares, and the low-order point that fixes the secret
+        for share in ["", "zz", &"00".repeat(31), &"00".repeat(32)] {
+            assert!(KeyShare::generate().agree(Side::Client, share).is_err());
+        }
+    }
+
+    #[test]
+    fn test_frames() {
+        let key = [7; 32];
+        let mut sending = FrameKeys::derive(&key, "client cipher");
+        let mut receiving = FrameKeys::derive(&key, "client cipher");
+
+        for text in [&b"first"[..], b"second"] {
+            let frame = sending.seal(text);
+            let header: [u8; 4] = frame[..4].try_into().unwrap();
+            assert_ne!(&frame[4..4 + text.len()], text);
+            assert_eq!(receiving.open(&header, &frame[4..]).unwrap(), text);
+        }
+
+        // Tampered, replayed, or sent in the other direction
+        let mut frame = sending.seal(b"third");
+        let header: [u8; 4] = frame[..4].try_into().unwrap();
+        frame[5] ^= 1;
+        assert!(receiving.open(&header, &frame[4..]).is_none());
+        let mut other = FrameKeys::derive(&key, "server cipher");
+        let frame = other.seal(b"third");
+        assert!(receiving.open(&header, &frame[4..]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_channel() {
+        let (client_keys, server_keys) = agree();
+        let (client_end, server_end) = tokio::io::duplex(1024);
+        let (client_reader, client_writer) = tokio::io::split(client_end);
+        let (server_reader, server_writer) = tokio::io::split(server_end);
+        let (_, mut client_writer) = wrap(client_reader, client_writer, Some(client_keys));
+        let (mut server_reader, _) = wrap(server_reader, server_writer, Some(server_keys));
+
+        // Longer than a frame, and than the pipe holds at once
+        let message: Vec<u8> = (0..3 * MAX_FRAME).map(|i| i as u8).collect();
+        let sent = message.clone();
+        let writing = tokio::spawn(async move {
+            client_writer.write_all(&sent).await.unwrap();
+            client_writer.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        server_reader.read_to_end(&mut received).await.unwrap();
+        writing.await.unwrap();
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn test_channel_rejects_tampering() {
+        let (client_keys, server_keys) = agree();
+        let (_, mut writer) = wrap(tokio::io::empty(), Vec::new(), Some(client_keys));
+        writer.write_all(b"ssh-ed25519 AAAA").await.unwrap();
+        let mut wire = writer.inner;
+        assert!(!wire.windows(4).any(|w| w == b"AAAA"));
+
+        wire[HEADER_LEN] ^= 1;
+        let (mut reader, _) = wrap(&wire[..], tokio::io::sink(), Some(server_keys));
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // Without keys, bytes pass through unchanged
+        let (mut reader, _) = wrap(&b"plain"[..], tokio::io::sink(), None);
+        let mut plain = String::new();
+        reader.read_to_string(&mut plain).await.unwrap();
+        assert_eq!(plain, "plain");
+    }
+
+    #[test]
+    fn test_code_exchange() {
+        let exchange = |server_code: &str, client_code: &str, same_channel: bool| {
+            let (client_keys, server_keys) = agree();
+            let (other_keys, _) = agree();
+            let client_binding = if same_channel {
+                client_keys.binding()
+            } else {
+                other_keys.binding()
+            };
+            let server = CodeExchange::start(Side::Server, server_code, server_keys.binding());
+            let client = CodeExchange::start(Side::Client, client_code, client_binding);
+            let (server_message, client_message) = (server.message(), client.message());
+            let server_key = server.finish(&client_message).unwrap();
+            let client_key = client.finish(&server_message).unwrap();
+            server_key.confirms(Side::Client, &client_key.confirmation(Side::Client))
+                && client_key.confirms(Side::Server, &server_key.confirmation(Side::Server))
+        };
+
+        assert!(exchange("482193", "482193", true));
+        assert!(!exchange("482193", "482194", true));
+        // A device in the middle relaying the messages between two channels
+        assert!(!exchange("482193", "482193", false));
+    }
+}
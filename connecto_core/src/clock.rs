@@ -0,0 +1,166 @@
+//! Clock module
+//!
+//! Devices send their clock in the first message of a handshake, so each
+//! side can tell how far the other's clock is from its own. Nothing depends
+//! on the clocks agreeing yet (key lifetimes are sent as durations), but
+//! signed receipts will, so a large difference is worth reporting while both
+//! devices are at hand.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Clock difference in seconds beyond which both sides are warned
+pub const CLOCK_SKEW_WARN_SECS: i64 = 60;
+
+/// This machine's clock, in seconds since the Unix epoch
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// How far the peer's clock is ahead of ours, in seconds
+///
+/// `peer_timestamp` is the peer's clock when it answered a message we sent
+/// at `sent_at` and got the answer to at `received_at`; the peer is assumed
+/// to have answered halfway between.
+pub fn skew(peer_timestamp: i64, sent_at: i64, received_at: i64) -> i64 {
+    peer_timestamp - (sent_at + (received_at - sent_at) / 2)
+}
+
+/// Whether a skew is large enough to warn about
+pub fn is_large(skew: i64) -> bool {
+    skew.abs() > CLOCK_SKEW_WARN_SECS
+}
+
+/// Log a warning if `peer_name`'s clock is too far from ours
+pub(crate) fn warn_if_large(peer_name: &str, skew: Option<i64>) {
+    if let Some(skew) = skew.filter(|s| is_large(*s)) {
+        warn!(
+            "Clock of {} is {} compared to ours",
+            peer_name,
+            describe(skew)
+        );
+    }
+}
+
+/// Whether the system keeps its clock in sync with a time server, `None`
+/// when the platform does not say
+pub fn time_synchronized() -> Option<bool> {
+    platform_synchronized()
+}
+
+#[cfg(target_os = "linux")]
+fn platform_synchronized() -> Option<bool> {
+    let output = std::process::Command::new("timedatectl")
+        .args(["show", "--property=NTPSynchronized", "--value"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn platform_synchronized() -> Option<bool> {
+    None
+}
+
+/// A skew for people, e.g. `3m 20s ahead`
+pub fn describe(skew: i64) -> String {
+    if skew == 0 {
+        return "in sync".to_string();
+    }
+    let secs = skew.unsigned_abs();
+    let amount = match (secs / 86_400, secs / 3_600 % 24, secs / 60 % 60, secs % 60) {
+        (0, 0, 0, s) => format!("{}s", s),
+        (0, 0, m, s) => format!("{}m {}s", m, s),
+        (0, h, m, _) => format!("{}h {}m", h, m),
+        (d, h, _, _) => format!("{}d {}h", d, h),
+    };
+    let direction = if skew > 0 { "ahead" } else { "behind" };
+    format!("{} {}", amount, direction)
+}
+
+/// How times are written to files, e.g. `2026-11-15T09:30:00Z`
+pub const UTC_FILE_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// How times are shown to people, e.g. `2026-11-15 09:30 UTC`
+pub const UTC_DISPLAY_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+
+/// A Unix timestamp as a UTC time in `format`, one of
+/// [`UTC_FILE_FORMAT`] and [`UTC_DISPLAY_FORMAT`]
+pub fn format_utc(secs: u64, format: &str) -> String {
+    i64::try_from(secs)
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+        .format(format)
+        .to_string()
+}
+
+/// Parse a time written in [`UTC_FILE_FORMAT`]
+pub fn parse_utc(time: &str) -> Option<u64> {
+    let time = NaiveDateTime::parse_from_str(time, UTC_FILE_FORMAT).ok()?;
+    u64::try_from(time.and_utc().timestamp()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skew() {
+        // The peer answered halfway through a 2s round trip
+        assert_eq!(skew(1_000, 1_000, 1_002), -1);
+        assert_eq!(skew(1_301, 1_000, 1_002), 300);
+        assert_eq!(skew(900, 1_000, 1_000), -100);
+        assert!(unix_now() > 1_600_000_000);
+    }
+
+    #[test]
+    fn test_is_large() {
+        assert!(!is_large(0));
+        assert!(!is_large(CLOCK_SKEW_WARN_SECS));
+        assert!(is_large(CLOCK_SKEW_WARN_SECS + 1));
+        assert!(is_large(-CLOCK_SKEW_WARN_SECS - 1));
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(0), "in sync");
+        assert_eq!(describe(45), "45s ahead");
+        assert_eq!(describe(-200), "3m 20s behind");
+        assert_eq!(describe(7_260), "2h 1m ahead");
+        assert_eq!(describe(-90_000), "1d 1h behind");
+    }
+
+    #[test]
+    fn test_format_and_parse_utc() {
+        let file = |secs| format_utc(secs, UTC_FILE_FORMAT);
+        assert_eq!(file(0), "1970-01-01T00:00:00Z");
+        assert_eq!(file(1_763_199_005), "2025-11-15T09:30:05Z");
+        assert_eq!(file(1_709_164_800), "2024-02-29T00:00:00Z");
+        for secs in [0, 1_709_164_800, 1_763_199_005, 4_102_444_799] {
+            assert_eq!(parse_utc(&file(secs)), Some(secs));
+        }
+        assert_eq!(format_utc(0, UTC_DISPLAY_FORMAT), "1970-01-01 00:00 UTC");
+        assert_eq!(
+            format_utc(951_827_696, UTC_DISPLAY_FORMAT),
+            "2000-02-29 12:34 UTC"
+        );
+        assert_eq!(
+            format_utc(1_790_000_000, UTC_DISPLAY_FORMAT),
+            "2026-09-21 14:13 UTC"
+        );
+        assert_eq!(parse_utc("2025-11-15T09:30:05"), None);
+        assert_eq!(parse_utc("2025-13-01T00:00:00Z"), None);
+        assert_eq!(parse_utc("1969-12-31T23:59:59Z"), None);
+        assert_eq!(parse_utc("soon"), None);
+    }
+}
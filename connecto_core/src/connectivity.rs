@@ -0,0 +1,451 @@
+//! Connectivity module
+//!
+//! Checks whether a paired host's SSH server is reachable, following the
+//! same route `ssh` would. Hosts behind a bastion (`ProxyJump` or
+//! `ProxyCommand`) are probed through it instead of with a direct TCP
+//! connect, which would falsely report them offline.
+
+use crate::error::{ConnectoError, Result};
+use crate::net;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+/// Default SSH port
+pub const SSH_PORT: u16 = 22;
+
+/// How ssh reaches a host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    /// A direct TCP connection
+    Direct,
+    /// Through one or more jump hosts (`ProxyJump`)
+    ProxyJump(String),
+    /// Through a command that relays the connection (`ProxyCommand`)
+    ProxyCommand(String),
+}
+
+impl Route {
+    /// Build a route from `ProxyJump` / `ProxyCommand` values
+    ///
+    /// `none` disables a proxy, as in ssh_config. `ProxyCommand` wins when
+    /// both are set, matching how `ssh -G` reports them.
+    pub fn from_options(proxy_jump: Option<&str>, proxy_command: Option<&str>) -> Self {
+        let set = |value: Option<&str>| {
+            value
+                .map(str::trim)
+                .filter(|v| !v.is_empty() && !v.eq_ignore_ascii_case("none"))
+                .map(str::to_string)
+        };
+        if let Some(command) = set(proxy_command) {
+            Route::ProxyCommand(command)
+        } else if let Some(jump) = set(proxy_jump) {
+            Route::ProxyJump(jump)
+        } else {
+            Route::Direct
+        }
+    }
+
+    /// Whether the route goes through a bastion
+    pub fn is_proxied(&self) -> bool {
+        !matches!(self, Route::Direct)
+    }
+
+    /// Short description for messages (e.g. `via bastion.example.com`)
+    pub fn describe(&self) -> String {
+        match self {
+            Route::Direct => "directly".to_string(),
+            Route::ProxyJump(jump) => format!("via {}", jump),
+            Route::ProxyCommand(command) => format!("via ProxyCommand '{}'", command),
+        }
+    }
+}
+
+/// A host's SSH server and the route to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeTarget {
+    pub hostname: String,
+    pub port: u16,
+    pub route: Route,
+}
+
+impl ProbeTarget {
+    /// A directly reachable SSH server
+    pub fn new(hostname: &str, port: u16) -> Self {
+        Self {
+            hostname: hostname.to_string(),
+            port,
+            route: Route::Direct,
+        }
+    }
+
+    /// Reach the server through `route`
+    pub fn with_route(mut self, route: Route) -> Self {
+        self.route = route;
+        self
+    }
+
+    /// Resolve a host alias the way ssh does, using `ssh -G`
+    ///
+    /// This honors everything ssh would (`Include`, `Match`, wildcards), so
+    /// the probe follows the same route as a real connection.
+    pub async fn resolve(alias: &str) -> Result<Self> {
+        let output = Command::new("ssh")
+            .args(["-G", alias])
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| ConnectoError::Network(format!("Failed to run ssh -G: {}", e)))?;
+
+        if !output.status.success() {
+            return Err(ConnectoError::Network(format!(
+                "ssh -G {} failed: {}",
+                alias,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(Self::from_resolved_config(
+            alias,
+            &String::from_utf8_lossy(&output.stdout),
+        ))
+    }
+
+    /// Parse the output of `ssh -G <alias>`
+    pub fn from_resolved_config(alias: &str, config: &str) -> Self {
+        let mut hostname = None;
+        let mut port = None;
+        let mut proxy_jump = None;
+        let mut proxy_command = None;
+
+        for line in config.lines() {
+            let Some((key, value)) = line.trim().split_once(char::is_whitespace) else {
+                continue;
+            };
+            let value = value.trim();
+            match key.to_ascii_lowercase().as_str() {
+                "hostname" => hostname = Some(value.to_string()),
+                "port" => port = value.parse().ok(),
+                "proxyjump" => proxy_jump = Some(value.to_string()),
+                "proxycommand" => proxy_command = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Self {
+            hostname: hostname.unwrap_or_else(|| alias.to_string()),
+            port: port.unwrap_or(SSH_PORT),
+            route: Route::from_options(proxy_jump.as_deref(), proxy_command.as_deref()),
+        }
+    }
+}
+
+/// Check that the host's SSH server answers, following its route
+///
+/// Direct routes need a successful TCP connect. Proxied routes open a
+/// tunnel through the bastion and wait for the server's SSH banner.
+pub async fn probe(target: &ProbeTarget, timeout: Duration) -> Result<()> {
+    match &target.route {
+        Route::Direct => {
+            let addr = net::join_host_port(&target.hostname, target.port);
+            let connect = async {
+                // Resolves scoped link-local hosts like `fe80::1%en0` too
+                let addrs = net::resolve(&addr).await?;
+                TcpStream::connect(&addrs[..]).await.map_err(|e| {
+                    ConnectoError::Network(format!("Cannot connect to {}: {}", addr, e))
+                })
+            };
+            match tokio::time::timeout(timeout, connect).await {
+                Ok(result) => result.map(|_| ()),
+                Err(_) => Err(ConnectoError::Timeout(format!(
+                    "No answer from {} within {}s",
+                    addr,
+                    timeout.as_secs()
+                ))),
+            }
+        }
+        Route::ProxyJump(jump) => {
+            let mut command = Command::new("ssh");
+            command.args(jump_args(jump, &target.hostname, target.port, timeout));
+            probe_tunnel(command, target, timeout).await
+        }
+        Route::ProxyCommand(proxy_command) => {
+            let expanded = expand_proxy_command(proxy_command, &target.hostname, target.port);
+            probe_tunnel(shell_command(&expanded), target, timeout).await
+        }
+    }
+}
+
+/// The identification string of the SSH server on this machine's `port`,
+/// e.g. `SSH-2.0-OpenSSH_9.6`
+///
+/// Fails if nothing listens there, or something other than an SSH server
+/// does.
+pub async fn local_ssh_server(port: u16, timeout: Duration) -> Result<String> {
+    let addr = net::join_host_port("127.0.0.1", port);
+    let identify = async {
+        let stream = TcpStream::connect(&addr)
+            .await
+            .map_err(|e| ConnectoError::Network(format!("Cannot connect to {}: {}", addr, e)))?;
+        let mut banner = String::new();
+        BufReader::new(stream).read_line(&mut banner).await?;
+        let banner = banner.trim();
+        if is_ssh_banner(banner) {
+            Ok(banner.to_string())
+        } else {
+            Err(ConnectoError::Network(format!(
+                "Something other than an SSH server listens on {}",
+                addr
+            )))
+        }
+    };
+    tokio::time::timeout(timeout, identify)
+        .await
+        .unwrap_or_else(|_| {
+            Err(ConnectoError::Timeout(format!(
+                "No answer from {} within {}s",
+                addr,
+                timeout.as_secs()
+            )))
+        })
+}
+
+/// Arguments for an `ssh -W` tunnel to `hostname:port` through `jump`
+///
+/// The last jump host relays the connection; any earlier ones are passed
+/// on with `-J`, so multi-hop `ProxyJump` chains work as they do for ssh.
+fn jump_args(jump: &str, hostname: &str, port: u16, timeout: Duration) -> Vec<String> {
+    let mut args = vec![
+        "-o".to_string(),
+        "BatchMode=yes".to_string(),
+        "-o".to_string(),
+        format!("ConnectTimeout={}", timeout.as_secs().max(1)),
+        "-W".to_string(),
+        format!("{}:{}", hostname, port),
+    ];
+
+    let (earlier, last) = match jump.rsplit_once(',') {
+        Some((earlier, last)) => (Some(earlier), last),
+        None => (None, jump),
+    };
+    if let Some(earlier) = earlier {
+        args.push("-J".to_string());
+        args.push(earlier.to_string());
+    }
+
+    // ProxyJump allows [user@]host[:port], which needs URI form as a destination
+    if last.contains(':') && !last.starts_with("ssh://") {
+        args.push(format!("ssh://{}", last));
+    } else {
+        args.push(last.to_string());
+    }
+    args
+}
+
+/// Run a tunnel command and wait for an SSH banner on its output
+async fn probe_tunnel(mut command: Command, target: &ProbeTarget, timeout: Duration) -> Result<()> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| ConnectoError::Network(format!("Failed to start proxy: {}", e)))?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| ConnectoError::Network("Proxy has no output".to_string()))?;
+    let mut reader = BufReader::new(stdout);
+    let mut banner = String::new();
+
+    let read = tokio::time::timeout(timeout, reader.read_line(&mut banner)).await;
+    let _ = child.kill().await;
+
+    match read {
+        Ok(Ok(_)) if is_ssh_banner(&banner) => Ok(()),
+        Ok(_) => {
+            let mut stderr = String::new();
+            if let Some(err) = child.stderr.take() {
+                let _ = BufReader::new(err).read_line(&mut stderr).await;
+            }
+            Err(ConnectoError::Network(format!(
+                "No SSH server answered at {}:{}{}",
+                target.hostname,
+                target.port,
+                if stderr.trim().is_empty() {
+                    String::new()
+                } else {
+                    format!(": {}", stderr.trim())
+                }
+            )))
+        }
+        Err(_) => Err(ConnectoError::Timeout(format!(
+            "No answer from {}:{} within {}s",
+            target.hostname,
+            target.port,
+            timeout.as_secs()
+        ))),
+    }
+}
+
+/// Whether a line is an SSH protocol identification string
+fn is_ssh_banner(line: &str) -> bool {
+    line.starts_with("SSH-")
+}
+
+/// Substitute the `%h`, `%p` and `%%` tokens of a `ProxyCommand`
+pub fn expand_proxy_command(command: &str, hostname: &str, port: u16) -> String {
+    let mut expanded = String::with_capacity(command.len());
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => expanded.push_str(hostname),
+            Some('p') => expanded.push_str(&port.to_string()),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.args(["/C", command]);
+    cmd
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", command]);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_route_from_options() {
+        assert_eq!(Route::from_options(None, None), Route::Direct);
+        assert_eq!(Route::from_options(Some("none"), None), Route::Direct);
+        assert_eq!(
+            Route::from_options(Some("bastion"), None),
+            Route::ProxyJump("bastion".to_string())
+        );
+        assert_eq!(
+            Route::from_options(Some("bastion"), Some("nc %h %p")),
+            Route::ProxyCommand("nc %h %p".to_string())
+        );
+        assert!(!Route::Direct.is_proxied());
+    }
+
+    #[test]
+    fn test_from_resolved_config() {
+        let config = "host desk\nhostname 10.0.5.20\nport 2222\nproxyjump admin@bastion.example.com\nuser alice\n";
+        let target = ProbeTarget::from_resolved_config("desk", config);
+        assert_eq!(target.hostname, "10.0.5.20");
+        assert_eq!(target.port, 2222);
+        assert_eq!(
+            target.route,
+            Route::ProxyJump("admin@bastion.example.com".to_string())
+        );
+
+        let target = ProbeTarget::from_resolved_config("laptop", "user bob\n");
+        assert_eq!(target, ProbeTarget::new("laptop", SSH_PORT));
+    }
+
+    #[test]
+    fn test_expand_proxy_command() {
+        assert_eq!(
+            expand_proxy_command("ssh -W %h:%p bastion", "10.0.0.5", 22),
+            "ssh -W 10.0.0.5:22 bastion"
+        );
+        assert_eq!(expand_proxy_command("echo 100%%", "h", 1), "echo 100%");
+        assert_eq!(expand_proxy_command("a %r b %", "h", 1), "a %r b %");
+    }
+
+    #[test]
+    fn test_jump_args() {
+        let timeout = Duration::from_secs(5);
+        let args = jump_args("bastion", "10.0.5.20", 22, timeout);
+        assert_eq!(&args[4..], ["-W", "10.0.5.20:22", "bastion"]);
+
+        let args = jump_args("outer,admin@inner:2222", "10.0.5.20", 22, timeout);
+        assert_eq!(
+            &args[4..],
+            [
+                "-W",
+                "10.0.5.20:22",
+                "-J",
+                "outer",
+                "ssh://admin@inner:2222"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_direct() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let target = ProbeTarget::new("127.0.0.1", port);
+        assert!(probe(&target, Duration::from_secs(2)).await.is_ok());
+
+        drop(listener);
+        assert!(probe(&target, Duration::from_secs(2)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_ssh_server() {
+        use tokio::io::AsyncWriteExt;
+
+        let serve = |banner: &'static str| async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream.write_all(banner.as_bytes()).await.unwrap();
+            });
+            port
+        };
+
+        let port = serve("SSH-2.0-OpenSSH_9.6\r\n").await;
+        assert_eq!(
+            local_ssh_server(port, Duration::from_secs(2))
+                .await
+                .unwrap(),
+            "SSH-2.0-OpenSSH_9.6"
+        );
+        let port = serve("HTTP/1.1 400 Bad Request\r\n").await;
+        assert!(local_ssh_server(port, Duration::from_secs(2))
+            .await
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_probe_through_proxy_command() {
+        // The proxy answers with a banner although the host itself is
+        // unresolvable, proving the probe does not connect directly
+        let target = ProbeTarget::new("unreachable.invalid", 22)
+            .with_route(Route::ProxyCommand("echo SSH-2.0-relay-for-%h".to_string()));
+        assert!(probe(&target, Duration::from_secs(5)).await.is_ok());
+
+        let target = ProbeTarget::new("unreachable.invalid", 22)
+            .with_route(Route::ProxyCommand("echo nothing here".to_string()));
+        assert!(probe(&target, Duration::from_secs(5)).await.is_err());
+    }
+}
@@ -0,0 +1,353 @@
+//! Device store module
+//!
+//! Keeps discovered devices in bounded memory. Scans of large networks and
+//! browsers running for days would otherwise collect every device they
+//! ever saw; the store instead holds up to a set number, dropping the one
+//! seen least recently to make room.
+
+use crate::discovery::DiscoveredDevice;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::net::{IpAddr, SocketAddr};
+
+/// How many devices a store holds unless told otherwise
+pub const DEFAULT_DEVICE_CAPACITY: usize = 4096;
+
+/// A discovered device, without the spare capacity and repeated strings of
+/// [`DiscoveredDevice`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LeanDevice {
+    /// `None` when the same as the instance name, as it is for mDNS
+    name: Option<Box<str>>,
+    instance_name: Box<str>,
+    hostname: Box<str>,
+    addresses: Box<[IpAddr]>,
+    port: u16,
+    identity: Option<Box<str>>,
+    scope: Option<Box<str>>,
+    external: Option<SocketAddr>,
+    os: Option<Box<str>>,
+    version: Option<Box<str>>,
+    /// When the device was last seen, in insertions
+    seen: u64,
+}
+
+impl LeanDevice {
+    fn new(device: DiscoveredDevice, seen: u64) -> Self {
+        Self {
+            name: (device.name != device.instance_name).then(|| device.name.into()),
+            instance_name: device.instance_name.into(),
+            hostname: device.hostname.into(),
+            addresses: device.addresses.into(),
+            port: device.port,
+            identity: device.identity.map(Into::into),
+            scope: device.scope.map(Into::into),
+            external: device.external,
+            os: device.os.map(Into::into),
+            version: device.version.map(Into::into),
+            seen,
+        }
+    }
+
+    fn to_device(&self) -> DiscoveredDevice {
+        DiscoveredDevice {
+            name: self
+                .name
+                .as_deref()
+                .unwrap_or(&self.instance_name)
+                .to_string(),
+            hostname: self.hostname.to_string(),
+            addresses: self.addresses.to_vec(),
+            port: self.port,
+            instance_name: self.instance_name.to_string(),
+            identity: self.identity.as_deref().map(str::to_string),
+            scope: self.scope.as_deref().map(str::to_string),
+            external: self.external,
+            os: self.os.as_deref().map(str::to_string),
+            version: self.version.as_deref().map(str::to_string),
+            latency: None,
+        }
+    }
+
+    /// Bytes the device takes, counting its heap allocations
+    fn size(&self) -> usize {
+        let strings = [
+            self.name.as_deref(),
+            Some(&*self.instance_name),
+            Some(&*self.hostname),
+            self.identity.as_deref(),
+            self.scope.as_deref(),
+            self.os.as_deref(),
+            self.version.as_deref(),
+        ];
+        size_of::<Self>()
+            + strings.iter().flatten().map(|s| s.len()).sum::<usize>()
+            + self.addresses.len() * size_of::<IpAddr>()
+    }
+}
+
+/// Where [`DeviceStore::insert`] put a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stored {
+    /// Index of the device, kept for as long as it is stored
+    pub index: usize,
+    /// Index of the device dropped to make room, if the store was full
+    pub evicted: Option<usize>,
+}
+
+/// Size of a [`DeviceStore`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreStats {
+    /// Devices stored
+    pub devices: usize,
+    /// Devices the store holds at most
+    pub capacity: usize,
+    /// Devices dropped to make room since the store was created
+    pub evicted: u64,
+    /// Approximate memory the devices take, in bytes
+    pub bytes: usize,
+}
+
+/// Discovered devices by instance name, holding at most `capacity`
+///
+/// Each device gets an index when first stored and keeps it until it is
+/// removed or evicted, so callers can refer to devices by index while
+/// others come and go. Indices are not reused.
+#[derive(Debug, Clone)]
+pub struct DeviceStore {
+    capacity: usize,
+    devices: BTreeMap<usize, LeanDevice>,
+    indices: HashMap<Box<str>, usize>,
+    /// Device indices by when they were last seen
+    recency: BTreeMap<u64, usize>,
+    next_index: usize,
+    clock: u64,
+    evicted: u64,
+}
+
+impl DeviceStore {
+    /// A store holding at most `capacity` devices (at least one)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            devices: BTreeMap::new(),
+            indices: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_index: 0,
+            clock: 0,
+            evicted: 0,
+        }
+    }
+
+    /// Store a device, or update it if it is already stored
+    ///
+    /// A device seen again keeps its index. A new device in a full store
+    /// takes the place of the one seen least recently.
+    pub fn insert(&mut self, device: DiscoveredDevice) -> Stored {
+        self.clock += 1;
+        if let Some(&index) = self.indices.get(device.instance_name.as_str()) {
+            let old = self
+                .devices
+                .insert(index, LeanDevice::new(device, self.clock));
+            if let Some(old) = old {
+                self.recency.remove(&old.seen);
+            }
+            self.recency.insert(self.clock, index);
+            return Stored {
+                index,
+                evicted: None,
+            };
+        }
+
+        let evicted = if self.devices.len() >= self.capacity {
+            self.evict()
+        } else {
+            None
+        };
+
+        let index = self.next_index;
+        self.next_index += 1;
+        self.indices
+            .insert(device.instance_name.as_str().into(), index);
+        self.devices
+            .insert(index, LeanDevice::new(device, self.clock));
+        self.recency.insert(self.clock, index);
+        Stored { index, evicted }
+    }
+
+    /// Drop the device seen least recently, returning its index
+    fn evict(&mut self) -> Option<usize> {
+        let (_, index) = self.recency.pop_first()?;
+        if let Some(device) = self.devices.remove(&index) {
+            self.indices.remove(&device.instance_name);
+        }
+        self.evicted += 1;
+        Some(index)
+    }
+
+    /// Remove a device by instance name, returning its index
+    pub fn remove(&mut self, instance_name: &str) -> Option<usize> {
+        let index = self.indices.remove(instance_name)?;
+        if let Some(device) = self.devices.remove(&index) {
+            self.recency.remove(&device.seen);
+        }
+        Some(index)
+    }
+
+    /// The device at `index`
+    pub fn get(&self, index: usize) -> Option<DiscoveredDevice> {
+        self.devices.get(&index).map(LeanDevice::to_device)
+    }
+
+    /// The index of the device with this instance name
+    pub fn index_of(&self, instance_name: &str) -> Option<usize> {
+        self.indices.get(instance_name).copied()
+    }
+
+    /// Every device with its index, in index order
+    pub fn devices(&self) -> Vec<(usize, DiscoveredDevice)> {
+        self.devices
+            .iter()
+            .map(|(&index, device)| (index, device.to_device()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Remove every device and start indices from 0 again
+    pub fn clear(&mut self) {
+        self.devices.clear();
+        self.indices.clear();
+        self.recency.clear();
+        self.next_index = 0;
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            devices: self.devices.len(),
+            capacity: self.capacity,
+            evicted: self.evicted,
+            bytes: self.devices.values().map(LeanDevice::size).sum(),
+        }
+    }
+}
+
+impl Default for DeviceStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEVICE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(instance: &str, ip: &str) -> DiscoveredDevice {
+        DiscoveredDevice {
+            name: instance.to_string(),
+            hostname: format!("{}.local.", instance),
+            addresses: vec![ip.parse().unwrap()],
+            port: 8099,
+            instance_name: instance.to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        }
+    }
+
+    #[test]
+    fn test_lean_device_round_trip() {
+        let mdns = DiscoveredDevice {
+            identity: Some("SHA256:abc".to_string()),
+            scope: Some("en0".to_string()),
+            external: Some("203.0.113.5:8099".parse().unwrap()),
+            ..device("Desk._connecto._tcp.local.", "fe80::1")
+        };
+        assert_eq!(LeanDevice::new(mdns.clone(), 1).to_device(), mdns);
+
+        // Subnet scans name devices apart from their instance name
+        let scanned = DiscoveredDevice {
+            name: "Desk".to_string(),
+            ..device("Desk._connecto._tcp.local.", "192.168.1.10")
+        };
+        assert_eq!(LeanDevice::new(scanned.clone(), 1).to_device(), scanned);
+    }
+
+    #[test]
+    fn test_insert_keeps_indices() {
+        let mut store = DeviceStore::default();
+        assert_eq!(store.insert(device("a", "192.168.1.10")).index, 0);
+        assert_eq!(store.insert(device("b", "192.168.1.11")).index, 1);
+        // Seen again at a new address
+        assert_eq!(store.insert(device("a", "192.168.1.12")).index, 0);
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            store.get(0).unwrap().addresses,
+            ["192.168.1.12".parse::<IpAddr>().unwrap()]
+        );
+
+        assert_eq!(store.remove("a"), Some(0));
+        assert_eq!(store.get(0), None);
+        // Indices are not reused
+        assert_eq!(store.insert(device("a", "192.168.1.12")).index, 2);
+        assert_eq!(store.index_of("b"), Some(1));
+        let names: Vec<_> = store
+            .devices()
+            .into_iter()
+            .map(|(i, d)| (i, d.name))
+            .collect();
+        assert_eq!(names, [(1, "b".to_string()), (2, "a".to_string())]);
+
+        store.clear();
+        assert!(store.is_empty());
+        assert_eq!(store.insert(device("c", "192.168.1.13")).index, 0);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_seen() {
+        let mut store = DeviceStore::new(2);
+        store.insert(device("a", "10.0.0.1"));
+        store.insert(device("b", "10.0.0.2"));
+        // Seeing `a` again makes `b` the oldest
+        store.insert(device("a", "10.0.0.1"));
+
+        let stored = store.insert(device("c", "10.0.0.3"));
+        assert_eq!(
+            stored,
+            Stored {
+                index: 2,
+                evicted: Some(1)
+            }
+        );
+        assert_eq!(store.index_of("b"), None);
+        assert_eq!(store.len(), 2);
+
+        let stats = store.stats();
+        assert_eq!((stats.devices, stats.capacity, stats.evicted), (2, 2, 1));
+        assert!(stats.bytes > 2 * size_of::<LeanDevice>());
+    }
+
+    #[test]
+    fn test_stays_bounded() {
+        let mut store = DeviceStore::new(100);
+        for i in 0..10_000u32 {
+            let ip = IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + i));
+            store.insert(device(&format!("host-{}", i), &ip.to_string()));
+        }
+        assert_eq!(store.len(), 100);
+        assert_eq!(store.stats().evicted, 9_900);
+        // The newest devices are kept
+        assert_eq!(store.index_of("host-9999"), Some(9_999));
+        assert_eq!(store.index_of("host-9899"), None);
+    }
+}
@@ -0,0 +1,637 @@
+//! Diagnostics module
+//!
+//! Runs the checks behind `connecto doctor`: whether this machine can find
+//! other devices and be found, accept pairings, and be reached over SSH.
+//! Each [`Check`] says what was found and, when something is wrong, what to
+//! do about it, so the CLI can print the [`Report`] for people or as JSON
+//! for scripts.
+
+use crate::clock::{self, CLOCK_SKEW_WARN_SECS};
+use crate::connectivity;
+use crate::discovery::{self, DEFAULT_PORT};
+use crate::error::ConnectoError;
+use crate::firewall::{self, PortAccess};
+use crate::keys::{KeyAudit, KeyIssue, KeyManager, Severity};
+use crate::known_hosts;
+use crate::net::{self, InterfaceAddress};
+use crate::pairings::{PairingRecord, PairingStore};
+use crate::ports;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long a check waits for anything on the network to answer
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How old a pairing's clock measurement may be to still count
+const RECENT_SKEW_SECS: u64 = 30 * 86_400;
+
+/// Identifiers of the checks, stable for scripts
+pub const MDNS_DAEMON: &str = "mdns_daemon";
+pub const MULTICAST: &str = "multicast";
+pub const LISTEN_PORT: &str = "listen_port";
+pub const FIREWALL: &str = "firewall";
+pub const SSH_SERVER: &str = "ssh_server";
+pub const SSH_PERMISSIONS: &str = "ssh_permissions";
+pub const AUTHORIZED_KEYS: &str = "authorized_keys";
+pub const CLOCK: &str = "clock";
+
+/// The outcome of a [`Check`], ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Nothing to do
+    Pass,
+    /// Works, but something may get in the way
+    Warn,
+    /// Something Connecto or SSH needs is broken
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        })
+    }
+}
+
+/// The result of one check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Check {
+    /// What was checked, e.g. [`MULTICAST`]
+    pub id: String,
+    /// The part of the machine it was checked on, e.g. an interface
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    pub status: Status,
+    /// What was found
+    pub detail: String,
+    /// What to do about it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+    /// Commands that do it, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<String>,
+}
+
+impl Check {
+    fn new(id: &str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            id: id.to_string(),
+            subject: None,
+            status,
+            detail: detail.into(),
+            fix: None,
+            commands: Vec::new(),
+        }
+    }
+
+    fn pass(id: &str, detail: impl Into<String>) -> Self {
+        Self::new(id, Status::Pass, detail)
+    }
+
+    fn warn(id: &str, detail: impl Into<String>) -> Self {
+        Self::new(id, Status::Warn, detail)
+    }
+
+    fn fail(id: &str, detail: impl Into<String>) -> Self {
+        Self::new(id, Status::Fail, detail)
+    }
+
+    fn with_subject(mut self, subject: impl Into<String>) -> Self {
+        self.subject = Some(subject.into());
+        self
+    }
+
+    fn with_fix(mut self, fix: impl Into<String>) -> Self {
+        self.fix = Some(fix.into());
+        self
+    }
+
+    fn with_command(mut self, command: impl Into<String>) -> Self {
+        self.commands.push(command.into());
+        self
+    }
+}
+
+/// The results of [`Doctor::run`], in the order the checks ran
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// The worst status of any check, `None` if nothing was checked
+    pub fn worst(&self) -> Option<Status> {
+        self.checks.iter().map(|check| check.status).max()
+    }
+
+    /// How many checks ended with `status`
+    pub fn count(&self, status: Status) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == status)
+            .count()
+    }
+}
+
+/// Runs every check on this machine
+#[derive(Debug, Clone)]
+pub struct Doctor {
+    port: u16,
+    ssh_dir: Option<PathBuf>,
+    pairings: Option<PathBuf>,
+}
+
+impl Default for Doctor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Doctor {
+    /// Check for the default handshake port and SSH directory
+    pub fn new() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            ssh_dir: None,
+            pairings: None,
+        }
+    }
+
+    /// Check the handshake port `connecto listen --port` would use
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Check another SSH directory than the default
+    pub fn with_ssh_dir(mut self, ssh_dir: PathBuf) -> Self {
+        self.ssh_dir = Some(ssh_dir);
+        self
+    }
+
+    /// Read clock measurements from another pairing database
+    pub fn with_pairings(mut self, path: PathBuf) -> Self {
+        self.pairings = Some(path);
+        self
+    }
+
+    /// Run all checks
+    pub async fn run(&self) -> Report {
+        let mut checks = vec![mdns_daemon()];
+        checks.extend(multicast(&net::interface_addresses()));
+        checks.extend(self.listen_port().await);
+        checks.push(ssh_server().await);
+
+        let key_manager = match &self.ssh_dir {
+            Some(dir) => Ok(KeyManager::with_dir(dir.clone())),
+            None => KeyManager::new(),
+        };
+        match key_manager.and_then(|manager| Ok((manager.audit()?, manager))) {
+            Ok((audit, manager)) => {
+                checks.push(ssh_permissions(&audit));
+                checks.push(authorized_keys(&audit, &manager.authorized_keys_paths()));
+            }
+            Err(e) => {
+                let reason = reason(e);
+                checks.push(Check::fail(SSH_PERMISSIONS, reason.clone()));
+                checks.push(Check::fail(AUTHORIZED_KEYS, reason));
+            }
+        }
+
+        let store = match &self.pairings {
+            Some(path) => Ok(PairingStore::with_path(path.clone())),
+            None => PairingStore::new(),
+        };
+        let records = store.and_then(|store| store.all()).unwrap_or_default();
+        checks.push(clock_check(
+            &records,
+            clock::unix_now().max(0) as u64,
+            clock::time_synchronized(),
+        ));
+
+        Report { checks }
+    }
+
+    async fn listen_port(&self) -> Vec<Check> {
+        let port = self.port;
+        let mut checks = Vec::new();
+        match ports::check_available(port) {
+            Ok(()) => checks.push(reachability(port).await),
+            Err(ConnectoError::PortInUse { owner, .. }) => match owner {
+                Some(owner) if owner.is_connecto() => checks.push(Check::pass(
+                    LISTEN_PORT,
+                    format!("Connecto is listening on port {}", port),
+                )),
+                owner => {
+                    let owner = owner.map_or("another program".to_string(), |o| o.to_string());
+                    let mut check =
+                        Check::fail(LISTEN_PORT, format!("Port {} is taken by {}", port, owner))
+                            .with_fix("Stop that program, or listen on another port");
+                    if let Some(free) = ports::suggest_free_port(port) {
+                        check = check.with_command(format!("connecto listen --port {}", free));
+                    }
+                    checks.push(check);
+                }
+            },
+            Err(e) => checks.push(Check::fail(LISTEN_PORT, reason(e))),
+        }
+        checks.push(firewall_check(port));
+        checks
+    }
+}
+
+/// Whether an mDNS daemon starts
+fn mdns_daemon() -> Check {
+    match discovery::check_mdns_daemon() {
+        Ok(()) => Check::pass(MDNS_DAEMON, "The mDNS daemon starts"),
+        Err(e) => Check::fail(MDNS_DAEMON, reason(e))
+            .with_fix("Let Connecto use UDP port 5353; until then, pair by address")
+            .with_command("connecto pair <address>"),
+    }
+}
+
+/// Whether each interface mDNS would use can join its multicast group
+///
+/// mDNS over IPv6 runs on link-local addresses, so other IPv6 addresses
+/// are left out.
+fn multicast(interfaces: &[InterfaceAddress]) -> Vec<Check> {
+    let interfaces: Vec<_> = interfaces
+        .iter()
+        .filter(|iface| iface.address.is_ipv4() || net::is_link_local(&iface.address))
+        .collect();
+    if interfaces.is_empty() {
+        return vec![
+            Check::fail(MULTICAST, "No network interface besides loopback")
+                .with_fix("Connect to the network the other devices are on"),
+        ];
+    }
+
+    let results: Vec<_> = interfaces
+        .iter()
+        .map(|iface| (iface, discovery::check_multicast(iface)))
+        .collect();
+    // One interface that works is enough to find devices on its network
+    let failed = if results.iter().any(|(_, result)| result.is_ok()) {
+        Status::Warn
+    } else {
+        Status::Fail
+    };
+    results
+        .into_iter()
+        .map(|(iface, result)| match result {
+            Ok(()) => {
+                Check::pass(MULTICAST, "Joins the mDNS group").with_subject(iface.to_string())
+            }
+            Err(e) => Check::new(MULTICAST, failed, reason(e))
+                .with_subject(iface.to_string())
+                .with_fix("Check that the interface is up and no VPN keeps it out of multicast"),
+        })
+        .collect()
+}
+
+/// Whether this machine reaches itself on a free `port` at each of its IPv4
+/// addresses
+async fn reachability(port: u16) -> Check {
+    let addresses: Vec<IpAddr> = net::interface_addresses()
+        .into_iter()
+        .map(|iface| iface.address)
+        .filter(IpAddr::is_ipv4)
+        .collect();
+    let bind = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    match firewall::unreachable_addresses(bind, &addresses, port, firewall::SELF_CHECK_TIMEOUT)
+        .await
+    {
+        Ok(unreachable) if !unreachable.is_empty() => {
+            let unreachable: Vec<String> = unreachable.iter().map(ToString::to_string).collect();
+            Check::warn(
+                LISTEN_PORT,
+                format!(
+                    "Port {} is free, but this device cannot connect to itself at {}",
+                    port,
+                    unreachable.join(", ")
+                ),
+            )
+            .with_fix("Other devices will not reach it there either; check VPN and routing rules")
+        }
+        _ => Check::pass(LISTEN_PORT, format!("Port {} is free", port)),
+    }
+}
+
+/// Whether a firewall lets connections to `port` through
+fn firewall_check(port: u16) -> Check {
+    let program = std::env::current_exe()
+        .map(|exe| exe.canonicalize().unwrap_or(exe))
+        .unwrap_or_else(|_| "connecto".into());
+    let Some(check) = firewall::check(port, &program) else {
+        return Check::pass(FIREWALL, "No firewall filters incoming connections");
+    };
+    let fix = format!(
+        "Let port {} through {}, as root or Administrator",
+        port, check.firewall
+    );
+    let result = match check.access {
+        PortAccess::Allowed => {
+            return Check::pass(
+                FIREWALL,
+                format!("{} lets port {} through", check.firewall, port),
+            )
+        }
+        PortAccess::Blocked => {
+            Check::fail(FIREWALL, format!("{} blocks port {}", check.firewall, port))
+        }
+        PortAccess::Unknown => Check::warn(
+            FIREWALL,
+            format!(
+                "{} is on and would not say whether it lets port {} through",
+                check.firewall, port
+            ),
+        ),
+    };
+    check
+        .firewall
+        .allow_commands(port, &program)
+        .iter()
+        .fold(result.with_fix(fix), |result, command| {
+            result.with_command(command.to_string())
+        })
+}
+
+/// Whether an SSH server answers on the port in `sshd_config`
+async fn ssh_server() -> Check {
+    let port = known_hosts::local_ssh_port();
+    match connectivity::local_ssh_server(port, CHECK_TIMEOUT).await {
+        Ok(banner) => Check::pass(SSH_SERVER, format!("{} answers on port {}", banner, port)),
+        // Pairing works without it, but nobody can connect to this device
+        Err(e) => Check::warn(SSH_SERVER, reason(e))
+            .with_fix("Start the SSH server so paired devices can connect to this one")
+            .with_command("connecto ssh on"),
+    }
+}
+
+/// Whether OpenSSH will use the SSH directory and the files in it
+fn ssh_permissions(audit: &KeyAudit) -> Check {
+    let loose: Vec<_> = audit
+        .findings
+        .iter()
+        .filter_map(|finding| match finding.issue {
+            KeyIssue::LoosePermissions { mode, expected } => Some((&finding.path, mode, expected)),
+            _ => None,
+        })
+        .collect();
+    if loose.is_empty() {
+        let detail = if audit.ssh_dir.is_dir() {
+            format!(
+                "{} and its files have safe permissions",
+                audit.ssh_dir.display()
+            )
+        } else {
+            format!(
+                "{} does not exist yet; it is created when needed",
+                audit.ssh_dir.display()
+            )
+        };
+        return Check::pass(SSH_PERMISSIONS, detail);
+    }
+
+    let files: Vec<String> = loose
+        .iter()
+        .map(|(path, mode, _)| format!("{} ({:04o})", relative(path, &audit.ssh_dir), mode))
+        .collect();
+    loose.iter().fold(
+        Check::fail(
+            SSH_PERMISSIONS,
+            format!(
+                "OpenSSH refuses files others can change or read: {}",
+                files.join(", ")
+            ),
+        )
+        .with_fix("Restrict them to your user"),
+        |check, (path, _, expected)| {
+            check.with_command(format!("chmod {:o} '{}'", expected, path.display()))
+        },
+    )
+}
+
+/// Whether every authorized_keys entry can be used
+///
+/// Permissions are left to [`ssh_permissions`].
+fn authorized_keys(audit: &KeyAudit, paths: &[PathBuf]) -> Check {
+    let findings: Vec<_> = audit
+        .findings
+        .iter()
+        .filter(|finding| paths.contains(&finding.path))
+        .filter(|finding| !matches!(finding.issue, KeyIssue::LoosePermissions { .. }))
+        .filter(|finding| finding.severity > Severity::Info)
+        .collect();
+    let Some(worst) = findings.iter().map(|finding| finding.severity).max() else {
+        return Check::pass(AUTHORIZED_KEYS, "Every entry is a usable key");
+    };
+
+    let issues: Vec<String> = findings
+        .iter()
+        .map(|finding| {
+            let file = relative(&finding.path, &audit.ssh_dir);
+            match finding.line {
+                Some(line) => format!("{}:{}: {}", file, line, finding.issue),
+                None => format!("{}: {}", file, finding.issue),
+            }
+        })
+        .collect();
+    let status = if worst == Severity::Critical {
+        Status::Fail
+    } else {
+        Status::Warn
+    };
+    Check::new(AUTHORIZED_KEYS, status, issues.join("; "))
+        .with_fix("Remove or replace the entries the audit lists")
+        .with_command("connecto keys audit")
+}
+
+/// Whether this machine's clock agrees with the devices it paired with
+///
+/// The latest measurement from a recent pairing counts; without one, the
+/// system's own time synchronization is all there is to go on.
+fn clock_check(records: &[PairingRecord], now: u64, synchronized: Option<bool>) -> Check {
+    let measured = records
+        .iter()
+        .filter(|record| record.paired_at + RECENT_SKEW_SECS >= now)
+        .filter_map(|record| record.clock_skew.map(|skew| (record, skew)))
+        .max_by_key(|(record, _)| record.paired_at);
+
+    let sync_fix = |check: Check| {
+        let check = check.with_fix("Turn on automatic time synchronization");
+        if cfg!(target_os = "linux") {
+            check.with_command("timedatectl set-ntp true")
+        } else {
+            check
+        }
+    };
+    match (measured, synchronized) {
+        (Some((record, skew)), _) if clock::is_large(skew) => sync_fix(Check::warn(
+            CLOCK,
+            format!(
+                "The clock of {} was {} of this one when they last paired",
+                record.peer_name,
+                clock::describe(skew)
+            ),
+        )),
+        (_, Some(false)) => sync_fix(Check::warn(
+            CLOCK,
+            "The system clock is not synchronized with a time server",
+        )),
+        (Some((record, _)), _) => Check::pass(
+            CLOCK,
+            format!(
+                "Within {}s of {} when they last paired",
+                CLOCK_SKEW_WARN_SECS, record.peer_name
+            ),
+        ),
+        (None, Some(true)) => Check::pass(CLOCK, "Synchronized with a time server"),
+        (None, None) => Check::pass(
+            CLOCK,
+            "Not measured yet; pairing compares clocks with the other device",
+        ),
+    }
+}
+
+/// What went wrong, without the kind of error in front
+fn reason(e: ConnectoError) -> String {
+    match e {
+        ConnectoError::Discovery(reason)
+        | ConnectoError::Network(reason)
+        | ConnectoError::Timeout(reason) => reason,
+        other => other.to_string(),
+    }
+}
+
+/// `path` inside `dir` without the directory, or all of it
+fn relative(path: &Path, dir: &Path) -> String {
+    path.strip_prefix(dir)
+        .ok()
+        .filter(|relative| !relative.as_os_str().is_empty())
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{KeyAlgorithm, SshKeyPair};
+    use crate::pairings::PairingDirection;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        assert_eq!(report.worst(), None);
+
+        report.checks.push(Check::pass(CLOCK, "ok"));
+        report.checks.push(
+            Check::warn(SSH_SERVER, "down")
+                .with_fix("start it")
+                .with_command("connecto ssh on"),
+        );
+        assert_eq!(report.worst(), Some(Status::Warn));
+        assert_eq!(report.count(Status::Pass), 1);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"][0]["status"], "pass");
+        assert!(json["checks"][0].get("fix").is_none());
+        assert_eq!(json["checks"][1]["id"], "ssh_server");
+        assert_eq!(json["checks"][1]["commands"][0], "connecto ssh on");
+    }
+
+    #[test]
+    fn test_multicast_without_interfaces() {
+        let checks = multicast(&[]);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].status, Status::Fail);
+    }
+
+    #[test]
+    fn test_key_checks() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = KeyManager::with_dir(temp_dir.path().join("ssh"));
+        let paths = manager.authorized_keys_paths();
+
+        let audit = manager.audit().unwrap();
+        assert_eq!(ssh_permissions(&audit).status, Status::Pass);
+        assert_eq!(authorized_keys(&audit, &paths).status, Status::Pass);
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@host").unwrap();
+        manager.add_authorized_key(&key_pair.public_key).unwrap();
+        let content = fs::read_to_string(manager.authorized_keys_path()).unwrap();
+        fs::write(
+            manager.authorized_keys_path(),
+            format!("{}not a key\n", content),
+        )
+        .unwrap();
+
+        let audit = manager.audit().unwrap();
+        let check = authorized_keys(&audit, &paths);
+        assert_eq!(check.status, Status::Warn);
+        assert!(check.detail.starts_with("authorized_keys:2: unreadable"));
+        assert_eq!(check.commands, ["connecto keys audit"]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(
+                manager.authorized_keys_path(),
+                fs::Permissions::from_mode(0o666),
+            )
+            .unwrap();
+            let audit = manager.audit().unwrap();
+            let check = ssh_permissions(&audit);
+            assert_eq!(check.status, Status::Fail);
+            assert!(check.detail.contains("authorized_keys (0666)"));
+            assert_eq!(check.commands.len(), 1);
+            assert!(check.commands[0].starts_with("chmod 600 "));
+            // Reported once, as a permission problem
+            assert_eq!(authorized_keys(&audit, &paths).status, Status::Warn);
+        }
+    }
+
+    #[test]
+    fn test_clock_check() {
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@host").unwrap();
+        let record = |skew: Option<i64>, paired_at: u64| {
+            let mut record = PairingRecord::new(
+                "desk",
+                &key_pair.public_key,
+                "10.0.0.5:8099",
+                PairingDirection::Outgoing,
+            )
+            .unwrap()
+            .with_clock_skew(skew);
+            record.paired_at = paired_at;
+            record
+        };
+        let now = 100 * 86_400;
+
+        let check = clock_check(&[], now, None);
+        assert_eq!(check.status, Status::Pass);
+        assert_eq!(clock_check(&[], now, Some(false)).status, Status::Warn);
+
+        // The latest recent measurement counts
+        let records = [record(Some(3_600), now - 86_400), record(Some(2), now - 60)];
+        assert_eq!(clock_check(&records, now, None).status, Status::Pass);
+        let records = [record(Some(2), now - 86_400), record(Some(200), now - 60)];
+        let check = clock_check(&records, now, Some(true));
+        assert_eq!(check.status, Status::Warn);
+        assert!(check.detail.contains("3m 20s ahead"));
+
+        // Old measurements and records without one do not
+        let records = [record(Some(3_600), now - 60 * 86_400), record(None, now)];
+        assert_eq!(clock_check(&records, now, None).status, Status::Pass);
+    }
+}
@@ -2,15 +2,20 @@
 //!
 //! Handles automatic discovery of Connecto instances on the local network
 
+use crate::devices::{DeviceStore, StoreStats};
 use crate::error::{ConnectoError, Result};
-use crate::protocol::Message;
-use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use crate::framing::Framing;
+use crate::limits::DEFAULT_MAX_MESSAGE_LEN;
+use crate::net::{self, InterfaceAddress};
+use crate::protocol::{Message, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::retry::{Retry, RetryPolicy};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
@@ -19,39 +24,208 @@ use tracing::{debug, info, warn};
 pub const SERVICE_TYPE: &str = "_connecto._tcp.local.";
 /// Default port for the Connecto handshake service
 pub const DEFAULT_PORT: u16 = 8099;
+/// TXT record property carrying the device's identity fingerprint
+pub const IDENTITY_PROPERTY: &str = "id";
+/// TXT record property set on devices that keep their hostname private
+pub const PRIVATE_PROPERTY: &str = "private";
+/// TXT record property carrying the address the device's router forwards to
+/// it, e.g. `203.0.113.5:8099`
+pub const EXTERNAL_PROPERTY: &str = "ext";
+/// TXT record property carrying the device's operating system, e.g. `linux`
+pub const OS_PROPERTY: &str = "os";
+/// TXT record property carrying the device's Connecto version
+pub const VERSION_PROPERTY: &str = "ver";
+/// Default number of hosts a subnet scan probes at the same time
+pub const DEFAULT_SCAN_CONCURRENCY: usize = 100;
+/// Multicast group mDNS uses over IPv4
+pub const MDNS_GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// Multicast group mDNS uses over IPv6, scoped to the link
+pub const MDNS_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
 
 /// Represents a discovered Connecto device
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct DiscoveredDevice {
     pub name: String,
+    /// mDNS hostname, empty for devices in privacy mode
     pub hostname: String,
     pub addresses: Vec<IpAddr>,
     pub port: u16,
     pub instance_name: String,
+    /// Identity fingerprint announced by the device, if any
+    ///
+    /// Anyone can announce any fingerprint; have the device prove it with
+    /// [`HandshakeClient::verify_identity`](crate::protocol::HandshakeClient::verify_identity)
+    /// before acting on it.
+    #[serde(default)]
+    pub identity: Option<String>,
+    /// Interface (e.g. `en0`) the device's link-local IPv6 addresses are
+    /// reached through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Address on the device's router that forwards to it, for clients on
+    /// segments that cannot reach its local addresses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external: Option<SocketAddr>,
+    /// Operating system the device announced, e.g. `linux`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    /// Connecto version the device announced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Round trip of the connection a subnet scan found the device with;
+    /// devices found over mDNS are never connected to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Duration>,
 }
 
 impl DiscoveredDevice {
-    /// Get the primary IP address (prefers IPv4)
+    /// Get the primary IP address (prefers IPv4, then routable IPv6)
     pub fn primary_address(&self) -> Option<IpAddr> {
-        // Prefer IPv4 addresses
         self.addresses
             .iter()
             .find(|addr| addr.is_ipv4())
+            .or_else(|| self.addresses.iter().find(|addr| !net::is_link_local(addr)))
             .or(self.addresses.first())
             .copied()
     }
 
-    /// Format as a connection string
+    /// The primary address as an SSH host name, scoped if it is link-local
+    pub fn primary_host(&self) -> Option<String> {
+        self.primary_address()
+            .map(|addr| net::format_host(addr, self.scope.as_deref()))
+    }
+
+    /// Format as a connection string, e.g. `192.168.1.10:8099` or
+    /// `[fe80::1%en0]:8099`
     pub fn connection_string(&self) -> Option<String> {
         self.primary_address()
-            .map(|addr| format!("{}:{}", addr, self.port))
+            .map(|addr| net::format_address(addr, self.port, self.scope.as_deref()))
+    }
+
+    /// The device's mDNS hostname without the trailing dot, e.g.
+    /// `desk-pc.local`, or `None` for a device in privacy mode
+    pub fn mdns_hostname(&self) -> Option<&str> {
+        Some(self.hostname.trim_end_matches('.')).filter(|hostname| !hostname.is_empty())
+    }
+
+    /// The device name the listener announces, e.g. `Study` for the mDNS
+    /// name `Study (desk-pc)._connecto._tcp.local.`
+    pub fn device_name(&self) -> &str {
+        let name = self.name.split("._connecto").next().unwrap_or(&self.name);
+        let hostname = self
+            .hostname
+            .trim_end_matches('.')
+            .trim_end_matches(".local");
+        if hostname.is_empty() {
+            return name;
+        }
+        name.strip_suffix(&format!(" ({})", hostname))
+            .unwrap_or(name)
+    }
+
+    /// Whether `other` is the same listener, perhaps found another way
+    ///
+    /// Devices announcing an identity are the same if the identities match.
+    /// Otherwise they must share an address and the port.
+    pub fn is_same_device(&self, other: &DiscoveredDevice) -> bool {
+        match (&self.identity, &other.identity) {
+            (Some(mine), Some(theirs)) => mine == theirs,
+            _ => {
+                self.port == other.port
+                    && self.addresses.iter().any(|a| other.addresses.contains(a))
+            }
+        }
     }
+
+    /// Add what `other`, the same listener, knows and this entry does not
+    ///
+    /// The name and hostname stay, since subnet probes make theirs up.
+    pub fn merge(&mut self, other: DiscoveredDevice) {
+        for address in other.addresses {
+            if !self.addresses.contains(&address) {
+                self.addresses.push(address);
+            }
+        }
+        if self.identity.is_none() {
+            self.identity = other.identity;
+        }
+        if self.scope.is_none() {
+            self.scope = other.scope;
+        }
+        if self.external.is_none() {
+            self.external = other.external;
+        }
+        if self.os.is_none() {
+            self.os = other.os;
+        }
+        if self.version.is_none() {
+            self.version = other.version;
+        }
+    }
+}
+
+/// One entry per listener among `devices`, in the order they were found
+///
+/// Pass mDNS results before subnet probes, so their names are the ones kept.
+pub fn merge_devices(devices: impl IntoIterator<Item = DiscoveredDevice>) -> Vec<DiscoveredDevice> {
+    let mut merged: Vec<DiscoveredDevice> = Vec::new();
+    for device in devices {
+        match merged
+            .iter_mut()
+            .find(|known| known.is_same_device(&device))
+        {
+            Some(known) => {
+                debug!("{} was found twice, merging", known.name);
+                known.merge(device);
+            }
+            None => merged.push(device),
+        }
+    }
+    merged
+}
+
+/// When an mDNS scan may end before its time is up
+///
+/// By default a scan runs for its whole duration. It can stop once enough
+/// devices answered, or once no new device appeared for a while after the
+/// first one did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EarlyExit {
+    min_devices: Option<usize>,
+    quiet_period: Option<Duration>,
+}
+
+impl EarlyExit {
+    /// Stop once `count` devices were found (at least one)
+    pub fn with_min_devices(mut self, count: usize) -> Self {
+        self.min_devices = Some(count.max(1));
+        self
+    }
+
+    /// Stop when no new device appeared for `period` after the first one
+    pub fn with_quiet_period(mut self, period: Duration) -> Self {
+        self.quiet_period = Some(period);
+        self
+    }
+}
+
+/// Why an mDNS scan ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanStop {
+    /// The scan ran for its whole duration
+    TimedOut,
+    /// The [`EarlyExit::with_min_devices`] count was reached
+    EnoughDevices,
+    /// No new device appeared for the [`EarlyExit::with_quiet_period`]
+    Quiet,
+    /// The browse was stopped
+    Stopped,
 }
 
 /// Events emitted during discovery
 #[derive(Debug, Clone)]
 pub enum DiscoveryEvent {
-    DeviceFound(DiscoveredDevice),
+    DeviceFound(Box<DiscoveredDevice>),
     DeviceLost(String), // instance name
     SearchStarted,
     SearchStopped,
@@ -61,6 +235,13 @@ pub enum DiscoveryEvent {
 pub struct ServiceAdvertiser {
     daemon: ServiceDaemon,
     service_fullname: Option<String>,
+    /// The registered service, kept to re-register it after a suspension
+    service: Option<ServiceInfo>,
+    suspended: bool,
+    identity: Option<String>,
+    private: bool,
+    interfaces: Vec<InterfaceAddress>,
+    external: Option<SocketAddr>,
 }
 
 impl ServiceAdvertiser {
@@ -73,43 +254,177 @@ impl ServiceAdvertiser {
         Ok(Self {
             daemon,
             service_fullname: None,
+            service: None,
+            suspended: false,
+            identity: None,
+            private: false,
+            interfaces: Vec::new(),
+            external: None,
         })
     }
 
+    /// Include this device's identity fingerprint in the advertisement
+    ///
+    /// Ignored in privacy mode, where a stable fingerprint would let the
+    /// device be tracked across pseudonyms.
+    pub fn with_identity(mut self, identity: &str) -> Self {
+        self.identity = Some(identity.to_string());
+        self
+    }
+
+    /// Advertise only the device name, keeping the hostname off the network
+    ///
+    /// The service is published under a random `.local` hostname and without
+    /// the identity fingerprint.
+    pub fn with_privacy(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Advertise only on `interfaces`, and only their addresses
+    ///
+    /// By default the device is advertised on every interface with all of
+    /// its addresses, following them as they change.
+    pub fn with_interfaces(mut self, interfaces: Vec<InterfaceAddress>) -> Self {
+        self.interfaces = interfaces;
+        self
+    }
+
+    /// Announce `external`, a port the router forwards to this device, as
+    /// another address to reach it at
+    ///
+    /// Ignored in privacy mode, where it would tell which network the
+    /// device is on.
+    pub fn with_external_address(mut self, external: SocketAddr) -> Self {
+        self.external = Some(external);
+        self
+    }
+
+    /// The interface addresses the device is advertised with
+    pub fn interfaces(&self) -> Vec<InterfaceAddress> {
+        if self.interfaces.is_empty() {
+            net::interface_addresses()
+        } else {
+            self.interfaces.clone()
+        }
+    }
+
     /// Start advertising this device
     pub fn advertise(&mut self, device_name: &str, port: u16) -> Result<()> {
-        let hostname = hostname::get()
-            .map(|h: std::ffi::OsString| h.to_string_lossy().to_string())
-            .unwrap_or_else(|_| "unknown".to_string());
-
-        let service_hostname = format!("{}.local.", hostname);
-        let instance_name = format!("{} ({})", device_name, hostname);
+        let (service_hostname, instance_name, properties) = if self.private {
+            (
+                format!("{}.local.", generate_pseudonym()),
+                device_name.to_string(),
+                Some(HashMap::from([(
+                    PRIVATE_PROPERTY.to_string(),
+                    "1".to_string(),
+                )])),
+            )
+        } else {
+            let hostname = get_hostname();
+            let properties: HashMap<String, String> = [
+                (IDENTITY_PROPERTY, self.identity.clone()),
+                (EXTERNAL_PROPERTY, self.external.map(|e| e.to_string())),
+                (OS_PROPERTY, Some(std::env::consts::OS.to_string())),
+                (
+                    VERSION_PROPERTY,
+                    Some(env!("CARGO_PKG_VERSION").to_string()),
+                ),
+            ]
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?)))
+            .collect();
+            let properties = (!properties.is_empty()).then_some(properties);
+            (
+                format!("{}.local.", hostname),
+                format!("{} ({})", device_name, hostname),
+                properties,
+            )
+        };
 
+        let addresses: Vec<IpAddr> = self.interfaces.iter().map(|i| i.address).collect();
         let service_info = ServiceInfo::new(
             SERVICE_TYPE,
             &instance_name,
             &service_hostname,
-            "",
+            addresses.as_slice(),
             port,
-            None,
+            properties,
         )
         .map_err(|e| ConnectoError::Discovery(format!("Failed to create service info: {}", e)))?;
+        let service_info = if addresses.is_empty() {
+            service_info.enable_addr_auto()
+        } else {
+            // Keep the other interfaces from answering for the device
+            self.daemon
+                .disable_interface(IfKind::All)
+                .and_then(|_| self.daemon.enable_interface(addresses))
+                .map_err(|e| {
+                    ConnectoError::Discovery(format!("Failed to select interfaces: {}", e))
+                })?;
+            service_info
+        };
 
         let fullname = service_info.get_fullname().to_string();
 
         self.daemon
-            .register(service_info)
+            .register(service_info.clone())
             .map_err(|e| ConnectoError::Discovery(format!("Failed to register service: {}", e)))?;
 
         self.service_fullname = Some(fullname.clone());
+        self.service = Some(service_info);
+        self.suspended = false;
         info!("Advertising service: {}", fullname);
 
         Ok(())
     }
 
+    /// Withdraw the advertisement until [`resume`](Self::resume) is called
+    ///
+    /// Meant for when the machine goes to sleep: the goodbye packet tells
+    /// scanners the device is gone instead of leaving a record that outlives
+    /// it. Waits briefly for the packet to be sent.
+    pub fn suspend(&mut self) -> Result<()> {
+        let Some(fullname) = self.service_fullname.as_ref().filter(|_| !self.suspended) else {
+            return Ok(());
+        };
+        let status = self.daemon.unregister(fullname).map_err(|e| {
+            ConnectoError::Discovery(format!("Failed to unregister service: {}", e))
+        })?;
+        let _ = status.recv_timeout(Duration::from_secs(1));
+        self.suspended = true;
+        info!("Suspended advertising service: {}", fullname);
+        Ok(())
+    }
+
+    /// Advertise again after [`suspend`](Self::suspend)
+    ///
+    /// Without a suspension the advertisement is announced afresh, for a
+    /// sleep noticed only after waking, when peers may have expired it.
+    pub fn resume(&mut self) -> Result<()> {
+        let Some(service_info) = self.service.clone() else {
+            return Ok(());
+        };
+        self.daemon
+            .register(service_info)
+            .map_err(|e| ConnectoError::Discovery(format!("Failed to register service: {}", e)))?;
+        self.suspended = false;
+        info!("Resumed advertising service");
+        Ok(())
+    }
+
+    /// Whether the advertisement is suspended
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
     /// Stop advertising
     pub fn stop(&mut self) -> Result<()> {
+        self.service = None;
         if let Some(fullname) = self.service_fullname.take() {
+            if std::mem::take(&mut self.suspended) {
+                return Ok(());
+            }
             // Ignore errors during unregister - the daemon may already be shut down
             // This is expected during normal shutdown and shouldn't be treated as an error
             let _ = self.daemon.unregister(&fullname);
@@ -128,7 +443,7 @@ impl Drop for ServiceAdvertiser {
 /// Service browser for discovering other devices
 pub struct ServiceBrowser {
     daemon: ServiceDaemon,
-    devices: Arc<Mutex<HashMap<String, DiscoveredDevice>>>,
+    devices: Arc<Mutex<DeviceStore>>,
 }
 
 impl ServiceBrowser {
@@ -140,10 +455,17 @@ impl ServiceBrowser {
 
         Ok(Self {
             daemon,
-            devices: Arc::new(Mutex::new(HashMap::new())),
+            devices: Arc::new(Mutex::new(DeviceStore::default())),
         })
     }
 
+    /// Remember at most `capacity` devices, forgetting the ones seen least
+    /// recently first
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        *self.devices.lock().unwrap() = DeviceStore::new(capacity);
+        self
+    }
+
     /// Start browsing for devices
     pub fn browse(&self) -> Result<mpsc::Receiver<DiscoveryEvent>> {
         let receiver = self
@@ -160,22 +482,47 @@ impl ServiceBrowser {
             while let Ok(event) = receiver.recv() {
                 match event {
                     ServiceEvent::ServiceResolved(info) => {
+                        // The hostname of a private device is a throwaway pseudonym
+                        let hostname = if info.get_property(PRIVATE_PROPERTY).is_some() {
+                            String::new()
+                        } else {
+                            info.get_hostname().to_string()
+                        };
+                        let addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+                        // mDNS doesn't say which interface an answer came in on
+                        let scope = if addresses.iter().any(net::is_link_local) {
+                            net::default_link_local_scope()
+                        } else {
+                            None
+                        };
                         let device = DiscoveredDevice {
                             name: info.get_fullname().to_string(),
-                            hostname: info.get_hostname().to_string(),
-                            addresses: info.get_addresses().iter().copied().collect(),
+                            hostname,
+                            addresses,
                             port: info.get_port(),
                             instance_name: info.get_fullname().to_string(),
+                            identity: info
+                                .get_property_val_str(IDENTITY_PROPERTY)
+                                .map(str::to_string),
+                            scope,
+                            external: info
+                                .get_property_val_str(EXTERNAL_PROPERTY)
+                                .and_then(|external| external.parse().ok()),
+                            os: info.get_property_val_str(OS_PROPERTY).map(str::to_string),
+                            version: info
+                                .get_property_val_str(VERSION_PROPERTY)
+                                .map(str::to_string),
+                            latency: None,
                         };
 
                         debug!("Discovered device: {:?}", device);
 
-                        {
-                            let mut devs = devices.lock().unwrap();
-                            devs.insert(device.instance_name.clone(), device.clone());
+                        let stored = devices.lock().unwrap().insert(device.clone());
+                        if stored.evicted.is_some() {
+                            debug!("Device store full, forgot the device seen least recently");
                         }
 
-                        let event = DiscoveryEvent::DeviceFound(device);
+                        let event = DiscoveryEvent::DeviceFound(Box::new(device));
                         if let Some(ref handle) = rt {
                             let tx = tx.clone();
                             handle.spawn(async move {
@@ -187,10 +534,7 @@ impl ServiceBrowser {
                         }
                     }
                     ServiceEvent::ServiceRemoved(_, fullname) => {
-                        {
-                            let mut devs = devices.lock().unwrap();
-                            devs.remove(&fullname);
-                        }
+                        devices.lock().unwrap().remove(&fullname);
 
                         let event = DiscoveryEvent::DeviceLost(fullname);
                         if let Some(ref handle) = rt {
@@ -219,40 +563,98 @@ impl ServiceBrowser {
         Ok(rx)
     }
 
-    /// Get currently discovered devices
+    /// Stop browsing and shut the mDNS daemon down
+    ///
+    /// The receiver from [`ServiceBrowser::browse`] gets
+    /// [`DiscoveryEvent::SearchStopped`] and then closes.
+    pub fn stop(&self) -> Result<()> {
+        self.daemon
+            .stop_browse(SERVICE_TYPE)
+            .map_err(|e| ConnectoError::Discovery(format!("Failed to stop browsing: {}", e)))?;
+        // The daemon may already be shut down
+        let _ = self.daemon.shutdown();
+        Ok(())
+    }
+
+    /// Get currently discovered devices, in the order they were found
     pub fn get_devices(&self) -> Vec<DiscoveredDevice> {
         let devices = self.devices.lock().unwrap();
-        devices.values().cloned().collect()
+        devices.devices().into_iter().map(|(_, d)| d).collect()
+    }
+
+    /// How many devices the browser remembers, and the memory they take
+    pub fn stats(&self) -> StoreStats {
+        self.devices.lock().unwrap().stats()
     }
 
     /// Scan for devices for a specified duration
     pub async fn scan_for_duration(&self, duration: Duration) -> Result<Vec<DiscoveredDevice>> {
-        let mut rx = self.browse()?;
+        self.scan_until(duration, EarlyExit::default()).await
+    }
 
-        let timeout = tokio::time::sleep(duration);
-        tokio::pin!(timeout);
+    /// Scan for devices for up to `duration`, stopping early as `early_exit`
+    /// allows
+    pub async fn scan_until(
+        &self,
+        duration: Duration,
+        early_exit: EarlyExit,
+    ) -> Result<Vec<DiscoveredDevice>> {
+        let rx = self.browse()?;
+        let started = Instant::now();
+        let stop = wait_for_devices(rx, duration, early_exit).await;
+        debug!(
+            "mDNS scan ended after {} ms: {:?}",
+            started.elapsed().as_millis(),
+            stop
+        );
 
-        loop {
-            tokio::select! {
-                _ = &mut timeout => {
-                    break;
-                }
-                event = rx.recv() => {
-                    match event {
-                        Some(DiscoveryEvent::DeviceFound(device)) => {
-                            debug!("Found device during scan: {}", device.name);
-                        }
-                        Some(DiscoveryEvent::SearchStopped) => {
-                            break;
-                        }
-                        None => break,
-                        _ => {}
+        let stats = self.stats();
+        debug!(
+            "Scan kept {} of at most {} devices ({} forgotten, ~{} bytes)",
+            stats.devices, stats.capacity, stats.evicted, stats.bytes
+        );
+        Ok(self.get_devices())
+    }
+}
+
+/// Follow `rx` until `duration` is up or `early_exit` says enough was found
+async fn wait_for_devices(
+    mut rx: mpsc::Receiver<DiscoveryEvent>,
+    duration: Duration,
+    early_exit: EarlyExit,
+) -> ScanStop {
+    let deadline = tokio::time::Instant::now() + duration;
+    let timeout = tokio::time::sleep_until(deadline);
+    // Armed by the first device, and pushed back by every new one
+    let quiet = tokio::time::sleep_until(deadline);
+    tokio::pin!(timeout, quiet);
+
+    // A device is found again whenever it re-announces itself
+    let mut found = HashSet::new();
+    loop {
+        tokio::select! {
+            biased;
+            _ = &mut timeout => return ScanStop::TimedOut,
+            _ = &mut quiet, if !found.is_empty() => return ScanStop::Quiet,
+            event = rx.recv() => match event {
+                Some(DiscoveryEvent::DeviceFound(device)) => {
+                    debug!("Found device during scan: {}", device.name);
+                    if !found.insert(device.instance_name) {
+                        continue;
+                    }
+                    if early_exit.min_devices.is_some_and(|min| found.len() >= min) {
+                        return ScanStop::EnoughDevices;
+                    }
+                    if let Some(period) = early_exit.quiet_period {
+                        quiet
+                            .as_mut()
+                            .reset(deadline.min(tokio::time::Instant::now() + period));
                     }
                 }
+                Some(DiscoveryEvent::SearchStopped) | None => return ScanStop::Stopped,
+                _ => {}
             }
         }
-
-        Ok(self.get_devices())
     }
 }
 
@@ -263,6 +665,104 @@ pub fn get_hostname() -> String {
         .unwrap_or_else(|_| "unknown".to_string())
 }
 
+/// Get the name people know this device by
+///
+/// This is the Computer Name on macOS, the pretty hostname (as set with
+/// `hostnamectl set-hostname --pretty`) on Linux, and the computer name on
+/// Windows. Without one, the hostname is used without its domain, so
+/// `Johns-MBP.localdomain` becomes `Johns-MBP`.
+pub fn get_device_name() -> String {
+    friendly_name().unwrap_or_else(|| short_hostname(&get_hostname()))
+}
+
+#[cfg(target_os = "macos")]
+fn friendly_name() -> Option<String> {
+    command_output("scutil", &["--get", "ComputerName"])
+}
+
+#[cfg(target_os = "linux")]
+fn friendly_name() -> Option<String> {
+    // hostnamectl keeps the pretty hostname here
+    std::fs::read_to_string("/etc/machine-info")
+        .ok()
+        .and_then(|content| parse_pretty_hostname(&content))
+}
+
+#[cfg(target_os = "windows")]
+fn friendly_name() -> Option<String> {
+    let output = command_output(
+        "reg",
+        &[
+            "query",
+            r"HKLM\SYSTEM\CurrentControlSet\Control\ComputerName\ComputerName",
+            "/v",
+            "ComputerName",
+        ],
+    )?;
+    parse_reg_value(&output)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+fn friendly_name() -> Option<String> {
+    None
+}
+
+/// Trimmed stdout of a command, if it succeeded and printed anything
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+/// `PRETTY_HOSTNAME` from the contents of `/etc/machine-info`
+#[cfg(any(target_os = "linux", test))]
+fn parse_pretty_hostname(machine_info: &str) -> Option<String> {
+    let value = machine_info
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("PRETTY_HOSTNAME="))?;
+    let value = value.trim();
+    let value = ['"', '\'']
+        .iter()
+        .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+        .unwrap_or(value)
+        .replace("\\\"", "\"");
+    (!value.is_empty()).then_some(value)
+}
+
+/// The data of a `REG_SZ` value in `reg query` output
+#[cfg(any(target_os = "windows", test))]
+fn parse_reg_value(output: &str) -> Option<String> {
+    let value = output
+        .lines()
+        .find_map(|line| line.split_once("REG_SZ"))?
+        .1
+        .trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// A hostname without its domain
+fn short_hostname(hostname: &str) -> String {
+    match hostname.split('.').next() {
+        Some(short) if !short.is_empty() => short.to_string(),
+        _ => hostname.to_string(),
+    }
+}
+
+/// Generate a random device name for privacy mode, e.g. `connecto-3fa9c2`
+///
+/// A new one is generated each time, so a device using pseudonyms cannot be
+/// followed from one listening session to the next.
+pub fn generate_pseudonym() -> String {
+    format!("connecto-{:06x}", rand::random::<u32>() & 0xff_ffff)
+}
+
 /// Get local IP addresses
 pub fn get_local_addresses() -> Vec<IpAddr> {
     let mut addresses = Vec::new();
@@ -278,16 +778,106 @@ pub fn get_local_addresses() -> Vec<IpAddr> {
     addresses
 }
 
+/// Check that an mDNS daemon can be started, which advertising and browsing
+/// both need
+///
+/// The daemon is shut down again at once.
+pub fn check_mdns_daemon() -> Result<()> {
+    let daemon = ServiceDaemon::new()
+        .map_err(|e| ConnectoError::Discovery(format!("Failed to create mDNS daemon: {}", e)))?;
+    // Waiting for the answer keeps the daemon from logging that nobody took it
+    if let Ok(status) = daemon.shutdown() {
+        let _ = status.recv_timeout(Duration::from_secs(1));
+    }
+    Ok(())
+}
+
+/// Check that `interface` can join the mDNS multicast group of its IP
+/// version, without which nothing announced on its network is seen
+///
+/// Interfaces that are down, or that a VPN or the platform keeps out of
+/// multicast, fail here.
+pub fn check_multicast(interface: &InterfaceAddress) -> Result<()> {
+    let joined = match interface.address {
+        IpAddr::V4(address) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| socket.join_multicast_v4(&MDNS_GROUP_V4, &address)),
+        IpAddr::V6(_) => {
+            let index = net::scope_index(&interface.name)?;
+            UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+                .and_then(|socket| socket.join_multicast_v6(&MDNS_GROUP_V6, index))
+        }
+    };
+    joined.map_err(|e| {
+        ConnectoError::Discovery(format!(
+            "Cannot join the mDNS group on {}: {}",
+            interface, e
+        ))
+    })
+}
+
+/// How far a subnet scan has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanProgress {
+    /// Hosts probed so far
+    pub scanned: usize,
+    /// Hosts in the scan
+    pub total: usize,
+    /// Connecto devices found so far
+    pub found: usize,
+}
+
 /// Subnet scanner for when mDNS is blocked (corporate networks)
 pub struct SubnetScanner {
     port: u16,
     timeout: Duration,
+    concurrency: usize,
+    rate_limit: Option<u32>,
+    progress: Option<mpsc::Sender<ScanProgress>>,
+    retry: RetryPolicy,
 }
 
 impl SubnetScanner {
-    /// Create a new subnet scanner
+    /// Create a new subnet scanner, waiting up to `timeout` for each host
     pub fn new(port: u16, timeout: Duration) -> Self {
-        Self { port, timeout }
+        Self {
+            port,
+            timeout,
+            concurrency: DEFAULT_SCAN_CONCURRENCY,
+            rate_limit: None,
+            progress: None,
+            retry: RetryPolicy::none(),
+        }
+    }
+
+    /// Probe up to `concurrency` hosts at the same time (at least one)
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Start at most `per_second` probes a second
+    ///
+    /// Keeps large scans from tripping intrusion detection or flooding slow
+    /// links.
+    pub fn with_rate_limit(mut self, per_second: u32) -> Self {
+        self.rate_limit = Some(per_second.max(1));
+        self
+    }
+
+    /// Report progress on `progress` after every probed host
+    pub fn with_progress(mut self, progress: mpsc::Sender<ScanProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Retry hosts that do not answer as `policy` says; each host is tried
+    /// once by default
+    ///
+    /// Hosts that refuse the connection are not retried, so this mostly
+    /// slows down the addresses nobody uses.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
     }
 
     /// Scan specific subnets provided in CIDR notation (e.g., "10.105.225.0/24")
@@ -440,29 +1030,94 @@ impl SubnetScanner {
 
         let port = self.port;
         let timeout = self.timeout;
+        let retry = self.retry;
+        let limiter = self.rate_limit.map(TokenBucket::new);
+        let limiter = limiter.as_ref();
+
+        let mut progress = ScanProgress {
+            scanned: 0,
+            total: ips.len(),
+            found: 0,
+        };
+        self.report(progress).await;
 
-        // Scan with concurrency limit of 100
-        let results: Vec<Option<DiscoveredDevice>> = stream::iter(ips)
-            .map(|ip| async move { Self::probe_host(ip, port, timeout).await })
-            .buffer_unordered(100)
-            .collect()
-            .await;
+        let mut probes = stream::iter(ips)
+            .map(|ip| async move {
+                if let Some(limiter) = limiter {
+                    limiter.acquire().await;
+                }
+                Self::probe_host(ip, port, timeout, retry).await
+            })
+            .buffer_unordered(self.concurrency);
+
+        let mut devices = Vec::new();
+        while let Some(result) = probes.next().await {
+            devices.extend(result);
+            progress.scanned += 1;
+            progress.found = devices.len();
+            self.report(progress).await;
+        }
+        devices
+    }
 
-        results.into_iter().flatten().collect()
+    async fn report(&self, progress: ScanProgress) {
+        if let Some(tx) = &self.progress {
+            let _ = tx.send(progress).await;
+        }
     }
 
     /// Probe a single host to check if it's running connecto
-    async fn probe_host(ip: Ipv4Addr, port: u16, timeout: Duration) -> Option<DiscoveredDevice> {
+    async fn probe_host(
+        ip: Ipv4Addr,
+        port: u16,
+        timeout: Duration,
+        retry: RetryPolicy,
+    ) -> Option<DiscoveredDevice> {
         let addr = SocketAddr::new(IpAddr::V4(ip), port);
-
-        // Try to connect with timeout
-        let stream = match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
-            Ok(Ok(stream)) => stream,
-            _ => return None,
+        // Connecting takes one round trip, so it doubles as a ping
+        let connect = || async {
+            let started = Instant::now();
+            match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => Ok((stream, started.elapsed())),
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    Err(ConnectoError::ConnectionRefused(e.to_string()))
+                }
+                Ok(Err(e)) => Err(ConnectoError::Network(e.to_string())),
+                Err(_) => Err(ConnectoError::Timeout(format!("{} did not answer", addr))),
+            }
+        };
+        let on_retry = |retry: &Retry| {
+            debug!(
+                "Probe {}/{} of {} failed: {}; retrying in {:.1}s",
+                retry.attempt,
+                retry.attempts,
+                addr,
+                retry.reason,
+                retry.delay.as_secs_f64()
+            );
         };
 
-        // Try to get device info via protocol handshake
-        match Self::identify_device(stream, ip, port).await {
+        // Listeners asking for a verification code turn away the oldest
+        // version, and listeners from before negotiation only know it
+        let mut result = Err(ConnectoError::Protocol("Not probed".to_string()));
+        for version in [PROTOCOL_VERSION, MIN_PROTOCOL_VERSION] {
+            let (stream, latency) = match retry.run(connect, on_retry).await {
+                Ok(connected) => connected,
+                Err(_) => return None,
+            };
+
+            // Try to get device info via protocol handshake
+            result = Self::identify_device(stream, ip, port, version)
+                .await
+                .map(|device| DiscoveredDevice {
+                    latency: Some(latency),
+                    ..device
+                });
+            if !matches!(result, Err(ConnectoError::Protocol(_))) {
+                break;
+            }
+        }
+        match result {
             Ok(device) => {
                 info!("Found connecto device at {}: {}", addr, device.name);
                 Some(device)
@@ -476,14 +1131,21 @@ impl SubnetScanner {
         mut stream: TcpStream,
         ip: Ipv4Addr,
         port: u16,
+        version: u32,
     ) -> Result<DiscoveredDevice> {
         let (reader, mut writer) = stream.split();
         let mut reader = BufReader::new(reader);
 
         // Send Hello message
         let hello = Message::Hello {
-            version: 1,
+            version,
             device_name: format!("scanner-{}", std::process::id()),
+            timestamp: None,
+            min_version: None,
+            capabilities: None,
+            identity: None,
+            nonce: None,
+            key_share: None,
         };
         writer
             .write_all(hello.to_json()?.as_bytes())
@@ -496,7 +1158,8 @@ impl SubnetScanner {
 
         // Read HelloAck response
         let mut line = String::new();
-        tokio::time::timeout(Duration::from_secs(2), reader.read_line(&mut line))
+        let read = Framing::Lines.read(&mut reader, &mut line, DEFAULT_MAX_MESSAGE_LEN);
+        tokio::time::timeout(Duration::from_secs(2), read)
             .await
             .map_err(|_| ConnectoError::Timeout("Timed out waiting for response".to_string()))?
             .map_err(|e| ConnectoError::Network(e.to_string()))?;
@@ -505,12 +1168,22 @@ impl SubnetScanner {
             .map_err(|e| ConnectoError::Protocol(format!("Invalid response: {}", e)))?;
 
         match response {
-            Message::HelloAck { device_name, .. } => Ok(DiscoveredDevice {
+            Message::HelloAck {
+                device_name,
+                identity,
+                ..
+            } => Ok(DiscoveredDevice {
                 name: device_name.clone(),
                 hostname: format!("{}.local.", device_name.to_lowercase().replace(' ', "-")),
                 addresses: vec![IpAddr::V4(ip)],
                 port,
                 instance_name: format!("{}._connecto._tcp.local.", device_name),
+                identity,
+                scope: None,
+                external: None,
+                os: None,
+                version: None,
+                latency: None,
             }),
             Message::Error { message, .. } => Err(ConnectoError::Protocol(message)),
             _ => Err(ConnectoError::Protocol("Unexpected response".to_string())),
@@ -518,9 +1191,52 @@ impl SubnetScanner {
     }
 }
 
+/// Token bucket spacing out subnet scan probes
+///
+/// Holds up to a tenth of a second's worth of tokens, so probes start in
+/// small bursts rather than all at once.
+struct TokenBucket {
+    per_second: f64,
+    capacity: f64,
+    /// Tokens available and when they were last topped up
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(per_second: u32) -> Self {
+        let per_second = f64::from(per_second.max(1));
+        let capacity = (per_second / 10.0).max(1.0);
+        Self {
+            per_second,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Wait for a token and take it
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                let refill = now.duration_since(state.1).as_secs_f64() * self.per_second;
+                *state = ((state.0 + refill).min(self.capacity), now);
+                if state.0 >= 1.0 {
+                    state.0 -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - state.0) / self.per_second)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::ProtocolErrorCode;
+    use tokio::io::AsyncBufReadExt;
 
     #[test]
     fn test_service_type_constant() {
@@ -532,6 +1248,79 @@ mod tests {
         assert_eq!(DEFAULT_PORT, 8099);
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_scan_early_exit() {
+        fn found(name: &str) -> DiscoveryEvent {
+            DiscoveryEvent::DeviceFound(Box::new(DiscoveredDevice {
+                name: name.to_string(),
+                hostname: String::new(),
+                addresses: vec![],
+                port: DEFAULT_PORT,
+                instance_name: format!("{}._connecto._tcp.local.", name),
+                identity: None,
+                scope: None,
+                external: None,
+                os: None,
+                version: None,
+                latency: None,
+            }))
+        }
+
+        // Devices answer 300 ms, 500 ms and 500 ms (again) into the scan
+        async fn scan(early_exit: EarlyExit) -> (ScanStop, Duration) {
+            let (tx, rx) = mpsc::channel(8);
+            let started = tokio::time::Instant::now();
+            tokio::spawn(async move {
+                for (at, name) in [(300, "desk"), (500, "laptop"), (500, "desk")] {
+                    tokio::time::sleep_until(started + Duration::from_millis(at)).await;
+                    let _ = tx.send(found(name)).await;
+                }
+                // Keep the channel open until the scan is over
+                tx.closed().await;
+            });
+            let stop = wait_for_devices(rx, Duration::from_secs(5), early_exit).await;
+            (stop, started.elapsed())
+        }
+
+        let (stop, elapsed) = scan(EarlyExit::default()).await;
+        assert_eq!(stop, ScanStop::TimedOut);
+        assert_eq!(elapsed, Duration::from_secs(5));
+
+        let (stop, elapsed) = scan(EarlyExit::default().with_min_devices(2)).await;
+        assert_eq!(stop, ScanStop::EnoughDevices);
+        assert_eq!(elapsed, Duration::from_millis(500));
+
+        // The repeated announcement does not count as a new device
+        let (stop, elapsed) = scan(EarlyExit::default().with_min_devices(3)).await;
+        assert_eq!(stop, ScanStop::TimedOut);
+        assert_eq!(elapsed, Duration::from_secs(5));
+
+        let quiet = EarlyExit::default().with_quiet_period(Duration::from_secs(1));
+        let (stop, elapsed) = scan(quiet).await;
+        assert_eq!(stop, ScanStop::Quiet);
+        assert_eq!(elapsed, Duration::from_millis(1500));
+
+        // Nothing found, so there is no quiet period to wait out
+        let (tx, rx) = mpsc::channel(1);
+        let started = tokio::time::Instant::now();
+        assert_eq!(
+            wait_for_devices(rx, Duration::from_secs(5), quiet).await,
+            ScanStop::TimedOut
+        );
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        drop(tx);
+    }
+
+    #[test]
+    fn test_generate_pseudonym() {
+        let pseudonym = generate_pseudonym();
+        assert!(pseudonym.starts_with("connecto-"));
+        assert_eq!(pseudonym.len(), "connecto-".len() + 6);
+        assert!(pseudonym["connecto-".len()..]
+            .chars()
+            .all(|c| c.is_ascii_hexdigit()));
+    }
+
     #[test]
     fn test_discovered_device_creation() {
         let device = DiscoveredDevice {
@@ -540,6 +1329,12 @@ mod tests {
             addresses: vec!["192.168.1.100".parse().unwrap()],
             port: 8099,
             instance_name: "test-instance".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
         assert_eq!(device.name, "Test Device");
@@ -558,6 +1353,12 @@ mod tests {
             ],
             port: 8099,
             instance_name: "test".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
         let primary = device.primary_address().unwrap();
@@ -573,6 +1374,12 @@ mod tests {
             addresses: vec!["::1".parse().unwrap()],
             port: 8099,
             instance_name: "test".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
         let primary = device.primary_address().unwrap();
@@ -587,6 +1394,12 @@ mod tests {
             addresses: vec!["192.168.1.100".parse().unwrap()],
             port: 8099,
             instance_name: "test".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
         assert_eq!(
@@ -595,6 +1408,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_connection_string_link_local() {
+        let mut device = DiscoveredDevice {
+            name: "Test".to_string(),
+            hostname: "test.local.".to_string(),
+            addresses: vec!["fe80::1".parse().unwrap()],
+            port: 8099,
+            instance_name: "test".to_string(),
+            identity: None,
+            scope: Some("en0".to_string()),
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        };
+
+        assert_eq!(
+            device.connection_string(),
+            Some("[fe80::1%en0]:8099".to_string())
+        );
+        assert_eq!(device.primary_host(), Some("fe80::1%en0".to_string()));
+
+        // A routable IPv6 address is preferred and needs no scope
+        device.addresses.push("2001:db8::1".parse().unwrap());
+        assert_eq!(
+            device.connection_string(),
+            Some("[2001:db8::1]:8099".to_string())
+        );
+
+        device.addresses = vec!["fe80::1".parse().unwrap()];
+        device.scope = None;
+        assert_eq!(
+            device.connection_string(),
+            Some("[fe80::1]:8099".to_string())
+        );
+    }
+
     #[test]
     fn test_connection_string_empty_addresses() {
         let device = DiscoveredDevice {
@@ -603,11 +1453,110 @@ mod tests {
             addresses: vec![],
             port: 8099,
             instance_name: "test".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
         assert_eq!(device.connection_string(), None);
     }
 
+    #[test]
+    fn test_mdns_hostname() {
+        let mut device = DiscoveredDevice {
+            name: "Test".to_string(),
+            hostname: "desk-pc.local.".to_string(),
+            addresses: vec!["192.168.1.100".parse().unwrap()],
+            port: 8099,
+            instance_name: "test".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        };
+        assert_eq!(device.mdns_hostname(), Some("desk-pc.local"));
+
+        device.hostname = String::new();
+        assert_eq!(device.mdns_hostname(), None);
+    }
+
+    #[test]
+    fn test_device_name() {
+        let mut device = DiscoveredDevice {
+            name: "My Mac (2) (desk-pc)._connecto._tcp.local.".to_string(),
+            hostname: "desk-pc.local.".to_string(),
+            addresses: vec![],
+            port: 8099,
+            instance_name: "My Mac (2) (desk-pc)._connecto._tcp.local.".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        };
+        assert_eq!(device.device_name(), "My Mac (2)");
+
+        // Subnet probes and private listeners report the bare name
+        device.name = "My Mac (2)".to_string();
+        device.hostname = "my-mac-(2).local.".to_string();
+        assert_eq!(device.device_name(), "My Mac (2)");
+        device.hostname = String::new();
+        assert_eq!(device.device_name(), "My Mac (2)");
+    }
+
+    #[test]
+    fn test_merge_devices() {
+        let device = |name: &str, addresses: &[&str], identity: Option<&str>| DiscoveredDevice {
+            name: name.to_string(),
+            hostname: format!("{}.local.", name.to_lowercase()),
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            port: 8099,
+            instance_name: name.to_string(),
+            identity: identity.map(str::to_string),
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        };
+
+        let merged = merge_devices([
+            // mDNS
+            device("Study (desk-pc)", &["fe80::1", "192.168.1.20"], None),
+            device("Laptop (mbp)", &["192.168.1.30"], Some("SHA256:laptop")),
+            // Subnet probes
+            device("Study", &["192.168.1.20"], Some("SHA256:study")),
+            device("Laptop", &["10.8.0.30"], Some("SHA256:laptop")),
+            device("Printer", &["192.168.1.40"], None),
+        ]);
+
+        let names: Vec<_> = merged.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["Study (desk-pc)", "Laptop (mbp)", "Printer"]);
+        assert_eq!(merged[0].identity.as_deref(), Some("SHA256:study"));
+        assert_eq!(merged[0].addresses.len(), 2);
+        assert_eq!(
+            merged[1].addresses,
+            vec![
+                "192.168.1.30".parse::<IpAddr>().unwrap(),
+                "10.8.0.30".parse().unwrap()
+            ]
+        );
+
+        // Another listener on the same machine, or another identity behind
+        // the same address, is kept apart
+        let mut other_port = device("Study", &["192.168.1.20"], None);
+        other_port.port = 9100;
+        assert!(!merged[0].is_same_device(&other_port));
+        let replaced = device("Study", &["192.168.1.20"], Some("SHA256:new"));
+        assert!(!merged[0].is_same_device(&replaced));
+    }
+
     #[test]
     fn test_discovered_device_equality() {
         let device1 = DiscoveredDevice {
@@ -616,6 +1565,12 @@ mod tests {
             addresses: vec!["192.168.1.100".parse().unwrap()],
             port: 8099,
             instance_name: "test".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
         let device2 = device1.clone();
@@ -630,6 +1585,12 @@ mod tests {
             addresses: vec!["192.168.1.100".parse().unwrap()],
             port: 8099,
             instance_name: "test-instance".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
         let json = serde_json::to_string(&device).unwrap();
@@ -638,12 +1599,167 @@ mod tests {
         assert_eq!(device, deserialized);
     }
 
+    #[tokio::test]
+    async fn test_token_bucket_spaces_out_probes() {
+        // 100/s with bursts of 10: the other 20 tokens take about 200ms
+        let bucket = TokenBucket::new(100);
+        let start = Instant::now();
+        for _ in 0..30 {
+            bucket.acquire().await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(180), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_subnet_scan_reports_progress() {
+        // A listener on 127.0.0.1 only; the rest of 127.0.0.0/8 refuses
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.split();
+            let mut line = String::new();
+            BufReader::new(reader).read_line(&mut line).await.unwrap();
+            let ack = Message::HelloAck {
+                version: MIN_PROTOCOL_VERSION,
+                device_name: "Desk".to_string(),
+                verification_code: None,
+                identity: None,
+                pin_required: false,
+                timestamp: None,
+                capabilities: None,
+                users: Vec::new(),
+                identity_proof: None,
+                nonce: None,
+                key_share: None,
+            };
+            let ack = serde_json::to_string(&ack).unwrap() + "\n";
+            writer.write_all(ack.as_bytes()).await.unwrap();
+        });
+
+        let (tx, mut rx) = mpsc::channel(64);
+        let scanner = SubnetScanner::new(port, Duration::from_millis(200))
+            .with_concurrency(2)
+            .with_rate_limit(1000)
+            .with_progress(tx);
+        let ips: Vec<Ipv4Addr> = (1..=4).map(|last| Ipv4Addr::new(127, 0, 0, last)).collect();
+        let devices = scanner.scan_ips(ips).await;
+        drop(scanner);
+
+        let mut events = Vec::new();
+        while let Some(progress) = rx.recv().await {
+            events.push(progress);
+        }
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "Desk");
+        assert_eq!(events.len(), 5);
+        assert_eq!(
+            events[0],
+            ScanProgress {
+                scanned: 0,
+                total: 4,
+                found: 0
+            }
+        );
+        assert_eq!(
+            events[4],
+            ScanProgress {
+                scanned: 4,
+                total: 4,
+                found: 1
+            }
+        );
+        assert!(events.windows(2).all(|w| w[0].scanned < w[1].scanned));
+    }
+
+    #[tokio::test]
+    async fn test_subnet_scan_falls_back_to_oldest_version() {
+        // A listener from before version negotiation only answers its own
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.split();
+                let mut line = String::new();
+                BufReader::new(reader).read_line(&mut line).await.unwrap();
+                let reply = match Message::from_json(&line).unwrap() {
+                    Message::Hello { version, .. } if version == MIN_PROTOCOL_VERSION => {
+                        Message::HelloAck {
+                            version,
+                            device_name: "Old Desk".to_string(),
+                            verification_code: None,
+                            identity: None,
+                            pin_required: false,
+                            timestamp: None,
+                            capabilities: None,
+                            users: Vec::new(),
+                            identity_proof: None,
+                            nonce: None,
+                            key_share: None,
+                        }
+                    }
+                    _ => Message::Error {
+                        code: ProtocolErrorCode::VersionMismatch,
+                        message: "Protocol version mismatch".to_string(),
+                    },
+                };
+                let reply = serde_json::to_string(&reply).unwrap() + "\n";
+                writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+
+        let scanner = SubnetScanner::new(port, Duration::from_millis(200));
+        let devices = scanner.scan_ips(vec![Ipv4Addr::LOCALHOST]).await;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "Old Desk");
+        // Timed by the connection the device answered on
+        assert!(devices[0].latency.unwrap() < Duration::from_millis(200));
+    }
+
     #[test]
     fn test_get_hostname() {
         let hostname = get_hostname();
         assert!(!hostname.is_empty());
     }
 
+    #[test]
+    fn test_get_device_name() {
+        assert!(!get_device_name().is_empty());
+
+        assert_eq!(short_hostname("Johns-MBP.localdomain"), "Johns-MBP");
+        assert_eq!(short_hostname("desk"), "desk");
+        assert_eq!(short_hostname(".hidden"), ".hidden");
+    }
+
+    #[test]
+    fn test_parse_pretty_hostname() {
+        let machine_info = "CHASSIS=laptop\nPRETTY_HOSTNAME=\"John's \\\"Work\\\" Laptop\"\n";
+        assert_eq!(
+            parse_pretty_hostname(machine_info).as_deref(),
+            Some("John's \"Work\" Laptop")
+        );
+        assert_eq!(
+            parse_pretty_hostname("PRETTY_HOSTNAME='Desk'").as_deref(),
+            Some("Desk")
+        );
+        assert_eq!(
+            parse_pretty_hostname("PRETTY_HOSTNAME=Desk").as_deref(),
+            Some("Desk")
+        );
+        assert_eq!(parse_pretty_hostname("PRETTY_HOSTNAME=\"\""), None);
+        assert_eq!(parse_pretty_hostname("CHASSIS=desktop"), None);
+    }
+
+    #[test]
+    fn test_parse_reg_value() {
+        let output = "\r\nHKEY_LOCAL_MACHINE\\SYSTEM\\CurrentControlSet\\Control\\ComputerName\\ComputerName\r\n    ComputerName    REG_SZ    JOHNS-DESKTOP\r\n\r\n";
+        assert_eq!(parse_reg_value(output).as_deref(), Some("JOHNS-DESKTOP"));
+        assert_eq!(parse_reg_value("ERROR: not found"), None);
+    }
+
     #[test]
     fn test_discovery_event_variants() {
         let device = DiscoveredDevice {
@@ -652,9 +1768,15 @@ mod tests {
             addresses: vec![],
             port: 8099,
             instance_name: "test".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
-        let event1 = DiscoveryEvent::DeviceFound(device);
+        let event1 = DiscoveryEvent::DeviceFound(Box::new(device));
         let event2 = DiscoveryEvent::DeviceLost("test".to_string());
         let event3 = DiscoveryEvent::SearchStarted;
         let event4 = DiscoveryEvent::SearchStopped;
@@ -742,10 +1864,47 @@ mod tests {
         assert!(advertiser.is_ok());
     }
 
+    #[tokio::test]
+    #[ignore] // Run manually with: cargo test -- --ignored
+    async fn test_service_advertiser_suspend_resume() {
+        let mut advertiser = ServiceAdvertiser::new().unwrap();
+        // Nothing to suspend before advertising
+        advertiser.suspend().unwrap();
+        assert!(!advertiser.is_suspended());
+
+        advertiser.advertise("Test", DEFAULT_PORT).unwrap();
+        advertiser.suspend().unwrap();
+        assert!(advertiser.is_suspended());
+        advertiser.resume().unwrap();
+        assert!(!advertiser.is_suspended());
+        // Announcing again while advertising is fine too
+        advertiser.resume().unwrap();
+        advertiser.stop().unwrap();
+        advertiser.resume().unwrap();
+        assert!(!advertiser.is_suspended());
+    }
+
     #[tokio::test]
     #[ignore] // Run manually with: cargo test -- --ignored
     async fn test_service_browser_creation() {
         let browser = ServiceBrowser::new();
         assert!(browser.is_ok());
     }
+
+    #[tokio::test]
+    #[ignore] // Run manually with: cargo test -- --ignored
+    async fn test_service_browser_stop_ends_events() {
+        let browser = ServiceBrowser::new().unwrap();
+        let mut rx = browser.browse().unwrap();
+        browser.stop().unwrap();
+
+        let drained = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut stopped = false;
+            while let Some(event) = rx.recv().await {
+                stopped |= matches!(event, DiscoveryEvent::SearchStopped);
+            }
+            stopped
+        });
+        assert!(drained.await.expect("the event stream closes"));
+    }
 }
@@ -1,5 +1,6 @@
 //! Error types for Connecto
 
+use crate::ports::PortOwner;
 use thiserror::Error;
 
 /// Main error type for Connecto operations
@@ -17,12 +18,24 @@ pub enum ConnectoError {
     #[error("Network error: {0}")]
     Network(String),
 
+    #[error("Connection refused: {0}")]
+    ConnectionRefused(String),
+
     #[error("Handshake error: {0}")]
     Handshake(String),
 
     #[error("Protocol error: {0}")]
     Protocol(String),
 
+    #[error("Version mismatch: {0}")]
+    VersionMismatch(String),
+
+    #[error("Rejected: {0}")]
+    Rejected(String),
+
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
@@ -49,6 +62,75 @@ pub enum ConnectoError {
 
     #[error("Sync with self: cannot sync a device with itself")]
     SyncWithSelf,
+
+    #[error("Identity mismatch: {0}")]
+    IdentityMismatch(String),
+
+    #[error("Verification failed: {0}")]
+    VerificationFailed(String),
+
+    #[error("Key already exists: {0}")]
+    KeyExists(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Decision log error: {0}")]
+    DecisionLog(String),
+
+    #[error("Audit log error: {0}")]
+    AuditLog(String),
+
+    #[error("Pairing already in progress: {0}")]
+    PairingInProgress(String),
+
+    #[error("Relay error: {0}")]
+    Relay(String),
+
+    #[error("Keep-warm error: {0}")]
+    KeepWarm(String),
+
+    #[error("Transfer error: {0}")]
+    Transfer(String),
+
+    #[error("Access list error: {0}")]
+    Access(String),
+
+    #[error("Key import error: {0}")]
+    KeyImport(String),
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Firewall error: {0}")]
+    Firewall(String),
+
+    #[error("Port mapping error: {0}")]
+    PortMapping(String),
+
+    #[error("SSH config error: {0}")]
+    SshConfig(String),
+
+    #[error("Port {port} is already in use{}", owned_by(.owner))]
+    PortInUse {
+        port: u16,
+        /// The process holding the port, if it could be found
+        owner: Option<PortOwner>,
+    },
+}
+
+fn owned_by(owner: &Option<PortOwner>) -> String {
+    owner
+        .as_ref()
+        .map(|owner| format!(" by {}", owner))
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+impl From<rustix::io::Errno> for ConnectoError {
+    fn from(e: rustix::io::Errno) -> Self {
+        Self::Io(e.into())
+    }
 }
 
 pub type Result<T> = std::result::Result<T, ConnectoError>;
@@ -91,6 +173,33 @@ mod tests {
         assert_eq!(err.to_string(), "Sync rejected: peer declined");
     }
 
+    #[test]
+    fn test_identity_mismatch_error_display() {
+        let err = ConnectoError::IdentityMismatch("desk changed".to_string());
+        assert_eq!(err.to_string(), "Identity mismatch: desk changed");
+    }
+
+    #[test]
+    fn test_port_in_use_error_display() {
+        let err = ConnectoError::PortInUse {
+            port: 8099,
+            owner: None,
+        };
+        assert_eq!(err.to_string(), "Port 8099 is already in use");
+
+        let err = ConnectoError::PortInUse {
+            port: 8099,
+            owner: Some(PortOwner {
+                pid: 4321,
+                name: Some("connecto".to_string()),
+            }),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Port 8099 is already in use by connecto (pid 4321)"
+        );
+    }
+
     #[test]
     fn test_sync_with_self_error_display() {
         let err = ConnectoError::SyncWithSelf;
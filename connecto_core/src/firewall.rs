@@ -0,0 +1,589 @@
+//! Firewall module
+//!
+//! A listener that a firewall hides looks fine on its own machine while every
+//! client times out. This module finds the firewall in charge (ufw or
+//! firewalld on Linux, Windows Defender Firewall, the macOS application
+//! firewall), asks it whether the listening port gets through, and gives the
+//! commands that let it through.
+//!
+//! [`unreachable_addresses`] also connects to the advertised addresses from a
+//! second socket. Connections to the machine's own addresses usually go over
+//! loopback, past the firewall, so this catches addresses the port is not
+//! bound on rather than firewall rules.
+
+use crate::error::{ConnectoError, Result};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// How long [`unreachable_addresses`] waits for each connection
+pub const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// UDP port of mDNS, which discovery needs let through too
+const MDNS_PORT: u16 = 5353;
+
+/// Name of the rules Connecto adds to Windows Defender Firewall
+const WINDOWS_RULE_NAME: &str = "Connecto";
+
+/// `socketfilterfw`, which controls the macOS application firewall
+const SOCKETFILTERFW: &str = "/usr/libexec/ApplicationFirewall/socketfilterfw";
+
+/// A firewall that filters incoming connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firewall {
+    /// Uncomplicated Firewall, common on Ubuntu and Debian
+    Ufw,
+    /// firewalld, common on Fedora, RHEL and openSUSE
+    Firewalld,
+    /// Windows Defender Firewall
+    Windows,
+    /// The macOS application firewall, which allows or blocks programs
+    /// rather than ports
+    MacOs,
+}
+
+impl fmt::Display for Firewall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ufw => "ufw",
+            Self::Firewalld => "firewalld",
+            Self::Windows => "Windows Defender Firewall",
+            Self::MacOs => "The macOS firewall",
+        })
+    }
+}
+
+/// Whether a firewall lets a listener's connections through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortAccess {
+    /// A rule or the default policy lets them through
+    Allowed,
+    /// Nothing lets them through
+    Blocked,
+    /// The firewall would not say, usually for lack of privileges
+    Unknown,
+}
+
+/// An active firewall and what it does with a listener's connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirewallCheck {
+    pub firewall: Firewall,
+    pub access: PortAccess,
+}
+
+/// A command that changes the firewall, run as root or Administrator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleCommand {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl RuleCommand {
+    fn new(program: &str, args: &[&str]) -> Self {
+        Self {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    /// Run the command, failing with its output when it fails
+    fn run(&self) -> Result<()> {
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .output()
+            .map_err(|e| ConnectoError::Firewall(format!("Cannot run {}: {}", self.program, e)))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let reason = [stderr.trim(), stdout.trim()]
+            .into_iter()
+            .find(|s| !s.is_empty())
+            .unwrap_or("no output")
+            .to_string();
+        Err(ConnectoError::Firewall(format!(
+            "'{}' failed: {}",
+            self, reason
+        )))
+    }
+}
+
+impl fmt::Display for RuleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.program)?;
+        for arg in &self.args {
+            if arg.contains([' ', '"']) {
+                write!(f, " '{}'", arg)?;
+            } else {
+                write!(f, " {}", arg)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Firewall {
+    /// The commands that let connections to TCP `port`, and mDNS, through
+    ///
+    /// `program` is the listening executable, which the macOS firewall
+    /// allows instead of a port.
+    pub fn allow_commands(&self, port: u16, program: &Path) -> Vec<RuleCommand> {
+        let tcp = format!("{}/tcp", port);
+        let mdns = format!("{}/udp", MDNS_PORT);
+        match self {
+            Self::Ufw => vec![
+                RuleCommand::new("ufw", &["allow", &tcp]),
+                RuleCommand::new("ufw", &["allow", &mdns]),
+            ],
+            Self::Firewalld => vec![
+                RuleCommand::new(
+                    "firewall-cmd",
+                    &["--permanent", &format!("--add-port={}", tcp)],
+                ),
+                RuleCommand::new("firewall-cmd", &["--permanent", "--add-service=mdns"]),
+                RuleCommand::new("firewall-cmd", &["--reload"]),
+            ],
+            Self::Windows => {
+                let name = format!("name={}", WINDOWS_RULE_NAME);
+                let rule = |protocol: &str, port: u16| {
+                    RuleCommand::new(
+                        "netsh",
+                        &[
+                            "advfirewall",
+                            "firewall",
+                            "add",
+                            "rule",
+                            &name,
+                            "dir=in",
+                            "action=allow",
+                            &format!("protocol={}", protocol),
+                            &format!("localport={}", port),
+                        ],
+                    )
+                };
+                vec![rule("TCP", port), rule("UDP", MDNS_PORT)]
+            }
+            Self::MacOs => {
+                let program = program.to_string_lossy();
+                vec![
+                    RuleCommand::new(SOCKETFILTERFW, &["--add", &program]),
+                    RuleCommand::new(SOCKETFILTERFW, &["--unblockapp", &program]),
+                ]
+            }
+        }
+    }
+
+    /// Let connections to `port` through, by running [`Firewall::allow_commands`]
+    ///
+    /// Needs root or Administrator.
+    pub fn allow(&self, port: u16, program: &Path) -> Result<()> {
+        self.allow_commands(port, program)
+            .iter()
+            .try_for_each(RuleCommand::run)
+    }
+}
+
+/// The active firewall on this machine and whether it lets connections to
+/// TCP `port` through, or `None` when no firewall is filtering
+///
+/// `program` is the listening executable, for the macOS firewall.
+pub fn check(port: u16, program: &Path) -> Option<FirewallCheck> {
+    platform_check(port, program)
+}
+
+#[cfg(target_os = "linux")]
+fn platform_check(port: u16, _program: &Path) -> Option<FirewallCheck> {
+    // firewalld answers unprivileged users over D-Bus
+    if command_output("firewall-cmd", &["--state"]).is_some_and(|state| state.trim() == "running") {
+        let access = command_output("firewall-cmd", &["--list-ports"])
+            .map(|ports| parse_firewalld_ports(&ports, port))
+            .unwrap_or(PortAccess::Unknown);
+        return Some(FirewallCheck {
+            firewall: Firewall::Firewalld,
+            access,
+        });
+    }
+
+    // ufw only shows its rules to root; its config file says whether it is on
+    match command_output("ufw", &["status", "verbose"]) {
+        Some(status) => parse_ufw_status(&status, port).map(|access| FirewallCheck {
+            firewall: Firewall::Ufw,
+            access,
+        }),
+        None => std::fs::read_to_string("/etc/ufw/ufw.conf")
+            .ok()
+            .filter(|conf| ufw_enabled(conf))
+            .map(|_| FirewallCheck {
+                firewall: Firewall::Ufw,
+                access: PortAccess::Unknown,
+            }),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn platform_check(_port: u16, program: &Path) -> Option<FirewallCheck> {
+    let state = command_output(SOCKETFILTERFW, &["--getglobalstate"])?;
+    if !macos_enabled(&state) {
+        return None;
+    }
+    let access = command_output(
+        SOCKETFILTERFW,
+        &["--getappblocked", &program.to_string_lossy()],
+    )
+    .map(|answer| parse_macos_app(&answer))
+    .unwrap_or(PortAccess::Unknown);
+    Some(FirewallCheck {
+        firewall: Firewall::MacOs,
+        access,
+    })
+}
+
+#[cfg(windows)]
+fn platform_check(port: u16, _program: &Path) -> Option<FirewallCheck> {
+    let state = command_output("netsh", &["advfirewall", "show", "currentprofile", "state"])?;
+    if !windows_enabled(&state) {
+        return None;
+    }
+    // Without a port rule, Windows may still let the program through if the
+    // user allowed it when asked, so a missing rule is not proof of a block
+    let access = command_output(
+        "netsh",
+        &[
+            "advfirewall",
+            "firewall",
+            "show",
+            "rule",
+            "name=all",
+            "dir=in",
+        ],
+    )
+    .filter(|rules| parse_windows_rules(rules, port))
+    .map_or(PortAccess::Unknown, |_| PortAccess::Allowed);
+    Some(FirewallCheck {
+        firewall: Firewall::Windows,
+        access,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn platform_check(_port: u16, _program: &Path) -> Option<FirewallCheck> {
+    None
+}
+
+/// Standard output of a command that succeeded
+#[cfg_attr(
+    not(any(target_os = "linux", target_os = "macos", windows)),
+    allow(dead_code)
+)]
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Whether `ports`, a port as ufw or firewalld write it (`8099`, `8099/tcp`,
+/// `8000:8100/tcp`, `8000-8100/tcp`, `80,8099/tcp`), covers TCP `port`
+fn covers_tcp_port(ports: &str, port: u16) -> bool {
+    let (ports, protocol) = ports.split_once('/').unwrap_or((ports, "tcp"));
+    if protocol != "tcp" {
+        return false;
+    }
+    ports.split(',').any(|range| {
+        let (low, high) = range.split_once([':', '-']).unwrap_or((range, range));
+        matches!(
+            (low.parse::<u16>(), high.parse::<u16>()),
+            (Ok(low), Ok(high)) if (low..=high).contains(&port)
+        )
+    })
+}
+
+/// What `ufw status verbose` output does with TCP `port`, or `None` when ufw
+/// is inactive
+///
+/// The first rule for the port decides, as in ufw; without one, the default
+/// incoming policy does.
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn parse_ufw_status(status: &str, port: u16) -> Option<PortAccess> {
+    let mut lines = status.lines();
+    if lines.next()?.trim() != "Status: active" {
+        return None;
+    }
+    let mut default = PortAccess::Blocked;
+    for line in status.lines() {
+        if let Some(policies) = line.strip_prefix("Default:") {
+            if policies.contains("allow (incoming)") {
+                default = PortAccess::Allowed;
+            }
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(to), Some(action)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if !covers_tcp_port(to, port) {
+            continue;
+        }
+        return Some(match action {
+            "ALLOW" => PortAccess::Allowed,
+            _ => PortAccess::Blocked,
+        });
+    }
+    Some(default)
+}
+
+/// Whether `/etc/ufw/ufw.conf` turns ufw on
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn ufw_enabled(conf: &str) -> bool {
+    conf.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .any(|(key, value)| key.trim() == "ENABLED" && value.trim() == "yes")
+}
+
+/// What firewalld does with TCP `port`, given `firewall-cmd --list-ports`
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn parse_firewalld_ports(ports: &str, port: u16) -> PortAccess {
+    if ports
+        .split_whitespace()
+        .any(|open| covers_tcp_port(open, port))
+    {
+        PortAccess::Allowed
+    } else {
+        PortAccess::Blocked
+    }
+}
+
+/// Whether `socketfilterfw --getglobalstate` output says the firewall is on
+#[cfg_attr(not(any(target_os = "macos", test)), allow(dead_code))]
+fn macos_enabled(state: &str) -> bool {
+    let state = state.to_lowercase();
+    (state.contains("enabled") && !state.contains("disabled")) || state.contains("state = 1")
+}
+
+/// What `socketfilterfw --getappblocked` output says about the program
+#[cfg_attr(not(any(target_os = "macos", test)), allow(dead_code))]
+fn parse_macos_app(answer: &str) -> PortAccess {
+    let answer = answer.to_lowercase();
+    if answer.contains("permitted") {
+        PortAccess::Allowed
+    } else if answer.contains("blocked") {
+        PortAccess::Blocked
+    } else {
+        PortAccess::Unknown
+    }
+}
+
+/// Whether `netsh advfirewall show currentprofile state` output says the
+/// firewall is on
+#[cfg_attr(not(any(windows, test)), allow(dead_code))]
+fn windows_enabled(state: &str) -> bool {
+    state.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields == ["State", "ON"]
+    })
+}
+
+/// Whether `netsh advfirewall firewall show rule` output has an enabled
+/// inbound rule allowing TCP `port`
+#[cfg_attr(not(any(windows, test)), allow(dead_code))]
+fn parse_windows_rules(rules: &str, port: u16) -> bool {
+    // Rules are blocks of "Field: value" lines separated by dashes
+    rules.split("Rule Name:").skip(1).any(|rule| {
+        let field = |name: &str| {
+            rule.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                (key.trim() == name).then(|| value.trim())
+            })
+        };
+        field("Enabled") == Some("Yes")
+            && field("Direction") == Some("In")
+            && field("Action") == Some("Allow")
+            && field("Protocol") == Some("TCP")
+            && field("LocalPort").is_some_and(|ports| {
+                ports == "Any" || covers_tcp_port(&ports.replace('-', ":"), port)
+            })
+    })
+}
+
+/// The addresses among `addresses` that a connection to `port` cannot reach
+/// when listening on `bind`
+///
+/// A throwaway listener holds the port while each address is connected to
+/// from another socket, so call this before the real server binds it.
+/// Addresses of the other IP version than `bind` are skipped.
+pub async fn unreachable_addresses(
+    bind: IpAddr,
+    addresses: &[IpAddr],
+    port: u16,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>> {
+    let listener = TcpListener::bind(SocketAddr::new(bind, port))
+        .await
+        .map_err(|e| crate::ports::bind_error(port, e))?;
+    let port = listener.local_addr()?.port();
+    let probes = addresses
+        .iter()
+        .filter(|address| address.is_ipv4() == bind.is_ipv4())
+        .map(|&address| async move {
+            let connect = TcpStream::connect(SocketAddr::new(address, port));
+            match tokio::time::timeout(timeout, connect).await {
+                Ok(Ok(_)) => None,
+                _ => Some(address),
+            }
+        });
+    let unreachable = futures::future::join_all(probes)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    drop(listener);
+    Ok(unreachable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_ufw_status() {
+        let status = "\
+Status: active
+Logging: on (low)
+Default: deny (incoming), allow (outgoing), disabled (routed)
+New profiles: skip
+
+To                         Action      From
+--                         ------      ----
+22/tcp                     ALLOW IN    Anywhere
+8000:8100/tcp              ALLOW IN    192.168.1.0/24
+9000                       DENY IN     Anywhere
+9000/udp                   ALLOW IN    Anywhere
+22/tcp (v6)                ALLOW IN    Anywhere (v6)
+";
+        assert_eq!(parse_ufw_status(status, 22), Some(PortAccess::Allowed));
+        assert_eq!(parse_ufw_status(status, 8099), Some(PortAccess::Allowed));
+        assert_eq!(parse_ufw_status(status, 9000), Some(PortAccess::Blocked));
+        assert_eq!(parse_ufw_status(status, 9100), Some(PortAccess::Blocked));
+
+        let open = status.replace("deny (incoming)", "allow (incoming)");
+        assert_eq!(parse_ufw_status(&open, 9100), Some(PortAccess::Allowed));
+        assert_eq!(parse_ufw_status("Status: inactive\n", 8099), None);
+
+        assert!(ufw_enabled(
+            "# /etc/ufw/ufw.conf\nENABLED=yes\nLOGLEVEL=low\n"
+        ));
+        assert!(!ufw_enabled("ENABLED=no\n"));
+        assert!(!ufw_enabled("#ENABLED=yes\n"));
+    }
+
+    #[test]
+    fn test_parse_firewalld_ports() {
+        assert_eq!(
+            parse_firewalld_ports("5353/udp 8099/tcp\n", 8099),
+            PortAccess::Allowed
+        );
+        assert_eq!(
+            parse_firewalld_ports("8000-8100/tcp", 8099),
+            PortAccess::Allowed
+        );
+        assert_eq!(parse_firewalld_ports("8099/udp", 8099), PortAccess::Blocked);
+        assert_eq!(parse_firewalld_ports("\n", 8099), PortAccess::Blocked);
+    }
+
+    #[test]
+    fn test_parse_macos_and_windows() {
+        assert!(macos_enabled("Firewall is enabled. (State = 1)"));
+        assert!(!macos_enabled("Firewall is disabled. (State = 0)"));
+        assert_eq!(
+            parse_macos_app("The application /usr/local/bin/connecto is permitted"),
+            PortAccess::Allowed
+        );
+        assert_eq!(
+            parse_macos_app("The application /usr/local/bin/connecto is blocked"),
+            PortAccess::Blocked
+        );
+        assert_eq!(parse_macos_app(""), PortAccess::Unknown);
+
+        let state = "\r\nPrivate Profile Settings: \r\n----------------------------------------------------------------------\r\nState                                 ON\r\nOk.\r\n";
+        assert!(windows_enabled(state));
+        assert!(!windows_enabled(&state.replace(" ON", " OFF")));
+
+        let rules = "\r\nRule Name:                            Connecto\r\n----------------------------------------------------------------------\r\nEnabled:                              Yes\r\nDirection:                            In\r\nProfiles:                             Domain,Private,Public\r\nLocalIP:                              Any\r\nRemoteIP:                             Any\r\nProtocol:                             TCP\r\nLocalPort:                            8099\r\nRemotePort:                           Any\r\nAction:                               Allow\r\n\r\nRule Name:                            Connecto\r\n----------------------------------------------------------------------\r\nEnabled:                              Yes\r\nDirection:                            In\r\nProtocol:                             UDP\r\nLocalPort:                            5353\r\nAction:                               Allow\r\nOk.\r\n";
+        assert!(parse_windows_rules(rules, 8099));
+        assert!(!parse_windows_rules(rules, 5353));
+        assert!(!parse_windows_rules(&rules.replace("Yes", "No"), 8099));
+    }
+
+    #[test]
+    fn test_allow_commands() {
+        let program = Path::new("/usr/local/bin/connecto");
+        let lines = |firewall: Firewall| -> Vec<String> {
+            firewall
+                .allow_commands(8099, program)
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        };
+        assert_eq!(
+            lines(Firewall::Ufw),
+            ["ufw allow 8099/tcp", "ufw allow 5353/udp"]
+        );
+        assert_eq!(
+            lines(Firewall::Firewalld),
+            [
+                "firewall-cmd --permanent --add-port=8099/tcp",
+                "firewall-cmd --permanent --add-service=mdns",
+                "firewall-cmd --reload"
+            ]
+        );
+        assert_eq!(
+            lines(Firewall::Windows)[0],
+            "netsh advfirewall firewall add rule name=Connecto dir=in action=allow protocol=TCP localport=8099"
+        );
+        assert_eq!(
+            lines(Firewall::MacOs)[1],
+            format!("{} --unblockapp /usr/local/bin/connecto", SOCKETFILTERFW)
+        );
+
+        let spaced =
+            Firewall::MacOs.allow_commands(8099, Path::new("/Applications/My Apps/connecto"));
+        assert!(spaced[0]
+            .to_string()
+            .ends_with("--add '/Applications/My Apps/connecto'"));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_addresses() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let unreachable = unreachable_addresses(loopback, &[loopback], port, SELF_CHECK_TIMEOUT)
+            .await
+            .unwrap();
+        assert!(unreachable.is_empty());
+
+        // An address the port is not bound on is reported; IPv6 is skipped
+        let elsewhere = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let unreachable = unreachable_addresses(
+            loopback,
+            &[loopback, elsewhere, "::1".parse().unwrap()],
+            port,
+            SELF_CHECK_TIMEOUT,
+        )
+        .await
+        .unwrap();
+        assert_eq!(unreachable, [elsewhere]);
+    }
+}
@@ -0,0 +1,196 @@
+//! Public keys people publish on GitHub and GitLab
+//!
+//! Both sites serve a user's SSH public keys as plain text, one per line, at
+//! `https://<host>/<user>.keys`. Importing them grants someone access without
+//! them running Connecto. The listing is fetched with `curl`, which ships
+//! with macOS, Windows 10 and later, and most Linux distributions.
+
+use crate::error::{ConnectoError, Result};
+use crate::keys::{parse_public_key_info, PublicKeyInfo};
+use std::process::Command;
+
+/// Host of the public GitLab instance
+pub const GITLAB_HOST: &str = "gitlab.com";
+
+/// Seconds a fetch may take before it is given up
+const FETCH_TIMEOUT_SECS: u32 = 20;
+
+/// The exit code of `curl --fail` for an HTTP error, such as 404 for an
+/// unknown user
+const CURL_HTTP_ERROR: i32 = 22;
+
+/// A site that publishes its users' public keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Forge {
+    GitHub,
+    /// A GitLab instance on the given host, [`GITLAB_HOST`] for gitlab.com
+    GitLab(String),
+}
+
+impl Forge {
+    /// Where `user`'s keys are published
+    pub fn keys_url(&self, user: &str) -> String {
+        match self {
+            Forge::GitHub => format!("https://github.com/{}.keys", user),
+            Forge::GitLab(host) => format!("https://{}/{}.keys", host, user),
+        }
+    }
+
+    /// The prefix of the comment imported keys get, e.g. `github`
+    ///
+    /// Self-hosted GitLab instances are named by their host, so keys of
+    /// namesakes on different instances stay apart.
+    pub fn label(&self) -> &str {
+        match self {
+            Forge::GitHub => "github",
+            Forge::GitLab(host) if host == GITLAB_HOST => "gitlab",
+            Forge::GitLab(host) => host,
+        }
+    }
+
+    /// Check that `user` can be a user name on this forge
+    ///
+    /// GitHub names are letters, digits and hyphens; GitLab also
+    /// allows dots and underscores. Anything else would change the URL.
+    pub fn validate_user(&self, user: &str) -> Result<()> {
+        let allowed = |c: char| match self {
+            Forge::GitHub => c.is_ascii_alphanumeric() || c == '-',
+            Forge::GitLab(_) => c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'),
+        };
+        if user.is_empty()
+            || user.len() > 255
+            || !user.chars().all(allowed)
+            || user.starts_with(['-', '.'])
+        {
+            return Err(ConnectoError::KeyImport(format!(
+                "'{}' is not a valid {} user name",
+                user,
+                self.label()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A public key published on a forge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishedKey {
+    /// The key in OpenSSH format, commented with where it came from, e.g.
+    /// `github:octocat`
+    pub public_key: String,
+    pub info: PublicKeyInfo,
+}
+
+/// Download the public keys `user` publishes on `forge`
+///
+/// A user without keys gets an empty list; an unknown user is an error.
+pub fn fetch_keys(forge: &Forge, user: &str) -> Result<Vec<PublishedKey>> {
+    forge.validate_user(user)?;
+    let url = forge.keys_url(user);
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--proto", "=https", "--max-time"])
+        .arg(FETCH_TIMEOUT_SECS.to_string())
+        .arg(&url)
+        .output()
+        .map_err(|e| ConnectoError::KeyImport(format!("Could not run curl: {}", e)))?;
+
+    if !output.status.success() {
+        if output.status.code() == Some(CURL_HTTP_ERROR) {
+            return Err(ConnectoError::KeyImport(format!(
+                "{} has no user {} ({})",
+                forge.label(),
+                user,
+                url
+            )));
+        }
+        return Err(ConnectoError::Network(format!(
+            "Could not fetch {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let comment = format!("{}:{}", forge.label(), user);
+    Ok(parse_keys(
+        &String::from_utf8_lossy(&output.stdout),
+        &comment,
+    ))
+}
+
+/// The keys in a `.keys` listing, each commented with `comment`
+///
+/// Lines that are not public keys are skipped, as are repeats.
+pub fn parse_keys(listing: &str, comment: &str) -> Vec<PublishedKey> {
+    let mut keys: Vec<PublishedKey> = Vec::new();
+    for line in listing.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(key_type), Some(blob)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let public_key = format!("{} {} {}", key_type, blob, comment);
+        let Ok(info) = parse_public_key_info(&public_key) else {
+            continue;
+        };
+        if keys
+            .iter()
+            .all(|key| key.info.fingerprint != info.fingerprint)
+        {
+            keys.push(PublishedKey { public_key, info });
+        }
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+    #[test]
+    fn test_keys_url_and_label() {
+        assert_eq!(
+            Forge::GitHub.keys_url("octocat"),
+            "https://github.com/octocat.keys"
+        );
+        let gitlab = Forge::GitLab(GITLAB_HOST.to_string());
+        assert_eq!(gitlab.keys_url("jane"), "https://gitlab.com/jane.keys");
+        assert_eq!(gitlab.label(), "gitlab");
+
+        let own = Forge::GitLab("git.example.org".to_string());
+        assert_eq!(own.keys_url("jane"), "https://git.example.org/jane.keys");
+        assert_eq!(own.label(), "git.example.org");
+    }
+
+    #[test]
+    fn test_validate_user() {
+        let gitlab = Forge::GitLab(GITLAB_HOST.to_string());
+        assert!(Forge::GitHub.validate_user("octo-cat42").is_ok());
+        assert!(gitlab.validate_user("jane.doe_1").is_ok());
+
+        assert!(Forge::GitHub.validate_user("jane.doe").is_err());
+        for user in ["", "-jane", "../etc", "jane/keys", "jane?x=1", "jane doe"] {
+            assert!(Forge::GitHub.validate_user(user).is_err(), "{}", user);
+            assert!(gitlab.validate_user(user).is_err(), "{}", user);
+        }
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let ed25519 = SshKeyPair::generate(KeyAlgorithm::Ed25519, "laptop").unwrap();
+        let ecdsa = SshKeyPair::generate(KeyAlgorithm::EcdsaP256, "work").unwrap();
+        let listing = format!(
+            "{}\n\nnot a key\n{}\n{}\n",
+            ed25519.public_key, ecdsa.public_key, ed25519.public_key
+        );
+
+        let keys = parse_keys(&listing, "github:octocat");
+        assert_eq!(keys.len(), 2);
+        assert!(keys[0].public_key.ends_with(" github:octocat"));
+        assert_eq!(keys[0].info.comment, "github:octocat");
+        assert_eq!(keys[0].info.fingerprint, ed25519.fingerprint().unwrap());
+        assert_eq!(keys[1].info.algorithm, "ecdsa-sha2-nistp256");
+
+        assert!(parse_keys("", "github:octocat").is_empty());
+    }
+}
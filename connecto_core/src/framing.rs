@@ -0,0 +1,178 @@
+//! Message framing
+//!
+//! Protocol messages used to travel as one JSON object per line. From
+//! [`FRAMED_VERSION`](crate::protocol::FRAMED_VERSION) on, the pairing
+//! protocol sends everything after `Hello` and `HelloAck` as length-prefixed
+//! frames instead: a 4-byte big-endian length, then that many bytes of JSON.
+//! Either way, a reader never buffers more than the size cap it is given, so
+//! a peer cannot exhaust its memory with one endless message.
+
+use crate::error::Result;
+use crate::protocol::Message;
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Bytes of the length that starts a frame
+const LENGTH_LEN: usize = 4;
+
+/// How messages are delimited on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// One JSON object per line
+    #[default]
+    Lines,
+    /// A 4-byte big-endian length, then that many bytes of JSON
+    LengthPrefixed,
+}
+
+impl Framing {
+    /// Write `message` as one frame
+    pub async fn write(
+        self,
+        writer: &mut (impl AsyncWrite + Unpin),
+        message: &Message,
+    ) -> Result<()> {
+        match self {
+            Self::Lines => writer.write_all(message.to_json()?.as_bytes()).await?,
+            Self::LengthPrefixed => {
+                let json = serde_json::to_vec(message)?;
+                let len = u32::try_from(json.len())
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "Message too long"))?;
+                let mut frame = Vec::with_capacity(LENGTH_LEN + json.len());
+                frame.extend_from_slice(&len.to_be_bytes());
+                frame.extend_from_slice(&json);
+                writer.write_all(&frame).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the JSON of the next frame, of at most `max_len` bytes, into `json`
+    ///
+    /// Returns the number of bytes read, 0 if the peer closed the connection
+    /// before the frame started. A longer frame fails with
+    /// [`ErrorKind::InvalidData`] without being buffered.
+    pub async fn read(
+        self,
+        reader: &mut (impl AsyncBufRead + Unpin),
+        json: &mut String,
+        max_len: usize,
+    ) -> std::io::Result<usize> {
+        match self {
+            Self::Lines => {
+                let read = (&mut *reader).take(max_len as u64).read_line(json).await?;
+                if read == max_len && !json.ends_with('\n') {
+                    return Err(too_long(max_len));
+                }
+                Ok(read)
+            }
+            Self::LengthPrefixed => {
+                let mut length = [0; LENGTH_LEN];
+                // A clean close between frames is not an error
+                if reader.fill_buf().await?.is_empty() {
+                    return Ok(0);
+                }
+                reader.read_exact(&mut length).await?;
+                let len = u32::from_be_bytes(length) as usize;
+                if len > max_len {
+                    return Err(too_long(max_len));
+                }
+                if len == 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Empty message"));
+                }
+                let mut buf = vec![0; len];
+                reader.read_exact(&mut buf).await?;
+                let text = String::from_utf8(buf)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Message is not UTF-8"))?;
+                json.push_str(&text);
+                Ok(LENGTH_LEN + len)
+            }
+        }
+    }
+}
+
+fn too_long(max_len: usize) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Message longer than {} bytes", max_len),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ProtocolErrorCode;
+    use tokio::io::BufReader;
+
+    async fn roundtrip(framing: Framing, message: &Message) -> Message {
+        let mut wire = Vec::new();
+        framing.write(&mut wire, message).await.unwrap();
+        let mut reader = BufReader::new(&wire[..]);
+        let mut json = String::new();
+        assert_eq!(
+            framing.read(&mut reader, &mut json, 1024).await.unwrap(),
+            wire.len()
+        );
+        // Nothing is left over, and the end of the stream reads as 0
+        assert_eq!(framing.read(&mut reader, &mut json, 1024).await.unwrap(), 0);
+        Message::from_json(&json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let message = Message::Error {
+            code: ProtocolErrorCode::UnexpectedMessage,
+            message: "line one\nline two".to_string(),
+        };
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            match roundtrip(framing, &message).await {
+                Message::Error { code, message } => {
+                    assert_eq!(code, ProtocolErrorCode::UnexpectedMessage);
+                    assert_eq!(message, "line one\nline two");
+                }
+                other => panic!("Expected Error, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_length_prefix() {
+        let mut wire = Vec::new();
+        Framing::LengthPrefixed
+            .write(&mut wire, &Message::PinAccepted { mac: None })
+            .await
+            .unwrap();
+        let json = br#"{"type":"PinAccepted"}"#;
+        assert_eq!(wire[..LENGTH_LEN], (json.len() as u32).to_be_bytes());
+        assert_eq!(&wire[LENGTH_LEN..], json);
+    }
+
+    #[tokio::test]
+    async fn test_size_cap() {
+        let mut json = String::new();
+        let long = [b'x'; 300];
+        let error = Framing::Lines
+            .read(&mut BufReader::new(&long[..]), &mut json, 256)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // The announced length is refused before any of the frame is read
+        let frame = u32::MAX.to_be_bytes();
+        let error = Framing::LengthPrefixed
+            .read(&mut BufReader::new(&frame[..]), &mut json, 256)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Message longer than 256 bytes");
+
+        // A frame cut short is an error, not the end of the stream
+        let mut frame = 10u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(b"{}");
+        let error = Framing::LengthPrefixed
+            .read(&mut BufReader::new(&frame[..]), &mut json, 256)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,269 @@
+//! Translated user-facing messages
+//!
+//! Messages are looked up by key in the catalog of the user's language, with
+//! English standing in for languages and keys that have no translation. A
+//! catalog is plain text, one `key = text` per line, with `{name}`
+//! placeholders; lines starting with `#` are comments.
+//!
+//! English and German are built in. Distributions can add or correct a
+//! language by installing `<language>.txt` in the directory named by
+//! [`LOCALE_DIR_ENV`], and users by putting it in `locales` in the config
+//! directory; entries there take over the built-in ones.
+
+use crate::paths;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Environment variable choosing the language, e.g. `de`, ahead of the
+/// system locale
+pub const LANG_ENV: &str = "CONNECTO_LANG";
+
+/// Environment variable naming a directory of extra catalogs
+pub const LOCALE_DIR_ENV: &str = "CONNECTO_LOCALE_DIR";
+
+/// Language used when no other is chosen or available
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Catalogs compiled into the binary
+const BUILT_IN: [(&str, &str); 2] = [
+    ("en", include_str!("../locales/en.txt")),
+    ("de", include_str!("../locales/de.txt")),
+];
+
+/// Variables naming the locale, most specific first, as POSIX orders them
+const LOCALE_VARS: [&str; 4] = [LANG_ENV, "LC_ALL", "LC_MESSAGES", "LANG"];
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+/// The messages of one language, backed by English
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    language: String,
+    messages: HashMap<String, String>,
+    fallback: HashMap<String, String>,
+}
+
+impl Catalog {
+    /// The catalog for `language` (e.g. `de`), or English if there is none
+    pub fn for_language(language: &str) -> Self {
+        let fallback = load(DEFAULT_LANGUAGE).unwrap_or_default();
+        match load(language) {
+            Some(messages) if language != DEFAULT_LANGUAGE => Self {
+                language: language.to_string(),
+                messages,
+                fallback,
+            },
+            _ => Self {
+                language: DEFAULT_LANGUAGE.to_string(),
+                messages: HashMap::new(),
+                fallback,
+            },
+        }
+    }
+
+    /// The language the messages are in
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    /// The message for `key`, in English if it has no translation, or the
+    /// key itself if no catalog has it
+    pub fn text<'a>(&'a self, key: &'a str) -> &'a str {
+        self.messages
+            .get(key)
+            .or_else(|| self.fallback.get(key))
+            .map_or(key, String::as_str)
+    }
+
+    /// The message for `key` with each `{name}` replaced by its value
+    pub fn format(&self, key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+        let mut text = self.text(key).to_string();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{}}}", name), &value.to_string());
+        }
+        text
+    }
+}
+
+/// Use `language` for the rest of the run instead of the detected one
+///
+/// Has no effect once a message was looked up.
+pub fn set_language(language: &str) {
+    let _ = CATALOG.set(Catalog::for_language(language));
+}
+
+/// The catalog in use, for the detected language unless
+/// [`set_language`] chose one
+pub fn catalog() -> &'static Catalog {
+    CATALOG.get_or_init(|| Catalog::for_language(&detect_language()))
+}
+
+/// The message for `key` in the language in use
+pub fn text(key: &str) -> &str {
+    catalog().text(key)
+}
+
+/// The message for `key` in the language in use, with its placeholders
+/// filled in
+pub fn format(key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    catalog().format(key, args)
+}
+
+/// The message for a key in the language in use, as a `String`, with
+/// placeholders filled in from `name = value` pairs
+///
+/// ```
+/// let line = connecto_core::tr!("update-ip.not-found", host = "desk");
+/// assert!(line.contains("desk"));
+/// ```
+#[macro_export]
+macro_rules! tr {
+    ($key:expr) => {
+        $crate::i18n::text($key).to_string()
+    };
+    ($key:expr, $($name:ident = $value:expr),+ $(,)?) => {
+        $crate::i18n::format(
+            $key,
+            &[$((stringify!($name), &$value as &dyn ::std::fmt::Display)),+],
+        )
+    };
+}
+
+/// The language of the environment's locale, e.g. `de` for `de_DE.UTF-8`
+pub fn detect_language() -> String {
+    detect_language_in(&|name| std::env::var(name).ok())
+}
+
+fn detect_language_in(env: &impl Fn(&str) -> Option<String>) -> String {
+    LOCALE_VARS
+        .iter()
+        .filter_map(|name| env(name))
+        .find(|value| !value.is_empty())
+        .and_then(|locale| language_of(&locale))
+        .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string())
+}
+
+/// The language part of a locale such as `pt_BR.UTF-8@euro`; `C` and
+/// `POSIX` are English
+fn language_of(locale: &str) -> Option<String> {
+    let language = locale
+        .split(['_', '-', '.', '@'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match language.as_str() {
+        "" => None,
+        "c" | "posix" => Some(DEFAULT_LANGUAGE.to_string()),
+        _ if language.chars().all(|c| c.is_ascii_lowercase()) => Some(language),
+        _ => None,
+    }
+}
+
+/// The messages of `language`: the built-in ones, with installed catalogs
+/// on top
+fn load(language: &str) -> Option<HashMap<String, String>> {
+    let mut messages = BUILT_IN
+        .iter()
+        .find(|(built_in, _)| *built_in == language)
+        .map(|(_, text)| parse(text));
+    for dir in catalog_dirs() {
+        if let Ok(text) = std::fs::read_to_string(dir.join(format!("{}.txt", language))) {
+            messages
+                .get_or_insert_with(HashMap::new)
+                .extend(parse(&text));
+        }
+    }
+    messages
+}
+
+/// Directories holding installed catalogs, the later taking precedence
+fn catalog_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(dir) = std::env::var_os(LOCALE_DIR_ENV).map(PathBuf::from) {
+        dirs.push(dir);
+    }
+    if let Ok(dir) = paths::config_dir() {
+        dirs.push(dir.join("locales"));
+    }
+    dirs
+}
+
+/// Parse `key = text` lines, skipping blank lines, comments and lines
+/// without `=`
+fn parse(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_parse() {
+        let messages =
+            parse("# comment\n\ngreeting = Hello, {name}!\nbroken line\n a.b =  x = y \n");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages["greeting"], "Hello, {name}!");
+        assert_eq!(messages["a.b"], "x = y");
+    }
+
+    #[test]
+    fn test_detect_language() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(detect_language_in(&env(&[])), "en");
+        assert_eq!(detect_language_in(&env(&[("LANG", "de_DE.UTF-8")])), "de");
+        assert_eq!(detect_language_in(&env(&[("LANG", "C")])), "en");
+        assert_eq!(
+            detect_language_in(&env(&[("LANG", "de_DE.UTF-8"), ("LC_ALL", "fr_FR")])),
+            "fr"
+        );
+        assert_eq!(
+            detect_language_in(&env(&[("LC_ALL", ""), ("LANG", "pt_BR@euro")])),
+            "pt"
+        );
+        assert_eq!(
+            detect_language_in(&env(&[("LANG", "fr_FR"), (LANG_ENV, "de")])),
+            "de"
+        );
+    }
+
+    #[test]
+    fn test_catalog_lookup() {
+        let german = Catalog::for_language("de");
+        assert_eq!(german.language(), "de");
+        assert_eq!(german.text("label.error"), "Fehler:");
+        assert_eq!(german.text("no.such.key"), "no.such.key");
+        assert_eq!(
+            german.format("update-ip.not-found", &[("host", &"desk")]),
+            "Host 'desk' nicht in der SSH-Konfiguration gefunden"
+        );
+
+        // Languages without a catalog are English
+        let unknown = Catalog::for_language("xx");
+        assert_eq!(unknown.language(), "en");
+        assert_eq!(unknown.text("label.error"), "Error:");
+    }
+
+    #[test]
+    fn test_built_in_catalogs_match() {
+        let keys = |text| parse(text).into_keys().collect::<BTreeSet<_>>();
+        let english = keys(BUILT_IN[0].1);
+        for (language, text) in &BUILT_IN[1..] {
+            assert_eq!(keys(text), english, "{} differs from en", language);
+        }
+    }
+}
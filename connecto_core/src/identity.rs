@@ -0,0 +1,239 @@
+//! Device identity module
+//!
+//! Each device has a persistent Ed25519 identity key, generated on first use
+//! and stored in the Connecto config directory. Its fingerprint identifies the
+//! device independently of its name, hostname, or IP address.
+//!
+//! A fingerprint alone is only a claim: anyone can announce it. A device
+//! proves its identity by signing a fresh nonce from its peer with the
+//! identity key ([`DeviceIdentity::prove`]), and only the
+//! [`VerifiedIdentity`] that checking the signature yields is pinned, trusted
+//! or followed to a new address.
+
+use crate::error::{ConnectoError, Result};
+use crate::keys::{KeyAlgorithm, SshKeyPair};
+use crate::paths;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// File name of the identity key inside the config directory
+const IDENTITY_FILE: &str = "identity";
+
+/// SSH signature namespace for proofs that a device holds its identity key
+pub const IDENTITY_NAMESPACE: &str = "connecto-identity";
+
+/// A device's persistent identity
+#[derive(Debug, Clone)]
+pub struct DeviceIdentity {
+    key_pair: SshKeyPair,
+    fingerprint: String,
+}
+
+impl DeviceIdentity {
+    /// Default location of the identity key
+    pub fn default_path() -> Result<PathBuf> {
+        paths::config_file(IDENTITY_FILE)
+    }
+
+    /// Load this device's identity, generating it on first use
+    pub fn load_or_create() -> Result<Self> {
+        Self::load_or_create_at(&Self::default_path()?)
+    }
+
+    /// Load the identity stored at `path`, generating it if the file does not exist
+    pub fn load_or_create_at(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let key_pair = SshKeyPair::load_from_file(&path.to_string_lossy())?;
+            return Self::from_key_pair(key_pair);
+        }
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "connecto-device-identity")?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Only ever readable by us, and never written through a link
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = match options.open(path) {
+            // Another process got there first
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Self::from_key_pair(SshKeyPair::load_from_file(&path.to_string_lossy())?)
+            }
+            result => result?,
+        };
+        file.write_all(key_pair.private_key.as_bytes())?;
+        file.sync_all()?;
+        fs::write(format!("{}.pub", path.display()), &key_pair.public_key)?;
+
+        let identity = Self::from_key_pair(key_pair)?;
+        info!("Generated device identity {}", identity.fingerprint);
+        Ok(identity)
+    }
+
+    /// Use an existing key pair as the device identity
+    pub fn from_key_pair(key_pair: SshKeyPair) -> Result<Self> {
+        let fingerprint = key_pair.fingerprint()?;
+        Ok(Self {
+            key_pair,
+            fingerprint,
+        })
+    }
+
+    /// Fingerprint that identifies this device (e.g. `SHA256:...`)
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Public half of the identity key in OpenSSH format
+    pub fn public_key(&self) -> &str {
+        &self.key_pair.public_key
+    }
+
+    /// Answer a peer's challenge by signing its `nonce` with the identity key
+    pub fn prove(&self, nonce: &str) -> Result<IdentityProof> {
+        Ok(IdentityProof {
+            public_key: self.key_pair.public_key.clone(),
+            signature: self.key_pair.sign(IDENTITY_NAMESPACE, nonce.as_bytes())?,
+        })
+    }
+}
+
+/// A device's answer to an identity challenge
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityProof {
+    /// Public half of the identity key in OpenSSH format
+    pub public_key: String,
+    /// Armored SSH signature over the challenge nonce
+    pub signature: String,
+}
+
+impl IdentityProof {
+    /// The identity proven by this answer to the challenge `nonce`
+    ///
+    /// Fails with [`ConnectoError::VerificationFailed`] unless the signature
+    /// was made over `nonce` with the key sent along.
+    pub fn verify(&self, nonce: &str) -> Result<VerifiedIdentity> {
+        SshKeyPair::verify_signature(
+            &self.public_key,
+            IDENTITY_NAMESPACE,
+            nonce.as_bytes(),
+            &self.signature,
+        )
+        .map_err(|e| ConnectoError::VerificationFailed(format!("Identity proof: {}", e)))?;
+        Ok(VerifiedIdentity(SshKeyPair::public_key_fingerprint(
+            &self.public_key,
+        )?))
+    }
+
+    /// The identity proven, which must be `announced` if the peer announced
+    /// one
+    pub fn verify_announced(
+        &self,
+        nonce: &str,
+        announced: Option<&str>,
+    ) -> Result<VerifiedIdentity> {
+        let verified = self.verify(nonce)?;
+        match announced {
+            Some(announced) if announced != verified.fingerprint() => {
+                Err(ConnectoError::VerificationFailed(format!(
+                    "Announced identity {} but proved {}",
+                    announced, verified
+                )))
+            }
+            _ => Ok(verified),
+        }
+    }
+}
+
+/// The fingerprint of an identity key whose holder signed a fresh challenge
+///
+/// Only [`IdentityProof::verify`] makes one, so a device's mere claim to an
+/// identity cannot be passed where a proven one is needed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VerifiedIdentity(String);
+
+impl VerifiedIdentity {
+    /// The identity fingerprint (e.g. `SHA256:...`)
+    pub fn fingerprint(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for VerifiedIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_identity_created_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("connecto").join("identity");
+
+        let first = DeviceIdentity::load_or_create_at(&path).unwrap();
+        assert!(path.exists());
+        assert!(first.fingerprint().starts_with("SHA256:"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let second = DeviceIdentity::load_or_create_at(&path).unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        assert_eq!(first.public_key(), second.public_key());
+    }
+
+    #[test]
+    fn test_identities_differ_between_devices() {
+        let temp_dir = TempDir::new().unwrap();
+        let a = DeviceIdentity::load_or_create_at(&temp_dir.path().join("a")).unwrap();
+        let b = DeviceIdentity::load_or_create_at(&temp_dir.path().join("b")).unwrap();
+        assert_ne!(a.fingerprint(), b.fingerprint());
+    }
+
+    #[test]
+    fn test_identity_proof() {
+        let temp_dir = TempDir::new().unwrap();
+        let identity = DeviceIdentity::load_or_create_at(&temp_dir.path().join("a")).unwrap();
+        let other = DeviceIdentity::load_or_create_at(&temp_dir.path().join("b")).unwrap();
+
+        let proof = identity.prove("nonce").unwrap();
+        let verified = proof.verify("nonce").unwrap();
+        assert_eq!(verified.fingerprint(), identity.fingerprint());
+        assert!(proof
+            .verify_announced("nonce", Some(identity.fingerprint()))
+            .is_ok());
+
+        // Not for another challenge, nor claiming another identity
+        assert!(matches!(
+            proof.verify("other nonce"),
+            Err(ConnectoError::VerificationFailed(_))
+        ));
+        assert!(proof
+            .verify_announced("nonce", Some(other.fingerprint()))
+            .is_err());
+        // Nor with someone else's key swapped in
+        let forged = IdentityProof {
+            public_key: other.public_key().to_string(),
+            ..proof
+        };
+        assert!(forged.verify("nonce").is_err());
+    }
+}
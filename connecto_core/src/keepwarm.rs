@@ -0,0 +1,513 @@
+//! Keep-warm connections to paired hosts
+//!
+//! A host with a keep-warm schedule gets an SSH control master during its
+//! hours, so `ssh` and anything else connecting to it reuses an open,
+//! authenticated connection instead of setting up a new one. The schedule is
+//! kept in the host's `~/.ssh/config` entry, next to the `ControlPath` that
+//! connections share the master through.
+//!
+//! Outside its hours a master is asked to stop: it takes no new sessions and
+//! exits once the open ones end, so nobody is cut off mid-session.
+
+use crate::error::{ConnectoError, Result};
+use crate::ssh_config::SshConfig;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Control socket of keep-warm hosts, one per destination
+pub const CONTROL_PATH: &str = "~/.ssh/connecto-%C";
+
+/// How often a running keeper checks the schedules
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a new master may take to connect and log in
+const START_TIMEOUT: Duration = Duration::from_secs(15);
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// The local time, which schedules are in
+pub fn local_time() -> NaiveDateTime {
+    chrono::Local::now().naive_local()
+}
+
+/// Days and hours during which a host is kept warm, e.g. `mon-fri 09:00-18:00`
+///
+/// Hours ending before they start run past midnight, into the next day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// Bit 0 is Monday, bit 6 Sunday
+    days: u8,
+    /// Minutes after midnight
+    start: u32,
+    end: u32,
+}
+
+impl Schedule {
+    /// Work hours: Monday to Friday, 09:00 to 18:00
+    pub const WORK_HOURS: Self = Self {
+        days: 0b001_1111,
+        start: 9 * 60,
+        end: 18 * 60,
+    };
+
+    /// A schedule from days like `mon-fri`, `sat,sun` or `daily`, and hours
+    /// like `09:00-18:00`
+    pub fn new(days: &str, hours: &str) -> Result<Self> {
+        let invalid = |what: &str| {
+            ConnectoError::KeepWarm(format!("Invalid {} in schedule '{} {}'", what, days, hours))
+        };
+
+        let mut day_bits = 0;
+        if days.eq_ignore_ascii_case("daily") {
+            day_bits = 0b111_1111;
+        } else {
+            for range in days.split(',') {
+                let (first, last) = range.split_once('-').unwrap_or((range, range));
+                let (first, last) = (day_index(first), day_index(last));
+                let (Some(first), Some(last)) = (first, last) else {
+                    return Err(invalid("days"));
+                };
+                // Ranges may wrap around the week, e.g. fri-mon
+                let mut day = first;
+                loop {
+                    day_bits |= 1 << day;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+        }
+
+        let (start, end) = hours.split_once('-').ok_or_else(|| invalid("hours"))?;
+        let (Some(start), Some(end)) = (minutes(start), minutes(end)) else {
+            return Err(invalid("hours"));
+        };
+        // 24:00 only ends the hours
+        if start == end || start == 24 * 60 {
+            return Err(invalid("hours"));
+        }
+
+        Ok(Self {
+            days: day_bits,
+            start,
+            end,
+        })
+    }
+
+    /// Whether `time` (local) falls within the schedule
+    pub fn is_active(&self, time: &NaiveDateTime) -> bool {
+        let day = time.weekday().num_days_from_monday();
+        let minute = time.hour() * 60 + time.minute();
+        let on = |day: u32| self.days & (1 << day) != 0;
+        if self.start < self.end {
+            on(day) && (self.start..self.end).contains(&minute)
+        } else {
+            // Past midnight, the hours belong to the day they started on
+            (on(day) && minute >= self.start) || (on((day + 6) % 7) && minute < self.end)
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.days == 0b111_1111 {
+            write!(f, "daily")?;
+        } else {
+            // Runs of consecutive days, e.g. mon-wed,fri
+            let mut runs = Vec::new();
+            let mut day = 0;
+            while day < 7 {
+                if self.days & (1 << day) == 0 {
+                    day += 1;
+                    continue;
+                }
+                let first = day;
+                while day + 1 < 7 && self.days & (1 << (day + 1)) != 0 {
+                    day += 1;
+                }
+                runs.push(match day - first {
+                    0 => DAY_NAMES[first].to_string(),
+                    1 => format!("{},{}", DAY_NAMES[first], DAY_NAMES[day]),
+                    _ => format!("{}-{}", DAY_NAMES[first], DAY_NAMES[day]),
+                });
+                day += 1;
+            }
+            write!(f, "{}", runs.join(","))?;
+        }
+        write!(
+            f,
+            " {:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = ConnectoError;
+
+    /// Parse `DAYS HOURS`, or `HOURS` alone for every day
+    fn from_str(s: &str) -> Result<Self> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            [hours] => Self::new("daily", hours),
+            [days, hours] => Self::new(days, hours),
+            _ => Err(ConnectoError::KeepWarm(format!(
+                "Invalid schedule '{}': expected days and hours, like mon-fri 09:00-18:00",
+                s
+            ))),
+        }
+    }
+}
+
+fn day_index(name: &str) -> Option<usize> {
+    let name = name.trim().to_ascii_lowercase();
+    DAY_NAMES
+        .iter()
+        .position(|day| name.len() >= 3 && day.starts_with(&name[..3]))
+}
+
+fn minutes(time: &str) -> Option<u32> {
+    let (hour, minute) = time.trim().split_once(':')?;
+    let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+    (hour < 24 && minute < 60 || hour == 24 && minute == 0).then_some(hour * 60 + minute)
+}
+
+/// Opens and closes SSH control masters
+pub trait ControlMaster {
+    /// Whether a master for `host` is up
+    fn is_running(&self, host: &str) -> bool;
+
+    /// Open a master for `host`, returning once it is logged in
+    fn start(&self, host: &str) -> Result<()>;
+
+    /// Have the master for `host` take no new sessions; it exits once the
+    /// open ones end
+    fn stop(&self, host: &str) -> Result<()>;
+}
+
+/// Control masters run by the system `ssh` client, never prompting
+///
+/// Masters are children of this process, so they are gone with it. Not
+/// supported by the OpenSSH client for Windows.
+#[derive(Debug, Default)]
+pub struct SshControlMaster {
+    children: Mutex<HashMap<String, Child>>,
+}
+
+impl SshControlMaster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn control(&self, host: &str, command: &str) -> bool {
+        Command::new("ssh")
+            .args(["-o", &format!("ControlPath={}", CONTROL_PATH)])
+            .args(["-O", command, host])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+}
+
+impl ControlMaster for SshControlMaster {
+    fn is_running(&self, host: &str) -> bool {
+        self.control(host, "check")
+    }
+
+    fn start(&self, host: &str) -> Result<()> {
+        let mut children = self.children.lock().unwrap();
+        // Reap the master of an earlier start, if it ended
+        if let Some(mut old) = children.remove(host) {
+            if old.try_wait().ok().flatten().is_none() {
+                children.insert(host.to_string(), old);
+            }
+        }
+
+        let mut child = Command::new("ssh")
+            .args(["-o", &format!("ControlPath={}", CONTROL_PATH)])
+            .args([
+                "-o",
+                "ControlMaster=yes",
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ConnectTimeout=10",
+                "-o",
+                "ServerAliveInterval=30",
+                "-N",
+                host,
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| ConnectoError::Network(format!("Failed to run ssh: {}", e)))?;
+
+        let deadline = Instant::now() + START_TIMEOUT;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                let mut stderr = String::new();
+                if let Some(mut pipe) = child.stderr.take() {
+                    use std::io::Read;
+                    let _ = pipe.read_to_string(&mut stderr);
+                }
+                let reason = stderr
+                    .lines()
+                    .rev()
+                    .find(|line| !line.trim().is_empty())
+                    .map(|line| line.trim().to_string())
+                    .unwrap_or_else(|| format!("ssh exited with {}", status));
+                return Err(if reason.contains("Permission denied") {
+                    ConnectoError::PermissionDenied(reason)
+                } else {
+                    ConnectoError::Network(reason)
+                });
+            }
+            if self.is_running(host) {
+                children.insert(host.to_string(), child);
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(ConnectoError::Timeout(format!(
+                    "No connection to {} within {}s",
+                    host,
+                    START_TIMEOUT.as_secs()
+                )));
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    fn stop(&self, host: &str) -> Result<()> {
+        if self.control(host, "stop") {
+            Ok(())
+        } else {
+            Err(ConnectoError::Network(format!(
+                "Could not stop the connection to {}",
+                host
+            )))
+        }
+    }
+}
+
+/// How a keep-warm host is doing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HostStatus {
+    pub host: String,
+    pub schedule: String,
+    /// Whether the schedule is within its hours
+    pub in_hours: bool,
+    /// Whether a master connection is up
+    pub warm: bool,
+    /// Why the connection could not be opened, if it could not
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Keeps the hosts with a keep-warm schedule connected during their hours
+pub struct KeepWarm<M> {
+    master: M,
+    ssh_config: SshConfig,
+}
+
+impl<M: ControlMaster> KeepWarm<M> {
+    pub fn new(master: M, ssh_config: SshConfig) -> Self {
+        Self { master, ssh_config }
+    }
+
+    /// Open masters for hosts within their hours and stop the others
+    ///
+    /// Called every [`CHECK_INTERVAL`] with the local time; a master that
+    /// dropped is opened again on the next call.
+    pub fn tick(&self, now: &NaiveDateTime) -> Result<Vec<HostStatus>> {
+        self.check(now, true)
+    }
+
+    /// How each keep-warm host is doing at `now`, changing nothing
+    pub fn status(&self, now: &NaiveDateTime) -> Result<Vec<HostStatus>> {
+        self.check(now, false)
+    }
+
+    /// Stop the masters of every keep-warm host, e.g. when shutting down
+    pub fn stop_all(&self) -> Result<()> {
+        for entry in self.ssh_config.entries()? {
+            if entry.keep_warm.is_some() && self.master.is_running(&entry.host) {
+                self.master.stop(&entry.host)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, now: &NaiveDateTime, act: bool) -> Result<Vec<HostStatus>> {
+        let mut statuses = Vec::new();
+        for entry in self.ssh_config.entries()? {
+            let Some(schedule) = entry.keep_warm else {
+                continue;
+            };
+            let in_hours = schedule.is_active(now);
+            let mut warm = self.master.is_running(&entry.host);
+            let mut error = None;
+            if act && in_hours && !warm {
+                match self.master.start(&entry.host) {
+                    Ok(()) => warm = true,
+                    Err(e) => error = Some(e.to_string()),
+                }
+            } else if act && !in_hours && warm {
+                match self.master.stop(&entry.host) {
+                    Ok(()) => warm = false,
+                    Err(e) => error = Some(e.to_string()),
+                }
+            }
+            statuses.push(HostStatus {
+                host: entry.host,
+                schedule: schedule.to_string(),
+                in_hours,
+                warm,
+                error,
+            });
+        }
+        Ok(statuses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// 2026-10-12 is a Monday
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, 11 + day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let work: Schedule = "mon-fri 09:00-18:00".parse().unwrap();
+        assert_eq!(work, Schedule::WORK_HOURS);
+        assert_eq!(work.to_string(), "mon-fri 09:00-18:00");
+        assert!(work.is_active(&at(1, 9, 0)));
+        assert!(work.is_active(&at(5, 17, 59)));
+        assert!(!work.is_active(&at(1, 18, 0)));
+        assert!(!work.is_active(&at(6, 12, 0)));
+
+        // Past midnight, into the next day
+        let night: Schedule = "fri-sun 22:00-02:00".parse().unwrap();
+        assert_eq!(night.to_string(), "fri-sun 22:00-02:00");
+        assert!(night.is_active(&at(5, 23, 0)));
+        assert!(night.is_active(&at(6, 1, 59)));
+        assert!(night.is_active(&at(8, 1, 0)));
+        assert!(!night.is_active(&at(5, 1, 0)));
+        assert!(!night.is_active(&at(2, 1, 0)));
+
+        assert_eq!(
+            "08:00-24:00".parse::<Schedule>().unwrap().to_string(),
+            "daily 08:00-24:00"
+        );
+        assert_eq!(
+            "Monday,wed,thu 10:00-12:30"
+                .parse::<Schedule>()
+                .unwrap()
+                .to_string(),
+            "mon,wed,thu 10:00-12:30"
+        );
+        for invalid in [
+            "",
+            "mon-fri",
+            "mon-fri 9-18",
+            "someday 09:00-18:00",
+            "mon 09:00-09:00",
+            "mon 09:00-25:00",
+            "mon 24:00-08:00",
+            "mon 09:00-18:00 extra",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{}", invalid);
+        }
+    }
+
+    /// Masters that come up for every host but `down`
+    #[derive(Default)]
+    struct FakeMaster {
+        running: Mutex<BTreeSet<String>>,
+    }
+
+    impl ControlMaster for FakeMaster {
+        fn is_running(&self, host: &str) -> bool {
+            self.running.lock().unwrap().contains(host)
+        }
+
+        fn start(&self, host: &str) -> Result<()> {
+            if host == "down" {
+                return Err(ConnectoError::Network("No route to host".to_string()));
+            }
+            self.running.lock().unwrap().insert(host.to_string());
+            Ok(())
+        }
+
+        fn stop(&self, host: &str) -> Result<()> {
+            self.running.lock().unwrap().remove(host);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_keep_warm() {
+        let temp = TempDir::new().unwrap();
+        let ssh_config = SshConfig::with_path(temp.path().join("config"));
+        fs::write(
+            ssh_config.path(),
+            "# Added by connecto\nHost desk\n    HostName 10.0.0.5\n    User me\n    IdentityFile ~/.ssh/connecto_desk\n\n# Added by connecto\nHost down\n    HostName 10.0.0.6\n    User me\n    IdentityFile ~/.ssh/connecto_down\n\n# Added by connecto\nHost nas\n    HostName 10.0.0.7\n    User me\n    IdentityFile ~/.ssh/connecto_nas\n",
+        )
+        .unwrap();
+        for host in ["desk", "down"] {
+            assert!(ssh_config
+                .set_keep_warm(host, Some(Schedule::WORK_HOURS))
+                .unwrap());
+        }
+
+        let keeper = KeepWarm::new(FakeMaster::default(), ssh_config);
+        let statuses = keeper.tick(&at(1, 10, 0)).unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].host, "desk");
+        assert!(statuses[0].in_hours && statuses[0].warm);
+        assert_eq!(statuses[1].host, "down");
+        assert!(!statuses[1].warm);
+        assert_eq!(
+            statuses[1].error.as_deref(),
+            Some("Network error: No route to host")
+        );
+        assert!(keeper.master.is_running("desk"));
+
+        // Reporting changes nothing
+        let statuses = keeper.status(&at(1, 19, 0)).unwrap();
+        assert!(!statuses[0].in_hours && statuses[0].warm);
+
+        let statuses = keeper.tick(&at(1, 19, 0)).unwrap();
+        assert!(!statuses[0].warm && statuses[0].error.is_none());
+        assert!(!keeper.master.is_running("desk"));
+
+        keeper.tick(&at(2, 9, 30)).unwrap();
+        keeper.stop_all().unwrap();
+        assert!(keeper.master.running.lock().unwrap().is_empty());
+    }
+}
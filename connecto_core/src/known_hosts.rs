@@ -0,0 +1,833 @@
+//! Known hosts module
+//!
+//! Records the SSH host keys of paired servers in `~/.ssh/known_hosts`, so
+//! the first `ssh` after pairing does not ask whether to trust the host.
+//! Servers send their host keys during pairing (see
+//! [`protocol::HOST_KEYS_VERSION`](crate::protocol::HOST_KEYS_VERSION)).
+//! Host names can be stored hashed, as `ssh-keygen -H` writes them, and
+//! hashed entries are matched the way OpenSSH matches them.
+//!
+//! On the server side, it reads the host keys and port of this machine's
+//! SSH server to send them.
+
+use crate::connectivity::SSH_PORT;
+use crate::error::{ConnectoError, Result};
+use crate::keys::{self, KeyManager, SshKeyPair};
+use base64ct::{Base64, Encoding};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Prefix of a hashed host name: `|1|<salt>|<hash>`
+const HASH_MAGIC: &str = "|1|";
+
+/// Length of the salt of a hashed host name, as OpenSSH uses
+const SALT_LEN: usize = 20;
+
+/// One entry in known_hosts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownHost {
+    /// `cert-authority` or `revoked` for entries marked with `@`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker: Option<String>,
+    /// Host patterns as written, e.g. `desk,192.168.1.10` or a hashed
+    /// `|1|…|…`
+    pub hosts: String,
+    /// Key type, e.g. `ssh-ed25519`
+    pub key_type: String,
+    /// Base64 key data
+    pub blob: String,
+    /// Text after the key, empty if there is none
+    pub comment: String,
+}
+
+impl KnownHost {
+    /// Whether the host name is stored hashed
+    pub fn is_hashed(&self) -> bool {
+        self.hosts.starts_with(HASH_MAGIC)
+    }
+
+    /// Whether the entry is for `host` on `port`
+    ///
+    /// Patterns may use `*` and `?` wildcards, and a matching `!pattern`
+    /// excludes the host, as in OpenSSH.
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let name = host_pattern(host, port);
+        if self.is_hashed() {
+            return hashed_name_matches(&self.hosts, &name);
+        }
+        let mut matched = false;
+        for pattern in self.hosts.split(',') {
+            match pattern.strip_prefix('!') {
+                Some(negated) if wildcard_match(negated, &name) => return false,
+                Some(_) => {}
+                None => matched |= wildcard_match(pattern, &name),
+            }
+        }
+        matched
+    }
+
+    /// Whether the entry holds `public_key` (in OpenSSH format)
+    pub fn has_key(&self, public_key: &str) -> bool {
+        let mut parts = public_key.split_whitespace();
+        parts.next() == Some(self.key_type.as_str()) && parts.next() == Some(self.blob.as_str())
+    }
+
+    /// SHA-256 fingerprint of the host key
+    pub fn fingerprint(&self) -> Result<String> {
+        keys::fingerprint(&format!("{} {}", self.key_type, self.blob))
+    }
+}
+
+impl fmt::Display for KnownHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(marker) = &self.marker {
+            write!(f, "@{} ", marker)?;
+        }
+        write!(f, "{} {} {}", self.hosts, self.key_type, self.blob)?;
+        if !self.comment.is_empty() {
+            write!(f, " {}", self.comment)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for KnownHost {
+    type Err = ConnectoError;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let invalid = || ConnectoError::KeyParsing(format!("Not a known_hosts entry: {}", line));
+        let mut rest = line.trim();
+        let mut marker = None;
+        if let Some(marked) = rest.strip_prefix('@') {
+            let (name, after) = marked.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            marker = Some(name.to_string());
+            rest = after.trim_start();
+        }
+
+        let mut parts = rest
+            .splitn(4, char::is_whitespace)
+            .filter(|p| !p.is_empty());
+        let hosts = parts.next().ok_or_else(invalid)?;
+        let key_type = parts.next().ok_or_else(invalid)?;
+        let blob = parts.next().ok_or_else(invalid)?;
+        Ok(Self {
+            marker,
+            hosts: hosts.to_string(),
+            key_type: key_type.to_string(),
+            blob: blob.to_string(),
+            comment: parts.next().unwrap_or("").trim().to_string(),
+        })
+    }
+}
+
+/// The name OpenSSH looks up in known_hosts for `host` on `port`:
+/// `host` for port 22, `[host]:port` otherwise
+pub fn host_pattern(host: &str, port: u16) -> String {
+    if port == SSH_PORT {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// Hash a name from [`host_pattern`] with a random salt, as `ssh-keygen -H`
+/// does
+pub fn hash_host(name: &str) -> String {
+    hash_with_salt(name, &rand::random::<[u8; SALT_LEN]>())
+}
+
+fn hash_with_salt(name: &str, salt: &[u8]) -> String {
+    format!(
+        "{}{}|{}",
+        HASH_MAGIC,
+        Base64::encode_string(salt),
+        Base64::encode_string(&hmac_sha1(salt, name.as_bytes()))
+    )
+}
+
+fn hashed_name_matches(hashed: &str, name: &str) -> bool {
+    let Some((salt, _)) = hashed
+        .strip_prefix(HASH_MAGIC)
+        .and_then(|rest| rest.split_once('|'))
+    else {
+        return false;
+    };
+    Base64::decode_vec(salt).is_ok_and(|salt| hash_with_salt(name, &salt) == hashed)
+}
+
+/// Match `name` against a pattern with `*` and `?` wildcards
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of the name it has taken
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// HMAC-SHA1, which OpenSSH uses to hash host names
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..20].copy_from_slice(&sha1(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner: Vec<u8> = block
+        .iter()
+        .map(|b| b ^ 0x36)
+        .chain(message.iter().copied())
+        .collect();
+    let outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).chain(sha1(&inner)).collect();
+    sha1(&outer)
+}
+
+/// SHA-1 (FIPS 180-4)
+///
+/// Only used for hashed host names, whose format OpenSSH fixed to SHA-1;
+/// it protects nothing but the privacy of the names.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(chunk.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, s) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&s.to_be_bytes());
+    }
+    digest
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    Host(KnownHost),
+    /// A comment, blank line, or anything else that is not an entry
+    Other(String),
+}
+
+/// The contents of a known_hosts file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnownHostsFile {
+    lines: Vec<Line>,
+}
+
+impl KnownHostsFile {
+    /// Parse the contents of a known_hosts file
+    pub fn parse(content: &str) -> Self {
+        let lines = content
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    return Line::Other(line.to_string());
+                }
+                match trimmed.parse() {
+                    Ok(entry) => Line::Host(entry),
+                    Err(_) => Line::Other(line.to_string()),
+                }
+            })
+            .collect();
+        Self { lines }
+    }
+
+    /// The entries, in file order
+    pub fn entries(&self) -> impl Iterator<Item = &KnownHost> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Host(entry) => Some(entry),
+            Line::Other(_) => None,
+        })
+    }
+
+    /// Whether any entry has a hashed host name
+    pub fn is_hashed(&self) -> bool {
+        self.entries().any(KnownHost::is_hashed)
+    }
+
+    /// Append an entry
+    pub fn push(&mut self, entry: KnownHost) {
+        self.lines.push(Line::Host(entry));
+    }
+
+    /// Remove the entries `remove` returns `true` for, returning them
+    pub fn remove_where(&mut self, mut remove: impl FnMut(&KnownHost) -> bool) -> Vec<KnownHost> {
+        let mut removed = Vec::new();
+        self.lines.retain(|line| match line {
+            Line::Host(entry) if remove(entry) => {
+                removed.push(entry.clone());
+                false
+            }
+            _ => true,
+        });
+        removed
+    }
+}
+
+impl fmt::Display for KnownHostsFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Host(entry) => writeln!(f, "{}", entry)?,
+                Line::Other(text) => writeln!(f, "{}", text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What [`KnownHostsStore::record`] changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recorded {
+    /// Host keys added
+    pub added: usize,
+    /// Earlier keys of the host that were not among the keys sent, and so
+    /// were removed. A host that was reinstalled has new host keys.
+    pub replaced: Vec<KnownHost>,
+}
+
+/// `~/.ssh/known_hosts`, or another known_hosts file
+#[derive(Debug, Clone)]
+pub struct KnownHostsStore {
+    path: PathBuf,
+}
+
+impl KnownHostsStore {
+    /// The known_hosts file in the default SSH directory
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            path: KeyManager::default_ssh_dir()?.join("known_hosts"),
+        })
+    }
+
+    /// Use a specific known_hosts file
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the file; a missing file has no entries
+    pub fn load(&self) -> Result<KnownHostsFile> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(KnownHostsFile::parse(&content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(KnownHostsFile::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, file: &KnownHostsFile) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let existed = self.path.exists();
+        fs::write(&self.path, file.to_string())?;
+
+        #[cfg(unix)]
+        if !existed {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+        }
+        #[cfg(not(unix))]
+        let _ = existed;
+        Ok(())
+    }
+
+    /// Every entry, in file order
+    pub fn list(&self) -> Result<Vec<KnownHost>> {
+        Ok(self.load()?.entries().cloned().collect())
+    }
+
+    /// Trust `host_keys` (public keys in OpenSSH format) for `host` on
+    /// `port`
+    ///
+    /// Other keys of the host are removed: the keys come from the host
+    /// itself over a verified pairing, so they are current. New entries are
+    /// hashed if the file already holds hashed entries, keeping it as
+    /// private as its owner chose. Entries marked `@revoked` or
+    /// `@cert-authority` are left alone.
+    pub fn record(&self, host: &str, port: u16, host_keys: &[String]) -> Result<Recorded> {
+        let mut file = self.load()?;
+        let hashed = file.is_hashed();
+
+        let replaced = file.remove_where(|entry| {
+            entry.marker.is_none()
+                && entry.matches(host, port)
+                && !host_keys.iter().any(|key| entry.has_key(key))
+        });
+
+        let mut added = 0;
+        for key in host_keys {
+            let known = file.entries().any(|entry| {
+                entry.marker.is_none() && entry.matches(host, port) && entry.has_key(key)
+            });
+            if known {
+                continue;
+            }
+            let parsed = SshKeyPair::parse_public_key(key)?;
+            let name = host_pattern(host, port);
+            file.push(KnownHost {
+                marker: None,
+                hosts: if hashed { hash_host(&name) } else { name },
+                key_type: parsed.algorithm().to_string(),
+                blob: key
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string(),
+                comment: String::new(),
+            });
+            added += 1;
+        }
+
+        if added > 0 || !replaced.is_empty() {
+            self.save(&file)?;
+        }
+        Ok(Recorded { added, replaced })
+    }
+
+    /// The host keys trusted for `host` on `port`, in OpenSSH format
+    ///
+    /// Entries marked `@revoked` or `@cert-authority` are not host keys of
+    /// their own and are left out.
+    pub fn keys_of(&self, host: &str, port: u16) -> Result<Vec<String>> {
+        Ok(self
+            .load()?
+            .entries()
+            .filter(|entry| entry.marker.is_none() && entry.matches(host, port))
+            .map(|entry| format!("{} {}", entry.key_type, entry.blob))
+            .collect())
+    }
+
+    /// Remove every entry for `host` on `port`, like `ssh-keygen -R`,
+    /// returning the entries removed
+    ///
+    /// As with `ssh-keygen -R`, `@revoked` and `@cert-authority` entries
+    /// are kept.
+    pub fn remove(&self, host: &str, port: u16) -> Result<Vec<KnownHost>> {
+        let mut file = self.load()?;
+        let removed =
+            file.remove_where(|entry| entry.marker.is_none() && entry.matches(host, port));
+        if !removed.is_empty() {
+            self.save(&file)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// Directory holding this machine's SSH host keys
+pub fn host_key_dir() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    {
+        PathBuf::from(r"C:\ProgramData\ssh")
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        PathBuf::from("/etc/ssh")
+    }
+}
+
+/// The public SSH host keys of this machine (`ssh_host_*_key.pub`), without
+/// their comments; empty if there are none or they cannot be read
+pub fn local_host_keys() -> Vec<String> {
+    read_host_keys(&host_key_dir())
+}
+
+/// The port this machine's SSH server listens on, from the first `Port` in
+/// `sshd_config` and the files it includes; 22 if it sets none
+///
+/// A `ListenAddress` with a port counts when no `Port` is set.
+pub fn local_ssh_port() -> u16 {
+    let mut ports = SshdPorts::default();
+    ports.read(&host_key_dir().join("sshd_config"), 0);
+    ports.port().unwrap_or(SSH_PORT)
+}
+
+/// How deep `Include`s of `sshd_config` are followed
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Ports found in `sshd_config`
+#[derive(Debug, Default)]
+struct SshdPorts {
+    port: Option<u16>,
+    listen_port: Option<u16>,
+    /// A `Match` block was reached; everything after it is conditional
+    done: bool,
+}
+
+impl SshdPorts {
+    fn port(&self) -> Option<u16> {
+        self.port.or(self.listen_port)
+    }
+
+    fn read(&mut self, path: &Path, depth: usize) {
+        if let Ok(content) = fs::read_to_string(path) {
+            self.parse(&content, path.parent().unwrap_or(Path::new("")), depth);
+        }
+    }
+
+    fn parse(&mut self, content: &str, dir: &Path, depth: usize) {
+        for line in content.lines() {
+            if self.done || self.port.is_some() {
+                return;
+            }
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Keywords are case-insensitive and may be followed by `=`
+            let (keyword, args) = line
+                .split_once(|c: char| c.is_whitespace() || c == '=')
+                .unwrap_or((line, ""));
+            let args = args.trim_start_matches(|c: char| c.is_whitespace() || c == '=');
+            match keyword.to_lowercase().as_str() {
+                "port" => self.port = args.split_whitespace().next().and_then(|p| p.parse().ok()),
+                "listenaddress" if self.listen_port.is_none() => {
+                    let address = args.split_whitespace().next().unwrap_or("");
+                    self.listen_port = address
+                        .rsplit_once(':')
+                        .filter(|(host, _)| !host.contains(':') || host.ends_with(']'))
+                        .and_then(|(_, port)| port.parse().ok());
+                }
+                "include" if depth < MAX_INCLUDE_DEPTH => {
+                    for pattern in args.split_whitespace() {
+                        for path in expand_include(dir, pattern) {
+                            self.read(&path, depth + 1);
+                        }
+                    }
+                }
+                "match" => self.done = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The files an `Include` pattern names, in lexical order as sshd reads
+/// them; relative patterns are relative to `dir`
+fn expand_include(dir: &Path, pattern: &str) -> Vec<PathBuf> {
+    let path = dir.join(pattern);
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Vec::new();
+    };
+    if !name.contains(['*', '?']) {
+        return vec![path];
+    }
+    let Some(parent) = path.parent() else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(parent) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|file| file.to_str())
+                .is_some_and(|file| wildcard_match(name, file))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn read_host_keys(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("ssh_host_") && name.ends_with("_key.pub"))
+        })
+        .collect();
+    paths.sort();
+
+    paths
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .filter_map(|content| {
+            let key = SshKeyPair::parse_public_key(content.trim()).ok()?;
+            let blob = content.split_whitespace().nth(1)?;
+            Some(format!("{} {}", key.algorithm(), blob))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAINbgPhPjAs5nRMnMgrJCO8ryVDeoTAERX3CACqShfgww";
+
+    #[test]
+    fn test_sha1() {
+        let hex =
+            |bytes: [u8; 20]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(
+            hex(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        // Two blocks after padding
+        assert_eq!(
+            hex(sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        // RFC 2202, test case 2
+        assert_eq!(
+            hex(hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+    }
+
+    #[test]
+    fn test_hashed_names_match_ssh_keygen() {
+        // Written by `ssh-keygen -H`
+        let entry: KnownHost = format!(
+            "|1|Db7DF6rGeoE0UcM+7vc1cMRsOFU=|QHp5uN4IV6uhWt/kDa+rONJv0Ug= {}",
+            KEY
+        )
+        .parse()
+        .unwrap();
+        assert!(entry.is_hashed());
+        assert!(entry.matches("192.168.1.10", 22));
+        assert!(!entry.matches("192.168.1.11", 22));
+        assert!(!entry.matches("192.168.1.10", 2222));
+
+        let entry: KnownHost = format!(
+            "|1|DthuGZEl/9b7hm9Yf2ExrYm0h3Y=|2fZGAbGCHmMI21i9bmLANP+1kIk= {}",
+            KEY
+        )
+        .parse()
+        .unwrap();
+        assert!(entry.matches("desk.local", 2222));
+
+        let hashed = hash_host("desk");
+        assert!(hashed_name_matches(&hashed, "desk"));
+        assert_ne!(hash_host("desk"), hashed, "salts are random");
+    }
+
+    #[test]
+    fn test_patterns() {
+        let entry: KnownHost = format!("@cert-authority *.lan,!bad.lan,[desk]:2222 {} ca", KEY)
+            .parse()
+            .unwrap();
+        assert_eq!(entry.marker.as_deref(), Some("cert-authority"));
+        assert_eq!(entry.comment, "ca");
+        assert!(entry.matches("nas.lan", 22));
+        assert!(!entry.matches("bad.lan", 22));
+        assert!(entry.matches("desk", 2222));
+        assert!(!entry.matches("desk", 22));
+        assert_eq!(
+            entry.to_string(),
+            format!("@cert-authority *.lan,!bad.lan,[desk]:2222 {} ca", KEY)
+        );
+
+        assert!(wildcard_match("10.0.0.?", "10.0.0.7"));
+        assert!(wildcard_match("*", "anything"));
+        assert!(!wildcard_match("10.0.0.?", "10.0.0.17"));
+        assert!("desk".parse::<KnownHost>().is_err());
+    }
+
+    #[test]
+    fn test_record_and_remove() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KnownHostsStore::with_path(temp_dir.path().join("known_hosts"));
+        fs::write(
+            store.path(),
+            "# mine\nother ssh-ed25519 AAAA\n@revoked * ssh-rsa BBBB\n",
+        )
+        .unwrap();
+
+        let old = SshKeyPair::generate(keys::KeyAlgorithm::Ed25519, "root@desk").unwrap();
+        let recorded = store
+            .record("192.168.1.10", 22, std::slice::from_ref(&old.public_key))
+            .unwrap();
+        assert_eq!(recorded.added, 1);
+        // Recording the same key again changes nothing
+        let recorded = store
+            .record("192.168.1.10", 22, std::slice::from_ref(&old.public_key))
+            .unwrap();
+        assert_eq!(recorded, Recorded::default());
+
+        // A reinstalled host has a new key
+        let recorded = store
+            .record("192.168.1.10", 22, &[KEY.to_string()])
+            .unwrap();
+        assert_eq!(recorded.added, 1);
+        assert_eq!(recorded.replaced.len(), 1);
+        assert!(recorded.replaced[0].has_key(&old.public_key));
+
+        let content = fs::read_to_string(store.path()).unwrap();
+        assert_eq!(
+            content,
+            format!(
+                "# mine\nother ssh-ed25519 AAAA\n@revoked * ssh-rsa BBBB\n192.168.1.10 {}\n",
+                KEY
+            )
+        );
+
+        assert_eq!(
+            store.keys_of("192.168.1.10", 22).unwrap(),
+            [KEY.to_string()]
+        );
+        assert!(store.keys_of("192.168.1.10", 2222).unwrap().is_empty());
+
+        // Revocations are kept
+        let removed = store.remove("192.168.1.10", 22).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(store.list().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_record_hashes_when_file_does() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KnownHostsStore::with_path(temp_dir.path().join("known_hosts"));
+        fs::write(
+            store.path(),
+            format!(
+                "|1|Db7DF6rGeoE0UcM+7vc1cMRsOFU=|QHp5uN4IV6uhWt/kDa+rONJv0Ug= {}\n",
+                KEY
+            ),
+        )
+        .unwrap();
+
+        store
+            .record("desk.local", 2222, &[KEY.to_string()])
+            .unwrap();
+        let entries = store.list().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[1].is_hashed());
+        assert!(entries[1].matches("desk.local", 2222));
+
+        assert_eq!(store.remove("192.168.1.10", 22).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_read_host_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join("ssh_host_ed25519_key.pub"),
+            format!("{} root@desk\n", KEY),
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("ssh_host_ed25519_key"), "private").unwrap();
+        fs::write(temp_dir.path().join("ssh_host_rsa_key.pub"), "garbage").unwrap();
+        assert_eq!(read_host_keys(temp_dir.path()), [KEY]);
+        assert!(read_host_keys(&temp_dir.path().join("missing")).is_empty());
+    }
+
+    fn sshd_port(content: &str) -> Option<u16> {
+        let mut ports = SshdPorts::default();
+        ports.parse(content, Path::new("/nonexistent"), 0);
+        ports.port()
+    }
+
+    #[test]
+    fn test_sshd_port() {
+        assert_eq!(sshd_port("#Port 22\nPermitRootLogin no\n"), None);
+        assert_eq!(sshd_port("port 2222\nPort 2200\n"), Some(2222));
+        assert_eq!(sshd_port("Port=2022\n"), Some(2022));
+        // Port wins over ListenAddress, which counts without it
+        assert_eq!(
+            sshd_port("ListenAddress 0.0.0.0:2200\nPort 2222\n"),
+            Some(2222)
+        );
+        assert_eq!(sshd_port("ListenAddress [::]:2200\n"), Some(2200));
+        assert_eq!(sshd_port("ListenAddress fe80::1\n"), None);
+        // Settings in Match blocks are conditional
+        assert_eq!(sshd_port("Match User git\n    Port 2222\n"), None);
+    }
+
+    #[test]
+    fn test_sshd_port_follows_includes() {
+        let temp_dir = TempDir::new().unwrap();
+        let conf_d = temp_dir.path().join("sshd_config.d");
+        fs::create_dir(&conf_d).unwrap();
+        fs::write(
+            conf_d.join("10-hardening.conf"),
+            "PasswordAuthentication no\n",
+        )
+        .unwrap();
+        fs::write(conf_d.join("50-port.conf"), "Port 2222\n").unwrap();
+        fs::write(conf_d.join("90-port.conf"), "Port 2200\n").unwrap();
+        fs::write(conf_d.join("port.bak"), "Port 9\n").unwrap();
+        let config = temp_dir.path().join("sshd_config");
+        fs::write(
+            &config,
+            "Include sshd_config.d/*.conf\nPort 22\nInclude missing/*.conf\n",
+        )
+        .unwrap();
+
+        let mut ports = SshdPorts::default();
+        ports.read(&config, 0);
+        assert_eq!(ports.port(), Some(2222));
+    }
+}
@@ -6,11 +6,54 @@
 //!
 //! # Architecture
 //!
-//! The library is organized into three main modules:
+//! The library is organized into the following main modules:
 //!
+//! - [`access`]: Which clients a listener answers, by address and device name
+//! - [`accounts`]: Local accounts a listener installs keys for
+//! - [`attempts`]: Duplicate-attempt suppression and key reuse for retries
+//! - [`audit`]: Tamper-evident logs of accept/reject decisions and security events
+//! - [`authorized_keys`]: Structured reading and writing of `authorized_keys`
+//! - [`backups`]: Atomic rewrites of SSH files, with backups to roll them back
+//! - [`batch`]: Concurrent pairing with several devices
+//! - [`capabilities`]: Features peers announce in the pairing handshake
+//! - [`channel`]: Encrypted channels between devices
+//! - [`clock`]: Clock skew between paired devices
+//! - [`devices`]: Discovered devices kept in bounded memory
+//! - [`diagnostics`]: Checks of what discovery, pairing and SSH need on this machine
+//! - [`connectivity`]: Route-aware reachability checks for paired hosts
 //! - [`discovery`]: mDNS-based device discovery using the `mdns-sd` crate
+//! - [`firewall`]: Firewalls that keep clients from reaching a listener
+//! - [`forges`]: Public keys people publish on GitHub and GitLab
+//! - [`framing`]: How protocol messages are delimited on the wire, with size caps
+//! - [`i18n`]: Translated user-facing messages
+//! - [`identity`]: The persistent identity of this device
+//! - [`keepwarm`]: Open connections to paired hosts during set hours
 //! - [`keys`]: SSH key generation, parsing, and management
+//! - [`known_hosts`]: Host keys of paired servers in `~/.ssh/known_hosts`
+//! - [`limits`]: Rate limits and timeouts that protect listeners from abusive clients
+//! - [`net`]: Connection strings, including scoped IPv6 link-local addresses
+//! - [`next_steps`]: Suggested actions after pairing, syncing or testing
+//! - [`notifications`]: Desktop notifications for listener events
+//! - [`pairings`]: A record of every successful pairing
+//! - [`paths`]: Where Connecto keeps its files, with overrides for tests and containers
+//! - [`portmap`]: Forwarding the listener's port on the router with NAT-PMP or UPnP
+//! - [`ports`]: Who holds a port that cannot be bound
+//! - [`power`]: Sleep and wake notifications for listeners
 //! - [`protocol`]: The handshake protocol for secure key exchange
+//! - [`relay`]: Pairing through a rendezvous server across subnets and NAT
+//! - [`relocation`]: Following paired hosts that moved to a new address
+//! - [`renames`]: Following paired devices that changed their name
+//! - [`retry`]: Retrying connections with backoff on flaky networks
+//! - [`rotation`]: Replacing the keys of paired hosts
+//! - [`sealed`]: Data encrypted with a passphrase
+//! - [`session_log`]: The pairing attempts a running listener received
+//! - [`shutdown`]: Stopping running servers from another task
+//! - [`ssh_client`]: A built-in SSH client that tests logging in to paired hosts
+//! - [`ssh_config`]: Host entries Connecto writes to `~/.ssh/config`
+//! - [`stdio`]: Pairing over stdin and stdout, for channels Connecto does not open
+//! - [`sync_keys`]: Key lists the sync daemon keeps up to date between peers
+//! - [`transfer`]: Sending files to paired devices
+//! - [`trust`]: Trust-on-first-use pinning of peer identities
 //!
 //! # Example
 //!
@@ -36,7 +79,7 @@
 //!     let devices = browser.scan_for_duration(std::time::Duration::from_secs(5)).await?;
 //!
 //!     if let Some(device) = devices.first() {
-//!         let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "user@host")?;
+//!         let key_pair = SshKeyPair::generate_async(KeyAlgorithm::Ed25519, "user@host").await?;
 //!         let client = HandshakeClient::new("My Laptop");
 //!
 //!         if let Some(addr) = device.connection_string() {
@@ -47,13 +90,60 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! The `examples/` directory has complete programs built on this API:
+//! `listener`, `pair`, `sync`, and `scan`. Run one with
+//! `cargo run -p connecto_core --example listener`.
 
+pub mod access;
+pub mod accounts;
+pub mod attempts;
+pub mod audit;
+pub mod authorized_keys;
+pub mod backups;
+pub mod batch;
+pub mod capabilities;
+pub mod channel;
+pub mod clock;
+pub mod connectivity;
+pub mod devices;
+pub mod diagnostics;
 pub mod discovery;
 pub mod error;
 pub mod fallback;
+pub mod firewall;
+pub mod forges;
+pub mod framing;
+pub mod i18n;
+pub mod identity;
+pub mod keepwarm;
 pub mod keys;
+pub mod known_hosts;
+pub mod limits;
+pub mod net;
+pub mod next_steps;
+pub mod notifications;
+pub mod pairings;
+pub mod paths;
+pub mod portmap;
+pub mod ports;
+pub mod power;
 pub mod protocol;
+pub mod relay;
+pub mod relocation;
+pub mod renames;
+pub mod retry;
+pub mod rotation;
+pub mod sealed;
+pub mod session_log;
+pub mod shutdown;
+pub mod ssh_client;
+pub mod ssh_config;
+pub mod stdio;
 pub mod sync;
+pub mod sync_keys;
+pub mod transfer;
+pub mod trust;
 
 // Re-export commonly used types
 pub use discovery::{
@@ -61,11 +151,21 @@ pub use discovery::{
     DEFAULT_PORT, SERVICE_TYPE,
 };
 pub use error::{ConnectoError, Result};
+pub use identity::DeviceIdentity;
 pub use keys::{KeyAlgorithm, KeyManager, SshKeyPair};
 pub use protocol::{
-    HandshakeClient, HandshakeServer, Message, PairingResult, ServerEvent, PROTOCOL_VERSION,
+    HandshakeClient, HandshakeServer, Message, PairingResult, ProtocolErrorCode, ServerEvent,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use shutdown::ShutdownHandle;
+pub use sync::{
+    KeyOutcome, ReceivedKey, SyncEvent, SyncHandler, SyncResult, DEFAULT_SYNC_INTERVAL_SECS,
+    DEFAULT_SYNC_TIMEOUT_SECS, SYNC_SERVICE_TYPE,
+};
+pub use transfer::{
+    FileReceiver, FileSender, TransferEvent, TransferResult, DEFAULT_TRANSFER_PORT,
 };
-pub use sync::{SyncEvent, SyncHandler, SyncResult, DEFAULT_SYNC_TIMEOUT_SECS, SYNC_SERVICE_TYPE};
+pub use trust::{TrustLevel, TrustMode, TrustStore};
 
 /// Get the version of the connecto_core library
 pub fn version() -> &'static str {
@@ -77,6 +177,11 @@ pub fn hostname() -> String {
     discovery::get_hostname()
 }
 
+/// Get the name people know this device by, e.g. "John's MacBook Pro"
+pub fn device_name() -> String {
+    discovery::get_device_name()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,6 +204,7 @@ mod tests {
         let _ = DEFAULT_PORT;
         let _ = SERVICE_TYPE;
         let _ = PROTOCOL_VERSION;
+        let _ = MIN_PROTOCOL_VERSION;
         let _ = KeyAlgorithm::default();
         let _ = SYNC_SERVICE_TYPE;
         let _ = DEFAULT_SYNC_TIMEOUT_SECS;
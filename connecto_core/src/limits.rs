@@ -0,0 +1,137 @@
+//! Limits that protect listeners from abusive clients
+//!
+//! A listener on a shared network answers anyone who connects. These limits
+//! keep a single host from flooding it with connections, hold the number of
+//! handshakes running at once in check, and make sure a client that stalls
+//! or sends endless data cannot tie a handshake up forever.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Handshakes a listener runs at once by default
+pub const DEFAULT_MAX_HANDSHAKES: usize = 16;
+
+/// New connections one address may open per minute by default
+pub const DEFAULT_CONNECTIONS_PER_MINUTE: u32 = 20;
+
+/// How long a listener waits for each message of a handshake by default
+pub const DEFAULT_STEP_TIMEOUT_SECS: u64 = 30;
+
+/// Longest message a listener reads by default, in bytes
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 16 * 1024;
+
+/// The window connection rates are counted over
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Thresholds a handshake server enforces on its clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeLimits {
+    /// Handshakes in progress at once; further connections are refused
+    pub max_handshakes: usize,
+    /// New connections one address may open per minute; 0 disables the limit
+    pub connections_per_minute: u32,
+    /// How long to wait for each message the client sends
+    ///
+    /// Waiting for a verification code or for the user to approve a request
+    /// has its own, longer timeout.
+    pub step_timeout: Duration,
+    /// Longest message the client may send, in bytes
+    pub max_message_len: usize,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        Self {
+            max_handshakes: DEFAULT_MAX_HANDSHAKES,
+            connections_per_minute: DEFAULT_CONNECTIONS_PER_MINUTE,
+            step_timeout: Duration::from_secs(DEFAULT_STEP_TIMEOUT_SECS),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+}
+
+/// Counts the connections each address opened in the last minute
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: u32,
+    seen: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    /// Allow `limit` connections per address and minute; 0 allows any number
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Record a connection from `ip`, and whether it is within the limit
+    ///
+    /// Refused connections count too, so a host that keeps hammering stays
+    /// locked out until it slows down.
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        // Forget addresses that have been quiet for a whole window
+        self.seen.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|t| now.duration_since(*t) >= RATE_WINDOW)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = self.seen.entry(ip.to_canonical()).or_default();
+        let allowed = times.len() < self.limit as usize;
+        // Keep no more than the limit, enough to tell when the host may retry
+        if !allowed {
+            times.pop_front();
+        }
+        times.push_back(now);
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(3);
+        let start = Instant::now();
+        for i in 0..3 {
+            assert!(limiter.allow_at(ip("10.0.0.5"), start + Duration::from_secs(i)));
+        }
+        assert!(!limiter.allow_at(ip("10.0.0.5"), start + Duration::from_secs(5)));
+        // Other addresses have their own budget
+        assert!(limiter.allow_at(ip("10.0.0.6"), start + Duration::from_secs(5)));
+        // IPv4-mapped addresses count as the IPv4 address
+        assert!(!limiter.allow_at(ip("::ffff:10.0.0.5"), start + Duration::from_secs(6)));
+
+        // Refused attempts keep the window full
+        assert!(!limiter.allow_at(ip("10.0.0.5"), start + Duration::from_secs(61)));
+        assert!(limiter.allow_at(ip("10.0.0.5"), start + Duration::from_secs(200)));
+        assert_eq!(limiter.seen.len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter_disabled() {
+        let mut limiter = RateLimiter::new(0);
+        let now = Instant::now();
+        assert!((0..100).all(|_| limiter.allow_at(ip("10.0.0.5"), now)));
+    }
+}
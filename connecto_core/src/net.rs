@@ -0,0 +1,379 @@
+//! Network address module
+//!
+//! Formats, parses, and connects to the addresses Connecto hands around as
+//! connection strings. IPv6 link-local addresses (`fe80::/10`) are only
+//! reachable through a specific interface, so they carry a scope:
+//! `[fe80::1%en0]:8099`. Scopes can be interface names or numeric indexes on
+//! every platform.
+
+use crate::error::{ConnectoError, Result};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// Whether `addr` is an IPv6 link-local address (`fe80::/10`)
+pub fn is_link_local(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+        IpAddr::V4(_) => false,
+    }
+}
+
+/// `addr` as a host name for SSH, scoped to `scope` if it is link-local
+///
+/// For example `192.168.1.10`, `2001:db8::1`, or `fe80::1%en0`.
+pub fn format_host(addr: IpAddr, scope: Option<&str>) -> String {
+    match scope {
+        Some(scope) if is_link_local(&addr) => format!("{}%{}", addr, scope),
+        _ => addr.to_string(),
+    }
+}
+
+/// A connection string for `addr` and `port`, scoped to `scope` if the
+/// address is link-local
+///
+/// IPv6 addresses are bracketed: `[fe80::1%en0]:8099`.
+pub fn format_address(addr: IpAddr, port: u16, scope: Option<&str>) -> String {
+    join_host_port(&format_host(addr, scope), port)
+}
+
+/// Join a host and port, bracketing IPv6 hosts
+pub fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// The host part of a connection string, without brackets or port
+///
+/// `[fe80::1%en0]:8099` gives `fe80::1%en0`, and `10.0.0.5:8099` gives
+/// `10.0.0.5`.
+pub fn host_of(address: &str) -> &str {
+    if let Some(rest) = address.strip_prefix('[') {
+        return rest.split_once(']').map_or(rest, |(host, _)| host);
+    }
+    match address.rsplit_once(':') {
+        // More than one colon without brackets is a bare IPv6 address
+        Some((host, _)) if !host.contains(':') => host,
+        _ => address,
+    }
+}
+
+/// An address of a local network interface, e.g. `192.168.1.20` on `en0`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub name: String,
+    pub address: IpAddr,
+}
+
+impl std::fmt::Display for InterfaceAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.address)
+    }
+}
+
+/// Addresses of the local interfaces, loopback left out
+pub fn interface_addresses() -> Vec<InterfaceAddress> {
+    if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|iface| !iface.is_loopback())
+        .map(|iface| InterfaceAddress {
+            address: iface.ip(),
+            name: iface.name,
+        })
+        .collect()
+}
+
+/// The addresses among `available` that `selectors` name, each an interface
+/// name such as `en0` or one of its addresses
+///
+/// Fails naming the first selector that matches nothing.
+pub fn select_interfaces(
+    available: &[InterfaceAddress],
+    selectors: &[String],
+) -> Result<Vec<InterfaceAddress>> {
+    let mut selected: Vec<InterfaceAddress> = Vec::new();
+    for selector in selectors {
+        let address = selector.parse::<IpAddr>().ok();
+        let matching: Vec<_> = available
+            .iter()
+            .filter(|iface| iface.name == *selector || Some(iface.address) == address)
+            .collect();
+        if matching.is_empty() {
+            let mut names: Vec<_> = available.iter().map(|iface| iface.name.as_str()).collect();
+            names.dedup();
+            return Err(ConnectoError::Network(format!(
+                "No interface or address '{}' on this machine (interfaces: {})",
+                selector,
+                names.join(", ")
+            )));
+        }
+        for iface in matching {
+            if !selected.contains(iface) {
+                selected.push(iface.clone());
+            }
+        }
+    }
+    Ok(selected)
+}
+
+/// Local interfaces with a link-local IPv6 address, as name and index
+pub fn link_local_interfaces() -> Vec<(String, u32)> {
+    let mut interfaces: Vec<(String, u32)> = Vec::new();
+    for iface in if_addrs::get_if_addrs().unwrap_or_default() {
+        if iface.is_loopback() || !is_link_local(&iface.ip()) {
+            continue;
+        }
+        if let Some(index) = iface.index {
+            if !interfaces.iter().any(|(_, i)| *i == index) {
+                interfaces.push((iface.name, index));
+            }
+        }
+    }
+    interfaces
+}
+
+/// The name of the only local interface with a link-local IPv6 address
+///
+/// With several such interfaces a link-local peer could be behind any of
+/// them, so `None` is returned and [`connect`] tries each one.
+pub fn default_link_local_scope() -> Option<String> {
+    match link_local_interfaces().as_slice() {
+        [(name, _)] => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// Interface index for a scope given as a name or a number
+pub(crate) fn scope_index(scope: &str) -> Result<u32> {
+    if let Ok(index) = scope.parse() {
+        return Ok(index);
+    }
+    link_local_interfaces()
+        .into_iter()
+        .find(|(name, _)| name == scope)
+        .map(|(_, index)| index)
+        .ok_or_else(|| {
+            ConnectoError::Network(format!(
+                "No interface named '{}' with an IPv6 link-local address",
+                scope
+            ))
+        })
+}
+
+/// Whether `host` is an mDNS hostname such as `desk-pc.local`
+pub fn is_mdns_hostname(host: &str) -> bool {
+    host.trim_end_matches('.')
+        .to_ascii_lowercase()
+        .ends_with(".local")
+}
+
+/// Whether `host` resolves to `addr` through the system resolver
+///
+/// `.local` names are looked up over mDNS where the system does that
+/// (macOS, Windows, and Linux with nss-mdns).
+pub fn resolves_to(host: &str, addr: IpAddr) -> bool {
+    (host, 0)
+        .to_socket_addrs()
+        .is_ok_and(|mut found| found.any(|found| found.ip() == addr))
+}
+
+/// Split `[ipv6%scope]:port` or `[ipv6]:port` into its parts
+fn parse_bracketed(address: &str) -> Option<(Ipv6Addr, Option<&str>, u16)> {
+    let (host, port) = address.strip_prefix('[')?.split_once("]:")?;
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+    Some((ip.parse().ok()?, scope, port.parse().ok()?))
+}
+
+/// Resolve a connection string to the socket addresses to try, in order
+///
+/// A link-local address without a scope gives one candidate per local
+/// interface that has a link-local address.
+pub async fn resolve(address: &str) -> Result<Vec<SocketAddr>> {
+    if let Some((ip, scope, port)) = parse_bracketed(address) {
+        let scope_ids = match scope {
+            Some(scope) => vec![scope_index(scope)?],
+            None if is_link_local(&IpAddr::V6(ip)) => link_local_interfaces()
+                .into_iter()
+                .map(|(_, index)| index)
+                .collect(),
+            None => vec![0],
+        };
+        return Ok(scope_ids
+            .into_iter()
+            .map(|scope_id| SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+            .collect());
+    }
+
+    Ok(tokio::net::lookup_host(address)
+        .await
+        .map_err(|e| ConnectoError::Network(format!("Cannot resolve {}: {}", address, e)))?
+        .collect())
+}
+
+/// Connect to a connection string, trying each address it resolves to
+pub async fn connect(address: &str) -> Result<TcpStream> {
+    let mut last_error = None;
+    for addr in resolve(address).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!("Failed to connect to {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(match last_error {
+        Some(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            ConnectoError::ConnectionRefused(format!("nothing is listening at {}", address))
+        }
+        Some(e) => ConnectoError::Network(format!("Failed to connect: {}", e)),
+        None => ConnectoError::Network(format!(
+            "Failed to connect: no usable address for {}",
+            address
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_interfaces() {
+        let iface = |name: &str, address: &str| InterfaceAddress {
+            name: name.to_string(),
+            address: address.parse().unwrap(),
+        };
+        let available = [
+            iface("en0", "192.168.1.20"),
+            iface("en0", "fe80::1"),
+            iface("utun3", "10.8.0.2"),
+        ];
+
+        let by_name = select_interfaces(&available, &["en0".to_string()]).unwrap();
+        assert_eq!(by_name, available[..2]);
+        let by_address =
+            select_interfaces(&available, &["10.8.0.2".to_string(), "utun3".to_string()]).unwrap();
+        assert_eq!(by_address, [available[2].clone()]);
+        assert_eq!(by_address[0].to_string(), "utun3 (10.8.0.2)");
+
+        let err = select_interfaces(&available, &["wlan0".to_string()])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'wlan0'"), "{}", err);
+        assert!(err.contains("en0, utun3"), "{}", err);
+    }
+
+    #[test]
+    fn test_is_link_local() {
+        assert!(is_link_local(&"fe80::1".parse().unwrap()));
+        assert!(is_link_local(&"febf::1".parse().unwrap()));
+        assert!(!is_link_local(&"fec0::1".parse().unwrap()));
+        assert!(!is_link_local(&"2001:db8::1".parse().unwrap()));
+        assert!(!is_link_local(&"169.254.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_format_address() {
+        let v4: IpAddr = "192.168.1.10".parse().unwrap();
+        let global: IpAddr = "2001:db8::1".parse().unwrap();
+        let link_local: IpAddr = "fe80::1".parse().unwrap();
+
+        assert_eq!(format_address(v4, 8099, Some("en0")), "192.168.1.10:8099");
+        assert_eq!(
+            format_address(global, 8099, Some("en0")),
+            "[2001:db8::1]:8099"
+        );
+        assert_eq!(format_address(link_local, 8099, None), "[fe80::1]:8099");
+        assert_eq!(
+            format_address(link_local, 8099, Some("en0")),
+            "[fe80::1%en0]:8099"
+        );
+        assert_eq!(format_host(link_local, Some("3")), "fe80::1%3");
+    }
+
+    #[test]
+    fn test_is_mdns_hostname() {
+        assert!(is_mdns_hostname("desk-pc.local"));
+        assert!(is_mdns_hostname("Desk-PC.LOCAL."));
+        assert!(!is_mdns_hostname("192.168.1.10"));
+        assert!(!is_mdns_hostname("desk.localdomain"));
+        assert!(!is_mdns_hostname("local"));
+    }
+
+    #[test]
+    fn test_resolves_to() {
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(resolves_to("127.0.0.1", localhost));
+        assert!(!resolves_to("127.0.0.1", "10.0.0.1".parse().unwrap()));
+        assert!(!resolves_to("desk.invalid", localhost));
+    }
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("10.0.0.5:8099"), "10.0.0.5");
+        assert_eq!(host_of("10.0.0.5"), "10.0.0.5");
+        assert_eq!(host_of("desk.local:8099"), "desk.local");
+        assert_eq!(host_of("[fe80::1%en0]:8099"), "fe80::1%en0");
+        assert_eq!(host_of("[2001:db8::1]:8099"), "2001:db8::1");
+        assert_eq!(host_of("2001:db8::1"), "2001:db8::1");
+    }
+
+    #[test]
+    fn test_parse_bracketed() {
+        let ip: Ipv6Addr = "fe80::1".parse().unwrap();
+        assert_eq!(
+            parse_bracketed("[fe80::1%en0]:8099"),
+            Some((ip, Some("en0"), 8099))
+        );
+        assert_eq!(parse_bracketed("[fe80::1]:22"), Some((ip, None, 22)));
+        assert_eq!(parse_bracketed("10.0.0.5:8099"), None);
+        assert_eq!(parse_bracketed("[fe80::1%en0]"), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_scoped() {
+        let addrs = resolve("[fe80::1%7]:8099").await.unwrap();
+        match addrs.as_slice() {
+            [SocketAddr::V6(addr)] => {
+                assert_eq!(addr.port(), 8099);
+                assert_eq!(addr.scope_id(), 7);
+            }
+            other => panic!("Expected one scoped address, got {:?}", other),
+        }
+
+        assert!(resolve("[fe80::1%no-such-interface]:8099").await.is_err());
+
+        let addrs = resolve("127.0.0.1:8099").await.unwrap();
+        assert_eq!(addrs, ["127.0.0.1:8099".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let err = connect(&address).await.unwrap_err();
+        assert!(matches!(err, ConnectoError::ConnectionRefused(_)));
+    }
+
+    #[tokio::test]
+    async fn test_connect_ipv6_loopback() {
+        let Ok(listener) = tokio::net::TcpListener::bind("[::1]:0").await else {
+            return; // No IPv6 in this environment
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        let address = format_address("::1".parse().unwrap(), port, None);
+        assert_eq!(address, format!("[::1]:{}", port));
+        assert!(connect(&address).await.is_ok());
+    }
+}
@@ -0,0 +1,397 @@
+//! Next steps module
+//!
+//! Suggests what to do after pairing, syncing or testing a host. The CLI and
+//! the GUI describe what they know as a [`Situation`] and show the
+//! [`NextStep`]s [`recommend`] returns, so both suggest the same commands in
+//! the same order.
+
+use crate::connectivity::{self, ProbeTarget, SSH_PORT};
+use crate::keys::SshKeyPair;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::time::Duration;
+
+/// How long to wait for the local SSH server to answer
+const SSHD_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What just happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// This device paired with another
+    #[default]
+    Paired,
+    /// Two devices exchanged keys both ways
+    Synced,
+    /// A connection to a paired host was tested
+    Tested,
+}
+
+/// Why a connection to a paired host failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionIssue {
+    /// The host did not answer at its saved address
+    Unreachable,
+    /// ssh refused to use the private key because of its permissions
+    KeyPermissions,
+    /// The host rejected the key
+    KeyRejected,
+    /// Something else
+    Unknown,
+}
+
+impl ConnectionIssue {
+    /// Whether `connecto test --fix` knows how to repair it
+    pub fn is_repairable(self) -> bool {
+        self != ConnectionIssue::Unknown
+    }
+}
+
+/// What is known after an event
+///
+/// Fields left at their defaults are treated as unknown, so callers only
+/// fill in what they checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Situation {
+    pub event: Event,
+    /// `Host` alias in `~/.ssh/config`, if the host has an entry there
+    pub host: Option<String>,
+    /// Command that connects without a config entry
+    pub ssh_command: Option<String>,
+    /// Adding the host to `~/.ssh/config` failed
+    pub ssh_config_failed: bool,
+    /// Address the device was paired at
+    pub address: Option<String>,
+    /// Path of the private key used for the host
+    pub key_path: Option<String>,
+    /// Whether the SSH agent holds the key, if it needs to
+    pub key_in_agent: Option<bool>,
+    /// Whether this machine's SSH server accepts connections, if checked
+    pub sshd_running: Option<bool>,
+    /// The device announced a different identity than the one pinned
+    pub identity_changed: bool,
+    /// Why the connection failed, if it did
+    pub issue: Option<ConnectionIssue>,
+    /// `connecto test --fix` already tried to repair the issue
+    pub repair_attempted: bool,
+}
+
+/// The kind of a suggested action, for picking an icon or button
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Connect,
+    LoadKey,
+    StartSshServer,
+    FixSshConfig,
+    Repair,
+    CheckHost,
+    UpdateAddress,
+    PairAgain,
+}
+
+/// A suggested action
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NextStep {
+    pub action: Action,
+    /// What to do, e.g. `Connect to desk`
+    pub title: String,
+    /// Command that does it, if there is one
+    pub command: Option<String>,
+}
+
+impl NextStep {
+    fn new(action: Action, title: impl Into<String>, command: Option<String>) -> Self {
+        Self {
+            action,
+            title: title.into(),
+            command,
+        }
+    }
+}
+
+/// The actions worth taking next, most important first
+pub fn recommend(situation: &Situation) -> Vec<NextStep> {
+    let name = situation.host.as_deref().unwrap_or("the host");
+    let mut steps = Vec::new();
+
+    if situation.identity_changed {
+        // Nothing was installed, so there is nothing to connect to yet
+        steps.push(NextStep::new(
+            Action::PairAgain,
+            "If the device was reinstalled, pair again and accept its new identity",
+            Some(match &situation.address {
+                Some(address) => format!("connecto pair {} --accept-new-identity", address),
+                None => "connecto pair <address> --accept-new-identity".to_string(),
+            }),
+        ));
+        return steps;
+    }
+
+    if let Some(issue) = situation.issue {
+        if issue.is_repairable() && !situation.repair_attempted {
+            steps.push(NextStep::new(
+                Action::Repair,
+                "Let Connecto attempt the fix",
+                Some(format!("connecto test {} --fix", name)),
+            ));
+        }
+        match issue {
+            ConnectionIssue::KeyPermissions => steps.push(NextStep::new(
+                Action::Repair,
+                "Make the private key readable only by you",
+                situation
+                    .key_path
+                    .as_ref()
+                    .map(|path| format!("chmod 600 {}", path)),
+            )),
+            ConnectionIssue::KeyRejected => {
+                if let Some(path) = &situation.key_path {
+                    steps.push(NextStep::new(
+                        Action::LoadKey,
+                        "Add the key to the SSH agent",
+                        Some(format!("ssh-add {}", path)),
+                    ));
+                }
+                steps.push(NextStep::new(
+                    Action::PairAgain,
+                    format!("Pair again if {} no longer accepts the key", name),
+                    Some(match &situation.address {
+                        Some(address) => format!("connecto pair {}", address),
+                        None => "connecto pair <address>".to_string(),
+                    }),
+                ));
+            }
+            ConnectionIssue::Unreachable | ConnectionIssue::Unknown => {
+                steps.push(NextStep::new(
+                    Action::CheckHost,
+                    format!("Check that {} is online and its address is correct", name),
+                    Some("connecto hosts".to_string()),
+                ));
+                steps.push(NextStep::new(
+                    Action::UpdateAddress,
+                    "Update the address if it changed",
+                    Some(format!("connecto update-ip {} <new-ip>", name)),
+                ));
+            }
+        }
+        return steps;
+    }
+
+    if situation.key_in_agent == Some(false) {
+        steps.push(NextStep::new(
+            Action::LoadKey,
+            "Add the passphrase-protected key to the SSH agent",
+            situation
+                .key_path
+                .as_ref()
+                .map(|path| format!("ssh-add {}", path)),
+        ));
+    }
+
+    // A test that passed already connected, so there is nothing to suggest
+    if situation.event != Event::Tested {
+        match (&situation.host, &situation.ssh_command) {
+            (Some(host), _) => steps.push(NextStep::new(
+                Action::Connect,
+                format!("Connect to {}", host),
+                Some(format!("ssh {}", host)),
+            )),
+            (None, command) => steps.push(NextStep::new(
+                Action::Connect,
+                "Connect with the full command",
+                command.clone(),
+            )),
+        }
+        if situation.ssh_config_failed {
+            steps.push(NextStep::new(
+                Action::FixSshConfig,
+                "Make ~/.ssh/config writable and pair again to get a short alias",
+                None,
+            ));
+        }
+    }
+
+    if situation.event == Event::Synced && situation.sshd_running == Some(false) {
+        steps.push(NextStep::new(
+            Action::StartSshServer,
+            "Start the SSH server so the other device can connect back",
+            Some("connecto ssh on".to_string()),
+        ));
+    }
+
+    steps
+}
+
+/// Whether the SSH agent holds `key_pair`, when the key needs the agent
+///
+/// Keys without a passphrase work without an agent, so this is `None` for
+/// them. It is also `None` when no agent is running or `ssh-add` is missing.
+pub fn key_in_agent(key_pair: &SshKeyPair) -> Option<bool> {
+    if !key_pair.is_passphrase_protected() {
+        return None;
+    }
+
+    let output = Command::new("ssh-add").arg("-L").output().ok()?;
+    match output.status.code() {
+        Some(0) => {
+            let key_data = key_pair.public_key.split_whitespace().nth(1)?;
+            let listed = String::from_utf8_lossy(&output.stdout);
+            Some(
+                listed
+                    .lines()
+                    .any(|line| line.split_whitespace().nth(1) == Some(key_data)),
+            )
+        }
+        // The agent is running but holds no keys
+        Some(1) => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether this machine's SSH server accepts connections
+pub async fn ssh_server_running() -> bool {
+    let target = ProbeTarget::new("127.0.0.1", SSH_PORT);
+    connectivity::probe(&target, SSHD_PROBE_TIMEOUT)
+        .await
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(steps: &[NextStep]) -> Vec<Option<&str>> {
+        steps.iter().map(|s| s.command.as_deref()).collect()
+    }
+
+    #[test]
+    fn test_recommend_after_pairing() {
+        let paired = Situation {
+            host: Some("desk".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(commands(&recommend(&paired)), [Some("ssh desk")]);
+
+        // A passphrase-protected key has to be loaded before connecting
+        let locked = Situation {
+            key_path: Some("~/.ssh/id_ed25519".to_string()),
+            key_in_agent: Some(false),
+            ..paired.clone()
+        };
+        assert_eq!(
+            commands(&recommend(&locked)),
+            [Some("ssh-add ~/.ssh/id_ed25519"), Some("ssh desk")]
+        );
+
+        // Without a config entry, the full command is the way in
+        let no_config = Situation {
+            ssh_command: Some("ssh -i ~/.ssh/key me@10.0.0.5".to_string()),
+            ssh_config_failed: true,
+            ..Default::default()
+        };
+        let steps = recommend(&no_config);
+        assert_eq!(
+            steps[0].command.as_deref(),
+            no_config.ssh_command.as_deref()
+        );
+        assert_eq!(steps[1].action, Action::FixSshConfig);
+
+        let changed = Situation {
+            address: Some("10.0.0.5:8099".to_string()),
+            identity_changed: true,
+            ..paired
+        };
+        assert_eq!(
+            commands(&recommend(&changed)),
+            [Some("connecto pair 10.0.0.5:8099 --accept-new-identity")]
+        );
+    }
+
+    #[test]
+    fn test_recommend_after_sync() {
+        let synced = Situation {
+            event: Event::Synced,
+            host: Some("desk".to_string()),
+            sshd_running: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(commands(&recommend(&synced)), [Some("ssh desk")]);
+
+        let no_server = Situation {
+            sshd_running: Some(false),
+            ..synced
+        };
+        let steps = recommend(&no_server);
+        assert_eq!(steps[1].action, Action::StartSshServer);
+        assert_eq!(steps[1].command.as_deref(), Some("connecto ssh on"));
+    }
+
+    #[test]
+    fn test_recommend_after_test() {
+        let passed = Situation {
+            event: Event::Tested,
+            host: Some("desk".to_string()),
+            ..Default::default()
+        };
+        assert!(recommend(&passed).is_empty());
+
+        let unreachable = Situation {
+            issue: Some(ConnectionIssue::Unreachable),
+            ..passed.clone()
+        };
+        assert_eq!(
+            commands(&recommend(&unreachable)),
+            [
+                Some("connecto test desk --fix"),
+                Some("connecto hosts"),
+                Some("connecto update-ip desk <new-ip>")
+            ]
+        );
+
+        // Don't suggest the repair that just failed
+        let still_unreachable = Situation {
+            repair_attempted: true,
+            ..unreachable
+        };
+        assert_eq!(recommend(&still_unreachable)[0].action, Action::CheckHost);
+
+        let unknown = Situation {
+            issue: Some(ConnectionIssue::Unknown),
+            ..passed.clone()
+        };
+        assert_eq!(recommend(&unknown)[0].action, Action::CheckHost);
+
+        let rejected = Situation {
+            issue: Some(ConnectionIssue::KeyRejected),
+            key_path: Some("~/.ssh/connecto_desk".to_string()),
+            ..passed
+        };
+        assert_eq!(
+            commands(&recommend(&rejected)),
+            [
+                Some("connecto test desk --fix"),
+                Some("ssh-add ~/.ssh/connecto_desk"),
+                Some("connecto pair <address>")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_next_step_serialization() {
+        let step = NextStep::new(Action::StartSshServer, "Start", None);
+        let json = serde_json::to_string(&step).unwrap();
+        assert_eq!(
+            json,
+            r#"{"action":"start_ssh_server","title":"Start","command":null}"#
+        );
+    }
+
+    #[test]
+    fn test_key_in_agent_ignores_unprotected_keys() {
+        let key_pair =
+            SshKeyPair::generate(crate::keys::KeyAlgorithm::Ed25519, "test@host").unwrap();
+        assert_eq!(key_in_agent(&key_pair), None);
+    }
+}
@@ -0,0 +1,300 @@
+//! Desktop notifications
+//!
+//! A listener usually runs out of sight, in a terminal in the background or
+//! the GUI in the tray, so a device asking to pair easily goes unnoticed.
+//! Native notifications tell the user when a device asks to pair, pairs, or
+//! is turned away.
+//!
+//! They are shown with the tools each platform ships: `osascript` on macOS,
+//! `notify-send` (libnotify) on Linux, and a PowerShell toast on Windows.
+//! Where the tool is missing or no desktop session is running, nothing is
+//! shown and the listener carries on.
+
+use crate::protocol::ServerEvent;
+use std::process::{Command, Stdio};
+use tracing::debug;
+
+/// Title prefix and app name notifications are shown under
+pub const APP_NAME: &str = "Connecto";
+
+/// A notification to show
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+}
+
+impl Notification {
+    pub fn new(title: &str, body: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    /// The notification for a listener event, if the event deserves one
+    ///
+    /// Pairing requests, completed pairings, revoked keys and refused or
+    /// rejected clients are shown; progress in between is not.
+    pub fn for_server_event(event: &ServerEvent) -> Option<Self> {
+        match event {
+            ServerEvent::PairingRequest {
+                device_name,
+                address,
+            } => Some(Self::new(
+                "Pairing request",
+                &format!("{} ({}) wants to pair", device_name, address.ip()),
+            )),
+            ServerEvent::PairingComplete { device_name } => Some(Self::new(
+                &format!("Paired with {}", device_name),
+                &format!("{} can now SSH to this machine", device_name),
+            )),
+            ServerEvent::PairingRejected { device_name } => Some(Self::new(
+                "Pairing rejected",
+                &format!("The key of {} was not installed", device_name),
+            )),
+            ServerEvent::AccessDenied {
+                device_name,
+                address,
+                reason,
+            } => Some(Self::new(
+                "Pairing refused",
+                &format!("{} ({}): {}", device_name, address.ip(), reason),
+            )),
+            ServerEvent::KeyRevoked {
+                device_name,
+                address,
+                ..
+            } => Some(Self::new(
+                "Key revoked",
+                &format!(
+                    "{} ({}) can no longer SSH to this machine",
+                    device_name,
+                    address.ip()
+                ),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Shows notifications, unless turned off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Notifier {
+    enabled: bool,
+}
+
+impl Notifier {
+    /// A notifier that shows notifications
+    pub fn new() -> Self {
+        Self { enabled: true }
+    }
+
+    /// A notifier that shows nothing, for users who opted out
+    pub fn disabled() -> Self {
+        Self { enabled: false }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Show `notification` without waiting for it
+    ///
+    /// Failures are only logged: a missing notification is never worth
+    /// interrupting the listener for.
+    pub fn notify(&self, notification: &Notification) {
+        if !self.enabled {
+            return;
+        }
+        let Some(mut command) = command(notification) else {
+            return;
+        };
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        // Waited for on a thread of its own, so the tool is reaped
+        std::thread::spawn(move || match command.status() {
+            Ok(status) if !status.success() => debug!("Notification failed: {}", status),
+            Err(e) => debug!("Notifications unavailable: {}", e),
+            Ok(_) => {}
+        });
+    }
+
+    /// Show the notification for a listener event, if it has one
+    pub fn notify_server_event(&self, event: &ServerEvent) {
+        if let Some(notification) = Notification::for_server_event(event) {
+            self.notify(&notification);
+        }
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The command that shows `notification` on this platform
+#[cfg(target_os = "macos")]
+fn command(notification: &Notification) -> Option<Command> {
+    let mut command = Command::new("osascript");
+    command.arg("-e").arg(format!(
+        "display notification {} with title {}",
+        applescript_string(&notification.body),
+        applescript_string(&format!("{}: {}", APP_NAME, notification.title)),
+    ));
+    Some(command)
+}
+
+#[cfg(target_os = "linux")]
+fn command(notification: &Notification) -> Option<Command> {
+    let mut command = Command::new("notify-send");
+    command
+        .args(["--app-name", APP_NAME, "--icon", "dialog-password"])
+        .arg("--")
+        .arg(&notification.title)
+        .arg(&notification.body);
+    Some(command)
+}
+
+#[cfg(target_os = "windows")]
+fn command(notification: &Notification) -> Option<Command> {
+    Some(toast_command(notification))
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+fn command(_notification: &Notification) -> Option<Command> {
+    None
+}
+
+/// `s` as an AppleScript string literal
+#[cfg_attr(not(any(target_os = "macos", test)), allow(dead_code))]
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Environment variables the toast script reads the title and body from
+const TOAST_TITLE_VAR: &str = "CONNECTO_TOAST_TITLE";
+const TOAST_BODY_VAR: &str = "CONNECTO_TOAST_BODY";
+
+/// PowerShell showing a toast with the title and body from the environment
+///
+/// Unpackaged apps have no app ID of their own; PowerShell's is always
+/// registered, so toasts shown under it are not dropped.
+const TOAST_SCRIPT: &str = "\
+[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null; \
+$xml = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+$text = $xml.GetElementsByTagName('text'); \
+$text.Item(0).AppendChild($xml.CreateTextNode($env:CONNECTO_TOAST_TITLE)) > $null; \
+$text.Item(1).AppendChild($xml.CreateTextNode($env:CONNECTO_TOAST_BODY)) > $null; \
+[Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}\\WindowsPowerShell\\v1.0\\powershell.exe').Show([Windows.UI.Notifications.ToastNotification]::new($xml))";
+
+/// The PowerShell command showing `notification` as a toast
+///
+/// The title and body name devices that have not proven anything, so they
+/// are handed over as data in the environment and never become part of the
+/// script, whatever quotes they contain.
+#[cfg_attr(not(any(target_os = "windows", test)), allow(dead_code))]
+fn toast_command(notification: &Notification) -> Command {
+    let mut command = Command::new("powershell");
+    command
+        .args(["-NoProfile", "-NonInteractive", "-Command", TOAST_SCRIPT])
+        .env(
+            TOAST_TITLE_VAR,
+            format!("{}: {}", APP_NAME, notification.title),
+        )
+        .env(TOAST_BODY_VAR, &notification.body);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifications_for_server_events() {
+        let address = "192.168.1.42:50123".parse().unwrap();
+        let request = ServerEvent::PairingRequest {
+            device_name: "laptop".to_string(),
+            address,
+        };
+        assert_eq!(
+            Notification::for_server_event(&request),
+            Some(Notification::new(
+                "Pairing request",
+                "laptop (192.168.1.42) wants to pair"
+            ))
+        );
+
+        let denied = ServerEvent::AccessDenied {
+            device_name: "laptop".to_string(),
+            address,
+            reason: "192.168.1.42 is not in the allowed addresses".to_string(),
+        };
+        assert_eq!(
+            Notification::for_server_event(&denied).unwrap().title,
+            "Pairing refused"
+        );
+        let complete = ServerEvent::PairingComplete {
+            device_name: "laptop".to_string(),
+        };
+        assert_eq!(
+            Notification::for_server_event(&complete).unwrap().title,
+            "Paired with laptop"
+        );
+
+        // Progress in between is left to the listener's own output
+        let connected = ServerEvent::ClientConnected { address };
+        assert_eq!(Notification::for_server_event(&connected), None);
+    }
+
+    #[test]
+    fn test_string_literals() {
+        assert_eq!(
+            applescript_string(r#"say "hi" \o/"#),
+            r#""say \"hi\" \\o/""#
+        );
+    }
+
+    #[test]
+    fn test_toast_command_keeps_names_out_of_the_script() {
+        // PowerShell takes all of these for single quotes
+        for quote in ['\'', '\u{2018}', '\u{2019}', '\u{201A}', '\u{201B}'] {
+            let name = format!("x{}; calc; {}", quote, quote);
+            let notification = Notification::new(&format!("Paired with {}", name), &name);
+            let command = toast_command(&notification);
+
+            let args: Vec<_> = command.get_args().collect();
+            assert_eq!(
+                args,
+                ["-NoProfile", "-NonInteractive", "-Command", TOAST_SCRIPT]
+            );
+            let envs: Vec<_> = command.get_envs().collect();
+            assert!(envs.contains(&(
+                TOAST_TITLE_VAR.as_ref(),
+                Some(format!("Connecto: Paired with {}", name).as_ref())
+            )));
+            assert!(envs.contains(&(TOAST_BODY_VAR.as_ref(), Some(name.as_ref()))));
+        }
+        assert!(TOAST_SCRIPT.contains(&format!("$env:{}", TOAST_TITLE_VAR)));
+        assert!(TOAST_SCRIPT.contains(&format!("$env:{}", TOAST_BODY_VAR)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_linux_command() {
+        let command = command(&Notification::new("-t", "body")).unwrap();
+        assert_eq!(command.get_program(), "notify-send");
+        // A title starting with a dash is not taken for an option
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args[args.len() - 3..], ["--", "-t", "body"]);
+    }
+
+    #[test]
+    fn test_disabled_notifier() {
+        assert!(Notifier::default().is_enabled());
+        assert!(!Notifier::disabled().is_enabled());
+    }
+}
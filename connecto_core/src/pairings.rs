@@ -0,0 +1,370 @@
+//! Pairing database module
+//!
+//! Records every successful pairing in `pairings.json` in the Connecto config
+//! directory, so users can see when and with whom they paired without
+//! reading `~/.ssh/config`.
+
+use crate::error::Result;
+use crate::keys::SshKeyPair;
+use crate::paths;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File name of the pairing database inside the config directory
+const PAIRINGS_FILE: &str = "pairings.json";
+
+/// Which side initiated a pairing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PairingDirection {
+    /// This device sent its key to the peer (`connecto pair`)
+    Outgoing,
+    /// The peer sent its key to this device (`connecto listen`)
+    Incoming,
+    /// Both devices exchanged keys (`connecto sync`)
+    Sync,
+}
+
+impl std::fmt::Display for PairingDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Outgoing => write!(f, "outgoing"),
+            Self::Incoming => write!(f, "incoming"),
+            Self::Sync => write!(f, "sync"),
+        }
+    }
+}
+
+/// A single successful pairing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingRecord {
+    /// Device name the peer announced
+    pub peer_name: String,
+    /// Fingerprint of the SSH key that was exchanged
+    pub fingerprint: String,
+    /// Peer address at the time of pairing
+    pub address: String,
+    /// Unix timestamp (seconds) of the pairing
+    pub paired_at: u64,
+    pub direction: PairingDirection,
+    /// Private key (outgoing) or authorized_keys file (incoming) involved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<String>,
+    /// SSH config host alias written for the peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Port of the peer's SSH server, if it told us
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_port: Option<u16>,
+    /// Identity fingerprint of the peer device, if it announced one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_identity: Option<String>,
+    /// How far the peer's clock was ahead of ours in seconds, if measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_skew: Option<i64>,
+    /// Unix timestamp (seconds) after which the key is no longer accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl PairingRecord {
+    /// Create a record timestamped now, fingerprinting `public_key`
+    pub fn new(
+        peer_name: &str,
+        public_key: &str,
+        address: &str,
+        direction: PairingDirection,
+    ) -> Result<Self> {
+        let fingerprint = SshKeyPair::public_key_fingerprint(public_key)?;
+
+        Ok(Self {
+            peer_name: peer_name.to_string(),
+            fingerprint,
+            address: address.to_string(),
+            paired_at: now(),
+            direction,
+            key_path: None,
+            host: None,
+            ssh_port: None,
+            peer_identity: None,
+            clock_skew: None,
+            expires_at: None,
+        })
+    }
+
+    /// Set the key file involved in the pairing
+    pub fn with_key_path(mut self, key_path: &str) -> Self {
+        self.key_path = Some(key_path.to_string());
+        self
+    }
+
+    /// Set the SSH config host alias written for the peer
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    /// Set the port of the peer's SSH server
+    pub fn with_ssh_port(mut self, port: u16) -> Self {
+        self.ssh_port = Some(port);
+        self
+    }
+
+    /// Set the peer's identity fingerprint
+    pub fn with_peer_identity(mut self, identity: Option<&str>) -> Self {
+        self.peer_identity = identity.map(str::to_string);
+        self
+    }
+
+    /// Set the measured clock skew of the peer
+    pub fn with_clock_skew(mut self, skew: Option<i64>) -> Self {
+        self.clock_skew = skew;
+        self
+    }
+
+    /// Set when the key stops being accepted
+    pub fn with_expires_at(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    /// Whether the key's lifetime ended by `now` (Unix seconds)
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The pairing database
+#[derive(Debug, Clone)]
+pub struct PairingStore {
+    path: PathBuf,
+}
+
+impl PairingStore {
+    /// Default location of the pairing database
+    pub fn default_path() -> Result<PathBuf> {
+        paths::config_file(PAIRINGS_FILE)
+    }
+
+    /// Use the default database in the Connecto config directory
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            path: Self::default_path()?,
+        })
+    }
+
+    /// Use a specific database file
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Path of the database file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All recorded pairings, oldest first
+    pub fn all(&self) -> Result<Vec<PairingRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = fs::read_to_string(&self.path)?;
+        if content.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Append a pairing to the database
+    pub fn record(&self, record: PairingRecord) -> Result<()> {
+        let mut records = self.all()?;
+        records.push(record);
+        self.save(&records)
+    }
+
+    fn save(&self, records: &[PairingRecord]) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(records)?)?;
+        Ok(())
+    }
+
+    /// Record that the device whose identity is `identity` now goes by
+    /// `name`, returning the name it was last paired under if that differs
+    pub fn rename_peer(&self, identity: &str, name: &str) -> Result<Option<String>> {
+        let mut records = self.all()?;
+        let previous = records
+            .iter()
+            .filter(|r| r.peer_identity.as_deref() == Some(identity))
+            .max_by_key(|r| r.paired_at)
+            .map(|r| r.peer_name.clone());
+        let Some(previous) = previous.filter(|previous| previous != name) else {
+            return Ok(None);
+        };
+        for record in records
+            .iter_mut()
+            .filter(|r| r.peer_identity.as_deref() == Some(identity))
+        {
+            record.peer_name = name.to_string();
+        }
+        self.save(&records)?;
+        Ok(Some(previous))
+    }
+
+    /// Point pairings recorded for the host alias `host` at `new_host`,
+    /// returning how many changed
+    pub fn rename_host(&self, host: &str, new_host: &str) -> Result<usize> {
+        let mut records = self.all()?;
+        let mut renamed = 0;
+        for record in records
+            .iter_mut()
+            .filter(|r| r.host.as_deref() == Some(host))
+        {
+            record.host = Some(new_host.to_string());
+            renamed += 1;
+        }
+        if renamed > 0 {
+            self.save(&records)?;
+        }
+        Ok(renamed)
+    }
+
+    /// Pairings whose SSH config host alias is `host`, oldest first
+    pub fn for_host(&self, host: &str) -> Result<Vec<PairingRecord>> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|r| r.host.as_deref() == Some(host))
+            .collect())
+    }
+
+    /// Pairings with the device whose identity is `identity`, oldest first
+    pub fn for_identity(&self, identity: &str) -> Result<Vec<PairingRecord>> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|r| r.peer_identity.as_deref() == Some(identity))
+            .collect())
+    }
+
+    /// The most recent pairing for `host`, if any
+    pub fn latest_for_host(&self, host: &str) -> Result<Option<PairingRecord>> {
+        Ok(self.for_host(host)?.into_iter().max_by_key(|r| r.paired_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyAlgorithm;
+    use tempfile::TempDir;
+
+    fn record(host: &str, paired_at: u64) -> PairingRecord {
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+        let mut record = PairingRecord::new(
+            "Desk",
+            &key.public_key,
+            "192.168.1.10",
+            PairingDirection::Outgoing,
+        )
+        .unwrap()
+        .with_host(host)
+        .with_ssh_port(2222)
+        .with_key_path("/home/alice/.ssh/connecto_desk")
+        .with_peer_identity(Some("SHA256:desk"));
+        record.paired_at = paired_at;
+        record
+    }
+
+    #[test]
+    fn test_record_fingerprints_key() {
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@host").unwrap();
+        let record =
+            PairingRecord::new("Desk", &key.public_key, "10.0.0.1", PairingDirection::Sync)
+                .unwrap();
+        assert_eq!(record.fingerprint, key.fingerprint().unwrap());
+        assert!(record.paired_at > 0);
+        assert!(record.host.is_none());
+        assert!(!record.is_expired(u64::MAX));
+
+        let record = record.with_expires_at(Some(500));
+        assert!(!record.is_expired(499));
+        assert!(record.is_expired(500));
+
+        assert!(PairingRecord::new("Desk", "garbage", "", PairingDirection::Sync).is_err());
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = PairingStore::with_path(temp_dir.path().join("connecto").join(PAIRINGS_FILE));
+        assert!(store.all().unwrap().is_empty());
+
+        store.record(record("desk", 100)).unwrap();
+        store.record(record("laptop", 200)).unwrap();
+        store.record(record("desk", 300)).unwrap();
+
+        let all = store.all().unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].direction, PairingDirection::Outgoing);
+        assert_eq!(all[0].ssh_port, Some(2222));
+
+        assert_eq!(store.for_host("desk").unwrap().len(), 2);
+        assert_eq!(store.for_identity("SHA256:desk").unwrap().len(), 3);
+        assert_eq!(
+            store.latest_for_host("desk").unwrap().unwrap().paired_at,
+            300
+        );
+        assert!(store.latest_for_host("unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rename_peer_and_host() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = PairingStore::with_path(temp_dir.path().join(PAIRINGS_FILE));
+        assert_eq!(store.rename_peer("SHA256:desk", "Study").unwrap(), None);
+
+        store.record(record("desk", 100)).unwrap();
+        store.record(record("desk", 200)).unwrap();
+        let mut other = record("laptop", 300);
+        other.peer_name = "Laptop".to_string();
+        other.peer_identity = Some("SHA256:laptop".to_string());
+        store.record(other).unwrap();
+
+        assert_eq!(store.rename_peer("SHA256:desk", "Desk").unwrap(), None);
+        assert_eq!(
+            store.rename_peer("SHA256:desk", "Study").unwrap(),
+            Some("Desk".to_string())
+        );
+        let names: Vec<String> = store
+            .all()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.peer_name)
+            .collect();
+        assert_eq!(names, ["Study", "Study", "Laptop"]);
+        assert_eq!(store.rename_peer("SHA256:desk", "Study").unwrap(), None);
+
+        assert_eq!(store.rename_host("desk", "study").unwrap(), 2);
+        assert!(store.for_host("desk").unwrap().is_empty());
+        assert_eq!(store.for_host("study").unwrap().len(), 2);
+        assert_eq!(store.rename_host("desk", "study").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_direction_serialization() {
+        let json = serde_json::to_string(&PairingDirection::Incoming).unwrap();
+        assert_eq!(json, "\"incoming\"");
+        assert_eq!(PairingDirection::Sync.to_string(), "sync");
+    }
+}
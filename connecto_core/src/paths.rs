@@ -0,0 +1,155 @@
+//! Where Connecto keeps its files
+//!
+//! Every path to the config directory or the SSH directory is resolved here,
+//! so both can be moved for tests, containers and machines where the home
+//! directory is not where the files should go:
+//!
+//! | Directory | Resolved from, first one set wins |
+//! |-----------|-----------------------------------|
+//! | Config | [`CONFIG_DIR_ENV`], `$XDG_CONFIG_HOME/connecto`, the platform's config directory |
+//! | SSH | [`SSH_DIR_ENV`], `~/.ssh` |
+
+use crate::error::{ConnectoError, Result};
+use directories::{ProjectDirs, UserDirs};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Environment variable that moves the config directory
+pub const CONFIG_DIR_ENV: &str = "CONNECTO_CONFIG_DIR";
+
+/// Environment variable that moves the SSH directory
+pub const SSH_DIR_ENV: &str = "CONNECTO_SSH_DIR";
+
+/// The XDG base directory variable for config files
+pub const XDG_CONFIG_HOME_ENV: &str = "XDG_CONFIG_HOME";
+
+/// Name of Connecto's directory under `XDG_CONFIG_HOME`
+const APP_DIR: &str = "connecto";
+
+/// The directory holding Connecto's config, pairing history and identity
+pub fn config_dir() -> Result<PathBuf> {
+    config_dir_in(&process_env)
+}
+
+/// The file `name` in the config directory
+pub fn config_file(name: impl AsRef<Path>) -> Result<PathBuf> {
+    Ok(config_dir()?.join(name))
+}
+
+/// The user's home directory
+pub fn home_dir() -> Result<PathBuf> {
+    UserDirs::new()
+        .map(|dirs| dirs.home_dir().to_path_buf())
+        .ok_or_else(|| not_found("Could not determine home directory"))
+}
+
+/// The directory holding the user's SSH keys, config and authorized_keys
+pub fn ssh_dir() -> Result<PathBuf> {
+    match env_dir(&process_env, SSH_DIR_ENV) {
+        Some(dir) => Ok(dir),
+        None => Ok(home_dir()?.join(".ssh")),
+    }
+}
+
+/// The user's SSH client config, `~/.ssh/config`
+pub fn ssh_config_file() -> Result<PathBuf> {
+    Ok(ssh_dir()?.join("config"))
+}
+
+/// Expand a leading `~/` to the home directory
+///
+/// Paths under `~/.ssh/` go to [`ssh_dir`], so they follow
+/// [`SSH_DIR_ENV`] like the keys they point at.
+pub fn expand_home(path: &str) -> Result<PathBuf> {
+    if let Some(rest) = path.strip_prefix("~/.ssh/") {
+        return Ok(ssh_dir()?.join(rest));
+    }
+    match path.strip_prefix("~/") {
+        Some(rest) => Ok(home_dir()?.join(rest)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+fn process_env(name: &str) -> Option<OsString> {
+    std::env::var_os(name)
+}
+
+fn config_dir_in(env: &impl Fn(&str) -> Option<OsString>) -> Result<PathBuf> {
+    if let Some(dir) = env_dir(env, CONFIG_DIR_ENV) {
+        return Ok(dir);
+    }
+    if let Some(dir) = env_dir(env, XDG_CONFIG_HOME_ENV) {
+        return Ok(dir.join(APP_DIR));
+    }
+    ProjectDirs::from("com", "connecto", "connecto")
+        .map(|dirs| dirs.config_dir().to_path_buf())
+        .ok_or_else(|| not_found("Could not determine config directory"))
+}
+
+/// The directory in the environment variable `name`, if it is set to an
+/// absolute path
+///
+/// Relative paths are ignored, as the XDG spec asks, so a stray value cannot
+/// put files wherever the command happens to run.
+fn env_dir(env: &impl Fn(&str) -> Option<OsString>, name: &str) -> Option<PathBuf> {
+    env(name).map(PathBuf::from).filter(|dir| dir.is_absolute())
+}
+
+fn not_found(message: &str) -> ConnectoError {
+    ConnectoError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env<'a>(vars: &'a [(&'a str, &'a Path)]) -> impl Fn(&str) -> Option<OsString> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.as_os_str().to_os_string())
+        }
+    }
+
+    #[test]
+    fn test_config_dir_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let own = dir.path().join("own");
+        let xdg = dir.path().join("xdg");
+
+        let both = [(CONFIG_DIR_ENV, own.as_path()), (XDG_CONFIG_HOME_ENV, &xdg)];
+        assert_eq!(config_dir_in(&env(&both)).unwrap(), own);
+
+        let xdg_only = [(XDG_CONFIG_HOME_ENV, xdg.as_path())];
+        assert_eq!(
+            config_dir_in(&env(&xdg_only)).unwrap(),
+            xdg.join("connecto")
+        );
+
+        // Relative paths are ignored
+        let relative = [
+            (CONFIG_DIR_ENV, Path::new("relative/dir")),
+            (XDG_CONFIG_HOME_ENV, &xdg),
+        ];
+        assert_eq!(
+            config_dir_in(&env(&relative)).unwrap(),
+            xdg.join("connecto")
+        );
+
+        assert!(config_dir_in(&env(&[])).unwrap().is_absolute());
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let ssh = ssh_dir().unwrap();
+        assert_eq!(
+            expand_home("~/.ssh/connecto_desk").unwrap(),
+            ssh.join("connecto_desk")
+        );
+        assert_eq!(
+            expand_home("~/keys/id").unwrap(),
+            home_dir().unwrap().join("keys/id")
+        );
+        assert_eq!(expand_home("/etc/key").unwrap(), PathBuf::from("/etc/key"));
+    }
+}
@@ -0,0 +1,938 @@
+//! Port mapping module
+//!
+//! Guest networks and double NAT put devices on segments that cannot reach
+//! each other's LAN addresses, though both can reach the router. Asking the
+//! router to forward a port on its external address gives the listener an
+//! address those devices can use. NAT-PMP (RFC 6886) is tried first, being a
+//! single UDP exchange with the default gateway; UPnP IGD, found with SSDP
+//! and driven with SOAP, second.
+//!
+//! Mappings are leased: [`PortMapping::keep_renewed`] renews them in the
+//! background, and one that is never removed lapses after its lifetime.
+
+use crate::error::{ConnectoError, Result};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// How long a mapping is leased for before it must be renewed
+pub const MAPPING_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Port NAT-PMP gateways answer on
+const NATPMP_PORT: u16 = 5351;
+
+/// Wait for the first NAT-PMP answer, doubled for each of
+/// [`NATPMP_ATTEMPTS`]
+const NATPMP_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// NAT-PMP requests sent before giving up
+const NATPMP_ATTEMPTS: u32 = 3;
+
+/// Where SSDP searches are sent
+const SSDP_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+/// How long to wait for gateways to answer an SSDP search
+const SSDP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long each HTTP request to a UPnP gateway may take
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest HTTP response read from a gateway
+const MAX_HTTP_RESPONSE: u64 = 1024 * 1024;
+
+/// External ports tried in turn when a UPnP gateway has the first one taken
+const UPNP_PORT_ATTEMPTS: u16 = 5;
+
+/// UPnP error for an external port mapped to another device already
+const UPNP_CONFLICT: &str = "718";
+
+/// Description of mappings Connecto makes on UPnP gateways
+const MAPPING_DESCRIPTION: &str = "Connecto";
+
+/// How a mapping was made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingProtocol {
+    NatPmp,
+    Upnp,
+}
+
+impl fmt::Display for MappingProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NatPmp => "NAT-PMP",
+            Self::Upnp => "UPnP",
+        })
+    }
+}
+
+/// The gateway a mapping lives on, and how to reach it again
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Gateway {
+    NatPmp(SocketAddr),
+    Upnp {
+        control_url: String,
+        service: String,
+        internal_client: IpAddr,
+    },
+}
+
+/// A TCP port forwarded from the router's external address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub protocol: MappingProtocol,
+    /// Where devices outside the local segment connect
+    pub external: SocketAddr,
+    /// The local port connections are forwarded to
+    pub internal_port: u16,
+    /// How long the gateway keeps the mapping without a renewal
+    pub lifetime: Duration,
+    gateway: Gateway,
+}
+
+impl PortMapping {
+    /// Extend the lease by another lifetime
+    ///
+    /// The gateway may move the mapping to another external port or
+    /// address; [`PortMapping::external`] follows it.
+    pub async fn renew(&mut self) -> Result<()> {
+        let renewed = match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                natpmp_map(
+                    *gateway,
+                    self.internal_port,
+                    self.external.port(),
+                    self.lifetime,
+                )
+                .await?
+            }
+            Gateway::Upnp {
+                control_url,
+                service,
+                internal_client,
+            } => {
+                upnp_add(
+                    control_url,
+                    service,
+                    *internal_client,
+                    self.internal_port,
+                    self.external.port(),
+                    self.lifetime,
+                )
+                .await?;
+                let address = upnp_external_address(control_url, service).await?;
+                PortMapping {
+                    external: SocketAddr::new(address, self.external.port()),
+                    ..self.clone()
+                }
+            }
+        };
+        if renewed.external != self.external {
+            warn!(
+                "Port mapping moved from {} to {}",
+                self.external, renewed.external
+            );
+        }
+        *self = renewed;
+        Ok(())
+    }
+
+    /// Take the mapping off the gateway
+    pub async fn remove(self) -> Result<()> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                let request = natpmp_map_request(self.internal_port, 0, 0);
+                natpmp_exchange(*gateway, &request).await.map(drop)
+            }
+            Gateway::Upnp {
+                control_url,
+                service,
+                ..
+            } => soap_call(
+                control_url,
+                service,
+                "DeletePortMapping",
+                &[
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", self.external.port().to_string()),
+                    ("NewProtocol", "TCP".to_string()),
+                ],
+            )
+            .await
+            .map(drop),
+        }
+    }
+
+    /// Renew the mapping in the background, at half its lifetime, until it
+    /// is removed
+    pub fn keep_renewed(self) -> RenewedMapping {
+        let external = self.external;
+        let protocol = self.protocol;
+        let interval = self.lifetime / 2;
+        let mapping = Arc::new(tokio::sync::Mutex::new(self));
+        let renewing = mapping.clone();
+        let renewer = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = renewing.lock().await.renew().await {
+                    warn!("Could not renew the port mapping: {}", e);
+                }
+            }
+        });
+        RenewedMapping {
+            mapping,
+            renewer,
+            external,
+            protocol,
+        }
+    }
+}
+
+/// A [`PortMapping`] kept alive in the background
+pub struct RenewedMapping {
+    mapping: Arc<tokio::sync::Mutex<PortMapping>>,
+    renewer: JoinHandle<()>,
+    external: SocketAddr,
+    protocol: MappingProtocol,
+}
+
+impl RenewedMapping {
+    /// Where devices outside the local segment connect, as first mapped
+    pub fn external(&self) -> SocketAddr {
+        self.external
+    }
+
+    pub fn protocol(&self) -> MappingProtocol {
+        self.protocol
+    }
+
+    /// Stop renewing the mapping and take it off the gateway
+    pub async fn remove(self) -> Result<()> {
+        self.renewer.abort();
+        let mapping = self.mapping.lock().await.clone();
+        mapping.remove().await
+    }
+}
+
+/// Forward TCP `port` from the router's external address, leased for
+/// `lifetime`
+///
+/// Tries NAT-PMP on the default gateway, then UPnP. Fails with the reasons
+/// of both when neither gateway maps the port.
+pub async fn map_port(port: u16, lifetime: Duration) -> Result<PortMapping> {
+    let natpmp = match default_gateway() {
+        Some(gateway) => {
+            let gateway = SocketAddr::new(IpAddr::V4(gateway), NATPMP_PORT);
+            natpmp_map(gateway, port, port, lifetime).await
+        }
+        None => Err(ConnectoError::PortMapping(
+            "no default gateway found".to_string(),
+        )),
+    };
+    let natpmp_error = match natpmp {
+        Ok(mapping) => return Ok(mapping),
+        Err(e) => e,
+    };
+    debug!("NAT-PMP mapping failed: {}", natpmp_error);
+    let upnp_error = match ssdp_search(SSDP_TIMEOUT).await {
+        Some(location) => match upnp_map(&location, port, lifetime).await {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => e,
+        },
+        None => ConnectoError::PortMapping("no UPnP gateway answered".to_string()),
+    };
+    Err(ConnectoError::PortMapping(format!(
+        "the router mapped no port (NAT-PMP: {}; UPnP: {})",
+        reason(&natpmp_error),
+        reason(&upnp_error)
+    )))
+}
+
+/// The message of a port mapping error, without its prefix
+fn reason(error: &ConnectoError) -> String {
+    match error {
+        ConnectoError::PortMapping(reason) => reason.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// A request to map TCP `internal_port` to `external_port` for `lifetime`;
+/// a lifetime of zero removes the mapping
+fn natpmp_map_request(internal_port: u16, external_port: u16, lifetime: u32) -> [u8; 12] {
+    const MAP_TCP: u8 = 2;
+    let mut request = [0; 12];
+    request[1] = MAP_TCP;
+    request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+/// The request for the gateway's external address
+const NATPMP_ADDRESS_REQUEST: [u8; 2] = [0, 0];
+
+/// Check a NAT-PMP response's header, returning its body
+fn natpmp_response(response: &[u8], opcode: u8) -> Result<&[u8]> {
+    if response.len() < 8 || response[0] != 0 || response[1] != 128 + opcode {
+        return Err(ConnectoError::PortMapping(
+            "malformed NAT-PMP response".to_string(),
+        ));
+    }
+    let message = match u16::from_be_bytes([response[2], response[3]]) {
+        0 => return Ok(&response[8..]),
+        1 => "unsupported version",
+        2 => "not authorized, port mapping may be turned off on the router",
+        3 => "the router has no external address",
+        4 => "the router is out of mappings",
+        5 => "unsupported request",
+        code => {
+            return Err(ConnectoError::PortMapping(format!(
+                "NAT-PMP error {}",
+                code
+            )))
+        }
+    };
+    Err(ConnectoError::PortMapping(message.to_string()))
+}
+
+/// The external address in a response to [`NATPMP_ADDRESS_REQUEST`]
+fn parse_natpmp_address(response: &[u8]) -> Result<Ipv4Addr> {
+    let body = natpmp_response(response, 0)?;
+    let octets: [u8; 4] = body
+        .get(..4)
+        .and_then(|octets| octets.try_into().ok())
+        .ok_or_else(|| ConnectoError::PortMapping("short NAT-PMP response".to_string()))?;
+    Ok(Ipv4Addr::from(octets))
+}
+
+/// The external port and lifetime in a response to a mapping request
+fn parse_natpmp_mapping(response: &[u8]) -> Result<(u16, u32)> {
+    let body = natpmp_response(response, 2)?;
+    match body {
+        [_, _, e0, e1, l0, l1, l2, l3, ..] => Ok((
+            u16::from_be_bytes([*e0, *e1]),
+            u32::from_be_bytes([*l0, *l1, *l2, *l3]),
+        )),
+        _ => Err(ConnectoError::PortMapping(
+            "short NAT-PMP response".to_string(),
+        )),
+    }
+}
+
+/// Send `request` to `gateway` until it answers, waiting twice as long
+/// after each try
+async fn natpmp_exchange(gateway: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+    let mut wait = NATPMP_TIMEOUT;
+    let mut buffer = [0; 16];
+    let exchange = async {
+        for _ in 0..NATPMP_ATTEMPTS {
+            socket.send(request).await?;
+            match tokio::time::timeout(wait, socket.recv(&mut buffer)).await {
+                Ok(Ok(len)) => return Ok(Some(buffer[..len].to_vec())),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => wait *= 2,
+            }
+        }
+        Ok(None)
+    };
+    match exchange.await {
+        Ok(Some(response)) => Ok(response),
+        // Refused means nothing listens on the gateway's port, reported on
+        // whichever call comes after the ICMP message
+        Ok(None) => Err(ConnectoError::PortMapping(format!(
+            "gateway {} did not answer",
+            gateway.ip()
+        ))),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => Err(
+            ConnectoError::PortMapping(format!("gateway {} does not offer it", gateway.ip())),
+        ),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Map `internal_port` through the NAT-PMP gateway at `gateway`
+async fn natpmp_map(
+    gateway: SocketAddr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> Result<PortMapping> {
+    let address = parse_natpmp_address(&natpmp_exchange(gateway, &NATPMP_ADDRESS_REQUEST).await?)?;
+    let lifetime_secs = lifetime.as_secs().min(u32::MAX as u64) as u32;
+    let request = natpmp_map_request(internal_port, external_port, lifetime_secs);
+    let (port, granted) = parse_natpmp_mapping(&natpmp_exchange(gateway, &request).await?)?;
+    Ok(PortMapping {
+        protocol: MappingProtocol::NatPmp,
+        external: SocketAddr::new(IpAddr::V4(address), port),
+        internal_port,
+        lifetime: Duration::from_secs(granted.into()),
+        gateway: Gateway::NatPmp(gateway),
+    })
+}
+
+/// The default IPv4 gateway, where NAT-PMP requests go
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    platform_gateway()
+}
+
+#[cfg(target_os = "linux")]
+fn platform_gateway() -> Option<Ipv4Addr> {
+    parse_proc_net_route(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+#[cfg(target_os = "macos")]
+fn platform_gateway() -> Option<Ipv4Addr> {
+    let output = std::process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .ok()?;
+    parse_route_get(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(windows)]
+fn platform_gateway() -> Option<Ipv4Addr> {
+    let output = std::process::Command::new("route")
+        .args(["print", "-4", "0.0.0.0"])
+        .output()
+        .ok()?;
+    parse_route_print(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn platform_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// The gateway of the default route in a `/proc/net/route` table
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn parse_proc_net_route(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "00000000", gateway, _, _, _, _, "00000000", ..] => {
+                // Written in host byte order, which is little-endian on Linux
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                Some(Ipv4Addr::from(gateway.to_le_bytes())).filter(|ip| !ip.is_unspecified())
+            }
+            _ => None,
+        }
+    })
+}
+
+/// The gateway in `route -n get default` output
+#[cfg_attr(not(any(target_os = "macos", test)), allow(dead_code))]
+fn parse_route_get(output: &str) -> Option<Ipv4Addr> {
+    output.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "gateway")
+            .then(|| value.trim().parse().ok())
+            .flatten()
+    })
+}
+
+/// The gateway of the default route in `route print -4 0.0.0.0` output
+#[cfg_attr(not(any(windows, test)), allow(dead_code))]
+fn parse_route_print(output: &str) -> Option<Ipv4Addr> {
+    output.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["0.0.0.0", "0.0.0.0", gateway, ..] => gateway.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Look for an Internet Gateway Device, returning the URL of its description
+async fn ssdp_search(timeout: Duration) -> Option<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.ok()?;
+    for version in [1, 2] {
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:{}\r\n\r\n",
+            SSDP_ADDRESS, version
+        );
+        if let Err(e) = socket.send_to(search.as_bytes(), SSDP_ADDRESS).await {
+            debug!("SSDP search failed: {}", e);
+            return None;
+        }
+    }
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buffer = [0; 2048];
+    loop {
+        let (len, _) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer))
+            .await
+            .ok()?
+            .ok()?;
+        if let Some(location) = parse_ssdp_location(&String::from_utf8_lossy(&buffer[..len])) {
+            return Some(location);
+        }
+    }
+}
+
+/// The `LOCATION` header of an SSDP response
+fn parse_ssdp_location(response: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// The text of the first `tag` element in `xml`, ignoring namespace prefixes
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let name = rest[..end].split_whitespace().next().unwrap_or_default();
+        let local = name.rsplit(':').next().unwrap_or(name);
+        if local == tag && !name.starts_with('/') {
+            let content = &rest[end + 1..];
+            return Some(content[..content.find("</")?].trim());
+        }
+    }
+    None
+}
+
+/// The control URL and service type of the WAN connection service in a
+/// gateway's description, fetched from `location`
+fn parse_control_url(description: &str, location: &str) -> Option<(String, String)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = xml_text(service, "serviceType")?;
+        if !service_type.contains("WANIPConnection") && !service_type.contains("WANPPPConnection") {
+            return None;
+        }
+        let control = xml_text(service, "controlURL")?;
+        let url = if control.starts_with("http://") {
+            control.to_string()
+        } else {
+            let base = xml_text(description, "URLBase")
+                .filter(|base| !base.is_empty())
+                .unwrap_or(location);
+            let (host, _) = split_url(base)?;
+            format!("http://{}/{}", host, control.trim_start_matches('/'))
+        };
+        Some((url, service_type.to_string()))
+    })
+}
+
+/// The `host:port` and path of an `http://` URL
+fn split_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let path = if path.is_empty() { "/" } else { path };
+    Some((host, path.to_string()))
+}
+
+/// The status and body of a raw HTTP response, undoing chunked encoding
+fn parse_http_response(raw: &[u8]) -> Result<(u16, String)> {
+    let malformed = || ConnectoError::PortMapping("malformed HTTP response".to_string());
+    let raw = String::from_utf8_lossy(raw);
+    let (head, body) = raw.split_once("\r\n\r\n").ok_or_else(malformed)?;
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(malformed)?;
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(key, value)| {
+            key.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    if !chunked {
+        return Ok((status, body.to_string()));
+    }
+    let mut decoded = String::new();
+    let mut rest = body;
+    loop {
+        let (size, after) = rest.split_once("\r\n").ok_or_else(malformed)?;
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)
+            .map_err(|_| malformed())?;
+        if size == 0 {
+            return Ok((status, decoded));
+        }
+        decoded.push_str(after.get(..size).ok_or_else(malformed)?);
+        rest = after[size..].trim_start_matches("\r\n");
+    }
+}
+
+/// Send an HTTP request to `url`, returning the status, the body, and the
+/// local address the gateway was reached from
+async fn http_request(
+    url: &str,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(u16, String, IpAddr)> {
+    let (host, path) = split_url(url)
+        .ok_or_else(|| ConnectoError::PortMapping(format!("unsupported URL {}", url)))?;
+    let exchange = async {
+        let mut stream = TcpStream::connect(&host).await?;
+        let local = stream.local_addr()?.ip();
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            host,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream
+            .take(MAX_HTTP_RESPONSE)
+            .read_to_end(&mut response)
+            .await?;
+        Ok::<_, std::io::Error>((response, local))
+    };
+    let (response, local) = tokio::time::timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| ConnectoError::PortMapping(format!("{} did not answer", host)))?
+        .map_err(|e| ConnectoError::PortMapping(format!("{}: {}", host, e)))?;
+    let (status, body) = parse_http_response(&response)?;
+    Ok((status, body, local))
+}
+
+/// Call `action` on a gateway's WAN connection service, returning the
+/// response body and the local address the gateway was reached from
+async fn soap_call(
+    control_url: &str,
+    service: &str,
+    action: &str,
+    arguments: &[(&str, String)],
+) -> Result<(String, IpAddr)> {
+    let arguments: String = arguments
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let envelope = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        action, service, arguments
+    );
+    let soap_action = format!("\"{}#{}\"", service, action);
+    let (status, body, local) = http_request(
+        control_url,
+        "POST",
+        &[
+            ("Content-Type", "text/xml; charset=\"utf-8\""),
+            ("SOAPAction", &soap_action),
+        ],
+        &envelope,
+    )
+    .await?;
+    if status == 200 {
+        return Ok((body, local));
+    }
+    let code = xml_text(&body, "errorCode").unwrap_or_default();
+    let description = xml_text(&body, "errorDescription").unwrap_or("no description");
+    Err(ConnectoError::PortMapping(format!(
+        "{} failed with UPnP error {} ({})",
+        action, code, description
+    )))
+}
+
+/// Forward `external_port` to `internal_client:internal_port`
+async fn upnp_add(
+    control_url: &str,
+    service: &str,
+    internal_client: IpAddr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> Result<()> {
+    soap_call(
+        control_url,
+        service,
+        "AddPortMapping",
+        &[
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", "TCP".to_string()),
+            ("NewInternalPort", internal_port.to_string()),
+            ("NewInternalClient", internal_client.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", MAPPING_DESCRIPTION.to_string()),
+            ("NewLeaseDuration", lifetime.as_secs().to_string()),
+        ],
+    )
+    .await
+    .map(drop)
+}
+
+/// The external address of a UPnP gateway
+async fn upnp_external_address(control_url: &str, service: &str) -> Result<IpAddr> {
+    let (body, _) = soap_call(control_url, service, "GetExternalIPAddress", &[]).await?;
+    xml_text(&body, "NewExternalIPAddress")
+        .and_then(|address| address.parse().ok())
+        .ok_or_else(|| ConnectoError::PortMapping("the router has no external address".to_string()))
+}
+
+/// Map `port` through the UPnP gateway described at `location`
+async fn upnp_map(location: &str, port: u16, lifetime: Duration) -> Result<PortMapping> {
+    let (status, description, _) = http_request(location, "GET", &[], "").await?;
+    if status != 200 {
+        return Err(ConnectoError::PortMapping(format!(
+            "{} answered HTTP {}",
+            location, status
+        )));
+    }
+    let (control_url, service) = parse_control_url(&description, location).ok_or_else(|| {
+        ConnectoError::PortMapping("the gateway offers no WAN connection service".to_string())
+    })?;
+    // The address the gateway sees us at is the one to forward to
+    let (body, internal_client) =
+        soap_call(&control_url, &service, "GetExternalIPAddress", &[]).await?;
+    let address: IpAddr = xml_text(&body, "NewExternalIPAddress")
+        .and_then(|address| address.parse().ok())
+        .ok_or_else(|| {
+            ConnectoError::PortMapping("the router has no external address".to_string())
+        })?;
+
+    let mut last_error = None;
+    for external_port in (port..).take(UPNP_PORT_ATTEMPTS.into()) {
+        match upnp_add(
+            &control_url,
+            &service,
+            internal_client,
+            port,
+            external_port,
+            lifetime,
+        )
+        .await
+        {
+            Ok(()) => {
+                return Ok(PortMapping {
+                    protocol: MappingProtocol::Upnp,
+                    external: SocketAddr::new(address, external_port),
+                    internal_port: port,
+                    lifetime,
+                    gateway: Gateway::Upnp {
+                        control_url,
+                        service,
+                        internal_client,
+                    },
+                })
+            }
+            Err(ConnectoError::PortMapping(reason)) if reason.contains(UPNP_CONFLICT) => {
+                debug!("External port {} is taken, trying the next", external_port);
+                last_error = Some(ConnectoError::PortMapping(reason));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        ConnectoError::PortMapping("the router has no free external port".to_string())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_natpmp_messages() {
+        assert_eq!(
+            natpmp_map_request(8099, 8099, 3600),
+            [0, 2, 0, 0, 0x1f, 0xa3, 0x1f, 0xa3, 0, 0, 0x0e, 0x10]
+        );
+        let address = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 5];
+        assert_eq!(
+            parse_natpmp_address(&address).unwrap(),
+            Ipv4Addr::new(203, 0, 113, 5)
+        );
+        let mapping = [
+            0, 130, 0, 0, 0, 0, 0, 9, 0x1f, 0xa3, 0x1f, 0xa4, 0, 0, 0x0e, 0x10,
+        ];
+        assert_eq!(parse_natpmp_mapping(&mapping).unwrap(), (8100, 3600));
+
+        let refused = [0, 130, 0, 2, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 0];
+        let error = parse_natpmp_mapping(&refused).unwrap_err();
+        assert!(error.to_string().contains("not authorized"), "{}", error);
+        assert!(parse_natpmp_mapping(&address).is_err());
+    }
+
+    #[test]
+    fn test_parse_default_gateway() {
+        let table = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+";
+        assert_eq!(
+            parse_proc_net_route(table),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(parse_proc_net_route(table.lines().next().unwrap()), None);
+
+        let route_get = "   route to: default\ndestination: default\n       mask: default\n    gateway: 10.0.0.1\n  interface: en0\n";
+        assert_eq!(parse_route_get(route_get), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+        let route_print = "IPv4 Route Table\n===========================================================================\nActive Routes:\nNetwork Destination        Netmask          Gateway       Interface  Metric\n          0.0.0.0          0.0.0.0      192.168.0.1    192.168.0.20     25\n";
+        assert_eq!(
+            parse_route_print(route_print),
+            Some(Ipv4Addr::new(192, 168, 0, 1))
+        );
+    }
+
+    #[test]
+    fn test_parse_upnp_description() {
+        let ssdp = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        assert_eq!(
+            parse_ssdp_location(ssdp).as_deref(),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+
+        let description = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device><serviceList>
+<service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType><controlURL>/ctl/L3F</controlURL></service>
+<service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl/IPConn</controlURL></service>
+</serviceList></device></root>"#;
+        assert_eq!(
+            parse_control_url(description, "http://192.168.1.1:5000/rootDesc.xml"),
+            Some((
+                "http://192.168.1.1:5000/ctl/IPConn".to_string(),
+                "urn:schemas-upnp-org:service:WANIPConnection:1".to_string()
+            ))
+        );
+        let based = description.replace(
+            "<device>",
+            "<URLBase>http://10.0.0.1:49000</URLBase><device>",
+        );
+        assert_eq!(
+            parse_control_url(&based, "http://192.168.1.1:5000/rootDesc.xml")
+                .unwrap()
+                .0,
+            "http://10.0.0.1:49000/ctl/IPConn"
+        );
+
+        let fault = "<s:Envelope><s:Body><s:Fault><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>718</errorCode><errorDescription>ConflictInMappingEntry</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>";
+        assert_eq!(xml_text(fault, "errorCode"), Some("718"));
+        assert_eq!(
+            xml_text(
+                "<u:R><NewExternalIPAddress>203.0.113.5</NewExternalIPAddress></u:R>",
+                "NewExternalIPAddress"
+            ),
+            Some("203.0.113.5")
+        );
+
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        assert_eq!(
+            parse_http_response(chunked).unwrap(),
+            (200, "hello world".to_string())
+        );
+        assert_eq!(
+            parse_http_response(b"HTTP/1.0 500 Internal Server Error\r\n\r\nfault").unwrap(),
+            (500, "fault".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_natpmp_mapping_lifecycle() {
+        // A gateway that maps every port one up and remembers the requests
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = gateway.local_addr().unwrap();
+        let requests = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            let mut buffer = [0; 16];
+            while seen.len() < 3 {
+                let (len, from) = gateway.recv_from(&mut buffer).await.unwrap();
+                let request = buffer[..len].to_vec();
+                let response = match request[1] {
+                    0 => vec![0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 5],
+                    _ => {
+                        let external = u16::from_be_bytes([request[4], request[5]]) + 1;
+                        let mut response = vec![0, 130, 0, 0, 0, 0, 0, 1];
+                        response.extend_from_slice(&request[4..6]);
+                        response.extend_from_slice(&external.to_be_bytes());
+                        response.extend_from_slice(&request[8..12]);
+                        response
+                    }
+                };
+                gateway.send_to(&response, from).await.unwrap();
+                seen.push(request);
+            }
+            seen
+        });
+
+        let mapping = natpmp_map(address, 8099, 8099, Duration::from_secs(120))
+            .await
+            .unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::NatPmp);
+        assert_eq!(mapping.external, "203.0.113.5:8100".parse().unwrap());
+        assert_eq!(mapping.lifetime, Duration::from_secs(120));
+
+        mapping.remove().await.unwrap();
+        let seen = requests.await.unwrap();
+        // The removal asks for a lifetime of zero
+        assert_eq!(seen[2], natpmp_map_request(8099, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_upnp_mapping() {
+        // A gateway whose first external port is taken
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        let gateway = tokio::spawn(async move {
+            let mut actions = Vec::new();
+            while actions.len() < 4 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 8192];
+                let len = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..len]).to_string();
+                let (status, body) = if request.starts_with("GET") {
+                    actions.push("describe".to_string());
+                    ("200 OK", "<root><service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl</controlURL></service></root>".to_string())
+                } else if request.contains("#GetExternalIPAddress") {
+                    actions.push("address".to_string());
+                    (
+                        "200 OK",
+                        "<NewExternalIPAddress>203.0.113.5</NewExternalIPAddress>".to_string(),
+                    )
+                } else if request.contains("<NewExternalPort>8099</NewExternalPort>") {
+                    actions.push("conflict".to_string());
+                    ("500 Internal Server Error", "<errorCode>718</errorCode><errorDescription>ConflictInMappingEntry</errorDescription>".to_string())
+                } else {
+                    assert!(request.contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+                    actions.push("added".to_string());
+                    ("200 OK", String::new())
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            actions
+        });
+
+        let mapping = upnp_map(&location, 8099, MAPPING_LIFETIME).await.unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::Upnp);
+        assert_eq!(mapping.external, "203.0.113.5:8100".parse().unwrap());
+        assert_eq!(mapping.internal_port, 8099);
+        assert_eq!(
+            gateway.await.unwrap(),
+            ["describe", "address", "conflict", "added"]
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! Ports module
+//!
+//! Turns a failure to bind a listening port into an error that says who holds
+//! the port, when the platform lets us find out, so the user can stop a stale
+//! listener or pick another port.
+
+use crate::error::{ConnectoError, Result};
+use std::fmt;
+use std::io;
+#[cfg(any(target_os = "macos", windows))]
+use std::process::Command;
+
+/// How many ports after a taken one [`suggest_free_port`] tries
+const FREE_PORT_SEARCH: u16 = 20;
+
+/// A process listening on a port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortOwner {
+    pub pid: u32,
+    /// Process name, if it could be read
+    pub name: Option<String>,
+}
+
+impl PortOwner {
+    /// Whether the owner is another Connecto instance
+    pub fn is_connecto(&self) -> bool {
+        self.name
+            .as_deref()
+            .is_some_and(|name| name.to_lowercase().starts_with("connecto"))
+    }
+}
+
+impl fmt::Display for PortOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} (pid {})", name, self.pid),
+            None => write!(f, "pid {}", self.pid),
+        }
+    }
+}
+
+/// The error for failing to bind TCP `port`
+///
+/// A port in use becomes [`ConnectoError::PortInUse`] naming its owner when
+/// it can be found; anything else stays a network error.
+pub fn bind_error(port: u16, e: io::Error) -> ConnectoError {
+    if e.kind() == io::ErrorKind::AddrInUse {
+        ConnectoError::PortInUse {
+            port,
+            owner: find_owner(port),
+        }
+    } else {
+        ConnectoError::Network(format!("Failed to bind port {}: {}", port, e))
+    }
+}
+
+/// Check that TCP `port` can be bound, before announcing anything on it
+///
+/// The port is released again at once; the server binds it for real later.
+pub fn check_available(port: u16) -> Result<()> {
+    std::net::TcpListener::bind(("0.0.0.0", port))
+        .map(drop)
+        .map_err(|e| bind_error(port, e))
+}
+
+/// The first port after `port` that can be bound right now
+pub fn suggest_free_port(port: u16) -> Option<u16> {
+    (1..=FREE_PORT_SEARCH)
+        .filter_map(|offset| port.checked_add(offset))
+        .find(|candidate| std::net::TcpListener::bind(("0.0.0.0", *candidate)).is_ok())
+}
+
+/// The process listening on TCP `port`, if the platform tells us
+///
+/// Processes of other users are usually hidden unless we run as root.
+pub fn find_owner(port: u16) -> Option<PortOwner> {
+    platform_owner(port)
+}
+
+#[cfg(target_os = "linux")]
+fn platform_owner(port: u16) -> Option<PortOwner> {
+    let inodes: Vec<u64> = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|table| parse_proc_net_tcp(&table, port))
+        .collect();
+    if inodes.is_empty() {
+        return None;
+    }
+
+    let targets: Vec<String> = inodes
+        .iter()
+        .map(|inode| format!("socket:[{}]", inode))
+        .collect();
+    for process in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = process
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        let holds_socket = fds.flatten().any(|fd| {
+            std::fs::read_link(fd.path())
+                .is_ok_and(|link| targets.iter().any(|t| link.as_os_str() == t.as_str()))
+        });
+        if holds_socket {
+            let name = std::fs::read_to_string(process.path().join("comm"))
+                .ok()
+                .map(|comm| comm.trim().to_string());
+            return Some(PortOwner { pid, name });
+        }
+    }
+    None
+}
+
+#[cfg(target_os = "macos")]
+fn platform_owner(port: u16) -> Option<PortOwner> {
+    let output = Command::new("lsof")
+        .args(["-nP", &format!("-iTCP:{}", port), "-sTCP:LISTEN", "-Fpc"])
+        .output()
+        .ok()?;
+    parse_lsof(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(windows)]
+fn platform_owner(port: u16) -> Option<PortOwner> {
+    let output = Command::new("netstat")
+        .args(["-ano", "-p", "TCP"])
+        .output()
+        .ok()?;
+    let pid = parse_netstat(&String::from_utf8_lossy(&output.stdout), port)?;
+    let name = Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/FO", "CSV", "/NH"])
+        .output()
+        .ok()
+        .and_then(|output| parse_tasklist(&String::from_utf8_lossy(&output.stdout)));
+    Some(PortOwner { pid, name })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn platform_owner(_port: u16) -> Option<PortOwner> {
+    None
+}
+
+/// Socket inodes listening on `port` in a `/proc/net/tcp` table
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn parse_proc_net_tcp(table: &str, port: u16) -> Vec<u64> {
+    const LISTEN: &str = "0A";
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let local_port = fields.get(1)?.rsplit(':').next()?;
+            let listening = *fields.get(3)? == LISTEN;
+            (listening && u16::from_str_radix(local_port, 16).ok()? == port)
+                .then(|| fields.get(9)?.parse().ok())
+                .flatten()
+        })
+        .collect()
+}
+
+/// The first process in `lsof -F pc` output
+#[cfg_attr(not(any(target_os = "macos", test)), allow(dead_code))]
+fn parse_lsof(output: &str) -> Option<PortOwner> {
+    let mut pid = None;
+    let mut name = None;
+    for line in output.lines() {
+        if let Some(value) = line.strip_prefix('p') {
+            if pid.is_some() {
+                break;
+            }
+            pid = value.parse().ok();
+        } else if let Some(value) = line.strip_prefix('c') {
+            name = Some(value.to_string());
+        }
+    }
+    Some(PortOwner { pid: pid?, name })
+}
+
+/// The process listening on `port` in `netstat -ano` output
+#[cfg_attr(not(any(windows, test)), allow(dead_code))]
+fn parse_netstat(output: &str, port: u16) -> Option<u32> {
+    let suffix = format!(":{}", port);
+    output.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["TCP", local, _, "LISTENING", pid] if local.ends_with(&suffix) => pid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// The image name in `tasklist /FO CSV /NH` output
+#[cfg_attr(not(any(windows, test)), allow(dead_code))]
+fn parse_tasklist(output: &str) -> Option<String> {
+    let name = output.lines().next()?.split(',').next()?.trim_matches('"');
+    (!name.is_empty() && !name.starts_with("INFO:")).then(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_net_tcp() {
+        let table = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:1FA3 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 48213 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:1FA3 0100007F:C350 01 00000000:00000000 00:00000000 00000000  1000        0 48999 1 0000000000000000 20 4 30 10 -1
+   2: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1234 1 0000000000000000 100 0 0 10 0
+";
+        // Only the listening socket on 8099 counts, not the connection to it
+        assert_eq!(parse_proc_net_tcp(table, 8099), vec![48213]);
+        assert_eq!(parse_proc_net_tcp(table, 22), vec![1234]);
+        assert!(parse_proc_net_tcp(table, 8100).is_empty());
+    }
+
+    #[test]
+    fn test_parse_lsof() {
+        // IPv4 and IPv6 sockets may belong to different processes; take the first
+        let owner = parse_lsof("p4321\ncconnecto\np99\ncother\n").unwrap();
+        assert_eq!(owner.pid, 4321);
+        assert_eq!(owner.name.as_deref(), Some("connecto"));
+        assert_eq!(parse_lsof(""), None);
+    }
+
+    #[test]
+    fn test_parse_netstat_and_tasklist() {
+        let netstat = "
+Active Connections
+
+  Proto  Local Address          Foreign Address        State           PID
+  TCP    0.0.0.0:135            0.0.0.0:0              LISTENING       1044
+  TCP    0.0.0.0:8099           0.0.0.0:0              LISTENING       5120
+  TCP    192.168.1.7:8099       192.168.1.9:50123      ESTABLISHED     5120
+";
+        assert_eq!(parse_netstat(netstat, 8099), Some(5120));
+        assert_eq!(parse_netstat(netstat, 8100), None);
+
+        let tasklist = "\"connecto.exe\",\"5120\",\"Console\",\"1\",\"12,345 K\"\r\n";
+        assert_eq!(parse_tasklist(tasklist).as_deref(), Some("connecto.exe"));
+        assert_eq!(
+            parse_tasklist("INFO: No tasks are running which match the specified criteria."),
+            None
+        );
+    }
+
+    #[test]
+    fn test_port_owner() {
+        let owner = PortOwner {
+            pid: 42,
+            name: Some("connecto".to_string()),
+        };
+        assert!(owner.is_connecto());
+        assert_eq!(owner.to_string(), "connecto (pid 42)");
+
+        let unnamed = PortOwner { pid: 7, name: None };
+        assert!(!unnamed.is_connecto());
+        assert_eq!(unnamed.to_string(), "pid 7");
+    }
+
+    #[test]
+    fn test_bind_error_reports_port_in_use() {
+        let taken = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let e = std::net::TcpListener::bind(("0.0.0.0", port)).unwrap_err();
+
+        match bind_error(port, e) {
+            ConnectoError::PortInUse { port: p, owner } => {
+                assert_eq!(p, port);
+                // We hold the port ourselves, so we can always see the owner here
+                #[cfg(target_os = "linux")]
+                assert_eq!(owner.unwrap().pid, std::process::id());
+                #[cfg(not(target_os = "linux"))]
+                let _ = owner;
+            }
+            other => panic!("Expected PortInUse, got {:?}", other),
+        }
+
+        let other = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert!(matches!(bind_error(80, other), ConnectoError::Network(_)));
+        assert!(check_available(port).is_err());
+        assert!(suggest_free_port(port).is_some_and(|free| free > port));
+        drop(taken);
+        assert!(check_available(port).is_ok());
+    }
+}
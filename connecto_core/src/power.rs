@@ -0,0 +1,294 @@
+//! Power state module
+//!
+//! Tells listeners when the machine goes to sleep and wakes up again, so they
+//! can withdraw their mDNS advertisement instead of leaving a stale record
+//! that scanners keep finding.
+//!
+//! On Linux, systemd-logind announces a suspend before it happens; its
+//! `PrepareForSleep` signal is read through `gdbus monitor`. Other platforms,
+//! and Linux without logind, only learn about a sleep after waking: the wall
+//! clock jumping ahead between two ticks of a timer that stood still while
+//! the machine slept gives it away.
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// How often the wall clock is checked for a jump
+pub const DEFAULT_TICK: Duration = Duration::from_secs(5);
+
+/// A jump beyond the tick longer than this counts as sleep
+const SLEEP_THRESHOLD: Duration = Duration::from_secs(20);
+
+/// A change in the machine's power state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerEvent {
+    /// The machine is about to sleep
+    Sleeping,
+    /// The machine woke up; sent after a detected sleep even if `Sleeping`
+    /// was never sent
+    Woke,
+}
+
+/// Watches for sleep and wake
+#[derive(Debug, Clone)]
+pub struct PowerMonitor {
+    tick: Duration,
+}
+
+impl PowerMonitor {
+    pub fn new() -> Self {
+        Self { tick: DEFAULT_TICK }
+    }
+
+    /// Check the wall clock every `tick` instead of the default
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    /// Start watching in the background
+    ///
+    /// Events arrive on the returned receiver; watching stops when it is
+    /// dropped.
+    pub fn watch(self) -> mpsc::Receiver<PowerEvent> {
+        let (tx, rx) = mpsc::channel(8);
+        let (raw_tx, mut raw_rx) = mpsc::channel(8);
+
+        tokio::spawn(watch_clock(self.tick, raw_tx.clone()));
+        #[cfg(target_os = "linux")]
+        tokio::spawn(watch_logind(raw_tx));
+        #[cfg(not(target_os = "linux"))]
+        drop(raw_tx);
+
+        let tick = self.tick;
+        tokio::spawn(async move {
+            let mut state = PowerState::default();
+            loop {
+                let (event, source) = tokio::select! {
+                    raw = raw_rx.recv() => match raw {
+                        Some(raw) => raw,
+                        None => break,
+                    },
+                    _ = tx.closed() => break,
+                };
+                if state.accept(event, source, Instant::now(), tick)
+                    && tx.send(event).await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+impl Default for PowerMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where a raw event came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    /// The OS announced it
+    Os,
+    /// The wall clock jumped
+    Clock,
+}
+
+/// Merges events from the OS and the clock into one stream without repeats
+#[derive(Debug, Default)]
+struct PowerState {
+    asleep: bool,
+    last_woke: Option<Instant>,
+}
+
+impl PowerState {
+    /// Whether `event` should be passed on
+    fn accept(&mut self, event: PowerEvent, source: Source, now: Instant, tick: Duration) -> bool {
+        match event {
+            PowerEvent::Sleeping if self.asleep => false,
+            PowerEvent::Sleeping => {
+                self.asleep = true;
+                true
+            }
+            PowerEvent::Woke => {
+                // The clock notices a wake the OS already announced a tick later
+                let reported = self
+                    .last_woke
+                    .is_some_and(|woke| now.duration_since(woke) <= tick * 3);
+                if !self.asleep && source == Source::Clock && reported {
+                    return false;
+                }
+                self.asleep = false;
+                self.last_woke = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Whether `wall_elapsed` between two ticks `tick` apart means the machine slept
+fn slept(wall_elapsed: Duration, tick: Duration) -> bool {
+    wall_elapsed > tick + SLEEP_THRESHOLD
+}
+
+async fn watch_clock(tick: Duration, tx: mpsc::Sender<(PowerEvent, Source)>) {
+    let mut interval = tokio::time::interval(tick);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+    let mut last = SystemTime::now();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = tx.closed() => return,
+        }
+        let now = SystemTime::now();
+        // A clock set backwards is not sleep
+        let elapsed = now.duration_since(last).unwrap_or_default();
+        last = now;
+        if slept(elapsed, tick) {
+            debug!("Wall clock jumped {:?}; the machine was asleep", elapsed);
+            if tx.send((PowerEvent::Woke, Source::Clock)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Parse a line of `gdbus monitor` output for logind's `PrepareForSleep`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_logind_line(line: &str) -> Option<PowerEvent> {
+    let (_, args) = line.split_once(".PrepareForSleep ")?;
+    match args.trim() {
+        "(true,)" => Some(PowerEvent::Sleeping),
+        "(false,)" => Some(PowerEvent::Woke),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn watch_logind(tx: mpsc::Sender<(PowerEvent, Source)>) {
+    use std::process::Stdio;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::process::Command;
+
+    let child = Command::new("gdbus")
+        .args([
+            "monitor",
+            "--system",
+            "--dest",
+            "org.freedesktop.login1",
+            "--object-path",
+            "/org/freedesktop/login1",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            debug!(
+                "Sleep notifications unavailable, relying on the clock: {}",
+                e
+            );
+            return;
+        }
+    };
+    let Some(stdout) = child.stdout.take() else {
+        return;
+    };
+
+    let mut lines = BufReader::new(stdout).lines();
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = tx.closed() => return,
+        };
+        let Ok(Some(line)) = line else {
+            debug!("Sleep notifications ended, relying on the clock");
+            return;
+        };
+        if let Some(event) = parse_logind_line(&line) {
+            if tx.send((event, Source::Os)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_logind_line() {
+        assert_eq!(
+            parse_logind_line(
+                "/org/freedesktop/login1: org.freedesktop.login1.Manager.PrepareForSleep (true,)"
+            ),
+            Some(PowerEvent::Sleeping)
+        );
+        assert_eq!(
+            parse_logind_line(
+                "/org/freedesktop/login1: org.freedesktop.login1.Manager.PrepareForSleep (false,)"
+            ),
+            Some(PowerEvent::Woke)
+        );
+        assert_eq!(
+            parse_logind_line(
+                "/org/freedesktop/login1: org.freedesktop.login1.Manager.PrepareForShutdown (true,)"
+            ),
+            None
+        );
+        assert_eq!(parse_logind_line("Monitoring signals on object"), None);
+    }
+
+    #[test]
+    fn test_slept() {
+        let tick = Duration::from_secs(5);
+        assert!(!slept(Duration::from_secs(5), tick));
+        assert!(!slept(Duration::from_secs(12), tick));
+        assert!(slept(Duration::from_secs(600), tick));
+    }
+
+    #[test]
+    fn test_power_state_merges_sources() {
+        let tick = Duration::from_secs(5);
+        let start = Instant::now();
+        let mut state = PowerState::default();
+
+        // The OS announces both ends; the clock's late wake is a repeat
+        assert!(state.accept(PowerEvent::Sleeping, Source::Os, start, tick));
+        assert!(!state.accept(PowerEvent::Sleeping, Source::Os, start, tick));
+        assert!(state.accept(PowerEvent::Woke, Source::Os, start, tick));
+        assert!(!state.accept(
+            PowerEvent::Woke,
+            Source::Clock,
+            start + Duration::from_secs(5),
+            tick
+        ));
+
+        // A later sleep only the clock noticed still wakes listeners
+        assert!(state.accept(
+            PowerEvent::Woke,
+            Source::Clock,
+            start + Duration::from_secs(3600),
+            tick
+        ));
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        assert_eq!(
+            serde_json::to_string(&PowerEvent::Sleeping).unwrap(),
+            "\"sleeping\""
+        );
+    }
+}
@@ -2,45 +2,345 @@
 //!
 //! Defines the protocol for exchanging SSH keys between devices
 
+use crate::access::AccessList;
+use crate::accounts::Account;
+use crate::audit::{AuditEvent, AuditLog, Decision, DecisionLog, DecisionRecord};
+use crate::authorized_keys::Merge;
+use crate::capabilities::Capabilities;
+use crate::channel::{self, CodeExchange, CodeKey, KeyShare, Side};
+use crate::clock;
+use crate::connectivity::SSH_PORT;
+use crate::discovery::get_hostname;
 use crate::error::{ConnectoError, Result};
-use crate::keys::{KeyManager, SshKeyPair};
+use crate::framing::Framing;
+use crate::identity::{DeviceIdentity, IdentityProof, VerifiedIdentity};
+use crate::keys::{KeyManager, KeyOptions, SshKeyPair};
+use crate::known_hosts;
+use crate::limits::{
+    HandshakeLimits, RateLimiter, DEFAULT_MAX_MESSAGE_LEN, DEFAULT_STEP_TIMEOUT_SECS,
+};
+use crate::net;
+use crate::pairings::{PairingDirection, PairingRecord, PairingStore};
+use crate::ports;
+use crate::retry::{Retry, RetryPolicy};
+use crate::shutdown::ShutdownHandle;
+use crate::transfer::OfferedFile;
+use crate::trust::{self, TrustCheck, TrustLevel, TrustMode, TrustStore};
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 
 /// Protocol version for compatibility checking
-pub const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Oldest protocol version still accepted from peers
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// First protocol version in which clients prove possession of the key they send
+pub const KEY_PROOF_VERSION: u32 = 2;
+
+/// First protocol version in which servers tell clients they are waiting for approval
+pub const APPROVAL_PENDING_VERSION: u32 = 3;
+
+/// SSH signature namespace for key-possession proofs
+pub const KEY_PROOF_NAMESPACE: &str = "connecto-pairing";
+
+/// First protocol version in which clients echo the verification code
+pub const PIN_VERSION: u32 = 4;
+
+/// First protocol version in which servers honour key lifetimes sent by clients
+pub const KEY_LIFETIME_VERSION: u32 = 5;
+
+/// First protocol version in which servers send their SSH host keys
+pub const HOST_KEYS_VERSION: u32 = 6;
+
+/// First protocol version in which the messages after `Hello` and `HelloAck`
+/// are length-prefixed frames instead of lines
+pub const FRAMED_VERSION: u32 = 7;
+
+/// First protocol version in which peers prove their identity by signing
+/// each other's nonce with their identity key
+pub const IDENTITY_PROOF_VERSION: u32 = 8;
+
+/// First protocol version in which the messages after `Hello` and `HelloAck`
+/// travel over an encrypted [`channel`](crate::channel)
+pub const ENCRYPTED_VERSION: u32 = 9;
+
+/// How many wrong verification codes a client may enter
+pub const PIN_ATTEMPTS: u32 = 3;
+
+/// How long a client has to enter the verification code by default
+pub const PIN_TIMEOUT_SECS: u64 = 120;
+
+/// How long a pairing request waits for the user's approval by default
+pub const APPROVAL_TIMEOUT_SECS: u64 = 120;
+
+/// How often a waiting client is reminded that its request is still pending
+pub const APPROVAL_NOTICE_INTERVAL_SECS: u64 = 15;
+
+/// What went wrong, as sent in an `Error` message
+///
+/// Each code travels as a number, so peers that predate this enum still
+/// understand it. Numbers this version does not know read as
+/// [`InternalError`](Self::InternalError).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "u32", from = "u32")]
+pub enum ProtocolErrorCode {
+    /// The peers share no protocol version, or the client's version lacks
+    /// something the listener requires
+    VersionMismatch,
+    /// A message arrived that the protocol does not allow at that point
+    UnexpectedMessage,
+    /// A verification code, key fingerprint, key proof or identity did not
+    /// check out
+    VerificationFailed,
+    /// The listener's user turned the request down, or did not answer in time
+    Rejected,
+    /// The listener's access list refuses the client, or the client chose an
+    /// account the listener does not offer or cannot install keys for
+    AccessDenied,
+    /// The client opened too many connections, or the listener is busy with
+    /// other handshakes
+    RateLimited,
+    /// Something failed on the sender's side
+    InternalError,
+}
+
+impl ProtocolErrorCode {
+    /// The number sent on the wire
+    pub fn number(self) -> u32 {
+        match self {
+            Self::VersionMismatch => 1,
+            Self::UnexpectedMessage => 2,
+            Self::Rejected => 5,
+            Self::VerificationFailed => 6,
+            Self::AccessDenied => 7,
+            Self::RateLimited => 8,
+            Self::InternalError => 10,
+        }
+    }
+
+    /// The code for a number received on the wire
+    ///
+    /// Older listeners also sent 3 for unexpected messages and 4 for failed
+    /// key proofs.
+    pub fn from_number(number: u32) -> Self {
+        match number {
+            1 => Self::VersionMismatch,
+            2 | 3 => Self::UnexpectedMessage,
+            4 | 6 => Self::VerificationFailed,
+            5 => Self::Rejected,
+            7 => Self::AccessDenied,
+            8 => Self::RateLimited,
+            _ => Self::InternalError,
+        }
+    }
+
+    /// The error a client returns when the listener sent this code
+    pub fn into_error(self, message: String) -> ConnectoError {
+        match self {
+            Self::VersionMismatch => ConnectoError::VersionMismatch(message),
+            Self::UnexpectedMessage => ConnectoError::Protocol(message),
+            Self::VerificationFailed => ConnectoError::VerificationFailed(message),
+            Self::Rejected => ConnectoError::Rejected(message),
+            Self::AccessDenied => ConnectoError::PermissionDenied(message),
+            Self::RateLimited => ConnectoError::RateLimited(message),
+            Self::InternalError => ConnectoError::Handshake(message),
+        }
+    }
+}
+
+impl From<ProtocolErrorCode> for u32 {
+    fn from(code: ProtocolErrorCode) -> Self {
+        code.number()
+    }
+}
+
+impl From<u32> for ProtocolErrorCode {
+    fn from(number: u32) -> Self {
+        Self::from_number(number)
+    }
+}
+
+/// Version carried in revocation requests
+pub const REVOKE_VERSION: u32 = 1;
+
+/// SSH signature namespace for proofs that a revocation comes from the
+/// key's holder
+pub const REVOKE_NAMESPACE: &str = "connecto-revoke";
 
 /// Message types in the handshake protocol
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Message {
     /// Initial hello from client
-    Hello { version: u32, device_name: String },
+    Hello {
+        /// Newest version the client speaks
+        version: u32,
+        device_name: String,
+        /// Sender's clock in seconds since the Unix epoch, see [`crate::clock`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<i64>,
+        /// Oldest version the client speaks; older clients leave it out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_version: Option<u32>,
+        /// Features the client supports; older clients leave it out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Capabilities>,
+        /// Identity fingerprint of the client device; older clients and
+        /// scanners leave it out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// Nonce for the server to sign with its identity key (v8+)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+        /// Ephemeral X25519 public key for the encrypted channel (v9+)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_share: Option<String>,
+    },
 
     /// Server acknowledges hello
     HelloAck {
         version: u32,
         device_name: String,
+        /// Only sent by servers older than [`PIN_VERSION`]
         verification_code: Option<String>,
+        /// Identity fingerprint of the server device
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// The client must enter the code shown on the server before sending
+        /// its key (v4+)
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pin_required: bool,
+        /// Sender's clock in seconds since the Unix epoch, see [`crate::clock`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<i64>,
+        /// Features both sides support; older servers leave it out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Capabilities>,
+        /// Accounts the client may install its key into, the server's own
+        /// first; empty unless the server offers a choice
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        users: Vec<String>,
+        /// The server's identity key and its signature over the client's
+        /// nonce (v8+)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity_proof: Option<IdentityProof>,
+        /// Nonce for the client to sign with its identity key (v8+)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+        /// Ephemeral X25519 public key for the encrypted channel (v9+)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_share: Option<String>,
+    },
+
+    /// Client's identity key and its signature over the server's nonce, sent
+    /// first after `HelloAck` by clients that announced an identity (v8+)
+    IdentityProof { proof: IdentityProof },
+
+    /// Server asks for the verification code shown to its user (v4+)
+    PinRequest { attempts_left: u32 },
+
+    /// Client's answer to a PinRequest (v4-v8)
+    PinEntry { pin: String },
+
+    /// SPAKE2 message over the verification code and the channel binding,
+    /// sent by the client in answer to a PinRequest and by the server in
+    /// answer to that (v9+)
+    PinExchange { message: String },
+
+    /// Client's proof that it derived the same key from the exchange (v9+)
+    PinConfirm { mac: String },
+
+    /// Server accepted the verification code
+    PinAccepted {
+        /// Server's proof that it derived the same key from the exchange (v9+)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mac: Option<String>,
     },
 
     /// Client sends its public key
-    KeyExchange { public_key: String, comment: String },
+    KeyExchange {
+        public_key: String,
+        comment: String,
+        /// Seconds the key stays authorized (v5+); a duration rather than a
+        /// time, so it does not depend on the clocks agreeing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_in: Option<u64>,
+        /// SHA-256 fingerprint of `public_key` as the client computed it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<String>,
+        /// Account to install the key into, one of the server's `users`; the
+        /// server's own when left out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+    },
+
+    /// Server asks the client to sign a nonce with the key it sent (v2+)
+    KeyChallenge { nonce: String },
+
+    /// Client's armored SSH signature over the challenge nonce (v2+)
+    KeyProof { signature: String },
+
+    /// Server is waiting for its user to approve the key (v3+)
+    ApprovalPending { remaining_secs: u64 },
 
     /// Server acknowledges key received and installed
-    KeyAccepted { message: String },
+    KeyAccepted {
+        message: String,
+        /// SHA-256 fingerprint of the key the server installed, for the
+        /// client to check against the key it sent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<String>,
+    },
+
+    /// Server's SSH host keys, for the client's known_hosts (v6+)
+    HostKeys { keys: Vec<String> },
 
     /// Error occurred
-    Error { code: u32, message: String },
+    Error {
+        code: ProtocolErrorCode,
+        message: String,
+    },
 
     /// Pairing complete
-    PairingComplete { ssh_user: String },
+    PairingComplete {
+        ssh_user: String,
+        /// Real hostname of a server in privacy mode, revealed only now
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hostname: Option<String>,
+        /// Identity fingerprint of a server in privacy mode, revealed only now
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// Proof of the identity revealed, over the client's nonce (v8+)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity_proof: Option<IdentityProof>,
+        /// Port of the server's SSH server; 22 when left out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ssh_port: Option<u16>,
+    },
+
+    /// Client asks the server to remove one of its keys from
+    /// authorized_keys, in place of `Hello`; the server answers with a
+    /// `KeyChallenge` to sign with that key
+    Revoke {
+        version: u32,
+        device_name: String,
+        /// SHA-256 fingerprint of the key to remove
+        fingerprint: String,
+    },
+
+    /// Server removed the key named in `Revoke`
+    KeyRevoked { fingerprint: String },
 
     // Sync protocol messages (bidirectional pairing)
     /// Initial sync hello with priority and key
@@ -51,6 +351,19 @@ pub enum Message {
         public_key: String,
         key_comment: String,
         ssh_user: String,
+        /// Further public keys for the receiver to authorize, besides
+        /// `public_key`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        keys: Vec<String>,
+        /// Identity fingerprint of the initiating device
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// Sender's clock in seconds since the Unix epoch, see [`crate::clock`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<i64>,
+        /// Nonce for the responder to sign with its identity key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
     },
 
     /// Sync hello acknowledgment with key
@@ -61,10 +374,102 @@ pub enum Message {
         key_comment: String,
         ssh_user: String,
         accept_sync: bool,
+        /// Further public keys for the receiver to authorize, besides
+        /// `public_key`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        keys: Vec<String>,
+        /// Identity fingerprint of the responding device
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// Sender's clock in seconds since the Unix epoch, see [`crate::clock`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<i64>,
+        /// Proof of `identity` over the initiator's nonce
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity_proof: Option<IdentityProof>,
     },
 
     /// Sync complete confirmation
     SyncComplete { success: bool, message: String },
+
+    /// The sender refused the receiver's keys, in place of its next message;
+    /// whatever the receiver installed in this sync should be withdrawn
+    SyncRejected {
+        device_name: String,
+        message: String,
+    },
+
+    /// Digests of the sender's key list and of the list it holds for the
+    /// receiver, exchanged by sync daemons before sending changes
+    SyncKeyList {
+        version: u32,
+        device_name: String,
+        /// Identity fingerprint of the sending device
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// Nonce for the receiver to sign with its identity key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+        /// Proof of `identity` over the nonce of the list answered
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity_proof: Option<IdentityProof>,
+        /// Digest of the keys the sender wants the receiver to authorize
+        digest: String,
+        /// Digest of the receiver's list as the sender holds it, if they
+        /// synced before
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        held: Option<String>,
+    },
+
+    /// Changes to the sender's key list since the list the receiver holds
+    SyncKeyDiff {
+        /// Digest of the list the changes apply to; absent when `added` is
+        /// the whole list
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base: Option<String>,
+        #[serde(default)]
+        added: Vec<String>,
+        #[serde(default)]
+        removed: Vec<String>,
+        /// Digest of the list with the changes applied
+        digest: String,
+    },
+
+    // File transfer messages, see [`crate::transfer`]
+    /// Files the sender wants to send, before any of their data
+    TransferOffer {
+        version: u32,
+        device_name: String,
+        /// Identity fingerprint of the sending device
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// Nonce for the receiver to sign with its identity key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+        files: Vec<OfferedFile>,
+    },
+
+    /// The receiver's answer to an offer, for all of its files
+    TransferAccept {
+        device_name: String,
+        accepted: bool,
+        /// Identity fingerprint of the receiving device
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// Proof of `identity` over the sender's nonce
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity_proof: Option<IdentityProof>,
+        /// Why the offer was declined
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+
+    /// Base64 data of the file at index `file` in the offer
+    TransferChunk { file: usize, data: String },
+
+    /// All of the file at index `file` was sent, or was written by the
+    /// receiver, with its hex SHA-256
+    TransferComplete { file: usize, sha256: String },
 }
 
 impl Message {
@@ -93,48 +498,468 @@ pub enum ServerEvent {
         device_name: String,
         address: SocketAddr,
     },
+    /// Show this code to the user; the client has to enter it
+    VerificationCode {
+        device_name: String,
+        code: String,
+    },
     KeyReceived {
+        device_name: String,
         comment: String,
+        /// SHA-256 fingerprint of the key, to compare with the one the
+        /// client shows
+        fingerprint: String,
     },
     PairingComplete {
         device_name: String,
     },
+    PairingRejected {
+        device_name: String,
+    },
+    /// Nobody answered an approval request in time, so the default applied
+    ApprovalTimedOut {
+        device_name: String,
+        accepted: bool,
+    },
+    /// The client's clock is more than [`crate::clock::CLOCK_SKEW_WARN_SECS`]
+    /// away from ours; positive when it is ahead
+    ClockSkew {
+        device_name: String,
+        skew_secs: i64,
+    },
+    /// The access list refused the client before its request was answered
+    AccessDenied {
+        device_name: String,
+        address: SocketAddr,
+        reason: String,
+    },
+    /// A client proved it holds an authorized key and had it removed
+    KeyRevoked {
+        device_name: String,
+        address: SocketAddr,
+        fingerprint: String,
+    },
+    /// The connection from `address` ended without a pairing or revocation
+    ///
+    /// Scanner probes end like this too, so it only means a failed pairing
+    /// after a [`ServerEvent::PairingRequest`] from the same address.
+    PairingFailed {
+        address: SocketAddr,
+        reason: String,
+    },
     Error {
         message: String,
     },
 }
 
+/// Events emitted by the handshake client
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The server is waiting for its user to approve our key
+    AwaitingApproval { remaining_secs: u64 },
+    /// Connecting failed and will be tried again after `delay`
+    Retrying {
+        /// The attempt that failed, from 1
+        attempt: u32,
+        attempts: u32,
+        delay: Duration,
+        reason: String,
+    },
+}
+
+/// What happens to a pairing request nobody approves or rejects in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ApprovalTimeoutAction {
+    /// Reject the request
+    #[default]
+    Reject,
+    /// Accept the request if the client proved possession of its key,
+    /// otherwise reject it
+    AcceptIfVerified,
+}
+
+/// A pairing request waiting for the user to accept or reject it
+///
+/// Sent to the approval channel after the client has proven possession of
+/// its key, and before anything is written to `authorized_keys`. Dropping
+/// the request without answering rejects it.
+#[derive(Debug)]
+pub struct ApprovalRequest {
+    pub device_name: String,
+    pub address: SocketAddr,
+    /// SHA-256 fingerprint of the client's public key
+    pub fingerprint: String,
+    /// Comment of the client's public key (usually `user@host`)
+    pub comment: String,
+    responder: oneshot::Sender<bool>,
+}
+
+impl ApprovalRequest {
+    /// A request for the key with `fingerprint` and `comment`, and the
+    /// receiver its answer arrives on
+    pub(crate) fn new(
+        device_name: &str,
+        address: SocketAddr,
+        fingerprint: &str,
+        comment: &str,
+    ) -> (Self, oneshot::Receiver<bool>) {
+        let (responder, response) = oneshot::channel();
+        let request = Self {
+            device_name: device_name.to_string(),
+            address,
+            fingerprint: fingerprint.to_string(),
+            comment: comment.to_string(),
+            responder,
+        };
+        (request, response)
+    }
+
+    /// Answer the request
+    pub fn respond(self, approved: bool) {
+        let _ = self.responder.send(approved);
+    }
+
+    /// Install the client's key
+    pub fn approve(self) {
+        self.respond(true);
+    }
+
+    /// Refuse the client's key
+    pub fn reject(self) {
+        self.respond(false);
+    }
+
+    /// Wait until the request can no longer be answered, because it timed
+    /// out or the client went away
+    pub async fn closed(&mut self) {
+        self.responder.closed().await;
+    }
+
+    /// Whether the request can no longer be answered
+    pub fn is_closed(&self) -> bool {
+        self.responder.is_closed()
+    }
+}
+
+/// A server asking for the verification code shown to its user
+///
+/// Sent to the prompt channel each time the server asks. Dropping the prompt
+/// without answering gives up on the pairing.
+#[derive(Debug)]
+pub struct PinPrompt {
+    /// Name the server announced
+    pub server_name: String,
+    /// Address being paired with
+    pub address: String,
+    /// Tries left, including this one
+    pub attempts_left: u32,
+    responder: oneshot::Sender<String>,
+}
+
+impl PinPrompt {
+    /// Answer with the code the user entered
+    pub fn respond(self, pin: &str) {
+        let _ = self.responder.send(pin.to_string());
+    }
+}
+
 /// Handshake server that listens for pairing requests
 pub struct HandshakeServer {
     listener: Option<TcpListener>,
+    bind_address: IpAddr,
     key_manager: Arc<KeyManager>,
     device_name: String,
     require_verification: bool,
+    pin_timeout: Duration,
+    require_key_proof: bool,
+    require_encryption: bool,
+    identity: Option<DeviceIdentity>,
+    private: bool,
+    pairings: Option<PairingStore>,
+    decisions: Option<DecisionLog>,
+    approval_tx: Option<mpsc::Sender<ApprovalRequest>>,
+    approval_timeout: Duration,
+    approval_timeout_action: ApprovalTimeoutAction,
+    trust_levels: Option<TrustStore>,
+    key_options: KeyOptions,
+    restrict_source: bool,
+    host_keys: Vec<String>,
+    ssh_port: u16,
+    access: AccessList,
+    limits: HandshakeLimits,
+    own_user: String,
+    users: Vec<String>,
+    lookup_account: AccountLookup,
+    shutdown: ShutdownHandle,
 }
 
+/// How the accounts clients name are found, [`Account::lookup`] outside tests
+type AccountLookup = Arc<dyn Fn(&str) -> Result<Account> + Send + Sync>;
+
 impl HandshakeServer {
     /// Create a new handshake server
     pub fn new(key_manager: KeyManager, device_name: &str) -> Self {
         Self {
             listener: None,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             key_manager: Arc::new(key_manager),
             device_name: device_name.to_string(),
             require_verification: false,
+            pin_timeout: Duration::from_secs(PIN_TIMEOUT_SECS),
+            require_key_proof: true,
+            require_encryption: false,
+            identity: None,
+            private: false,
+            pairings: None,
+            decisions: None,
+            approval_tx: None,
+            approval_timeout: Duration::from_secs(APPROVAL_TIMEOUT_SECS),
+            approval_timeout_action: ApprovalTimeoutAction::default(),
+            trust_levels: None,
+            key_options: KeyOptions::default(),
+            restrict_source: false,
+            host_keys: known_hosts::local_host_keys(),
+            ssh_port: known_hosts::local_ssh_port(),
+            access: AccessList::default(),
+            limits: HandshakeLimits::default(),
+            own_user: current_user(),
+            users: Vec::new(),
+            lookup_account: Arc::new(Account::lookup),
+            shutdown: ShutdownHandle::new(),
         }
     }
 
-    /// Enable verification code requirement
+    /// Require clients to enter a verification code before sending their key
+    ///
+    /// Each client gets a fresh code, reported as
+    /// [`ServerEvent::VerificationCode`] for the user to read out. Clients
+    /// older than [`PIN_VERSION`] cannot enter one and are refused.
     pub fn with_verification(mut self, require: bool) -> Self {
         self.require_verification = require;
         self
     }
 
+    /// Give clients `timeout` to enter the verification code
+    ///
+    /// Defaults to [`PIN_TIMEOUT_SECS`].
+    pub fn with_pin_timeout(mut self, timeout: Duration) -> Self {
+        self.pin_timeout = timeout;
+        self
+    }
+
+    /// Whether to reject clients that cannot prove possession of their key
+    /// (default: yes)
+    ///
+    /// Clients speaking protocol version 1 never send a key proof. Turning
+    /// this off pairs with them, trusting their key unverified, and lets
+    /// anyone in the middle downgrade a pairing to skip the proof.
+    pub fn with_key_proof(mut self, require: bool) -> Self {
+        self.require_key_proof = require;
+        self
+    }
+
+    /// Whether to reject clients that cannot encrypt the channel (default: no)
+    ///
+    /// Clients older than [`ENCRYPTED_VERSION`] pair in plaintext otherwise,
+    /// which also lets anyone in the middle downgrade a pairing to it.
+    pub fn with_encryption(mut self, require: bool) -> Self {
+        self.require_encryption = require;
+        self
+    }
+
+    /// Announce this device's identity to clients, and prove it by signing
+    /// their nonce with its key
+    pub fn with_identity(mut self, identity: &DeviceIdentity) -> Self {
+        self.identity = Some(identity.clone());
+        self
+    }
+
+    /// Keep this device's hostname and identity from unpaired clients
+    ///
+    /// Clients only see the device name until pairing succeeds;
+    /// `PairingComplete` then reveals the hostname and identity.
+    pub fn with_privacy(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Record accepted pairings in a pairing database
+    pub fn with_pairing_store(mut self, store: PairingStore) -> Self {
+        self.pairings = Some(store);
+        self
+    }
+
+    /// Record every accept/reject decision in a decision log
+    pub fn with_decision_log(mut self, log: DecisionLog) -> Self {
+        self.decisions = Some(log);
+        self
+    }
+
+    /// Ask for approval before installing a client's key
+    ///
+    /// Each request is sent to `approval_tx` and the client waits until it is
+    /// answered, or until the approval timeout (see
+    /// [`with_approval_timeout`](Self::with_approval_timeout)) runs out.
+    pub fn with_approval(mut self, approval_tx: mpsc::Sender<ApprovalRequest>) -> Self {
+        self.approval_tx = Some(approval_tx);
+        self
+    }
+
+    /// Apply `action` to approval requests still unanswered after `timeout`
+    ///
+    /// Defaults to rejecting after [`APPROVAL_TIMEOUT_SECS`].
+    pub fn with_approval_timeout(
+        mut self,
+        timeout: Duration,
+        action: ApprovalTimeoutAction,
+    ) -> Self {
+        self.approval_timeout = timeout;
+        self.approval_timeout_action = action;
+        self
+    }
+
+    /// Decide per client by its [`TrustLevel`] in `store`
+    ///
+    /// Trusted devices pair without a verification code or approval, known
+    /// devices must enter a code, and unknown devices must enter a code and
+    /// be approved. Approval is only asked for with
+    /// [`with_approval`](Self::with_approval), and
+    /// [`with_verification`](Self::with_verification) still asks every
+    /// device for a code, trusted ones included.
+    pub fn with_trust_levels(mut self, store: TrustStore) -> Self {
+        self.trust_levels = Some(store);
+        self
+    }
+
+    /// Install accepted keys with `options`, e.g. `no-port-forwarding`
+    ///
+    /// A key that was already authorized takes these options instead of its
+    /// old ones.
+    pub fn with_key_options(mut self, options: KeyOptions) -> Self {
+        self.key_options = options;
+        self
+    }
+
+    /// Only accept installed keys from the address the client paired from
+    ///
+    /// Adds `from="<address>"` to the key's options. Through a relay, this is
+    /// the address the relay saw.
+    pub fn with_source_restriction(mut self, restrict: bool) -> Self {
+        self.restrict_source = restrict;
+        self
+    }
+
+    /// Send these SSH host keys to clients instead of this machine's own
+    ///
+    /// By default the server sends the keys in the SSH server's directory
+    /// (see [`known_hosts::local_host_keys`]), so clients can trust the host
+    /// before they first connect.
+    pub fn with_host_keys(mut self, host_keys: Vec<String>) -> Self {
+        self.host_keys = host_keys;
+        self
+    }
+
+    /// Tell clients the SSH server listens on `port`
+    ///
+    /// By default the server sends the port set in `sshd_config` (see
+    /// [`known_hosts::local_ssh_port`]).
+    pub fn with_ssh_port(mut self, port: u16) -> Self {
+        self.ssh_port = port;
+        self
+    }
+
+    /// Only answer clients `access` lets through
+    ///
+    /// Refused clients get an `Error` message instead of a `HelloAck`, and
+    /// are reported as [`ServerEvent::AccessDenied`].
+    pub fn with_access_list(mut self, access: AccessList) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Enforce `limits` instead of the defaults
+    ///
+    /// Connections over the rate or concurrency limit get an `Error` message
+    /// with [`ProtocolErrorCode::RateLimited`] and are closed before the handshake starts.
+    pub fn with_limits(mut self, limits: HandshakeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Let clients install their key into one of `users` instead of the
+    /// account the server runs as
+    ///
+    /// The accounts are listed in `HelloAck`, after the server's own. Keys
+    /// go to the account's `~/.ssh/authorized_keys`, which needs the
+    /// privileges to write it; a client that asks for an account not
+    /// listed, or one that cannot be written, gets an `Error` message with
+    /// [`ProtocolErrorCode::AccessDenied`].
+    pub fn with_users(mut self, users: Vec<String>) -> Self {
+        self.users = users;
+        self
+    }
+
+    /// Install keys for `account` rather than the account the server runs as
+    ///
+    /// Keys go to the account's `~/.ssh/authorized_keys`, which is handed to
+    /// the account and so needs root to write, and `PairingComplete` names
+    /// it as the user to log in as. It comes first among the accounts offered
+    /// with [`with_users`](Self::with_users).
+    pub fn with_account(mut self, account: &Account) -> Self {
+        self.key_manager = Arc::new(account_key_manager(&self.key_manager, account));
+        self.own_user = account.name.clone();
+        self
+    }
+
+    fn client_settings(&self) -> ClientSettings {
+        ClientSettings {
+            device_name: self.device_name.clone(),
+            require_verification: self.require_verification,
+            pin_timeout: self.pin_timeout,
+            require_key_proof: self.require_key_proof,
+            require_encryption: self.require_encryption,
+            identity: self.identity.clone(),
+            private: self.private,
+            pairings: self.pairings.clone(),
+            decisions: self.decisions.clone(),
+            audit: self.key_manager.audit_log().cloned(),
+            approval_tx: self.approval_tx.clone(),
+            approval_timeout: self.approval_timeout,
+            approval_timeout_action: self.approval_timeout_action,
+            trust_levels: self.trust_levels.clone(),
+            key_options: self.key_options.clone(),
+            restrict_source: self.restrict_source,
+            host_keys: self.host_keys.clone(),
+            ssh_port: self.ssh_port,
+            access: self.access.clone(),
+            limits: self.limits,
+            own_user: self.own_user.clone(),
+            users: self.users.clone(),
+            lookup_account: self.lookup_account.clone(),
+        }
+    }
+
+    /// A handle that stops [`HandshakeServer::run`] and
+    /// [`HandshakeServer::handle_one`] from another task
+    ///
+    /// Pairings still in progress are dropped, and the port is released
+    /// before the server returns.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Listen on `address` only, instead of every IPv4 address
+    pub fn with_bind_address(mut self, address: IpAddr) -> Self {
+        self.bind_address = address;
+        self
+    }
+
     /// Start listening on the specified port
     pub async fn listen(&mut self, port: u16) -> Result<SocketAddr> {
-        let addr = format!("0.0.0.0:{}", port);
-        let listener = TcpListener::bind(&addr)
+        let addr = SocketAddr::new(self.bind_address, port);
+        let listener = TcpListener::bind(addr)
             .await
-            .map_err(|e| ConnectoError::Network(format!("Failed to bind: {}", e)))?;
+            .map_err(|e| ports::bind_error(port, e))?;
 
         let local_addr = listener.local_addr()?;
         info!("Handshake server listening on {}", local_addr);
@@ -143,7 +968,7 @@ impl HandshakeServer {
         Ok(local_addr)
     }
 
-    /// Accept and handle incoming connections
+    /// Accept and handle incoming connections until shut down
     pub async fn run(&mut self, event_tx: mpsc::Sender<ServerEvent>) -> Result<()> {
         let listener = self
             .listener
@@ -153,31 +978,55 @@ impl HandshakeServer {
         let addr = listener.local_addr()?;
         let _ = event_tx.send(ServerEvent::Started { address: addr }).await;
 
+        let mut rate = RateLimiter::new(self.limits.connections_per_minute);
+        let handshakes = Arc::new(Semaphore::new(self.limits.max_handshakes));
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.shutdown.requested() => {
+                    info!("Handshake server on {} shut down", addr);
+                    return Ok(());
+                }
+            };
+            match accepted {
                 Ok((stream, peer_addr)) => {
+                    if !rate.allow(peer_addr.ip()) {
+                        refuse_connection(
+                            stream,
+                            peer_addr,
+                            "Too many connections from your address; try again in a minute",
+                        );
+                        continue;
+                    }
+                    let Ok(permit) = Arc::clone(&handshakes).try_acquire_owned() else {
+                        refuse_connection(
+                            stream,
+                            peer_addr,
+                            "Busy with other pairing requests; try again later",
+                        );
+                        continue;
+                    };
                     info!("Client connected from {}", peer_addr);
                     let _ = event_tx
                         .send(ServerEvent::ClientConnected { address: peer_addr })
                         .await;
 
                     let key_manager = Arc::clone(&self.key_manager);
-                    let device_name = self.device_name.clone();
-                    let require_verification = self.require_verification;
+                    let settings = self.client_settings();
                     let event_tx = event_tx.clone();
+                    let shutdown = self.shutdown.clone();
 
                     tokio::spawn(async move {
-                        if let Err(e) = handle_client(
-                            stream,
-                            peer_addr,
-                            key_manager,
-                            device_name,
-                            require_verification,
-                            event_tx,
-                        )
-                        .await
-                        {
-                            error!("Error handling client {}: {}", peer_addr, e);
+                        let _permit = permit;
+                        tokio::select! {
+                            result = handle_client(stream, peer_addr, key_manager, settings, event_tx) => {
+                                if let Err(e) = result {
+                                    error!("Error handling client {}: {}", peer_addr, e);
+                                }
+                            }
+                            _ = shutdown.requested() => {
+                                debug!("Dropped pairing with {} on shutdown", peer_addr);
+                            }
                         }
                     });
                 }
@@ -189,7 +1038,8 @@ impl HandshakeServer {
     }
 
     /// Handle a single pairing request (useful for one-shot mode)
-    /// Keeps accepting connections until one successfully completes pairing.
+    /// Keeps accepting connections until one successfully completes pairing,
+    /// or the server is shut down.
     /// This prevents incomplete handshakes (like from scanners) from consuming the session.
     pub async fn handle_one(&mut self, event_tx: mpsc::Sender<ServerEvent>) -> Result<()> {
         let listener = self
@@ -200,84 +1050,506 @@ impl HandshakeServer {
         let addr = listener.local_addr()?;
         let _ = event_tx.send(ServerEvent::Started { address: addr }).await;
 
-        loop {
-            let (stream, peer_addr) = listener.accept().await?;
-            info!("Client connected from {}", peer_addr);
-            let _ = event_tx
-                .send(ServerEvent::ClientConnected { address: peer_addr })
-                .await;
-
-            match handle_client(
-                stream,
-                peer_addr,
-                Arc::clone(&self.key_manager),
-                self.device_name.clone(),
-                self.require_verification,
-                event_tx.clone(),
-            )
-            .await
-            {
-                Ok(()) => {
-                    // Successful pairing, exit the loop
-                    return Ok(());
-                }
-                Err(e) => {
-                    // Failed handshake (e.g., scanner probe, incomplete connection)
-                    // This is expected behavior - scanners probe to identify devices
-                    // Log at debug level and continue waiting for real pairing requests
-                    debug!(
-                        "Incomplete handshake from {} (likely scanner probe): {}",
-                        peer_addr, e
+        let mut rate = RateLimiter::new(self.limits.connections_per_minute);
+        let pair_one = async {
+            loop {
+                let (stream, peer_addr) = listener.accept().await?;
+                if !rate.allow(peer_addr.ip()) {
+                    refuse_connection(
+                        stream,
+                        peer_addr,
+                        "Too many connections from your address; try again in a minute",
                     );
+                    continue;
+                }
+                info!("Client connected from {}", peer_addr);
+                let _ = event_tx
+                    .send(ServerEvent::ClientConnected { address: peer_addr })
+                    .await;
+
+                match handle_client(
+                    stream,
+                    peer_addr,
+                    Arc::clone(&self.key_manager),
+                    self.client_settings(),
+                    event_tx.clone(),
+                )
+                .await
+                {
+                    Ok(Handled::Paired) => {
+                        // Successful pairing, exit the loop
+                        return Ok(());
+                    }
+                    // A revocation is not the pairing we wait for
+                    Ok(Handled::Revoked) => {}
+                    Err(e) => {
+                        // Failed handshake (e.g., scanner probe, incomplete connection)
+                        // This is expected behavior - scanners probe to identify devices
+                        // Log at debug level and continue waiting for real pairing requests
+                        debug!(
+                            "Incomplete handshake from {} (likely scanner probe): {}",
+                            peer_addr, e
+                        );
+                    }
                 }
             }
+        };
+
+        tokio::select! {
+            result = pair_one => result,
+            _ = self.shutdown.requested() => {
+                info!("Handshake server on {} shut down", addr);
+                Ok(())
+            }
         }
     }
+
+    /// Handle a pairing request arriving on an already open stream, such as a
+    /// relay channel, from the device at `peer_addr`
+    ///
+    /// Does not need [`HandshakeServer::listen`].
+    pub async fn handle_stream(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        peer_addr: SocketAddr,
+        event_tx: mpsc::Sender<ServerEvent>,
+    ) -> Result<()> {
+        info!("Client connected from {}", peer_addr);
+        let _ = event_tx
+            .send(ServerEvent::ClientConnected { address: peer_addr })
+            .await;
+        handle_client(
+            stream,
+            peer_addr,
+            Arc::clone(&self.key_manager),
+            self.client_settings(),
+            event_tx,
+        )
+        .await
+        .map(|_| ())
+    }
 }
 
-async fn handle_client(
-    stream: TcpStream,
-    peer_addr: SocketAddr,
-    key_manager: Arc<KeyManager>,
+/// The newest version in both ranges, or `None` if they do not overlap
+pub fn negotiate_version(ours: RangeInclusive<u32>, theirs: RangeInclusive<u32>) -> Option<u32> {
+    let version = (*ours.end()).min(*theirs.end());
+    (version >= (*ours.start()).max(*theirs.start())).then_some(version)
+}
+
+/// How the messages after `Hello` and `HelloAck` are framed in `version`
+///
+/// `Hello`, `HelloAck` and errors answering `Hello` are always lines, since
+/// they are sent before the version is agreed.
+pub fn framing_for(version: u32) -> Framing {
+    if version >= FRAMED_VERSION {
+        Framing::LengthPrefixed
+    } else {
+        Framing::Lines
+    }
+}
+
+/// Turn away a client over the connection limits
+///
+/// The error is written to the non-blocking socket without waiting, so a
+/// flood of connections cannot hold up the accept loop.
+fn refuse_connection(stream: TcpStream, peer_addr: SocketAddr, message: &str) {
+    use std::io::{Read, Write};
+
+    debug!("Refused connection from {}: {}", peer_addr, message);
+    let error_msg = Message::Error {
+        code: ProtocolErrorCode::RateLimited,
+        message: message.to_string(),
+    };
+    let (Ok(json), Ok(mut stream)) = (error_msg.to_json(), stream.into_std()) else {
+        return;
+    };
+    let _ = stream.write_all(json.as_bytes());
+    // Closing with unread data resets the connection, which can discard the
+    // error before the client reads it
+    let _ = stream.read(&mut [0; 1024]);
+}
+
+/// Read the client's next message within the step timeout
+async fn read_step(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    framing: Framing,
+    line: &mut String,
+    limits: &HandshakeLimits,
+) -> std::result::Result<std::io::Result<usize>, tokio::time::error::Elapsed> {
+    tokio::time::timeout(
+        limits.step_timeout,
+        framing.read(reader, line, limits.max_message_len),
+    )
+    .await
+}
+
+/// Per-connection copy of the server's settings
+struct ClientSettings {
     device_name: String,
     require_verification: bool,
-    event_tx: mpsc::Sender<ServerEvent>,
-) -> Result<()> {
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+    pin_timeout: Duration,
+    require_key_proof: bool,
+    require_encryption: bool,
+    identity: Option<DeviceIdentity>,
+    private: bool,
+    pairings: Option<PairingStore>,
+    decisions: Option<DecisionLog>,
+    /// The key manager's audit log, if it has one
+    audit: Option<AuditLog>,
+    approval_tx: Option<mpsc::Sender<ApprovalRequest>>,
+    approval_timeout: Duration,
+    approval_timeout_action: ApprovalTimeoutAction,
+    trust_levels: Option<TrustStore>,
+    key_options: KeyOptions,
+    restrict_source: bool,
+    host_keys: Vec<String>,
+    ssh_port: u16,
+    access: AccessList,
+    limits: HandshakeLimits,
+    /// The account keys are installed for unless the client chooses another
+    own_user: String,
+    /// Accounts clients may choose besides ours
+    users: Vec<String>,
+    lookup_account: AccountLookup,
+}
 
-    // Read Hello message
-    line.clear();
-    reader.read_line(&mut line).await?;
-    let hello = Message::from_json(&line)?;
+impl ClientSettings {
+    /// Append a decision to the decision log, and the pairing it settles to
+    /// the audit log, if they are configured
+    fn record_decision(&self, record: DecisionRecord) {
+        if let Some(log) = &self.audit {
+            if let Err(e) = log.append(AuditEvent::from_decision(&record)) {
+                warn!("Failed to record pairing in the audit log: {}", e);
+            }
+        }
+        if let Some(log) = &self.decisions {
+            if let Err(e) = log.append(record) {
+                warn!("Failed to record pairing decision: {}", e);
+            }
+        }
+    }
 
-    let client_name = match hello {
-        Message::Hello {
-            version,
-            device_name: client_name,
+    /// The trust level of `client_name` having proved `identity`, if trust
+    /// levels are in use
+    fn trust_level(
+        &self,
+        client_name: &str,
+        identity: Option<&VerifiedIdentity>,
+    ) -> Option<TrustLevel> {
+        let store = self.trust_levels.as_ref()?;
+        Some(store.level(client_name, identity).unwrap_or_else(|e| {
+            warn!("Failed to read the trust level of {}: {}", client_name, e);
+            TrustLevel::Unknown
+        }))
+    }
+
+    /// The trust level `client_name` will have once it proves `announced`,
+    /// if trust levels are in use
+    ///
+    /// Only decides what to ask of the client in `HelloAck`; the level that
+    /// counts is [`trust_level`](Self::trust_level) once it proved an
+    /// identity.
+    fn claimed_trust_level(
+        &self,
+        client_name: &str,
+        announced: Option<&str>,
+    ) -> Option<TrustLevel> {
+        let store = self.trust_levels.as_ref()?;
+        Some(match store.get(client_name) {
+            Ok(peer) => peer.map_or(TrustLevel::Unknown, |peer| peer.level_for(announced)),
+            Err(e) => {
+                warn!("Failed to read the trust level of {}: {}", client_name, e);
+                TrustLevel::Unknown
+            }
+        })
+    }
+
+    /// Pin the identity a client proved on its first pairing, so its trust
+    /// level holds for it from then on
+    fn pin_client(&self, client_name: &str, identity: &VerifiedIdentity) {
+        let Some(store) = &self.trust_levels else {
+            return;
+        };
+        let pinned = match store.check(client_name, Some(identity)) {
+            Ok(TrustCheck::Changed { .. }) => return,
+            Ok(_) => store.pin(client_name, identity),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = pinned {
+            warn!("Failed to pin the identity of {}: {}", client_name, e);
+        }
+    }
+
+    /// The accounts offered to clients, ours first, or none if clients
+    /// cannot choose
+    fn offered_users(&self) -> Vec<String> {
+        if self.users.is_empty() {
+            return Vec::new();
+        }
+        let mut users = vec![self.own_user.clone()];
+        for user in &self.users {
+            if !users.contains(user) {
+                users.push(user.clone());
+            }
+        }
+        users
+    }
+
+    /// The account a client asked to install its key into, or `None` for
+    /// ours
+    fn account(&self, user: Option<&str>) -> std::result::Result<Option<Account>, String> {
+        let Some(user) = user.filter(|user| *user != self.own_user) else {
+            return Ok(None);
+        };
+        if !self.users.iter().any(|u| u == user) {
+            return Err(format!(
+                "{} does not offer the account {}",
+                self.device_name, user
+            ));
+        }
+        (self.lookup_account)(user)
+            .map(Some)
+            .map_err(|e| format!("{} has no account {}: {}", self.device_name, user, e))
+    }
+
+    /// The authorized key with `fingerprint` in the other accounts clients
+    /// may choose, with the keys of the account it is in
+    ///
+    /// An account that cannot be found or read is warned about and skipped,
+    /// so it does not keep a key from being revoked elsewhere.
+    fn find_in_other_accounts(
+        &self,
+        key_manager: &KeyManager,
+        fingerprint: &str,
+    ) -> Option<(KeyManager, String)> {
+        self.users
+            .iter()
+            .filter(|user| **user != self.own_user)
+            .find_map(|user| {
+                let keys = match (self.lookup_account)(user) {
+                    Ok(account) => account_key_manager(key_manager, &account),
+                    Err(e) => {
+                        warn!("Failed to find the account {}: {}", user, e);
+                        return None;
+                    }
+                };
+                match keys.find_authorized_key(fingerprint) {
+                    Ok(public_key) => public_key.map(|public_key| (keys, public_key)),
+                    Err(e) => {
+                        warn!("Failed to read the authorized keys of {}: {}", user, e);
+                        None
+                    }
+                }
+            })
+    }
+}
+
+/// The keys of `account`, recorded in the same audit log as `key_manager`
+fn account_key_manager(key_manager: &KeyManager, account: &Account) -> KeyManager {
+    let keys = KeyManager::for_account(account);
+    match key_manager.audit_log() {
+        Some(log) => keys.with_audit_log(log.clone()),
+        None => keys,
+    }
+}
+
+/// What a client's connection ended with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handled {
+    Paired,
+    Revoked,
+}
+
+/// Serve one client, reporting a failure as [`ServerEvent::PairingFailed`]
+async fn handle_client(
+    stream: impl AsyncRead + AsyncWrite,
+    peer_addr: SocketAddr,
+    key_manager: Arc<KeyManager>,
+    settings: ClientSettings,
+    event_tx: mpsc::Sender<ServerEvent>,
+) -> Result<Handled> {
+    let result = serve_client(stream, peer_addr, key_manager, settings, event_tx.clone()).await;
+    if let Err(e) = &result {
+        let _ = event_tx
+            .send(ServerEvent::PairingFailed {
+                address: peer_addr,
+                reason: e.to_string(),
+            })
+            .await;
+    }
+    result
+}
+
+async fn serve_client(
+    stream: impl AsyncRead + AsyncWrite,
+    peer_addr: SocketAddr,
+    key_manager: Arc<KeyManager>,
+    settings: ClientSettings,
+    event_tx: mpsc::Sender<ServerEvent>,
+) -> Result<Handled> {
+    let device_name = settings.device_name.clone();
+    // A private server keeps its identity to itself until pairing succeeds
+    let identity = if settings.private {
+        None
+    } else {
+        settings.identity.as_ref()
+    };
+    let require_key_proof = settings.require_key_proof;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    // Read Hello message
+    line.clear();
+    read_step(&mut reader, Framing::Lines, &mut line, &settings.limits)
+        .await
+        .map_err(|_| ConnectoError::Timeout("Client sent no Hello".to_string()))??;
+    let hello = Message::from_json(&line)?;
+
+    let (
+        client_name,
+        client_identity,
+        client_nonce,
+        client_share,
+        version,
+        capabilities,
+        clock_skew,
+        require_verification,
+    ) = match hello {
+        Message::Hello {
+            version: client_max,
+            device_name: client_name,
+            timestamp,
+            min_version: client_min,
+            capabilities: client_capabilities,
+            identity: client_identity,
+            nonce: client_nonce,
+            key_share: client_share,
         } => {
-            if version != PROTOCOL_VERSION {
+            if let Some(reason) = settings.access.refusal(peer_addr.ip(), &client_name) {
+                info!("Refused {} ({}): {}", client_name, peer_addr, reason);
                 let error_msg = Message::Error {
-                    code: 1,
-                    message: format!(
-                        "Protocol version mismatch: expected {}, got {}",
-                        PROTOCOL_VERSION, version
-                    ),
+                    code: ProtocolErrorCode::AccessDenied,
+                    message: format!("{} does not accept pairing requests from you", device_name),
                 };
-                writer.write_all(error_msg.to_json()?.as_bytes()).await?;
-                return Err(ConnectoError::Handshake(
-                    "Protocol version mismatch".to_string(),
-                ));
+                Framing::Lines.write(&mut writer, &error_msg).await?;
+                let _ = event_tx
+                    .send(ServerEvent::AccessDenied {
+                        device_name: client_name.clone(),
+                        address: peer_addr,
+                        reason: reason.clone(),
+                    })
+                    .await;
+                return Err(ConnectoError::PermissionDenied(format!(
+                    "Refused {}: {}",
+                    client_name, reason
+                )));
             }
-            client_name
+            // Clients too old to prove an identity are never trusted by it
+            let announced = client_identity
+                .as_deref()
+                .filter(|_| client_max >= IDENTITY_PROOF_VERSION && client_nonce.is_some());
+            let claimed_level = settings.claimed_trust_level(&client_name, announced);
+            let require_verification = settings.require_verification
+                || claimed_level.is_some_and(TrustLevel::requires_code);
+            // Older clients cannot encrypt the channel or enter a
+            // verification code
+            let min_version = if settings.require_encryption {
+                ENCRYPTED_VERSION
+            } else if require_verification {
+                PIN_VERSION
+            } else {
+                MIN_PROTOCOL_VERSION
+            };
+            // Clients that do not say how old a version they speak take any,
+            // and those that send no key share cannot encrypt
+            let client_min = client_min.unwrap_or(MIN_PROTOCOL_VERSION);
+            let usable_max = if client_share.is_some() {
+                client_max
+            } else {
+                client_max.min(ENCRYPTED_VERSION - 1)
+            };
+            let client_capabilities =
+                client_capabilities.unwrap_or_else(|| Capabilities::implied_by(client_max));
+            let negotiated =
+                match negotiate_version(min_version..=PROTOCOL_VERSION, client_min..=usable_max) {
+                    None if settings.require_encryption && usable_max < ENCRYPTED_VERSION => {
+                        Err(format!(
+                            "{} only pairs over an encrypted channel, which {} cannot set up",
+                            device_name, client_name
+                        ))
+                    }
+                    None => Err(format!(
+                        "Protocol version mismatch: {} speaks versions {}-{}, {} speaks {}-{}",
+                        device_name,
+                        min_version,
+                        PROTOCOL_VERSION,
+                        client_name,
+                        client_min,
+                        client_max
+                    )),
+                    Some(_)
+                        if require_verification
+                            && !client_capabilities.contains(Capabilities::VERIFICATION) =>
+                    {
+                        Err(format!(
+                            "{} requires a verification code, which {} cannot enter",
+                            device_name, client_name
+                        ))
+                    }
+                    Some(version) => Ok(version),
+                };
+            let version = match negotiated {
+                Ok(version) => version,
+                Err(message) => {
+                    let error_msg = Message::Error {
+                        code: ProtocolErrorCode::VersionMismatch,
+                        message: message.clone(),
+                    };
+                    Framing::Lines.write(&mut writer, &error_msg).await?;
+                    return Err(ConnectoError::Handshake(message));
+                }
+            };
+            let now = clock::unix_now();
+            let skew = timestamp.map(|t| clock::skew(t, now, now));
+            (
+                client_name,
+                client_identity,
+                client_nonce,
+                client_share,
+                version,
+                client_capabilities & Capabilities::SUPPORTED,
+                skew,
+                require_verification,
+            )
+        }
+        Message::Revoke {
+            version,
+            device_name: client_name,
+            fingerprint,
+        } => {
+            let request = RevokeRequest {
+                client_name,
+                version,
+                fingerprint,
+            };
+            return handle_revoke(
+                &mut reader,
+                &mut writer,
+                peer_addr,
+                &key_manager,
+                &settings,
+                &event_tx,
+                request,
+            )
+            .await;
         }
         _ => {
             let error_msg = Message::Error {
-                code: 2,
+                code: ProtocolErrorCode::UnexpectedMessage,
                 message: "Expected Hello message".to_string(),
             };
-            writer.write_all(error_msg.to_json()?.as_bytes()).await?;
+            Framing::Lines.write(&mut writer, &error_msg).await?;
             return Err(ConnectoError::Handshake("Expected Hello".to_string()));
         }
     };
@@ -288,6 +1560,15 @@ async fn handle_client(
             address: peer_addr,
         })
         .await;
+    clock::warn_if_large(&client_name, clock_skew);
+    if let Some(skew) = clock_skew.filter(|s| clock::is_large(*s)) {
+        let _ = event_tx
+            .send(ServerEvent::ClockSkew {
+                device_name: client_name.clone(),
+                skew_secs: skew,
+            })
+            .await;
+    }
 
     // Generate verification code if required
     let verification_code = if require_verification {
@@ -296,18 +1577,133 @@ async fn handle_client(
         None
     };
 
-    // Send HelloAck
+    // Agree on the keys of the channel everything after HelloAck runs in
+    let server_share = client_share
+        .filter(|_| version >= ENCRYPTED_VERSION)
+        .map(|client_share| (KeyShare::generate(), client_share));
+    let key_share = server_share.as_ref().map(|(share, _)| share.public());
+    let channel_keys = match server_share {
+        Some((share, client_share)) => match share.agree(Side::Server, &client_share) {
+            Ok(keys) => Some(keys),
+            Err(e) => {
+                let error_msg = Message::Error {
+                    code: ProtocolErrorCode::VerificationFailed,
+                    message: e.to_string(),
+                };
+                Framing::Lines.write(&mut writer, &error_msg).await?;
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+    let binding = channel_keys.as_ref().map(|keys| keys.binding().to_string());
+    let capabilities = if channel_keys.is_some() {
+        capabilities
+    } else {
+        capabilities.without(Capabilities::ENCRYPTION)
+    };
+
+    // Send HelloAck, proving our identity and challenging the client to
+    // prove its own; the code itself is only shown to our user
+    let proves_identity = version >= IDENTITY_PROOF_VERSION;
+    let identity_proof = match (identity, &client_nonce) {
+        (Some(identity), Some(nonce)) if proves_identity => {
+            Some(identity.prove(&identity_challenge(nonce, binding.as_deref()))?)
+        }
+        _ => None,
+    };
+    let identity_nonce = proves_identity.then(generate_nonce);
     let hello_ack = Message::HelloAck {
-        version: PROTOCOL_VERSION,
+        version,
         device_name: device_name.clone(),
-        verification_code: verification_code.clone(),
+        verification_code: None,
+        identity: identity.map(|identity| identity.fingerprint().to_string()),
+        pin_required: verification_code.is_some(),
+        timestamp: Some(clock::unix_now()),
+        capabilities: Some(capabilities),
+        users: settings.offered_users(),
+        identity_proof,
+        nonce: identity_nonce.clone(),
+        key_share,
+    };
+    Framing::Lines.write(&mut writer, &hello_ack).await?;
+    let framing = framing_for(version);
+    let (reader, mut writer) = channel::wrap(reader, writer, channel_keys);
+    let mut reader = BufReader::new(reader);
+    debug!(
+        "Speaking protocol version {} with {} (capabilities: {})",
+        version, client_name, capabilities
+    );
+
+    // An identity only counts once the client proves it
+    let client_identity = match (client_identity, &identity_nonce) {
+        (Some(announced), Some(nonce)) => Some(
+            receive_identity_proof(
+                &mut reader,
+                &mut writer,
+                framing,
+                &identity_challenge(nonce, binding.as_deref()),
+                &announced,
+                &settings.limits,
+            )
+            .await?,
+        ),
+        (Some(announced), None) => {
+            debug!(
+                "{} announced identity {} but cannot prove it",
+                client_name, announced
+            );
+            None
+        }
+        (None, _) => None,
     };
-    writer.write_all(hello_ack.to_json()?.as_bytes()).await?;
+
+    // Trust levels only hold for the identity the client proved
+    let trust_level = settings.trust_level(&client_name, client_identity.as_ref());
+    if trust_level.is_some_and(TrustLevel::requires_code) && verification_code.is_none() {
+        let message = format!(
+            "{} did not prove the identity that is trusted on {}",
+            client_name, device_name
+        );
+        let error_msg = Message::Error {
+            code: ProtocolErrorCode::AccessDenied,
+            message: message.clone(),
+        };
+        framing.write(&mut writer, &error_msg).await?;
+        return Err(ConnectoError::PermissionDenied(message));
+    }
+
+    // Take no key until the client has entered the code
+    if let Some(code) = &verification_code {
+        let _ = event_tx
+            .send(ServerEvent::VerificationCode {
+                device_name: client_name.clone(),
+                code: code.clone(),
+            })
+            .await;
+        if let Err(e) = verify_pin(
+            &mut reader,
+            &mut writer,
+            framing,
+            code,
+            binding.as_deref(),
+            settings.pin_timeout,
+            settings.limits.max_message_len,
+        )
+        .await
+        {
+            let _ = event_tx
+                .send(ServerEvent::Error {
+                    message: format!("Rejected {}: {}", client_name, e),
+                })
+                .await;
+            return Err(e);
+        }
+    }
 
     // Read KeyExchange with timeout (handles scanner probes that disconnect after HelloAck)
     line.clear();
-    let read_result =
-        tokio::time::timeout(Duration::from_secs(30), reader.read_line(&mut line)).await;
+    let read_result = read_step(&mut reader, framing, &mut line, &settings.limits).await;
 
     match read_result {
         Ok(Ok(0)) | Err(_) => {
@@ -333,32 +1729,309 @@ async fn handle_client(
         Message::KeyExchange {
             public_key,
             comment,
+            expires_in,
+            fingerprint: claimed_fingerprint,
+            user,
         } => {
             debug!("Received public key with comment: {}", comment);
 
+            let peer_ip = peer_addr.ip().to_string();
+            let decision =
+                |decision| DecisionRecord::new(decision, &client_name, &peer_ip, &public_key);
+
+            let fingerprint = SshKeyPair::public_key_fingerprint(&public_key)?;
+            if claimed_fingerprint.is_some_and(|claimed| claimed != fingerprint) {
+                let error_msg = Message::Error {
+                    code: ProtocolErrorCode::VerificationFailed,
+                    message: "Key fingerprint does not match the key sent".to_string(),
+                };
+                framing.write(&mut writer, &error_msg).await?;
+                settings.record_decision(
+                    decision(Decision::Rejected).with_reason("Key fingerprint mismatch"),
+                );
+                return Err(ConnectoError::Handshake(format!(
+                    "Key from {} does not match its fingerprint",
+                    client_name
+                )));
+            }
+
+            let account = match settings.account(user.as_deref()) {
+                Ok(account) => account,
+                Err(message) => {
+                    let error_msg = Message::Error {
+                        code: ProtocolErrorCode::AccessDenied,
+                        message: message.clone(),
+                    };
+                    framing.write(&mut writer, &error_msg).await?;
+                    settings.record_decision(decision(Decision::Rejected).with_reason(&message));
+                    return Err(ConnectoError::PermissionDenied(message));
+                }
+            };
+
             let _ = event_tx
                 .send(ServerEvent::KeyReceived {
+                    device_name: client_name.clone(),
                     comment: comment.clone(),
+                    fingerprint: fingerprint.clone(),
                 })
                 .await;
 
-            // Add the key to authorized_keys
-            key_manager.add_authorized_key(&public_key)?;
+            if version >= KEY_PROOF_VERSION {
+                if let Err(e) = verify_key_proof(
+                    &mut reader,
+                    &mut writer,
+                    framing,
+                    &public_key,
+                    KEY_PROOF_NAMESPACE,
+                    &settings.limits,
+                )
+                .await
+                {
+                    settings.record_decision(
+                        decision(Decision::Rejected).with_reason("Key proof verification failed"),
+                    );
+                    let _ = event_tx
+                        .send(ServerEvent::Error {
+                            message: format!("Rejected key from {}: {}", client_name, e),
+                        })
+                        .await;
+                    return Err(e);
+                }
+            } else if require_key_proof {
+                let error_msg = Message::Error {
+                    code: ProtocolErrorCode::VersionMismatch,
+                    message: format!(
+                        "Protocol version {} cannot prove key possession; upgrade to version {} or newer",
+                        version, KEY_PROOF_VERSION
+                    ),
+                };
+                framing.write(&mut writer, &error_msg).await?;
+                settings.record_decision(
+                    decision(Decision::Rejected).with_reason("Client cannot prove key possession"),
+                );
+                return Err(ConnectoError::Handshake(
+                    "Client does not support key proof".to_string(),
+                ));
+            } else {
+                warn!(
+                    "Client {} uses protocol version {}; accepting key without proof of possession",
+                    client_name, version
+                );
+            }
+
+            let mut approver = None;
+            let mut accepted_reason = None;
+            let approval_tx = settings.approval_tx.as_ref();
+            if let (Some(level), Some(_)) = (trust_level, approval_tx) {
+                if !level.requires_approval() {
+                    accepted_reason = Some(format!("Approval skipped for {} device", level));
+                }
+            }
+            let approval_tx =
+                approval_tx.filter(|_| trust_level.is_none_or(TrustLevel::requires_approval));
+            if let Some(approval_tx) = approval_tx {
+                let (request, response) =
+                    ApprovalRequest::new(&client_name, peer_addr, &fingerprint, &comment);
+                let answer = request_approval(
+                    approval_tx,
+                    request,
+                    response,
+                    settings.approval_timeout,
+                    &mut writer,
+                    framing,
+                    version >= APPROVAL_PENDING_VERSION,
+                )
+                .await?;
+
+                let (approved, reason, message) = match answer {
+                    Some(approved) => {
+                        // Whoever answers the approval prompt is the local user
+                        approver = Some(current_user());
+                        (
+                            approved,
+                            "Rejected by user".to_string(),
+                            format!("Pairing rejected by {}", device_name),
+                        )
+                    }
+                    None => {
+                        let verified = version >= KEY_PROOF_VERSION;
+                        let approved = verified
+                            && settings.approval_timeout_action
+                                == ApprovalTimeoutAction::AcceptIfVerified;
+                        let _ = event_tx
+                            .send(ServerEvent::ApprovalTimedOut {
+                                device_name: client_name.clone(),
+                                accepted: approved,
+                            })
+                            .await;
+                        let reason = format!(
+                            "No answer within {}s; {}",
+                            settings.approval_timeout.as_secs(),
+                            if approved {
+                                "accepted verified key by default"
+                            } else {
+                                "rejected by default"
+                            }
+                        );
+                        if approved {
+                            accepted_reason = Some(reason.clone());
+                        }
+                        (
+                            approved,
+                            reason,
+                            format!(
+                                "Pairing request to {} was not answered in time",
+                                device_name
+                            ),
+                        )
+                    }
+                };
+
+                if !approved {
+                    settings.record_decision(
+                        decision(Decision::Rejected)
+                            .with_approver(approver.as_deref())
+                            .with_reason(&reason),
+                    );
+                    let error_msg = Message::Error {
+                        code: ProtocolErrorCode::Rejected,
+                        message,
+                    };
+                    framing.write(&mut writer, &error_msg).await?;
+                    let _ = event_tx
+                        .send(ServerEvent::PairingRejected {
+                            device_name: client_name,
+                        })
+                        .await;
+                    return Err(ConnectoError::Handshake("Pairing rejected".to_string()));
+                }
+            }
+
+            // Add the key to the chosen account's authorized_keys
+            let account_keys = account
+                .as_ref()
+                .map(|account| account_key_manager(&key_manager, account));
+            let target_keys = account_keys.as_ref().unwrap_or(key_manager.as_ref());
+            let ssh_user =
+                account.map_or_else(|| settings.own_user.clone(), |account| account.name);
+            let expires_at = expires_in.map(|secs| clock::unix_now().max(0) as u64 + secs);
+            let mut options = settings.key_options.clone();
+            if settings.restrict_source {
+                options = options.with_from(&peer_addr.ip().to_canonical().to_string());
+            }
+            let merged =
+                match target_keys.add_authorized_key_with(&public_key, &options, expires_at) {
+                    Ok(merged) => merged,
+                    Err(e) if account_keys.is_some() => {
+                        let message = format!(
+                            "{} could not install the key for {}: {}",
+                            device_name, ssh_user, e
+                        );
+                        let error_msg = Message::Error {
+                            code: ProtocolErrorCode::AccessDenied,
+                            message: message.clone(),
+                        };
+                        framing.write(&mut writer, &error_msg).await?;
+                        settings
+                            .record_decision(decision(Decision::Rejected).with_reason(&message));
+                        return Err(ConnectoError::PermissionDenied(message));
+                    }
+                    Err(e) => {
+                        let error_msg = Message::Error {
+                            code: ProtocolErrorCode::InternalError,
+                            message: format!("{} could not install the key", device_name),
+                        };
+                        framing.write(&mut writer, &error_msg).await?;
+                        return Err(e);
+                    }
+                };
+            let mut accepted = decision(Decision::Accepted).with_approver(approver.as_deref());
+            if let Some(reason) = &accepted_reason {
+                accepted = accepted.with_reason(reason);
+            }
+            settings.record_decision(accepted);
 
             // Send KeyAccepted
             let accepted = Message::KeyAccepted {
-                message: "Key added to authorized_keys".to_string(),
+                message: match merged {
+                    Merge::Added => "Key added to authorized_keys",
+                    Merge::Replaced => "Key already authorized; its entry was updated",
+                    Merge::Unchanged => "Key already authorized",
+                }
+                .to_string(),
+                fingerprint: Some(fingerprint),
             };
-            writer.write_all(accepted.to_json()?.as_bytes()).await?;
+            framing.write(&mut writer, &accepted).await?;
 
-            // Get current user (USER on Unix, USERNAME on Windows)
-            let ssh_user = std::env::var("USER")
-                .or_else(|_| std::env::var("USERNAME"))
-                .unwrap_or_else(|_| "unknown".to_string());
+            if version >= HOST_KEYS_VERSION {
+                let host_keys = Message::HostKeys {
+                    keys: settings.host_keys.clone(),
+                };
+                framing.write(&mut writer, &host_keys).await?;
+            }
 
             // Send PairingComplete
-            let complete = Message::PairingComplete { ssh_user };
-            writer.write_all(complete.to_json()?.as_bytes()).await?;
+            let complete = if settings.private {
+                let identity_proof = match (&settings.identity, &client_nonce) {
+                    (Some(identity), Some(nonce)) if proves_identity => {
+                        Some(identity.prove(&identity_challenge(nonce, binding.as_deref()))?)
+                    }
+                    _ => None,
+                };
+                Message::PairingComplete {
+                    ssh_user,
+                    hostname: Some(get_hostname()),
+                    identity: settings
+                        .identity
+                        .as_ref()
+                        .map(|identity| identity.fingerprint().to_string()),
+                    identity_proof,
+                    ssh_port: Some(settings.ssh_port),
+                }
+            } else {
+                Message::PairingComplete {
+                    ssh_user,
+                    hostname: None,
+                    identity: None,
+                    identity_proof: None,
+                    ssh_port: Some(settings.ssh_port),
+                }
+            };
+            framing.write(&mut writer, &complete).await?;
+
+            if let Some(identity) = &client_identity {
+                settings.pin_client(&client_name, identity);
+            }
+            if let Some(store) = &settings.pairings {
+                // Earlier pairings with the device follow its new name
+                if let Some(identity) = &client_identity {
+                    match store.rename_peer(identity.fingerprint(), &client_name) {
+                        Ok(Some(old_name)) => {
+                            info!("{} was paired before as {}", client_name, old_name)
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!("Failed to rename pairings with {}: {}", client_name, e),
+                    }
+                }
+                let recorded = PairingRecord::new(
+                    &client_name,
+                    &public_key,
+                    &peer_addr.ip().to_string(),
+                    PairingDirection::Incoming,
+                )
+                .map(|r| {
+                    r.with_key_path(&target_keys.authorized_keys_path().to_string_lossy())
+                        .with_peer_identity(
+                            client_identity.as_ref().map(VerifiedIdentity::fingerprint),
+                        )
+                        .with_clock_skew(clock_skew)
+                        .with_expires_at(expires_at)
+                })
+                .and_then(|r| store.record(r));
+                if let Err(e) = recorded {
+                    warn!("Failed to record pairing with {}: {}", client_name, e);
+                }
+            }
 
             let _ = event_tx
                 .send(ServerEvent::PairingComplete {
@@ -366,356 +2039,4175 @@ async fn handle_client(
                 })
                 .await;
 
-            Ok(())
+            Ok(Handled::Paired)
         }
         _ => {
             let error_msg = Message::Error {
-                code: 3,
+                code: ProtocolErrorCode::UnexpectedMessage,
                 message: "Expected KeyExchange message".to_string(),
             };
-            writer.write_all(error_msg.to_json()?.as_bytes()).await?;
+            framing.write(&mut writer, &error_msg).await?;
             Err(ConnectoError::Handshake("Expected KeyExchange".to_string()))
         }
     }
 }
 
-/// Client for initiating pairing with a server
-pub struct HandshakeClient {
-    device_name: String,
+/// A client's request to revoke one of its keys
+struct RevokeRequest {
+    client_name: String,
+    version: u32,
+    fingerprint: String,
 }
 
-impl HandshakeClient {
-    /// Create a new handshake client
-    pub fn new(device_name: &str) -> Self {
-        Self {
-            device_name: device_name.to_string(),
+/// Remove the key a client asks to revoke, once it signs a challenge with
+/// that key
+///
+/// The key is looked for in our own account, then in every account clients
+/// may install into. No verification code or approval is needed: only the
+/// key's holder can ask, and it can only take access away. The exchange is
+/// in lines, as it starts in place of `Hello`.
+async fn handle_revoke(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    peer_addr: SocketAddr,
+    key_manager: &KeyManager,
+    settings: &ClientSettings,
+    event_tx: &mpsc::Sender<ServerEvent>,
+    request: RevokeRequest,
+) -> Result<Handled> {
+    let RevokeRequest {
+        client_name,
+        version,
+        fingerprint,
+    } = request;
+    let framing = Framing::Lines;
+    if version < REVOKE_VERSION {
+        let message = format!("Revocation version {} is not supported", version);
+        let error_msg = Message::Error {
+            code: ProtocolErrorCode::VersionMismatch,
+            message: message.clone(),
+        };
+        framing.write(writer, &error_msg).await?;
+        return Err(ConnectoError::Handshake(message));
+    }
+
+    // Everyone is challenged and refused alike, so the answer tells nobody
+    // which keys are authorized here or whether they are allowed to connect
+    let (nonce, signature) = read_key_proof(reader, writer, framing, &settings.limits).await?;
+    let refusal = if let Some(reason) = settings.access.refusal(peer_addr.ip(), &client_name) {
+        let _ = event_tx
+            .send(ServerEvent::AccessDenied {
+                device_name: client_name.clone(),
+                address: peer_addr,
+                reason: reason.clone(),
+            })
+            .await;
+        Some(reason)
+    } else {
+        let found = match key_manager.find_authorized_key(&fingerprint)? {
+            Some(public_key) => Some((None, public_key)),
+            None => settings
+                .find_in_other_accounts(key_manager, &fingerprint)
+                .map(|(keys, public_key)| (Some(keys), public_key)),
+        };
+        match found {
+            None => Some(format!("{} is not authorized", fingerprint)),
+            Some((account_keys, public_key)) => match SshKeyPair::verify_signature(
+                &public_key,
+                REVOKE_NAMESPACE,
+                nonce.as_bytes(),
+                &signature,
+            ) {
+                Err(e) => Some(format!("Key proof verification failed: {}", e)),
+                Ok(()) => {
+                    let reason = format!("Revoked by {} ({})", client_name, peer_addr.ip());
+                    account_keys
+                        .as_ref()
+                        .unwrap_or(key_manager)
+                        .revoke_authorized_key(&public_key, &reason)?;
+                    None
+                }
+            },
         }
+    };
+    if let Some(reason) = refusal {
+        let error_msg = Message::Error {
+            code: ProtocolErrorCode::VerificationFailed,
+            message: format!(
+                "{} cannot revoke {} for you",
+                settings.device_name, fingerprint
+            ),
+        };
+        framing.write(writer, &error_msg).await?;
+        return Err(ConnectoError::Handshake(format!(
+            "Refused to revoke {} for {} ({}): {}",
+            fingerprint, client_name, peer_addr, reason
+        )));
     }
 
-    /// Connect to a server and perform key exchange
-    pub async fn pair(&self, address: &str, key_pair: &SshKeyPair) -> Result<PairingResult> {
-        let stream = TcpStream::connect(address)
-            .await
-            .map_err(|e| ConnectoError::Network(format!("Failed to connect: {}", e)))?;
+    info!(
+        "{} ({}) revoked key {}",
+        client_name, peer_addr, fingerprint
+    );
 
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
-        let mut line = String::new();
+    let revoked = Message::KeyRevoked {
+        fingerprint: fingerprint.clone(),
+    };
+    framing.write(writer, &revoked).await?;
+    let _ = event_tx
+        .send(ServerEvent::KeyRevoked {
+            device_name: client_name,
+            address: peer_addr,
+            fingerprint,
+        })
+        .await;
+    Ok(Handled::Revoked)
+}
 
-        // Send Hello
-        let hello = Message::Hello {
-            version: PROTOCOL_VERSION,
-            device_name: self.device_name.clone(),
-        };
-        writer.write_all(hello.to_json()?.as_bytes()).await?;
+/// Current user (USER on Unix, USERNAME on Windows)
+pub(crate) fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
 
-        // Read HelloAck
-        line.clear();
-        reader.read_line(&mut line).await?;
-        let hello_ack = Message::from_json(&line)?;
+/// Ask the approval channel whether to install a client's key
+///
+/// Returns `None` if nobody answered within `timeout`. A dropped request
+/// counts as a rejection. With `notify`, the client is sent
+/// [`Message::ApprovalPending`] every [`APPROVAL_NOTICE_INTERVAL_SECS`]
+/// while it waits.
+async fn request_approval(
+    approval_tx: &mpsc::Sender<ApprovalRequest>,
+    request: ApprovalRequest,
+    mut decision: oneshot::Receiver<bool>,
+    timeout: Duration,
+    writer: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+    notify: bool,
+) -> Result<Option<bool>> {
+    let deadline = Instant::now() + timeout;
+    if approval_tx.send(request).await.is_err() {
+        return Ok(Some(false));
+    }
 
-        let (server_name, verification_code) = match hello_ack {
-            Message::HelloAck {
-                version,
-                device_name,
-                verification_code,
-            } => {
-                if version != PROTOCOL_VERSION {
-                    return Err(ConnectoError::Handshake(
-                        "Protocol version mismatch".to_string(),
-                    ));
-                }
-                (device_name, verification_code)
+    let expired = tokio::time::sleep_until(deadline);
+    tokio::pin!(expired);
+    let mut notices = tokio::time::interval(Duration::from_secs(APPROVAL_NOTICE_INTERVAL_SECS));
+    loop {
+        tokio::select! {
+            answer = &mut decision => return Ok(Some(answer.unwrap_or(false))),
+            _ = &mut expired => return Ok(None),
+            _ = notices.tick(), if notify => {
+                let pending = Message::ApprovalPending {
+                    remaining_secs: deadline.saturating_duration_since(Instant::now()).as_secs(),
+                };
+                framing.write(writer, &pending).await?;
+            }
+        }
+    }
+}
+
+/// Have the client enter the verification code shown to the server's user
+///
+/// The client gets [`PIN_ATTEMPTS`] tries, all within `timeout`. In an
+/// encrypted channel, the code is never sent: both sides run a
+/// [`CodeExchange`] over it and the channel's `binding` and prove they
+/// derived the same key, which fails for a client that entered another code
+/// or talks to us through a device in the middle.
+async fn verify_pin(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+    code: &str,
+    binding: Option<&str>,
+    timeout: Duration,
+    max_len: usize,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    for attempts_left in (1..=PIN_ATTEMPTS).rev() {
+        let request = Message::PinRequest { attempts_left };
+        framing.write(writer, &request).await?;
+
+        let entry = read_pin_step(reader, writer, framing, deadline, max_len).await?;
+        let accepted = match (entry, binding) {
+            (Message::PinEntry { pin }, None) => {
+                pin_matches(&pin, code).then_some(Message::PinAccepted { mac: None })
             }
-            Message::Error { message, .. } => {
-                return Err(ConnectoError::Handshake(message));
+            (Message::PinExchange { message }, Some(binding)) => {
+                let exchange = CodeExchange::start(Side::Server, code, binding);
+                let reply = Message::PinExchange {
+                    message: exchange.message(),
+                };
+                framing.write(writer, &reply).await?;
+                let key = exchange.finish(&message)?;
+                match read_pin_step(reader, writer, framing, deadline, max_len).await? {
+                    Message::PinConfirm { mac } => {
+                        key.confirms(Side::Client, &mac)
+                            .then(|| Message::PinAccepted {
+                                mac: Some(key.confirmation(Side::Server)),
+                            })
+                    }
+                    _ => {
+                        let error_msg = Message::Error {
+                            code: ProtocolErrorCode::UnexpectedMessage,
+                            message: "Expected PinConfirm message".to_string(),
+                        };
+                        framing.write(writer, &error_msg).await?;
+                        return Err(ConnectoError::Handshake("Expected PinConfirm".to_string()));
+                    }
+                }
             }
             _ => {
-                return Err(ConnectoError::Handshake("Unexpected response".to_string()));
+                let expected = if binding.is_some() {
+                    "PinExchange"
+                } else {
+                    "PinEntry"
+                };
+                let error_msg = Message::Error {
+                    code: ProtocolErrorCode::UnexpectedMessage,
+                    message: format!("Expected {} message", expected),
+                };
+                framing.write(writer, &error_msg).await?;
+                return Err(ConnectoError::Handshake(format!("Expected {}", expected)));
             }
         };
 
-        // Send KeyExchange
-        let key_exchange = Message::KeyExchange {
-            public_key: key_pair.public_key.clone(),
-            comment: key_pair.comment.clone(),
-        };
-        writer.write_all(key_exchange.to_json()?.as_bytes()).await?;
-
-        // Read KeyAccepted
-        line.clear();
-        reader.read_line(&mut line).await?;
-        let accepted = Message::from_json(&line)?;
-
         match accepted {
-            Message::KeyAccepted { .. } => {}
-            Message::Error { message, .. } => {
-                return Err(ConnectoError::Handshake(message));
-            }
-            _ => {
-                return Err(ConnectoError::Handshake("Expected KeyAccepted".to_string()));
+            Some(accepted) => {
+                framing.write(writer, &accepted).await?;
+                return Ok(());
             }
+            None => debug!("Wrong verification code, {} tries left", attempts_left - 1),
         }
+    }
 
-        // Read PairingComplete
-        line.clear();
-        reader.read_line(&mut line).await?;
-        let complete = Message::from_json(&line)?;
+    let error_msg = Message::Error {
+        code: ProtocolErrorCode::VerificationFailed,
+        message: "Wrong verification code".to_string(),
+    };
+    framing.write(writer, &error_msg).await?;
+    Err(ConnectoError::VerificationFailed(
+        "Wrong verification code".to_string(),
+    ))
+}
 
-        match complete {
-            Message::PairingComplete { ssh_user } => Ok(PairingResult {
-                server_name,
-                ssh_user,
-                verification_code,
-            }),
-            _ => Err(ConnectoError::Handshake(
-                "Expected PairingComplete".to_string(),
-            )),
+/// Read the client's next message while it enters the verification code,
+/// which must arrive before `deadline`
+async fn read_pin_step(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+    deadline: Instant,
+    max_len: usize,
+) -> Result<Message> {
+    let mut line = String::new();
+    match tokio::time::timeout_at(deadline, framing.read(reader, &mut line, max_len)).await {
+        Err(_) => {
+            let error_msg = Message::Error {
+                code: ProtocolErrorCode::VerificationFailed,
+                message: "Verification code not entered in time".to_string(),
+            };
+            framing.write(writer, &error_msg).await?;
+            Err(ConnectoError::Handshake(
+                "Verification code not entered in time".to_string(),
+            ))
         }
+        Ok(Ok(0)) => Err(ConnectoError::Handshake(
+            "Client disconnected before entering the verification code".to_string(),
+        )),
+        Ok(Err(e)) => Err(ConnectoError::Network(format!(
+            "Failed to read the verification code: {}",
+            e
+        ))),
+        Ok(Ok(_)) => Message::from_json(&line),
     }
 }
 
-/// Result of a successful pairing
-#[derive(Debug, Clone)]
-pub struct PairingResult {
-    pub server_name: String,
-    pub ssh_user: String,
-    pub verification_code: Option<String>,
+/// What an identity proof signs: the peer's `nonce`, tied to the encrypted
+/// channel's `binding` if there is one, so a proof cannot be relayed from
+/// one channel into another
+fn identity_challenge(nonce: &str, binding: Option<&str>) -> String {
+    match binding {
+        Some(binding) => format!("{}:{}", nonce, binding),
+        None => nonce.to_string(),
+    }
 }
 
-/// Generate a random 4-digit verification code
-pub fn generate_verification_code() -> String {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
-    format!("{:04}", rng.gen_range(0..10000))
+/// Whether the code a client entered is `code`, compared in constant time
+fn pin_matches(pin: &str, code: &str) -> bool {
+    use subtle::ConstantTimeEq;
+    pin.trim().as_bytes().ct_eq(code.as_bytes()).into()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
-
-    #[test]
-    fn test_protocol_version() {
-        assert_eq!(PROTOCOL_VERSION, 1);
+/// Challenge the client to sign a fresh nonce in `namespace` with
+/// `public_key`
+async fn verify_key_proof(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+    public_key: &str,
+    namespace: &str,
+    limits: &HandshakeLimits,
+) -> Result<()> {
+    let (nonce, signature) = read_key_proof(reader, writer, framing, limits).await?;
+    if let Err(e) =
+        SshKeyPair::verify_signature(public_key, namespace, nonce.as_bytes(), &signature)
+    {
+        let error_msg = Message::Error {
+            code: ProtocolErrorCode::VerificationFailed,
+            message: "Key proof verification failed".to_string(),
+        };
+        framing.write(writer, &error_msg).await?;
+        return Err(ConnectoError::Handshake(format!(
+            "Key proof verification failed: {}",
+            e
+        )));
     }
 
-    #[test]
-    fn test_message_hello_serialization() {
+    Ok(())
+}
+
+/// Send a fresh challenge and read the client's signature of it, returning
+/// the nonce and the signature
+async fn read_key_proof(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+    limits: &HandshakeLimits,
+) -> Result<(String, String)> {
+    let nonce = generate_nonce();
+    let challenge = Message::KeyChallenge {
+        nonce: nonce.clone(),
+    };
+    framing.write(writer, &challenge).await?;
+
+    let mut line = String::new();
+    match read_step(reader, framing, &mut line, limits).await {
+        Ok(Ok(0)) | Err(_) => {
+            return Err(ConnectoError::Handshake(
+                "Client disconnected before proving key possession".to_string(),
+            ));
+        }
+        Ok(Err(e)) => {
+            return Err(ConnectoError::Network(format!(
+                "Failed to read KeyProof: {}",
+                e
+            )));
+        }
+        Ok(Ok(_)) => {}
+    }
+
+    let signature = match Message::from_json(&line)? {
+        Message::KeyProof { signature } => signature,
+        _ => {
+            let error_msg = Message::Error {
+                code: ProtocolErrorCode::UnexpectedMessage,
+                message: "Expected KeyProof message".to_string(),
+            };
+            framing.write(writer, &error_msg).await?;
+            return Err(ConnectoError::Handshake("Expected KeyProof".to_string()));
+        }
+    };
+    Ok((nonce, signature))
+}
+
+/// Read the proof of the identity a client announced, which it sends right
+/// after `HelloAck`, and check it against the `nonce` we sent
+async fn receive_identity_proof(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+    nonce: &str,
+    announced: &str,
+    limits: &HandshakeLimits,
+) -> Result<VerifiedIdentity> {
+    let mut line = String::new();
+    match read_step(reader, framing, &mut line, limits).await {
+        Ok(Ok(0)) | Err(_) => {
+            return Err(ConnectoError::Handshake(
+                "Client disconnected before proving its identity".to_string(),
+            ));
+        }
+        Ok(Err(e)) => {
+            return Err(ConnectoError::Network(format!(
+                "Failed to read IdentityProof: {}",
+                e
+            )));
+        }
+        Ok(Ok(_)) => {}
+    }
+
+    let proof = match Message::from_json(&line)? {
+        Message::IdentityProof { proof } => proof,
+        _ => {
+            let error_msg = Message::Error {
+                code: ProtocolErrorCode::UnexpectedMessage,
+                message: "Expected IdentityProof message".to_string(),
+            };
+            framing.write(writer, &error_msg).await?;
+            return Err(ConnectoError::Handshake(
+                "Expected IdentityProof".to_string(),
+            ));
+        }
+    };
+
+    match proof.verify_announced(nonce, Some(announced)) {
+        Ok(identity) => Ok(identity),
+        Err(e) => {
+            let error_msg = Message::Error {
+                code: ProtocolErrorCode::VerificationFailed,
+                message: "Identity proof verification failed".to_string(),
+            };
+            framing.write(writer, &error_msg).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Client for initiating pairing with a server
+pub struct HandshakeClient {
+    device_name: String,
+    trust: Option<TrustStore>,
+    trust_mode: TrustMode,
+    address_pins: BTreeMap<String, String>,
+    event_tx: Option<mpsc::Sender<ClientEvent>>,
+    pin_tx: Option<mpsc::Sender<PinPrompt>>,
+    key_lifetime: Option<Duration>,
+    user: Option<String>,
+    step_timeout: Duration,
+    timeout: Option<Duration>,
+    cancel: Option<ShutdownHandle>,
+    retry: RetryPolicy,
+    identity: Option<DeviceIdentity>,
+    require_encryption: bool,
+}
+
+impl HandshakeClient {
+    /// Create a new handshake client
+    pub fn new(device_name: &str) -> Self {
+        Self {
+            device_name: device_name.to_string(),
+            trust: None,
+            trust_mode: TrustMode::default(),
+            address_pins: BTreeMap::new(),
+            event_tx: None,
+            pin_tx: None,
+            key_lifetime: None,
+            user: None,
+            step_timeout: Duration::from_secs(DEFAULT_STEP_TIMEOUT_SECS),
+            timeout: None,
+            cancel: None,
+            retry: RetryPolicy::default(),
+            identity: None,
+            require_encryption: false,
+        }
+    }
+
+    /// Check the server's identity against the pins in `store`, and pin it
+    /// after the first successful pairing
+    pub fn with_trust_store(mut self, store: TrustStore) -> Self {
+        self.trust = Some(store);
+        self
+    }
+
+    /// Require servers at known addresses to announce the identity pinned for
+    /// them, such as those of `~/.ssh/config` entries
+    ///
+    /// Pins are keyed by host, as given by [`net::host_of`].
+    pub fn with_address_pins(mut self, pins: BTreeMap<String, String>) -> Self {
+        self.address_pins = pins;
+        self
+    }
+
+    /// Set what happens when a server's identity differs from its pin
+    pub fn with_trust_mode(mut self, mode: TrustMode) -> Self {
+        self.trust_mode = mode;
+        self
+    }
+
+    /// Whether to refuse servers that cannot encrypt the channel (default: no)
+    ///
+    /// Servers older than [`ENCRYPTED_VERSION`] pair in plaintext otherwise,
+    /// which also lets anyone in the middle downgrade a pairing to it.
+    pub fn with_encryption(mut self, require: bool) -> Self {
+        self.require_encryption = require;
+        self
+    }
+
+    /// Announce this device's identity in Hello and prove it when asked, so
+    /// servers know us across renames and address changes
+    pub fn with_identity(mut self, identity: &DeviceIdentity) -> Self {
+        self.identity = Some(identity.clone());
+        self
+    }
+
+    /// Report progress, such as waiting for the server's approval, on `event_tx`
+    pub fn with_events(mut self, event_tx: mpsc::Sender<ClientEvent>) -> Self {
+        self.event_tx = Some(event_tx);
+        self
+    }
+
+    /// Ask on `pin_tx` for the verification code of servers that require one
+    ///
+    /// Without it, pairing with such a server fails.
+    pub fn with_pin_prompt(mut self, pin_tx: mpsc::Sender<PinPrompt>) -> Self {
+        self.pin_tx = Some(pin_tx);
+        self
+    }
+
+    /// Ask servers to authorize the key only for `lifetime`
+    ///
+    /// Servers older than [`KEY_LIFETIME_VERSION`] authorize it for good;
+    /// [`PairingResult::expires_at`] tells which did.
+    pub fn with_key_lifetime(mut self, lifetime: Duration) -> Self {
+        self.key_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Ask servers to install the key into the account `user` rather than
+    /// the one they run as
+    ///
+    /// Servers that do not offer the account are refused before the key is
+    /// sent; [`PairingResult::ssh_user`] is the account that got it.
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Give up when the server takes longer than `timeout` to send any one
+    /// message, [`DEFAULT_STEP_TIMEOUT_SECS`] by default
+    ///
+    /// A server waiting for its user to approve us keeps sending
+    /// [`Message::ApprovalPending`], so approvals are not cut short.
+    pub fn with_step_timeout(mut self, timeout: Duration) -> Self {
+        self.step_timeout = timeout;
+        self
+    }
+
+    /// Give up when a whole pairing or revocation takes longer than
+    /// `timeout`, including the time spent entering a verification code
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Stop pairing or revoking as soon as `cancel` is shut down
+    pub fn with_cancellation(mut self, cancel: ShutdownHandle) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Retry connecting to the server as `policy` says, three attempts by
+    /// default
+    ///
+    /// Only connecting is retried: once the server answered, a failed
+    /// handshake is not repeated. Each retry is logged and reported as
+    /// [`ClientEvent::Retrying`].
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Connect to a server and perform key exchange
+    ///
+    /// Servers that predate key-possession proofs reject the current protocol
+    /// version outright, so pairing is retried once with the oldest version.
+    /// Running out of time or being cancelled fails with
+    /// [`ConnectoError::Timeout`].
+    pub async fn pair(&self, address: &str, key_pair: &SshKeyPair) -> Result<PairingResult> {
+        self.bounded(address, self.pair_with_fallback(address, key_pair))
+            .await
+    }
+
+    async fn pair_with_fallback(
+        &self,
+        address: &str,
+        key_pair: &SshKeyPair,
+    ) -> Result<PairingResult> {
+        if let Some(result) = self
+            .pair_with_version(address, key_pair, PROTOCOL_VERSION)
+            .await?
+        {
+            return Ok(result);
+        }
+
+        debug!(
+            "Server rejected protocol version {}, retrying with version {}",
+            PROTOCOL_VERSION, MIN_PROTOCOL_VERSION
+        );
+        self.pair_with_version(address, key_pair, MIN_PROTOCOL_VERSION)
+            .await?
+            .ok_or_else(|| ConnectoError::Handshake("Protocol version mismatch".to_string()))
+    }
+
+    /// Pair using the given protocol version
+    ///
+    /// Returns `Ok(None)` if the server rejected the version in response to Hello.
+    async fn pair_with_version(
+        &self,
+        address: &str,
+        key_pair: &SshKeyPair,
+        version: u32,
+    ) -> Result<Option<PairingResult>> {
+        let stream = self.connect(address).await?;
+        self.pair_over_stream(stream, address, key_pair, version)
+            .await
+    }
+
+    /// Pair over an already open stream, such as a relay channel
+    ///
+    /// `address` stands for the server in pins, prompts and errors. Unlike
+    /// [`HandshakeClient::pair`], this cannot fall back to an older protocol
+    /// version, since the stream is used up by the first attempt.
+    pub async fn pair_over(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        address: &str,
+        key_pair: &SshKeyPair,
+    ) -> Result<PairingResult> {
+        let pairing = async {
+            self.pair_over_stream(stream, address, key_pair, PROTOCOL_VERSION)
+                .await?
+                .ok_or_else(|| ConnectoError::Handshake("Protocol version mismatch".to_string()))
+        };
+        self.bounded(address, pairing).await
+    }
+
+    /// Pair over a stream a person may have to carry by hand, such as
+    /// [`stdio::stream`](crate::stdio::stream)
+    ///
+    /// Like [`HandshakeClient::pair_over`], but speaks the version before
+    /// [`FRAMED_VERSION`], so every message stays one line of JSON that can
+    /// be copied from one terminal and pasted into another.
+    pub async fn pair_over_lines(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        address: &str,
+        key_pair: &SshKeyPair,
+    ) -> Result<PairingResult> {
+        let pairing = async {
+            self.pair_over_stream(stream, address, key_pair, FRAMED_VERSION - 1)
+                .await?
+                .ok_or_else(|| ConnectoError::Handshake("Protocol version mismatch".to_string()))
+        };
+        self.bounded(address, pairing).await
+    }
+
+    /// Ask the listener at `address` to remove `key_pair` from its
+    /// authorized_keys
+    ///
+    /// The listener has us sign a challenge with the key, so only its holder
+    /// can revoke it. A listener that predates revocation hangs up, which
+    /// fails with [`ConnectoError::Handshake`].
+    pub async fn revoke(&self, address: &str, key_pair: &SshKeyPair) -> Result<()> {
+        let revocation = async {
+            let stream = self.connect(address).await?;
+            self.revoke_unbounded(stream, address, key_pair).await
+        };
+        self.bounded(address, revocation).await
+    }
+
+    /// Revoke `key_pair` over an already open stream; see
+    /// [`HandshakeClient::revoke`]
+    pub async fn revoke_over(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        address: &str,
+        key_pair: &SshKeyPair,
+    ) -> Result<()> {
+        self.bounded(address, self.revoke_unbounded(stream, address, key_pair))
+            .await
+    }
+
+    async fn revoke_unbounded(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        address: &str,
+        key_pair: &SshKeyPair,
+    ) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        let framing = Framing::Lines;
+
+        let fingerprint = key_pair.fingerprint()?;
+        let request = Message::Revoke {
+            version: REVOKE_VERSION,
+            device_name: self.device_name.clone(),
+            fingerprint: fingerprint.clone(),
+        };
+        framing.write(&mut writer, &request).await?;
+
+        match self
+            .next_reply(&mut reader, framing, &mut line, address)
+            .await
+        {
+            Ok(Message::KeyChallenge { nonce }) => {
+                let signature = key_pair.sign(REVOKE_NAMESPACE, nonce.as_bytes())?;
+                framing
+                    .write(&mut writer, &Message::KeyProof { signature })
+                    .await?;
+            }
+            Ok(Message::Error { code, message }) => return Err(code.into_error(message)),
+            Ok(_) => {
+                return Err(ConnectoError::Handshake(
+                    "Expected KeyChallenge".to_string(),
+                ))
+            }
+            Err(e @ ConnectoError::Timeout(_)) => return Err(e),
+            Err(_) if line.is_empty() => {
+                return Err(ConnectoError::Handshake(format!(
+                    "{} hung up; its Connecto may be too old to revoke keys",
+                    address
+                )))
+            }
+            Err(e) => return Err(e),
+        }
+
+        match self
+            .next_reply(&mut reader, framing, &mut line, address)
+            .await?
+        {
+            Message::KeyRevoked {
+                fingerprint: revoked,
+            } if revoked == fingerprint => Ok(()),
+            Message::Error { code, message } => Err(code.into_error(message)),
+            _ => Err(ConnectoError::Handshake("Expected KeyRevoked".to_string())),
+        }
+    }
+
+    /// Have the listener at `address` prove which identity it holds
+    ///
+    /// The listener signs a fresh nonce with its identity key, so a device
+    /// that merely repeats another's fingerprint cannot pass for it. Fails
+    /// with [`ConnectoError::VerificationFailed`] if the listener is too old
+    /// to prove its identity, keeps it private, or gives a bad proof.
+    pub async fn verify_identity(&self, address: &str) -> Result<VerifiedIdentity> {
+        let verification = async {
+            let stream = self.connect(address).await?;
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut reader = BufReader::new(reader);
+            let mut line = String::new();
+
+            let nonce = generate_nonce();
+            let key_share = KeyShare::generate();
+            let hello = Message::Hello {
+                version: PROTOCOL_VERSION,
+                device_name: self.device_name.clone(),
+                timestamp: Some(clock::unix_now()),
+                min_version: Some(IDENTITY_PROOF_VERSION),
+                capabilities: Some(Capabilities::SUPPORTED),
+                identity: None,
+                nonce: Some(nonce.clone()),
+                key_share: Some(key_share.public()),
+            };
+            Framing::Lines.write(&mut writer, &hello).await?;
+
+            match self
+                .next_reply(&mut reader, Framing::Lines, &mut line, address)
+                .await?
+            {
+                Message::HelloAck {
+                    version,
+                    device_name,
+                    identity,
+                    identity_proof,
+                    key_share: server_share,
+                    ..
+                } => {
+                    // The proof is tied to the channel the server set up
+                    let binding = match server_share.filter(|_| version >= ENCRYPTED_VERSION) {
+                        Some(server_share) => Some(key_share.agree(Side::Client, &server_share)?),
+                        None => None,
+                    };
+                    let challenge =
+                        identity_challenge(&nonce, binding.as_ref().map(|keys| keys.binding()));
+                    proven_identity(&device_name, identity, identity_proof, &challenge)?.ok_or_else(
+                        || {
+                            ConnectoError::VerificationFailed(format!(
+                                "{} does not prove an identity",
+                                address
+                            ))
+                        },
+                    )
+                }
+                Message::Error {
+                    code: ProtocolErrorCode::VersionMismatch,
+                    ..
+                } => Err(ConnectoError::VerificationFailed(format!(
+                    "{} is too old to prove its identity",
+                    address
+                ))),
+                Message::Error { code, message } => Err(code.into_error(message)),
+                _ => Err(ConnectoError::Handshake("Unexpected response".to_string())),
+            }
+        };
+        self.bounded(address, verification).await
+    }
+
+    /// Pair over `stream` using the given protocol version
+    ///
+    /// Returns `Ok(None)` if the server rejected the version in response to Hello.
+    async fn pair_over_stream(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        address: &str,
+        key_pair: &SshKeyPair,
+        version: u32,
+    ) -> Result<Option<PairingResult>> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+
+        // Send Hello, challenging the server to prove its identity and
+        // offering a key share for the channel
+        let sent_at = clock::unix_now();
+        let nonce = generate_nonce();
+        let key_share = (version >= ENCRYPTED_VERSION).then(KeyShare::generate);
+        let hello = Message::Hello {
+            version,
+            device_name: self.device_name.clone(),
+            timestamp: Some(sent_at),
+            min_version: Some(MIN_PROTOCOL_VERSION),
+            capabilities: Some(Capabilities::SUPPORTED),
+            identity: self
+                .identity
+                .as_ref()
+                .map(|identity| identity.fingerprint().to_string()),
+            nonce: Some(nonce.clone()),
+            key_share: key_share.as_ref().map(KeyShare::public),
+        };
+        Framing::Lines.write(&mut writer, &hello).await?;
+
+        // Read HelloAck
+        let hello_ack = self
+            .next_reply(&mut reader, Framing::Lines, &mut line, address)
+            .await?;
+
+        let (
+            server_name,
+            verification_code,
+            version,
+            server_identity,
+            identity_proof,
+            identity_nonce,
+            pin_required,
+            clock_skew,
+            capabilities,
+            users,
+            server_share,
+        ) = match hello_ack {
+            Message::HelloAck {
+                version: server_version,
+                device_name,
+                verification_code,
+                identity,
+                pin_required,
+                timestamp,
+                capabilities,
+                users,
+                identity_proof,
+                nonce: identity_nonce,
+                key_share: server_share,
+            } => {
+                if server_version < MIN_PROTOCOL_VERSION || server_version > version {
+                    return Err(ConnectoError::Handshake(
+                        "Protocol version mismatch".to_string(),
+                    ));
+                }
+                // Servers that predate capabilities do not say what they support
+                let capabilities = capabilities
+                    .unwrap_or_else(|| Capabilities::implied_by(server_version))
+                    & Capabilities::SUPPORTED;
+                (
+                    device_name,
+                    verification_code,
+                    server_version,
+                    identity,
+                    identity_proof,
+                    identity_nonce,
+                    pin_required,
+                    timestamp.map(|t| clock::skew(t, sent_at, clock::unix_now())),
+                    capabilities,
+                    users,
+                    server_share,
+                )
+            }
+            Message::Error {
+                code: ProtocolErrorCode::VersionMismatch,
+                ..
+            } if version > MIN_PROTOCOL_VERSION => {
+                return Ok(None);
+            }
+            Message::Error { code, message } => {
+                return Err(code.into_error(message));
+            }
+            _ => {
+                return Err(ConnectoError::Handshake("Unexpected response".to_string()));
+            }
+        };
+
+        // Agree on the keys of the channel everything after HelloAck runs in
+        let channel_keys = match (key_share, server_share) {
+            (Some(share), Some(server_share)) if version >= ENCRYPTED_VERSION => {
+                Some(share.agree(Side::Client, &server_share)?)
+            }
+            _ if version >= ENCRYPTED_VERSION => {
+                return Err(ConnectoError::Handshake(format!(
+                    "{} sent no key share for the encrypted channel",
+                    server_name
+                )));
+            }
+            _ if self.require_encryption => {
+                return Err(ConnectoError::VersionMismatch(format!(
+                    "{} cannot encrypt the pairing channel",
+                    server_name
+                )));
+            }
+            _ => None,
+        };
+        let binding = channel_keys.as_ref().map(|keys| keys.binding().to_string());
+        let capabilities = if channel_keys.is_some() {
+            capabilities
+        } else {
+            capabilities.without(Capabilities::ENCRYPTION)
+        };
+
+        // Refuse to hand our key to a device impersonating a known peer;
+        // only an identity the server proved, in this channel, counts
+        let challenge = identity_challenge(&nonce, binding.as_deref());
+        let server_identity =
+            proven_identity(&server_name, server_identity, identity_proof, &challenge)?;
+        if let Some(trust) = &self.trust {
+            trust.verify(&server_name, server_identity.as_ref(), self.trust_mode)?;
+        }
+        let host = net::host_of(address);
+        if let Some(pinned) = self.address_pins.get(host) {
+            trust::verify_identity(
+                &format!("The device at {}", host),
+                pinned,
+                server_identity.as_ref(),
+                self.trust_mode,
+            )?;
+        }
+
+        if let Some(user) = &self.user {
+            check_user_offered(&server_name, user, &users)?;
+        }
+
+        clock::warn_if_large(&server_name, clock_skew);
+        let framing = framing_for(version);
+        let (reader, mut writer) = channel::wrap(reader, writer, channel_keys);
+        let mut reader = BufReader::new(reader);
+
+        // Prove our own identity when the server asks
+        if let (Some(identity), Some(identity_nonce)) = (&self.identity, &identity_nonce) {
+            let proof = Message::IdentityProof {
+                proof: identity.prove(&identity_challenge(identity_nonce, binding.as_deref()))?,
+            };
+            framing.write(&mut writer, &proof).await?;
+        }
+
+        if pin_required {
+            self.enter_pin(
+                &mut reader,
+                &mut writer,
+                framing,
+                binding.as_deref(),
+                &server_name,
+                address,
+            )
+            .await?;
+        }
+
+        // Send KeyExchange
+        let expires_in = self
+            .key_lifetime
+            .filter(|_| version >= KEY_LIFETIME_VERSION)
+            .map(|lifetime| lifetime.as_secs());
+        let key_fingerprint = key_pair.fingerprint()?;
+        let key_exchange = Message::KeyExchange {
+            public_key: key_pair.public_key.clone(),
+            comment: key_pair.comment.clone(),
+            expires_in,
+            fingerprint: Some(key_fingerprint.clone()),
+            user: self.user.clone(),
+        };
+        framing.write(&mut writer, &key_exchange).await?;
+
+        // Prove we hold the private key
+        if version >= KEY_PROOF_VERSION {
+            match self
+                .next_reply(&mut reader, framing, &mut line, address)
+                .await?
+            {
+                Message::KeyChallenge { nonce } => {
+                    let signature = key_pair.sign(KEY_PROOF_NAMESPACE, nonce.as_bytes())?;
+                    let proof = Message::KeyProof { signature };
+                    framing.write(&mut writer, &proof).await?;
+                }
+                Message::Error { code, message } => {
+                    return Err(code.into_error(message));
+                }
+                _ => {
+                    return Err(ConnectoError::Handshake(
+                        "Expected KeyChallenge".to_string(),
+                    ));
+                }
+            }
+        }
+
+        // Read KeyAccepted, possibly after the server asks us to wait for approval
+        let accepted = loop {
+            match self
+                .next_reply(&mut reader, framing, &mut line, address)
+                .await?
+            {
+                Message::ApprovalPending { remaining_secs } => {
+                    debug!(
+                        "Waiting for {} to approve the pairing ({}s left)",
+                        server_name, remaining_secs
+                    );
+                    if let Some(event_tx) = &self.event_tx {
+                        let _ = event_tx
+                            .send(ClientEvent::AwaitingApproval { remaining_secs })
+                            .await;
+                    }
+                }
+                message => break message,
+            }
+        };
+
+        // Older servers do not say which key they installed
+        let fingerprint_confirmed = match accepted {
+            Message::KeyAccepted {
+                fingerprint: Some(installed),
+                ..
+            } => {
+                if installed != key_fingerprint {
+                    return Err(ConnectoError::Handshake(format!(
+                        "{} installed a key with fingerprint {}, not the one sent ({})",
+                        server_name, installed, key_fingerprint
+                    )));
+                }
+                true
+            }
+            Message::KeyAccepted { .. } => false,
+            Message::Error { code, message } => {
+                return Err(code.into_error(message));
+            }
+            _ => {
+                return Err(ConnectoError::Handshake("Expected KeyAccepted".to_string()));
+            }
+        };
+
+        // Read the host keys (v6+), then PairingComplete
+        let mut host_keys = Vec::new();
+        let complete = loop {
+            match self
+                .next_reply(&mut reader, framing, &mut line, address)
+                .await?
+            {
+                Message::HostKeys { keys } => host_keys = valid_host_keys(&server_name, keys),
+                message => break message,
+            }
+        };
+
+        match complete {
+            Message::PairingComplete {
+                ssh_user,
+                hostname,
+                identity,
+                identity_proof,
+                ssh_port,
+            } => {
+                // A private server proves the identity it reveals only now
+                let server_identity = match server_identity {
+                    Some(identity) => Some(identity),
+                    None => proven_identity(&server_name, identity, identity_proof, &challenge)?,
+                };
+                let result = PairingResult {
+                    server_name,
+                    ssh_user,
+                    verification_code,
+                    server_identity,
+                    server_hostname: hostname,
+                    clock_skew,
+                    expires_at: expires_in.map(|secs| clock::unix_now().max(0) as u64 + secs),
+                    key_fingerprint,
+                    fingerprint_confirmed,
+                    host_keys,
+                    ssh_port: ssh_port.filter(|&port| port != 0).unwrap_or(SSH_PORT),
+                    capabilities,
+                };
+                if let (Some(trust), Some(identity)) = (&self.trust, &result.server_identity) {
+                    if let Err(e) = trust.pin(result.peer_name(), identity) {
+                        warn!("Failed to pin identity of {}: {}", result.peer_name(), e);
+                    }
+                }
+                Ok(Some(result))
+            }
+            _ => Err(ConnectoError::Handshake(
+                "Expected PairingComplete".to_string(),
+            )),
+        }
+    }
+
+    /// Answer the server's requests for its verification code until it
+    /// accepts one
+    ///
+    /// In an encrypted channel, the code goes into a [`CodeExchange`] over
+    /// the channel's `binding` instead of being sent, and the server must
+    /// prove it knows the code too.
+    async fn enter_pin(
+        &self,
+        reader: &mut (impl AsyncBufRead + Unpin),
+        writer: &mut (impl AsyncWrite + Unpin),
+        framing: Framing,
+        binding: Option<&str>,
+        server_name: &str,
+        address: &str,
+    ) -> Result<()> {
+        let mut line = String::new();
+        let mut key: Option<CodeKey> = None;
+        loop {
+            let attempts_left = match self.next_reply(reader, framing, &mut line, address).await? {
+                Message::PinRequest { attempts_left } => attempts_left,
+                Message::PinAccepted { mac } => {
+                    let proven = match (binding, &key, mac) {
+                        (None, _, _) => true,
+                        (Some(_), Some(key), Some(mac)) => key.confirms(Side::Server, &mac),
+                        (Some(_), _, _) => false,
+                    };
+                    if proven {
+                        return Ok(());
+                    }
+                    return Err(ConnectoError::VerificationFailed(format!(
+                        "{} did not prove it knows its verification code",
+                        server_name
+                    )));
+                }
+                Message::Error { code, message } => {
+                    return Err(code.into_error(message));
+                }
+                _ => {
+                    return Err(ConnectoError::Handshake("Expected PinRequest".to_string()));
+                }
+            };
+
+            let no_pin = || {
+                ConnectoError::Handshake(format!(
+                    "{} requires the verification code shown on its screen",
+                    server_name
+                ))
+            };
+            let pin_tx = self.pin_tx.as_ref().ok_or_else(no_pin)?;
+            let (responder, response) = oneshot::channel();
+            let prompt = PinPrompt {
+                server_name: server_name.to_string(),
+                address: address.to_string(),
+                attempts_left,
+                responder,
+            };
+            let cancelled = || {
+                ConnectoError::Handshake(format!(
+                    "No verification code entered for {}",
+                    server_name
+                ))
+            };
+            pin_tx.send(prompt).await.map_err(|_| cancelled())?;
+            let pin = response.await.map_err(|_| cancelled())?;
+
+            let Some(binding) = binding else {
+                let entry = Message::PinEntry { pin };
+                framing.write(writer, &entry).await?;
+                continue;
+            };
+            let exchange = CodeExchange::start(Side::Client, pin.trim(), binding);
+            let entry = Message::PinExchange {
+                message: exchange.message(),
+            };
+            framing.write(writer, &entry).await?;
+            let server_message = match self.next_reply(reader, framing, &mut line, address).await? {
+                Message::PinExchange { message } => message,
+                Message::Error { code, message } => {
+                    return Err(code.into_error(message));
+                }
+                _ => {
+                    return Err(ConnectoError::Handshake("Expected PinExchange".to_string()));
+                }
+            };
+            let code_key = exchange.finish(&server_message)?;
+            let confirm = Message::PinConfirm {
+                mac: code_key.confirmation(Side::Client),
+            };
+            framing.write(writer, &confirm).await?;
+            key = Some(code_key);
+        }
+    }
+
+    /// Read the server's next message, waiting at most the step timeout
+    async fn next_reply(
+        &self,
+        reader: &mut (impl AsyncBufRead + Unpin),
+        framing: Framing,
+        line: &mut String,
+        address: &str,
+    ) -> Result<Message> {
+        line.clear();
+        tokio::time::timeout(self.step_timeout, read_reply(reader, framing, line))
+            .await
+            .map_err(|_| {
+                ConnectoError::Timeout(format!(
+                    "{} stopped answering for {}s",
+                    address,
+                    self.step_timeout.as_secs()
+                ))
+            })?
+    }
+
+    /// Connect to `address` under the retry policy, giving each attempt
+    /// the step timeout
+    async fn connect(&self, address: &str) -> Result<TcpStream> {
+        let attempt = || async {
+            tokio::time::timeout(self.step_timeout, net::connect(address))
+                .await
+                .map_err(|_| {
+                    ConnectoError::Timeout(format!(
+                        "{} did not answer within {}s",
+                        address,
+                        self.step_timeout.as_secs()
+                    ))
+                })?
+        };
+        let on_retry = |retry: &Retry| {
+            warn!(
+                "Attempt {}/{} to reach {} failed: {}; retrying in {:.1}s",
+                retry.attempt,
+                retry.attempts,
+                address,
+                retry.reason,
+                retry.delay.as_secs_f64()
+            );
+            if let Some(tx) = &self.event_tx {
+                let _ = tx.try_send(ClientEvent::Retrying {
+                    attempt: retry.attempt,
+                    attempts: retry.attempts,
+                    delay: retry.delay,
+                    reason: retry.reason.clone(),
+                });
+            }
+        };
+        self.retry.run(attempt, on_retry).await
+    }
+
+    /// Run `work` within the overall timeout, until cancelled
+    async fn bounded<T>(&self, address: &str, work: impl Future<Output = Result<T>>) -> Result<T> {
+        let limited = async {
+            match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, work).await.map_err(|_| {
+                    ConnectoError::Timeout(format!(
+                        "{} did not finish within {}s",
+                        address,
+                        timeout.as_secs()
+                    ))
+                })?,
+                None => work.await,
+            }
+        };
+        let cancelled = async {
+            match &self.cancel {
+                Some(cancel) => cancel.requested().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = limited => result,
+            _ = cancelled => Err(ConnectoError::Timeout(format!("Cancelled talking to {}", address))),
+        }
+    }
+}
+
+/// The identity a peer announced, if it proved it by signing our `nonce`
+///
+/// A peer that announces an identity without proving it, as those older
+/// than [`IDENTITY_PROOF_VERSION`] do, is treated as having none; one whose
+/// proof does not check out is refused.
+pub(crate) fn proven_identity(
+    peer_name: &str,
+    announced: Option<String>,
+    proof: Option<IdentityProof>,
+    nonce: &str,
+) -> Result<Option<VerifiedIdentity>> {
+    match (announced, proof) {
+        (announced, Some(proof)) => proof
+            .verify_announced(nonce, announced.as_deref())
+            .map(Some)
+            .map_err(|e| {
+                ConnectoError::IdentityMismatch(format!(
+                    "{} could not prove its identity: {}",
+                    peer_name, e
+                ))
+            }),
+        (Some(announced), None) => {
+            warn!(
+                "{} announced identity {} without proving it; ignoring it",
+                peer_name, announced
+            );
+            Ok(None)
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Refuse to pair into `user` unless the server lists it among the
+/// accounts it offers
+fn check_user_offered(server_name: &str, user: &str, offered: &[String]) -> Result<()> {
+    if offered.iter().any(|u| u == user) {
+        return Ok(());
+    }
+    Err(ConnectoError::PermissionDenied(if offered.is_empty() {
+        format!(
+            "{} does not let clients choose the account to pair into",
+            server_name
+        )
+    } else {
+        format!(
+            "{} does not offer the account {}; it offers {}",
+            server_name,
+            user,
+            offered.join(", ")
+        )
+    }))
+}
+
+/// Read the server's next message, of at most
+/// [`DEFAULT_MAX_MESSAGE_LEN`] bytes
+async fn read_reply(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    framing: Framing,
+    line: &mut String,
+) -> Result<Message> {
+    line.clear();
+    framing.read(reader, line, DEFAULT_MAX_MESSAGE_LEN).await?;
+    Message::from_json(line)
+}
+
+/// Result of a successful pairing
+#[derive(Debug, Clone)]
+pub struct PairingResult {
+    pub server_name: String,
+    pub ssh_user: String,
+    pub verification_code: Option<String>,
+    /// Identity the server proved, if any
+    pub server_identity: Option<VerifiedIdentity>,
+    /// Hostname revealed by a server in privacy mode once pairing succeeded
+    pub server_hostname: Option<String>,
+    /// How far the server's clock is ahead of ours in seconds, if it sent it
+    pub clock_skew: Option<i64>,
+    /// When the server stops accepting the key (Unix seconds, our clock), if
+    /// it was given a lifetime
+    pub expires_at: Option<u64>,
+    /// SHA-256 fingerprint of the key sent, to compare with the one the
+    /// server shows
+    pub key_fingerprint: String,
+    /// The server reported installing the key with this fingerprint; `false`
+    /// for servers that do not report it
+    pub fingerprint_confirmed: bool,
+    /// The server's SSH host keys, to add to known_hosts; empty for servers
+    /// older than [`HOST_KEYS_VERSION`] and those without an SSH server
+    pub host_keys: Vec<String>,
+    /// Port of the server's SSH server; 22 for servers that do not say
+    pub ssh_port: u16,
+    /// Features both sides support
+    pub capabilities: Capabilities,
+}
+
+impl PairingResult {
+    /// Name to remember the server by
+    ///
+    /// A server in privacy mode announces a pseudonym, so its revealed
+    /// hostname is used instead.
+    pub fn peer_name(&self) -> &str {
+        self.server_hostname.as_deref().unwrap_or(&self.server_name)
+    }
+}
+
+/// The host keys that parse as public keys, without their comments
+fn valid_host_keys(server_name: &str, keys: Vec<String>) -> Vec<String> {
+    keys.into_iter()
+        .filter_map(|key| match SshKeyPair::parse_public_key(&key) {
+            Ok(parsed) => Some(format!(
+                "{} {}",
+                parsed.algorithm(),
+                key.split_whitespace().nth(1)?
+            )),
+            Err(e) => {
+                warn!("Ignoring invalid host key from {}: {}", server_name, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Generate a random 32-byte challenge nonce, hex-encoded
+pub fn generate_nonce() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Generate a random 6-digit verification code
+pub fn generate_verification_code() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    format!("{:06}", rng.gen_range(0..1_000_000))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_protocol_version() {
+        assert_eq!(PROTOCOL_VERSION, 9);
+        assert!(MIN_PROTOCOL_VERSION <= KEY_PROOF_VERSION);
+        assert!(KEY_PROOF_VERSION <= APPROVAL_PENDING_VERSION);
+        assert!(APPROVAL_PENDING_VERSION <= PIN_VERSION);
+        assert!(PIN_VERSION <= KEY_LIFETIME_VERSION);
+        assert!(KEY_LIFETIME_VERSION <= HOST_KEYS_VERSION);
+        assert!(HOST_KEYS_VERSION <= FRAMED_VERSION);
+        assert!(FRAMED_VERSION <= IDENTITY_PROOF_VERSION);
+        assert!(IDENTITY_PROOF_VERSION <= ENCRYPTED_VERSION);
+        assert!(ENCRYPTED_VERSION <= PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        assert_eq!(negotiate_version(1..=7, 1..=5), Some(5));
+        assert_eq!(negotiate_version(1..=7, 3..=9), Some(7));
+        assert_eq!(negotiate_version(4..=7, 1..=4), Some(4));
+        assert_eq!(negotiate_version(4..=7, 1..=3), None);
+        assert_eq!(negotiate_version(1..=7, 8..=9), None);
+    }
+
+    #[test]
+    fn test_message_hello_serialization() {
         let msg = Message::Hello {
             version: 1,
             device_name: "Test Device".to_string(),
+            timestamp: None,
+            min_version: None,
+            capabilities: None,
+            identity: None,
+            nonce: None,
+            key_share: None,
+        };
+
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("Hello"));
+        assert!(json.contains("Test Device"));
+        assert!(json.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_message_hello_deserialization() {
+        let json = r#"{"type":"Hello","version":1,"device_name":"Test"}"#;
+        let msg = Message::from_json(json).unwrap();
+
+        match msg {
+            Message::Hello {
+                version,
+                device_name,
+                ..
+            } => {
+                assert_eq!(version, 1);
+                assert_eq!(device_name, "Test");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_message_hello_ack_serialization() {
+        let msg = Message::HelloAck {
+            version: 1,
+            device_name: "Server".to_string(),
+            verification_code: Some("1234".to_string()),
+            identity: None,
+            pin_required: false,
+            timestamp: None,
+            capabilities: None,
+            users: Vec::new(),
+            identity_proof: None,
+            nonce: None,
+            key_share: None,
+        };
+
+        let json = msg.to_json().unwrap();
+        // Older peers never see the fields when there is no identity or PIN
+        assert!(!json.contains("identity"));
+        assert!(!json.contains("pin_required"));
+        let deserialized = Message::from_json(&json).unwrap();
+
+        match deserialized {
+            Message::HelloAck {
+                version,
+                device_name,
+                verification_code,
+                identity,
+                pin_required,
+                ..
+            } => {
+                assert_eq!(identity, None);
+                assert!(!pin_required);
+                assert_eq!(version, 1);
+                assert_eq!(device_name, "Server");
+                assert_eq!(verification_code, Some("1234".to_string()));
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_message_key_exchange_serialization() {
+        let msg = Message::KeyExchange {
+            public_key: "ssh-ed25519 AAAAC3... test@connecto".to_string(),
+            comment: "test@connecto".to_string(),
+            expires_in: None,
+            fingerprint: None,
+            user: None,
+        };
+
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("KeyExchange"));
+        assert!(json.contains("ssh-ed25519"));
+    }
+
+    #[test]
+    fn test_message_error_serialization() {
+        let msg = Message::Error {
+            code: ProtocolErrorCode::RateLimited,
+            message: "Something went wrong".to_string(),
+        };
+
+        let json = msg.to_json().unwrap();
+        assert!(json.contains(r#""code":8"#), "{}", json);
+        let deserialized = Message::from_json(&json).unwrap();
+
+        match deserialized {
+            Message::Error { code, message } => {
+                assert_eq!(code, ProtocolErrorCode::RateLimited);
+                assert_eq!(message, "Something went wrong");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let codes = [
+            ProtocolErrorCode::VersionMismatch,
+            ProtocolErrorCode::UnexpectedMessage,
+            ProtocolErrorCode::VerificationFailed,
+            ProtocolErrorCode::Rejected,
+            ProtocolErrorCode::AccessDenied,
+            ProtocolErrorCode::RateLimited,
+            ProtocolErrorCode::InternalError,
+        ];
+        for code in codes {
+            assert_eq!(ProtocolErrorCode::from_number(code.number()), code);
+        }
+
+        // Numbers older listeners sent, and ones from newer versions
+        let code_of = |json: &str| match Message::from_json(json).unwrap() {
+            Message::Error { code, .. } => code,
+            other => panic!("Expected Error, got {:?}", other),
+        };
+        assert_eq!(
+            code_of(r#"{"type":"Error","code":3,"message":"Expected KeyExchange message"}"#),
+            ProtocolErrorCode::UnexpectedMessage
+        );
+        assert_eq!(
+            code_of(r#"{"type":"Error","code":4,"message":"Key proof verification failed"}"#),
+            ProtocolErrorCode::VerificationFailed
+        );
+        assert_eq!(
+            code_of(r#"{"type":"Error","code":99,"message":"Something new"}"#),
+            ProtocolErrorCode::InternalError
+        );
+
+        let error = |code: ProtocolErrorCode| code.into_error("no".to_string());
+        assert!(matches!(
+            error(ProtocolErrorCode::VersionMismatch),
+            ConnectoError::VersionMismatch(_)
+        ));
+        assert!(matches!(
+            error(ProtocolErrorCode::VerificationFailed),
+            ConnectoError::VerificationFailed(_)
+        ));
+        assert!(matches!(
+            error(ProtocolErrorCode::Rejected),
+            ConnectoError::Rejected(_)
+        ));
+        assert!(matches!(
+            error(ProtocolErrorCode::AccessDenied),
+            ConnectoError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            error(ProtocolErrorCode::RateLimited),
+            ConnectoError::RateLimited(_)
+        ));
+    }
+
+    #[test]
+    fn test_message_pairing_complete_serialization() {
+        let msg = Message::PairingComplete {
+            ssh_user: "testuser".to_string(),
+            hostname: None,
+            identity: None,
+            ssh_port: None,
+            identity_proof: None,
+        };
+
+        let json = msg.to_json().unwrap();
+        // Older clients see the same message as before from non-private servers
+        assert!(!json.contains("hostname"));
+        let deserialized = Message::from_json(&json).unwrap();
+
+        match deserialized {
+            Message::PairingComplete {
+                ssh_user, hostname, ..
+            } => {
+                assert_eq!(ssh_user, "testuser");
+                assert_eq!(hostname, None);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_generate_verification_code() {
+        let code = generate_verification_code();
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_pin_matches() {
+        assert!(pin_matches("042917", "042917"));
+        assert!(pin_matches(" 042917\n", "042917"));
+        assert!(!pin_matches("042918", "042917"));
+        assert!(!pin_matches("0429", "042917"));
+        assert!(!pin_matches("0429170", "042917"));
+        assert!(!pin_matches("", "042917"));
+    }
+
+    #[test]
+    fn test_generate_verification_code_uniqueness() {
+        let codes: Vec<_> = (0..100).map(|_| generate_verification_code()).collect();
+        // Not all codes should be the same (statistically very unlikely)
+        let unique: std::collections::HashSet<_> = codes.iter().collect();
+        assert!(unique.len() > 1);
+    }
+
+    #[test]
+    fn test_handshake_client_creation() {
+        let client = HandshakeClient::new("Test Client");
+        assert_eq!(client.device_name, "Test Client");
+    }
+
+    #[test]
+    fn test_pairing_result() {
+        let result = PairingResult {
+            server_name: "Server".to_string(),
+            ssh_user: "user".to_string(),
+            verification_code: Some("1234".to_string()),
+            server_identity: None,
+            server_hostname: None,
+            clock_skew: None,
+            expires_at: None,
+            key_fingerprint: "SHA256:abc".to_string(),
+            fingerprint_confirmed: true,
+            host_keys: Vec::new(),
+            ssh_port: 22,
+            capabilities: Capabilities::SUPPORTED,
+        };
+
+        assert_eq!(result.server_name, "Server");
+        assert_eq!(result.ssh_user, "user");
+        assert_eq!(result.verification_code, Some("1234".to_string()));
+        assert_eq!(result.peer_name(), "Server");
+
+        let private = PairingResult {
+            server_name: "connecto-3fa9c2".to_string(),
+            server_hostname: Some("desk".to_string()),
+            ..result
+        };
+        assert_eq!(private.peer_name(), "desk");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_server_creation() {
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir);
+
+        let server = HandshakeServer::new(key_manager, "Test Server");
+        assert_eq!(server.device_name, "Test Server");
+        assert!(!server.require_verification);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_server_with_verification() {
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir);
+
+        let server = HandshakeServer::new(key_manager, "Test Server").with_verification(true);
+        assert!(server.require_verification);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_server_listen() {
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir);
+
+        let mut server = HandshakeServer::new(key_manager, "Test Server");
+        let addr = server.listen(0).await.unwrap(); // Port 0 = random available port
+
+        assert!(addr.port() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_server_shutdown() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let mut server = HandshakeServer::new(key_manager, "Test Server");
+        let addr = server.listen(0).await.unwrap();
+        let shutdown = server.shutdown_handle();
+
+        let (event_tx, _event_rx) = mpsc::channel(10);
+        let server_handle = tokio::spawn(async move { server.run(event_tx).await });
+        // A client that never finishes its handshake does not hold the server up
+        let _idle = TcpStream::connect(("127.0.0.1", addr.port()))
+            .await
+            .unwrap();
+
+        shutdown.shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(5), server_handle)
+            .await
+            .expect("server stops")
+            .unwrap()
+            .unwrap();
+
+        // The port is free again
+        let mut server = HandshakeServer::new(
+            KeyManager::with_dir(temp_dir.path().join(".ssh")),
+            "Test Server",
+        );
+        server.listen(addr.port()).await.unwrap();
+        server.shutdown_handle().shutdown();
+        let (event_tx, _event_rx) = mpsc::channel(10);
+        server.handle_one(event_tx).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_full_handshake() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir.clone());
+
+        // Start server
+        let host_key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+        let mut server = HandshakeServer::new(key_manager, "Test Server")
+            .with_host_keys(vec![
+                format!("{} root@desk", host_key),
+                "not a key".to_string(),
+            ])
+            .with_ssh_port(2222);
+        let addr = server.listen(0).await.unwrap();
+        let server_addr = format!("127.0.0.1:{}", addr.port());
+
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+
+        // Run server in background
+        let server_handle = tokio::spawn(async move { server.handle_one(event_tx).await });
+
+        // Give server time to start
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // Create client and key pair
+        let client = HandshakeClient::new("Test Client");
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+
+        // Perform pairing
+        let result = client.pair(&server_addr, &key_pair).await.unwrap();
+
+        assert_eq!(result.server_name, "Test Server");
+        assert!(!result.ssh_user.is_empty());
+        // Both clocks are this machine's
+        assert!(result.clock_skew.is_some_and(|skew| skew.abs() <= 1));
+        let fingerprint = key_pair.fingerprint().unwrap();
+        assert_eq!(result.key_fingerprint, fingerprint);
+        assert!(result.fingerprint_confirmed);
+        // Comments are dropped and keys that don't parse are skipped
+        assert_eq!(result.host_keys, [host_key]);
+        assert_eq!(result.ssh_port, 2222);
+        assert_eq!(result.capabilities, Capabilities::SUPPORTED);
+
+        // Verify key was added
+        let key_manager = KeyManager::with_dir(ssh_dir);
+        let keys = key_manager.list_authorized_keys().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].contains("test@connecto"));
+
+        // Wait for server to finish
+        server_handle.await.unwrap().unwrap();
+
+        // Verify events were sent, showing the same fingerprint
+        let mut events = Vec::new();
+        while let Ok(event) = event_rx.try_recv() {
+            events.push(event);
+        }
+        assert!(!events.is_empty());
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ServerEvent::KeyReceived { fingerprint: f, .. } if *f == fingerprint
+        )));
+    }
+
+    async fn start_server(
+        server: HandshakeServer,
+    ) -> (String, tokio::task::JoinHandle<Result<()>>) {
+        let mut server = server;
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, event_rx) = mpsc::channel(10);
+        let handle = tokio::spawn(async move {
+            let result = server.handle_one(event_tx).await;
+            drop(event_rx);
+            result
+        });
+        (format!("127.0.0.1:{}", addr.port()), handle)
+    }
+
+    async fn send(writer: &mut OwnedWriteHalf, msg: Message) {
+        writer
+            .write_all(msg.to_json().unwrap().as_bytes())
+            .await
+            .unwrap();
+    }
+
+    async fn recv(reader: &mut BufReader<OwnedReadHalf>) -> Message {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        Message::from_json(&line).unwrap()
+    }
+
+    /// Send a message after `HelloAck` of a [`FRAMED_VERSION`] session
+    async fn send_framed(writer: &mut (impl AsyncWrite + Unpin), msg: Message) {
+        Framing::LengthPrefixed.write(writer, &msg).await.unwrap();
+    }
+
+    /// Read a message after `HelloAck` of a [`FRAMED_VERSION`] session
+    async fn recv_framed(reader: &mut (impl AsyncBufRead + Unpin)) -> Message {
+        read_reply(reader, Framing::LengthPrefixed, &mut String::new())
+            .await
+            .unwrap()
+    }
+
+    /// A fresh device identity, kept in `temp_dir` under `name`
+    fn test_identity(temp_dir: &TempDir, name: &str) -> DeviceIdentity {
+        DeviceIdentity::load_or_create_at(&temp_dir.path().join(name)).unwrap()
+    }
+
+    /// `identity` as its holder proves it
+    fn proven(identity: &DeviceIdentity) -> VerifiedIdentity {
+        identity.prove("nonce").unwrap().verify("nonce").unwrap()
+    }
+
+    /// A server that answers one Hello with the `HelloAck` made by `ack` from
+    /// the client's nonce, then hangs up
+    async fn fake_server(ack: impl FnOnce(String) -> Message + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let Message::Hello { nonce, .. } = recv(&mut reader).await else {
+                panic!("Expected Hello");
+            };
+            send(&mut writer, ack(nonce.unwrap_or_default())).await;
+        });
+        addr.to_string()
+    }
+
+    /// A `HelloAck` from "Desk" announcing `identity` with `identity_proof`,
+    /// in the last version before the channel is encrypted
+    fn desk_ack(identity: &str, identity_proof: Option<IdentityProof>) -> Message {
+        Message::HelloAck {
+            version: ENCRYPTED_VERSION - 1,
+            device_name: "Desk".to_string(),
+            verification_code: None,
+            identity: Some(identity.to_string()),
+            pin_required: false,
+            timestamp: None,
+            capabilities: None,
+            users: Vec::new(),
+            identity_proof,
+            nonce: None,
+            key_share: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_needs_proof_of_server_identity() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let desk = test_identity(&temp_dir, "desk");
+        let impostor = test_identity(&temp_dir, "impostor");
+        let trust = TrustStore::with_path(temp_dir.path().join("known_peers.json"));
+        trust.pin("Desk", &proven(&desk)).unwrap();
+        let client = HandshakeClient::new("Test Client").with_trust_store(trust);
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+
+        // Repeating the pinned fingerprint without a proof counts for nothing
+        let fingerprint = desk.fingerprint().to_string();
+        let addr = fake_server(move |_| desk_ack(&fingerprint, None)).await;
+        let err = client.pair(&addr, &key_pair).await.unwrap_err();
+        assert!(matches!(err, ConnectoError::IdentityMismatch(_)));
+
+        // Nor does a proof made with another key
+        let fingerprint = desk.fingerprint().to_string();
+        let addr =
+            fake_server(move |nonce| desk_ack(&fingerprint, Some(impostor.prove(&nonce).unwrap())))
+                .await;
+        let err = client.pair(&addr, &key_pair).await.unwrap_err();
+        assert!(matches!(err, ConnectoError::IdentityMismatch(_)));
+
+        // Nor a proof replayed from another session
+        let fingerprint = desk.fingerprint().to_string();
+        let replayed = desk.prove(&generate_nonce()).unwrap();
+        let addr = fake_server(move |_| desk_ack(&fingerprint, Some(replayed))).await;
+        let err = client.pair(&addr, &key_pair).await.unwrap_err();
+        assert!(matches!(err, ConnectoError::IdentityMismatch(_)));
+    }
+
+    #[tokio::test]
+    async fn test_server_needs_proof_of_client_identity() {
+        let temp_dir = TempDir::new().unwrap();
+        let laptop = test_identity(&temp_dir, "laptop");
+        let impostor = test_identity(&temp_dir, "impostor");
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let server = HandshakeServer::new(key_manager, "Test Server");
+        let (server_addr, handle) = start_server(server).await;
+
+        let (reader, mut writer) = TcpStream::connect(&server_addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(
+            &mut writer,
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                device_name: "Laptop".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: Some(laptop.fingerprint().to_string()),
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        let Message::HelloAck {
+            nonce: Some(nonce), ..
+        } = recv(&mut reader).await
+        else {
+            panic!("Expected HelloAck with a nonce");
+        };
+
+        // Claiming the laptop's identity with another key is refused
+        send_framed(
+            &mut writer,
+            Message::IdentityProof {
+                proof: impostor.prove(&nonce).unwrap(),
+            },
+        )
+        .await;
+        match recv_framed(&mut reader).await {
+            Message::Error { code, .. } => {
+                assert_eq!(code, ProtocolErrorCode::VerificationFailed)
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_verify_identity() {
+        let temp_dir = TempDir::new().unwrap();
+        let identity = test_identity(&temp_dir, "identity");
+        let client = HandshakeClient::new("Test Client");
+
+        let server = HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join("a")), "Desk")
+            .with_identity(&identity);
+        let (server_addr, handle) = start_server(server).await;
+        let proven = client.verify_identity(&server_addr).await.unwrap();
+        assert_eq!(proven.fingerprint(), identity.fingerprint());
+        handle.abort();
+
+        // A private server proves nothing to strangers
+        let server = HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join("b")), "Desk")
+            .with_identity(&identity)
+            .with_privacy(true);
+        let (server_addr, handle) = start_server(server).await;
+        let err = client.verify_identity(&server_addr).await.unwrap_err();
+        assert!(matches!(err, ConnectoError::VerificationFailed(_)));
+        handle.abort();
+
+        // An unproven claim is no identity
+        let fingerprint = identity.fingerprint().to_string();
+        let addr = fake_server(move |_| desk_ack(&fingerprint, None)).await;
+        let err = client.verify_identity(&addr).await.unwrap_err();
+        assert!(matches!(err, ConnectoError::VerificationFailed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_server_identity_reported_to_client() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let identity = test_identity(&temp_dir, "identity");
+        let server = HandshakeServer::new(key_manager, "Test Server").with_identity(&identity);
+        let (server_addr, handle) = start_server(server).await;
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let result = HandshakeClient::new("Test Client")
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            result
+                .server_identity
+                .as_ref()
+                .map(VerifiedIdentity::fingerprint),
+            Some(identity.fingerprint())
+        );
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_key_lifetime() {
+        use crate::keys::{authorized_key_expiry, KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir.clone());
+        let store = PairingStore::with_path(temp_dir.path().join("pairings.json"));
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Test Server")
+            .with_pairing_store(store.clone());
+        let (server_addr, handle) = start_server(server).await;
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let before = clock::unix_now() as u64;
+        let result = HandshakeClient::new("Test Client")
+            .with_key_lifetime(Duration::from_secs(3_600))
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+
+        let expires_at = result.expires_at.unwrap();
+        assert!((before + 3_600..=before + 3_602).contains(&expires_at));
+        let keys = key_manager.list_authorized_keys().unwrap();
+        let installed = authorized_key_expiry(&keys[0]).unwrap();
+        assert!(installed.abs_diff(expires_at) <= 1);
+        assert_eq!(store.all().unwrap()[0].expires_at, Some(installed));
+
+        // Before version 5 the lifetime is not sent, so the key is
+        // authorized for good again
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir), "Test Server");
+        let (server_addr, handle) = start_server(server).await;
+        let result = HandshakeClient::new("Test Client")
+            .with_key_lifetime(Duration::from_secs(3_600))
+            .pair_with_version(&server_addr, &key_pair, PIN_VERSION)
+            .await
+            .unwrap()
+            .unwrap();
+        handle.await.unwrap().unwrap();
+        assert_eq!(result.expires_at, None);
+        let keys = key_manager.list_authorized_keys().unwrap();
+        assert_eq!(authorized_key_expiry(&keys[0]), None);
+    }
+
+    #[tokio::test]
+    async fn test_user_selection() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir.clone());
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let missing = "no-such-connecto-user";
+
+        // A server that offers no choice is refused before the key is sent
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Test Server");
+        let (server_addr, handle) = start_server(server).await;
+        let err = HandshakeClient::new("Test Client")
+            .with_user("deploy")
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ConnectoError::PermissionDenied(_)), "{}", err);
+        // The server takes the hang-up for a probe and keeps listening
+        handle.abort();
+
+        // So is an account it does not list
+        let offering = || {
+            HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Test Server")
+                .with_users(vec![missing.to_string()])
+        };
+        let (server_addr, handle) = start_server(offering()).await;
+        let err = HandshakeClient::new("Test Client")
+            .with_user("deploy")
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("it offers {}, {}", current_user(), missing)),
+            "{}",
+            err
+        );
+        handle.abort();
+
+        // A listed account that does not exist is refused by the server
+        let (server_addr, handle) = start_server(offering()).await;
+        let err = HandshakeClient::new("Test Client")
+            .with_user(missing)
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("has no account"), "{}", err);
+        handle.abort();
+        assert!(key_manager.list_authorized_keys().unwrap().is_empty());
+
+        // The server's own account is always offered
+        let (server_addr, handle) = start_server(offering()).await;
+        let result = HandshakeClient::new("Test Client")
+            .with_user(&current_user())
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+        assert_eq!(result.ssh_user, current_user());
+        assert_eq!(key_manager.list_authorized_keys().unwrap().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_server_installs_for_account() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+        use std::os::unix::fs::MetadataExt;
+
+        // Handing files to ourselves needs no privileges
+        let own_dir = TempDir::new().unwrap();
+        let home = TempDir::new().unwrap();
+        let metadata = std::fs::metadata(home.path()).unwrap();
+        let account = Account {
+            name: "deploy".to_string(),
+            home: home.path().to_path_buf(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        };
+        let server = HandshakeServer::new(
+            KeyManager::with_dir(own_dir.path().join(".ssh")),
+            "Test Server",
+        )
+        .with_account(&account)
+        .with_users(vec!["backup".to_string()]);
+        let (server_addr, handle) = start_server(server).await;
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let result = HandshakeClient::new("Test Client")
+            .with_user("deploy")
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+
+        assert_eq!(result.ssh_user, "deploy");
+        let keys = KeyManager::for_account(&account)
+            .list_authorized_keys()
+            .unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(!own_dir.path().join(".ssh").exists());
+    }
+
+    #[tokio::test]
+    async fn test_key_options_and_source_restriction() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir.clone());
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir), "Test Server")
+            .with_key_options("no-port-forwarding,no-agent-forwarding".parse().unwrap())
+            .with_source_restriction(true);
+        let (server_addr, handle) = start_server(server).await;
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        HandshakeClient::new("Test Client")
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+
+        let keys = key_manager.list_authorized_keys().unwrap();
+        assert_eq!(
+            keys,
+            [format!(
+                "from=\"127.0.0.1\",no-port-forwarding,no-agent-forwarding {}",
+                key_pair.public_key.trim()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_private_server_reveals_hostname_after_pairing() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let identity = test_identity(&temp_dir, "identity");
+        let server = HandshakeServer::new(key_manager, "connecto-3fa9c2")
+            .with_identity(&identity)
+            .with_privacy(true);
+        let (server_addr, handle) = start_server(server).await;
+
+        // An unpaired client only learns the pseudonym
+        let (reader, mut writer) = TcpStream::connect(&server_addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(
+            &mut writer,
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                device_name: "Scanner".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        match recv(&mut reader).await {
+            Message::HelloAck {
+                device_name,
+                identity,
+                ..
+            } => {
+                assert_eq!(device_name, "connecto-3fa9c2");
+                assert_eq!(identity, None);
+            }
+            other => panic!("Expected HelloAck, got {:?}", other),
+        }
+        drop(writer);
+
+        let trust = TrustStore::with_path(temp_dir.path().join("known_peers.json"));
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let result = HandshakeClient::new("Test Client")
+            .with_trust_store(trust.clone())
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+
+        assert_eq!(result.server_name, "connecto-3fa9c2");
+        assert_eq!(result.server_hostname, Some(get_hostname()));
+        assert_eq!(
+            result
+                .server_identity
+                .as_ref()
+                .map(VerifiedIdentity::fingerprint),
+            Some(identity.fingerprint())
+        );
+        // The identity is pinned under the real name, not the pseudonym
+        assert!(trust.get("connecto-3fa9c2").unwrap().is_none());
+        assert_eq!(
+            trust
+                .get(&get_hostname())
+                .unwrap()
+                .unwrap()
+                .fingerprint
+                .as_deref(),
+            Some(identity.fingerprint())
+        );
+    }
+
+    #[test]
+    fn test_approval_request_closes() {
+        let address = "192.168.1.42:50123".parse().unwrap();
+        let (request, response) =
+            ApprovalRequest::new("laptop", address, "SHA256:abc", "me@laptop");
+        assert!(!request.is_closed());
+        // The server stops waiting, e.g. after the approval timeout
+        drop(response);
+        assert!(request.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_approval_accepts_and_rejects() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        for approve in [true, false] {
+            let temp_dir = TempDir::new().unwrap();
+            let audit = AuditLog::with_path(temp_dir.path().join("audit.jsonl"));
+            let key_manager =
+                KeyManager::with_dir(temp_dir.path().join(".ssh")).with_audit_log(audit.clone());
+            let (approval_tx, mut approval_rx) = mpsc::channel(1);
+            let log = DecisionLog::with_path(temp_dir.path().join("decisions.jsonl"));
+            let server = HandshakeServer::new(key_manager, "Test Server")
+                .with_approval(approval_tx)
+                .with_decision_log(log.clone());
+            let (server_addr, _handle) = start_server(server).await;
+
+            let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+            let expected_fingerprint = key_pair.fingerprint().unwrap();
+            let decide = tokio::spawn(async move {
+                let request = approval_rx.recv().await.unwrap();
+                assert_eq!(request.device_name, "Test Client");
+                assert_eq!(request.fingerprint, expected_fingerprint);
+                assert_eq!(request.comment, "test@connecto");
+                request.respond(approve);
+            });
+
+            let result = HandshakeClient::new("Test Client")
+                .pair(&server_addr, &key_pair)
+                .await;
+            decide.await.unwrap();
+
+            let installed = KeyManager::with_dir(temp_dir.path().join(".ssh"))
+                .list_authorized_keys()
+                .unwrap();
+            let decisions = log.all().unwrap();
+            assert_eq!(decisions.len(), 1);
+            assert_eq!(decisions[0].peer_name, "Test Client");
+            assert_eq!(decisions[0].approver, Some(current_user()));
+            let events: Vec<_> = audit
+                .all()
+                .unwrap()
+                .iter()
+                .map(|record| record.event.name())
+                .collect();
+            if approve {
+                assert!(result.is_ok());
+                assert_eq!(installed.len(), 1);
+                assert_eq!(decisions[0].decision, Decision::Accepted);
+                assert_eq!(events, ["key_installed", "pairing_accepted"]);
+            } else {
+                let err = result.unwrap_err().to_string();
+                assert!(err.contains("rejected"), "{}", err);
+                assert!(installed.is_empty());
+                assert_eq!(decisions[0].decision, Decision::Rejected);
+                assert_eq!(events, ["pairing_rejected"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_approval_applies_default() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let cases = [
+            (ApprovalTimeoutAction::Reject, PROTOCOL_VERSION, false),
+            (
+                ApprovalTimeoutAction::AcceptIfVerified,
+                PROTOCOL_VERSION,
+                true,
+            ),
+            // Without a key proof the client is never accepted unanswered
+            (
+                ApprovalTimeoutAction::AcceptIfVerified,
+                MIN_PROTOCOL_VERSION,
+                false,
+            ),
+        ];
+        for (action, version, expect_accepted) in cases {
+            let temp_dir = TempDir::new().unwrap();
+            let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+            let (approval_tx, mut approval_rx) = mpsc::channel(1);
+            let log = DecisionLog::with_path(temp_dir.path().join("decisions.jsonl"));
+            let server = HandshakeServer::new(key_manager, "Test Server")
+                .with_approval(approval_tx)
+                .with_approval_timeout(Duration::from_millis(300), action)
+                .with_decision_log(log.clone())
+                .with_key_proof(false);
+            let (server_addr, _handle) = start_server(server).await;
+
+            // Hold the request without ever answering it
+            let ignore = tokio::spawn(async move {
+                let mut request = approval_rx.recv().await.unwrap();
+                request.closed().await;
+            });
+
+            let (event_tx, mut event_rx) = mpsc::channel(10);
+            let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+            let result = HandshakeClient::new("Test Client")
+                .with_events(event_tx)
+                .pair_with_version(&server_addr, &key_pair, version)
+                .await;
+            ignore.await.unwrap();
+
+            let decisions = log.all().unwrap();
+            assert_eq!(decisions.len(), 1);
+            assert_eq!(decisions[0].approver, None);
+            assert!(decisions[0]
+                .reason
+                .as_deref()
+                .unwrap()
+                .starts_with("No answer"));
+            if expect_accepted {
+                assert!(result.unwrap().is_some());
+                assert_eq!(decisions[0].decision, Decision::Accepted);
+            } else {
+                let err = result.unwrap_err().to_string();
+                assert!(err.contains("not answered in time"), "{}", err);
+                assert_eq!(decisions[0].decision, Decision::Rejected);
+            }
+
+            // Only clients that understand the notice are told to wait
+            let notified = matches!(
+                event_rx.try_recv(),
+                Ok(ClientEvent::AwaitingApproval { .. })
+            );
+            assert_eq!(notified, version >= APPROVAL_PENDING_VERSION);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_records_incoming_pairing() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let store = PairingStore::with_path(temp_dir.path().join("pairings.json"));
+        let server =
+            HandshakeServer::new(key_manager, "Test Server").with_pairing_store(store.clone());
+        let (server_addr, handle) = start_server(server).await;
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        HandshakeClient::new("Test Client")
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+
+        let records = store.all().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer_name, "Test Client");
+        assert_eq!(records[0].direction, PairingDirection::Incoming);
+        assert_eq!(records[0].fingerprint, key_pair.fingerprint().unwrap());
+        assert_eq!(records[0].address, "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_server_follows_client_identity() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let store = PairingStore::with_path(temp_dir.path().join("pairings.json"));
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let identity = test_identity(&temp_dir, "identity");
+
+        // The client pairs, is renamed, and pairs again
+        for name in ["Laptop", "Work Laptop"] {
+            let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+            let server =
+                HandshakeServer::new(key_manager, "Test Server").with_pairing_store(store.clone());
+            let (server_addr, handle) = start_server(server).await;
+            HandshakeClient::new(name)
+                .with_identity(&identity)
+                .pair(&server_addr, &key_pair)
+                .await
+                .unwrap();
+            handle.await.unwrap().unwrap();
+        }
+
+        let records = store.for_identity(identity.fingerprint()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|r| r.peer_name == "Work Laptop"));
+    }
+
+    #[tokio::test]
+    async fn test_access_list_refuses_client() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let access = AccessList {
+            allow: vec!["127.0.0.0/8".parse().unwrap()],
+            allow_names: vec!["alice-*".to_string()],
+            ..Default::default()
+        };
+        let mut server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Server")
+            .with_access_list(access);
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+        let handle = tokio::spawn(async move { server.handle_one(event_tx).await });
+        let server_addr = format!("127.0.0.1:{}", addr.port());
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+
+        let error = HandshakeClient::new("bob-laptop")
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ConnectoError::PermissionDenied(_)));
+        assert!(KeyManager::with_dir(ssh_dir.clone())
+            .list_authorized_keys()
+            .unwrap()
+            .is_empty());
+
+        // The refusal does not end the session
+        HandshakeClient::new("alice-laptop")
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+
+        let mut refused = Vec::new();
+        while let Ok(event) = event_rx.try_recv() {
+            if let ServerEvent::AccessDenied {
+                device_name,
+                reason,
+                ..
+            } = event
+            {
+                refused.push((device_name, reason));
+            }
+        }
+        assert_eq!(
+            refused,
+            [(
+                "bob-laptop".to_string(),
+                "'bob-laptop' is not an allowed device name".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let mut server = HandshakeServer::new(key_manager, "Server").with_limits(HandshakeLimits {
+            max_handshakes: 1,
+            connections_per_minute: 2,
+            step_timeout: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let addr = server.listen(0).await.unwrap();
+        let shutdown = server.shutdown_handle();
+        let (event_tx, _event_rx) = mpsc::channel(10);
+        let handle = tokio::spawn(async move { server.run(event_tx).await });
+        let connect = || async {
+            let (reader, writer) = TcpStream::connect(("127.0.0.1", addr.port()))
+                .await
+                .unwrap()
+                .into_split();
+            (BufReader::new(reader), writer)
         };
 
+        // The first client takes the only handshake slot without saying anything
+        let (mut idle, _idle_writer) = connect().await;
+        let (mut busy, _busy_writer) = connect().await;
+        match recv(&mut busy).await {
+            Message::Error { code, message } => {
+                assert_eq!(code, ProtocolErrorCode::RateLimited);
+                assert!(message.contains("Busy"), "{}", message);
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+
+        // The silent client is dropped after the step timeout
+        let mut line = String::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), idle.read_line(&mut line))
+            .await
+            .expect("idle client is dropped");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        // A third connection within the minute is over the rate limit
+        let (mut limited, _limited_writer) = connect().await;
+        match recv(&mut limited).await {
+            Message::Error { code, message } => {
+                assert_eq!(code, ProtocolErrorCode::RateLimited);
+                assert!(message.contains("Too many connections"), "{}", message);
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+
+        shutdown.shutdown();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_refuses_long_messages() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let mut server = HandshakeServer::new(key_manager, "Server").with_limits(HandshakeLimits {
+            max_message_len: 256,
+            ..Default::default()
+        });
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(10);
+        let handle = tokio::spawn(async move { server.handle_one(event_tx).await });
+
+        let (reader, mut writer) = TcpStream::connect(("127.0.0.1", addr.port()))
+            .await
+            .unwrap()
+            .into_split();
+        let mut reader = BufReader::new(reader);
+        // A Hello that never ends is cut off at the limit, not buffered
+        writer.write_all(&[b'x'; 4096]).await.unwrap();
+        let mut line = String::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .expect("client is dropped");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_reports_clock_skew() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let store = PairingStore::with_path(temp_dir.path().join("pairings.json"));
+        // A version 1 client, so it needs no key proof
+        let mut server = HandshakeServer::new(key_manager, "Test Server")
+            .with_pairing_store(store.clone())
+            .with_key_proof(false);
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+        let handle = tokio::spawn(async move { server.handle_one(event_tx).await });
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "fast@connecto").unwrap();
+        let (reader, mut writer) = TcpStream::connect(("127.0.0.1", addr.port()))
+            .await
+            .unwrap()
+            .into_split();
+        let mut reader = BufReader::new(reader);
+
+        // A client whose clock runs an hour ahead
+        send(
+            &mut writer,
+            Message::Hello {
+                version: 1,
+                device_name: "Fast Clock".to_string(),
+                timestamp: Some(clock::unix_now() + 3_600),
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        match recv(&mut reader).await {
+            Message::HelloAck { timestamp, .. } => assert!(timestamp.is_some()),
+            other => panic!("Expected HelloAck, got {:?}", other),
+        }
+        send(
+            &mut writer,
+            Message::KeyExchange {
+                public_key: key_pair.public_key.clone(),
+                comment: key_pair.comment.clone(),
+                expires_in: None,
+                fingerprint: None,
+                user: None,
+            },
+        )
+        .await;
+        assert!(matches!(
+            recv(&mut reader).await,
+            Message::KeyAccepted { .. }
+        ));
+        assert!(matches!(
+            recv(&mut reader).await,
+            Message::PairingComplete { .. }
+        ));
+        handle.await.unwrap().unwrap();
+
+        let mut skews = Vec::new();
+        while let Ok(event) = event_rx.try_recv() {
+            if let ServerEvent::ClockSkew {
+                device_name,
+                skew_secs,
+            } = event
+            {
+                assert_eq!(device_name, "Fast Clock");
+                skews.push(skew_secs);
+            }
+        }
+        assert_eq!(skews.len(), 1);
+        assert!((3_599..=3_601).contains(&skews[0]));
+
+        let records = store.all().unwrap();
+        assert_eq!(records[0].clock_skew, Some(skews[0]));
+    }
+
+    #[tokio::test]
+    async fn test_client_pins_server_identity() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let trust = TrustStore::with_path(temp_dir.path().join("known_peers.json"));
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let client = HandshakeClient::new("Test Client").with_trust_store(trust.clone());
+        let desk = test_identity(&temp_dir, "desk");
+        let impostor = test_identity(&temp_dir, "impostor");
+
+        // First pairing pins the identity
+        let server = HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join("a")), "Desk")
+            .with_identity(&desk);
+        let (server_addr, handle) = start_server(server).await;
+        client.pair(&server_addr, &key_pair).await.unwrap();
+        handle.await.unwrap().unwrap();
+        assert_eq!(
+            trust.get("Desk").unwrap().unwrap().fingerprint.as_deref(),
+            Some(desk.fingerprint())
+        );
+
+        // An impostor with the same name never receives our key
+        let impostor_dir = temp_dir.path().join("b");
+        let server = HandshakeServer::new(KeyManager::with_dir(impostor_dir.clone()), "Desk")
+            .with_identity(&impostor);
+        let (server_addr, _handle) = start_server(server).await;
+        let err = client.pair(&server_addr, &key_pair).await.unwrap_err();
+        assert!(matches!(err, ConnectoError::IdentityMismatch(_)));
+        assert!(!impostor_dir.join("authorized_keys").exists());
+
+        // Warn mode pairs anyway and re-pins
+        let client = client.with_trust_mode(TrustMode::Warn);
+        let server = HandshakeServer::new(KeyManager::with_dir(impostor_dir), "Desk")
+            .with_identity(&impostor);
+        let (server_addr, handle) = start_server(server).await;
+        client.pair(&server_addr, &key_pair).await.unwrap();
+        handle.await.unwrap().unwrap();
+        assert_eq!(
+            trust.get("Desk").unwrap().unwrap().fingerprint.as_deref(),
+            Some(impostor.fingerprint())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_checks_address_pins() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let desk = test_identity(&temp_dir, "desk-id");
+        let impostor = test_identity(&temp_dir, "impostor-id");
+        let pins = BTreeMap::from([("127.0.0.1".to_string(), desk.fingerprint().to_string())]);
+        let client = HandshakeClient::new("Test Client").with_address_pins(pins);
+
+        // A new name doesn't hide a different device at a pinned address
+        let impostor_dir = temp_dir.path().join("impostor");
+        let server =
+            HandshakeServer::new(KeyManager::with_dir(impostor_dir.clone()), "Renamed Desk")
+                .with_identity(&impostor);
+        let (server_addr, _handle) = start_server(server).await;
+        let err = client.pair(&server_addr, &key_pair).await.unwrap_err();
+        assert!(err.to_string().contains("The device at 127.0.0.1"));
+        assert!(!impostor_dir.join("authorized_keys").exists());
+
+        // The pinned device pairs under any name
+        let server =
+            HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join("desk")), "Desk")
+                .with_identity(&desk);
+        let (server_addr, handle) = start_server(server).await;
+        client.pair(&server_addr, &key_pair).await.unwrap();
+        handle.await.unwrap().unwrap();
+
+        // Accepting the new identity pairs with the other device
+        let server =
+            HandshakeServer::new(KeyManager::with_dir(impostor_dir.clone()), "Renamed Desk")
+                .with_identity(&impostor);
+        let (server_addr, handle) = start_server(server).await;
+        client
+            .with_trust_mode(TrustMode::Warn)
+            .pair(&server_addr, &key_pair)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(impostor_dir.join("authorized_keys").exists());
+    }
+
+    #[test]
+    fn test_message_key_challenge_serialization() {
+        let msg = Message::KeyChallenge {
+            nonce: generate_nonce(),
+        };
         let json = msg.to_json().unwrap();
-        assert!(json.contains("Hello"));
-        assert!(json.contains("Test Device"));
-        assert!(json.ends_with('\n'));
+        assert!(json.contains("KeyChallenge"));
+
+        match Message::from_json(&json).unwrap() {
+            Message::KeyChallenge { nonce } => assert_eq!(nonce.len(), 64),
+            _ => panic!("Wrong message type"),
+        }
     }
 
     #[test]
-    fn test_message_hello_deserialization() {
-        let json = r#"{"type":"Hello","version":1,"device_name":"Test"}"#;
-        let msg = Message::from_json(json).unwrap();
+    fn test_generate_nonce_uniqueness() {
+        assert_ne!(generate_nonce(), generate_nonce());
+    }
 
-        match msg {
-            Message::Hello {
+    #[test]
+    fn test_message_revoke_serialization() {
+        let msg = Message::Revoke {
+            version: REVOKE_VERSION,
+            device_name: "laptop".to_string(),
+            fingerprint: "SHA256:abc".to_string(),
+        };
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("Revoke"));
+
+        match Message::from_json(&json).unwrap() {
+            Message::Revoke {
                 version,
                 device_name,
+                fingerprint,
             } => {
-                assert_eq!(version, 1);
-                assert_eq!(device_name, "Test");
+                assert_eq!(version, REVOKE_VERSION);
+                assert_eq!(device_name, "laptop");
+                assert_eq!(fingerprint, "SHA256:abc");
             }
             _ => panic!("Wrong message type"),
         }
     }
 
-    #[test]
-    fn test_message_hello_ack_serialization() {
-        let msg = Message::HelloAck {
-            version: 1,
-            device_name: "Server".to_string(),
-            verification_code: Some("1234".to_string()),
+    #[tokio::test]
+    async fn test_revoke_removes_key() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir.clone());
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let other = SshKeyPair::generate(KeyAlgorithm::Ed25519, "other@connecto").unwrap();
+        key_manager
+            .add_authorized_key(&key_pair.public_key)
+            .unwrap();
+        key_manager.add_authorized_key(&other.public_key).unwrap();
+
+        let mut server = HandshakeServer::new(KeyManager::with_dir(ssh_dir), "Server");
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+        let handle = tokio::spawn(async move { server.handle_one(event_tx).await });
+        let server_addr = format!("127.0.0.1:{}", addr.port());
+
+        HandshakeClient::new("laptop")
+            .revoke(&server_addr, &key_pair)
+            .await
+            .unwrap();
+
+        let keys = key_manager.list_authorized_keys().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].contains("other@connecto"));
+
+        let fingerprint = key_pair.fingerprint().unwrap();
+        let mut revoked = false;
+        while let Some(event) = event_rx.recv().await {
+            if let ServerEvent::KeyRevoked {
+                device_name,
+                fingerprint: f,
+                ..
+            } = event
+            {
+                assert_eq!(device_name, "laptop");
+                assert_eq!(f, fingerprint);
+                revoked = true;
+                break;
+            }
+        }
+        assert!(revoked);
+
+        // Revoking the same key again finds nothing to remove
+        let error = HandshakeClient::new("laptop")
+            .revoke(&server_addr, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ConnectoError::VerificationFailed(_)));
+
+        // A revocation does not end the wait for a pairing
+        assert!(!handle.is_finished());
+        handle.abort();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_revoke_searches_offered_accounts() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+        use std::os::unix::fs::MetadataExt;
+
+        let own_dir = TempDir::new().unwrap();
+        let home = TempDir::new().unwrap();
+        let metadata = std::fs::metadata(home.path()).unwrap();
+        let account = Account {
+            name: "deploy".to_string(),
+            home: home.path().to_path_buf(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
         };
+        let account_keys = KeyManager::for_account(&account);
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        account_keys
+            .add_authorized_key(&key_pair.public_key)
+            .unwrap();
 
-        let json = msg.to_json().unwrap();
-        let deserialized = Message::from_json(&json).unwrap();
+        // The key was installed for an account the server offers, not its own
+        let mut server =
+            HandshakeServer::new(KeyManager::with_dir(own_dir.path().join(".ssh")), "Server")
+                .with_users(vec!["backup".to_string(), "deploy".to_string()]);
+        server.lookup_account = Arc::new(move |name| match name {
+            "deploy" => Ok(account.clone()),
+            _ => Err(ConnectoError::Io(std::io::ErrorKind::NotFound.into())),
+        });
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(10);
+        let handle = tokio::spawn(async move { server.handle_one(event_tx).await });
 
-        match deserialized {
-            Message::HelloAck {
+        HandshakeClient::new("laptop")
+            .revoke(&format!("127.0.0.1:{}", addr.port()), &key_pair)
+            .await
+            .unwrap();
+        handle.abort();
+
+        assert!(account_keys.list_authorized_keys().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_needs_the_key() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let key_manager = KeyManager::with_dir(ssh_dir.clone());
+        let victim = SshKeyPair::generate(KeyAlgorithm::Ed25519, "victim@connecto").unwrap();
+        let impostor = SshKeyPair::generate(KeyAlgorithm::Ed25519, "impostor@connecto").unwrap();
+        key_manager.add_authorized_key(&victim.public_key).unwrap();
+
+        // Asks to revoke `fingerprint` as `device_name`, signing with `key_pair`
+        async fn refusal(
+            access: AccessList,
+            ssh_dir: &std::path::Path,
+            device_name: &str,
+            fingerprint: String,
+            key_pair: &SshKeyPair,
+        ) -> Message {
+            let mut server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.into()), "Server")
+                .with_access_list(access);
+            let addr = server.listen(0).await.unwrap();
+            let (event_tx, _event_rx) = mpsc::channel(10);
+            let handle = tokio::spawn(async move { server.handle_one(event_tx).await });
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            send(
+                &mut writer,
+                Message::Revoke {
+                    version: REVOKE_VERSION,
+                    device_name: device_name.to_string(),
+                    fingerprint,
+                },
+            )
+            .await;
+            // Everyone is challenged, whether or not the key is authorized
+            let Message::KeyChallenge { nonce } = recv(&mut reader).await else {
+                panic!("Expected KeyChallenge");
+            };
+            let signature = key_pair.sign(REVOKE_NAMESPACE, nonce.as_bytes()).unwrap();
+            send(&mut writer, Message::KeyProof { signature }).await;
+            let reply = recv(&mut reader).await;
+            handle.abort();
+            reply
+        }
+
+        let victim_fingerprint = victim.fingerprint().unwrap();
+        let refusals = [
+            // Signed with another key
+            refusal(
+                AccessList::default(),
+                &ssh_dir,
+                "impostor",
+                victim_fingerprint.clone(),
+                &impostor,
+            )
+            .await,
+            // A key that is not authorized
+            refusal(
+                AccessList::default(),
+                &ssh_dir,
+                "impostor",
+                impostor.fingerprint().unwrap(),
+                &impostor,
+            )
+            .await,
+            // The right key, from a device the access list refuses
+            refusal(
+                AccessList {
+                    allow_names: vec!["alice-*".to_string()],
+                    ..Default::default()
+                },
+                &ssh_dir,
+                "victim",
+                victim_fingerprint.clone(),
+                &victim,
+            )
+            .await,
+        ];
+        for reply in &refusals {
+            let Message::Error { code, message } = reply else {
+                panic!("Expected Error, got {:?}", reply);
+            };
+            assert_eq!(*code, ProtocolErrorCode::VerificationFailed);
+            assert!(message.starts_with("Server cannot revoke"), "{}", message);
+        }
+        assert_eq!(format!("{:?}", refusals[0]), format!("{:?}", refusals[2]));
+
+        assert_eq!(key_manager.list_authorized_keys().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_client_pairs_without_proof() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        // Only when the listener explicitly allows it
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Server")
+            .with_key_proof(false);
+        let (addr, handle) = start_server(server).await;
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "legacy@connecto").unwrap();
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+
+        send(
+            &mut writer,
+            Message::Hello {
+                version: 1,
+                device_name: "Legacy".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        match recv(&mut reader).await {
+            Message::HelloAck { version, .. } => assert_eq!(version, 1),
+            other => panic!("Expected HelloAck, got {:?}", other),
+        }
+
+        send(
+            &mut writer,
+            Message::KeyExchange {
+                public_key: key_pair.public_key.clone(),
+                comment: key_pair.comment.clone(),
+                expires_in: None,
+                fingerprint: None,
+                user: None,
+            },
+        )
+        .await;
+        assert!(matches!(
+            recv(&mut reader).await,
+            Message::KeyAccepted { .. }
+        ));
+        assert!(matches!(
+            recv(&mut reader).await,
+            Message::PairingComplete { .. }
+        ));
+
+        handle.await.unwrap().unwrap();
+        let keys = KeyManager::with_dir(ssh_dir)
+            .list_authorized_keys()
+            .unwrap();
+        assert_eq!(keys.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_framing_follows_negotiated_version() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let server = HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join(".ssh")), "S");
+        let (addr, handle) = start_server(server).await;
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@connecto").unwrap();
+        let key_exchange = Message::KeyExchange {
+            public_key: key_pair.public_key.clone(),
+            comment: key_pair.comment.clone(),
+            expires_in: None,
+            fingerprint: None,
+            user: None,
+        };
+
+        for version in [FRAMED_VERSION - 1, FRAMED_VERSION] {
+            let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+            let mut reader = BufReader::new(reader);
+            let hello = Message::Hello {
                 version,
-                device_name,
-                verification_code,
-            } => {
-                assert_eq!(version, 1);
-                assert_eq!(device_name, "Server");
-                assert_eq!(verification_code, Some("1234".to_string()));
+                device_name: "Client".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            };
+            send(&mut writer, hello).await;
+            match recv(&mut reader).await {
+                Message::HelloAck {
+                    version: agreed, ..
+                } => assert_eq!(agreed, version),
+                other => panic!("Expected HelloAck, got {:?}", other),
             }
-            _ => panic!("Wrong message type"),
+
+            let framing = framing_for(version);
+            framing.write(&mut writer, &key_exchange).await.unwrap();
+            let challenge = read_reply(&mut reader, framing, &mut String::new()).await;
+            assert!(
+                matches!(challenge, Ok(Message::KeyChallenge { .. })),
+                "version {}: {:?}",
+                version,
+                challenge
+            );
+        }
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pair_over_lines_sends_one_message_per_line() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        // Carry each line across like someone pasting it, checking it is a
+        // whole message
+        async fn paste(from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin) -> usize {
+            let mut lines = BufReader::new(from).lines();
+            let mut pasted = 0;
+            while let Ok(Some(line)) = lines.next_line().await {
+                Message::from_json(&line).unwrap();
+                to.write_all(format!("{}\n", line).as_bytes())
+                    .await
+                    .unwrap();
+                pasted += 1;
+            }
+            let _ = to.shutdown().await;
+            pasted
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Server");
+        let (client_end, client_side) = tokio::io::duplex(4096);
+        let (server_end, server_side) = tokio::io::duplex(4096);
+        let (client_reader, client_writer) = tokio::io::split(client_side);
+        let (server_reader, server_writer) = tokio::io::split(server_side);
+        let requests = tokio::spawn(paste(client_reader, server_writer));
+        let replies = tokio::spawn(paste(server_reader, client_writer));
+        let (event_tx, _event_rx) = mpsc::channel(10);
+        let peer = SocketAddr::from(([0, 0, 0, 0], 0));
+        let serving =
+            tokio::spawn(async move { server.handle_stream(server_end, peer, event_tx).await });
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@stdin").unwrap();
+        let result = HandshakeClient::new("Client")
+            .pair_over_lines(client_end, "stdin", &key_pair)
+            .await
+            .unwrap();
+        assert_eq!(result.server_name, "Server");
+        serving.await.unwrap().unwrap();
+        assert!(requests.await.unwrap() >= 2);
+        assert!(replies.await.unwrap() >= 2);
+
+        let keys = KeyManager::with_dir(ssh_dir)
+            .list_authorized_keys()
+            .unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].contains("test@stdin"));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_client_rejected_by_default() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Server");
+        let (addr, handle) = start_server(server).await;
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "legacy@connecto").unwrap();
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+
+        send(
+            &mut writer,
+            Message::Hello {
+                version: 1,
+                device_name: "Legacy".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        assert!(matches!(recv(&mut reader).await, Message::HelloAck { .. }));
+
+        send(
+            &mut writer,
+            Message::KeyExchange {
+                public_key: key_pair.public_key.clone(),
+                comment: key_pair.comment.clone(),
+                expires_in: None,
+                fingerprint: None,
+                user: None,
+            },
+        )
+        .await;
+        match recv(&mut reader).await {
+            Message::Error { code, .. } => assert_eq!(code, ProtocolErrorCode::VersionMismatch),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+
+        handle.abort();
+        let keys = KeyManager::with_dir(ssh_dir)
+            .list_authorized_keys()
+            .unwrap();
+        assert!(keys.is_empty());
+    }
+
+    /// Start a server requiring a verification code, returning its address
+    /// and the codes it shows
+    async fn start_verifying_server(
+        ssh_dir: std::path::PathBuf,
+        pin_timeout: Duration,
+    ) -> (String, mpsc::Receiver<String>) {
+        let mut server = HandshakeServer::new(KeyManager::with_dir(ssh_dir), "Server")
+            .with_verification(true)
+            .with_pin_timeout(pin_timeout);
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+        let (code_tx, code_rx) = mpsc::channel(4);
+        tokio::spawn(async move { server.run(event_tx).await });
+        tokio::spawn(async move {
+            while let Some(event) = event_rx.recv().await {
+                if let ServerEvent::VerificationCode { code, .. } = event {
+                    let _ = code_tx.send(code).await;
+                }
+            }
+        });
+        (format!("127.0.0.1:{}", addr.port()), code_rx)
+    }
+
+    #[tokio::test]
+    async fn test_client_must_enter_verification_code() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let (addr, mut codes) =
+            start_verifying_server(ssh_dir.clone(), Duration::from_secs(30)).await;
+
+        // One typo, then the code the server shows
+        let (pin_tx, mut pin_rx) = mpsc::channel::<PinPrompt>(4);
+        let typist = tokio::spawn(async move {
+            let code = codes.recv().await.unwrap();
+            let mut tries = Vec::new();
+            while let Some(prompt) = pin_rx.recv().await {
+                tries.push(prompt.attempts_left);
+                if tries.len() == 1 {
+                    prompt.respond("not the code");
+                } else {
+                    prompt.respond(&code);
+                }
+            }
+            tries
+        });
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let result = HandshakeClient::new("Client")
+            .with_pin_prompt(pin_tx)
+            .pair(&addr, &key_pair)
+            .await
+            .unwrap();
+        assert_eq!(result.server_name, "Server");
+        // The code never travels to the client
+        assert_eq!(result.verification_code, None);
+        assert_eq!(typist.await.unwrap(), [PIN_ATTEMPTS, PIN_ATTEMPTS - 1]);
+
+        let keys = KeyManager::with_dir(ssh_dir)
+            .list_authorized_keys()
+            .unwrap();
+        assert_eq!(keys.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_trust_levels_decide_prompts() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let trust = TrustStore::with_path(temp_dir.path().join("known_peers.json"));
+        let (desk, laptop) = (
+            test_identity(&temp_dir, "desk"),
+            test_identity(&temp_dir, "laptop"),
+        );
+        trust.pin("Desk", &proven(&desk)).unwrap();
+        trust.set_level("Desk", TrustLevel::Trusted).unwrap();
+        trust.pin("Laptop", &proven(&laptop)).unwrap();
+
+        let (approval_tx, mut approval_rx) = mpsc::channel(4);
+        let log = DecisionLog::with_path(temp_dir.path().join("decisions.jsonl"));
+        let mut server =
+            HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join(".ssh")), "Server")
+                .with_trust_levels(trust.clone())
+                .with_approval(approval_tx)
+                .with_decision_log(log.clone());
+        let addr = server.listen(0).await.unwrap();
+        let addr = format!("127.0.0.1:{}", addr.port());
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+        tokio::spawn(async move { server.run(event_tx).await });
+        let (code_tx, mut codes) = mpsc::channel(4);
+        tokio::spawn(async move {
+            while let Some(event) = event_rx.recv().await {
+                if let ServerEvent::VerificationCode { code, .. } = event {
+                    let _ = code_tx.send(code).await;
+                }
+            }
+        });
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+
+        // Trusted: no code, no approval; the client has no way to enter a code
+        HandshakeClient::new("Desk")
+            .with_identity(&desk)
+            .pair(&addr, &key_pair)
+            .await
+            .unwrap();
+        assert!(approval_rx.try_recv().is_err());
+
+        // The name alone is not trusted, nor is another identity under it
+        let impostor = test_identity(&temp_dir, "impostor");
+        for client in [
+            HandshakeClient::new("Desk"),
+            HandshakeClient::new("Desk").with_identity(&impostor),
+        ] {
+            let err = client.pair(&addr, &key_pair).await.unwrap_err();
+            assert!(
+                err.to_string().contains("requires the verification code"),
+                "{}",
+                err
+            );
+            // The code it could not enter
+            codes.recv().await.unwrap();
+        }
+
+        // Known (pinned): a code but no approval
+        let (pin_tx, mut pin_rx) = mpsc::channel::<PinPrompt>(1);
+        let client = HandshakeClient::new("Laptop")
+            .with_identity(&laptop)
+            .with_pin_prompt(pin_tx);
+        let pairing = tokio::spawn({
+            let addr = addr.clone();
+            async move { client.pair(&addr, &key_pair).await }
+        });
+        let prompt = pin_rx.recv().await.unwrap();
+        prompt.respond(&codes.recv().await.unwrap());
+        pairing.await.unwrap().unwrap();
+        assert!(approval_rx.try_recv().is_err());
+
+        // Unknown: a code and approval
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let (pin_tx, mut pin_rx) = mpsc::channel::<PinPrompt>(1);
+        let stranger = test_identity(&temp_dir, "stranger");
+        let client = HandshakeClient::new("Stranger")
+            .with_identity(&stranger)
+            .with_pin_prompt(pin_tx);
+        let pairing = tokio::spawn({
+            let addr = addr.clone();
+            async move { client.pair(&addr, &key_pair).await }
+        });
+        let prompt = pin_rx.recv().await.unwrap();
+        prompt.respond(&codes.recv().await.unwrap());
+        let request = approval_rx.recv().await.unwrap();
+        assert_eq!(request.device_name, "Stranger");
+        request.approve();
+        pairing.await.unwrap().unwrap();
+        // and is known from then on
+        assert_eq!(
+            trust.level("Stranger", Some(&proven(&stranger))).unwrap(),
+            TrustLevel::Known
+        );
+
+        let reasons: Vec<_> = log.all().unwrap().into_iter().map(|d| d.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                Some("Approval skipped for trusted device".to_string()),
+                Some("Approval skipped for known device".to_string()),
+                None,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_verification_codes_refused() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        let (addr, _codes) = start_verifying_server(ssh_dir.clone(), Duration::from_secs(30)).await;
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+
+        // A wrong guess every time
+        let (pin_tx, mut pin_rx) = mpsc::channel::<PinPrompt>(4);
+        let guesser = tokio::spawn(async move {
+            let mut prompts = 0;
+            while let Some(prompt) = pin_rx.recv().await {
+                prompts += 1;
+                prompt.respond("12345");
+            }
+            prompts
+        });
+        let err = HandshakeClient::new("Client")
+            .with_pin_prompt(pin_tx)
+            .pair(&addr, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ConnectoError::VerificationFailed(_)));
+        assert!(err.to_string().contains("Wrong verification code"));
+        assert_eq!(guesser.await.unwrap(), PIN_ATTEMPTS);
+
+        // Without a way to ask the user, the client cannot pair at all
+        let err = HandshakeClient::new("Client")
+            .pair(&addr, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("requires the verification code"));
+
+        let keys = KeyManager::with_dir(ssh_dir)
+            .list_authorized_keys()
+            .unwrap();
+        assert!(keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_verification_code_bound_to_channel() {
+        let temp_dir = TempDir::new().unwrap();
+        let (addr, mut codes) =
+            start_verifying_server(temp_dir.path().join(".ssh"), Duration::from_secs(30)).await;
+
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        let share = KeyShare::generate();
+        send(
+            &mut writer,
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                device_name: "Client".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: Some(share.public()),
+            },
+        )
+        .await;
+        let Message::HelloAck {
+            key_share: Some(server_share),
+            ..
+        } = recv(&mut reader).await
+        else {
+            panic!("Expected HelloAck with a key share");
+        };
+        let keys = share.agree(Side::Client, &server_share).unwrap();
+        let binding = keys.binding().to_string();
+        let (reader, mut writer) = channel::wrap(reader, writer, Some(keys));
+        let mut reader = BufReader::new(reader);
+        let code = codes.recv().await.unwrap();
+
+        // The right code, but run over another channel's binding, as a device
+        // relaying between two channels would have to
+        let elsewhere = KeyShare::generate()
+            .agree(Side::Client, &server_share)
+            .unwrap();
+        let mut accepted = None;
+        for (binding, attempts_left) in [
+            (elsewhere.binding(), PIN_ATTEMPTS),
+            (binding.as_str(), PIN_ATTEMPTS - 1),
+        ] {
+            assert!(matches!(
+                recv_framed(&mut reader).await,
+                Message::PinRequest { attempts_left: left } if left == attempts_left
+            ));
+            let exchange = CodeExchange::start(Side::Client, &code, binding);
+            let entry = Message::PinExchange {
+                message: exchange.message(),
+            };
+            send_framed(&mut writer, entry).await;
+            let Message::PinExchange { message } = recv_framed(&mut reader).await else {
+                panic!("Expected PinExchange");
+            };
+            let key = exchange.finish(&message).unwrap();
+            let confirm = Message::PinConfirm {
+                mac: key.confirmation(Side::Client),
+            };
+            send_framed(&mut writer, confirm).await;
+            accepted = Some(key);
+        }
+
+        // The server proves it knows the code too
+        match recv_framed(&mut reader).await {
+            Message::PinAccepted { mac: Some(mac) } => {
+                assert!(accepted.unwrap().confirms(Side::Server, &mac));
+            }
+            other => panic!("Expected PinAccepted, got {:?}", other),
         }
     }
 
-    #[test]
-    fn test_message_key_exchange_serialization() {
-        let msg = Message::KeyExchange {
-            public_key: "ssh-ed25519 AAAAC3... test@connecto".to_string(),
-            comment: "test@connecto".to_string(),
-        };
+    #[tokio::test]
+    async fn test_pairing_channel_is_encrypted() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+        use tokio::io::AsyncReadExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let server =
+            HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join(".ssh")), "Server");
+        let (addr, handle) = start_server(server).await;
+
+        // Something in between that forwards the client's bytes and keeps them
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap().to_string();
+        let recorder = tokio::spawn(async move {
+            let (client, _) = proxy.accept().await.unwrap();
+            let server = TcpStream::connect(&addr).await.unwrap();
+            let (mut client_reader, mut client_writer) = client.into_split();
+            let (mut server_reader, mut server_writer) = server.into_split();
+            let answers = tokio::spawn(async move {
+                tokio::io::copy(&mut server_reader, &mut client_writer).await
+            });
+            let mut seen = Vec::new();
+            let mut buf = [0; 4096];
+            loop {
+                let n = client_reader.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                seen.extend_from_slice(&buf[..n]);
+                server_writer.write_all(&buf[..n]).await.unwrap();
+            }
+            answers.abort();
+            String::from_utf8_lossy(&seen).into_owned()
+        });
+
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let result = HandshakeClient::new("Client")
+            .with_encryption(true)
+            .pair(&proxy_addr, &key_pair)
+            .await
+            .unwrap();
+        assert!(result.capabilities.contains(Capabilities::ENCRYPTION));
+        handle.await.unwrap().unwrap();
 
-        let json = msg.to_json().unwrap();
-        assert!(json.contains("KeyExchange"));
-        assert!(json.contains("ssh-ed25519"));
+        // Hello goes in the clear, everything after it does not
+        let seen = recorder.await.unwrap();
+        assert!(seen.contains(r#""type":"Hello""#));
+        assert!(!seen.contains("KeyExchange"));
+        let key_data = key_pair.public_key.split_whitespace().nth(1).unwrap();
+        assert!(!seen.contains(key_data));
     }
 
-    #[test]
-    fn test_message_error_serialization() {
-        let msg = Message::Error {
-            code: 42,
-            message: "Something went wrong".to_string(),
-        };
+    #[tokio::test]
+    async fn test_server_identity_proof_bound_to_channel() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
 
-        let json = msg.to_json().unwrap();
-        let deserialized = Message::from_json(&json).unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let desk = test_identity(&temp_dir, "desk");
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
 
-        match deserialized {
-            Message::Error { code, message } => {
-                assert_eq!(code, 42);
-                assert_eq!(message, "Something went wrong");
-            }
-            _ => panic!("Wrong message type"),
+        // A proof of the nonce alone, which a device in the middle could
+        // relay from the real server, does not hold in an encrypted channel
+        for bound in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let desk = desk.clone();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = BufReader::new(reader);
+                let Message::Hello {
+                    nonce: Some(nonce),
+                    key_share: Some(client_share),
+                    ..
+                } = recv(&mut reader).await
+                else {
+                    panic!("Expected Hello with a nonce and a key share");
+                };
+                let share = KeyShare::generate();
+                let public = share.public();
+                let keys = share.agree(Side::Server, &client_share).unwrap();
+                let challenge = identity_challenge(&nonce, bound.then_some(keys.binding()));
+                let mut ack = desk_ack(desk.fingerprint(), Some(desk.prove(&challenge).unwrap()));
+                if let Message::HelloAck {
+                    version, key_share, ..
+                } = &mut ack
+                {
+                    *version = PROTOCOL_VERSION;
+                    *key_share = Some(public);
+                }
+                send(&mut writer, ack).await;
+            });
+
+            // Past the proof, the pairing fails only because the server hangs up
+            let err = HandshakeClient::new("Client")
+                .pair(&addr, &key_pair)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                matches!(err, ConnectoError::IdentityMismatch(_)),
+                !bound,
+                "{}",
+                err
+            );
         }
     }
 
-    #[test]
-    fn test_message_pairing_complete_serialization() {
-        let msg = Message::PairingComplete {
-            ssh_user: "testuser".to_string(),
-        };
-
-        let json = msg.to_json().unwrap();
-        let deserialized = Message::from_json(&json).unwrap();
+    #[tokio::test]
+    async fn test_verification_code_times_out() {
+        let temp_dir = TempDir::new().unwrap();
+        let (addr, _codes) =
+            start_verifying_server(temp_dir.path().join(".ssh"), Duration::from_millis(100)).await;
 
-        match deserialized {
-            Message::PairingComplete { ssh_user } => {
-                assert_eq!(ssh_user, "testuser");
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(
+            &mut writer,
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                device_name: "Slow".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        match recv(&mut reader).await {
+            Message::HelloAck {
+                verification_code,
+                pin_required,
+                ..
+            } => {
+                assert_eq!(verification_code, None);
+                assert!(pin_required);
             }
-            _ => panic!("Wrong message type"),
+            other => panic!("Expected HelloAck, got {:?}", other),
+        }
+        assert!(matches!(
+            recv_framed(&mut reader).await,
+            Message::PinRequest { attempts_left } if attempts_left == PIN_ATTEMPTS
+        ));
+        match recv_framed(&mut reader).await {
+            Message::Error { code, message } => {
+                assert_eq!(code, ProtocolErrorCode::VerificationFailed);
+                assert!(message.contains("in time"));
+            }
+            other => panic!("Expected Error, got {:?}", other),
         }
     }
 
-    #[test]
-    fn test_generate_verification_code() {
-        let code = generate_verification_code();
-        assert_eq!(code.len(), 4);
-        assert!(code.chars().all(|c| c.is_ascii_digit()));
-    }
+    #[tokio::test]
+    async fn test_client_gives_up_on_silent_server() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
 
-    #[test]
-    fn test_generate_verification_code_uniqueness() {
-        let codes: Vec<_> = (0..100).map(|_| generate_verification_code()).collect();
-        // Not all codes should be the same (statistically very unlikely)
-        let unique: std::collections::HashSet<_> = codes.iter().collect();
-        assert!(unique.len() > 1);
-    }
+        // Accepts connections, reads Hello and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
 
-    #[test]
-    fn test_handshake_client_creation() {
-        let client = HandshakeClient::new("Test Client");
-        assert_eq!(client.device_name, "Test Client");
-    }
+        let error = HandshakeClient::new("Client")
+            .with_step_timeout(Duration::from_millis(100))
+            .pair(&address, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, ConnectoError::Timeout(m) if m.contains("stopped answering")),
+            "{}",
+            error
+        );
 
-    #[test]
-    fn test_pairing_result() {
-        let result = PairingResult {
-            server_name: "Server".to_string(),
-            ssh_user: "user".to_string(),
-            verification_code: Some("1234".to_string()),
-        };
+        let error = HandshakeClient::new("Client")
+            .with_timeout(Duration::from_millis(100))
+            .pair(&address, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, ConnectoError::Timeout(m) if m.contains("did not finish")),
+            "{}",
+            error
+        );
 
-        assert_eq!(result.server_name, "Server");
-        assert_eq!(result.ssh_user, "user");
-        assert_eq!(result.verification_code, Some("1234".to_string()));
+        let cancel = ShutdownHandle::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.shutdown();
+        });
+        let error = HandshakeClient::new("Client")
+            .with_cancellation(cancel)
+            .revoke(&address, &key_pair)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, ConnectoError::Timeout(m) if m.contains("Cancelled")),
+            "{}",
+            error
+        );
     }
 
     #[tokio::test]
-    async fn test_handshake_server_creation() {
+    async fn test_abandoned_pairing_reports_failure() {
+        use crate::session_log::{AttemptOutcome, SessionLog};
+
         let temp_dir = TempDir::new().unwrap();
-        let ssh_dir = temp_dir.path().join(".ssh");
-        let key_manager = KeyManager::with_dir(ssh_dir);
+        let mut server =
+            HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join(".ssh")), "Server");
+        let addr = server.listen(0).await.unwrap();
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+        let handle = tokio::spawn(async move { server.handle_one(event_tx).await });
 
-        let server = HandshakeServer::new(key_manager, "Test Server");
-        assert_eq!(server.device_name, "Test Server");
-        assert!(!server.require_verification);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let client_addr = stream.local_addr().unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        send(
+            &mut writer,
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                device_name: "Quitter".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        assert!(matches!(recv(&mut reader).await, Message::HelloAck { .. }));
+        drop(writer);
+        drop(reader);
+
+        let mut log = SessionLog::new();
+        while let Some(event) = event_rx.recv().await {
+            log.record(&event);
+            if let ServerEvent::PairingFailed { address, .. } = event {
+                assert_eq!(address.port(), client_addr.port());
+                break;
+            }
+        }
+        let attempt = log.attempts().next().unwrap();
+        assert_eq!(attempt.device_name, "Quitter");
+        assert!(matches!(attempt.outcome, AttemptOutcome::Failed { .. }));
+        handle.abort();
     }
 
     #[tokio::test]
-    async fn test_handshake_server_with_verification() {
+    async fn test_verifying_server_refuses_clients_without_pin_support() {
         let temp_dir = TempDir::new().unwrap();
-        let ssh_dir = temp_dir.path().join(".ssh");
-        let key_manager = KeyManager::with_dir(ssh_dir);
+        let (addr, _codes) =
+            start_verifying_server(temp_dir.path().join(".ssh"), Duration::from_secs(30)).await;
 
-        let server = HandshakeServer::new(key_manager, "Test Server").with_verification(true);
-        assert!(server.require_verification);
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(
+            &mut writer,
+            Message::Hello {
+                version: PIN_VERSION - 1,
+                device_name: "Older".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        match recv(&mut reader).await {
+            Message::Error { code, .. } => assert_eq!(code, ProtocolErrorCode::VersionMismatch),
+            other => panic!("Expected Error, got {:?}", other),
+        }
     }
 
     #[tokio::test]
-    async fn test_handshake_server_listen() {
+    async fn test_invalid_key_proof_rejected() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
         let temp_dir = TempDir::new().unwrap();
         let ssh_dir = temp_dir.path().join(".ssh");
-        let key_manager = KeyManager::with_dir(ssh_dir);
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Server");
+        let (addr, handle) = start_server(server).await;
 
-        let mut server = HandshakeServer::new(key_manager, "Test Server");
-        let addr = server.listen(0).await.unwrap(); // Port 0 = random available port
+        // Send someone else's public key, then sign the challenge with our own
+        let victim = SshKeyPair::generate(KeyAlgorithm::Ed25519, "victim@connecto").unwrap();
+        let attacker = SshKeyPair::generate(KeyAlgorithm::Ed25519, "attacker@connecto").unwrap();
 
-        assert!(addr.port() > 0);
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+
+        send(
+            &mut writer,
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                device_name: "Attacker".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        assert!(matches!(recv(&mut reader).await, Message::HelloAck { .. }));
+
+        send_framed(
+            &mut writer,
+            Message::KeyExchange {
+                public_key: victim.public_key.clone(),
+                comment: victim.comment.clone(),
+                expires_in: None,
+                fingerprint: None,
+                user: None,
+            },
+        )
+        .await;
+        let nonce = match recv_framed(&mut reader).await {
+            Message::KeyChallenge { nonce } => nonce,
+            other => panic!("Expected KeyChallenge, got {:?}", other),
+        };
+
+        let signature = attacker
+            .sign(KEY_PROOF_NAMESPACE, nonce.as_bytes())
+            .unwrap();
+        send_framed(&mut writer, Message::KeyProof { signature }).await;
+        match recv_framed(&mut reader).await {
+            Message::Error { code, .. } => assert_eq!(code, ProtocolErrorCode::VerificationFailed),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+
+        handle.abort();
+        let keys = KeyManager::with_dir(ssh_dir)
+            .list_authorized_keys()
+            .unwrap();
+        assert!(keys.is_empty());
     }
 
     #[tokio::test]
-    async fn test_full_handshake() {
+    async fn test_mismatched_fingerprint_rejected() {
         use crate::keys::{KeyAlgorithm, SshKeyPair};
 
         let temp_dir = TempDir::new().unwrap();
         let ssh_dir = temp_dir.path().join(".ssh");
-        let key_manager = KeyManager::with_dir(ssh_dir.clone());
+        let server = HandshakeServer::new(KeyManager::with_dir(ssh_dir.clone()), "Server");
+        let (addr, handle) = start_server(server).await;
 
-        // Start server
-        let mut server = HandshakeServer::new(key_manager, "Test Server");
-        let addr = server.listen(0).await.unwrap();
-        let server_addr = format!("127.0.0.1:{}", addr.port());
+        let sent = SshKeyPair::generate(KeyAlgorithm::Ed25519, "a@connecto").unwrap();
+        let other = SshKeyPair::generate(KeyAlgorithm::Ed25519, "b@connecto").unwrap();
 
-        let (event_tx, mut event_rx) = mpsc::channel(10);
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(
+            &mut writer,
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                device_name: "Client".to_string(),
+                timestamp: None,
+                min_version: None,
+                capabilities: None,
+                identity: None,
+                nonce: None,
+                key_share: None,
+            },
+        )
+        .await;
+        assert!(matches!(recv(&mut reader).await, Message::HelloAck { .. }));
 
-        // Run server in background
-        let server_handle = tokio::spawn(async move { server.handle_one(event_tx).await });
+        send_framed(
+            &mut writer,
+            Message::KeyExchange {
+                public_key: sent.public_key.clone(),
+                comment: sent.comment.clone(),
+                expires_in: None,
+                fingerprint: Some(other.fingerprint().unwrap()),
+                user: None,
+            },
+        )
+        .await;
+        match recv_framed(&mut reader).await {
+            Message::Error { code, .. } => assert_eq!(code, ProtocolErrorCode::VerificationFailed),
+            other => panic!("Expected Error, got {:?}", other),
+        }
 
-        // Give server time to start
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        handle.abort();
+        let keys = KeyManager::with_dir(ssh_dir)
+            .list_authorized_keys()
+            .unwrap();
+        assert!(keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_falls_back_for_legacy_server() {
+        use crate::keys::{KeyAlgorithm, SshKeyPair};
+
+        // A version 1 server rejects anything but version 1
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let legacy_server = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = BufReader::new(reader);
+
+                match recv(&mut reader).await {
+                    Message::Hello { version: 1, .. } => {}
+                    _ => {
+                        let error = Message::Error {
+                            code: ProtocolErrorCode::VersionMismatch,
+                            message: "Protocol version mismatch".to_string(),
+                        };
+                        send(&mut writer, error).await;
+                        continue;
+                    }
+                }
+
+                let ack = Message::HelloAck {
+                    version: 1,
+                    device_name: "Legacy Server".to_string(),
+                    verification_code: None,
+                    identity: None,
+                    pin_required: false,
+                    timestamp: None,
+                    capabilities: None,
+                    users: Vec::new(),
+                    identity_proof: None,
+                    nonce: None,
+                    key_share: None,
+                };
+                send(&mut writer, ack).await;
+                assert!(matches!(
+                    recv(&mut reader).await,
+                    Message::KeyExchange { .. }
+                ));
+                let accepted = Message::KeyAccepted {
+                    message: "ok".to_string(),
+                    fingerprint: None,
+                };
+                send(&mut writer, accepted).await;
+                let complete = Message::PairingComplete {
+                    ssh_user: "legacy".to_string(),
+                    hostname: None,
+                    identity: None,
+                    ssh_port: None,
+                    identity_proof: None,
+                };
+                send(&mut writer, complete).await;
+                return;
+            }
+        });
 
-        // Create client and key pair
         let client = HandshakeClient::new("Test Client");
         let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let result = client.pair(&addr, &key_pair).await.unwrap();
 
-        // Perform pairing
-        let result = client.pair(&server_addr, &key_pair).await.unwrap();
+        assert_eq!(result.server_name, "Legacy Server");
+        assert_eq!(result.ssh_user, "legacy");
+        assert!(!result.fingerprint_confirmed);
+        assert_eq!(result.ssh_port, 22);
+        assert_eq!(result.capabilities, Capabilities::NONE);
+        legacy_server.await.unwrap();
+    }
 
-        assert_eq!(result.server_name, "Test Server");
-        assert!(!result.ssh_user.is_empty());
+    #[tokio::test]
+    async fn test_server_negotiates_version_and_capabilities() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join(".ssh")), "S");
+        let (addr, handle) = start_server(server).await;
 
-        // Verify key was added
-        let key_manager = KeyManager::with_dir(ssh_dir);
-        let keys = key_manager.list_authorized_keys().unwrap();
-        assert_eq!(keys.len(), 1);
-        assert!(keys[0].contains("test@connecto"));
+        let hello = |min_version, version, capabilities, key_share| Message::Hello {
+            version,
+            device_name: "Client".to_string(),
+            timestamp: None,
+            min_version: Some(min_version),
+            capabilities,
+            identity: None,
+            nonce: None,
+            key_share,
+        };
 
-        // Wait for server to finish
-        server_handle.await.unwrap().unwrap();
+        // A newer client gets the newest version both speak, and only the
+        // features both support
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        let newer =
+            Capabilities::ENCRYPTION | Capabilities::SYNC | serde_json::from_str("64").unwrap();
+        let share = Some(KeyShare::generate().public());
+        send(
+            &mut writer,
+            hello(3, PROTOCOL_VERSION + 5, Some(newer), share),
+        )
+        .await;
+        match recv(&mut reader).await {
+            Message::HelloAck {
+                version,
+                capabilities,
+                key_share,
+                ..
+            } => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(
+                    capabilities,
+                    Some(Capabilities::ENCRYPTION | Capabilities::SYNC)
+                );
+                assert!(key_share.is_some());
+            }
+            other => panic!("Expected HelloAck, got {:?}", other),
+        }
 
-        // Verify events were sent
-        let mut events = Vec::new();
-        while let Ok(event) = event_rx.try_recv() {
-            events.push(event);
+        // A client that sends no capabilities is judged by its version
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(&mut writer, hello(1, PIN_VERSION, None, None)).await;
+        match recv(&mut reader).await {
+            Message::HelloAck {
+                version,
+                capabilities,
+                ..
+            } => {
+                assert_eq!(version, PIN_VERSION);
+                assert_eq!(capabilities, Some(Capabilities::VERIFICATION));
+            }
+            other => panic!("Expected HelloAck, got {:?}", other),
         }
-        assert!(!events.is_empty());
+
+        // A client that only speaks versions this server does not is told why
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(
+            &mut writer,
+            hello(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2, None, None),
+        )
+        .await;
+        match recv(&mut reader).await {
+            Message::Error { code, message } => {
+                assert_eq!(code, ProtocolErrorCode::VersionMismatch);
+                assert_eq!(
+                    message,
+                    format!(
+                        "Protocol version mismatch: S speaks versions 1-{}, Client speaks {}-{}",
+                        PROTOCOL_VERSION,
+                        PROTOCOL_VERSION + 1,
+                        PROTOCOL_VERSION + 2
+                    )
+                );
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_requires_key_share_to_encrypt() {
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION,
+            device_name: "Client".to_string(),
+            timestamp: None,
+            min_version: None,
+            capabilities: Some(Capabilities::SUPPORTED),
+            identity: None,
+            nonce: None,
+            key_share: None,
+        };
+
+        // A client that sends no key share cannot encrypt, whatever it claims
+        let temp_dir = TempDir::new().unwrap();
+        let server = HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join(".ssh")), "S");
+        let (addr, handle) = start_server(server).await;
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(&mut writer, hello.clone()).await;
+        match recv(&mut reader).await {
+            Message::HelloAck {
+                version,
+                capabilities,
+                key_share,
+                ..
+            } => {
+                assert_eq!(version, ENCRYPTED_VERSION - 1);
+                assert!(!capabilities.unwrap().contains(Capabilities::ENCRYPTION));
+                assert_eq!(key_share, None);
+            }
+            other => panic!("Expected HelloAck, got {:?}", other),
+        }
+        handle.abort();
+
+        // and is turned away by a server that only pairs encrypted
+        let server = HandshakeServer::new(KeyManager::with_dir(temp_dir.path().join(".ssh")), "S")
+            .with_encryption(true);
+        let (addr, handle) = start_server(server).await;
+        let (reader, mut writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        send(&mut writer, hello).await;
+        match recv(&mut reader).await {
+            Message::Error { code, message } => {
+                assert_eq!(code, ProtocolErrorCode::VersionMismatch);
+                assert_eq!(
+                    message,
+                    "S only pairs over an encrypted channel, which Client cannot set up"
+                );
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        handle.abort();
     }
 
     // Sync protocol message tests
@@ -729,6 +6221,10 @@ mod tests {
             public_key: "ssh-ed25519 AAAAC3... test@device-a".to_string(),
             key_comment: "test@device-a".to_string(),
             ssh_user: "alice".to_string(),
+            keys: vec!["ssh-ed25519 AAAAC3... deploy@device-a".to_string()],
+            identity: Some("SHA256:aaa".to_string()),
+            timestamp: None,
+            nonce: None,
         };
 
         let json = msg.to_json().unwrap();
@@ -745,7 +6241,12 @@ mod tests {
                 public_key,
                 key_comment,
                 ssh_user,
+                keys,
+                identity,
+                ..
             } => {
+                assert_eq!(keys, vec!["ssh-ed25519 AAAAC3... deploy@device-a"]);
+                assert_eq!(identity, Some("SHA256:aaa".to_string()));
                 assert_eq!(version, PROTOCOL_VERSION);
                 assert_eq!(device_name, "Device A");
                 assert_eq!(initiator_priority, 12345678901234567890);
@@ -766,11 +6267,17 @@ mod tests {
             key_comment: "test@device-b".to_string(),
             ssh_user: "bob".to_string(),
             accept_sync: true,
+            keys: Vec::new(),
+            identity: None,
+            timestamp: None,
+            identity_proof: None,
         };
 
         let json = msg.to_json().unwrap();
         assert!(json.contains("SyncHelloAck"));
         assert!(json.contains("accept_sync"));
+        // Peers that share one key send what older versions sent
+        assert!(!json.contains("\"keys\""));
 
         let deserialized = Message::from_json(&json).unwrap();
         match deserialized {
@@ -781,7 +6288,10 @@ mod tests {
                 key_comment,
                 ssh_user,
                 accept_sync,
+                keys,
+                ..
             } => {
+                assert!(keys.is_empty());
                 assert_eq!(version, PROTOCOL_VERSION);
                 assert_eq!(device_name, "Device B");
                 assert!(public_key.contains("ssh-ed25519"));
@@ -802,6 +6312,10 @@ mod tests {
             key_comment: "".to_string(),
             ssh_user: "".to_string(),
             accept_sync: false,
+            keys: Vec::new(),
+            identity: None,
+            timestamp: None,
+            identity_proof: None,
         };
 
         let json = msg.to_json().unwrap();
@@ -815,6 +6329,62 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sync_rejected_serialization() {
+        let msg = Message::SyncRejected {
+            device_name: "Device A".to_string(),
+            message: "Rejected by user".to_string(),
+        };
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("SyncRejected"));
+
+        match Message::from_json(&json).unwrap() {
+            Message::SyncRejected {
+                device_name,
+                message,
+            } => {
+                assert_eq!(device_name, "Device A");
+                assert_eq!(message, "Rejected by user");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_transfer_messages_serialization() {
+        let msg = Message::TransferOffer {
+            version: 1,
+            device_name: "Device A".to_string(),
+            identity: None,
+            nonce: None,
+            files: vec![OfferedFile {
+                name: "notes.txt".to_string(),
+                size: 12,
+            }],
+        };
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("TransferOffer"));
+        assert!(!json.contains("identity"));
+        match Message::from_json(&json).unwrap() {
+            Message::TransferOffer { files, .. } => {
+                assert_eq!(files[0].name, "notes.txt");
+                assert_eq!(files[0].size, 12);
+            }
+            _ => panic!("Wrong message type"),
+        }
+
+        let json = r#"{"type":"TransferAccept","device_name":"Device B","accepted":false}"#;
+        match Message::from_json(json).unwrap() {
+            Message::TransferAccept {
+                accepted, message, ..
+            } => {
+                assert!(!accepted);
+                assert!(message.is_none());
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
     #[test]
     fn test_sync_complete_serialization() {
         let msg = Message::SyncComplete {
@@ -853,4 +6423,39 @@ mod tests {
             _ => panic!("Wrong message type"),
         }
     }
+
+    #[test]
+    fn test_sync_key_list_serialization() {
+        let msg = Message::SyncKeyList {
+            version: 1,
+            device_name: "Device A".to_string(),
+            identity: None,
+            nonce: None,
+            identity_proof: None,
+            digest: "abc".to_string(),
+            held: None,
+        };
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("SyncKeyList"));
+        assert!(!json.contains("held"));
+
+        let diff = Message::from_json(
+            r#"{"type":"SyncKeyDiff","added":["ssh-ed25519 AAAA"],"digest":"def"}"#,
+        )
+        .unwrap();
+        match diff {
+            Message::SyncKeyDiff {
+                base,
+                added,
+                removed,
+                digest,
+            } => {
+                assert_eq!(base, None);
+                assert_eq!(added, ["ssh-ed25519 AAAA"]);
+                assert!(removed.is_empty());
+                assert_eq!(digest, "def");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
 }
@@ -0,0 +1,955 @@
+//! Relay module
+//!
+//! Lets two devices that cannot reach each other directly, such as machines
+//! on different subnets or behind NAT, pair through a rendezvous server both
+//! of them can reach.
+//!
+//! The listening device opens a room on the relay and shows a code like
+//! `4821-K7PQ2M`. The part before the dash names the room; the part after it
+//! never leaves the two devices. Once the other device joins the room, the
+//! relay forwards bytes between them without looking at them, and the devices
+//! run SPAKE2 over Ed25519 with the whole code as the password. A relay that
+//! does not know the code can neither read the pairing nor take part in it,
+//! and a device guessing codes gets one guess per pairing.
+//!
+//! After the key agreement, every frame is sealed with ChaCha20-Poly1305,
+//! with a separate key for each direction and the frame's sequence number as
+//! nonce.
+
+use crate::channel::{from_hex, to_hex, FrameKeys, MAX_FRAME, TAG_LEN};
+use crate::error::{ConnectoError, Result};
+use crate::net;
+use crate::ports;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+/// Default port relays listen on
+pub const DEFAULT_RELAY_PORT: u16 = 8098;
+
+/// Relay protocol version
+pub const RELAY_VERSION: u32 = 2;
+
+/// How long a device waits on the relay for the other one by default
+pub const RELAY_WAIT_SECS: u64 = 600;
+
+/// How long the relay forwards between two devices before closing
+const SESSION_LIMIT: Duration = Duration::from_secs(15 * 60);
+
+/// How long to wait for a device's next message before the channel is set up
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Rooms a relay keeps open at once
+const MAX_ROOMS: usize = 1024;
+
+/// Longest control message a relay reads
+const MAX_LINE: u64 = 1024;
+
+/// Characters of a generated code's secret, without look-alikes like 0 and O
+const SECRET_ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+/// Length of a generated code's secret
+const SECRET_LEN: usize = 6;
+
+/// How many rooms a listener tries before giving up
+const OPEN_ATTEMPTS: usize = 3;
+
+/// Relay error code: the room is taken by a device with the same role
+const ROOM_TAKEN: u32 = 3;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Which end of the pairing a device is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayRole {
+    /// The device that opened the room and receives the key
+    Listener,
+    /// The device that joined with the code and sends its key
+    Client,
+}
+
+impl RelayRole {
+    fn other(self) -> Self {
+        match self {
+            RelayRole::Listener => RelayRole::Client,
+            RelayRole::Client => RelayRole::Listener,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            RelayRole::Listener => "listener",
+            RelayRole::Client => "client",
+        }
+    }
+}
+
+/// Messages between devices and the relay, and between the two devices
+/// before their channel is encrypted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum RelayMessage {
+    /// Device to relay: wait in `room` for the device with the other role
+    Join {
+        version: u32,
+        room: String,
+        role: RelayRole,
+    },
+    /// Relay to device: joined, waiting for the other device
+    Waiting,
+    /// Relay to device: the other device is here, at `peer` as the relay
+    /// sees it. Everything after this message is forwarded between the devices
+    Matched { peer: String },
+    /// Relay to device, which is then disconnected
+    Error { code: u32, message: String },
+    /// Device to device: the SPAKE2 message, in hex
+    Pake { message: String },
+    /// Device to device: proof of having derived the same key, in hex
+    Confirm { mac: String },
+}
+
+impl RelayMessage {
+    /// Serialize as one JSON line
+    pub fn to_json(&self) -> Result<String> {
+        let mut json = serde_json::to_string(self)?;
+        json.push('\n');
+        Ok(json)
+    }
+
+    /// Parse a JSON line
+    pub fn from_json(s: &str) -> Result<Self> {
+        Ok(serde_json::from_str(s.trim())?)
+    }
+}
+
+/// The code two devices pair with through a relay, e.g. `4821-K7PQ2M`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayCode {
+    room: String,
+    secret: String,
+}
+
+impl RelayCode {
+    /// A random code
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let room = rng.gen_range(1000..10000).to_string();
+        let secret = (0..SECRET_LEN)
+            .map(|_| SECRET_ALPHABET[rng.gen_range(0..SECRET_ALPHABET.len())] as char)
+            .collect();
+        Self { room, secret }
+    }
+
+    /// The room, the only part of the code the relay learns
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+}
+
+impl fmt::Display for RelayCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.room, self.secret)
+    }
+}
+
+impl FromStr for RelayCode {
+    type Err = ConnectoError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            ConnectoError::Relay(format!(
+                "Invalid code '{}': expected digits, a dash and letters, like 4821-K7PQ2M",
+                s
+            ))
+        };
+        let (room, secret) = s.trim().split_once('-').ok_or_else(invalid)?;
+        if !valid_room(room)
+            || secret.len() < 4
+            || !secret.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(invalid());
+        }
+        Ok(Self {
+            room: room.to_string(),
+            secret: secret.to_ascii_uppercase(),
+        })
+    }
+}
+
+fn valid_room(room: &str) -> bool {
+    (1..=8).contains(&room.len()) && room.chars().all(|c| c.is_ascii_digit())
+}
+
+/// The relay's connection string, with the default port if none is given
+pub fn relay_address(relay: &str) -> String {
+    let host = net::host_of(relay);
+    if host == relay || relay.ends_with(']') {
+        net::join_host_port(host, DEFAULT_RELAY_PORT)
+    } else {
+        relay.to_string()
+    }
+}
+
+/// A device registered with a relay, waiting for the other device
+pub struct PendingChannel {
+    code: RelayCode,
+    role: RelayRole,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    /// The other device's address, if it was already waiting
+    matched: Option<String>,
+}
+
+/// Outcome of joining a room
+enum Joined {
+    Pending(PendingChannel),
+    RoomTaken,
+}
+
+impl PendingChannel {
+    /// Open a room on `relay` under a new code, for the user to pass on to
+    /// the device that will join
+    pub async fn open(relay: &str) -> Result<Self> {
+        for _ in 0..OPEN_ATTEMPTS {
+            match Self::join_as(relay, RelayCode::generate(), RelayRole::Listener).await? {
+                Joined::Pending(pending) => return Ok(pending),
+                Joined::RoomTaken => debug!("Room taken on {}, trying another", relay),
+            }
+        }
+        Err(ConnectoError::Relay(
+            "The relay has no free room; try again".to_string(),
+        ))
+    }
+
+    /// Join the room `code` opened on `relay`
+    pub async fn join(relay: &str, code: &RelayCode) -> Result<Self> {
+        match Self::join_as(relay, code.clone(), RelayRole::Client).await? {
+            Joined::Pending(pending) => Ok(pending),
+            Joined::RoomTaken => Err(ConnectoError::Relay(format!(
+                "Another device already joined with code {}",
+                code
+            ))),
+        }
+    }
+
+    async fn join_as(relay: &str, code: RelayCode, role: RelayRole) -> Result<Joined> {
+        let address = relay_address(relay);
+        let (reader, mut writer) = net::connect(&address).await?.into_split();
+        let join = RelayMessage::Join {
+            version: RELAY_VERSION,
+            room: code.room.clone(),
+            role,
+        };
+        writer.write_all(join.to_json()?.as_bytes()).await?;
+
+        let mut pending = Self {
+            code,
+            role,
+            reader: BufReader::new(reader),
+            writer,
+            matched: None,
+        };
+        // The second device is matched at once; the first is told to wait
+        match read_message(&mut pending.reader).await? {
+            RelayMessage::Waiting => {
+                debug!("Waiting on {} in room {}", address, pending.code.room);
+            }
+            RelayMessage::Matched { peer } => pending.matched = Some(peer),
+            RelayMessage::Error {
+                code: ROOM_TAKEN, ..
+            } => return Ok(Joined::RoomTaken),
+            message => return Err(unexpected(message)),
+        }
+        Ok(Joined::Pending(pending))
+    }
+
+    /// The code the other device joins with
+    pub fn code(&self) -> &RelayCode {
+        &self.code
+    }
+
+    /// Wait for the other device, then agree on the channel's keys with it
+    ///
+    /// Fails if the other device used a different code.
+    pub async fn connect(mut self) -> Result<RelayChannel> {
+        let peer = match self.matched.take() {
+            Some(peer) => peer,
+            None => match read_message(&mut self.reader).await? {
+                RelayMessage::Matched { peer } => peer,
+                message => return Err(unexpected(message)),
+            },
+        };
+        let peer_addr = peer.parse().map_err(|_| {
+            ConnectoError::Relay(format!("The relay sent an invalid address: {}", peer))
+        })?;
+
+        // Only devices holding the same code derive the same key
+        let spake = Spake::start(self.role, &self.code);
+        self.send(&RelayMessage::Pake {
+            message: to_hex(spake.message()),
+        })
+        .await?;
+        let peer_message = match self.read_timely().await? {
+            RelayMessage::Pake { message } => from_hex(&message),
+            message => return Err(unexpected(message)),
+        };
+        let key = spake.finish(peer_message.as_deref().unwrap_or_default())?;
+
+        self.send(&RelayMessage::Confirm {
+            mac: to_hex(&confirmation(&key, self.role).finalize().into_bytes()),
+        })
+        .await?;
+        let confirmed = match self.read_timely().await? {
+            RelayMessage::Confirm { mac } => from_hex(&mac).is_some_and(|mac| {
+                confirmation(&key, self.role.other())
+                    .verify_slice(&mac)
+                    .is_ok()
+            }),
+            message => return Err(unexpected(message)),
+        };
+        if !confirmed {
+            return Err(ConnectoError::Relay(
+                "The other device used a different code".to_string(),
+            ));
+        }
+
+        Ok(RelayChannel {
+            peer_addr,
+            reader: self.reader,
+            writer: self.writer,
+            sending: FrameKeys::derive(&key, &format!("{} cipher", self.role.label())),
+            receiving: FrameKeys::derive(&key, &format!("{} cipher", self.role.other().label())),
+        })
+    }
+
+    async fn send(&mut self, message: &RelayMessage) -> Result<()> {
+        self.writer.write_all(message.to_json()?.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn read_timely(&mut self) -> Result<RelayMessage> {
+        tokio::time::timeout(MESSAGE_TIMEOUT, read_message(&mut self.reader))
+            .await
+            .map_err(|_| {
+                ConnectoError::Timeout("The other device stopped responding".to_string())
+            })?
+    }
+}
+
+/// Read one message, turning the relay's errors into [`ConnectoError::Relay`]
+async fn read_message(reader: &mut BufReader<OwnedReadHalf>) -> Result<RelayMessage> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(ConnectoError::Relay(
+            "The relay closed the connection".to_string(),
+        ));
+    }
+    match RelayMessage::from_json(&line)? {
+        RelayMessage::Error { code, message } if code != ROOM_TAKEN => {
+            Err(ConnectoError::Relay(message))
+        }
+        message => Ok(message),
+    }
+}
+
+fn unexpected(message: RelayMessage) -> ConnectoError {
+    ConnectoError::Relay(format!("Unexpected message: {:?}", message))
+}
+
+/// An end-to-end encrypted channel to the other device through a relay
+pub struct RelayChannel {
+    peer_addr: SocketAddr,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    sending: FrameKeys,
+    receiving: FrameKeys,
+}
+
+impl RelayChannel {
+    /// The other device's address, as the relay sees it
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Use the channel as a plain stream
+    ///
+    /// Background tasks encrypt what is written to the stream and decrypt
+    /// what arrives; a frame that fails authentication closes the channel.
+    pub fn into_stream(self) -> DuplexStream {
+        let (local, remote) = tokio::io::duplex(2 * MAX_FRAME);
+        let (mut remote_reader, mut remote_writer) = tokio::io::split(remote);
+        let Self {
+            mut reader,
+            mut writer,
+            mut sending,
+            mut receiving,
+            ..
+        } = self;
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_FRAME];
+            let sent: std::io::Result<()> = async {
+                loop {
+                    let n = remote_reader.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    writer.write_all(&sending.seal(&buf[..n])).await?;
+                }
+                writer.shutdown().await
+            }
+            .await;
+            if let Err(e) = sent {
+                debug!("Relay channel closed while sending: {}", e);
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                match read_frame(&mut reader, &mut receiving).await {
+                    Ok(Some(data)) => {
+                        if remote_writer.write_all(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Closing relay channel: {}", e);
+                        break;
+                    }
+                }
+            }
+            let _ = remote_writer.shutdown().await;
+        });
+
+        local
+    }
+}
+
+/// Read and decrypt the next frame, or `None` at the end of the stream
+async fn read_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    keys: &mut FrameKeys,
+) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME {
+        return Err(ConnectoError::Relay(format!(
+            "Frame of {} bytes is too long",
+            len
+        )));
+    }
+    let mut body = vec![0; len + TAG_LEN];
+    reader.read_exact(&mut body).await?;
+    keys.open(&header, &body)
+        .map(Some)
+        .ok_or_else(|| ConnectoError::Relay("A frame failed authentication".to_string()))
+}
+
+/// One side of a SPAKE2 key agreement; the listener is side A
+struct Spake {
+    state: Spake2<Ed25519Group>,
+    message: Vec<u8>,
+}
+
+impl Spake {
+    fn start(role: RelayRole, code: &RelayCode) -> Self {
+        let password = Password::new(code.to_string());
+        let listener = Identity::new(b"connecto relay listener");
+        let client = Identity::new(b"connecto relay client");
+        let (state, message) = match role {
+            RelayRole::Listener => Spake2::start_a(&password, &listener, &client),
+            RelayRole::Client => Spake2::start_b(&password, &listener, &client),
+        };
+        Self { state, message }
+    }
+
+    fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// The shared key, given the other side's message
+    fn finish(self, peer_message: &[u8]) -> Result<[u8; 32]> {
+        let key = self
+            .state
+            .finish(peer_message)
+            .map_err(|_| ConnectoError::Relay("Invalid key agreement message".to_string()))?;
+        key.try_into()
+            .map_err(|_| ConnectoError::Relay("Invalid key agreement message".to_string()))
+    }
+}
+
+/// The MAC `sender` proves it derived `key` with
+fn confirmation(key: &[u8; 32], sender: RelayRole) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(format!("{} confirm", sender.label()).as_bytes());
+    mac
+}
+
+/// Events from a running relay
+#[derive(Debug, Clone)]
+pub enum RelayEvent {
+    /// The relay is accepting devices
+    Started { address: SocketAddr },
+    /// A device opened or joined a room and waits for the other one
+    Waiting { room: String, address: SocketAddr },
+    /// Two devices met and are being relayed
+    Matched {
+        room: String,
+        listener: SocketAddr,
+        client: SocketAddr,
+    },
+    /// Relaying between the devices in `room` ended
+    Closed { room: String },
+    /// A device was turned away
+    Refused { address: SocketAddr, reason: String },
+}
+
+/// A device waiting in a room
+struct Waiter {
+    id: u64,
+    role: RelayRole,
+    /// Hands the other device's connection to the waiting device's task
+    match_tx: oneshot::Sender<(BufReader<TcpStream>, SocketAddr)>,
+}
+
+type Rooms = Arc<Mutex<HashMap<String, Waiter>>>;
+
+/// Rendezvous server that forwards between devices joining the same room
+pub struct RelayServer {
+    listener: Option<TcpListener>,
+    wait: Duration,
+    rooms: Rooms,
+    next_id: Arc<AtomicU64>,
+}
+
+impl RelayServer {
+    pub fn new() -> Self {
+        Self {
+            listener: None,
+            wait: Duration::from_secs(RELAY_WAIT_SECS),
+            rooms: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+
+    /// Close a room nobody else joins within `wait` instead of the default
+    pub fn with_wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    /// Start listening on the specified port
+    pub async fn listen(&mut self, port: u16) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
+            .await
+            .map_err(|e| ports::bind_error(port, e))?;
+        let local_addr = listener.local_addr()?;
+        info!("Relay listening on {}", local_addr);
+        self.listener = Some(listener);
+        Ok(local_addr)
+    }
+
+    /// Accept and relay devices until the task is dropped
+    pub async fn run(&mut self, event_tx: mpsc::Sender<RelayEvent>) -> Result<()> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| ConnectoError::Network("Relay not started".to_string()))?;
+        let address = listener.local_addr()?;
+        let _ = event_tx.send(RelayEvent::Started { address }).await;
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    let device = Device {
+                        rooms: Arc::clone(&self.rooms),
+                        next_id: Arc::clone(&self.next_id),
+                        wait: self.wait,
+                        event_tx: event_tx.clone(),
+                    };
+                    tokio::spawn(async move {
+                        if let Err(e) = device.serve(stream, address).await {
+                            debug!("Relay connection from {} ended: {}", address, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept connection: {}", e),
+            }
+        }
+    }
+}
+
+impl Default for RelayServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the relay does with a device that joined a room
+enum Placement {
+    /// Wait for the other device
+    Wait(u64, oneshot::Receiver<(BufReader<TcpStream>, SocketAddr)>),
+    /// The other device was waiting and got this one's connection
+    Handed,
+    Refuse(u32, &'static str),
+}
+
+/// The relay's view of one connected device
+struct Device {
+    rooms: Rooms,
+    next_id: Arc<AtomicU64>,
+    wait: Duration,
+    event_tx: mpsc::Sender<RelayEvent>,
+}
+
+impl Device {
+    async fn serve(self, stream: TcpStream, address: SocketAddr) -> Result<()> {
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        tokio::time::timeout(
+            MESSAGE_TIMEOUT,
+            (&mut stream).take(MAX_LINE).read_line(&mut line),
+        )
+        .await
+        .map_err(|_| ConnectoError::Timeout("No Join message".to_string()))??;
+
+        let (room, role) = match RelayMessage::from_json(&line) {
+            Ok(RelayMessage::Join { version, .. }) if version != RELAY_VERSION => {
+                let reason = format!(
+                    "Relay protocol version mismatch: expected {}, got {}",
+                    RELAY_VERSION, version
+                );
+                return self.refuse(stream, address, 1, &reason).await;
+            }
+            Ok(RelayMessage::Join { room, role, .. }) if valid_room(&room) => (room, role),
+            _ => {
+                return self
+                    .refuse(stream, address, 2, "Expected Join message")
+                    .await
+            }
+        };
+
+        let mut stream = Some(stream);
+        let (id, matched_rx) = loop {
+            match self.place(&room, role, address, stream.take().expect("stream is kept")) {
+                (Placement::Wait(id, matched_rx), Some(own)) => {
+                    stream = Some(own);
+                    break (id, matched_rx);
+                }
+                (Placement::Handed, _) => return Ok(()),
+                (Placement::Refuse(code, reason), Some(own)) => {
+                    return self.refuse(own, address, code, reason).await;
+                }
+                // The waiting device left just now; take its place
+                (_, own) => stream = own,
+            }
+        };
+        let mut stream = stream.expect("stream is kept");
+
+        stream
+            .write_all(RelayMessage::Waiting.to_json()?.as_bytes())
+            .await?;
+        let _ = self
+            .event_tx
+            .send(RelayEvent::Waiting {
+                room: room.clone(),
+                address,
+            })
+            .await;
+
+        enum Outcome {
+            Matched(BufReader<TcpStream>, SocketAddr),
+            Expired,
+            Left,
+        }
+        let outcome = tokio::select! {
+            matched = matched_rx => match matched {
+                Ok((other, other_address)) => Outcome::Matched(other, other_address),
+                Err(_) => Outcome::Left,
+            },
+            _ = tokio::time::sleep(self.wait) => Outcome::Expired,
+            // Nothing is sent before a match, so this is the device leaving
+            _ = stream.fill_buf() => Outcome::Left,
+        };
+        let (mut other, other_address) = match outcome {
+            Outcome::Matched(other, other_address) => (other, other_address),
+            outcome => {
+                self.leave(&room, id);
+                if let Outcome::Expired = outcome {
+                    let error = RelayMessage::Error {
+                        code: 5,
+                        message: "Nobody joined with this code in time".to_string(),
+                    };
+                    let _ = stream.write_all(error.to_json()?.as_bytes()).await;
+                }
+                return Ok(());
+            }
+        };
+
+        let matched = |peer: SocketAddr| RelayMessage::Matched {
+            peer: peer.to_string(),
+        };
+        stream
+            .write_all(matched(other_address).to_json()?.as_bytes())
+            .await?;
+        other
+            .write_all(matched(address).to_json()?.as_bytes())
+            .await?;
+        let (listener, client) = match role {
+            RelayRole::Listener => (address, other_address),
+            RelayRole::Client => (other_address, address),
+        };
+        info!("Relaying room {} between {} and {}", room, listener, client);
+        let _ = self
+            .event_tx
+            .send(RelayEvent::Matched {
+                room: room.clone(),
+                listener,
+                client,
+            })
+            .await;
+
+        let relayed = tokio::time::timeout(
+            SESSION_LIMIT,
+            tokio::io::copy_bidirectional(&mut stream, &mut other),
+        )
+        .await;
+        if relayed.is_err() {
+            debug!("Room {} reached the session limit", room);
+        }
+        let _ = self.event_tx.send(RelayEvent::Closed { room }).await;
+        Ok(())
+    }
+
+    /// Put the device in `room`, or hand it to the device waiting there
+    ///
+    /// Gives the stream back unless it was handed over.
+    fn place(
+        &self,
+        room: &str,
+        role: RelayRole,
+        address: SocketAddr,
+        stream: BufReader<TcpStream>,
+    ) -> (Placement, Option<BufReader<TcpStream>>) {
+        let mut rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        match rooms.remove(room) {
+            Some(waiter) if waiter.role == role.other() => {
+                match waiter.match_tx.send((stream, address)) {
+                    Ok(()) => (Placement::Handed, None),
+                    Err((stream, _)) => (Placement::Handed, Some(stream)),
+                }
+            }
+            Some(waiter) => {
+                rooms.insert(room.to_string(), waiter);
+                (
+                    Placement::Refuse(ROOM_TAKEN, "This code is already in use"),
+                    Some(stream),
+                )
+            }
+            None if rooms.len() >= MAX_ROOMS => (
+                Placement::Refuse(4, "The relay is busy; try again later"),
+                Some(stream),
+            ),
+            None => {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                let (match_tx, match_rx) = oneshot::channel();
+                rooms.insert(room.to_string(), Waiter { id, role, match_tx });
+                (Placement::Wait(id, match_rx), Some(stream))
+            }
+        }
+    }
+
+    /// Close `room` if the waiter `id` still holds it
+    fn leave(&self, room: &str, id: u64) {
+        let mut rooms = self.rooms.lock().unwrap_or_else(|e| e.into_inner());
+        if rooms.get(room).is_some_and(|waiter| waiter.id == id) {
+            rooms.remove(room);
+        }
+    }
+
+    async fn refuse(
+        &self,
+        mut stream: BufReader<TcpStream>,
+        address: SocketAddr,
+        code: u32,
+        reason: &str,
+    ) -> Result<()> {
+        let error = RelayMessage::Error {
+            code,
+            message: reason.to_string(),
+        };
+        stream.write_all(error.to_json()?.as_bytes()).await?;
+        let _ = self
+            .event_tx
+            .send(RelayEvent::Refused {
+                address,
+                reason: reason.to_string(),
+            })
+            .await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn start_relay(wait: Duration) -> (String, mpsc::Receiver<RelayEvent>) {
+        let mut server = RelayServer::new().with_wait(wait);
+        let address = server.listen(0).await.unwrap();
+        let (event_tx, event_rx) = mpsc::channel(16);
+        tokio::spawn(async move { server.run(event_tx).await });
+        (format!("127.0.0.1:{}", address.port()), event_rx)
+    }
+
+    #[test]
+    fn test_relay_code() {
+        let code = RelayCode::generate();
+        let parsed: RelayCode = code.to_string().parse().unwrap();
+        assert_eq!(parsed, code);
+        assert_eq!(code.room().len(), 4);
+
+        let lowercase: RelayCode = "4821-k7pq2m".parse().unwrap();
+        assert_eq!(lowercase.to_string(), "4821-K7PQ2M");
+        assert!("4821".parse::<RelayCode>().is_err());
+        assert!("ABCD-K7PQ2M".parse::<RelayCode>().is_err());
+        assert!("4821-K7".parse::<RelayCode>().is_err());
+    }
+
+    #[test]
+    fn test_relay_address() {
+        assert_eq!(relay_address("relay.example.com"), "relay.example.com:8098");
+        assert_eq!(relay_address("10.0.0.5:9000"), "10.0.0.5:9000");
+        assert_eq!(relay_address("2001:db8::1"), "[2001:db8::1]:8098");
+        assert_eq!(relay_address("[2001:db8::1]"), "[2001:db8::1]:8098");
+    }
+
+    #[test]
+    fn test_key_agreement() {
+        let code: RelayCode = "4821-K7PQ2M".parse().unwrap();
+        let listener = Spake::start(RelayRole::Listener, &code);
+        let client = Spake::start(RelayRole::Client, &code);
+        let (listener_message, client_message) =
+            (listener.message().to_vec(), client.message().to_vec());
+        assert_eq!(
+            listener.finish(&client_message).unwrap(),
+            client.finish(&listener_message).unwrap()
+        );
+
+        let wrong: RelayCode = "4821-K7PQ2N".parse().unwrap();
+        let listener = Spake::start(RelayRole::Listener, &code);
+        let client = Spake::start(RelayRole::Client, &wrong);
+        let (listener_message, client_message) =
+            (listener.message().to_vec(), client.message().to_vec());
+        assert_ne!(
+            listener.finish(&client_message).unwrap(),
+            client.finish(&listener_message).unwrap()
+        );
+
+        let listener = Spake::start(RelayRole::Listener, &code);
+        assert!(listener.finish(&[0xff; 33]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_round_trip() {
+        let (relay, mut events) = start_relay(Duration::from_secs(10)).await;
+        let pending = PendingChannel::open(&relay).await.unwrap();
+        let code = pending.code().clone();
+
+        let listener = tokio::spawn(async move {
+            let mut stream = pending.connect().await.unwrap().into_stream();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(b"world").await.unwrap();
+            buf
+        });
+
+        let channel = PendingChannel::join(&relay, &code)
+            .await
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        assert!(channel.peer_addr().ip().is_loopback());
+        let mut stream = channel.into_stream();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(&buf, b"world");
+        assert_eq!(&listener.await.unwrap(), b"hello");
+        assert!(matches!(
+            events.recv().await,
+            Some(RelayEvent::Started { .. })
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Some(RelayEvent::Waiting { room, .. }) if room == code.room()
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Some(RelayEvent::Matched { room, .. }) if room == code.room()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_relay_wrong_code() {
+        let (relay, _events) = start_relay(Duration::from_secs(10)).await;
+        let pending = PendingChannel::open(&relay).await.unwrap();
+        let wrong: RelayCode = format!("{}-WRONG2", pending.code().room()).parse().unwrap();
+
+        let listener = tokio::spawn(pending.connect());
+        let client = PendingChannel::join(&relay, &wrong)
+            .await
+            .unwrap()
+            .connect()
+            .await;
+
+        assert!(client.is_err());
+        assert!(listener.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_room_taken() {
+        let (relay, _events) = start_relay(Duration::from_secs(10)).await;
+        let code = RelayCode::generate();
+        let _first = PendingChannel::join(&relay, &code).await.unwrap();
+
+        // A room holds one device of each role
+        let second = PendingChannel::join(&relay, &code).await;
+        assert!(matches!(second, Err(ConnectoError::Relay(_))));
+        let listener = PendingChannel::join_as(&relay, code, RelayRole::Listener).await;
+        assert!(matches!(listener, Ok(Joined::Pending(_))));
+    }
+
+    #[tokio::test]
+    async fn test_relay_wait_expires() {
+        let (relay, _events) = start_relay(Duration::from_millis(100)).await;
+        let pending = PendingChannel::open(&relay).await.unwrap();
+        let result = pending.connect().await;
+        assert!(
+            matches!(result, Err(ConnectoError::Relay(message)) if message.contains("in time"))
+        );
+    }
+}
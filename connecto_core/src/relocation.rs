@@ -0,0 +1,221 @@
+//! Paired hosts that moved to a new address
+//!
+//! A device that gets a new DHCP lease stops answering at the address in its
+//! SSH config entry. Devices found on the network are matched to the entry by
+//! identity, or by name for entries and devices without one, using the names
+//! recorded in the pairing database as well as the alias. The entry then
+//! follows the device, and so do the host keys trusted for its old address.
+//! An entry bound to an identity only follows a device that proved it.
+
+use crate::connectivity::SSH_PORT;
+use crate::discovery::DiscoveredDevice;
+use crate::error::{ConnectoError, Result};
+use crate::identity::VerifiedIdentity;
+use crate::known_hosts::KnownHostsStore;
+use crate::pairings::PairingStore;
+use crate::ssh_config::{host_alias, HostEntry, SshConfig};
+
+/// A host paired with from this machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairedHost {
+    /// The host's entry in `~/.ssh/config`
+    pub entry: HostEntry,
+    /// Device names recorded for the host's pairings, oldest first
+    pub peer_names: Vec<String>,
+}
+
+impl PairedHost {
+    /// Every Connecto entry in `ssh_config`, with the device names `store`
+    /// recorded for it
+    pub fn all(ssh_config: &SshConfig, store: &PairingStore) -> Result<Vec<Self>> {
+        let records = store.all()?;
+        Ok(ssh_config
+            .entries()?
+            .into_iter()
+            .map(|entry| {
+                let mut peer_names: Vec<String> = Vec::new();
+                for record in records
+                    .iter()
+                    .filter(|r| r.host.as_deref() == Some(entry.host.as_str()))
+                {
+                    if !peer_names.contains(&record.peer_name) {
+                        peer_names.push(record.peer_name.clone());
+                    }
+                }
+                Self { entry, peer_names }
+            })
+            .collect())
+    }
+
+    /// The alias of the host in `~/.ssh/config`
+    pub fn host(&self) -> &str {
+        &self.entry.host
+    }
+
+    /// Port of the host's SSH server
+    pub fn port(&self) -> u16 {
+        self.entry.port.unwrap_or(SSH_PORT)
+    }
+
+    /// Whether `device` is the device paired as this host
+    ///
+    /// An entry bound to an identity only matches a device announcing the
+    /// same one; a device announcing none is matched by name. Announcing is
+    /// not proving, so [`relocate`] still wants the proof.
+    pub fn matches(&self, device: &DiscoveredDevice) -> bool {
+        match (&self.entry.identity, &device.identity) {
+            (Some(expected), Some(found)) => expected == found,
+            _ => {
+                let name = device.device_name();
+                host_alias(name) == self.entry.host || self.peer_names.iter().any(|n| n == name)
+            }
+        }
+    }
+
+    /// The device among `devices` that is this host's, if any
+    pub fn find_device_in<'a>(
+        &self,
+        devices: &'a [DiscoveredDevice],
+    ) -> Option<&'a DiscoveredDevice> {
+        devices.iter().find(|device| self.matches(device))
+    }
+
+    /// The address `devices` found this host's device at, if any
+    pub fn find_in(&self, devices: &[DiscoveredDevice]) -> Option<String> {
+        self.find_device_in(devices)
+            .and_then(DiscoveredDevice::primary_host)
+    }
+}
+
+/// Point the SSH config entry of `paired` at `address`, trusting the host
+/// keys of its old address there too
+///
+/// An entry bound to an identity is only moved when the device at `address`
+/// proved that identity as `proven`; otherwise this fails with
+/// [`ConnectoError::IdentityMismatch`]. Returns whether the entry changed.
+pub fn relocate(
+    ssh_config: &SshConfig,
+    known_hosts: &KnownHostsStore,
+    paired: &PairedHost,
+    address: &str,
+    proven: Option<&VerifiedIdentity>,
+) -> Result<bool> {
+    if let Some(expected) = &paired.entry.identity {
+        if proven.map(VerifiedIdentity::fingerprint) != Some(expected.as_str()) {
+            return Err(ConnectoError::IdentityMismatch(format!(
+                "The device at {} did not prove identity {} of '{}'",
+                address,
+                expected,
+                paired.host()
+            )));
+        }
+    }
+    if !ssh_config.set_hostname(paired.host(), address)? {
+        return Ok(false);
+    }
+    let keys = known_hosts.keys_of(&paired.entry.hostname, paired.port())?;
+    if !keys.is_empty() {
+        known_hosts.record(address, paired.port(), &keys)?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::DeviceIdentity;
+    use crate::keys::{KeyAlgorithm, SshKeyPair};
+    use crate::pairings::{PairingDirection, PairingRecord};
+    use std::fs;
+    use tempfile::TempDir;
+
+    const HOST_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAINbgPhPjAs5nRMnMgrJCO8ryVDeoTAERX3CACqShfgww";
+
+    fn device(name: &str, address: &str, identity: Option<&str>) -> DiscoveredDevice {
+        DiscoveredDevice {
+            name: format!("{} (desk-pc)._connecto._tcp.local.", name),
+            hostname: "desk-pc.local.".to_string(),
+            addresses: vec![address.parse().unwrap()],
+            port: 8099,
+            instance_name: format!("{} (desk-pc)._connecto._tcp.local.", name),
+            identity: identity.map(str::to_string),
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        }
+    }
+
+    #[test]
+    fn test_find_and_relocate() {
+        let temp = TempDir::new().unwrap();
+        let store = PairingStore::with_path(temp.path().join("pairings.json"));
+        let ssh_config = SshConfig::with_path(temp.path().join("config"));
+        let known_hosts = KnownHostsStore::with_path(temp.path().join("known_hosts"));
+        let identity = DeviceIdentity::load_or_create_at(&temp.path().join("identity")).unwrap();
+        let proven = identity.prove("nonce").unwrap().verify("nonce").unwrap();
+        fs::write(
+            ssh_config.path(),
+            format!("# Added by connecto\nHost my_desk\n    HostName 10.0.0.5\n    User me\n    Port 2222\n    # connecto-identity {}\n    IdentityFile ~/.ssh/connecto_my_desk\n\n# Added by connecto\nHost nas\n    HostName 10.0.0.6\n    User me\n    IdentityFile ~/.ssh/connecto_nas\n", proven),
+        )
+        .unwrap();
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "me@laptop").unwrap();
+        let record = PairingRecord::new(
+            "Storage",
+            &key.public_key,
+            "10.0.0.6",
+            PairingDirection::Outgoing,
+        )
+        .unwrap()
+        .with_host("nas");
+        store.record(record).unwrap();
+        known_hosts
+            .record("10.0.0.5", 2222, &[HOST_KEY.to_string()])
+            .unwrap();
+
+        let hosts = PairedHost::all(&ssh_config, &store).unwrap();
+        assert_eq!(hosts.len(), 2);
+        let (desk, nas) = (&hosts[0], &hosts[1]);
+        assert_eq!(desk.port(), 2222);
+        assert!(desk.peer_names.is_empty());
+        assert_eq!(nas.peer_names, ["Storage"]);
+
+        // Identities win over names
+        let devices = [
+            device("My Desk", "10.0.0.7", Some("SHA256:other")),
+            device("Study", "10.0.0.9", Some(proven.fingerprint())),
+            device("Storage", "10.0.0.8", None),
+        ];
+        assert_eq!(desk.find_in(&devices).as_deref(), Some("10.0.0.9"));
+        // Found under the name it was paired with, not only its alias
+        assert_eq!(nas.find_in(&devices).as_deref(), Some("10.0.0.8"));
+        assert_eq!(desk.find_in(&devices[..1]), None);
+
+        // Announcing the identity is not enough to move the entry
+        let err = relocate(&ssh_config, &known_hosts, desk, "10.0.0.9", None).unwrap_err();
+        assert!(matches!(err, ConnectoError::IdentityMismatch(_)));
+        let other = DeviceIdentity::load_or_create_at(&temp.path().join("other")).unwrap();
+        let impostor = other.prove("nonce").unwrap().verify("nonce").unwrap();
+        assert!(relocate(&ssh_config, &known_hosts, desk, "10.0.0.9", Some(&impostor)).is_err());
+        assert_eq!(ssh_config.entries().unwrap()[0].hostname, "10.0.0.5");
+
+        assert!(relocate(&ssh_config, &known_hosts, desk, "10.0.0.9", Some(&proven)).unwrap());
+        let entries = ssh_config.entries().unwrap();
+        assert_eq!(entries[0].hostname, "10.0.0.9");
+        assert_eq!(entries[0].port, Some(2222));
+        assert_eq!(entries[1].hostname, "10.0.0.6");
+        assert_eq!(
+            known_hosts.keys_of("10.0.0.9", 2222).unwrap(),
+            [HOST_KEY.to_string()]
+        );
+        // The old address keeps its keys, in case another entry uses it
+        assert_eq!(known_hosts.keys_of("10.0.0.5", 2222).unwrap().len(), 1);
+
+        let moved = PairedHost::all(&ssh_config, &store).unwrap().remove(0);
+        assert!(!relocate(&ssh_config, &known_hosts, &moved, "10.0.0.9", Some(&proven)).unwrap());
+        // Entries without an identity follow the device by name
+        assert!(relocate(&ssh_config, &known_hosts, nas, "10.0.0.8", None).unwrap());
+    }
+}
@@ -0,0 +1,187 @@
+//! Name changes of paired devices
+//!
+//! A listener can be renamed after it was paired with. Devices found on the
+//! network are matched to pairings by identity, so the pairing records follow
+//! the new name right away. SSH config aliases made from the old name are only
+//! proposed for renaming, as scripts and habits may rely on them.
+
+use crate::discovery::DiscoveredDevice;
+use crate::error::Result;
+use crate::pairings::PairingStore;
+use crate::ssh_config::{host_alias, SshConfig};
+use serde::Serialize;
+
+/// A paired device that announces a new name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rename {
+    /// Identity fingerprint of the device
+    pub identity: String,
+    pub old_name: String,
+    pub new_name: String,
+    /// SSH config aliases made from the old name that could follow it
+    pub aliases: Vec<AliasRename>,
+}
+
+/// A proposed rename of an SSH config alias
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AliasRename {
+    pub host: String,
+    pub new_host: String,
+}
+
+/// Record the names `devices` announce, returning the paired devices that
+/// were renamed since they were last seen
+pub fn refresh(
+    store: &PairingStore,
+    ssh_config: &SshConfig,
+    devices: &[DiscoveredDevice],
+) -> Result<Vec<Rename>> {
+    let entries = ssh_config.entries()?;
+    let mut renames = Vec::new();
+    for device in devices {
+        let Some(identity) = &device.identity else {
+            continue;
+        };
+        let name = device.device_name();
+        if name.is_empty() {
+            continue;
+        }
+        let Some(old_name) = store.rename_peer(identity, name)? else {
+            continue;
+        };
+
+        // Aliases picked by hand are not derived from the old name
+        let old_host = host_alias(&old_name);
+        let new_host = host_alias(name);
+        let taken = entries.iter().any(|e| e.host == new_host);
+        let aliases = entries
+            .iter()
+            .filter(|e| !taken && e.host == old_host && e.host != new_host)
+            .filter(|e| e.identity.as_deref() == Some(identity.as_str()))
+            .map(|e| AliasRename {
+                host: e.host.clone(),
+                new_host: new_host.clone(),
+            })
+            .collect();
+        renames.push(Rename {
+            identity: identity.clone(),
+            old_name,
+            new_name: name.to_string(),
+            aliases,
+        });
+    }
+    Ok(renames)
+}
+
+/// Rename the SSH config alias `host` to `new_host`, along with the pairings
+/// recorded for it
+///
+/// Returns false if `host` has no entry or `new_host` is already taken.
+pub fn rename_alias(
+    store: &PairingStore,
+    ssh_config: &SshConfig,
+    host: &str,
+    new_host: &str,
+) -> Result<bool> {
+    if !ssh_config.rename_host(host, new_host)? {
+        return Ok(false);
+    }
+    store.rename_host(host, new_host)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{KeyAlgorithm, SshKeyPair};
+    use crate::pairings::{PairingDirection, PairingRecord};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn device(name: &str, identity: &str) -> DiscoveredDevice {
+        DiscoveredDevice {
+            name: format!("{} (desk-pc)._connecto._tcp.local.", name),
+            hostname: "desk-pc.local.".to_string(),
+            addresses: vec!["10.0.0.5".parse().unwrap()],
+            port: 8099,
+            instance_name: format!("{} (desk-pc)._connecto._tcp.local.", name),
+            identity: Some(identity.to_string()),
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
+        }
+    }
+
+    #[test]
+    fn test_refresh_and_rename_alias() {
+        let temp = TempDir::new().unwrap();
+        let store = PairingStore::with_path(temp.path().join("pairings.json"));
+        let ssh_config = SshConfig::with_path(temp.path().join("config"));
+        fs::write(
+            ssh_config.path(),
+            "# Added by connecto\nHost my_desk\n    HostName 10.0.0.5\n    User me\n    # connecto-identity SHA256:desk\n    IdentityFile ~/.ssh/connecto_my_desk\n\n# Added by connecto\nHost nas\n    HostName 10.0.0.6\n    User me\n    # connecto-identity SHA256:nas\n    IdentityFile ~/.ssh/connecto_nas\n",
+        )
+        .unwrap();
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "me@laptop").unwrap();
+        for (name, host, identity) in [
+            ("My Desk", "my_desk", "SHA256:desk"),
+            ("Storage", "nas", "SHA256:nas"),
+        ] {
+            let record = PairingRecord::new(
+                name,
+                &key.public_key,
+                "10.0.0.5",
+                PairingDirection::Outgoing,
+            )
+            .unwrap()
+            .with_host(host)
+            .with_peer_identity(Some(identity));
+            store.record(record).unwrap();
+        }
+
+        let unpaired = device("Kitchen", "SHA256:kitchen");
+        let renames = refresh(
+            &store,
+            &ssh_config,
+            &[
+                device("Study", "SHA256:desk"),
+                device("Attic", "SHA256:nas"),
+                unpaired,
+            ],
+        )
+        .unwrap();
+        assert_eq!(renames.len(), 2);
+        assert_eq!(renames[0].old_name, "My Desk");
+        assert_eq!(renames[0].new_name, "Study");
+        assert_eq!(
+            renames[0].aliases,
+            vec![AliasRename {
+                host: "my_desk".to_string(),
+                new_host: "study".to_string(),
+            }]
+        );
+        // nas was not named after the device, so it stays
+        assert_eq!(renames[1].new_name, "Attic");
+        assert!(renames[1].aliases.is_empty());
+
+        // The records already carry the new names
+        assert!(
+            refresh(&store, &ssh_config, &[device("Study", "SHA256:desk")])
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(rename_alias(&store, &ssh_config, "my_desk", "study").unwrap());
+        let hosts: Vec<String> = ssh_config
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.host)
+            .collect();
+        assert_eq!(hosts, ["study", "nas"]);
+        assert_eq!(store.for_host("study").unwrap()[0].peer_name, "Study");
+        assert!(!rename_alias(&store, &ssh_config, "study", "nas").unwrap());
+    }
+}
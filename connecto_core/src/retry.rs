@@ -0,0 +1,231 @@
+//! Retry module
+//!
+//! On a busy WiFi network the first connection to a device often fails
+//! while later ones go through. A [`RetryPolicy`] tries again a few times,
+//! waiting twice as long before each try and spreading the waits a little
+//! so several clients do not retry in lockstep. Only failures that may pass
+//! are retried: a device that answered and refused is not asked again.
+
+use crate::error::{ConnectoError, Result};
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+
+/// Attempts a connection gets by default, the first one included
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry by default
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest wait between two attempts by default
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+/// Share of each wait that is randomized by default
+pub const DEFAULT_RETRY_JITTER: f64 = 0.25;
+
+/// A failed attempt about to be retried
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retry {
+    /// The attempt that failed, from 1
+    pub attempt: u32,
+    /// Attempts in the policy
+    pub attempts: u32,
+    /// How long until the next attempt
+    pub delay: Duration,
+    /// Why the attempt failed
+    pub reason: String,
+}
+
+/// How often and how patiently to retry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    attempts: u32,
+    delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETRY_ATTEMPTS)
+    }
+}
+
+impl RetryPolicy {
+    /// Make up to `attempts` attempts (at least one), with the default
+    /// backoff
+    pub fn new(attempts: u32) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            delay: DEFAULT_RETRY_DELAY,
+            max_delay: DEFAULT_MAX_RETRY_DELAY,
+            jitter: DEFAULT_RETRY_JITTER,
+        }
+    }
+
+    /// Make a single attempt
+    pub fn none() -> Self {
+        Self::new(1)
+    }
+
+    /// Wait `delay` before the first retry, doubling up to `max_delay`
+    pub fn with_backoff(mut self, delay: Duration, max_delay: Duration) -> Self {
+        self.delay = delay;
+        self.max_delay = max_delay.max(delay);
+        self
+    }
+
+    /// Randomize `jitter` of each wait (0 to 1): 0.25 waits between 75% and
+    /// 125% of it
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Attempts in the policy, the first one included
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// How long to wait after failed attempt `attempt` (from 1), before
+    /// jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.delay
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay)
+    }
+
+    /// [`RetryPolicy::backoff`] with the jitter applied
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff(attempt);
+        if self.jitter == 0.0 {
+            return backoff;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+        backoff.mul_f64(factor)
+    }
+
+    /// Run `operation` until it succeeds, fails for good, or runs out of
+    /// attempts
+    ///
+    /// `on_retry` hears about each failed attempt that is retried. The error
+    /// of the last attempt is returned.
+    pub async fn run<T, F>(
+        &self,
+        mut operation: impl FnMut() -> F,
+        mut on_retry: impl FnMut(&Retry),
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    let retry = Retry {
+                        attempt,
+                        attempts: self.attempts,
+                        delay: self.delay(attempt),
+                        reason: e.to_string(),
+                    };
+                    on_retry(&retry);
+                    tokio::time::sleep(retry.delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether `error` may go away on another attempt
+///
+/// Timeouts and network errors do; refused connections, rejections and
+/// protocol errors come from a device that answered, and do not.
+pub fn is_transient(error: &ConnectoError) -> bool {
+    matches!(error, ConnectoError::Network(_) | ConnectoError::Timeout(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let policy = RetryPolicy::new(10)
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(700));
+        let waits: Vec<_> = (1..=5).map(|n| policy.backoff(n).as_millis()).collect();
+        assert_eq!(waits, [100, 200, 400, 700, 700]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(700));
+
+        let jittered = RetryPolicy::new(2)
+            .with_backoff(Duration::from_millis(1000), Duration::from_secs(10))
+            .with_jitter(0.25);
+        for _ in 0..50 {
+            let delay = jittered.delay(1).as_millis();
+            assert!((750..=1250).contains(&delay), "{}", delay);
+        }
+        assert_eq!(RetryPolicy::none().attempts(), 1);
+        assert_eq!(RetryPolicy::new(0).attempts(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_retries_transient_failures() {
+        let policy = RetryPolicy::new(3).with_jitter(0.0);
+        let calls = Cell::new(0);
+        let mut retries = Vec::new();
+        let result = policy
+            .run(
+                || {
+                    calls.set(calls.get() + 1);
+                    let call = calls.get();
+                    async move {
+                        if call < 3 {
+                            Err(ConnectoError::Network("no route to host".to_string()))
+                        } else {
+                            Ok(call)
+                        }
+                    }
+                },
+                |retry| retries.push(retry.clone()),
+            )
+            .await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(retries.len(), 2);
+        assert_eq!(retries[0].attempt, 1);
+        assert_eq!(retries[0].attempts, 3);
+        assert_eq!(retries[0].delay, DEFAULT_RETRY_DELAY);
+        assert_eq!(retries[1].delay, DEFAULT_RETRY_DELAY * 2);
+        assert!(retries[1].reason.contains("no route to host"));
+
+        // Out of attempts, the last error stands
+        calls.set(0);
+        let result: Result<()> = RetryPolicy::new(2)
+            .run(
+                || {
+                    calls.set(calls.get() + 1);
+                    async { Err(ConnectoError::Timeout("slow".to_string())) }
+                },
+                |_| {},
+            )
+            .await;
+        assert!(matches!(result, Err(ConnectoError::Timeout(_))));
+        assert_eq!(calls.get(), 2);
+
+        // A device that refused is not asked again
+        calls.set(0);
+        let result: Result<()> = policy
+            .run(
+                || {
+                    calls.set(calls.get() + 1);
+                    async { Err(ConnectoError::ConnectionRefused("closed".to_string())) }
+                },
+                |_| panic!("refusals are not retried"),
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,601 @@
+//! Key rotation for paired hosts
+//!
+//! Each host gets the new public key installed over SSH with its old key,
+//! and its `IdentityFile` moved to the new key. Only once logging in with the
+//! new key works is the old key removed from the host. A host that fails at
+//! any step is left as it was, still using its old key.
+//!
+//! Hosts sharing a key get the same new key, and the old key pair is deleted
+//! once no entry in `~/.ssh/config` uses it any more.
+
+use crate::error::{ConnectoError, Result};
+use crate::keys::{KeyAlgorithm, KeyManager, SshKeyPair};
+use crate::paths::expand_home;
+use crate::ssh_config::{HostEntry, SshConfig};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Printed by [`install_command`] once the key is in place
+const INSTALLED_MARKER: &str = "connecto-installed";
+
+/// Printed by [`revoke_command`], followed by how many keys it removed
+const REVOKED_MARKER: &str = "connecto-revoked";
+
+/// Printed by the login check with the new key
+const LOGIN_MARKER: &str = "connecto-ok";
+
+/// Runs commands on paired hosts
+pub trait RemoteShell {
+    /// Run the POSIX shell `command` on `host`, logging in only with the
+    /// private key `identity_file`, and return what it printed
+    fn run(&self, host: &str, identity_file: &Path, command: &str) -> Result<String>;
+}
+
+/// Runs commands with the system `ssh` client, never prompting
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SshCommand;
+
+impl RemoteShell for SshCommand {
+    fn run(&self, host: &str, identity_file: &Path, command: &str) -> Result<String> {
+        let output = Command::new("ssh")
+            .args([
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ConnectTimeout=5",
+                "-o",
+                "IdentitiesOnly=yes",
+                "-i",
+            ])
+            .arg(identity_file)
+            .args([host, command])
+            .output()
+            .map_err(|e| ConnectoError::Network(format!("Failed to run ssh: {}", e)))?;
+
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or("ssh failed")
+            .trim()
+            .to_string();
+        if reason.contains("Permission denied") {
+            Err(ConnectoError::PermissionDenied(reason))
+        } else {
+            Err(ConnectoError::Network(reason))
+        }
+    }
+}
+
+/// Split a public key into `type base64` and a comment safe to put in a
+/// shell command, refusing keys that are not plain OpenSSH public keys
+fn split_public_key(public_key: &str) -> Result<(String, &str, String)> {
+    let mut words = public_key.split_whitespace();
+    let (Some(kind), Some(data)) = (words.next(), words.next()) else {
+        return Err(ConnectoError::KeyParsing(
+            "Invalid public key format".to_string(),
+        ));
+    };
+    let plain = |word: &str, extra: &str| {
+        word.chars()
+            .all(|c| c.is_ascii_alphanumeric() || extra.contains(c))
+    };
+    if !plain(kind, "-@.") || !plain(data, "+/=") {
+        return Err(ConnectoError::KeyParsing(
+            "Invalid public key format".to_string(),
+        ));
+    }
+    // A comment that would need quoting is dropped; it is only a label
+    let comment: Vec<&str> = words.collect();
+    let comment = if comment.iter().all(|word| plain(word, "@._-")) {
+        comment.join(" ")
+    } else {
+        String::new()
+    };
+    Ok((format!("{} {}", kind, data), data, comment))
+}
+
+/// Shell command adding `public_key` to authorized_keys unless it is there
+pub fn install_command(public_key: &str) -> Result<String> {
+    let (key, data, comment) = split_public_key(public_key)?;
+    let line = format!("{} {}", key, comment);
+    Ok(format!(
+        "umask 077; mkdir -p \"$HOME/.ssh\" && f=\"$HOME/.ssh/authorized_keys\" && \
+         {{ grep -qF '{data}' \"$f\" 2>/dev/null || \
+         {{ [ ! -s \"$f\" ] || [ -z \"$(tail -c1 \"$f\")\" ] || echo >> \"$f\"; \
+         echo '{line}' >> \"$f\"; }}; }} && echo {marker}",
+        data = data,
+        line = line.trim_end(),
+        marker = INSTALLED_MARKER
+    ))
+}
+
+/// Shell command removing every authorized_keys line that holds
+/// `public_key`, keeping the file's mode
+///
+/// It prints how many lines it removed, read back with [`parse_revoked`].
+pub fn revoke_command(public_key: &str) -> Result<String> {
+    let (_, data, _) = split_public_key(public_key)?;
+    Ok(format!(
+        "f=\"$HOME/.ssh/authorized_keys\"; [ -f \"$f\" ] || {{ echo {m} 0; exit 0; }}; \
+         n=$(grep -cF '{k}' \"$f\"); t=\"$f.connecto.$$\"; \
+         grep -vF '{k}' \"$f\" > \"$t\"; cat \"$t\" > \"$f\" && rm -f \"$t\" && echo {m} $n",
+        m = REVOKED_MARKER,
+        k = data
+    ))
+}
+
+/// How many keys a [`revoke_command`] removed, from its output
+pub fn parse_revoked(output: &str) -> Option<usize> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(REVOKED_MARKER))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+/// Name for the key replacing the key `name`, stamped with `now`
+///
+/// A stamp left by an earlier rotation is replaced rather than appended to.
+pub fn rotated_key_name(name: &str, now: u64) -> String {
+    let base = match name.rsplit_once('-') {
+        Some((base, stamp)) if !stamp.is_empty() && stamp.chars().all(|c| c.is_ascii_digit()) => {
+            base
+        }
+        _ => name,
+    };
+    format!("{}-{}", base, now)
+}
+
+/// Outcome of rotating one key
+#[derive(Debug)]
+pub struct Rotation {
+    /// Private key the hosts used, as written in `~/.ssh/config`
+    pub old_key: String,
+    /// Private key that replaced it, unless no host took it
+    pub new_key: Option<PathBuf>,
+    /// Whether the old key pair was deleted, as no entry uses it any more
+    pub old_key_deleted: bool,
+    pub hosts: Vec<HostRotation>,
+}
+
+/// Outcome of rotating the key of one host
+#[derive(Debug)]
+pub struct HostRotation {
+    pub host: String,
+    /// Whether the old key was removed from the host, or why the host kept
+    /// its old key
+    pub result: std::result::Result<bool, String>,
+}
+
+/// Replaces the keys of paired hosts
+pub struct KeyRotator<S> {
+    shell: S,
+    ssh_config: SshConfig,
+    algorithm: KeyAlgorithm,
+    shred: bool,
+}
+
+impl<S: RemoteShell> KeyRotator<S> {
+    /// Rotate keys of the hosts in `ssh_config`, reaching them through `shell`
+    pub fn new(shell: S, ssh_config: SshConfig) -> Self {
+        Self {
+            shell,
+            ssh_config,
+            algorithm: KeyAlgorithm::default(),
+            shred: false,
+        }
+    }
+
+    /// Generate new keys of type `algorithm`
+    pub fn with_algorithm(mut self, algorithm: KeyAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Overwrite old private keys before deleting them
+    pub fn with_shred(mut self, shred: bool) -> Self {
+        self.shred = shred;
+        self
+    }
+
+    /// Rotate the keys of `hosts`, one new key per key they share
+    ///
+    /// Hosts without a Connecto entry are skipped.
+    pub fn rotate(&self, hosts: &[String]) -> Result<Vec<Rotation>> {
+        let entries = self.ssh_config.entries()?;
+        let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+        for entry in entries.iter().filter(|e| hosts.contains(&e.host)) {
+            match groups
+                .iter_mut()
+                .find(|(key, _)| *key == entry.identity_file)
+            {
+                Some((_, group)) => group.push(entry.host.clone()),
+                None => groups.push((entry.identity_file.clone(), vec![entry.host.clone()])),
+            }
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut rotations = Vec::new();
+        for (old_key, group) in groups {
+            let rotation = match self.rotate_key(&old_key, &group, &entries, now) {
+                Ok(rotation) => rotation,
+                Err(e) => Rotation {
+                    old_key,
+                    new_key: None,
+                    old_key_deleted: false,
+                    hosts: group
+                        .into_iter()
+                        .map(|host| HostRotation {
+                            host,
+                            result: Err(e.to_string()),
+                        })
+                        .collect(),
+                },
+            };
+            rotations.push(rotation);
+        }
+        Ok(rotations)
+    }
+
+    /// Move `hosts` from `old_key` to a new key
+    fn rotate_key(
+        &self,
+        old_key: &str,
+        hosts: &[String],
+        entries: &[HostEntry],
+        now: u64,
+    ) -> Result<Rotation> {
+        let old_path = expand_home(old_key)?;
+        let old_public = public_key_of(&old_path)?;
+        let (dir, old_name) = split_key_path(&old_path)?;
+        let key_manager = KeyManager::with_dir(dir.clone());
+
+        let comment = old_public
+            .split_whitespace()
+            .skip(2)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let comment = if comment.is_empty() {
+            "connecto".to_string()
+        } else {
+            comment
+        };
+        let key_pair = SshKeyPair::generate(self.algorithm, &comment)?;
+        // Rotating again within a second must not reuse the name
+        let mut stamp = now;
+        while dir.join(rotated_key_name(&old_name, stamp)).exists() {
+            stamp += 1;
+        }
+        let (new_path, _) =
+            key_manager.save_new_key_pair(&key_pair, &rotated_key_name(&old_name, stamp))?;
+
+        // Entries staying on the old key that log in to the same account
+        let shared: Vec<(&str, &str)> = entries
+            .iter()
+            .filter(|e| e.identity_file == old_key && !hosts.contains(&e.host))
+            .map(|e| (e.hostname.as_str(), e.user.as_str()))
+            .collect();
+
+        let mut results = Vec::new();
+        for host in hosts {
+            let keep_old = entries.iter().any(|e| {
+                e.host == *host && shared.contains(&(e.hostname.as_str(), e.user.as_str()))
+            });
+            let result = self.rotate_host(
+                host,
+                old_key,
+                &old_path,
+                &old_public,
+                &new_path,
+                &key_pair.public_key,
+                keep_old,
+            );
+            results.push(HostRotation {
+                host: host.clone(),
+                result,
+            });
+        }
+
+        let new_name = key_name(&new_path)?;
+        let new_key = if results.iter().any(|r| r.result.is_ok()) {
+            Some(new_path)
+        } else {
+            key_manager.delete_key_pair(&new_name, false)?;
+            None
+        };
+
+        let still_used = self
+            .ssh_config
+            .entries()?
+            .iter()
+            .any(|e| e.identity_file == old_key);
+        let old_key_deleted = !still_used && key_manager.delete_key_pair(&old_name, self.shred)?;
+
+        Ok(Rotation {
+            old_key: old_key.to_string(),
+            new_key,
+            old_key_deleted,
+            hosts: results,
+        })
+    }
+
+    /// Move one host to the new key, returning whether the old key was
+    /// removed from it
+    #[allow(clippy::too_many_arguments)]
+    fn rotate_host(
+        &self,
+        host: &str,
+        old_key: &str,
+        old_path: &Path,
+        old_public: &str,
+        new_path: &Path,
+        new_public: &str,
+        keep_old: bool,
+    ) -> std::result::Result<bool, String> {
+        let installed = self
+            .shell
+            .run(
+                host,
+                old_path,
+                &install_command(new_public).map_err(|e| e.to_string())?,
+            )
+            .map_err(|e| format!("could not install the new key: {}", e))?;
+        if !installed.contains(INSTALLED_MARKER) {
+            return Err("could not install the new key".to_string());
+        }
+
+        // ssh offers the IdentityFile of the entry too, so switch it before
+        // checking that the new key alone gets in
+        let new_key = new_path.display().to_string();
+        self.ssh_config
+            .set_identity_file(host, &new_key)
+            .map_err(|e| format!("could not update ~/.ssh/config: {}", e))?;
+        let login = self
+            .shell
+            .run(host, new_path, &format!("echo {}", LOGIN_MARKER));
+        if let Err(reason) = login.and_then(|out| match out.contains(LOGIN_MARKER) {
+            true => Ok(()),
+            false => Err(ConnectoError::Network("unexpected response".to_string())),
+        }) {
+            // Leave the host as it was
+            let _ = self.ssh_config.set_identity_file(host, old_key);
+            if let Ok(command) = revoke_command(new_public) {
+                let _ = self.shell.run(host, old_path, &command);
+            }
+            return Err(format!("login with the new key failed: {}", reason));
+        }
+
+        if keep_old {
+            return Ok(false);
+        }
+        let revoked = revoke_command(old_public)
+            .and_then(|command| self.shell.run(host, new_path, &command))
+            .ok()
+            .and_then(|out| parse_revoked(&out))
+            .is_some_and(|count| count > 0);
+        Ok(revoked)
+    }
+}
+
+/// The public key belonging to the private key at `path`
+pub fn public_key_of(path: &Path) -> Result<String> {
+    let pub_path = PathBuf::from(format!("{}.pub", path.display()));
+    match fs::read_to_string(&pub_path) {
+        Ok(public_key) => Ok(public_key.trim().to_string()),
+        Err(_) => Ok(SshKeyPair::load_from_file(&path.display().to_string())?.public_key),
+    }
+}
+
+fn key_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| ConnectoError::SshKey(format!("Not a key file: {}", path.display())))
+}
+
+/// Directory and file name of the key at `path`
+fn split_key_path(path: &Path) -> Result<(PathBuf, String)> {
+    let dir = path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    Ok((dir, key_name(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_config::HostEntry;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    /// Hosts that are home directories on this machine, each accepting the
+    /// keys in its authorized_keys like sshd would
+    #[derive(Default)]
+    struct LocalHosts {
+        homes: HashMap<String, PathBuf>,
+    }
+
+    impl RemoteShell for LocalHosts {
+        fn run(&self, host: &str, identity_file: &Path, command: &str) -> Result<String> {
+            let home = self
+                .homes
+                .get(host)
+                .ok_or_else(|| ConnectoError::Network("No route to host".to_string()))?;
+            let public_key = public_key_of(identity_file)?;
+            let data = public_key.split_whitespace().nth(1).unwrap_or_default();
+            let authorized =
+                fs::read_to_string(home.join(".ssh/authorized_keys")).unwrap_or_default();
+            if !authorized.lines().any(|line| line.contains(data)) {
+                return Err(ConnectoError::PermissionDenied(
+                    "Permission denied (publickey)".to_string(),
+                ));
+            }
+            let output = Command::new("sh")
+                .args(["-c", command])
+                .env("HOME", home)
+                .output()?;
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+    }
+
+    fn authorized(home: &Path) -> String {
+        fs::read_to_string(home.join(".ssh/authorized_keys")).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_rotated_key_name() {
+        assert_eq!(
+            rotated_key_name("connecto_desk", 1700),
+            "connecto_desk-1700"
+        );
+        assert_eq!(
+            rotated_key_name("connecto_desk-1700", 1800),
+            "connecto_desk-1800"
+        );
+        assert_eq!(rotated_key_name("id-rsa", 1800), "id-rsa-1800");
+    }
+
+    #[test]
+    fn test_commands_refuse_unsafe_keys() {
+        assert!(install_command("ssh-ed25519 AAAA'; rm -rf ~ '").is_err());
+        assert!(revoke_command("not-a-key").is_err());
+
+        // A comment that needs quoting is left out
+        let command = install_command("ssh-ed25519 AAAAkey it's mine").unwrap();
+        assert!(command.contains("'ssh-ed25519 AAAAkey'"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_and_revoke_commands() {
+        let home = TempDir::new().unwrap();
+        let run = |command: String| {
+            let output = Command::new("sh")
+                .args(["-c", &command])
+                .env("HOME", home.path())
+                .output()
+                .unwrap();
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        };
+
+        // Nothing to remove without an authorized_keys file
+        let revoke = revoke_command("ssh-ed25519 AAAAkeep+/= me@laptop").unwrap();
+        assert_eq!(parse_revoked(&run(revoke.clone())), Some(0));
+        assert!(!home.path().join(".ssh").exists());
+
+        // A file without a trailing newline gets one before the new key
+        fs::create_dir(home.path().join(".ssh")).unwrap();
+        fs::write(
+            home.path().join(".ssh/authorized_keys"),
+            "ssh-ed25519 AAAAother alice@desk",
+        )
+        .unwrap();
+        let install = install_command("ssh-ed25519 AAAAkeep+/= me@laptop").unwrap();
+        assert!(run(install.clone()).contains(INSTALLED_MARKER));
+        assert!(run(install).contains(INSTALLED_MARKER));
+        assert_eq!(
+            authorized(home.path()),
+            "ssh-ed25519 AAAAother alice@desk\nssh-ed25519 AAAAkeep+/= me@laptop\n"
+        );
+
+        assert_eq!(parse_revoked(&run(revoke.clone())), Some(1));
+        assert_eq!(
+            authorized(home.path()),
+            "ssh-ed25519 AAAAother alice@desk\n"
+        );
+        assert_eq!(fs::read_dir(home.path().join(".ssh")).unwrap().count(), 1);
+        assert_eq!(parse_revoked(&run(revoke)), Some(0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rotate_hosts() {
+        let temp = TempDir::new().unwrap();
+        let ssh_dir = temp.path().join("local/.ssh");
+        let keys = KeyManager::with_dir(ssh_dir.clone());
+        let shared = SshKeyPair::generate(KeyAlgorithm::Ed25519, "me@laptop").unwrap();
+        let (shared_path, _) = keys.save_key_pair(&shared, "connecto_shared").unwrap();
+
+        // desk and lab share a key; gone is unreachable and keeps it
+        let mut shell = LocalHosts::default();
+        let config = SshConfig::with_path(ssh_dir.join("config"));
+        for (host, reachable) in [("desk", true), ("lab", true), ("gone", false)] {
+            let home = temp.path().join(host);
+            KeyManager::with_dir(home.join(".ssh"))
+                .add_authorized_key(&shared.public_key)
+                .unwrap();
+            if reachable {
+                shell.homes.insert(host.to_string(), home);
+            }
+            let entry = HostEntry {
+                host: host.to_string(),
+                hostname: format!("{}.lan", host),
+                user: "me".to_string(),
+                identity_file: shared_path.display().to_string(),
+                ..Default::default()
+            };
+            config.add_entry(&entry).unwrap();
+        }
+
+        let mut shell_homes = shell.homes.clone();
+        let rotator = KeyRotator::new(shell, config.clone());
+        let rotations = rotator
+            .rotate(&["desk".to_string(), "gone".to_string()])
+            .unwrap();
+        assert_eq!(rotations.len(), 1);
+        let rotation = &rotations[0];
+        let new_key = rotation.new_key.clone().unwrap();
+        assert_eq!(rotation.hosts[0].result, Ok(true));
+        assert!(rotation.hosts[1]
+            .result
+            .as_ref()
+            .unwrap_err()
+            .contains("could not install"));
+
+        // lab and gone still use the old key, so it is kept
+        assert!(!rotation.old_key_deleted);
+        assert!(shared_path.exists());
+
+        let entries = config.entries().unwrap();
+        let key_of = |host: &str| {
+            entries
+                .iter()
+                .find(|e| e.host == host)
+                .unwrap()
+                .identity_file
+                .clone()
+        };
+        assert_eq!(key_of("desk"), new_key.display().to_string());
+        assert_eq!(key_of("lab"), shared_path.display().to_string());
+        assert_eq!(key_of("gone"), shared_path.display().to_string());
+
+        let new_public = public_key_of(&new_key).unwrap();
+        let desk = authorized(&temp.path().join("desk"));
+        assert!(desk.contains(new_public.split_whitespace().nth(1).unwrap()));
+        assert!(!desk.contains(shared.public_key.split_whitespace().nth(1).unwrap()));
+        assert!(authorized(&temp.path().join("lab")).contains(&shared.public_key));
+
+        // Rotating again within the same second still gets a fresh key
+        let rotations = rotator.rotate(&["lab".to_string()]).unwrap();
+        assert_eq!(rotations[0].hosts[0].result, Ok(true));
+        assert_ne!(rotations[0].new_key.as_ref(), Some(&new_key));
+        assert!(!rotations[0].old_key_deleted);
+
+        // Once the last host moves over, the old key goes
+        shell_homes.insert("gone".to_string(), temp.path().join("gone"));
+        let rotator = KeyRotator::new(LocalHosts { homes: shell_homes }, config.clone());
+        let rotations = rotator.rotate(&["gone".to_string()]).unwrap();
+        assert_eq!(rotations[0].hosts[0].result, Ok(true));
+        assert!(rotations[0].old_key_deleted);
+        assert!(!shared_path.exists());
+    }
+}
@@ -0,0 +1,173 @@
+//! Data encrypted with a passphrase
+//!
+//! Used for exports that carry private keys. The passphrase is stretched
+//! with Argon2id into a key for AES-256-GCM; the salt, nonce and Argon2
+//! parameters are stored next to the ciphertext, so a [`Sealed`] value is
+//! all that is needed, with the passphrase, to get the data back.
+
+use crate::error::{ConnectoError, Result};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64ct::{Base64, Encoding};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// The cipher [`seal`] uses
+pub const CIPHER: &str = "aes-256-gcm";
+
+/// The key derivation [`seal`] uses
+pub const KDF: &str = "argon2id";
+
+/// Shortest passphrase [`seal`] accepts
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Argon2 memory cost in KiB, OWASP's recommended 19 MiB
+const MEMORY_KIB: u32 = 19 * 1024;
+/// Argon2 passes over the memory
+const ITERATIONS: u32 = 2;
+/// Argon2 lanes
+const PARALLELISM: u32 = 1;
+/// Most memory [`Sealed::open`] lets a sealed value ask for, 1 GiB
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+
+/// Ciphertext with what is needed to decrypt it, given the passphrase
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sealed {
+    /// Always [`CIPHER`] for now
+    pub cipher: String,
+    /// Always [`KDF`] for now
+    pub kdf: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    /// Base64
+    pub salt: String,
+    /// Base64
+    pub nonce: String,
+    /// Base64, with the authentication tag at the end
+    pub ciphertext: String,
+}
+
+/// Encrypt `plaintext` with `passphrase`
+pub fn seal(plaintext: &[u8], passphrase: &str) -> Result<Sealed> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(ConnectoError::Encryption(format!(
+            "The passphrase must be at least {} characters long",
+            MIN_PASSPHRASE_LEN
+        )));
+    }
+    let mut rng = rand::thread_rng();
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+
+    let key = derive_key(passphrase, &salt, MEMORY_KIB, ITERATIONS, PARALLELISM)?;
+    let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| ConnectoError::Encryption("Encryption failed".to_string()))?;
+
+    Ok(Sealed {
+        cipher: CIPHER.to_string(),
+        kdf: KDF.to_string(),
+        memory_kib: MEMORY_KIB,
+        iterations: ITERATIONS,
+        parallelism: PARALLELISM,
+        salt: Base64::encode_string(&salt),
+        nonce: Base64::encode_string(&nonce),
+        ciphertext: Base64::encode_string(&ciphertext),
+    })
+}
+
+impl Sealed {
+    /// Decrypt with `passphrase`
+    ///
+    /// A wrong passphrase and tampered data fail alike.
+    pub fn open(&self, passphrase: &str) -> Result<Vec<u8>> {
+        if self.cipher != CIPHER || self.kdf != KDF || self.memory_kib > MAX_MEMORY_KIB {
+            return Err(ConnectoError::Encryption(format!(
+                "Unsupported encryption: {} with {}",
+                self.cipher, self.kdf
+            )));
+        }
+        let decode = |what: &str, value: &str| {
+            Base64::decode_vec(value)
+                .map_err(|_| ConnectoError::Encryption(format!("The {} is not base64", what)))
+        };
+        let salt = decode("salt", &self.salt)?;
+        let nonce = decode("nonce", &self.nonce)?;
+        let ciphertext = decode("ciphertext", &self.ciphertext)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(ConnectoError::Encryption(
+                "The nonce has the wrong length".to_string(),
+            ));
+        }
+
+        let key = derive_key(
+            passphrase,
+            &salt,
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+        )?;
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| {
+                ConnectoError::Encryption("Wrong passphrase, or the data was modified".to_string())
+            })
+    }
+}
+
+/// Stretch `passphrase` into a 256-bit key
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+) -> Result<[u8; 32]> {
+    let params = Params::new(memory_kib, iterations, parallelism, Some(32))
+        .map_err(|e| ConnectoError::Encryption(format!("Bad Argon2 parameters: {}", e)))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| ConnectoError::Encryption(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let sealed = seal(b"private keys", "correct horse").unwrap();
+        assert_eq!(sealed.cipher, CIPHER);
+        assert_eq!(sealed.open("correct horse").unwrap(), b"private keys");
+
+        // Fresh salt and nonce every time
+        let again = seal(b"private keys", "correct horse").unwrap();
+        assert_ne!(sealed.ciphertext, again.ciphertext);
+
+        let err = sealed.open("wrong horse").unwrap_err();
+        assert!(matches!(err, ConnectoError::Encryption(_)));
+    }
+
+    #[test]
+    fn test_tampering_and_short_passphrases() {
+        let mut sealed = seal(b"private keys", "correct horse").unwrap();
+        let mut ciphertext = Base64::decode_vec(&sealed.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        sealed.ciphertext = Base64::encode_string(&ciphertext);
+        assert!(sealed.open("correct horse").is_err());
+
+        sealed.kdf = "pbkdf2".to_string();
+        assert!(sealed.open("correct horse").is_err());
+
+        assert!(seal(b"private keys", "short").is_err());
+    }
+}
@@ -0,0 +1,349 @@
+//! Session log module
+//!
+//! Folds the [`ServerEvent`]s of a running listener into one
+//! [`PairingAttempt`] per pairing request: who asked, from where, with which
+//! key, and how it ended. Requests still waiting for a code or an approval
+//! show as pending. The log lives as long as the listener; the pairing
+//! store and decision log keep the lasting record.
+
+use crate::clock;
+use crate::protocol::ServerEvent;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// How many attempts a log holds unless told otherwise
+pub const DEFAULT_ATTEMPT_CAPACITY: usize = 1000;
+
+/// How a pairing attempt ended, if it has
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AttemptOutcome {
+    /// Still waiting for the client, or for the user to approve it
+    Pending,
+    /// The key was installed
+    Accepted,
+    /// Refused by the access list or the user, or not approved in time
+    Rejected { reason: String },
+    /// The handshake broke off
+    Failed { reason: String },
+}
+
+impl AttemptOutcome {
+    /// Why the attempt was rejected or failed
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Self::Rejected { reason } | Self::Failed { reason } => Some(reason),
+            Self::Pending | Self::Accepted => None,
+        }
+    }
+}
+
+impl fmt::Display for AttemptOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pending => "pending",
+            Self::Accepted => "accepted",
+            Self::Rejected { .. } => "rejected",
+            Self::Failed { .. } => "failed",
+        })
+    }
+}
+
+/// One pairing request a listener received
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingAttempt {
+    /// When the request arrived, in Unix seconds
+    pub started_at: u64,
+    pub device_name: String,
+    pub address: SocketAddr,
+    /// SHA-256 fingerprint of the key, once the client sent it
+    pub fingerprint: Option<String>,
+    pub outcome: AttemptOutcome,
+}
+
+/// How many attempts of a log ended each way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub failed: usize,
+    pub pending: usize,
+}
+
+/// The pairing attempts of a listener, oldest first
+#[derive(Debug, Clone)]
+pub struct SessionLog {
+    attempts: VecDeque<PairingAttempt>,
+    capacity: usize,
+}
+
+impl Default for SessionLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionLog {
+    /// An empty log holding up to [`DEFAULT_ATTEMPT_CAPACITY`] attempts
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_ATTEMPT_CAPACITY)
+    }
+
+    /// An empty log holding up to `capacity` attempts, dropping the oldest
+    /// to make room
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            attempts: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Fold `event` into the log, returning the attempt it started or changed
+    ///
+    /// Events that name only the device go to its newest pending attempt.
+    pub fn record(&mut self, event: &ServerEvent) -> Option<&PairingAttempt> {
+        match event {
+            ServerEvent::PairingRequest {
+                device_name,
+                address,
+            } => Some(self.start(device_name, *address, AttemptOutcome::Pending)),
+            ServerEvent::AccessDenied {
+                device_name,
+                address,
+                reason,
+            } => Some(self.start(
+                device_name,
+                *address,
+                AttemptOutcome::Rejected {
+                    reason: reason.clone(),
+                },
+            )),
+            ServerEvent::KeyReceived {
+                device_name,
+                fingerprint,
+                ..
+            } => {
+                let attempt = self.pending_from(device_name)?;
+                attempt.fingerprint = Some(fingerprint.clone());
+                Some(attempt)
+            }
+            ServerEvent::PairingComplete { device_name } => {
+                Self::end(self.pending_from(device_name)?, AttemptOutcome::Accepted)
+            }
+            ServerEvent::ApprovalTimedOut {
+                device_name,
+                accepted: false,
+            } => Self::end(
+                self.pending_from(device_name)?,
+                AttemptOutcome::Rejected {
+                    reason: "Not approved in time".to_string(),
+                },
+            ),
+            ServerEvent::PairingRejected { device_name } => Self::end(
+                self.pending_from(device_name)?,
+                AttemptOutcome::Rejected {
+                    reason: "Rejected on this device".to_string(),
+                },
+            ),
+            ServerEvent::PairingFailed { address, reason } => {
+                let attempt = self
+                    .attempts
+                    .iter_mut()
+                    .rev()
+                    .find(|a| a.address == *address && a.outcome == AttemptOutcome::Pending)?;
+                Self::end(
+                    attempt,
+                    AttemptOutcome::Failed {
+                        reason: reason.clone(),
+                    },
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// Every attempt, oldest first
+    pub fn attempts(&self) -> impl DoubleEndedIterator<Item = &PairingAttempt> {
+        self.attempts.iter()
+    }
+
+    /// Attempts still waiting for the client or the user
+    pub fn pending(&self) -> impl Iterator<Item = &PairingAttempt> {
+        self.attempts
+            .iter()
+            .filter(|a| a.outcome == AttemptOutcome::Pending)
+    }
+
+    /// How many attempts ended each way
+    pub fn summary(&self) -> SessionSummary {
+        let mut summary = SessionSummary::default();
+        for attempt in &self.attempts {
+            match attempt.outcome {
+                AttemptOutcome::Pending => summary.pending += 1,
+                AttemptOutcome::Accepted => summary.accepted += 1,
+                AttemptOutcome::Rejected { .. } => summary.rejected += 1,
+                AttemptOutcome::Failed { .. } => summary.failed += 1,
+            }
+        }
+        summary
+    }
+
+    /// Number of attempts in the log
+    pub fn len(&self) -> usize {
+        self.attempts.len()
+    }
+
+    /// Whether the listener had no pairing requests yet
+    pub fn is_empty(&self) -> bool {
+        self.attempts.is_empty()
+    }
+
+    fn start(
+        &mut self,
+        device_name: &str,
+        address: SocketAddr,
+        outcome: AttemptOutcome,
+    ) -> &PairingAttempt {
+        if self.attempts.len() == self.capacity {
+            self.attempts.pop_front();
+        }
+        self.attempts.push_back(PairingAttempt {
+            started_at: clock::unix_now().max(0) as u64,
+            device_name: device_name.to_string(),
+            address,
+            fingerprint: None,
+            outcome,
+        });
+        self.attempts.back().expect("just pushed")
+    }
+
+    fn pending_from(&mut self, device_name: &str) -> Option<&mut PairingAttempt> {
+        self.attempts
+            .iter_mut()
+            .rev()
+            .find(|a| a.device_name == device_name && a.outcome == AttemptOutcome::Pending)
+    }
+
+    fn end(attempt: &mut PairingAttempt, outcome: AttemptOutcome) -> Option<&PairingAttempt> {
+        attempt.outcome = outcome;
+        Some(attempt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: &str, address: &str) -> ServerEvent {
+        ServerEvent::PairingRequest {
+            device_name: name.to_string(),
+            address: address.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_attempt_outcomes() {
+        let mut log = SessionLog::new();
+        log.record(&ServerEvent::ClientConnected {
+            address: "10.0.0.9:40000".parse().unwrap(),
+        });
+        assert!(log.is_empty());
+
+        log.record(&request("laptop", "10.0.0.2:40001"));
+        log.record(&request("phone", "10.0.0.3:40002"));
+        log.record(&request("tablet", "10.0.0.4:40003"));
+        let keyed = log
+            .record(&ServerEvent::KeyReceived {
+                device_name: "laptop".to_string(),
+                comment: "me@laptop".to_string(),
+                fingerprint: "SHA256:laptop".to_string(),
+            })
+            .unwrap();
+        assert_eq!(keyed.fingerprint.as_deref(), Some("SHA256:laptop"));
+        log.record(&ServerEvent::PairingComplete {
+            device_name: "laptop".to_string(),
+        });
+        log.record(&ServerEvent::ApprovalTimedOut {
+            device_name: "phone".to_string(),
+            accepted: false,
+        });
+        // The rejection that follows a timeout does not overwrite its reason
+        assert!(log
+            .record(&ServerEvent::PairingRejected {
+                device_name: "phone".to_string(),
+            })
+            .is_none());
+        log.record(&ServerEvent::AccessDenied {
+            device_name: "mallory".to_string(),
+            address: "10.0.0.66:40004".parse().unwrap(),
+            reason: "not on the allow list".to_string(),
+        });
+
+        let outcomes: Vec<_> = log
+            .attempts()
+            .map(|a| (a.device_name.as_str(), a.outcome.to_string()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("laptop", "accepted".to_string()),
+                ("phone", "rejected".to_string()),
+                ("tablet", "pending".to_string()),
+                ("mallory", "rejected".to_string()),
+            ]
+        );
+        assert_eq!(
+            log.attempts().nth(1).unwrap().outcome.reason(),
+            Some("Not approved in time")
+        );
+        assert_eq!(log.pending().count(), 1);
+        assert_eq!(
+            log.summary(),
+            SessionSummary {
+                accepted: 1,
+                rejected: 2,
+                failed: 0,
+                pending: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_failures_match_the_connection() {
+        let mut log = SessionLog::new();
+        log.record(&request("laptop", "10.0.0.2:40001"));
+        log.record(&request("laptop", "10.0.0.2:40002"));
+
+        // A scanner probe that never sent Hello is not an attempt
+        assert!(log
+            .record(&ServerEvent::PairingFailed {
+                address: "10.0.0.9:40000".parse().unwrap(),
+                reason: "Client sent no Hello".to_string(),
+            })
+            .is_none());
+
+        let failed = log
+            .record(&ServerEvent::PairingFailed {
+                address: "10.0.0.2:40001".parse().unwrap(),
+                reason: "Wrong verification code".to_string(),
+            })
+            .unwrap();
+        assert_eq!(failed.outcome.reason(), Some("Wrong verification code"));
+
+        let attempts: Vec<_> = log.attempts().collect();
+        assert_eq!(attempts[0].outcome.to_string(), "failed");
+        assert_eq!(attempts[1].outcome, AttemptOutcome::Pending);
+    }
+
+    #[test]
+    fn test_capacity_drops_oldest() {
+        let mut log = SessionLog::with_capacity(2);
+        log.record(&request("one", "10.0.0.1:1"));
+        log.record(&request("two", "10.0.0.2:2"));
+        log.record(&request("three", "10.0.0.3:3"));
+        let names: Vec<_> = log.attempts().map(|a| a.device_name.as_str()).collect();
+        assert_eq!(names, ["two", "three"]);
+    }
+}
@@ -0,0 +1,73 @@
+//! Shutdown module
+//!
+//! Lets another task stop a server that otherwise runs until its future is
+//! dropped, such as the GUI stopping its listener. A stopped server closes
+//! its socket before returning, so the port can be bound again at once.
+
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Stops a [`HandshakeServer`](crate::protocol::HandshakeServer) or a
+/// [`SyncHandler`](crate::sync::SyncHandler) from another task
+///
+/// Clones share the same signal, and once given it stays given.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self { tx: Arc::new(tx) }
+    }
+
+    /// Tell the server to stop
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+
+    /// Whether [`ShutdownHandle::shutdown`] was called
+    pub fn is_shutdown(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Wait until [`ShutdownHandle::shutdown`] is called
+    pub async fn requested(&self) {
+        let mut rx = self.tx.subscribe();
+        // The sender lives as long as `self`, so this only returns on shutdown
+        let _ = rx.wait_for(|stop| *stop).await;
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_shutdown_handle() {
+        let handle = ShutdownHandle::new();
+        let clone = handle.clone();
+        assert!(!clone.is_shutdown());
+
+        let waiter = tokio::spawn(async move { clone.requested().await });
+        handle.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiter sees the shutdown")
+            .unwrap();
+        assert!(handle.is_shutdown());
+
+        // Waiting after the fact returns at once
+        tokio::time::timeout(Duration::from_secs(1), handle.requested())
+            .await
+            .unwrap();
+    }
+}
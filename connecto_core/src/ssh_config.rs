@@ -0,0 +1,1324 @@
+//! SSH config module
+//!
+//! Reads and updates the host entries Connecto writes to `~/.ssh/config`.
+//! Each entry records the paired device's identity fingerprint so that the
+//! entry can follow the device when its address changes.
+//!
+//! Entries can also carry tags. Tag templates map a tag to SSH options, e.g.
+//! `prod` to `StrictHostKeyChecking yes`; a tagged entry's options are always
+//! the ones its templates give, so they are rewritten whenever tags or
+//! templates change.
+//!
+//! An entry with a keep-warm schedule also shares connections through a
+//! control socket; see [`crate::keepwarm`].
+//!
+//! Values are checked before they are written, so a name with a space or a
+//! newline cannot split a line or smuggle in a directive. `HostName` and
+//! `IdentityFile` are written literally: `%` is doubled, since ssh expands
+//! tokens there, and paths with spaces are quoted.
+
+use crate::backups;
+use crate::error::{ConnectoError, Result};
+use crate::keepwarm::{Schedule, CONTROL_PATH};
+use crate::keys::KeyManager;
+use crate::net;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Comment line that precedes every host entry written by Connecto
+pub const CONNECTO_MARKER: &str = "# Added by connecto";
+
+/// Comment prefix recording the paired device's identity inside an entry
+pub const IDENTITY_MARKER: &str = "# connecto-identity";
+
+/// Comment prefix listing an entry's tags, separated by spaces
+pub const TAGS_MARKER: &str = "# connecto-tags";
+
+/// Comment prefix recording an entry's keep-warm schedule
+pub const KEEP_WARM_MARKER: &str = "# connecto-keep-warm";
+
+/// Check that `alias` can name a host on a `Host` line
+///
+/// Besides being one word, it must not hold the wildcards `*` and `?` or the
+/// negation `!`, which would make it a pattern for other hosts too.
+pub fn validate_host_alias(alias: &str) -> Result<()> {
+    check_word("Host alias", alias)?;
+    if alias.contains(['*', '?', '!']) {
+        return Err(invalid(
+            "Host alias",
+            alias,
+            "cannot contain the pattern characters *, ? or !",
+        ));
+    }
+    Ok(())
+}
+
+/// Check that `hostname` can be written after `HostName`
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    check_word("HostName", hostname)
+}
+
+/// Check that `user` can be written after `User`
+pub fn validate_user(user: &str) -> Result<()> {
+    check_word("User", user)
+}
+
+/// Check that `path` can be written after `IdentityFile`
+///
+/// Spaces are fine, as the path is quoted; quotes are not, as ssh has no way
+/// to escape them inside a quoted value.
+pub fn validate_path(path: &str) -> Result<()> {
+    check_value("IdentityFile", path)?;
+    if path.contains('"') {
+        return Err(invalid(
+            "IdentityFile",
+            path,
+            "cannot contain double quotes",
+        ));
+    }
+    Ok(())
+}
+
+/// `value` as written after `HostName` or `IdentityFile`: `%` doubled so
+/// ssh does not take it for a token, and quoted if it contains spaces
+pub fn format_arg(value: &str) -> String {
+    let escaped = value.replace('%', "%%");
+    if escaped.contains(char::is_whitespace) {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+/// The value a `HostName` or `IdentityFile` argument written by
+/// [`format_arg`] stands for
+///
+/// Arguments written before values were escaped come back unchanged.
+pub fn parse_arg(arg: &str) -> String {
+    let arg = arg.trim();
+    let unquoted = arg
+        .strip_prefix('"')
+        .and_then(|arg| arg.strip_suffix('"'))
+        .unwrap_or(arg);
+    unquoted.replace("%%", "%")
+}
+
+/// Check that `value` is not empty and has no control characters, which
+/// would end the line early or garble it
+fn check_value(what: &str, value: &str) -> Result<()> {
+    if value.trim().is_empty() {
+        return Err(ConnectoError::SshConfig(format!("{} is empty", what)));
+    }
+    if value.contains(char::is_control) {
+        return Err(invalid(
+            what,
+            value,
+            "cannot contain newlines or control characters",
+        ));
+    }
+    Ok(())
+}
+
+/// Check that `value` can stand as one word of a config line
+fn check_word(what: &str, value: &str) -> Result<()> {
+    check_value(what, value)?;
+    if value.contains(char::is_whitespace) {
+        return Err(invalid(what, value, "cannot contain spaces"));
+    }
+    if value.contains(['"', '\'']) {
+        return Err(invalid(what, value, "cannot contain quotes"));
+    }
+    if value.starts_with('#') {
+        return Err(invalid(
+            what,
+            value,
+            "cannot start with #, which begins a comment",
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the option `name` with `value` can be written as one line
+fn validate_option(name: &str, value: &str) -> Result<()> {
+    check_word("Option name", name)?;
+    check_value(&format!("Option {}", name), value)
+}
+
+/// Check every option of `templates`
+fn validate_templates(templates: &TagTemplates) -> Result<()> {
+    for (name, value) in templates.values().flatten() {
+        validate_option(name, value)?;
+    }
+    Ok(())
+}
+
+fn invalid(what: &str, value: &str, reason: &str) -> ConnectoError {
+    ConnectoError::SshConfig(format!("{} {:?} {}", what, value, reason))
+}
+
+/// SSH options to add to hosts by tag, e.g. `prod` → `ForwardAgent no`
+pub type TagTemplates = BTreeMap<String, BTreeMap<String, String>>;
+
+/// A host entry written by Connecto
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostEntry {
+    pub host: String,
+    pub hostname: String,
+    pub user: String,
+    /// SSH port, written as `Port` unless a tag's template sets one
+    pub port: Option<u16>,
+    pub identity_file: String,
+    /// Identity fingerprint of the paired device, if known
+    pub identity: Option<String>,
+    /// Tags selecting the templates this entry follows
+    pub tags: Vec<String>,
+    /// When to keep a connection to the host open
+    pub keep_warm: Option<Schedule>,
+    /// Further SSH options, as name and value
+    pub options: Vec<(String, String)>,
+}
+
+impl HostEntry {
+    /// Check that every value of the entry can be written to the config
+    /// as it is meant
+    pub fn validate(&self) -> Result<()> {
+        validate_host_alias(&self.host)?;
+        validate_hostname(&self.hostname)?;
+        validate_user(&self.user)?;
+        validate_path(&self.identity_file)?;
+        for tag in &self.tags {
+            check_word("Tag", tag)?;
+        }
+        for (name, value) in &self.options {
+            validate_option(name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Tag the entry, taking its options from the templates of its tags
+    pub fn with_tags(mut self, tags: &[String], templates: &TagTemplates) -> Self {
+        self.tags = tags.to_vec();
+        self.apply_templates(templates);
+        self
+    }
+
+    /// Replace the entry's options with those its tags' templates give
+    ///
+    /// Untagged entries are left alone, so options added by hand survive.
+    /// Returns whether anything changed.
+    pub fn apply_templates(&mut self, templates: &TagTemplates) -> bool {
+        if self.tags.is_empty() {
+            return false;
+        }
+        let options = template_options(&self.tags, templates);
+        if options == self.options {
+            return false;
+        }
+        self.options = options;
+        true
+    }
+
+    /// Render the entry as an SSH config block, including the leading marker
+    ///
+    /// Everything but `IdentityFile` comes first, as the line that ends an
+    /// entry.
+    pub fn to_block(&self) -> String {
+        let mut block = format!(
+            "\n{}\nHost {}\n    HostName {}\n    User {}\n",
+            CONNECTO_MARKER,
+            self.host,
+            format_arg(&self.hostname),
+            self.user
+        );
+        let templated_port = self
+            .options
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Port"));
+        if let Some(port) = self.port.filter(|_| !templated_port) {
+            block.push_str(&format!("    Port {}\n", port));
+        }
+        if let Some(identity) = &self.identity {
+            block.push_str(&format!("    {} {}\n", IDENTITY_MARKER, identity));
+        }
+        if !self.tags.is_empty() {
+            block.push_str(&format!("    {} {}\n", TAGS_MARKER, self.tags.join(" ")));
+        }
+        if let Some(schedule) = &self.keep_warm {
+            block.push_str(&format!("    {} {}\n", KEEP_WARM_MARKER, schedule));
+            block.push_str(&format!("    ControlPath {}\n", CONTROL_PATH));
+        }
+        for (name, value) in &self.options {
+            block.push_str(&format!("    {} {}\n", name, value));
+        }
+        block.push_str(&format!(
+            "    IdentityFile {}\n",
+            format_arg(&self.identity_file)
+        ));
+        block
+    }
+}
+
+/// SSH options for a host with `tags`, in tag order
+///
+/// SSH uses the first value it sees for an option, so when two tags set the
+/// same option the earlier tag wins.
+pub fn template_options(tags: &[String], templates: &TagTemplates) -> Vec<(String, String)> {
+    let mut options: Vec<(String, String)> = Vec::new();
+    for template in tags.iter().filter_map(|tag| templates.get(tag)) {
+        for (name, value) in template {
+            if !options.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                options.push((name.clone(), value.clone()));
+            }
+        }
+    }
+    options
+}
+
+/// Host alias for a device name, e.g. `My Mac (2)` gives `my_mac__2_`
+pub fn host_alias(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Whether any `Host` line in `content` names `host`, Connecto's or not
+pub fn has_host_in(content: &str, host: &str) -> bool {
+    content.lines().any(|line| {
+        let mut words = line.split_whitespace();
+        words
+            .next()
+            .is_some_and(|keyword| keyword.eq_ignore_ascii_case("Host"))
+            && words.any(|pattern| pattern == host)
+    })
+}
+
+/// Parse all Connecto host entries from SSH config content
+pub fn parse_entries(content: &str) -> Vec<HostEntry> {
+    let mut entries = Vec::new();
+    let mut current: Option<HostEntry> = None;
+    let mut in_connecto_block = false;
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+
+        if trimmed == CONNECTO_MARKER {
+            entries.extend(current.take());
+            in_connecto_block = true;
+            continue;
+        }
+
+        if !in_connecto_block {
+            continue;
+        }
+
+        if let Some(host) = trimmed.strip_prefix("Host ") {
+            entries.extend(current.take());
+            current = Some(HostEntry {
+                host: host.trim().to_string(),
+                ..Default::default()
+            });
+        } else if let Some(entry) = current.as_mut() {
+            if let Some(hostname) = trimmed.strip_prefix("HostName ") {
+                entry.hostname = parse_arg(hostname);
+            } else if let Some(user) = trimmed.strip_prefix("User ") {
+                entry.user = user.trim().to_string();
+            } else if let Some(port) = trimmed
+                .strip_prefix("Port ")
+                .filter(|_| entry.tags.is_empty() && entry.options.is_empty())
+            {
+                // After the tags, `Port` comes from a template
+                entry.port = port.trim().parse().ok();
+            } else if let Some(identity) = trimmed.strip_prefix(IDENTITY_MARKER) {
+                let identity = identity.trim();
+                if !identity.is_empty() {
+                    entry.identity = Some(identity.to_string());
+                }
+            } else if let Some(tags) = trimmed.strip_prefix(TAGS_MARKER) {
+                entry.tags = tags.split_whitespace().map(str::to_string).collect();
+            } else if let Some(schedule) = trimmed.strip_prefix(KEEP_WARM_MARKER) {
+                entry.keep_warm = schedule.parse().ok();
+            } else if entry.keep_warm.is_some() && trimmed.starts_with("ControlPath ") {
+                // Goes with the schedule, rather than being an option
+            } else if let Some(identity_file) = trimmed.strip_prefix("IdentityFile ") {
+                entry.identity_file = parse_arg(identity_file);
+                entries.extend(current.take());
+                in_connecto_block = false;
+            } else if trimmed.is_empty() {
+                entries.extend(current.take());
+                in_connecto_block = false;
+            } else if !trimmed.starts_with('#') {
+                if let Some((name, value)) = trimmed.split_once(char::is_whitespace) {
+                    entry
+                        .options
+                        .push((name.to_string(), value.trim().to_string()));
+                }
+            }
+        }
+    }
+
+    entries.extend(current);
+    entries
+}
+
+/// Point every entry bound to `identity` at `address`
+///
+/// Entries reaching the device by its mDNS hostname already follow it and
+/// are left alone. Returns the updated content and the host aliases whose
+/// `HostName` changed.
+pub fn update_address_in(content: &str, identity: &str, address: &str) -> (String, Vec<String>) {
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let mut updated = Vec::new();
+
+    // Walk each Connecto block, remembering where its HostName line is
+    let mut block: Option<(String, Option<usize>)> = None;
+    let mut in_connecto_block = false;
+
+    for i in 0..lines.len() {
+        let trimmed = lines[i].trim().to_string();
+
+        if trimmed == CONNECTO_MARKER {
+            in_connecto_block = true;
+            block = None;
+            continue;
+        }
+        if !in_connecto_block {
+            continue;
+        }
+
+        if let Some(host) = trimmed.strip_prefix("Host ") {
+            block = Some((host.trim().to_string(), None));
+        } else if trimmed.starts_with("HostName ") {
+            if let Some((_, hostname_line)) = block.as_mut() {
+                *hostname_line = Some(i);
+            }
+        } else if let Some(found) = trimmed.strip_prefix(IDENTITY_MARKER) {
+            if found.trim() != identity {
+                continue;
+            }
+            if let Some((host, Some(line))) = block.as_ref() {
+                let current = parse_arg(lines[*line].trim().trim_start_matches("HostName "));
+                if current != address && !net::is_mdns_hostname(&current) {
+                    let indent: String = lines[*line]
+                        .chars()
+                        .take_while(|c| c.is_whitespace())
+                        .collect();
+                    lines[*line] = format!("{}HostName {}", indent, format_arg(address));
+                    updated.push(host.clone());
+                }
+            }
+        } else if trimmed.starts_with("IdentityFile ") || trimmed.is_empty() {
+            in_connecto_block = false;
+            block = None;
+        }
+    }
+
+    let mut new_content = lines.join("\n");
+    if content.ends_with('\n') {
+        new_content.push('\n');
+    }
+    (new_content, updated)
+}
+
+/// Rewrite the Connecto entries `f` changes
+///
+/// `f` returns whether it changed the entry. Returns the updated content and
+/// the host aliases that were rewritten.
+fn rewrite_entries_in(
+    content: &str,
+    mut f: impl FnMut(&mut HostEntry) -> bool,
+) -> (String, Vec<String>) {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut new_lines: Vec<String> = Vec::new();
+    let mut updated = Vec::new();
+
+    let mut start: Option<usize> = None;
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if trimmed == CONNECTO_MARKER {
+            // An unfinished block before this one is kept as it is
+            if let Some(start) = start {
+                new_lines.extend(lines[start..i].iter().map(|l| l.to_string()));
+            }
+            start = Some(i);
+            continue;
+        }
+        let Some(block_start) = start else {
+            new_lines.push(line.to_string());
+            continue;
+        };
+        if trimmed.is_empty() {
+            new_lines.extend(lines[block_start..=i].iter().map(|l| l.to_string()));
+            start = None;
+        } else if trimmed.starts_with("IdentityFile ") {
+            let block = &lines[block_start..=i];
+            let mut entries = parse_entries(&block.join("\n"));
+            let rewritten = match entries.as_mut_slice() {
+                [entry] => f(entry).then_some(entry),
+                _ => None,
+            };
+            match rewritten {
+                Some(entry) => {
+                    let rendered = entry.to_block();
+                    new_lines.extend(rendered.trim().lines().map(str::to_string));
+                    updated.push(entry.host.clone());
+                }
+                None => new_lines.extend(block.iter().map(|l| l.to_string())),
+            }
+            start = None;
+        }
+    }
+    if let Some(start) = start {
+        new_lines.extend(lines[start..].iter().map(|l| l.to_string()));
+    }
+
+    let mut new_content = new_lines.join("\n");
+    if content.ends_with('\n') {
+        new_content.push('\n');
+    }
+    (new_content, updated)
+}
+
+/// Identities pinned for hosts, keyed by `HostName`
+///
+/// A host whose entries name different identities is left out, as there is
+/// no telling which one is current.
+pub fn identity_pins(entries: &[HostEntry]) -> BTreeMap<String, String> {
+    let mut pins: BTreeMap<String, Option<String>> = BTreeMap::new();
+    for entry in entries {
+        let Some(identity) = &entry.identity else {
+            continue;
+        };
+        pins.entry(entry.hostname.clone())
+            .and_modify(|pin| {
+                if pin.as_ref() != Some(identity) {
+                    *pin = None;
+                }
+            })
+            .or_insert_with(|| Some(identity.clone()));
+    }
+    pins.into_iter()
+        .filter_map(|(host, pin)| Some((host, pin?)))
+        .collect()
+}
+
+/// Bind the entry for `host` to `identity` at `hostname`
+///
+/// Used after the user accepted a device's new identity. Returns the updated
+/// content and whether the entry changed.
+pub fn set_identity_in(
+    content: &str,
+    host: &str,
+    identity: &str,
+    hostname: &str,
+) -> (String, bool) {
+    let (new_content, updated) = rewrite_entries_in(content, |entry| {
+        if entry.host != host {
+            return false;
+        }
+        let before = entry.clone();
+        entry.identity = Some(identity.to_string());
+        entry.hostname = hostname.to_string();
+        *entry != before
+    });
+    (new_content, !updated.is_empty())
+}
+
+/// Replace the Connecto entry with the same alias as `entry`
+///
+/// Returns the updated content and whether the entry changed. Hosts the
+/// user wrote by hand are never replaced.
+pub fn replace_entry_in(content: &str, entry: &HostEntry) -> (String, bool) {
+    let (new_content, updated) = rewrite_entries_in(content, |existing| {
+        if existing.host != entry.host || existing == entry {
+            return false;
+        }
+        *existing = entry.clone();
+        true
+    });
+    (new_content, !updated.is_empty())
+}
+
+/// Point the entry for `host` at the private key `identity_file`
+///
+/// Returns the updated content and whether the entry changed.
+pub fn set_identity_file_in(content: &str, host: &str, identity_file: &str) -> (String, bool) {
+    let (new_content, updated) = rewrite_entries_in(content, |entry| {
+        if entry.host != host || entry.identity_file == identity_file {
+            return false;
+        }
+        entry.identity_file = identity_file.to_string();
+        true
+    });
+    (new_content, !updated.is_empty())
+}
+
+/// Point the entry for `host` at `hostname`
+///
+/// Returns the updated content and whether the entry changed.
+pub fn set_hostname_in(content: &str, host: &str, hostname: &str) -> (String, bool) {
+    let (new_content, updated) = rewrite_entries_in(content, |entry| {
+        if entry.host != host || entry.hostname == hostname {
+            return false;
+        }
+        entry.hostname = hostname.to_string();
+        true
+    });
+    (new_content, !updated.is_empty())
+}
+
+/// Rename the entry for `host` to `new_host`, unless a `Host` line already
+/// names `new_host`
+///
+/// Returns the updated content and whether the entry changed.
+pub fn rename_host_in(content: &str, host: &str, new_host: &str) -> (String, bool) {
+    if has_host_in(content, new_host) {
+        return (content.to_string(), false);
+    }
+    let (new_content, updated) = rewrite_entries_in(content, |entry| {
+        if entry.host != host {
+            return false;
+        }
+        entry.host = new_host.to_string();
+        true
+    });
+    (new_content, !updated.is_empty())
+}
+
+/// Bring every tagged entry's options in line with `templates`
+///
+/// Returns the updated content and the host aliases whose options changed.
+pub fn apply_templates_in(content: &str, templates: &TagTemplates) -> (String, Vec<String>) {
+    rewrite_entries_in(content, |entry| entry.apply_templates(templates))
+}
+
+/// Set the tags of the entry for `host`, taking its options from `templates`
+///
+/// Removing the last tag also removes the options the templates gave it.
+/// Returns the updated content and whether the entry changed.
+pub fn set_tags_in(
+    content: &str,
+    host: &str,
+    tags: &[String],
+    templates: &TagTemplates,
+) -> (String, bool) {
+    let (new_content, updated) = rewrite_entries_in(content, |entry| {
+        if entry.host != host {
+            return false;
+        }
+        let before = entry.clone();
+        if tags.is_empty() {
+            entry.options.clear();
+        }
+        entry.tags = tags.to_vec();
+        entry.apply_templates(templates);
+        *entry != before
+    });
+    (new_content, !updated.is_empty())
+}
+
+/// Set or clear the keep-warm schedule of the entry for `host`
+///
+/// Returns the updated content and whether the entry changed.
+pub fn set_keep_warm_in(content: &str, host: &str, schedule: Option<Schedule>) -> (String, bool) {
+    let (new_content, updated) = rewrite_entries_in(content, |entry| {
+        if entry.host != host || entry.keep_warm == schedule {
+            return false;
+        }
+        entry.keep_warm = schedule;
+        true
+    });
+    (new_content, !updated.is_empty())
+}
+
+/// The user's SSH config file
+#[derive(Debug, Clone)]
+pub struct SshConfig {
+    path: PathBuf,
+}
+
+impl SshConfig {
+    /// Use the default `~/.ssh/config`
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            path: KeyManager::default_ssh_dir()?.join("config"),
+        })
+    }
+
+    /// Use a specific config file
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Path of the config file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All host entries written by Connecto
+    pub fn entries(&self) -> Result<Vec<HostEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(parse_entries(&fs::read_to_string(&self.path)?))
+    }
+
+    /// Whether any `Host` line names `host`, Connecto's or not
+    pub fn has_host(&self, host: &str) -> Result<bool> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+        Ok(has_host_in(&fs::read_to_string(&self.path)?, host))
+    }
+
+    /// Append `entry` to the file, creating it and `~/.ssh` if needed
+    ///
+    /// Returns `false`, leaving the file alone, if a `Host` line already names
+    /// the entry's alias.
+    pub fn add_entry(&self, entry: &HostEntry) -> Result<bool> {
+        entry.validate()?;
+        if let Some(dir) = self.path.parent() {
+            KeyManager::with_dir(dir.to_path_buf()).ensure_ssh_dir()?;
+        }
+        let _lock = backups::lock(&self.path)?;
+        if self.path.exists() && has_host_in(&fs::read_to_string(&self.path)?, &entry.host) {
+            return Ok(false);
+        }
+
+        let mut content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        content.push_str(&entry.to_block());
+        backups::replace(&self.path, &content)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(true)
+    }
+
+    /// Replace the Connecto entry with the same alias as `entry`
+    ///
+    /// Returns whether the entry changed; see [`replace_entry_in`].
+    pub fn replace_entry(&self, entry: &HostEntry) -> Result<bool> {
+        entry.validate()?;
+        self.rewrite(|content| replace_entry_in(content, entry))
+    }
+
+    /// Update the `HostName` of every entry bound to `identity`
+    ///
+    /// Returns the host aliases that were changed; the file is only rewritten
+    /// when something changed.
+    pub fn update_address(&self, identity: &str, address: &str) -> Result<Vec<String>> {
+        validate_hostname(address)?;
+        self.rewrite(|content| update_address_in(content, identity, address))
+    }
+
+    /// Identities pinned for hosts, keyed by `HostName`
+    ///
+    /// See [`identity_pins`].
+    pub fn identity_pins(&self) -> Result<BTreeMap<String, String>> {
+        Ok(identity_pins(&self.entries()?))
+    }
+
+    /// Bind the entry for `host` to `identity` at `hostname`
+    ///
+    /// Returns whether the entry changed.
+    pub fn set_identity(&self, host: &str, identity: &str, hostname: &str) -> Result<bool> {
+        validate_hostname(hostname)?;
+        self.rewrite(|content| set_identity_in(content, host, identity, hostname))
+    }
+
+    /// Re-apply `templates` to every tagged entry
+    ///
+    /// Returns the host aliases whose options changed; the file is only
+    /// rewritten when something changed.
+    pub fn apply_templates(&self, templates: &TagTemplates) -> Result<Vec<String>> {
+        validate_templates(templates)?;
+        self.rewrite(|content| apply_templates_in(content, templates))
+    }
+
+    /// Point the entry for `host` at the private key `identity_file`
+    ///
+    /// Returns whether the entry changed.
+    pub fn set_identity_file(&self, host: &str, identity_file: &str) -> Result<bool> {
+        validate_path(identity_file)?;
+        self.rewrite(|content| set_identity_file_in(content, host, identity_file))
+    }
+
+    /// Point the entry for `host` at `hostname`
+    ///
+    /// Returns whether the entry changed.
+    pub fn set_hostname(&self, host: &str, hostname: &str) -> Result<bool> {
+        validate_hostname(hostname)?;
+        self.rewrite(|content| set_hostname_in(content, host, hostname))
+    }
+
+    /// Rename the entry for `host` to `new_host`, unless that alias is taken
+    ///
+    /// Returns whether the entry changed.
+    pub fn rename_host(&self, host: &str, new_host: &str) -> Result<bool> {
+        validate_host_alias(new_host)?;
+        self.rewrite(|content| rename_host_in(content, host, new_host))
+    }
+
+    /// Set the tags of the entry for `host`, applying `templates`
+    ///
+    /// Returns whether the entry changed.
+    pub fn set_tags(&self, host: &str, tags: &[String], templates: &TagTemplates) -> Result<bool> {
+        for tag in tags {
+            check_word("Tag", tag)?;
+        }
+        validate_templates(templates)?;
+        self.rewrite(|content| set_tags_in(content, host, tags, templates))
+    }
+
+    /// Set or clear the keep-warm schedule of the entry for `host`
+    ///
+    /// Returns whether the entry changed.
+    pub fn set_keep_warm(&self, host: &str, schedule: Option<Schedule>) -> Result<bool> {
+        self.rewrite(|content| set_keep_warm_in(content, host, schedule))
+    }
+
+    /// Apply `edit` to the file's content under [`backups::lock`], writing
+    /// the result back if `edit` reports a change
+    ///
+    /// A missing file is left alone.
+    fn rewrite<T: Edit>(&self, edit: impl FnOnce(&str) -> (String, T)) -> Result<T> {
+        if !self.path.exists() {
+            return Ok(T::default());
+        }
+        let _lock = backups::lock(&self.path)?;
+        let content = fs::read_to_string(&self.path)?;
+        let (new_content, outcome) = edit(&content);
+        if outcome.changed() {
+            backups::replace(&self.path, &new_content)?;
+        }
+        Ok(outcome)
+    }
+}
+
+/// What an edit of the config returns: whether the entry changed, or the
+/// hosts that did
+trait Edit: Default {
+    fn changed(&self) -> bool;
+}
+
+impl Edit for bool {
+    fn changed(&self) -> bool {
+        *self
+    }
+}
+
+impl Edit for Vec<String> {
+    fn changed(&self) -> bool {
+        !self.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const CONFIG: &str = "Host github.com\n    User git\n\n# Added by connecto\nHost laptop\n    HostName 192.168.1.10\n    User alice\n    # connecto-identity SHA256:aaa\n    IdentityFile ~/.ssh/id_laptop\n\n# Added by connecto\nHost legacy\n    HostName 192.168.1.20\n    User bob\n    IdentityFile ~/.ssh/id_legacy\n";
+
+    #[test]
+    fn test_parse_entries() {
+        let entries = parse_entries(CONFIG);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].host, "laptop");
+        assert_eq!(entries[0].hostname, "192.168.1.10");
+        assert_eq!(entries[0].user, "alice");
+        assert_eq!(entries[0].identity.as_deref(), Some("SHA256:aaa"));
+        assert_eq!(entries[0].identity_file, "~/.ssh/id_laptop");
+        assert_eq!(entries[1].host, "legacy");
+        assert_eq!(entries[1].identity, None);
+    }
+
+    #[test]
+    fn test_block_round_trip() {
+        let entry = HostEntry {
+            host: "desk".to_string(),
+            hostname: "10.0.0.5".to_string(),
+            user: "carol".to_string(),
+            identity_file: "/home/carol/.ssh/id_desk".to_string(),
+            identity: Some("SHA256:bbb".to_string()),
+            ..Default::default()
+        }
+        .with_tags(&["prod".to_string()], &templates());
+        assert_eq!(parse_entries(&entry.to_block()), vec![entry.clone()]);
+
+        let with_port = HostEntry {
+            port: Some(2222),
+            ..entry
+        };
+        assert!(with_port
+            .to_block()
+            .contains("\n    User carol\n    Port 2222\n"));
+        assert_eq!(parse_entries(&with_port.to_block()), vec![with_port]);
+    }
+
+    #[test]
+    fn test_templated_port_wins() {
+        let mut templates = templates();
+        templates
+            .entry("lab".to_string())
+            .or_default()
+            .insert("Port".to_string(), "2200".to_string());
+        let entry = HostEntry {
+            host: "rig".to_string(),
+            hostname: "10.0.0.8".to_string(),
+            user: "dev".to_string(),
+            port: Some(22),
+            identity_file: "~/.ssh/connecto_rig".to_string(),
+            ..Default::default()
+        }
+        .with_tags(&tags(&["lab"]), &templates);
+
+        let block = entry.to_block();
+        assert_eq!(block.matches("Port").count(), 1);
+        assert!(block.contains("    Port 2200\n"));
+        let parsed = parse_entries(&block).remove(0);
+        assert_eq!(parsed.port, None);
+        assert_eq!(parsed.options, entry.options);
+    }
+
+    #[test]
+    fn test_replace_entry() {
+        let laptop = HostEntry {
+            host: "laptop".to_string(),
+            hostname: "192.168.1.30".to_string(),
+            user: "dave".to_string(),
+            port: Some(22),
+            identity_file: "~/.ssh/connecto_laptop".to_string(),
+            ..Default::default()
+        };
+        let (content, changed) = replace_entry_in(CONFIG, &laptop);
+        assert!(changed);
+        let entries = parse_entries(&content);
+        assert_eq!(entries[0], laptop);
+        assert_eq!(entries[1].host, "legacy");
+        assert!(content.starts_with("Host github.com\n    User git\n\n"));
+
+        // Replacing with the same entry, or a host that isn't Connecto's,
+        // changes nothing
+        assert!(!replace_entry_in(&content, &laptop).1);
+        let github = HostEntry {
+            host: "github.com".to_string(),
+            ..laptop
+        };
+        assert!(!replace_entry_in(CONFIG, &github).1);
+    }
+
+    fn templates() -> TagTemplates {
+        let mut templates = TagTemplates::new();
+        templates
+            .entry("prod".to_string())
+            .or_default()
+            .insert("StrictHostKeyChecking".to_string(), "yes".to_string());
+        templates
+            .entry("prod".to_string())
+            .or_default()
+            .insert("ForwardAgent".to_string(), "no".to_string());
+        templates
+            .entry("lab".to_string())
+            .or_default()
+            .insert("ForwardAgent".to_string(), "yes".to_string());
+        templates
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_template_options_first_tag_wins() {
+        let options = template_options(&tags(&["lab", "prod", "unknown"]), &templates());
+        assert_eq!(
+            options,
+            vec![
+                ("ForwardAgent".to_string(), "yes".to_string()),
+                ("StrictHostKeyChecking".to_string(), "yes".to_string()),
+            ]
+        );
+        assert!(template_options(&[], &templates()).is_empty());
+    }
+
+    #[test]
+    fn test_tagged_block_ends_with_identity_file() {
+        let block = HostEntry {
+            host: "db".to_string(),
+            hostname: "10.0.0.7".to_string(),
+            user: "ops".to_string(),
+            identity_file: "~/.ssh/connecto_db".to_string(),
+            ..Default::default()
+        }
+        .with_tags(&tags(&["prod"]), &templates())
+        .to_block();
+        assert_eq!(
+            block,
+            "\n# Added by connecto\nHost db\n    HostName 10.0.0.7\n    User ops\n    # connecto-tags prod\n    ForwardAgent no\n    StrictHostKeyChecking yes\n    IdentityFile ~/.ssh/connecto_db\n"
+        );
+    }
+
+    #[test]
+    fn test_set_tags_and_reapply() {
+        let (content, changed) = set_tags_in(CONFIG, "laptop", &tags(&["prod"]), &templates());
+        assert!(changed);
+        let laptop = parse_entries(&content).remove(0);
+        assert_eq!(laptop.tags, tags(&["prod"]));
+        assert_eq!(laptop.identity.as_deref(), Some("SHA256:aaa"));
+        assert_eq!(laptop.options.len(), 2);
+        // Everything around the entry is untouched
+        assert!(content.starts_with("Host github.com\n    User git\n\n# Added by connecto\n"));
+        assert!(content.ends_with("    IdentityFile ~/.ssh/id_legacy\n"));
+
+        // A template change reaches tagged entries only
+        let mut changed_templates = templates();
+        changed_templates.remove("prod");
+        changed_templates
+            .entry("prod".to_string())
+            .or_default()
+            .insert("ForwardAgent".to_string(), "no".to_string());
+        let (content, updated) = apply_templates_in(&content, &changed_templates);
+        assert_eq!(updated, vec!["laptop".to_string()]);
+        assert_eq!(
+            parse_entries(&content)[0].options,
+            vec![("ForwardAgent".to_string(), "no".to_string())]
+        );
+        let (_, updated) = apply_templates_in(&content, &changed_templates);
+        assert!(updated.is_empty());
+
+        // Untagging drops the template options
+        let (content, changed) = set_tags_in(&content, "laptop", &[], &changed_templates);
+        assert!(changed);
+        assert_eq!(content, CONFIG);
+    }
+
+    #[test]
+    fn test_identity_pins() {
+        let mut entries = parse_entries(CONFIG);
+        let pins = identity_pins(&entries);
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins["192.168.1.10"], "SHA256:aaa");
+
+        // Entries that disagree about a host pin nothing
+        entries.push(HostEntry {
+            host: "laptop-old".to_string(),
+            hostname: "192.168.1.10".to_string(),
+            identity: Some("SHA256:old".to_string()),
+            ..Default::default()
+        });
+        assert!(identity_pins(&entries).is_empty());
+    }
+
+    #[test]
+    fn test_set_identity() {
+        let (content, changed) = set_identity_in(CONFIG, "legacy", "SHA256:new", "192.168.1.21");
+        assert!(changed);
+        let legacy = parse_entries(&content).remove(1);
+        assert_eq!(legacy.identity.as_deref(), Some("SHA256:new"));
+        assert_eq!(legacy.hostname, "192.168.1.21");
+        assert_eq!(legacy.identity_file, "~/.ssh/id_legacy");
+
+        let (_, changed) = set_identity_in(&content, "legacy", "SHA256:new", "192.168.1.21");
+        assert!(!changed);
+    }
+
+    #[test]
+    fn test_set_identity_file() {
+        let (content, changed) = set_identity_file_in(CONFIG, "laptop", "~/.ssh/id_laptop-2");
+        assert!(changed);
+        let entries = parse_entries(&content);
+        assert_eq!(entries[0].identity_file, "~/.ssh/id_laptop-2");
+        assert_eq!(entries[0].identity.as_deref(), Some("SHA256:aaa"));
+        assert_eq!(entries[1].identity_file, "~/.ssh/id_legacy");
+        assert!(content.starts_with("Host github.com\n"));
+
+        let (_, changed) = set_identity_file_in(&content, "laptop", "~/.ssh/id_laptop-2");
+        assert!(!changed);
+    }
+
+    #[test]
+    fn test_set_hostname() {
+        let (content, changed) = set_hostname_in(CONFIG, "laptop", "192.168.1.99");
+        assert!(changed);
+        let entries = parse_entries(&content);
+        assert_eq!(entries[0].hostname, "192.168.1.99");
+        assert_eq!(entries[0].identity.as_deref(), Some("SHA256:aaa"));
+        assert_eq!(entries[1].hostname, "192.168.1.20");
+        assert!(content.starts_with("Host github.com\n"));
+
+        let (_, changed) = set_hostname_in(&content, "laptop", "192.168.1.99");
+        assert!(!changed);
+        let (_, changed) = set_hostname_in(CONFIG, "github.com", "10.0.0.1");
+        assert!(!changed);
+    }
+
+    #[test]
+    fn test_rename_host() {
+        let (content, changed) = rename_host_in(CONFIG, "laptop", "study");
+        assert!(changed);
+        let entries = parse_entries(&content);
+        assert_eq!(entries[0].host, "study");
+        assert_eq!(entries[0].identity.as_deref(), Some("SHA256:aaa"));
+        assert_eq!(entries[1].host, "legacy");
+
+        // Aliases in use, Connecto's or not, are left alone
+        assert!(!rename_host_in(&content, "study", "legacy").1);
+        assert!(!rename_host_in(&content, "study", "github.com").1);
+        assert!(!rename_host_in(&content, "laptop", "desk").1);
+    }
+
+    #[test]
+    fn test_set_keep_warm() {
+        let (content, changed) = set_keep_warm_in(CONFIG, "laptop", Some(Schedule::WORK_HOURS));
+        assert!(changed);
+        assert!(content.contains(
+            "    # connecto-keep-warm mon-fri 09:00-18:00\n    ControlPath ~/.ssh/connecto-%C\n    IdentityFile ~/.ssh/id_laptop\n"
+        ));
+        let entries = parse_entries(&content);
+        assert_eq!(entries[0].keep_warm, Some(Schedule::WORK_HOURS));
+        assert!(entries[0].options.is_empty());
+        assert_eq!(entries[1].keep_warm, None);
+        assert!(!set_keep_warm_in(&content, "laptop", Some(Schedule::WORK_HOURS)).1);
+
+        let (content, changed) = set_keep_warm_in(&content, "laptop", None);
+        assert!(changed);
+        assert_eq!(content, CONFIG);
+    }
+
+    #[test]
+    fn test_untagged_options_are_kept() {
+        let content = CONFIG.replace(
+            "    IdentityFile ~/.ssh/id_legacy\n",
+            "    Port 2222\n    Compression yes\n    IdentityFile ~/.ssh/id_legacy\n",
+        );
+        let entries = parse_entries(&content);
+        assert_eq!(entries[1].port, Some(2222));
+        assert_eq!(
+            entries[1].options,
+            vec![("Compression".to_string(), "yes".to_string())]
+        );
+
+        let (unchanged, updated) = apply_templates_in(&content, &templates());
+        assert!(updated.is_empty());
+        assert_eq!(unchanged, content);
+    }
+
+    #[test]
+    fn test_update_address_by_identity() {
+        let (content, updated) = update_address_in(CONFIG, "SHA256:aaa", "192.168.1.99");
+        assert_eq!(updated, vec!["laptop".to_string()]);
+        assert!(content.contains("    HostName 192.168.1.99\n"));
+        // Other entries are untouched
+        assert!(content.contains("    HostName 192.168.1.20\n"));
+        assert!(content.starts_with("Host github.com\n"));
+        assert!(content.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_update_address_noop() {
+        let (content, updated) = update_address_in(CONFIG, "SHA256:aaa", "192.168.1.10");
+        assert!(updated.is_empty());
+        assert_eq!(content, CONFIG);
+
+        let (_, updated) = update_address_in(CONFIG, "SHA256:unknown", "10.0.0.1");
+        assert!(updated.is_empty());
+    }
+
+    #[test]
+    fn test_update_address_keeps_mdns_hostnames() {
+        let config = CONFIG.replace("HostName 192.168.1.10", "HostName laptop.local");
+        let (content, updated) = update_address_in(&config, "SHA256:aaa", "192.168.1.99");
+        assert!(updated.is_empty());
+        assert_eq!(content, config);
+    }
+
+    #[test]
+    fn test_ssh_config_update_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("config");
+        fs::write(&path, CONFIG).unwrap();
+
+        let config = SshConfig::with_path(path.clone());
+        let updated = config.update_address("SHA256:aaa", "10.1.1.1").unwrap();
+        assert_eq!(updated, vec!["laptop".to_string()]);
+
+        let entries = config.entries().unwrap();
+        assert_eq!(entries[0].hostname, "10.1.1.1");
+
+        assert!(config
+            .set_tags("legacy", &tags(&["lab"]), &templates())
+            .unwrap());
+        assert!(!config
+            .set_tags("legacy", &tags(&["lab"]), &templates())
+            .unwrap());
+        assert!(!config
+            .set_tags("missing", &tags(&["lab"]), &templates())
+            .unwrap());
+        assert_eq!(config.entries().unwrap()[1].tags, tags(&["lab"]));
+        assert!(config.apply_templates(&templates()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_values() {
+        assert!(validate_host_alias("desk-pc.home").is_ok());
+        assert!(validate_hostname("fe80::1%en0").is_ok());
+        assert!(validate_user("alice").is_ok());
+        assert!(validate_path("/Users/Alice Smith/.ssh/connecto_desk").is_ok());
+
+        for bad in [
+            "",
+            "my desk",
+            "desk*",
+            "!desk",
+            "de\"sk",
+            "#desk",
+            "desk\nHost *",
+        ] {
+            assert!(validate_host_alias(bad).is_err(), "{:?}", bad);
+        }
+        assert!(validate_hostname("10.0.0.5 ProxyCommand evil").is_err());
+        assert!(validate_user("alice\n    ProxyCommand evil").is_err());
+        assert!(validate_path("/tmp/key\nProxyCommand evil").is_err());
+        assert!(validate_path("/tmp/\"key\"").is_err());
+
+        let e = validate_hostname("10.0.0.5\nProxyCommand evil").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "SSH config error: HostName \"10.0.0.5\\nProxyCommand evil\" cannot contain newlines or control characters"
+        );
+    }
+
+    #[test]
+    fn test_format_and_parse_arg() {
+        assert_eq!(format_arg("10.0.0.5"), "10.0.0.5");
+        assert_eq!(format_arg("fe80::1%en0"), "fe80::1%%en0");
+        assert_eq!(
+            format_arg("/Users/Alice Smith/.ssh/id"),
+            "\"/Users/Alice Smith/.ssh/id\""
+        );
+        for value in ["10.0.0.5", "fe80::1%en0", "/Users/Alice Smith/100% mine/id"] {
+            assert_eq!(parse_arg(&format_arg(value)), value);
+        }
+        // Written before values were escaped
+        assert_eq!(parse_arg(" ~/.ssh/id_desk "), "~/.ssh/id_desk");
+    }
+
+    #[test]
+    fn test_block_escapes_values() {
+        let entry = HostEntry {
+            host: "desk".to_string(),
+            hostname: "fe80::1%en0".to_string(),
+            user: "alice".to_string(),
+            identity_file: "/Users/Alice Smith/.ssh/connecto_desk".to_string(),
+            identity: Some("SHA256:ccc".to_string()),
+            ..Default::default()
+        };
+        let block = entry.to_block();
+        assert!(block.contains("    HostName fe80::1%%en0\n"));
+        assert!(block.contains("    IdentityFile \"/Users/Alice Smith/.ssh/connecto_desk\"\n"));
+        assert_eq!(parse_entries(&block), vec![entry.clone()]);
+
+        let (content, updated) = update_address_in(&block, "SHA256:ccc", "fe80::2%en1");
+        assert_eq!(updated, ["desk"]);
+        assert!(content.contains("    HostName fe80::2%%en1\n"));
+    }
+
+    #[test]
+    fn test_invalid_values_are_not_written() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("config");
+        let config = SshConfig::with_path(path.clone());
+        let entry = HostEntry {
+            host: "desk".to_string(),
+            hostname: "10.0.0.5".to_string(),
+            user: "alice".to_string(),
+            identity_file: "~/.ssh/connecto_desk".to_string(),
+            ..Default::default()
+        };
+
+        let injected = HostEntry {
+            user: "alice\n    ProxyCommand touch /tmp/pwned".to_string(),
+            ..entry.clone()
+        };
+        assert!(matches!(
+            config.add_entry(&injected),
+            Err(ConnectoError::SshConfig(_))
+        ));
+        assert!(!path.exists());
+
+        assert!(config.add_entry(&entry).unwrap());
+        let before = fs::read_to_string(&path).unwrap();
+        assert!(config
+            .set_hostname("desk", "10.0.0.6 -oProxyCommand=x")
+            .is_err());
+        assert!(config.rename_host("desk", "desk *").is_err());
+        assert!(config.set_identity_file("desk", "/tmp/a\rb").is_err());
+        let mut templates = TagTemplates::new();
+        templates
+            .entry("lab".to_string())
+            .or_default()
+            .insert("ProxyCommand".to_string(), "nc %h %p\nHost *".to_string());
+        assert!(config
+            .set_tags("desk", &tags(&["lab"]), &templates)
+            .is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn test_host_alias() {
+        assert_eq!(host_alias("My Device"), "my_device");
+        assert_eq!(host_alias("test-host"), "test-host");
+        assert_eq!(host_alias("Host (123)"), "host__123_");
+    }
+
+    #[test]
+    fn test_add_entry() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join(".ssh").join("config");
+        let config = SshConfig::with_path(path.clone());
+        let entry = HostEntry {
+            host: "desk".to_string(),
+            hostname: "10.0.0.5".to_string(),
+            user: "alice".to_string(),
+            identity_file: "~/.ssh/connecto_desk".to_string(),
+            identity: Some("SHA256:aaa".to_string()),
+            ..Default::default()
+        };
+
+        assert!(config.add_entry(&entry).unwrap());
+        assert_eq!(config.entries().unwrap(), [entry.clone()]);
+        assert!(!config.add_entry(&entry).unwrap());
+        assert_eq!(config.entries().unwrap().len(), 1);
+
+        // Hosts the user wrote by hand are not duplicated either
+        fs::write(&path, "Host build desk-old\n    HostName 10.0.0.9\n").unwrap();
+        let old = HostEntry {
+            host: "desk-old".to_string(),
+            ..entry.clone()
+        };
+        assert!(!config.add_entry(&old).unwrap());
+        assert!(config.add_entry(&entry).unwrap());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
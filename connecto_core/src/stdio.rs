@@ -0,0 +1,133 @@
+//! Pairing over stdin and stdout
+//!
+//! Lets the handshake travel over a channel Connecto does not open itself:
+//! `ssh` carrying one side's output to the other, or a person pasting each
+//! message from one terminal into another. The messages are the same ones
+//! sent over TCP; the client keeps them to one line of JSON each with
+//! [`HandshakeClient::pair_over_lines`](crate::protocol::HandshakeClient::pair_over_lines).
+//!
+//! Everything meant for a person has to go to stderr while the handshake
+//! runs, since stdout carries the protocol.
+
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+
+/// How long either side waits for each message over stdio, long enough for
+/// someone to copy and paste it
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Bytes buffered between the process's streams and the handshake
+const BUFFER_LEN: usize = 64 * 1024;
+
+/// Environment variable `sshd` sets to the client's address and port
+const SSH_CLIENT_VAR: &str = "SSH_CLIENT";
+
+/// A stream that reads this process's stdin and writes its stdout
+///
+/// Background tasks copy between them and the stream, flushing stdout after
+/// every write so each message leaves as soon as it is sent. Stdin is read on
+/// a thread of its own, so a read still waiting when the pairing is over
+/// does not keep the process from exiting.
+pub fn stream() -> DuplexStream {
+    let (local, remote) = tokio::io::duplex(BUFFER_LEN);
+    let (mut remote_reader, mut remote_writer) = tokio::io::split(remote);
+
+    let (chunk_tx, mut chunk_rx) = mpsc::channel::<Vec<u8>>(4);
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buf = vec![0; BUFFER_LEN];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if chunk_tx.blocking_send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(chunk) = chunk_rx.recv().await {
+            if remote_writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+        let _ = remote_writer.shutdown().await;
+    });
+
+    tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        let mut buf = vec![0; BUFFER_LEN];
+        loop {
+            match remote_reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let written = async {
+                        stdout.write_all(&buf[..n]).await?;
+                        stdout.flush().await
+                    };
+                    if written.await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    local
+}
+
+/// The address of the device on the other end of stdin
+///
+/// Under `sshd` this is the SSH client's address; otherwise nobody knows,
+/// and it is `0.0.0.0:0`.
+pub fn peer_address() -> SocketAddr {
+    std::env::var(SSH_CLIENT_VAR)
+        .ok()
+        .and_then(|value| parse_ssh_client(&value))
+        .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+}
+
+/// Whether [`peer_address`] knows who is on the other end
+pub fn peer_known(peer: SocketAddr) -> bool {
+    !peer.ip().is_unspecified()
+}
+
+/// Parse `SSH_CLIENT`: the client's address, its port and the server's port
+fn parse_ssh_client(value: &str) -> Option<SocketAddr> {
+    let mut fields = value.split_whitespace();
+    let ip = fields.next()?.parse().ok()?;
+    let port = fields.next()?.parse().ok()?;
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_parse_ssh_client() {
+        assert_eq!(
+            parse_ssh_client("192.168.1.20 52144 22"),
+            Some("192.168.1.20:52144".parse().unwrap())
+        );
+        assert_eq!(
+            parse_ssh_client("fe80::1 52144 22"),
+            Some(SocketAddr::new("fe80::1".parse::<IpAddr>().unwrap(), 52144))
+        );
+        assert_eq!(parse_ssh_client(""), None);
+        assert_eq!(parse_ssh_client("192.168.1.20"), None);
+        assert_eq!(parse_ssh_client("desk-pc 52144 22"), None);
+    }
+
+    #[test]
+    fn test_peer_known() {
+        assert!(peer_known("10.0.0.5:4000".parse().unwrap()));
+        assert!(!peer_known(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))));
+    }
+}
@@ -1,16 +1,40 @@
 //! Bidirectional sync module for Connecto
 //!
 //! Enables two devices to simultaneously exchange SSH keys so both can SSH to each other.
+//!
+//! [`SyncHandler::run`] syncs once. [`SyncHandler::run_daemon`] keeps
+//! running: it syncs with every new peer it finds, and keeps the keys of
+//! peers it synced with up to date by exchanging key lists (see
+//! [`crate::sync_keys`]).
+//!
+//! A peer that announces an identity is challenged to prove it with a
+//! `KeyChallenge`, answered by an `IdentityProof`, before its identity is
+//! checked against its pin; each side signs the nonce the other sent along
+//! with its first message.
 
+use crate::audit::{AuditEvent, Decision, DecisionLog, DecisionRecord};
+use crate::clock;
 use crate::error::{ConnectoError, Result};
-use crate::keys::{KeyManager, SshKeyPair};
-use crate::protocol::{Message, PROTOCOL_VERSION};
+use crate::framing::Framing;
+use crate::identity::{DeviceIdentity, IdentityProof, VerifiedIdentity};
+use crate::keys::{fingerprint, KeyManager, SshKeyPair};
+use crate::net;
+use crate::ports;
+use crate::protocol::{
+    current_user, generate_nonce, proven_identity, ApprovalRequest, Message, ProtocolErrorCode,
+    APPROVAL_TIMEOUT_SECS,
+};
+use crate::shutdown::ShutdownHandle;
+use crate::sync_keys::{key_id, key_list_digest, KeyDiff, SyncKeyStore};
+use crate::trust::{TrustLevel, TrustMode, TrustStore};
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
 use rand::Rng;
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
@@ -18,9 +42,25 @@ use tracing::{debug, info, warn};
 /// Service type for sync discovery (different from regular pairing)
 pub const SYNC_SERVICE_TYPE: &str = "_connecto-sync._tcp.local.";
 
+/// Version carried in sync messages
+///
+/// Sync messages have not changed since pairing protocol version 1, so sync
+/// keeps its own version to stay compatible with older peers.
+pub const SYNC_PROTOCOL_VERSION: u32 = 1;
+
 /// Default timeout for peer discovery
 pub const DEFAULT_SYNC_TIMEOUT_SECS: u64 = 60;
 
+/// Default time between key list exchanges in daemon mode
+pub const DEFAULT_SYNC_INTERVAL_SECS: u64 = 300;
+
+/// How long a daemon waits for one exchange with a peer
+const EXCHANGE_TIMEOUT_SECS: u64 = 15;
+
+/// Longest message a peer may send, in bytes; enough for a key list of
+/// thousands of keys
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
 /// Events emitted during sync operation
 #[derive(Debug, Clone)]
 pub enum SyncEvent {
@@ -49,6 +89,14 @@ pub enum SyncEvent {
     },
     /// Sync failed
     Failed { message: String },
+    /// A daemon finished syncing with a peer
+    PeerSynced { result: SyncResult },
+    /// A peer's key list changed, and its keys in authorized_keys with it
+    KeysChanged {
+        device_name: String,
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
 }
 
 /// Result of a successful sync operation
@@ -58,6 +106,43 @@ pub struct SyncResult {
     pub peer_user: String,
     pub peer_address: IpAddr,
     pub peer_port: u16,
+    /// Identity the peer proved, if any
+    pub peer_identity: Option<VerifiedIdentity>,
+    /// How far the peer's clock is ahead of ours in seconds, if it sent it
+    pub clock_skew: Option<i64>,
+    /// The keys the peer shared, its main key first
+    pub received_keys: Vec<ReceivedKey>,
+}
+
+/// What became of a key a peer shared
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyOutcome {
+    /// Added to authorized_keys
+    Added,
+    /// Already in authorized_keys
+    AlreadyAuthorized,
+    /// Refused by the deny list or the user, with the reason
+    Refused(String),
+    /// Not installed, with the reason
+    Failed(String),
+}
+
+/// A key a peer shared in a sync
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedKey {
+    pub public_key: String,
+    pub comment: String,
+    pub outcome: KeyOutcome,
+}
+
+impl ReceivedKey {
+    /// Whether the key is in authorized_keys now
+    pub fn is_installed(&self) -> bool {
+        matches!(
+            self.outcome,
+            KeyOutcome::Added | KeyOutcome::AlreadyAuthorized
+        )
+    }
 }
 
 /// A discovered sync peer
@@ -81,7 +166,80 @@ impl SyncPeer {
 
     fn connection_string(&self) -> Option<String> {
         self.primary_address()
-            .map(|addr| format!("{}:{}", addr, self.port))
+            .map(|addr| net::format_address(addr, self.port, None))
+    }
+
+    /// The device name the peer advertised, without its hostname
+    fn name(&self) -> &str {
+        let instance = self
+            .device_name
+            .strip_suffix(SYNC_SERVICE_TYPE)
+            .and_then(|name| name.strip_suffix('.'))
+            .unwrap_or(&self.device_name);
+        instance
+            .rsplit_once(" (")
+            .map_or(instance, |(name, _)| name)
+    }
+}
+
+/// Read the next message from a peer, of at most [`MAX_MESSAGE_LEN`] bytes
+async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Message> {
+    let mut line = String::new();
+    if Framing::Lines
+        .read(reader, &mut line, MAX_MESSAGE_LEN)
+        .await?
+        == 0
+    {
+        return Err(ConnectoError::Protocol(
+            "Peer closed the connection".to_string(),
+        ));
+    }
+    Message::from_json(&line)
+}
+
+/// Read a peer's proof of the identity it announced as `announced`, over
+/// the `nonce` we sent it
+///
+/// A bad proof is answered with an error before failing.
+async fn receive_identity_proof<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    writer: &mut OwnedWriteHalf,
+    nonce: &str,
+    announced: &str,
+) -> Result<VerifiedIdentity> {
+    let verified = match read_message(reader).await? {
+        Message::IdentityProof { proof } => proof.verify_announced(nonce, Some(announced)),
+        Message::Error { message, .. } => return Err(ConnectoError::Sync(message)),
+        _ => Err(ConnectoError::Protocol(
+            "Expected IdentityProof".to_string(),
+        )),
+    };
+    if verified.is_err() {
+        let error_msg = Message::Error {
+            code: ProtocolErrorCode::VerificationFailed,
+            message: "Identity proof verification failed".to_string(),
+        };
+        writer.write_all(error_msg.to_json()?.as_bytes()).await?;
+    }
+    verified
+}
+
+/// Read a peer's key changes
+async fn read_key_diff<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<KeyDiff> {
+    match read_message(reader).await? {
+        Message::SyncKeyDiff {
+            base,
+            added,
+            removed,
+            digest,
+        } => Ok(KeyDiff {
+            base,
+            added,
+            removed,
+            digest,
+        }),
+        Message::Error { message, .. } => Err(ConnectoError::Sync(message)),
+        _ => Err(ConnectoError::Protocol("Expected SyncKeyDiff".to_string())),
     }
 }
 
@@ -90,6 +248,16 @@ pub struct SyncHandler {
     key_manager: Arc<KeyManager>,
     device_name: String,
     key_pair: SshKeyPair,
+    shared_keys: Vec<String>,
+    identity: Option<DeviceIdentity>,
+    trust: Option<TrustStore>,
+    trust_mode: TrustMode,
+    decisions: Option<DecisionLog>,
+    keys: Option<SyncKeyStore>,
+    approval_tx: Option<mpsc::Sender<ApprovalRequest>>,
+    approval_timeout: Duration,
+    deny: Vec<String>,
+    shutdown: ShutdownHandle,
 }
 
 impl SyncHandler {
@@ -99,7 +267,634 @@ impl SyncHandler {
             key_manager: Arc::new(key_manager),
             device_name: device_name.to_string(),
             key_pair,
+            shared_keys: Vec::new(),
+            identity: None,
+            trust: None,
+            trust_mode: TrustMode::default(),
+            decisions: None,
+            keys: None,
+            approval_tx: None,
+            approval_timeout: Duration::from_secs(APPROVAL_TIMEOUT_SECS),
+            deny: Vec::new(),
+            shutdown: ShutdownHandle::new(),
+        }
+    }
+
+    /// A handle that cancels [`SyncHandler::run`] from another task
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Share these public keys with the peer as well as the key pair's own
+    ///
+    /// The peer authorizes all of them. Repeated keys are only sent once.
+    pub fn with_shared_keys(mut self, keys: Vec<String>) -> Self {
+        let mut seen = vec![key_id(&self.key_pair.public_key)];
+        self.shared_keys = keys
+            .into_iter()
+            .filter(|key| {
+                let id = key_id(key);
+                let new = !seen.contains(&id);
+                seen.push(id);
+                new
+            })
+            .collect();
+        self
+    }
+
+    /// Announce this device's identity to the peer, and prove it by
+    /// signing the peer's nonce with its key
+    pub fn with_identity(mut self, identity: &DeviceIdentity) -> Self {
+        self.identity = Some(identity.clone());
+        self
+    }
+
+    /// Check the peer's identity against the pins in `store`, and pin it
+    /// after the first successful sync
+    pub fn with_trust_store(mut self, store: TrustStore) -> Self {
+        self.trust = Some(store);
+        self
+    }
+
+    /// Set what happens when a peer's identity differs from its pin
+    pub fn with_trust_mode(mut self, mode: TrustMode) -> Self {
+        self.trust_mode = mode;
+        self
+    }
+
+    /// Record every accept/reject decision in a decision log
+    pub fn with_decision_log(mut self, log: DecisionLog) -> Self {
+        self.decisions = Some(log);
+        self
+    }
+
+    /// Remember the key lists exchanged with peers in `store`, so a daemon
+    /// can keep them up to date
+    pub fn with_key_store(mut self, store: SyncKeyStore) -> Self {
+        self.keys = Some(store);
+        self
+    }
+
+    /// Ask through `approval_tx` before installing a key of a device whose
+    /// trust level requires approval; without it, keys are accepted
+    ///
+    /// Keys that are already authorized are not asked about.
+    pub fn with_approval(mut self, approval_tx: mpsc::Sender<ApprovalRequest>) -> Self {
+        self.approval_tx = Some(approval_tx);
+        self
+    }
+
+    /// Refuse keys nobody approves within `timeout`
+    ///
+    /// Defaults to [`APPROVAL_TIMEOUT_SECS`].
+    pub fn with_approval_timeout(mut self, timeout: Duration) -> Self {
+        self.approval_timeout = timeout;
+        self
+    }
+
+    /// Refuse the devices named in `entries` and the keys whose SHA-256
+    /// fingerprints are in it
+    ///
+    /// Device names match regardless of case.
+    pub fn with_deny_list(mut self, entries: Vec<String>) -> Self {
+        self.deny = entries;
+        self
+    }
+
+    /// Append a decision to the decision log, if one is configured
+    fn record_decision(&self, record: DecisionRecord) {
+        if let Some(log) = &self.decisions {
+            if let Err(e) = log.append(record) {
+                warn!("Failed to record sync decision: {}", e);
+            }
+        }
+    }
+
+    /// Record the finished sync in the key manager's audit log
+    fn record_completed(&self, peer_name: &str, peer_ip: IpAddr, received: &[ReceivedKey]) {
+        let added = received
+            .iter()
+            .filter(|key| key.outcome == KeyOutcome::Added)
+            .count();
+        self.key_manager
+            .record(AuditEvent::sync_completed(peer_name, peer_ip, added));
+    }
+
+    /// Every key we share: the key pair's, then the shared keys
+    fn our_keys(&self) -> Vec<String> {
+        std::iter::once(&self.key_pair.public_key)
+            .chain(&self.shared_keys)
+            .cloned()
+            .collect()
+    }
+
+    /// Authorize the keys a peer shared, reporting what became of each
+    ///
+    /// Keys on the deny list, and keys not approved when approval is asked
+    /// for, are refused. The peer's main key must be installed for the sync
+    /// to go on: if the device or its main key is refused, nothing is
+    /// installed and [`ConnectoError::SyncRejected`] is returned. A further
+    /// key that can't be installed is reported and the others are installed.
+    async fn install_keys(
+        &self,
+        peer_name: &str,
+        peer_identity: Option<&VerifiedIdentity>,
+        peer_addr: SocketAddr,
+        (main_key, main_comment): (&str, String),
+        keys: &[String],
+        event_tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<Vec<ReceivedKey>> {
+        let peer_ip = peer_addr.ip().to_string();
+        let decision =
+            |decision, key: &str| DecisionRecord::new(decision, peer_name, &peer_ip, key);
+        if self.denies_device(peer_name) {
+            let reason = format!("{} is on the deny list", peer_name);
+            self.record_decision(decision(Decision::Rejected, main_key).with_reason(&reason));
+            return Err(ConnectoError::SyncRejected(reason));
+        }
+
+        let authorized: Vec<String> = self
+            .key_manager
+            .list_authorized_keys()?
+            .iter()
+            .map(|key| key_id(key))
+            .collect();
+        let needs_approval = self.requires_approval(peer_name, peer_identity);
+        let mut seen = Vec::new();
+        let mut received = Vec::new();
+
+        let shared = keys.iter().map(|key| {
+            let comment = key.split_whitespace().skip(2).collect::<Vec<_>>().join(" ");
+            (key.as_str(), comment)
+        });
+        for (i, (key, comment)) in std::iter::once((main_key, main_comment))
+            .chain(shared)
+            .enumerate()
+        {
+            let id = key_id(key);
+            if seen.contains(&id) {
+                continue;
+            }
+            seen.push(id.clone());
+
+            // Keys that can't be read fail below, when they are added
+            let review = match fingerprint(key) {
+                Ok(fingerprint) => {
+                    let ask = needs_approval && !authorized.contains(&id);
+                    self.review_key(peer_name, peer_addr, &fingerprint, &comment, ask)
+                        .await
+                }
+                Err(_) => Ok(None),
+            };
+            let outcome = match review {
+                Err(reason) => {
+                    self.record_decision(decision(Decision::Rejected, key).with_reason(&reason));
+                    if i == 0 {
+                        return Err(ConnectoError::SyncRejected(reason));
+                    }
+                    KeyOutcome::Refused(reason)
+                }
+                Ok(approver) => {
+                    debug!("Adding peer key to authorized_keys: {}", comment);
+                    match self.key_manager.add_authorized_key(key) {
+                        Ok(()) => {
+                            self.record_decision(
+                                decision(Decision::Accepted, key)
+                                    .with_approver(approver.as_deref()),
+                            );
+                            if authorized.contains(&id) {
+                                KeyOutcome::AlreadyAuthorized
+                            } else {
+                                KeyOutcome::Added
+                            }
+                        }
+                        Err(e) if i == 0 => return Err(e),
+                        Err(e) => {
+                            warn!("Could not authorize a key shared by {}: {}", peer_name, e);
+                            KeyOutcome::Failed(e.to_string())
+                        }
+                    }
+                }
+            };
+            let received_key = ReceivedKey {
+                public_key: key.to_string(),
+                comment,
+                outcome,
+            };
+            if received_key.is_installed() {
+                let _ = event_tx
+                    .send(SyncEvent::KeyReceived {
+                        device_name: peer_name.to_string(),
+                        key_comment: received_key.comment.clone(),
+                    })
+                    .await;
+            }
+            received.push(received_key);
+        }
+        Ok(received)
+    }
+
+    /// Whether `peer_name` is on the deny list
+    fn denies_device(&self, peer_name: &str) -> bool {
+        self.deny
+            .iter()
+            .any(|entry| entry.eq_ignore_ascii_case(peer_name))
+    }
+
+    /// Whether the key with `fingerprint` is on the deny list
+    fn denies_key(&self, fingerprint: &str) -> bool {
+        self.deny.iter().any(|entry| entry == fingerprint)
+    }
+
+    /// Whether keys from `peer_name`, which proved `peer_identity`, need
+    /// approval: only with an approval channel, and for devices whose trust
+    /// level asks for it
+    fn requires_approval(&self, peer_name: &str, peer_identity: Option<&VerifiedIdentity>) -> bool {
+        if self.approval_tx.is_none() {
+            return false;
+        }
+        let level = self
+            .trust
+            .as_ref()
+            .map(|trust| trust.level(peer_name, peer_identity));
+        level.is_none_or(|level| level.map_or(true, TrustLevel::requires_approval))
+    }
+
+    /// Check a key against the deny list and, with `ask`, have the user
+    /// approve it
+    ///
+    /// Returns who approved the key, if anybody was asked, or why it was
+    /// refused.
+    async fn review_key(
+        &self,
+        peer_name: &str,
+        peer_addr: SocketAddr,
+        fingerprint: &str,
+        comment: &str,
+        ask: bool,
+    ) -> std::result::Result<Option<String>, String> {
+        if self.denies_key(fingerprint) {
+            return Err(format!("Key {} is on the deny list", fingerprint));
+        }
+        let Some(approval_tx) = self.approval_tx.as_ref().filter(|_| ask) else {
+            return Ok(None);
+        };
+
+        let (request, response) = ApprovalRequest::new(peer_name, peer_addr, fingerprint, comment);
+        if approval_tx.send(request).await.is_err() {
+            return Err("Rejected by user".to_string());
+        }
+        match tokio::time::timeout(self.approval_timeout, response).await {
+            // Whoever answers the approval prompt is the local user
+            Ok(Ok(true)) => Ok(Some(current_user())),
+            // A dropped request counts as a rejection
+            Ok(_) => Err("Rejected by user".to_string()),
+            Err(_) => Err(format!(
+                "No answer within {}s",
+                self.approval_timeout.as_secs()
+            )),
+        }
+    }
+
+    /// Tell the peer its keys were refused
+    async fn send_rejection(&self, writer: &mut OwnedWriteHalf, reason: &str) -> Result<()> {
+        let rejected = Message::SyncRejected {
+            device_name: self.device_name.clone(),
+            message: reason.to_string(),
+        };
+        writer.write_all(rejected.to_json()?.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Take back the keys this sync added, after the peer refused ours
+    fn withdraw_keys(&self, received: &[ReceivedKey]) {
+        for key in received
+            .iter()
+            .filter(|key| key.outcome == KeyOutcome::Added)
+        {
+            if let Err(e) = self.key_manager.remove_authorized_key(&key.public_key) {
+                warn!("Failed to withdraw key {}: {}", key.comment, e);
+            }
+        }
+    }
+
+    /// Check a peer's proven identity before trusting its key
+    async fn verify_peer(
+        &self,
+        peer_name: &str,
+        peer_identity: Option<&VerifiedIdentity>,
+        peer_record: DecisionRecord,
+        event_tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<()> {
+        let Some(trust) = &self.trust else {
+            return Ok(());
+        };
+        if let Err(e) = trust.verify(peer_name, peer_identity, self.trust_mode) {
+            self.record_decision(
+                peer_record.with_reason("Identity does not match the pinned identity"),
+            );
+            let _ = event_tx
+                .send(SyncEvent::Failed {
+                    message: e.to_string(),
+                })
+                .await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Pin a peer's proven identity once the sync has succeeded
+    fn pin_peer(&self, peer_name: &str, peer_identity: Option<&VerifiedIdentity>) {
+        if let (Some(trust), Some(identity)) = (&self.trust, peer_identity) {
+            if let Err(e) = trust.pin(peer_name, identity) {
+                warn!("Failed to pin identity of {}: {}", peer_name, e);
+            }
+        }
+    }
+
+    /// Record the keys exchanged in a sync, if a key store is configured
+    fn remember_keys(&self, peer_name: &str, peer_keys: &[ReceivedKey]) {
+        let Some(store) = &self.keys else {
+            return;
+        };
+        let remembered = store.announce(&self.our_keys()).and_then(|_| {
+            peer_keys
+                .iter()
+                .filter(|key| key.is_installed())
+                .try_for_each(|key| store.hold(peer_name, &key.public_key))
+        });
+        if let Err(e) = remembered {
+            warn!("Failed to record the keys synced with {}: {}", peer_name, e);
+        }
+    }
+
+    /// This device's fingerprint, to announce
+    fn announced_identity(&self) -> Option<String> {
+        self.identity
+            .as_ref()
+            .map(|identity| identity.fingerprint().to_string())
+    }
+
+    /// Proof of this device's identity over the peer's `nonce`, if both are
+    /// there
+    fn prove_identity(&self, nonce: Option<&str>) -> Result<Option<IdentityProof>> {
+        match (&self.identity, nonce) {
+            (Some(identity), Some(nonce)) => identity.prove(nonce).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Answer a peer's identity challenge, failing if we have no identity
+    /// to prove
+    async fn answer_challenge(&self, writer: &mut OwnedWriteHalf, nonce: &str) -> Result<()> {
+        let proof = self.prove_identity(Some(nonce))?.ok_or_else(|| {
+            ConnectoError::Protocol("Challenged to prove an identity we have not".to_string())
+        })?;
+        let message = Message::IdentityProof { proof };
+        writer.write_all(message.to_json()?.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Our key list message for `peer`, challenging it with `nonce` and
+    /// answering its challenge `peer_nonce`
+    fn key_list(
+        &self,
+        peer: &str,
+        nonce: Option<String>,
+        peer_nonce: Option<&str>,
+    ) -> Result<Message> {
+        let (digest, held) = match &self.keys {
+            Some(store) => (
+                key_list_digest(&store.announced()?),
+                store.held(peer)?.map(|keys| key_list_digest(&keys)),
+            ),
+            None => (key_list_digest(&[]), None),
+        };
+        Ok(Message::SyncKeyList {
+            version: SYNC_PROTOCOL_VERSION,
+            device_name: self.device_name.clone(),
+            identity: self.announced_identity(),
+            nonce,
+            identity_proof: self.prove_identity(peer_nonce)?,
+            digest,
+            held,
+        })
+    }
+
+    /// Our key changes for a peer holding the list with digest `held`
+    fn key_diff(&self, held: Option<&str>) -> Result<Message> {
+        let diff = match &self.keys {
+            Some(store) => store.diff_since(held)?,
+            // Without our own lists there is nothing to change
+            None => KeyDiff {
+                base: held.map(str::to_string),
+                digest: held.map_or_else(|| key_list_digest(&[]), str::to_string),
+                ..Default::default()
+            },
+        };
+        Ok(Message::SyncKeyDiff {
+            base: diff.base,
+            added: diff.added,
+            removed: diff.removed,
+            digest: diff.digest,
+        })
+    }
+
+    /// Whether we hold keys of `peer` from an earlier sync
+    fn holds(&self, peer: &str) -> Result<bool> {
+        match &self.keys {
+            Some(store) => Ok(store.held(peer)?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// Apply a peer's key changes to authorized_keys
+    ///
+    /// Only peers we synced with before can change their keys; changes from
+    /// any other device are ignored. Keys on the deny list are not added, and
+    /// nothing is asked: the peer was approved when it first synced.
+    async fn apply_key_diff(
+        &self,
+        peer_name: &str,
+        peer_identity: Option<&VerifiedIdentity>,
+        peer_ip: &str,
+        diff: KeyDiff,
+        event_tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<()> {
+        let Some(store) = &self.keys else {
+            return Ok(());
+        };
+        let Some(held) = store.held(peer_name)? else {
+            return Ok(());
+        };
+        if self.denies_device(peer_name) {
+            return Ok(());
+        }
+        if diff.digest == key_list_digest(&held) {
+            return Ok(());
+        }
+
+        let first_key = diff.added.first().or(held.first()).cloned();
+        let decision = |decision, key: &str| DecisionRecord::new(decision, peer_name, peer_ip, key);
+        self.verify_peer(
+            peer_name,
+            peer_identity,
+            decision(Decision::Rejected, first_key.as_deref().unwrap_or_default()),
+            event_tx,
+        )
+        .await?;
+
+        let keys = diff.apply(&held)?;
+        let changes = KeyDiff::between(&held, &keys);
+        let mut added = Vec::new();
+        for key in changes.added {
+            if let Some(denied) = fingerprint(&key).ok().filter(|f| self.denies_key(f)) {
+                let reason = format!("Key {} is on the deny list", denied);
+                self.record_decision(decision(Decision::Rejected, &key).with_reason(&reason));
+                continue;
+            }
+            debug!("Adding key of {} to authorized_keys", peer_name);
+            self.key_manager.add_authorized_key(&key)?;
+            self.record_decision(decision(Decision::Accepted, &key));
+            added.push(key);
+        }
+        for key in &changes.removed {
+            // Another peer may use the same key
+            if !store.held_by_other(peer_name, key)? {
+                debug!("Removing retired key of {} from authorized_keys", peer_name);
+                self.key_manager.remove_authorized_key(key)?;
+            }
+        }
+        store.set_held(peer_name, &keys)?;
+
+        let _ = event_tx
+            .send(SyncEvent::KeysChanged {
+                device_name: peer_name.to_string(),
+                added,
+                removed: changes.removed,
+            })
+            .await;
+        Ok(())
+    }
+
+    /// Exchange key lists with a peer as the side that connected
+    ///
+    /// Returns whether either side holds keys of the other from an earlier
+    /// sync; if not, the two have yet to sync.
+    async fn reconcile_as_initiator(
+        &self,
+        address: &str,
+        peer_name: &str,
+        event_tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<bool> {
+        let stream = net::connect(address).await?;
+        let peer_addr = stream.peer_addr()?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let nonce = generate_nonce();
+        let list = self.key_list(peer_name, Some(nonce.clone()), None)?;
+        writer.write_all(list.to_json()?.as_bytes()).await?;
+
+        let (peer_name, peer_identity, peer_held) = match read_message(&mut reader).await? {
+            Message::SyncKeyList {
+                version,
+                device_name,
+                identity,
+                nonce: peer_nonce,
+                identity_proof,
+                held,
+                ..
+            } => {
+                if version != SYNC_PROTOCOL_VERSION {
+                    return Err(ConnectoError::Protocol(
+                        "Protocol version mismatch".to_string(),
+                    ));
+                }
+                let identity = proven_identity(&device_name, identity, identity_proof, &nonce)?;
+                if let Some(peer_nonce) = peer_nonce {
+                    self.answer_challenge(&mut writer, &peer_nonce).await?;
+                }
+                (device_name, identity, held)
+            }
+            Message::Error { message, .. } => return Err(ConnectoError::Sync(message)),
+            _ => return Err(ConnectoError::Protocol("Expected SyncKeyList".to_string())),
+        };
+
+        let diff = self.key_diff(peer_held.as_deref())?;
+        writer.write_all(diff.to_json()?.as_bytes()).await?;
+        let peer_diff = read_key_diff(&mut reader).await?;
+        self.apply_key_diff(
+            &peer_name,
+            peer_identity.as_ref(),
+            &peer_addr.ip().to_string(),
+            peer_diff,
+            event_tx,
+        )
+        .await?;
+
+        Ok(peer_held.is_some() || self.holds(&peer_name)?)
+    }
+
+    /// Exchange key lists with a peer that connected and sent its list
+    async fn reconcile_as_responder(
+        &self,
+        list: Message,
+        mut reader: BufReader<OwnedReadHalf>,
+        mut writer: OwnedWriteHalf,
+        peer_addr: SocketAddr,
+        event_tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<()> {
+        let Message::SyncKeyList {
+            version,
+            device_name: peer_name,
+            identity: peer_identity,
+            nonce: peer_nonce,
+            held: peer_held,
+            ..
+        } = list
+        else {
+            return Err(ConnectoError::Protocol("Expected SyncKeyList".to_string()));
+        };
+        if version != SYNC_PROTOCOL_VERSION {
+            let error_msg = Message::Error {
+                code: ProtocolErrorCode::VersionMismatch,
+                message: format!(
+                    "Protocol version mismatch: expected {}, got {}",
+                    SYNC_PROTOCOL_VERSION, version
+                ),
+            };
+            writer.write_all(error_msg.to_json()?.as_bytes()).await?;
+            return Err(ConnectoError::Protocol(
+                "Protocol version mismatch".to_string(),
+            ));
         }
+
+        // Challenge a peer that announced an identity and can prove it
+        let nonce = peer_identity
+            .as_ref()
+            .filter(|_| peer_nonce.is_some())
+            .map(|_| generate_nonce());
+        let list = self.key_list(&peer_name, nonce.clone(), peer_nonce.as_deref())?;
+        writer.write_all(list.to_json()?.as_bytes()).await?;
+        let peer_identity = match (peer_identity, nonce) {
+            (Some(announced), Some(nonce)) => {
+                Some(receive_identity_proof(&mut reader, &mut writer, &nonce, &announced).await?)
+            }
+            _ => None,
+        };
+        let peer_diff = read_key_diff(&mut reader).await?;
+        let diff = self.key_diff(peer_held.as_deref())?;
+        writer.write_all(diff.to_json()?.as_bytes()).await?;
+
+        self.apply_key_diff(
+            &peer_name,
+            peer_identity.as_ref(),
+            &peer_addr.ip().to_string(),
+            peer_diff,
+            event_tx,
+        )
+        .await
     }
 
     /// Run the sync operation
@@ -119,7 +914,7 @@ impl SyncHandler {
         // Start listening
         let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
             .await
-            .map_err(|e| ConnectoError::Network(format!("Failed to bind: {}", e)))?;
+            .map_err(|e| ports::bind_error(port, e))?;
 
         let local_addr = listener.local_addr()?;
         info!("Sync server listening on {}", local_addr);
@@ -156,7 +951,7 @@ impl SyncHandler {
         // Start browser in background
         let browser_handle = tokio::spawn(async move {
             browser
-                .find_peers(&device_name, browse_timeout, peer_found_tx)
+                .find_peers(&device_name, Some(browse_timeout), peer_found_tx)
                 .await
         });
 
@@ -164,72 +959,84 @@ impl SyncHandler {
         let timeout = tokio::time::sleep(Duration::from_secs(timeout_secs));
         tokio::pin!(timeout);
 
-        let result = loop {
-            tokio::select! {
-                // Timeout
-                _ = &mut timeout => {
-                    let _ = event_tx.send(SyncEvent::Failed {
-                        message: "Timeout waiting for sync peer".to_string(),
-                    }).await;
-                    break Err(ConnectoError::Timeout("No sync peer found".to_string()));
-                }
+        let exchange = async {
+            loop {
+                tokio::select! {
+                    // Timeout
+                    _ = &mut timeout => {
+                        let _ = event_tx.send(SyncEvent::Failed {
+                            message: "Timeout waiting for sync peer".to_string(),
+                        }).await;
+                        break Err(ConnectoError::Timeout("No sync peer found".to_string()));
+                    }
 
-                // Incoming connection
-                accept_result = listener.accept() => {
-                    match accept_result {
-                        Ok((stream, peer_addr)) => {
-                            info!("Incoming sync connection from {}", peer_addr);
+                    // Incoming connection
+                    accept_result = listener.accept() => {
+                        match accept_result {
+                            Ok((stream, peer_addr)) => {
+                                info!("Incoming sync connection from {}", peer_addr);
+
+                                // Handle as responder (we respond to their SyncHello)
+                                match self.handle_as_responder(
+                                    stream,
+                                    peer_addr,
+                                    our_priority,
+                                    &ssh_user,
+                                    event_tx.clone(),
+                                ).await {
+                                    Ok(result) => break Ok(result),
+                                    Err(e) => {
+                                        warn!("Responder sync failed: {}", e);
+                                        // Continue waiting for other connections
+                                        continue;
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Accept failed: {}", e);
+                                continue;
+                            }
+                        }
+                    }
 
-                            // Handle as responder (we respond to their SyncHello)
-                            match self.handle_as_responder(
-                                stream,
-                                peer_addr,
+                    // Found a peer via mDNS
+                    Some(peer) = peer_found_rx.recv() => {
+                        info!("Found sync peer via mDNS: {}", peer.device_name);
+                        let _ = event_tx.send(SyncEvent::PeerFound {
+                            device_name: peer.device_name.clone(),
+                            address: peer.primary_address()
+                                .map(|ip| SocketAddr::new(ip, peer.port))
+                                .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), peer.port)),
+                        }).await;
+
+                        if let Some(conn_str) = peer.connection_string() {
+                            // Try to connect as initiator
+                            match self.handle_as_initiator(
+                                &conn_str,
                                 our_priority,
                                 &ssh_user,
                                 event_tx.clone(),
                             ).await {
                                 Ok(result) => break Ok(result),
                                 Err(e) => {
-                                    warn!("Responder sync failed: {}", e);
-                                    // Continue waiting for other connections
+                                    warn!("Initiator sync failed: {}", e);
+                                    // Continue waiting - maybe they'll connect to us
                                     continue;
                                 }
                             }
                         }
-                        Err(e) => {
-                            warn!("Accept failed: {}", e);
-                            continue;
-                        }
                     }
                 }
+            }
+        };
 
-                // Found a peer via mDNS
-                Some(peer) = peer_found_rx.recv() => {
-                    info!("Found sync peer via mDNS: {}", peer.device_name);
-                    let _ = event_tx.send(SyncEvent::PeerFound {
-                        device_name: peer.device_name.clone(),
-                        address: peer.primary_address()
-                            .map(|ip| SocketAddr::new(ip, peer.port))
-                            .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), peer.port)),
-                    }).await;
-
-                    if let Some(conn_str) = peer.connection_string() {
-                        // Try to connect as initiator
-                        match self.handle_as_initiator(
-                            &conn_str,
-                            our_priority,
-                            &ssh_user,
-                            event_tx.clone(),
-                        ).await {
-                            Ok(result) => break Ok(result),
-                            Err(e) => {
-                                warn!("Initiator sync failed: {}", e);
-                                // Continue waiting - maybe they'll connect to us
-                                continue;
-                            }
-                        }
-                    }
-                }
+        let result = tokio::select! {
+            result = exchange => result,
+            _ = self.shutdown.requested() => {
+                let _ = event_tx.send(SyncEvent::Failed {
+                    message: "Sync cancelled".to_string(),
+                }).await;
+                Err(ConnectoError::Sync("Cancelled".to_string()))
             }
         };
 
@@ -240,6 +1047,203 @@ impl SyncHandler {
         result
     }
 
+    /// Keep syncing with peers until shut down
+    ///
+    /// Listens and advertises like [`SyncHandler::run`], and syncs with every
+    /// new peer it finds or that connects. Peers it synced with before
+    /// exchange key lists instead, when found and then every `interval`, so
+    /// their rotated and retired keys reach authorized_keys. Key lists are
+    /// only kept with a store set by [`SyncHandler::with_key_store`].
+    pub async fn run_daemon(
+        &self,
+        port: u16,
+        interval: Duration,
+        event_tx: mpsc::Sender<SyncEvent>,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
+            .await
+            .map_err(|e| ports::bind_error(port, e))?;
+
+        let local_addr = listener.local_addr()?;
+        info!("Sync daemon listening on {}", local_addr);
+        let _ = event_tx
+            .send(SyncEvent::Started {
+                address: local_addr,
+            })
+            .await;
+
+        // Peers we synced with get these keys in place of any older ones
+        if let Some(store) = &self.keys {
+            store.announce(&self.our_keys())?;
+        }
+
+        let mut advertiser = SyncAdvertiser::new()?;
+        advertiser.advertise(&self.device_name, local_addr.port())?;
+
+        let _ = event_tx.send(SyncEvent::Searching).await;
+        let browser = SyncBrowser::new()?;
+        let (peer_found_tx, mut peer_found_rx) = mpsc::channel::<SyncPeer>(10);
+        let device_name = self.device_name.clone();
+        let browser_handle =
+            tokio::spawn(
+                async move { browser.find_peers(&device_name, None, peer_found_tx).await },
+            );
+
+        let our_priority: u64 = rand::thread_rng().gen();
+        let ssh_user = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".to_string());
+        let exchange_timeout = self.exchange_timeout();
+
+        // Peers that connect are served while we connect to others, so two
+        // daemons finding each other at once don't wait on each other
+        let incoming = async {
+            loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Accept failed: {}", e);
+                        continue;
+                    }
+                };
+                info!("Incoming sync connection from {}", peer_addr);
+                let handled = tokio::time::timeout(
+                    exchange_timeout,
+                    self.handle_incoming(stream, peer_addr, our_priority, &ssh_user, &event_tx),
+                )
+                .await;
+                match handled {
+                    Ok(Ok(Some(result))) => {
+                        let _ = event_tx.send(SyncEvent::PeerSynced { result }).await;
+                    }
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => warn!("Sync with {} failed: {}", peer_addr, e),
+                    Err(_) => warn!("Sync with {} timed out", peer_addr),
+                }
+            }
+        };
+
+        let outgoing = async {
+            let mut peers: HashMap<String, SyncPeer> = HashMap::new();
+            let mut ticker =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                tokio::select! {
+                    Some(peer) = peer_found_rx.recv() => {
+                        info!("Found sync peer via mDNS: {}", peer.device_name);
+                        if let Some(ip) = peer.primary_address() {
+                            let _ = event_tx.send(SyncEvent::PeerFound {
+                                device_name: peer.device_name.clone(),
+                                address: SocketAddr::new(ip, peer.port),
+                            }).await;
+                        }
+                        self.sync_with(&peer, our_priority, &ssh_user, &event_tx).await;
+                        peers.insert(peer.device_name.clone(), peer);
+                    }
+                    _ = ticker.tick() => {
+                        for peer in peers.values() {
+                            self.sync_with(peer, our_priority, &ssh_user, &event_tx).await;
+                        }
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = async { tokio::join!(incoming, outgoing) } => {}
+            _ = self.shutdown.requested() => {}
+        }
+
+        browser_handle.abort();
+        advertiser.stop()
+    }
+
+    /// Serve a peer that connected to a daemon
+    ///
+    /// Returns the result of a full sync, or `None` after a key list
+    /// exchange.
+    async fn handle_incoming(
+        &self,
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        our_priority: u64,
+        ssh_user: &str,
+        event_tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<Option<SyncResult>> {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let message = read_message(&mut reader).await?;
+        if matches!(message, Message::SyncKeyList { .. }) {
+            self.reconcile_as_responder(message, reader, writer, peer_addr, event_tx)
+                .await?;
+            return Ok(None);
+        }
+        self.respond(
+            message,
+            reader,
+            writer,
+            peer_addr,
+            our_priority,
+            ssh_user,
+            event_tx.clone(),
+        )
+        .await
+        .map(Some)
+    }
+
+    /// How long a daemon waits for one exchange with a peer, including the
+    /// time the user has to answer an approval request
+    fn exchange_timeout(&self) -> Duration {
+        let timeout = Duration::from_secs(EXCHANGE_TIMEOUT_SECS);
+        match self.approval_tx {
+            Some(_) => timeout + self.approval_timeout,
+            None => timeout,
+        }
+    }
+
+    /// Exchange key lists with `peer`, or sync with it if the two never did
+    async fn sync_with(
+        &self,
+        peer: &SyncPeer,
+        our_priority: u64,
+        ssh_user: &str,
+        event_tx: &mpsc::Sender<SyncEvent>,
+    ) {
+        let Some(address) = peer.connection_string() else {
+            return;
+        };
+        let timeout = self.exchange_timeout();
+
+        let reconciled = tokio::time::timeout(
+            timeout,
+            self.reconcile_as_initiator(&address, peer.name(), event_tx),
+        )
+        .await;
+        match reconciled {
+            Ok(Ok(true)) => return,
+            Ok(Ok(false)) => {}
+            // Peers that only sync once don't know key lists
+            Ok(Err(e)) => debug!("Key list exchange with {} failed: {}", address, e),
+            Err(_) => {
+                warn!("Key list exchange with {} timed out", address);
+                return;
+            }
+        }
+
+        let synced = tokio::time::timeout(
+            timeout,
+            self.handle_as_initiator(&address, our_priority, ssh_user, event_tx.clone()),
+        )
+        .await;
+        match synced {
+            Ok(Ok(result)) => {
+                let _ = event_tx.send(SyncEvent::PeerSynced { result }).await;
+            }
+            Ok(Err(e)) => warn!("Sync with {} failed: {}", address, e),
+            Err(_) => warn!("Sync with {} timed out", address),
+        }
+    }
+
     /// Handle sync as the initiator (we send SyncHello first)
     async fn handle_as_initiator(
         &self,
@@ -248,30 +1252,35 @@ impl SyncHandler {
         ssh_user: &str,
         event_tx: mpsc::Sender<SyncEvent>,
     ) -> Result<SyncResult> {
-        let stream = TcpStream::connect(address)
-            .await
-            .map_err(|e| ConnectoError::Network(format!("Failed to connect: {}", e)))?;
+        let stream = net::connect(address).await?;
 
         let peer_addr = stream.peer_addr()?;
         let (reader, mut writer) = stream.into_split();
         let mut reader = BufReader::new(reader);
-        let mut line = String::new();
 
-        // Send SyncHello
+        // Send SyncHello, challenging the peer to prove its identity
+        let sent_at = clock::unix_now();
+        let nonce = generate_nonce();
         let sync_hello = Message::SyncHello {
-            version: PROTOCOL_VERSION,
+            version: SYNC_PROTOCOL_VERSION,
             device_name: self.device_name.clone(),
             initiator_priority: our_priority,
             public_key: self.key_pair.public_key.clone(),
             key_comment: self.key_pair.comment.clone(),
             ssh_user: ssh_user.to_string(),
+            keys: self.shared_keys.clone(),
+            identity: self.announced_identity(),
+            timestamp: Some(sent_at),
+            nonce: Some(nonce.clone()),
         };
         writer.write_all(sync_hello.to_json()?.as_bytes()).await?;
 
-        // Read SyncHelloAck
-        line.clear();
-        reader.read_line(&mut line).await?;
-        let response = Message::from_json(&line)?;
+        // Prove our identity if challenged, then read SyncHelloAck
+        let mut response = read_message(&mut reader).await?;
+        if let Message::KeyChallenge { nonce } = &response {
+            self.answer_challenge(&mut writer, nonce).await?;
+            response = read_message(&mut reader).await?;
+        }
 
         match response {
             Message::SyncHelloAck {
@@ -281,12 +1290,20 @@ impl SyncHandler {
                 key_comment: peer_comment,
                 ssh_user: peer_user,
                 accept_sync,
+                keys: peer_keys,
+                identity: peer_identity,
+                timestamp,
+                identity_proof,
             } => {
-                if version != PROTOCOL_VERSION {
+                if version != SYNC_PROTOCOL_VERSION {
                     return Err(ConnectoError::Protocol(
                         "Protocol version mismatch".to_string(),
                     ));
                 }
+                let peer_identity =
+                    proven_identity(&peer_name, peer_identity, identity_proof, &nonce)?;
+                let clock_skew = timestamp.map(|t| clock::skew(t, sent_at, clock::unix_now()));
+                clock::warn_if_large(&peer_name, clock_skew);
 
                 if !accept_sync {
                     return Err(ConnectoError::SyncRejected(
@@ -294,22 +1311,50 @@ impl SyncHandler {
                     ));
                 }
 
+                let peer_ip = peer_addr.ip().to_string();
+                let decision =
+                    |decision| DecisionRecord::new(decision, &peer_name, &peer_ip, &peer_key);
+                if let Err(e) = self
+                    .verify_peer(
+                        &peer_name,
+                        peer_identity.as_ref(),
+                        decision(Decision::Rejected),
+                        &event_tx,
+                    )
+                    .await
+                {
+                    let complete = Message::SyncComplete {
+                        success: false,
+                        message: "Identity does not match the pinned identity".to_string(),
+                    };
+                    writer.write_all(complete.to_json()?.as_bytes()).await?;
+                    return Err(e);
+                }
+
                 let _ = event_tx
                     .send(SyncEvent::Connected {
                         device_name: peer_name.clone(),
                     })
                     .await;
 
-                // Add peer's key to our authorized_keys
-                debug!("Adding peer key to authorized_keys: {}", peer_comment);
-                self.key_manager.add_authorized_key(&peer_key)?;
-
-                let _ = event_tx
-                    .send(SyncEvent::KeyReceived {
-                        device_name: peer_name.clone(),
-                        key_comment: peer_comment,
-                    })
+                // Add peer's keys to our authorized_keys
+                let installed = self
+                    .install_keys(
+                        &peer_name,
+                        peer_identity.as_ref(),
+                        peer_addr,
+                        (&peer_key, peer_comment),
+                        &peer_keys,
+                        &event_tx,
+                    )
                     .await;
+                let received_keys = match installed {
+                    Err(ConnectoError::SyncRejected(reason)) => {
+                        self.send_rejection(&mut writer, &reason).await?;
+                        return Err(ConnectoError::SyncRejected(reason));
+                    }
+                    installed => installed?,
+                };
 
                 // Send SyncComplete
                 let complete = Message::SyncComplete {
@@ -319,9 +1364,7 @@ impl SyncHandler {
                 writer.write_all(complete.to_json()?.as_bytes()).await?;
 
                 // Read SyncComplete from peer
-                line.clear();
-                reader.read_line(&mut line).await?;
-                let peer_complete = Message::from_json(&line)?;
+                let peer_complete = read_message(&mut reader).await?;
 
                 match peer_complete {
                     Message::SyncComplete { success, message } => {
@@ -348,13 +1391,27 @@ impl SyncHandler {
                     })
                     .await;
 
+                self.pin_peer(&peer_name, peer_identity.as_ref());
+                self.remember_keys(&peer_name, &received_keys);
+                self.record_completed(&peer_name, peer_ip, &received_keys);
+
                 Ok(SyncResult {
                     peer_name,
                     peer_user,
                     peer_address: peer_ip,
                     peer_port,
+                    peer_identity,
+                    clock_skew,
+                    received_keys,
                 })
             }
+            Message::SyncRejected {
+                device_name,
+                message,
+            } => Err(ConnectoError::SyncRejected(format!(
+                "{} refused our keys: {}",
+                device_name, message
+            ))),
             Message::Error { message, .. } => Err(ConnectoError::Sync(message)),
             _ => Err(ConnectoError::Protocol("Unexpected response".to_string())),
         }
@@ -369,15 +1426,35 @@ impl SyncHandler {
         ssh_user: &str,
         event_tx: mpsc::Sender<SyncEvent>,
     ) -> Result<SyncResult> {
-        let (reader, mut writer) = stream.into_split();
+        let (reader, writer) = stream.into_split();
         let mut reader = BufReader::new(reader);
-        let mut line = String::new();
 
         // Read SyncHello
-        line.clear();
-        reader.read_line(&mut line).await?;
-        let hello = Message::from_json(&line)?;
+        let hello = read_message(&mut reader).await?;
+        self.respond(
+            hello,
+            reader,
+            writer,
+            peer_addr,
+            our_priority,
+            ssh_user,
+            event_tx,
+        )
+        .await
+    }
 
+    /// Answer a peer's first message as the responder
+    #[allow(clippy::too_many_arguments)]
+    async fn respond(
+        &self,
+        hello: Message,
+        mut reader: BufReader<OwnedReadHalf>,
+        mut writer: OwnedWriteHalf,
+        peer_addr: SocketAddr,
+        our_priority: u64,
+        ssh_user: &str,
+        event_tx: mpsc::Sender<SyncEvent>,
+    ) -> Result<SyncResult> {
         match hello {
             Message::SyncHello {
                 version,
@@ -386,13 +1463,17 @@ impl SyncHandler {
                 public_key: peer_key,
                 key_comment: peer_comment,
                 ssh_user: peer_user,
+                keys: peer_keys,
+                identity: peer_identity,
+                timestamp,
+                nonce: peer_nonce,
             } => {
-                if version != PROTOCOL_VERSION {
+                if version != SYNC_PROTOCOL_VERSION {
                     let error_msg = Message::Error {
-                        code: 1,
+                        code: ProtocolErrorCode::VersionMismatch,
                         message: format!(
                             "Protocol version mismatch: expected {}, got {}",
-                            PROTOCOL_VERSION, version
+                            SYNC_PROTOCOL_VERSION, version
                         ),
                     };
                     writer.write_all(error_msg.to_json()?.as_bytes()).await?;
@@ -400,21 +1481,72 @@ impl SyncHandler {
                         "Protocol version mismatch".to_string(),
                     ));
                 }
+                let now = clock::unix_now();
+                let clock_skew = timestamp.map(|t| clock::skew(t, now, now));
+                clock::warn_if_large(&peer_name, clock_skew);
 
                 // Check if this is ourselves (same device trying to sync with itself)
                 if peer_name == self.device_name && peer_priority == our_priority {
                     let error_msg = Message::SyncHelloAck {
-                        version: PROTOCOL_VERSION,
+                        version: SYNC_PROTOCOL_VERSION,
                         device_name: self.device_name.clone(),
                         public_key: String::new(),
                         key_comment: String::new(),
                         ssh_user: String::new(),
                         accept_sync: false,
+                        keys: Vec::new(),
+                        identity: None,
+                        timestamp: None,
+                        identity_proof: None,
                     };
                     writer.write_all(error_msg.to_json()?.as_bytes()).await?;
                     return Err(ConnectoError::SyncWithSelf);
                 }
 
+                // Only an identity the peer proves counts; peers too old to
+                // take a challenge send no nonce
+                let peer_identity = match (peer_identity, &peer_nonce) {
+                    (Some(announced), Some(_)) => {
+                        let nonce = generate_nonce();
+                        let challenge = Message::KeyChallenge {
+                            nonce: nonce.clone(),
+                        };
+                        writer.write_all(challenge.to_json()?.as_bytes()).await?;
+                        Some(
+                            receive_identity_proof(&mut reader, &mut writer, &nonce, &announced)
+                                .await?,
+                        )
+                    }
+                    (Some(announced), None) => {
+                        debug!(
+                            "{} announced identity {} but cannot prove it",
+                            peer_name, announced
+                        );
+                        None
+                    }
+                    (None, _) => None,
+                };
+
+                let peer_ip = peer_addr.ip().to_string();
+                let decision =
+                    |decision| DecisionRecord::new(decision, &peer_name, &peer_ip, &peer_key);
+                if let Err(e) = self
+                    .verify_peer(
+                        &peer_name,
+                        peer_identity.as_ref(),
+                        decision(Decision::Rejected),
+                        &event_tx,
+                    )
+                    .await
+                {
+                    let error_msg = Message::Error {
+                        code: ProtocolErrorCode::VerificationFailed,
+                        message: "Identity does not match the pinned identity".to_string(),
+                    };
+                    writer.write_all(error_msg.to_json()?.as_bytes()).await?;
+                    return Err(e);
+                }
+
                 // Priority tie-breaker: higher priority wins (becomes the effective initiator)
                 // If we have lower priority, we yield and let them be the initiator
                 // This message exchange is just for the sync, so we always accept
@@ -424,36 +1556,58 @@ impl SyncHandler {
                     })
                     .await;
 
-                // Add peer's key to our authorized_keys
-                debug!("Adding peer key to authorized_keys: {}", peer_comment);
-                self.key_manager.add_authorized_key(&peer_key)?;
-
-                let _ = event_tx
-                    .send(SyncEvent::KeyReceived {
-                        device_name: peer_name.clone(),
-                        key_comment: peer_comment,
-                    })
+                // Add peer's keys to our authorized_keys
+                let installed = self
+                    .install_keys(
+                        &peer_name,
+                        peer_identity.as_ref(),
+                        peer_addr,
+                        (&peer_key, peer_comment),
+                        &peer_keys,
+                        &event_tx,
+                    )
                     .await;
+                let received_keys = match installed {
+                    Err(ConnectoError::SyncRejected(reason)) => {
+                        self.send_rejection(&mut writer, &reason).await?;
+                        return Err(ConnectoError::SyncRejected(reason));
+                    }
+                    installed => installed?,
+                };
 
-                // Send SyncHelloAck with our key
+                // Send SyncHelloAck with our keys
                 let ack = Message::SyncHelloAck {
-                    version: PROTOCOL_VERSION,
+                    version: SYNC_PROTOCOL_VERSION,
                     device_name: self.device_name.clone(),
                     public_key: self.key_pair.public_key.clone(),
                     key_comment: self.key_pair.comment.clone(),
                     ssh_user: ssh_user.to_string(),
                     accept_sync: true,
+                    keys: self.shared_keys.clone(),
+                    identity: self.announced_identity(),
+                    timestamp: Some(clock::unix_now()),
+                    identity_proof: self.prove_identity(peer_nonce.as_deref())?,
                 };
                 writer.write_all(ack.to_json()?.as_bytes()).await?;
 
                 // Read SyncComplete from peer
-                line.clear();
-                reader.read_line(&mut line).await?;
-                let peer_complete = Message::from_json(&line)?;
+                let peer_complete = read_message(&mut reader).await?;
 
                 match peer_complete {
+                    // The peer doesn't have our keys, so it doesn't get in either
+                    Message::SyncRejected {
+                        device_name,
+                        message,
+                    } => {
+                        self.withdraw_keys(&received_keys);
+                        return Err(ConnectoError::SyncRejected(format!(
+                            "{} refused our keys: {}",
+                            device_name, message
+                        )));
+                    }
                     Message::SyncComplete { success, message } => {
                         if !success {
+                            self.withdraw_keys(&received_keys);
                             return Err(ConnectoError::Sync(format!(
                                 "Peer reported failure: {}",
                                 message
@@ -483,16 +1637,23 @@ impl SyncHandler {
                     })
                     .await;
 
+                self.pin_peer(&peer_name, peer_identity.as_ref());
+                self.remember_keys(&peer_name, &received_keys);
+                self.record_completed(&peer_name, peer_ip, &received_keys);
+
                 Ok(SyncResult {
                     peer_name,
                     peer_user,
                     peer_address: peer_ip,
                     peer_port,
+                    peer_identity,
+                    clock_skew,
+                    received_keys,
                 })
             }
             _ => {
                 let error_msg = Message::Error {
-                    code: 2,
+                    code: ProtocolErrorCode::UnexpectedMessage,
                     message: "Expected SyncHello message".to_string(),
                 };
                 writer.write_all(error_msg.to_json()?.as_bytes()).await?;
@@ -580,10 +1741,12 @@ impl SyncBrowser {
         Ok(Self { daemon })
     }
 
+    /// Send the peers found to `peer_tx` until `timeout`, or until the
+    /// receiver is dropped when there is none
     async fn find_peers(
         &self,
         our_device_name: &str,
-        timeout: Duration,
+        timeout: Option<Duration>,
         peer_tx: mpsc::Sender<SyncPeer>,
     ) -> Result<()> {
         let receiver = self
@@ -595,9 +1758,9 @@ impl SyncBrowser {
 
         // Run browser in blocking thread
         let handle = std::thread::spawn(move || {
-            let deadline = std::time::Instant::now() + timeout;
+            let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
 
-            while std::time::Instant::now() < deadline {
+            while deadline.is_none_or(|deadline| std::time::Instant::now() < deadline) {
                 match receiver.recv_timeout(Duration::from_millis(100)) {
                     Ok(ServiceEvent::ServiceResolved(info)) => {
                         let device_name = info.get_fullname().to_string();
@@ -625,6 +1788,10 @@ impl SyncBrowser {
                     Ok(ServiceEvent::SearchStopped(_)) => {
                         break;
                     }
+                    // The sync finished or was cancelled
+                    Err(flume::RecvTimeoutError::Timeout) if peer_tx.is_closed() => {
+                        break;
+                    }
                     Err(flume::RecvTimeoutError::Timeout) => {
                         continue;
                     }
@@ -650,8 +1817,11 @@ impl SyncBrowser {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audit::AuditLog;
     use crate::keys::KeyAlgorithm;
+    use std::path::Path;
     use tempfile::TempDir;
+    use tokio::task::JoinHandle;
 
     #[test]
     fn test_sync_service_type() {
@@ -667,6 +1837,9 @@ mod tests {
             peer_user: "bob".to_string(),
             peer_address: "192.168.1.100".parse().unwrap(),
             peer_port: 8099,
+            peer_identity: None,
+            clock_skew: None,
+            received_keys: Vec::new(),
         };
 
         assert_eq!(result.peer_name, "Device B");
@@ -708,6 +1881,207 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sync_peer_name() {
+        let mut peer = SyncPeer {
+            device_name: "My Desk (desk-pc)._connecto-sync._tcp.local.".to_string(),
+            addresses: vec![],
+            port: 8099,
+            instance_name: "test".to_string(),
+        };
+        assert_eq!(peer.name(), "My Desk");
+        peer.device_name = "Desk (Work) (desk-pc)._connecto-sync._tcp.local.".to_string();
+        assert_eq!(peer.name(), "Desk (Work)");
+        peer.device_name = "Desk".to_string();
+        assert_eq!(peer.name(), "Desk");
+    }
+
+    /// A handler keeping its identity, authorized_keys, pins and key lists
+    /// in `dir`
+    fn daemon_handler(dir: &Path, name: &str, key_pair: SshKeyPair) -> SyncHandler {
+        let identity = DeviceIdentity::load_or_create_at(&dir.join("identity")).unwrap();
+        SyncHandler::new(KeyManager::with_dir(dir.join(".ssh")), name, key_pair)
+            .with_identity(&identity)
+            .with_trust_store(TrustStore::with_path(dir.join("known_peers.json")))
+            .with_key_store(SyncKeyStore::with_path(dir.join("sync_keys.json")))
+    }
+
+    /// Serve one connection with `handler` as a daemon would
+    async fn serve_once(
+        handler: SyncHandler,
+        event_tx: mpsc::Sender<SyncEvent>,
+    ) -> (
+        String,
+        JoinHandle<(SyncHandler, Result<Option<SyncResult>>)>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            let result = handler
+                .handle_incoming(stream, peer_addr, 1, "bob", &event_tx)
+                .await;
+            (handler, result)
+        });
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn test_daemon_key_lists() {
+        let dir_a = TempDir::new().unwrap();
+        let dir_b = TempDir::new().unwrap();
+        let key_a = SshKeyPair::generate(KeyAlgorithm::Ed25519, "alice@old").unwrap();
+        let key_b = SshKeyPair::generate(KeyAlgorithm::Ed25519, "bob@b").unwrap();
+        let handler_a = daemon_handler(dir_a.path(), "Device A", key_a);
+        let mut handler_b = daemon_handler(dir_b.path(), "Device B", key_b);
+        let (event_tx, mut event_rx) = mpsc::channel(100);
+        let authorized_b = || {
+            KeyManager::with_dir(dir_b.path().join(".ssh"))
+                .list_authorized_keys()
+                .unwrap()
+        };
+
+        // Devices that never synced have no lists to exchange
+        let (address, served) = serve_once(handler_b, event_tx.clone()).await;
+        let known = handler_a
+            .reconcile_as_initiator(&address, "Device B", &event_tx)
+            .await
+            .unwrap();
+        assert!(!known);
+        let (handler, result) = served.await.unwrap();
+        assert!(result.unwrap().is_none());
+        handler_b = handler;
+
+        // A daemon serves full syncs too
+        let (address, served) = serve_once(handler_b, event_tx.clone()).await;
+        handler_a
+            .handle_as_initiator(&address, 2, "alice", event_tx.clone())
+            .await
+            .unwrap();
+        let (handler, result) = served.await.unwrap();
+        assert_eq!(result.unwrap().unwrap().peer_name, "Device A");
+        handler_b = handler;
+        assert_eq!(authorized_b().len(), 1);
+
+        // Nothing changed since
+        let (address, served) = serve_once(handler_b, event_tx.clone()).await;
+        assert!(handler_a
+            .reconcile_as_initiator(&address, "Device B", &event_tx)
+            .await
+            .unwrap());
+        handler_b = served.await.unwrap().0;
+        while let Ok(event) = event_rx.try_recv() {
+            assert!(!matches!(event, SyncEvent::KeysChanged { .. }));
+        }
+
+        // A rotated key replaces the old one
+        let new_key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "alice@new").unwrap();
+        SyncKeyStore::with_path(dir_a.path().join("sync_keys.json"))
+            .announce(std::slice::from_ref(&new_key.public_key))
+            .unwrap();
+        let handler_a = daemon_handler(dir_a.path(), "Device A", new_key);
+        let (address, served) = serve_once(handler_b, event_tx.clone()).await;
+        assert!(handler_a
+            .reconcile_as_initiator(&address, "Device B", &event_tx)
+            .await
+            .unwrap());
+        handler_b = served.await.unwrap().0;
+        let keys = authorized_b();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].contains("alice@new"));
+        match event_rx.try_recv().unwrap() {
+            SyncEvent::KeysChanged {
+                device_name,
+                added,
+                removed,
+            } => {
+                assert_eq!(device_name, "Device A");
+                assert_eq!(added.len(), 1);
+                assert!(removed[0].contains("alice@old"));
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // Devices that never synced, or claim another's name, change nothing
+        let dir_c = TempDir::new().unwrap();
+        let key_c = SshKeyPair::generate(KeyAlgorithm::Ed25519, "eve@c").unwrap();
+        SyncKeyStore::with_path(dir_c.path().join("sync_keys.json"))
+            .announce(std::slice::from_ref(&key_c.public_key))
+            .unwrap();
+        let handler_c = daemon_handler(dir_c.path(), "Device C", key_c.clone());
+        let (address, served) = serve_once(handler_b, event_tx.clone()).await;
+        assert!(!handler_c
+            .reconcile_as_initiator(&address, "Device B", &event_tx)
+            .await
+            .unwrap());
+        handler_b = served.await.unwrap().0;
+
+        let impostor = daemon_handler(dir_c.path(), "Device A", key_c);
+        let (address, served) = serve_once(handler_b, event_tx.clone()).await;
+        let _ = impostor
+            .reconcile_as_initiator(&address, "Device B", &event_tx)
+            .await;
+        assert!(served.await.unwrap().1.is_err());
+        let keys = authorized_b();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].contains("alice@new"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_needs_proof_of_identity() {
+        let dir_a = TempDir::new().unwrap();
+        let dir_b = TempDir::new().unwrap();
+        let key_a = SshKeyPair::generate(KeyAlgorithm::Ed25519, "alice@a").unwrap();
+        let key_b = SshKeyPair::generate(KeyAlgorithm::Ed25519, "bob@b").unwrap();
+        let identity_a = DeviceIdentity::load_or_create_at(&dir_a.path().join("identity")).unwrap();
+        let handler_b = daemon_handler(dir_b.path(), "Device B", key_b);
+        let (event_tx, mut event_rx) = mpsc::channel(100);
+        tokio::spawn(async move { while event_rx.recv().await.is_some() {} });
+        let (address, served) = serve_once(handler_b, event_tx).await;
+
+        // Someone announcing Device A's identity without its key
+        let stream = TcpStream::connect(&address).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let hello = Message::SyncHello {
+            version: SYNC_PROTOCOL_VERSION,
+            device_name: "Device A".to_string(),
+            initiator_priority: 2,
+            public_key: key_a.public_key.clone(),
+            key_comment: key_a.comment.clone(),
+            ssh_user: "alice".to_string(),
+            keys: Vec::new(),
+            identity: Some(identity_a.fingerprint().to_string()),
+            timestamp: None,
+            nonce: Some(generate_nonce()),
+        };
+        writer
+            .write_all(hello.to_json().unwrap().as_bytes())
+            .await
+            .unwrap();
+        let Message::KeyChallenge { nonce } = read_message(&mut reader).await.unwrap() else {
+            panic!("Expected KeyChallenge");
+        };
+        let forger = DeviceIdentity::load_or_create_at(&dir_b.path().join("forger")).unwrap();
+        let proof = Message::IdentityProof {
+            proof: forger.prove(&nonce).unwrap(),
+        };
+        writer
+            .write_all(proof.to_json().unwrap().as_bytes())
+            .await
+            .unwrap();
+
+        match read_message(&mut reader).await.unwrap() {
+            Message::Error { code, .. } => assert_eq!(code, ProtocolErrorCode::VerificationFailed),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert!(served.await.unwrap().1.is_err());
+        assert!(KeyManager::with_dir(dir_b.path().join(".ssh"))
+            .list_authorized_keys()
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn test_sync_handler_creation() {
         let temp_dir = TempDir::new().unwrap();
@@ -719,6 +2093,31 @@ mod tests {
         assert_eq!(handler.device_name, "Test Device");
     }
 
+    #[tokio::test]
+    async fn test_sync_handler_shutdown() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_manager = KeyManager::with_dir(temp_dir.path().join(".ssh"));
+        let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@sync").unwrap();
+        let handler = SyncHandler::new(key_manager, "Test Device", key_pair);
+        let shutdown = handler.shutdown_handle();
+
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+        let sync = tokio::spawn(async move { handler.run(0, 60, event_tx).await });
+        // Cancel once the sync is waiting for peers
+        while let Some(event) = event_rx.recv().await {
+            if matches!(event, SyncEvent::Searching) {
+                break;
+            }
+        }
+        shutdown.shutdown();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), sync)
+            .await
+            .expect("sync stops")
+            .unwrap();
+        assert!(matches!(result, Err(ConnectoError::Sync(_))));
+    }
+
     #[test]
     fn test_sync_event_variants() {
         let addr: SocketAddr = "127.0.0.1:8099".parse().unwrap();
@@ -745,10 +2144,15 @@ mod tests {
             SyncEvent::Failed {
                 message: "Error".to_string(),
             },
+            SyncEvent::KeysChanged {
+                device_name: "Peer".to_string(),
+                added: vec![],
+                removed: vec![],
+            },
         ];
 
         // Just verify all variants can be created
-        assert_eq!(events.len(), 8);
+        assert_eq!(events.len(), 9);
     }
 
     #[tokio::test]
@@ -760,7 +2164,8 @@ mod tests {
         let ssh_dir_a = temp_dir_a.path().join(".ssh");
         let ssh_dir_b = temp_dir_b.path().join(".ssh");
 
-        let key_manager_a = KeyManager::with_dir(ssh_dir_a.clone());
+        let audit_a = AuditLog::with_path(temp_dir_a.path().join("audit.jsonl"));
+        let key_manager_a = KeyManager::with_dir(ssh_dir_a.clone()).with_audit_log(audit_a.clone());
         let key_manager_b = KeyManager::with_dir(ssh_dir_b.clone());
 
         let key_pair_a = SshKeyPair::generate(KeyAlgorithm::Ed25519, "alice@device-a").unwrap();
@@ -810,6 +2215,9 @@ mod tests {
         assert_eq!(result_a.peer_user, "bob");
         assert_eq!(result_b.peer_name, "Device A");
         assert_eq!(result_b.peer_user, "alice");
+        // Both clocks are this machine's
+        assert!(result_a.clock_skew.is_some_and(|skew| skew.abs() <= 1));
+        assert!(result_b.clock_skew.is_some_and(|skew| skew.abs() <= 1));
 
         // Verify keys were exchanged
         let key_manager_a = KeyManager::with_dir(ssh_dir_a);
@@ -821,6 +2229,17 @@ mod tests {
         // A should have B's key
         assert_eq!(keys_a.len(), 1);
         assert!(keys_a[0].contains("bob@device-b"));
+        let events: Vec<_> = audit_a
+            .all()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.event)
+            .collect();
+        assert_eq!(events[0].name(), "key_installed");
+        assert_eq!(
+            events[1],
+            AuditEvent::sync_completed("Device B", addr.ip(), 1)
+        );
 
         // B should have A's key
         assert_eq!(keys_b.len(), 1);
@@ -839,4 +2258,198 @@ mod tests {
         }
         assert!(!events_b.is_empty());
     }
+
+    #[tokio::test]
+    async fn test_sync_shares_several_keys() {
+        let temp_dir_a = TempDir::new().unwrap();
+        let temp_dir_b = TempDir::new().unwrap();
+        let key_manager_a = KeyManager::with_dir(temp_dir_a.path().join(".ssh"));
+        let key_manager_b = KeyManager::with_dir(temp_dir_b.path().join(".ssh"));
+
+        let key_pair_a = SshKeyPair::generate(KeyAlgorithm::Ed25519, "alice@device-a").unwrap();
+        let key_pair_b = SshKeyPair::generate(KeyAlgorithm::Ed25519, "bob@device-b").unwrap();
+        let deploy_a = SshKeyPair::generate(KeyAlgorithm::Ed25519, "deploy@device-a").unwrap();
+        let backup_b = SshKeyPair::generate(KeyAlgorithm::Ed25519, "backup@device-b").unwrap();
+        // A already trusts B's backup key
+        key_manager_a
+            .add_authorized_key(&backup_b.public_key)
+            .unwrap();
+
+        // The main key and repeats are only sent once
+        let handler_a = SyncHandler::new(key_manager_a, "Device A", key_pair_a.clone())
+            .with_shared_keys(vec![
+                deploy_a.public_key.clone(),
+                key_pair_a.public_key.clone(),
+                "not-a-key".to_string(),
+                deploy_a.public_key.clone(),
+            ]);
+        let handler_b = SyncHandler::new(key_manager_b, "Device B", key_pair_b.clone())
+            .with_shared_keys(vec![backup_b.public_key.clone()]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (event_tx_a, _event_rx_a) = mpsc::channel(10);
+        let (event_tx_b, mut event_rx_b) = mpsc::channel(10);
+        let b_handle = tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            handler_b
+                .handle_as_responder(stream, peer_addr, 1, "bob", event_tx_b)
+                .await
+        });
+
+        let result_a = handler_a
+            .handle_as_initiator(&addr, 2, "alice", event_tx_a)
+            .await
+            .unwrap();
+        let result_b = b_handle.await.unwrap().unwrap();
+
+        // B installs both of A's good keys and reports the bad one
+        let outcomes: Vec<(&str, &KeyOutcome)> = result_b
+            .received_keys
+            .iter()
+            .map(|key| (key.comment.as_str(), &key.outcome))
+            .collect();
+        assert_eq!(outcomes.len(), 3);
+        assert_eq!(outcomes[0], ("alice@device-a", &KeyOutcome::Added));
+        assert_eq!(outcomes[1], ("deploy@device-a", &KeyOutcome::Added));
+        assert!(matches!(outcomes[2].1, KeyOutcome::Failed(_)));
+        assert!(!result_b.received_keys[2].is_installed());
+        let keys_b = KeyManager::with_dir(temp_dir_b.path().join(".ssh"))
+            .list_authorized_keys()
+            .unwrap();
+        assert_eq!(keys_b.len(), 2);
+
+        // One KeyReceived event per installed key
+        let mut received = 0;
+        while let Ok(event) = event_rx_b.try_recv() {
+            if matches!(event, SyncEvent::KeyReceived { .. }) {
+                received += 1;
+            }
+        }
+        assert_eq!(received, 2);
+
+        let outcomes: Vec<&KeyOutcome> = result_a
+            .received_keys
+            .iter()
+            .map(|key| &key.outcome)
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![&KeyOutcome::Added, &KeyOutcome::AlreadyAuthorized]
+        );
+        let keys_a = KeyManager::with_dir(temp_dir_a.path().join(".ssh"))
+            .list_authorized_keys()
+            .unwrap();
+        assert_eq!(keys_a.len(), 2);
+    }
+
+    /// Sync `a` (initiator) with `b` (responder) over localhost
+    async fn sync_pair(a: SyncHandler, b: SyncHandler) -> (Result<SyncResult>, Result<SyncResult>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (event_tx_a, _event_rx_a) = mpsc::channel(10);
+        let (event_tx_b, _event_rx_b) = mpsc::channel(10);
+        let b_handle = tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            b.handle_as_responder(stream, peer_addr, 1, "bob", event_tx_b)
+                .await
+        });
+        let result_a = a.handle_as_initiator(&addr, 2, "alice", event_tx_a).await;
+        (result_a, b_handle.await.unwrap())
+    }
+
+    /// Answer every approval request with `approved`
+    fn approver(approved: bool) -> mpsc::Sender<ApprovalRequest> {
+        let (approval_tx, mut approval_rx) = mpsc::channel::<ApprovalRequest>(1);
+        tokio::spawn(async move {
+            while let Some(request) = approval_rx.recv().await {
+                assert!(request.fingerprint.starts_with("SHA256:"));
+                request.respond(approved);
+            }
+        });
+        approval_tx
+    }
+
+    #[tokio::test]
+    async fn test_sync_accept_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_a = temp_dir.path().join("a");
+        let dir_b = temp_dir.path().join("b");
+        let key_pair_a = SshKeyPair::generate(KeyAlgorithm::Ed25519, "alice@device-a").unwrap();
+        let key_pair_b = SshKeyPair::generate(KeyAlgorithm::Ed25519, "bob@device-b").unwrap();
+        let extra_a = SshKeyPair::generate(KeyAlgorithm::Ed25519, "extra@device-a").unwrap();
+        let handler = |dir: &Path, name: &str, key_pair: &SshKeyPair| {
+            SyncHandler::new(
+                KeyManager::with_dir(dir.join(".ssh")),
+                name,
+                key_pair.clone(),
+            )
+            .with_decision_log(DecisionLog::with_path(dir.join("decisions.jsonl")))
+        };
+        let authorized = |dir: &Path| {
+            KeyManager::with_dir(dir.join(".ssh"))
+                .list_authorized_keys()
+                .unwrap()
+        };
+
+        // B's user declines: B sends nothing of its own and installs nothing
+        let (result_a, result_b) = sync_pair(
+            handler(&dir_a, "Device A", &key_pair_a),
+            handler(&dir_b, "Device B", &key_pair_b).with_approval(approver(false)),
+        )
+        .await;
+        assert!(matches!(result_a, Err(ConnectoError::SyncRejected(_))));
+        assert!(matches!(result_b, Err(ConnectoError::SyncRejected(_))));
+        assert!(authorized(&dir_a).is_empty());
+        assert!(authorized(&dir_b).is_empty());
+
+        // B approves, but A denies B's key: B takes A's key back
+        let fingerprint_b = key_pair_b.fingerprint().unwrap();
+        let (result_a, result_b) = sync_pair(
+            handler(&dir_a, "Device A", &key_pair_a).with_deny_list(vec![fingerprint_b]),
+            handler(&dir_b, "Device B", &key_pair_b).with_approval(approver(true)),
+        )
+        .await;
+        assert!(matches!(result_a, Err(ConnectoError::SyncRejected(_))));
+        match result_b {
+            Err(ConnectoError::SyncRejected(reason)) => {
+                assert!(reason.contains("Device A refused our keys"))
+            }
+            other => panic!("Expected a rejection, got {:?}", other),
+        }
+        assert!(authorized(&dir_a).is_empty());
+        assert!(authorized(&dir_b).is_empty());
+        let decisions = DecisionLog::with_path(dir_b.join("decisions.jsonl"))
+            .all()
+            .unwrap();
+        let approved = decisions.last().unwrap();
+        assert_eq!(approved.decision, Decision::Accepted);
+        assert!(approved.approver.is_some());
+
+        // Denied device names match regardless of case
+        let (result_a, _) = sync_pair(
+            handler(&dir_a, "Device A", &key_pair_a).with_deny_list(vec!["device b".to_string()]),
+            handler(&dir_b, "Device B", &key_pair_b),
+        )
+        .await;
+        assert!(matches!(result_a, Err(ConnectoError::SyncRejected(_))));
+        assert!(authorized(&dir_a).is_empty());
+        assert!(authorized(&dir_b).is_empty());
+
+        // A further key on the deny list is refused, the rest goes through
+        let (result_a, result_b) = sync_pair(
+            handler(&dir_a, "Device A", &key_pair_a)
+                .with_shared_keys(vec![extra_a.public_key.clone()]),
+            handler(&dir_b, "Device B", &key_pair_b)
+                .with_approval(approver(true))
+                .with_deny_list(vec![extra_a.fingerprint().unwrap()]),
+        )
+        .await;
+        assert!(result_a.is_ok());
+        let received = result_b.unwrap().received_keys;
+        assert_eq!(received[0].outcome, KeyOutcome::Added);
+        assert!(matches!(received[1].outcome, KeyOutcome::Refused(_)));
+        assert_eq!(authorized(&dir_a).len(), 1);
+        assert_eq!(authorized(&dir_b).len(), 1);
+    }
 }
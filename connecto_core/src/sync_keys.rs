@@ -0,0 +1,343 @@
+//! Sync key lists module
+//!
+//! `connecto sync --daemon` keeps the keys synced devices authorized for each
+//! other up to date. Each device announces the list of its own keys, and
+//! remembers the list every peer announced to it in `sync_keys.json` in the
+//! Connecto config directory. Peers compare digests of the lists and send
+//! only what changed, so a rotated key replaces the old one in the peer's
+//! `authorized_keys` and a retired key is removed from it.
+
+use crate::error::{ConnectoError, Result};
+use crate::paths;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the sync key lists inside the config directory
+const SYNC_KEYS_FILE: &str = "sync_keys.json";
+
+/// How many of our earlier lists are kept to send changes against
+const ANNOUNCED_HISTORY: usize = 8;
+
+/// The key itself, without options or comment
+pub(crate) fn key_id(key: &str) -> String {
+    let (_, key) = crate::keys::split_authorized_key(key);
+    key.split_whitespace().take(2).collect::<Vec<_>>().join(" ")
+}
+
+/// Digest of a key list, independent of order, comments and duplicates
+pub fn key_list_digest(keys: &[String]) -> String {
+    let mut ids: Vec<String> = keys.iter().map(|key| key_id(key)).collect();
+    ids.sort();
+    ids.dedup();
+    let digest = Sha256::digest(ids.join("\n").as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Keys of `keys` that are not in `other`
+fn missing_from(keys: &[String], other: &[String]) -> Vec<String> {
+    let other: Vec<String> = other.iter().map(|key| key_id(key)).collect();
+    keys.iter()
+        .filter(|key| !other.contains(&key_id(key)))
+        .cloned()
+        .collect()
+}
+
+/// Changes to a key list, as sent in a `SyncKeyDiff` message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyDiff {
+    /// Digest of the list the changes apply to; `None` when `added` is the
+    /// whole list
+    pub base: Option<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Digest of the list once the changes are applied
+    pub digest: String,
+}
+
+impl KeyDiff {
+    /// The changes that turn `from` into `to`
+    pub fn between(from: &[String], to: &[String]) -> Self {
+        Self {
+            base: Some(key_list_digest(from)),
+            added: missing_from(to, from),
+            removed: missing_from(from, to),
+            digest: key_list_digest(to),
+        }
+    }
+
+    /// A diff replacing whatever list the receiver holds with `keys`
+    pub fn full(keys: &[String]) -> Self {
+        Self {
+            base: None,
+            added: keys.to_vec(),
+            removed: Vec::new(),
+            digest: key_list_digest(keys),
+        }
+    }
+
+    /// Apply the changes to `held`, checking the result against the digest
+    pub fn apply(&self, held: &[String]) -> Result<Vec<String>> {
+        let mut keys = match &self.base {
+            None => Vec::new(),
+            Some(base) if *base == key_list_digest(held) => {
+                let removed: Vec<String> = self.removed.iter().map(|key| key_id(key)).collect();
+                held.iter()
+                    .filter(|key| !removed.contains(&key_id(key)))
+                    .cloned()
+                    .collect()
+            }
+            Some(_) => {
+                return Err(ConnectoError::Sync(
+                    "Key changes are for a list we don't hold".to_string(),
+                ))
+            }
+        };
+        keys.extend(missing_from(&self.added, &keys));
+        if key_list_digest(&keys) != self.digest {
+            return Err(ConnectoError::Sync(
+                "Key list does not match its digest after the changes".to_string(),
+            ));
+        }
+        Ok(keys)
+    }
+}
+
+/// Contents of the sync key lists file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncKeys {
+    /// Lists of our own keys announced to peers, oldest first
+    #[serde(default)]
+    announced: Vec<Vec<String>>,
+    /// The list each peer announced to us, by device name
+    #[serde(default)]
+    held: BTreeMap<String, Vec<String>>,
+}
+
+/// The sync key lists of this device and its peers
+#[derive(Debug, Clone)]
+pub struct SyncKeyStore {
+    path: PathBuf,
+}
+
+impl SyncKeyStore {
+    /// Default location of the sync key lists
+    pub fn default_path() -> Result<PathBuf> {
+        paths::config_file(SYNC_KEYS_FILE)
+    }
+
+    /// Use the default file in the Connecto config directory
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            path: Self::default_path()?,
+        })
+    }
+
+    /// Use a specific file
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&self) -> Result<SyncKeys> {
+        if !self.path.exists() {
+            return Ok(SyncKeys::default());
+        }
+        let content = fs::read_to_string(&self.path)?;
+        if content.trim().is_empty() {
+            return Ok(SyncKeys::default());
+        }
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    fn save(&self, keys: &SyncKeys) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(keys)?)?;
+        Ok(())
+    }
+
+    /// Make `keys` the list of our own keys peers should authorize
+    ///
+    /// Returns its digest.
+    pub fn announce(&self, keys: &[String]) -> Result<String> {
+        let digest = key_list_digest(keys);
+        let mut state = self.load()?;
+        let current = state.announced.last().map(|list| key_list_digest(list));
+        if current.as_deref() != Some(digest.as_str()) {
+            state.announced.push(keys.to_vec());
+            let excess = state.announced.len().saturating_sub(ANNOUNCED_HISTORY);
+            state.announced.drain(..excess);
+            self.save(&state)?;
+        }
+        Ok(digest)
+    }
+
+    /// Our current list of keys, empty if none was announced
+    pub fn announced(&self) -> Result<Vec<String>> {
+        Ok(self.load()?.announced.pop().unwrap_or_default())
+    }
+
+    /// The changes to our current list since the list with digest `base`
+    ///
+    /// The whole list is sent when `base` is not one of our recent lists.
+    pub fn diff_since(&self, base: Option<&str>) -> Result<KeyDiff> {
+        let state = self.load()?;
+        let current = state.announced.last().cloned().unwrap_or_default();
+        let earlier = base.and_then(|base| {
+            state
+                .announced
+                .iter()
+                .rev()
+                .find(|list| key_list_digest(list) == base)
+        });
+        Ok(match earlier {
+            Some(earlier) => KeyDiff::between(earlier, &current),
+            None => KeyDiff::full(&current),
+        })
+    }
+
+    /// The list `peer` announced to us, if we synced with it
+    pub fn held(&self, peer: &str) -> Result<Option<Vec<String>>> {
+        Ok(self.load()?.held.remove(peer))
+    }
+
+    /// Record `keys` as the list `peer` announced
+    pub fn set_held(&self, peer: &str, keys: &[String]) -> Result<()> {
+        let mut state = self.load()?;
+        state.held.insert(peer.to_string(), keys.to_vec());
+        self.save(&state)
+    }
+
+    /// Add a key `peer` sent in a one-off sync to the list held for it
+    pub fn hold(&self, peer: &str, key: &str) -> Result<()> {
+        let mut state = self.load()?;
+        let held = state.held.entry(peer.to_string()).or_default();
+        if missing_from(&[key.to_string()], held).is_empty() {
+            return Ok(());
+        }
+        held.push(key.to_string());
+        self.save(&state)
+    }
+
+    /// Whether a peer other than `peer` announced `key`
+    pub fn held_by_other(&self, peer: &str, key: &str) -> Result<bool> {
+        let key = [key.to_string()];
+        Ok(self
+            .load()?
+            .held
+            .iter()
+            .any(|(name, keys)| name != peer && missing_from(&key, keys).is_empty()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const KEY_A: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAINbgPhPjAs5nRMnMgrJCO8ryVDeoTAERX3CACqShfgww alice@a";
+    const KEY_B: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl alice@b";
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn test_key_list_digest() {
+        let digest = key_list_digest(&keys(&[KEY_A, KEY_B]));
+        assert_eq!(digest.len(), 64);
+        // Order, comments and duplicates don't matter
+        assert_eq!(digest, key_list_digest(&keys(&[KEY_B, KEY_A, KEY_A])));
+        let uncommented = KEY_A.rsplit_once(' ').unwrap().0;
+        assert_eq!(
+            key_list_digest(&keys(&[uncommented])),
+            key_list_digest(&keys(&[KEY_A]))
+        );
+        assert_ne!(digest, key_list_digest(&keys(&[KEY_A])));
+    }
+
+    #[test]
+    fn test_diff_apply() {
+        let old = keys(&[KEY_A]);
+        let new = keys(&[KEY_B]);
+        let diff = KeyDiff::between(&old, &new);
+        assert_eq!(diff.added, new);
+        assert_eq!(diff.removed, old);
+        assert_eq!(diff.apply(&old).unwrap(), new);
+        // Changes against another list are refused
+        assert!(diff.apply(&new).is_err());
+
+        // A full list replaces anything
+        let full = KeyDiff::full(&keys(&[KEY_A, KEY_B]));
+        assert_eq!(full.apply(&old).unwrap(), keys(&[KEY_A, KEY_B]));
+        assert_eq!(
+            KeyDiff::full(&[]).apply(&old).unwrap(),
+            Vec::<String>::new()
+        );
+
+        // A wrong digest is caught
+        let mut tampered = KeyDiff::between(&old, &new);
+        tampered.added.push(KEY_A.to_string());
+        assert!(tampered.apply(&old).is_err());
+    }
+
+    #[test]
+    fn test_announce_and_diff_since() {
+        let temp = TempDir::new().unwrap();
+        let store = SyncKeyStore::with_path(temp.path().join("sync_keys.json"));
+        assert!(store.announced().unwrap().is_empty());
+
+        let first = store.announce(&keys(&[KEY_A])).unwrap();
+        assert_eq!(store.announce(&keys(&[KEY_A])).unwrap(), first);
+        store.announce(&keys(&[KEY_B])).unwrap();
+        assert_eq!(store.announced().unwrap(), keys(&[KEY_B]));
+
+        // A peer holding the first list gets the rotation
+        let diff = store.diff_since(Some(&first)).unwrap();
+        assert_eq!(diff.base.as_deref(), Some(first.as_str()));
+        assert_eq!(diff.added, keys(&[KEY_B]));
+        assert_eq!(diff.removed, keys(&[KEY_A]));
+
+        // Unknown lists get the whole current one
+        assert_eq!(
+            store.diff_since(Some("unknown")).unwrap(),
+            KeyDiff::full(&keys(&[KEY_B]))
+        );
+        assert_eq!(store.diff_since(None).unwrap().base, None);
+
+        for _ in 0..ANNOUNCED_HISTORY {
+            store.announce(&keys(&[KEY_A])).unwrap();
+            store.announce(&keys(&[KEY_B])).unwrap();
+        }
+        let content = fs::read_to_string(store.path()).unwrap();
+        let state: SyncKeys = serde_json::from_str(&content).unwrap();
+        assert_eq!(state.announced.len(), ANNOUNCED_HISTORY);
+    }
+
+    #[test]
+    fn test_held_keys() {
+        let temp = TempDir::new().unwrap();
+        let store = SyncKeyStore::with_path(temp.path().join("sync_keys.json"));
+        assert_eq!(store.held("Desk").unwrap(), None);
+
+        store.hold("Desk", KEY_A).unwrap();
+        store.hold("Desk", KEY_A).unwrap();
+        assert_eq!(store.held("Desk").unwrap(), Some(keys(&[KEY_A])));
+        assert!(!store.held_by_other("Desk", KEY_A).unwrap());
+
+        store.set_held("Laptop", &keys(&[KEY_A, KEY_B])).unwrap();
+        assert!(store.held_by_other("Desk", KEY_A).unwrap());
+        assert!(store.held_by_other("Laptop", KEY_A).unwrap());
+        assert!(!store.held_by_other("Laptop", KEY_B).unwrap());
+    }
+}
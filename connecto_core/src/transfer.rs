@@ -0,0 +1,1148 @@
+//! File transfer module
+//!
+//! Sends files to a device running a [`FileReceiver`], such as `connecto
+//! receive`, over the newline-delimited JSON [`Message`]s the pairing
+//! protocol uses:
+//!
+//! 1. The sender offers the files by name and size (`TransferOffer`)
+//! 2. The receiver accepts or declines all of them (`TransferAccept`)
+//! 3. Each file follows as base64 `TransferChunk`s of up to [`CHUNK_SIZE`]
+//!    bytes, then a `TransferComplete` with its SHA-256
+//! 4. The receiver answers with the SHA-256 of what it wrote, or deletes a
+//!    file whose digest does not match and answers with an error
+//!
+//! Both devices announce their identity and prove it by signing a nonce the
+//! other sent: the receiver challenges the sender with a `KeyChallenge`,
+//! and signs the nonce in the offer in its answer. Only a proven identity
+//! is checked against the pins in the [`TrustStore`]. The channel itself
+//! is not encrypted yet.
+
+use crate::error::{ConnectoError, Result};
+use crate::identity::{DeviceIdentity, VerifiedIdentity};
+use crate::net;
+use crate::ports;
+use crate::protocol::{
+    generate_nonce, proven_identity, Message, ProtocolErrorCode, APPROVAL_TIMEOUT_SECS,
+};
+use crate::shutdown::ShutdownHandle;
+use crate::trust::{TrustLevel, TrustMode, TrustStore};
+use base64ct::{Base64, Encoding};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info};
+
+/// Default port receivers listen on
+pub const DEFAULT_TRANSFER_PORT: u16 = 8097;
+
+/// Version carried in transfer offers
+pub const TRANSFER_VERSION: u32 = 1;
+
+/// Most file bytes in one chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest message either side reads, enough for a base64 chunk
+const MAX_LINE: u64 = 256 * 1024;
+
+/// How long to wait for the other device's next message
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A file offered for transfer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfferedFile {
+    /// File name, without any directory
+    pub name: String,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// A file transferred in full
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferredFile {
+    pub name: String,
+    /// Where the file was read from, or saved to
+    pub path: PathBuf,
+    pub size: u64,
+    /// Hex SHA-256 of the contents, the same on both devices
+    pub sha256: String,
+}
+
+/// Result of a successful transfer
+#[derive(Debug, Clone)]
+pub struct TransferResult {
+    /// Name the other device announced
+    pub peer_name: String,
+    pub peer_address: SocketAddr,
+    pub files: Vec<TransferredFile>,
+}
+
+impl TransferResult {
+    /// Bytes in all files
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+/// Events emitted during a transfer
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    /// The receiver is waiting for offers
+    Started { address: SocketAddr },
+    /// The receiver got an offer
+    Offered {
+        device_name: String,
+        address: SocketAddr,
+        files: Vec<OfferedFile>,
+    },
+    /// The receiver accepted the offer
+    Accepted { device_name: String },
+    /// Bytes of `name` sent or received so far
+    Progress { name: String, bytes: u64, size: u64 },
+    /// A file was transferred and both sides agree on its digest
+    FileCompleted { file: TransferredFile },
+    /// Every file was transferred
+    Completed { result: TransferResult },
+    /// The offer was declined, on this device or the other one
+    Declined { device_name: String, reason: String },
+    /// A transfer failed partway
+    Failed { message: String },
+}
+
+/// An offer waiting for the user's answer
+///
+/// Dropping the request without answering declines the files.
+#[derive(Debug)]
+pub struct TransferRequest {
+    pub device_name: String,
+    pub address: SocketAddr,
+    /// Identity the sender proved
+    pub identity: Option<VerifiedIdentity>,
+    pub files: Vec<OfferedFile>,
+    responder: oneshot::Sender<bool>,
+}
+
+impl TransferRequest {
+    /// Answer the request
+    pub fn respond(self, accepted: bool) {
+        let _ = self.responder.send(accepted);
+    }
+
+    /// Wait until the request can no longer be answered, because it timed
+    /// out or the sender went away
+    pub async fn closed(&mut self) {
+        self.responder.closed().await;
+    }
+}
+
+/// The files at `paths` as they will be offered
+///
+/// Fails for anything that is not a readable file, and for two files with
+/// the same name, which the receiver could not tell apart.
+pub fn offer_files(paths: &[PathBuf]) -> Result<Vec<OfferedFile>> {
+    let mut files: Vec<OfferedFile> = Vec::new();
+    for path in paths {
+        let metadata = std::fs::metadata(path).map_err(|e| {
+            ConnectoError::Transfer(format!("Cannot read {}: {}", path.display(), e))
+        })?;
+        if !metadata.is_file() {
+            return Err(ConnectoError::Transfer(format!(
+                "{} is not a file",
+                path.display()
+            )));
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| valid_name(name))
+            .ok_or_else(|| {
+                ConnectoError::Transfer(format!("{} has no usable file name", path.display()))
+            })?;
+        if files.iter().any(|file| file.name == name) {
+            return Err(ConnectoError::Transfer(format!(
+                "Two files are named {}",
+                name
+            )));
+        }
+        files.push(OfferedFile {
+            name: name.to_string(),
+            size: metadata.len(),
+        });
+    }
+    Ok(files)
+}
+
+/// Whether `name` is a plain file name that cannot leave the receiving
+/// directory
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+        && Path::new(name).file_name() == Some(name.as_ref())
+}
+
+/// Sends files to a [`FileReceiver`]
+pub struct FileSender {
+    device_name: String,
+    identity: Option<DeviceIdentity>,
+    trust: Option<TrustStore>,
+    trust_mode: TrustMode,
+}
+
+impl FileSender {
+    pub fn new(device_name: &str) -> Self {
+        Self {
+            device_name: device_name.to_string(),
+            identity: None,
+            trust: None,
+            trust_mode: TrustMode::default(),
+        }
+    }
+
+    /// Announce this device's identity to the receiver, and prove it when
+    /// challenged
+    pub fn with_identity(mut self, identity: &DeviceIdentity) -> Self {
+        self.identity = Some(identity.clone());
+        self
+    }
+
+    /// Check the receiver's identity against the pins in `store`
+    pub fn with_trust_store(mut self, store: TrustStore) -> Self {
+        self.trust = Some(store);
+        self
+    }
+
+    /// How to handle a receiver whose identity changed (default: refuse)
+    pub fn with_trust_mode(mut self, mode: TrustMode) -> Self {
+        self.trust_mode = mode;
+        self
+    }
+
+    /// Send the files at `paths` to the receiver at `address`
+    pub async fn send(
+        &self,
+        address: &str,
+        paths: &[PathBuf],
+        event_tx: mpsc::Sender<TransferEvent>,
+    ) -> Result<TransferResult> {
+        let files = offer_files(paths)?;
+        let stream = net::connect(address).await?;
+        let peer_address = stream.peer_addr()?;
+        self.send_over(stream, peer_address, paths, files, event_tx)
+            .await
+    }
+
+    /// Send files over an already open stream to the receiver at
+    /// `peer_address`
+    pub async fn send_over(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        peer_address: SocketAddr,
+        paths: &[PathBuf],
+        files: Vec<OfferedFile>,
+        event_tx: mpsc::Sender<TransferEvent>,
+    ) -> Result<TransferResult> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+
+        let nonce = generate_nonce();
+        send_message(
+            &mut writer,
+            &Message::TransferOffer {
+                version: TRANSFER_VERSION,
+                device_name: self.device_name.clone(),
+                identity: self
+                    .identity
+                    .as_ref()
+                    .map(|identity| identity.fingerprint().to_string()),
+                nonce: Some(nonce.clone()),
+                files: files.clone(),
+            },
+        )
+        .await?;
+
+        // The receiver's user may take a while to answer
+        let answer_timeout = MESSAGE_TIMEOUT + Duration::from_secs(APPROVAL_TIMEOUT_SECS);
+        let mut answer = read_message(&mut reader, answer_timeout).await?;
+        if let Message::KeyChallenge { nonce } = &answer {
+            let Some(identity) = &self.identity else {
+                return Err(ConnectoError::Protocol(
+                    "Challenged to prove an identity we have not".to_string(),
+                ));
+            };
+            let proof = identity.prove(nonce)?;
+            send_message(&mut writer, &Message::IdentityProof { proof }).await?;
+            answer = read_message(&mut reader, answer_timeout).await?;
+        }
+        let peer_name = match answer {
+            Message::TransferAccept {
+                device_name,
+                accepted,
+                identity,
+                identity_proof,
+                message,
+            } => {
+                if !accepted {
+                    let reason = message.unwrap_or_else(|| "Declined".to_string());
+                    let _ = event_tx
+                        .send(TransferEvent::Declined {
+                            device_name: device_name.clone(),
+                            reason: reason.clone(),
+                        })
+                        .await;
+                    return Err(ConnectoError::Transfer(format!(
+                        "{} declined the files: {}",
+                        device_name, reason
+                    )));
+                }
+                let identity = proven_identity(&device_name, identity, identity_proof, &nonce)?;
+                if let Some(trust) = &self.trust {
+                    trust.verify(&device_name, identity.as_ref(), self.trust_mode)?;
+                }
+                device_name
+            }
+            Message::Error { message, .. } => return Err(ConnectoError::Transfer(message)),
+            other => return Err(unexpected(other)),
+        };
+        let _ = event_tx
+            .send(TransferEvent::Accepted {
+                device_name: peer_name.clone(),
+            })
+            .await;
+
+        let mut sent = Vec::new();
+        for (index, (path, offered)) in paths.iter().zip(&files).enumerate() {
+            let file = send_file(&mut reader, &mut writer, index, path, offered, &event_tx).await?;
+            let _ = event_tx
+                .send(TransferEvent::FileCompleted { file: file.clone() })
+                .await;
+            sent.push(file);
+        }
+
+        let result = TransferResult {
+            peer_name,
+            peer_address,
+            files: sent,
+        };
+        let _ = event_tx
+            .send(TransferEvent::Completed {
+                result: result.clone(),
+            })
+            .await;
+        Ok(result)
+    }
+}
+
+/// Stream one file and wait for the receiver to confirm its digest
+async fn send_file<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    index: usize,
+    path: &Path,
+    offered: &OfferedFile,
+    event_tx: &mpsc::Sender<TransferEvent>,
+) -> Result<TransferredFile>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut bytes = 0;
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        bytes += read as u64;
+        if bytes > offered.size {
+            break;
+        }
+        send_message(
+            writer,
+            &Message::TransferChunk {
+                file: index,
+                data: Base64::encode_string(&buffer[..read]),
+            },
+        )
+        .await?;
+        let _ = event_tx
+            .send(TransferEvent::Progress {
+                name: offered.name.clone(),
+                bytes,
+                size: offered.size,
+            })
+            .await;
+    }
+    if bytes != offered.size {
+        return Err(ConnectoError::Transfer(format!(
+            "{} changed while it was being sent",
+            path.display()
+        )));
+    }
+
+    let sha256 = to_hex(&hasher.finalize());
+    send_message(
+        writer,
+        &Message::TransferComplete {
+            file: index,
+            sha256: sha256.clone(),
+        },
+    )
+    .await?;
+    match read_message(reader, MESSAGE_TIMEOUT).await? {
+        Message::TransferComplete {
+            file,
+            sha256: received,
+        } if file == index => {
+            if received != sha256 {
+                return Err(ConnectoError::Transfer(format!(
+                    "{} arrived with SHA-256 {}, but {} was sent",
+                    offered.name, received, sha256
+                )));
+            }
+        }
+        Message::Error { message, .. } => return Err(ConnectoError::Transfer(message)),
+        other => return Err(unexpected(other)),
+    }
+    Ok(TransferredFile {
+        name: offered.name.clone(),
+        path: path.to_path_buf(),
+        size: bytes,
+        sha256,
+    })
+}
+
+/// Receives files offered by a [`FileSender`] into a directory
+pub struct FileReceiver {
+    device_name: String,
+    dir: PathBuf,
+    identity: Option<DeviceIdentity>,
+    trust: Option<TrustStore>,
+    approval_tx: Option<mpsc::Sender<TransferRequest>>,
+    approval_timeout: Duration,
+    listener: Option<TcpListener>,
+    shutdown: ShutdownHandle,
+}
+
+impl FileReceiver {
+    /// A receiver saving files to `dir`
+    pub fn new(device_name: &str, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            device_name: device_name.to_string(),
+            dir: dir.into(),
+            identity: None,
+            trust: None,
+            approval_tx: None,
+            approval_timeout: Duration::from_secs(APPROVAL_TIMEOUT_SECS),
+            listener: None,
+            shutdown: ShutdownHandle::new(),
+        }
+    }
+
+    /// Announce this device's identity to senders, and prove it by signing
+    /// their nonce
+    pub fn with_identity(mut self, identity: &DeviceIdentity) -> Self {
+        self.identity = Some(identity.clone());
+        self
+    }
+
+    /// Check senders against the pins and trust levels in `store`
+    ///
+    /// A sender whose identity changed is always refused.
+    pub fn with_trust_store(mut self, store: TrustStore) -> Self {
+        self.trust = Some(store);
+        self
+    }
+
+    /// Ask about offers from devices that are not trusted over `approval_tx`
+    ///
+    /// Without it, only offers from trusted devices are accepted.
+    pub fn with_approval(mut self, approval_tx: mpsc::Sender<TransferRequest>) -> Self {
+        self.approval_tx = Some(approval_tx);
+        self
+    }
+
+    /// Decline an offer nobody answered within `timeout` instead of the
+    /// default
+    pub fn with_approval_timeout(mut self, timeout: Duration) -> Self {
+        self.approval_timeout = timeout;
+        self
+    }
+
+    /// A handle that stops [`FileReceiver::run`] and
+    /// [`FileReceiver::handle_one`] from another task
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Start listening on the specified port
+    pub async fn listen(&mut self, port: u16) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
+            .await
+            .map_err(|e| ports::bind_error(port, e))?;
+        let local_addr = listener.local_addr()?;
+        info!("File receiver listening on {}", local_addr);
+        self.listener = Some(listener);
+        Ok(local_addr)
+    }
+
+    /// Receive transfers one after another until shut down
+    pub async fn run(&mut self, event_tx: mpsc::Sender<TransferEvent>) -> Result<()> {
+        self.receive(event_tx, false).await
+    }
+
+    /// Receive until one transfer succeeds, or the receiver is shut down
+    ///
+    /// Declined offers and failed transfers are reported as events, and the
+    /// receiver keeps waiting.
+    pub async fn handle_one(&mut self, event_tx: mpsc::Sender<TransferEvent>) -> Result<()> {
+        self.receive(event_tx, true).await
+    }
+
+    async fn receive(&mut self, event_tx: mpsc::Sender<TransferEvent>, once: bool) -> Result<()> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| ConnectoError::Network("Receiver not started".to_string()))?;
+        let address = listener.local_addr()?;
+        let _ = event_tx.send(TransferEvent::Started { address }).await;
+
+        let receive = async {
+            loop {
+                let (stream, peer_addr) = listener.accept().await?;
+                debug!("Sender connected from {}", peer_addr);
+                match self
+                    .handle_stream(stream, peer_addr, event_tx.clone())
+                    .await
+                {
+                    Ok(_) if once => return Ok(()),
+                    Ok(_) => {}
+                    Err(e) => debug!("Transfer from {} ended: {}", peer_addr, e),
+                }
+            }
+        };
+        tokio::select! {
+            result = receive => result,
+            _ = self.shutdown.requested() => {
+                info!("File receiver on {} shut down", address);
+                Ok(())
+            }
+        }
+    }
+
+    /// Receive the files offered on an already open stream by the device at
+    /// `peer_addr`
+    ///
+    /// Does not need [`FileReceiver::listen`].
+    pub async fn handle_stream(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        peer_addr: SocketAddr,
+        event_tx: mpsc::Sender<TransferEvent>,
+    ) -> Result<TransferResult> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+
+        let (peer_name, identity, peer_nonce, files) =
+            match read_message(&mut reader, MESSAGE_TIMEOUT).await? {
+                Message::TransferOffer {
+                    version,
+                    device_name,
+                    identity,
+                    nonce,
+                    files,
+                } => {
+                    if version < TRANSFER_VERSION {
+                        return Err(ConnectoError::Protocol(format!(
+                            "Unsupported transfer version {}",
+                            version
+                        )));
+                    }
+                    (device_name, identity, nonce, files)
+                }
+                other => return Err(unexpected(other)),
+            };
+        // Only an identity the sender proves counts; senders too old to
+        // take a challenge send no nonce
+        let identity = match (identity, &peer_nonce) {
+            (Some(announced), Some(_)) => {
+                Some(self.challenge(&mut reader, &mut writer, &announced).await?)
+            }
+            _ => None,
+        };
+        let peer_nonce = peer_nonce.as_deref();
+        let _ = event_tx
+            .send(TransferEvent::Offered {
+                device_name: peer_name.clone(),
+                address: peer_addr,
+                files: files.clone(),
+            })
+            .await;
+
+        if let Err(reason) = self
+            .review_offer(&peer_name, peer_addr, identity, &files)
+            .await
+        {
+            let answer = self.answer(false, Some(reason.clone()), peer_nonce)?;
+            send_message(&mut writer, &answer).await?;
+            let _ = event_tx
+                .send(TransferEvent::Declined {
+                    device_name: peer_name.clone(),
+                    reason: reason.clone(),
+                })
+                .await;
+            return Err(ConnectoError::Transfer(format!(
+                "Declined files from {}: {}",
+                peer_name, reason
+            )));
+        }
+        send_message(&mut writer, &self.answer(true, None, peer_nonce)?).await?;
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let mut received = Vec::new();
+        for (index, offered) in files.iter().enumerate() {
+            match self
+                .receive_file(&mut reader, &mut writer, index, offered, &event_tx)
+                .await
+            {
+                Ok(file) => {
+                    let _ = event_tx
+                        .send(TransferEvent::FileCompleted { file: file.clone() })
+                        .await;
+                    received.push(file);
+                }
+                Err(e) => {
+                    let _ = event_tx
+                        .send(TransferEvent::Failed {
+                            message: e.to_string(),
+                        })
+                        .await;
+                    return Err(e);
+                }
+            }
+        }
+
+        let result = TransferResult {
+            peer_name,
+            peer_address: peer_addr,
+            files: received,
+        };
+        let _ = event_tx
+            .send(TransferEvent::Completed {
+                result: result.clone(),
+            })
+            .await;
+        Ok(result)
+    }
+
+    /// Challenge a sender to prove the identity it announced as `announced`
+    ///
+    /// A bad proof is answered with an error before failing.
+    async fn challenge<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        announced: &str,
+    ) -> Result<VerifiedIdentity>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let nonce = generate_nonce();
+        send_message(
+            writer,
+            &Message::KeyChallenge {
+                nonce: nonce.clone(),
+            },
+        )
+        .await?;
+        let verified = match read_message(reader, MESSAGE_TIMEOUT).await? {
+            Message::IdentityProof { proof } => proof.verify_announced(&nonce, Some(announced)),
+            other => Err(unexpected(other)),
+        };
+        if verified.is_err() {
+            let error_msg = Message::Error {
+                code: ProtocolErrorCode::VerificationFailed,
+                message: "Identity proof verification failed".to_string(),
+            };
+            send_message(writer, &error_msg).await?;
+        }
+        verified
+    }
+
+    /// Our answer to an offer, proving our identity over the sender's
+    /// `nonce`
+    fn answer(
+        &self,
+        accepted: bool,
+        message: Option<String>,
+        nonce: Option<&str>,
+    ) -> Result<Message> {
+        let (identity, identity_proof) = match &self.identity {
+            Some(identity) => (
+                Some(identity.fingerprint().to_string()),
+                nonce.map(|nonce| identity.prove(nonce)).transpose()?,
+            ),
+            None => (None, None),
+        };
+        Ok(Message::TransferAccept {
+            device_name: self.device_name.clone(),
+            accepted,
+            identity,
+            identity_proof,
+            message,
+        })
+    }
+
+    /// Check an offer's names and sender, asking the user unless the sender
+    /// is trusted
+    ///
+    /// Returns why the offer was declined.
+    async fn review_offer(
+        &self,
+        peer_name: &str,
+        peer_addr: SocketAddr,
+        identity: Option<VerifiedIdentity>,
+        files: &[OfferedFile],
+    ) -> std::result::Result<(), String> {
+        if files.is_empty() {
+            return Err("No files offered".to_string());
+        }
+        if let Some(file) = files.iter().find(|file| !valid_name(&file.name)) {
+            return Err(format!("Invalid file name {:?}", file.name));
+        }
+
+        let level = match &self.trust {
+            Some(trust) => {
+                trust
+                    .verify(peer_name, identity.as_ref(), TrustMode::Enforce)
+                    .map_err(|e| e.to_string())?;
+                trust
+                    .level(peer_name, identity.as_ref())
+                    .map_err(|e| e.to_string())?
+            }
+            None => TrustLevel::Unknown,
+        };
+        if level == TrustLevel::Trusted {
+            return Ok(());
+        }
+
+        let Some(approval_tx) = &self.approval_tx else {
+            return Err(format!("{} is not trusted on this device", peer_name));
+        };
+        let (responder, response) = oneshot::channel();
+        let request = TransferRequest {
+            device_name: peer_name.to_string(),
+            address: peer_addr,
+            identity,
+            files: files.to_vec(),
+            responder,
+        };
+        if approval_tx.send(request).await.is_err() {
+            return Err("Nobody is there to accept the files".to_string());
+        }
+        match tokio::time::timeout(self.approval_timeout, response).await {
+            Ok(Ok(true)) => Ok(()),
+            Ok(_) => Err("Declined by user".to_string()),
+            Err(_) => Err(format!(
+                "No answer within {}s",
+                self.approval_timeout.as_secs()
+            )),
+        }
+    }
+
+    /// Receive one file next to its final path, keeping it only once its
+    /// digest matches
+    async fn receive_file<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        index: usize,
+        offered: &OfferedFile,
+        event_tx: &mpsc::Sender<TransferEvent>,
+    ) -> Result<TransferredFile>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let partial = self.dir.join(format!(".{}.part", offered.name));
+        let result = receive_into(reader, &partial, index, offered, event_tx).await;
+        let sha256 = match result {
+            Ok(sha256) => sha256,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                // The sender may already be gone
+                let _ = send_message(
+                    writer,
+                    &Message::Error {
+                        code: ProtocolErrorCode::InternalError,
+                        message: e.to_string(),
+                    },
+                )
+                .await;
+                return Err(e);
+            }
+        };
+
+        let path = unused_path(&self.dir, &offered.name);
+        tokio::fs::rename(&partial, &path).await?;
+        send_message(
+            writer,
+            &Message::TransferComplete {
+                file: index,
+                sha256: sha256.clone(),
+            },
+        )
+        .await?;
+        Ok(TransferredFile {
+            name: offered.name.clone(),
+            path,
+            size: offered.size,
+            sha256,
+        })
+    }
+}
+
+/// Write the chunks of file `index` to `path`, returning the digest of what
+/// was written once it matches the sender's
+async fn receive_into<R>(
+    reader: &mut R,
+    path: &Path,
+    index: usize,
+    offered: &OfferedFile,
+    event_tx: &mpsc::Sender<TransferEvent>,
+) -> Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut hasher = Sha256::new();
+    let mut bytes = 0;
+    loop {
+        match read_message(reader, MESSAGE_TIMEOUT).await? {
+            Message::TransferChunk { file: chunk, data } if chunk == index => {
+                let data = Base64::decode_vec(&data).map_err(|_| {
+                    ConnectoError::Protocol(format!("Invalid chunk of {}", offered.name))
+                })?;
+                bytes += data.len() as u64;
+                if bytes > offered.size {
+                    return Err(ConnectoError::Transfer(format!(
+                        "{} is larger than the {} bytes offered",
+                        offered.name, offered.size
+                    )));
+                }
+                hasher.update(&data);
+                file.write_all(&data).await?;
+                let _ = event_tx
+                    .send(TransferEvent::Progress {
+                        name: offered.name.clone(),
+                        bytes,
+                        size: offered.size,
+                    })
+                    .await;
+            }
+            Message::TransferComplete { file: done, sha256 } if done == index => {
+                file.flush().await?;
+                let received = to_hex(&hasher.finalize());
+                if bytes != offered.size || received != sha256 {
+                    return Err(ConnectoError::Transfer(format!(
+                        "{} failed verification: received {} of {} bytes with SHA-256 {}, expected {}",
+                        offered.name, bytes, offered.size, received, sha256
+                    )));
+                }
+                return Ok(received);
+            }
+            other => return Err(unexpected(other)),
+        }
+    }
+}
+
+/// `name` in `dir`, numbered like `name (1)` if a file by that name exists
+fn unused_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    (1..)
+        .map(|n| match extension {
+            Some(extension) => dir.join(format!("{} ({}).{}", stem, n, extension)),
+            None => dir.join(format!("{} ({})", stem, n)),
+        })
+        .find(|path| !path.exists())
+        .expect("some numbered name is free")
+}
+
+async fn send_message(writer: &mut (impl AsyncWrite + Unpin), message: &Message) -> Result<()> {
+    writer.write_all(message.to_json()?.as_bytes()).await?;
+    Ok(())
+}
+
+/// Read one message of at most [`MAX_LINE`] bytes within `timeout`
+async fn read_message(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    timeout: Duration,
+) -> Result<Message> {
+    let mut line = String::new();
+    let read = tokio::time::timeout(timeout, (&mut *reader).take(MAX_LINE).read_line(&mut line))
+        .await
+        .map_err(|_| ConnectoError::Timeout("The other device stopped responding".to_string()))??;
+    if read == 0 {
+        return Err(ConnectoError::Transfer(
+            "The other device closed the connection".to_string(),
+        ));
+    }
+    if !line.ends_with('\n') {
+        return Err(ConnectoError::Protocol("Message too long".to_string()));
+    }
+    Message::from_json(&line)
+}
+
+fn unexpected(message: Message) -> ConnectoError {
+    ConnectoError::Protocol(format!("Unexpected message: {:?}", message))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_file(dir: &Path, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn peer_addr() -> SocketAddr {
+        "192.168.1.20:50000".parse().unwrap()
+    }
+
+    /// The identity kept in `dir` under `name`
+    fn identity(dir: &Path, name: &str) -> DeviceIdentity {
+        DeviceIdentity::load_or_create_at(&dir.join(name)).unwrap()
+    }
+
+    /// Send `paths` from "Device A", as `identity`, to `receiver` over an
+    /// in-memory stream
+    async fn transfer(
+        receiver: &FileReceiver,
+        identity: &DeviceIdentity,
+        paths: &[PathBuf],
+    ) -> (Result<TransferResult>, Result<TransferResult>) {
+        let (sender_stream, receiver_stream) = tokio::io::duplex(4096);
+        let sender = FileSender::new("Device A").with_identity(identity);
+        let files = offer_files(paths).unwrap();
+        let (sender_tx, mut sender_rx) = mpsc::channel(64);
+        let (receiver_tx, mut receiver_rx) = mpsc::channel(64);
+        tokio::spawn(async move { while sender_rx.recv().await.is_some() {} });
+        tokio::spawn(async move { while receiver_rx.recv().await.is_some() {} });
+        tokio::join!(
+            sender.send_over(sender_stream, peer_addr(), paths, files, sender_tx),
+            receiver.handle_stream(receiver_stream, peer_addr(), receiver_tx),
+        )
+    }
+
+    /// Answer every transfer request with `accepted`
+    fn approver(accepted: bool) -> mpsc::Sender<TransferRequest> {
+        let (tx, mut rx) = mpsc::channel::<TransferRequest>(1);
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                request.respond(accepted);
+            }
+        });
+        tx
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("notes.txt"));
+        assert!(valid_name(".bashrc"));
+        assert!(!valid_name(""));
+        assert!(!valid_name(".."));
+        assert!(!valid_name("../notes.txt"));
+        assert!(!valid_name("/etc/passwd"));
+        assert!(!valid_name("dir\\notes.txt"));
+    }
+
+    #[test]
+    fn test_offer_files() {
+        let dir = TempDir::new().unwrap();
+        let notes = write_file(dir.path(), "notes.txt", b"hello");
+        let files = offer_files(std::slice::from_ref(&notes)).unwrap();
+        assert_eq!(
+            files,
+            vec![OfferedFile {
+                name: "notes.txt".to_string(),
+                size: 5,
+            }]
+        );
+
+        // Directories, missing files and repeated names are refused
+        assert!(offer_files(&[dir.path().to_path_buf()]).is_err());
+        assert!(offer_files(&[dir.path().join("missing.txt")]).is_err());
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let other = write_file(&dir.path().join("sub"), "notes.txt", b"other");
+        assert!(offer_files(&[notes, other]).is_err());
+    }
+
+    #[test]
+    fn test_unused_path() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(
+            unused_path(dir.path(), "notes.txt"),
+            dir.path().join("notes.txt")
+        );
+        write_file(dir.path(), "notes.txt", b"");
+        write_file(dir.path(), "notes (1).txt", b"");
+        write_file(dir.path(), "README", b"");
+        assert_eq!(
+            unused_path(dir.path(), "notes.txt"),
+            dir.path().join("notes (2).txt")
+        );
+        assert_eq!(
+            unused_path(dir.path(), "README"),
+            dir.path().join("README (1)")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer_files() {
+        let source = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        // Larger than one chunk, so it arrives in several
+        let large: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let paths = vec![
+            write_file(source.path(), "large.bin", &large),
+            write_file(source.path(), "empty.txt", b""),
+        ];
+        // A file by the same name is kept
+        write_file(target.path(), "empty.txt", b"keep me");
+
+        let receiver = FileReceiver::new("Device B", target.path()).with_approval(approver(true));
+        let device_a = identity(source.path(), "device-a");
+        let (sent, received) = transfer(&receiver, &device_a, &paths).await;
+        let sent = sent.unwrap();
+        let received = received.unwrap();
+
+        assert_eq!(sent.peer_name, "Device B");
+        assert_eq!(received.peer_name, "Device A");
+        assert_eq!(received.total_bytes(), large.len() as u64);
+        assert_eq!(sent.files[0].sha256, received.files[0].sha256);
+        assert_eq!(std::fs::read(&received.files[0].path).unwrap(), large);
+        assert_eq!(received.files[1].path, target.path().join("empty (1).txt"));
+        assert_eq!(
+            std::fs::read(target.path().join("empty.txt")).unwrap(),
+            b"keep me"
+        );
+        // No partial files are left behind
+        assert_eq!(std::fs::read_dir(target.path()).unwrap().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_needs_trust_or_approval() {
+        let source = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        let paths = vec![write_file(source.path(), "notes.txt", b"hello")];
+        let trust = TrustStore::with_path(target.path().join("known_peers.json"));
+        let device_a = identity(source.path(), "device-a");
+
+        // Nobody to ask, and Device A is not trusted
+        let receiver = FileReceiver::new("Device B", target.path().join("files"))
+            .with_trust_store(trust.clone());
+        let (sent, received) = transfer(&receiver, &device_a, &paths).await;
+        let error = sent.unwrap_err().to_string();
+        assert!(error.contains("not trusted"), "{}", error);
+        assert!(received.is_err());
+        assert!(!target.path().join("files").exists());
+
+        // The user declines
+        let receiver = FileReceiver::new("Device B", target.path().join("files"))
+            .with_trust_store(trust.clone())
+            .with_approval(approver(false));
+        let (sent, _) = transfer(&receiver, &device_a, &paths).await;
+        assert!(sent.unwrap_err().to_string().contains("Declined by user"));
+
+        // A trusted device needs no approval
+        let proven = device_a.prove("nonce").unwrap().verify("nonce").unwrap();
+        trust.pin("Device A", &proven).unwrap();
+        trust.set_level("Device A", TrustLevel::Trusted).unwrap();
+        let receiver = FileReceiver::new("Device B", target.path().join("files"))
+            .with_trust_store(trust.clone());
+        let (sent, received) = transfer(&receiver, &device_a, &paths).await;
+        assert!(sent.is_ok());
+        assert_eq!(received.unwrap().files[0].size, 5);
+
+        // Unless its identity changed
+        let (sent, _) = transfer(&receiver, &identity(source.path(), "someone-else"), &paths).await;
+        assert!(sent.unwrap_err().to_string().contains("pinned"));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_rejects_corrupted_file() {
+        let target = TempDir::new().unwrap();
+        let receiver = FileReceiver::new("Device B", target.path()).with_approval(approver(true));
+        let (sender_stream, receiver_stream) = tokio::io::duplex(4096);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        tokio::spawn(async move { while event_rx.recv().await.is_some() {} });
+        let receiving = tokio::spawn(async move {
+            receiver
+                .handle_stream(receiver_stream, peer_addr(), event_tx)
+                .await
+        });
+
+        let (reader, mut writer) = tokio::io::split(sender_stream);
+        let mut reader = BufReader::new(reader);
+        let offer = Message::TransferOffer {
+            version: TRANSFER_VERSION,
+            device_name: "Device A".to_string(),
+            identity: None,
+            nonce: None,
+            files: vec![OfferedFile {
+                name: "notes.txt".to_string(),
+                size: 5,
+            }],
+        };
+        send_message(&mut writer, &offer).await.unwrap();
+        assert!(matches!(
+            read_message(&mut reader, MESSAGE_TIMEOUT).await.unwrap(),
+            Message::TransferAccept { accepted: true, .. }
+        ));
+        let chunk = Message::TransferChunk {
+            file: 0,
+            data: Base64::encode_string(b"hellO"),
+        };
+        send_message(&mut writer, &chunk).await.unwrap();
+        let complete = Message::TransferComplete {
+            file: 0,
+            sha256: to_hex(&Sha256::digest(b"hello")),
+        };
+        send_message(&mut writer, &complete).await.unwrap();
+
+        match read_message(&mut reader, MESSAGE_TIMEOUT).await.unwrap() {
+            Message::Error { code, message } => {
+                assert_eq!(code, ProtocolErrorCode::InternalError);
+                assert!(message.contains("failed verification"), "{}", message);
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert!(receiving.await.unwrap().is_err());
+        assert_eq!(std::fs::read_dir(target.path()).unwrap().count(), 0);
+    }
+}
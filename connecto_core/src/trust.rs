@@ -0,0 +1,487 @@
+//! Trust-on-first-use module
+//!
+//! Pins the identity fingerprint of every device we pair with in
+//! `known_peers.json` in the Connecto config directory. Later pairings with
+//! a device of the same name are checked against the pinned fingerprint, so
+//! an impostor announcing a familiar name is caught before keys change hands.
+//! Identities pinned elsewhere, such as in the SSH config entry for an
+//! address, are checked the same way with [`verify_identity`].
+//!
+//! Only a [`VerifiedIdentity`], which the device proved by signing a fresh
+//! challenge with its identity key, is pinned or compared with a pin; a
+//! fingerprint that was merely announced never is.
+//!
+//! Each device also has a [`TrustLevel`] deciding what a listener asks of it
+//! before taking its key. The level only holds for a device that proves the
+//! identity pinned for its name; under any other identity, or none, it is
+//! unknown.
+
+use crate::error::{ConnectoError, Result};
+use crate::identity::VerifiedIdentity;
+use crate::paths;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// File name of the known peers database inside the config directory
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+
+/// What to do when a peer's identity no longer matches its pin
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrustMode {
+    /// Abort the pairing
+    #[default]
+    Enforce,
+    /// Log a warning and pin the new identity
+    Warn,
+}
+
+/// How far a listener trusts a device asking to pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustLevel {
+    /// Pairs without a verification code or approval
+    Trusted,
+    /// Must enter a verification code
+    Known,
+    /// Must enter a verification code and be approved
+    Unknown,
+}
+
+impl TrustLevel {
+    /// Whether the device must enter a verification code
+    pub fn requires_code(self) -> bool {
+        self != Self::Trusted
+    }
+
+    /// Whether the listener's user must approve the device's key
+    pub fn requires_approval(self) -> bool {
+        self == Self::Unknown
+    }
+}
+
+impl fmt::Display for TrustLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Trusted => "trusted",
+            Self::Known => "known",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// A device whose identity has been pinned or whose trust level was set
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownPeer {
+    /// Identity fingerprint the device proved when first seen, if it has
+    /// been paired with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Unix timestamp (seconds) the identity was pinned
+    pub first_seen: u64,
+    /// Unix timestamp (seconds) of the latest pairing with this identity
+    pub last_seen: u64,
+    /// Trust level set with [`TrustStore::set_level`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<TrustLevel>,
+}
+
+impl KnownPeer {
+    /// The level the device is treated with: the one set for it, otherwise
+    /// known once its identity is pinned
+    pub fn trust_level(&self) -> TrustLevel {
+        match (self.level, &self.fingerprint) {
+            (Some(level), _) => level,
+            (None, Some(_)) => TrustLevel::Known,
+            (None, None) => TrustLevel::Unknown,
+        }
+    }
+
+    /// The level of a device with identity `fingerprint`: its own level if
+    /// that is the pinned identity, otherwise unknown
+    pub fn level_for(&self, fingerprint: Option<&str>) -> TrustLevel {
+        match (&self.fingerprint, fingerprint) {
+            (Some(pinned), Some(fingerprint)) if pinned == fingerprint => self.trust_level(),
+            _ => TrustLevel::Unknown,
+        }
+    }
+}
+
+/// Outcome of checking a peer against its pin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustCheck {
+    /// No identity is pinned for this device name
+    Unknown,
+    /// The proven identity matches the pin
+    Trusted,
+    /// The proven identity differs from the pin, or none was proven
+    Changed {
+        pinned: String,
+        proven: Option<String>,
+    },
+}
+
+/// The known peers database
+#[derive(Debug, Clone)]
+pub struct TrustStore {
+    path: PathBuf,
+}
+
+impl TrustStore {
+    /// Default location of the known peers database
+    pub fn default_path() -> Result<PathBuf> {
+        paths::config_file(KNOWN_PEERS_FILE)
+    }
+
+    /// Use the default database in the Connecto config directory
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            path: Self::default_path()?,
+        })
+    }
+
+    /// Use a specific database file
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Path of the database file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All known peers, keyed by device name
+    pub fn all(&self) -> Result<BTreeMap<String, KnownPeer>> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let content = fs::read_to_string(&self.path)?;
+        if content.trim().is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// The pin for `device_name`, if any
+    pub fn get(&self, device_name: &str) -> Result<Option<KnownPeer>> {
+        Ok(self.all()?.remove(device_name))
+    }
+
+    /// Compare the identity a device proved with its pin
+    pub fn check(
+        &self,
+        device_name: &str,
+        identity: Option<&VerifiedIdentity>,
+    ) -> Result<TrustCheck> {
+        let Some(pinned) = self.get(device_name)?.and_then(|peer| peer.fingerprint) else {
+            return Ok(TrustCheck::Unknown);
+        };
+        let proven = identity.map(VerifiedIdentity::fingerprint);
+        if proven == Some(pinned.as_str()) {
+            return Ok(TrustCheck::Trusted);
+        }
+        Ok(TrustCheck::Changed {
+            pinned,
+            proven: proven.map(str::to_string),
+        })
+    }
+
+    /// The trust level of `device_name` having proved `identity`; unknown
+    /// unless that is the identity pinned for it
+    ///
+    /// A level set for a device before its first pairing holds once its
+    /// identity is pinned.
+    pub fn level(
+        &self,
+        device_name: &str,
+        identity: Option<&VerifiedIdentity>,
+    ) -> Result<TrustLevel> {
+        Ok(self.get(device_name)?.map_or(TrustLevel::Unknown, |peer| {
+            peer.level_for(identity.map(VerifiedIdentity::fingerprint))
+        }))
+    }
+
+    /// Set the trust level of `device_name`, keeping any pinned identity
+    pub fn set_level(&self, device_name: &str, level: TrustLevel) -> Result<()> {
+        let mut peers = self.all()?;
+        let now = now();
+        peers
+            .entry(device_name.to_string())
+            .or_insert_with(|| KnownPeer {
+                fingerprint: None,
+                first_seen: now,
+                last_seen: now,
+                level: None,
+            })
+            .level = Some(level);
+        self.save(&peers)
+    }
+
+    /// Check a device before pairing, failing on a changed identity in
+    /// [`TrustMode::Enforce`]
+    pub fn verify(
+        &self,
+        device_name: &str,
+        identity: Option<&VerifiedIdentity>,
+        mode: TrustMode,
+    ) -> Result<()> {
+        let TrustCheck::Changed { pinned, proven } = self.check(device_name, identity)? else {
+            return Ok(());
+        };
+        identity_changed(
+            format!(
+                "{} proved identity {}, but {} was pinned on first pairing",
+                device_name,
+                proven.as_deref().unwrap_or("(none)"),
+                pinned
+            ),
+            mode,
+        )
+    }
+
+    /// Pin `identity` for `device_name`, replacing any previous pin
+    pub fn pin(&self, device_name: &str, identity: &VerifiedIdentity) -> Result<()> {
+        let identity = identity.fingerprint();
+        let mut peers = self.all()?;
+        let now = now();
+        let peer = peers
+            .entry(device_name.to_string())
+            .or_insert_with(|| KnownPeer {
+                fingerprint: None,
+                first_seen: now,
+                last_seen: now,
+                level: None,
+            });
+        if peer.fingerprint.as_deref() != Some(identity) {
+            peer.fingerprint = Some(identity.to_string());
+            peer.first_seen = now;
+        }
+        peer.last_seen = now;
+        self.save(&peers)
+    }
+
+    /// Remove the pin for `device_name`, returning whether one existed
+    pub fn forget(&self, device_name: &str) -> Result<bool> {
+        let mut peers = self.all()?;
+        if peers.remove(device_name).is_none() {
+            return Ok(false);
+        }
+        self.save(&peers)?;
+        Ok(true)
+    }
+
+    fn save(&self, peers: &BTreeMap<String, KnownPeer>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(peers)?)?;
+        Ok(())
+    }
+}
+
+/// Check a proven identity against `pinned`, failing on a mismatch in
+/// [`TrustMode::Enforce`]
+///
+/// `subject` names the device in messages, e.g. "The device at 10.0.0.5".
+pub fn verify_identity(
+    subject: &str,
+    pinned: &str,
+    identity: Option<&VerifiedIdentity>,
+    mode: TrustMode,
+) -> Result<()> {
+    let proven = identity.map(VerifiedIdentity::fingerprint);
+    if proven == Some(pinned) {
+        return Ok(());
+    }
+    identity_changed(
+        format!(
+            "{} proved identity {}, but {} is pinned for it",
+            subject,
+            proven.unwrap_or("(none)"),
+            pinned
+        ),
+        mode,
+    )
+}
+
+fn identity_changed(message: String, mode: TrustMode) -> Result<()> {
+    match mode {
+        TrustMode::Enforce => Err(ConnectoError::IdentityMismatch(message)),
+        TrustMode::Warn => {
+            warn!("Identity changed: {}", message);
+            Ok(())
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::DeviceIdentity;
+    use tempfile::TempDir;
+
+    fn store(temp_dir: &TempDir) -> TrustStore {
+        TrustStore::with_path(temp_dir.path().join("connecto").join(KNOWN_PEERS_FILE))
+    }
+
+    /// The identity of a fresh device, as it would prove it
+    fn proven(temp_dir: &TempDir, name: &str) -> VerifiedIdentity {
+        let identity = DeviceIdentity::load_or_create_at(&temp_dir.path().join(name)).unwrap();
+        identity.prove("nonce").unwrap().verify("nonce").unwrap()
+    }
+
+    #[test]
+    fn test_first_use_pins_identity() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = store(&temp_dir);
+        let desk = proven(&temp_dir, "desk");
+
+        assert_eq!(
+            store.check("desk", Some(&desk)).unwrap(),
+            TrustCheck::Unknown
+        );
+        store.pin("desk", &desk).unwrap();
+        assert_eq!(
+            store.check("desk", Some(&desk)).unwrap(),
+            TrustCheck::Trusted
+        );
+        assert!(store
+            .verify("desk", Some(&desk), TrustMode::Enforce)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_changed_identity() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = store(&temp_dir);
+        let (desk, other) = (proven(&temp_dir, "desk"), proven(&temp_dir, "other"));
+        store.pin("desk", &desk).unwrap();
+
+        assert_eq!(
+            store.check("desk", Some(&other)).unwrap(),
+            TrustCheck::Changed {
+                pinned: desk.to_string(),
+                proven: Some(other.to_string()),
+            }
+        );
+        // Dropping the identity altogether is a change too
+        assert!(matches!(
+            store.check("desk", None).unwrap(),
+            TrustCheck::Changed { proven: None, .. }
+        ));
+
+        let err = store
+            .verify("desk", Some(&other), TrustMode::Enforce)
+            .unwrap_err();
+        assert!(matches!(err, ConnectoError::IdentityMismatch(_)));
+        assert!(store.verify("desk", Some(&other), TrustMode::Warn).is_ok());
+
+        // Re-pinning replaces the old identity
+        store.pin("desk", &other).unwrap();
+        assert_eq!(
+            store.get("desk").unwrap().unwrap().fingerprint.as_deref(),
+            Some(other.fingerprint())
+        );
+    }
+
+    #[test]
+    fn test_trust_levels() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = store(&temp_dir);
+        let (desk, other) = (proven(&temp_dir, "desk"), proven(&temp_dir, "other"));
+
+        assert_eq!(
+            store.level("desk", Some(&desk)).unwrap(),
+            TrustLevel::Unknown
+        );
+        // A pinned device is known
+        store.pin("desk", &desk).unwrap();
+        assert_eq!(store.level("desk", Some(&desk)).unwrap(), TrustLevel::Known);
+
+        // Setting a level keeps the pin, and pinning again keeps the level
+        store.set_level("desk", TrustLevel::Trusted).unwrap();
+        store.pin("desk", &desk).unwrap();
+        assert_eq!(
+            store.level("desk", Some(&desk)).unwrap(),
+            TrustLevel::Trusted
+        );
+        assert_eq!(
+            store.check("desk", Some(&desk)).unwrap(),
+            TrustCheck::Trusted
+        );
+
+        // The name alone is worth nothing
+        assert_eq!(store.level("desk", None).unwrap(), TrustLevel::Unknown);
+        assert_eq!(
+            store.level("desk", Some(&other)).unwrap(),
+            TrustLevel::Unknown
+        );
+
+        // A level can be set before the first pairing, pinning nothing; it
+        // holds once the device's identity is pinned
+        store.set_level("laptop", TrustLevel::Trusted).unwrap();
+        assert_eq!(
+            store.check("laptop", Some(&other)).unwrap(),
+            TrustCheck::Unknown
+        );
+        assert_eq!(
+            store.level("laptop", Some(&other)).unwrap(),
+            TrustLevel::Unknown
+        );
+        store.pin("laptop", &other).unwrap();
+        assert_eq!(
+            store.level("laptop", Some(&other)).unwrap(),
+            TrustLevel::Trusted
+        );
+
+        assert!(!TrustLevel::Trusted.requires_code());
+        assert!(TrustLevel::Known.requires_code());
+        assert!(!TrustLevel::Known.requires_approval());
+        assert!(TrustLevel::Unknown.requires_approval());
+        assert_eq!(TrustLevel::Unknown.to_string(), "unknown");
+    }
+
+    #[test]
+    fn test_verify_identity() {
+        let temp_dir = TempDir::new().unwrap();
+        let (desk, other) = (proven(&temp_dir, "desk"), proven(&temp_dir, "other"));
+        let pinned = desk.fingerprint();
+        assert!(verify_identity("desk", pinned, Some(&desk), TrustMode::Enforce).is_ok());
+
+        let err = verify_identity("The device at 10.0.0.5", pinned, None, TrustMode::Enforce)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Identity mismatch: The device at 10.0.0.5 proved identity (none), but {} is pinned for it",
+                pinned
+            )
+        );
+        assert!(verify_identity("desk", pinned, Some(&other), TrustMode::Warn).is_ok());
+    }
+
+    #[test]
+    fn test_forget() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = store(&temp_dir);
+        let (desk, other) = (proven(&temp_dir, "desk"), proven(&temp_dir, "other"));
+        assert!(!store.forget("desk").unwrap());
+
+        store.pin("desk", &desk).unwrap();
+        store.pin("laptop", &other).unwrap();
+        assert!(store.forget("desk").unwrap());
+        assert_eq!(store.check("desk", None).unwrap(), TrustCheck::Unknown);
+        assert_eq!(store.all().unwrap().len(), 1);
+    }
+}
@@ -4,7 +4,10 @@
 
 use connecto_core::{
     keys::{KeyAlgorithm, KeyManager, SshKeyPair},
-    protocol::{HandshakeClient, HandshakeServer, Message, ServerEvent, PROTOCOL_VERSION},
+    protocol::{
+        HandshakeClient, HandshakeServer, Message, ProtocolErrorCode, ServerEvent, PROTOCOL_VERSION,
+    },
+    relay::{PendingChannel, RelayServer},
     DEFAULT_PORT,
 };
 use std::time::Duration;
@@ -88,6 +91,12 @@ fn test_protocol_messages() {
     let hello = Message::Hello {
         version: PROTOCOL_VERSION,
         device_name: "Test Device".to_string(),
+        timestamp: Some(1_700_000_000),
+        min_version: None,
+        capabilities: None,
+        identity: None,
+        nonce: None,
+        key_share: None,
     };
 
     let json = hello.to_json().unwrap();
@@ -97,9 +106,12 @@ fn test_protocol_messages() {
         Message::Hello {
             version,
             device_name,
+            timestamp,
+            ..
         } => {
             assert_eq!(version, PROTOCOL_VERSION);
             assert_eq!(device_name, "Test Device");
+            assert_eq!(timestamp, Some(1_700_000_000));
         }
         _ => panic!("Expected Hello message"),
     }
@@ -109,6 +121,14 @@ fn test_protocol_messages() {
         version: PROTOCOL_VERSION,
         device_name: "Server".to_string(),
         verification_code: Some("1234".to_string()),
+        identity: Some("SHA256:abc".to_string()),
+        pin_required: true,
+        timestamp: None,
+        capabilities: None,
+        users: Vec::new(),
+        identity_proof: None,
+        nonce: None,
+        key_share: None,
     };
 
     let json = hello_ack.to_json().unwrap();
@@ -119,10 +139,17 @@ fn test_protocol_messages() {
             version,
             device_name,
             verification_code,
+            identity,
+            pin_required,
+            timestamp,
+            ..
         } => {
             assert_eq!(version, PROTOCOL_VERSION);
             assert_eq!(device_name, "Server");
             assert_eq!(verification_code, Some("1234".to_string()));
+            assert_eq!(identity, Some("SHA256:abc".to_string()));
+            assert!(pin_required);
+            assert_eq!(timestamp, None);
         }
         _ => panic!("Expected HelloAck message"),
     }
@@ -131,6 +158,9 @@ fn test_protocol_messages() {
     let key_exchange = Message::KeyExchange {
         public_key: "ssh-ed25519 AAAA... test@connecto".to_string(),
         comment: "test@connecto".to_string(),
+        expires_in: Some(3_600),
+        fingerprint: Some("SHA256:abc".to_string()),
+        user: Some("deploy".to_string()),
     };
 
     let json = key_exchange.to_json().unwrap();
@@ -140,9 +170,15 @@ fn test_protocol_messages() {
         Message::KeyExchange {
             public_key,
             comment,
+            expires_in,
+            fingerprint,
+            user,
         } => {
             assert!(public_key.starts_with("ssh-ed25519"));
             assert_eq!(comment, "test@connecto");
+            assert_eq!(expires_in, Some(3_600));
+            assert_eq!(fingerprint.as_deref(), Some("SHA256:abc"));
+            assert_eq!(user.as_deref(), Some("deploy"));
         }
         _ => panic!("Expected KeyExchange message"),
     }
@@ -206,6 +242,56 @@ async fn test_full_pairing_workflow() {
         .any(|e| matches!(e, ServerEvent::PairingComplete { .. })));
 }
 
+/// Test pairing two devices through a relay
+#[tokio::test]
+async fn test_pairing_through_relay() {
+    let temp_dir = TempDir::new().unwrap();
+    let server_ssh_dir = temp_dir.path().join("server/.ssh");
+
+    let mut relay = RelayServer::new();
+    let relay_addr = relay.listen(0).await.unwrap();
+    let relay_addr = format!("127.0.0.1:{}", relay_addr.port());
+    let (relay_tx, _relay_rx) = mpsc::channel(10);
+    tokio::spawn(async move { relay.run(relay_tx).await });
+
+    // The listener opens a room and hands the pairing to the handshake server
+    let pending = PendingChannel::open(&relay_addr).await.unwrap();
+    let code = pending.code().clone();
+    let server = HandshakeServer::new(KeyManager::with_dir(server_ssh_dir.clone()), "Far Server");
+    let (event_tx, _event_rx) = mpsc::channel(10);
+    let server_task = tokio::spawn(async move {
+        let channel = pending.connect().await.unwrap();
+        let peer = channel.peer_addr();
+        server
+            .handle_stream(channel.into_stream(), peer, event_tx)
+            .await
+    });
+
+    // The client joins with the code and pairs over the channel
+    let channel = PendingChannel::join(&relay_addr, &code)
+        .await
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let address = channel.peer_addr().to_string();
+    let key_pair = SshKeyPair::generate(KeyAlgorithm::Ed25519, "client@relay").unwrap();
+    let client = HandshakeClient::new("Far Client");
+    let result = client
+        .pair_over(channel.into_stream(), &address, &key_pair)
+        .await
+        .unwrap();
+
+    assert_eq!(result.server_name, "Far Server");
+    server_task.await.unwrap().unwrap();
+
+    let authorized = KeyManager::with_dir(server_ssh_dir)
+        .list_authorized_keys()
+        .unwrap();
+    assert_eq!(authorized.len(), 1);
+    assert!(authorized[0].contains("client@relay"));
+}
+
 /// Test protocol version mismatch handling
 #[tokio::test]
 async fn test_protocol_version_mismatch() {
@@ -215,6 +301,12 @@ async fn test_protocol_version_mismatch() {
     let msg = Message::Hello {
         version: 999, // Invalid version
         device_name: "Bad Client".to_string(),
+        timestamp: None,
+        min_version: None,
+        capabilities: None,
+        identity: None,
+        nonce: None,
+        key_share: None,
     };
 
     let json = msg.to_json().unwrap();
@@ -232,7 +324,7 @@ async fn test_protocol_version_mismatch() {
 #[test]
 fn test_error_message() {
     let error_msg = Message::Error {
-        code: 42,
+        code: ProtocolErrorCode::InternalError,
         message: "Something went wrong".to_string(),
     };
 
@@ -241,7 +333,7 @@ fn test_error_message() {
 
     match parsed {
         Message::Error { code, message } => {
-            assert_eq!(code, 42);
+            assert_eq!(code, ProtocolErrorCode::InternalError);
             assert_eq!(message, "Something went wrong");
         }
         _ => panic!("Expected Error message"),
@@ -290,6 +382,12 @@ fn test_discovered_device() {
         ],
         port: DEFAULT_PORT,
         instance_name: "test-instance".to_string(),
+        identity: None,
+        scope: None,
+        external: None,
+        os: None,
+        version: None,
+        latency: None,
     };
 
     // Test primary address selection (should prefer first IPv4)
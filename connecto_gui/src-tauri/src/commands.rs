@@ -1,18 +1,44 @@
 //! Tauri commands for the GUI
 
 use connecto_core::{
+    attempts::PairingAttempts,
+    audit::{AuditLog, AuditRecord, DecisionLog},
+    batch::{BatchPairing, BatchProgress, PairingStatus},
+    clock,
+    connectivity::SSH_PORT,
     discovery::{
-        get_hostname, get_local_addresses, DiscoveredDevice, ServiceAdvertiser, ServiceBrowser,
+        self, get_hostname, get_local_addresses, DiscoveredDevice, DiscoveryEvent, EarlyExit,
+        ScanProgress, ServiceAdvertiser, ServiceBrowser, SubnetScanner, DEFAULT_PORT,
     },
-    keys::{KeyAlgorithm, KeyManager, SshKeyPair},
-    protocol::{HandshakeClient, HandshakeServer},
-    sync::SyncHandler,
+    identity::{DeviceIdentity, VerifiedIdentity},
+    keepwarm::{self, HostStatus, KeepWarm, Schedule, SshControlMaster},
+    keys::{
+        self, AuthorizedKeyEntry, KeyAlgorithm, KeyManager, KeygenProgress, PublicKeyInfo,
+        SshKeyPair,
+    },
+    known_hosts::KnownHostsStore,
+    net,
+    next_steps::{self, Event, NextStep, Situation},
+    notifications::Notifier,
+    pairings::{PairingDirection, PairingRecord, PairingStore},
+    ports,
+    power::{PowerEvent, PowerMonitor},
+    protocol::{ApprovalRequest, HandshakeClient, HandshakeServer, PinPrompt, ServerEvent},
+    renames,
+    session_log::{PairingAttempt, SessionLog},
+    ssh_client::{CheckFailure, CheckReport, SshCheck},
+    ssh_config::{self, HostEntry, SshConfig},
+    sync::{SyncEvent, SyncHandler},
+    tr,
+    trust::TrustStore,
+    ConnectoError,
 };
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
-use tauri::State;
+use tauri::{AppHandle, Manager, State};
 
-use crate::state::AppState;
+use crate::settings::{Settings, SettingsStore};
+use crate::state::{AppState, RunningScan, RunningServer};
 
 /// Device info for the frontend
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,6 +62,12 @@ impl From<(usize, &DiscoveredDevice)> for DeviceInfo {
     }
 }
 
+/// Payload of the `device-lost` event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceLost {
+    pub index: usize,
+}
+
 /// Pairing result for the frontend
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PairingInfo {
@@ -45,7 +77,332 @@ pub struct PairingInfo {
     pub ssh_command: String,
     pub private_key_path: String,
     pub public_key_path: String,
+    /// `Host` alias in `~/.ssh/config`, if the device has an entry there
+    pub host_alias: Option<String>,
+    /// Set when the device's clock is far from ours
+    pub clock_warning: Option<String>,
+    /// SHA-256 fingerprint of the key sent, to compare with the one the
+    /// device shows
+    pub key_fingerprint: Option<String>,
     pub error: Option<String>,
+    /// Suggestions shown as hint cards
+    pub next_steps: Vec<NextStep>,
+}
+
+impl PairingInfo {
+    fn failed(error: String) -> Self {
+        Self {
+            success: false,
+            server_name: String::new(),
+            ssh_user: String::new(),
+            ssh_command: String::new(),
+            private_key_path: String::new(),
+            public_key_path: String::new(),
+            host_alias: None,
+            clock_warning: None,
+            key_fingerprint: None,
+            error: Some(error),
+            next_steps: Vec::new(),
+        }
+    }
+}
+
+/// Progress of one device in a `pair_with_devices` batch, sent as a
+/// `pairing-progress` event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingProgress {
+    pub device_index: usize,
+    #[serde(flatten)]
+    pub status: PairingStatus,
+}
+
+/// Whether the listener's advertisement is suspended while the machine
+/// sleeps, sent as a `listener-power` event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerPower {
+    pub suspended: bool,
+}
+
+/// What the tray shows, sent as a `tray-status` event whenever it changes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrayStatus {
+    pub listening: bool,
+    /// Whether the listener's advertisement is suspended while asleep
+    pub suspended: bool,
+    /// Name and port of the running listener
+    pub device_name: Option<String>,
+    pub port: Option<u16>,
+    /// Pairings made in the last [`RECENT_PAIRINGS_SECS`]
+    pub recent_pairings: usize,
+    /// Pairing and sync requests waiting for the user to approve them
+    pub pending_approvals: usize,
+    pub syncing: bool,
+    pub sync_message: String,
+}
+
+impl TrayStatus {
+    /// What the listener is doing
+    pub fn listener_label(&self) -> String {
+        match (&self.device_name, self.port) {
+            (Some(name), Some(port)) if self.listening && self.suspended => {
+                format!(
+                    "Listening as {} on port {}, paused while asleep",
+                    name, port
+                )
+            }
+            (Some(name), Some(port)) if self.listening => {
+                format!("Listening as {} on port {}", name, port)
+            }
+            _ => "Not listening".to_string(),
+        }
+    }
+
+    pub fn pairings_label(&self) -> String {
+        match self.recent_pairings {
+            0 => "No pairings in the last day".to_string(),
+            1 => "1 pairing in the last day".to_string(),
+            n => format!("{} pairings in the last day", n),
+        }
+    }
+
+    pub fn approvals_label(&self) -> String {
+        match self.pending_approvals {
+            0 => "No requests waiting".to_string(),
+            1 => "1 request waiting for approval".to_string(),
+            n => format!("{} requests waiting for approval", n),
+        }
+    }
+}
+
+/// A quick action offered from the tray
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TrayAction {
+    /// Start listening on the default port
+    StartListener,
+    StopListener,
+    /// Wait for another device to sync for [`SYNC_WINDOW_SECS`]
+    OpenSyncWindow,
+    CloseSyncWindow,
+}
+
+/// How far back pairings count as recent in the tray
+pub const RECENT_PAIRINGS_SECS: u64 = 24 * 60 * 60;
+
+/// How long a sync window opened from the tray stays open
+pub const SYNC_WINDOW_SECS: u64 = 60;
+
+/// How long a scan waits for another device after the last new one,
+/// unless told otherwise
+pub const SCAN_QUIET_PERIOD_MS: u64 = 1500;
+
+/// Something that happened on the running listener, sent as a
+/// `listener-event` event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ListenerEvent {
+    ClientConnected {
+        address: String,
+    },
+    PairingRequest {
+        device_name: String,
+        address: String,
+    },
+    /// Show this code; the client has to enter it
+    VerificationCode {
+        device_name: String,
+        code: String,
+    },
+    KeyReceived {
+        device_name: String,
+        comment: String,
+        fingerprint: String,
+    },
+    PairingComplete {
+        device_name: String,
+    },
+    PairingRejected {
+        device_name: String,
+    },
+    ApprovalTimedOut {
+        device_name: String,
+        accepted: bool,
+    },
+    ClockSkew {
+        device_name: String,
+        warning: String,
+    },
+    /// The access list refused the client
+    AccessDenied {
+        device_name: String,
+        address: String,
+        reason: String,
+    },
+    /// A client had its key removed
+    KeyRevoked {
+        device_name: String,
+        address: String,
+        fingerprint: String,
+    },
+    Error {
+        message: String,
+    },
+    /// The listener stopped without being asked to
+    Stopped {
+        error: String,
+    },
+}
+
+impl ListenerEvent {
+    /// The frontend's version of a server event; `None` for events it
+    /// already knows about
+    fn from_server(event: ServerEvent) -> Option<Self> {
+        Some(match event {
+            ServerEvent::Started { .. } => return None,
+            ServerEvent::ClientConnected { address } => Self::ClientConnected {
+                address: address.to_string(),
+            },
+            ServerEvent::PairingRequest {
+                device_name,
+                address,
+            } => Self::PairingRequest {
+                device_name,
+                address: address.to_string(),
+            },
+            ServerEvent::VerificationCode { device_name, code } => {
+                Self::VerificationCode { device_name, code }
+            }
+            ServerEvent::KeyReceived {
+                device_name,
+                comment,
+                fingerprint,
+            } => Self::KeyReceived {
+                device_name,
+                comment,
+                fingerprint,
+            },
+            ServerEvent::PairingComplete { device_name } => Self::PairingComplete { device_name },
+            ServerEvent::PairingRejected { device_name } => Self::PairingRejected { device_name },
+            ServerEvent::ApprovalTimedOut {
+                device_name,
+                accepted,
+            } => Self::ApprovalTimedOut {
+                device_name,
+                accepted,
+            },
+            ServerEvent::ClockSkew {
+                device_name,
+                skew_secs,
+            } => Self::ClockSkew {
+                warning: clock_warning(&device_name, Some(skew_secs))?,
+                device_name,
+            },
+            ServerEvent::AccessDenied {
+                device_name,
+                address,
+                reason,
+            } => Self::AccessDenied {
+                device_name,
+                address: address.to_string(),
+                reason,
+            },
+            ServerEvent::KeyRevoked {
+                device_name,
+                address,
+                fingerprint,
+            } => Self::KeyRevoked {
+                device_name,
+                address: address.to_string(),
+                fingerprint,
+            },
+            // `get_listener_session` reports the ones that were pairings
+            ServerEvent::PairingFailed { .. } => return None,
+            ServerEvent::Error { message } => Self::Error { message },
+        })
+    }
+}
+
+/// A listener asking for its verification code, sent as a `pin-requested`
+/// event; answer it with `enter_pin`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinRequested {
+    pub address: String,
+    pub server_name: String,
+    pub attempts_left: u32,
+}
+
+/// A device's key waiting for approval, sent as a `sync-approval` event
+/// for a sync peer and a `listener-approval` event for a client of the
+/// listener; answer them with `answer_sync_approval` and
+/// `answer_listener_approval`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApprovalRequested {
+    pub device_name: String,
+    pub address: String,
+    pub fingerprint: String,
+    pub comment: String,
+}
+
+impl From<&ApprovalRequest> for ApprovalRequested {
+    fn from(request: &ApprovalRequest) -> Self {
+        Self {
+            device_name: request.device_name.clone(),
+            address: request.address.to_string(),
+            fingerprint: request.fingerprint.clone(),
+            comment: request.comment.clone(),
+        }
+    }
+}
+
+/// Why the listener could not start, for the frontend
+///
+/// A taken port comes with what holds it and a free port to offer instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ListenerError {
+    PortInUse {
+        message: String,
+        port: u16,
+        /// The process holding the port, if it could be found
+        owner: Option<String>,
+        /// Whether the owner is another Connecto instance
+        owner_is_connecto: bool,
+        suggested_port: Option<u16>,
+    },
+    Other {
+        message: String,
+    },
+}
+
+impl From<ConnectoError> for ListenerError {
+    fn from(e: ConnectoError) -> Self {
+        match &e {
+            ConnectoError::PortInUse { port, owner } => Self::PortInUse {
+                message: e.to_string(),
+                port: *port,
+                owner: owner.as_ref().map(|owner| owner.to_string()),
+                owner_is_connecto: owner.as_ref().is_some_and(|owner| owner.is_connecto()),
+                suggested_port: ports::suggest_free_port(*port),
+            },
+            _ => Self::Other {
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl ListenerError {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::PortInUse { message, .. } | Self::Other { message } => message,
+        }
+    }
+}
+
+impl From<String> for ListenerError {
+    fn from(message: String) -> Self {
+        Self::Other { message }
+    }
 }
 
 /// Server status for the frontend
@@ -64,6 +421,62 @@ pub struct PairedHost {
     pub hostname: String,
     pub user: String,
     pub identity_file: String,
+    /// Keep-warm schedule, e.g. `mon-fri 09:00-18:00`
+    pub keep_warm: Option<String>,
+}
+
+/// Result of logging in to a paired host with the built-in SSH client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ConnectionTest {
+    Passed {
+        server_version: String,
+        /// Fingerprint of the server's host key
+        host_key: String,
+    },
+    Failed {
+        /// Which step failed: `resolve`, `connect`, `timeout`, `protocol`,
+        /// `unknown_host_key`, `host_key_mismatch`, `key`, `key_permissions`
+        /// or `rejected`
+        reason: String,
+        message: String,
+    },
+}
+
+impl From<CheckReport> for ConnectionTest {
+    fn from(report: CheckReport) -> Self {
+        Self::Passed {
+            server_version: report.server_version,
+            host_key: report.host_key,
+        }
+    }
+}
+
+impl From<CheckFailure> for ConnectionTest {
+    fn from(failure: CheckFailure) -> Self {
+        let reason = match &failure {
+            CheckFailure::Resolve(_) => "resolve",
+            CheckFailure::Connect(_) => "connect",
+            CheckFailure::Timeout(_) => "timeout",
+            CheckFailure::Protocol(_) => "protocol",
+            CheckFailure::UnknownHostKey { .. } => "unknown_host_key",
+            CheckFailure::HostKeyMismatch { .. } => "host_key_mismatch",
+            CheckFailure::Key(_) => "key",
+            CheckFailure::KeyPermissions(_) => "key_permissions",
+            CheckFailure::Rejected { .. } => "rejected",
+        };
+        Self::Failed {
+            reason: reason.to_string(),
+            message: failure.to_string(),
+        }
+    }
+}
+
+/// Whether hosts are kept warm, and how each is doing
+#[derive(Debug, Clone, Serialize)]
+pub struct KeepWarmStatus {
+    pub running: bool,
+    pub hosts: Vec<HostStatus>,
 }
 
 /// Local SSH key information
@@ -71,6 +484,8 @@ pub struct PairedHost {
 pub struct LocalKeyInfo {
     pub name: String,
     pub algorithm: String,
+    /// Key size in bits, if known
+    pub bits: Option<u32>,
     pub comment: String,
     pub private_key_path: String,
     pub public_key_path: String,
@@ -78,10 +493,10 @@ pub struct LocalKeyInfo {
     pub created: Option<String>,
 }
 
-/// Get the current hostname
+/// Get the name this device is known by
 #[tauri::command]
 pub fn get_device_name() -> String {
-    get_hostname()
+    discovery::get_device_name()
 }
 
 /// Get local IP addresses
@@ -95,37 +510,175 @@ pub fn get_addresses() -> Vec<String> {
 }
 
 /// Scan for devices on the network
+///
+/// The mDNS search ends once `min_devices` were found, or when no new device
+/// appeared for `quiet_period_ms` ([`SCAN_QUIET_PERIOD_MS`] by default). Falls
+/// back to probing the local subnets when mDNS finds nothing, emitting a
+/// `scan-progress` event as hosts are probed.
 #[tauri::command]
 pub async fn scan_devices(
     timeout_secs: u64,
+    min_devices: Option<usize>,
+    quiet_period_ms: Option<u64>,
+    app: AppHandle,
     state: State<'_, AppState>,
 ) -> Result<Vec<DeviceInfo>, String> {
+    // The results replace the live scan's devices and their indices
+    if let Some(scan) = state.scan.lock().await.take() {
+        scan.stop();
+    }
+
     let browser = ServiceBrowser::new().map_err(|e| e.to_string())?;
 
-    let devices = browser
-        .scan_for_duration(Duration::from_secs(timeout_secs))
+    let mut early_exit = EarlyExit::default().with_quiet_period(Duration::from_millis(
+        quiet_period_ms.unwrap_or(SCAN_QUIET_PERIOD_MS),
+    ));
+    if let Some(count) = min_devices {
+        early_exit = early_exit.with_min_devices(count);
+    }
+    let mut devices = browser
+        .scan_until(Duration::from_secs(timeout_secs), early_exit)
         .await
         .map_err(|e| e.to_string())?;
 
+    if devices.is_empty() {
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel::<ScanProgress>(256);
+        let app = app.clone();
+        let forwarder = tokio::spawn(async move {
+            while let Some(progress) = progress_rx.recv().await {
+                let _ = app.emit_all("scan-progress", progress);
+            }
+        });
+        devices = SubnetScanner::new(DEFAULT_PORT, Duration::from_millis(500))
+            .with_progress(progress_tx)
+            .scan()
+            .await;
+        let _ = forwarder.await;
+    }
+
+    // Follow paired devices that came back at a different address
+    if let Ok(config) = SshConfig::new() {
+        for device in &devices {
+            if let (Some(identity), Some(address)) = (&device.identity, device.primary_host()) {
+                let _ = config.update_address(identity, &address);
+            }
+        }
+    }
+    follow_renames(&app, &devices);
+
     // Store devices in state
-    {
-        let mut cached = state.discovered_devices.lock().await;
-        *cached = devices.clone();
+    let mut cached = state.discovered_devices.lock().await;
+    cached.clear();
+    for device in devices {
+        cached.insert(device);
     }
 
-    Ok(devices
+    Ok(cached
+        .devices()
         .iter()
-        .enumerate()
-        .map(|(i, d)| DeviceInfo::from((i, d)))
+        .map(|(i, d)| DeviceInfo::from((*i, d)))
         .collect())
 }
 
+/// Record the new names of paired devices among `devices`
+///
+/// Sends `device-renamed` with a [`renames::Rename`] for each, so the
+/// frontend can offer to rename the host aliases made from the old name.
+fn follow_renames(app: &AppHandle, devices: &[DiscoveredDevice]) {
+    let (Ok(store), Ok(config)) = (PairingStore::new(), SshConfig::new()) else {
+        return;
+    };
+    if let Ok(renamed) = renames::refresh(&store, &config, devices) {
+        for rename in renamed {
+            let _ = app.emit_all("device-renamed", rename);
+        }
+    }
+}
+
+/// Start scanning for devices until `stop_scan`
+///
+/// Emits `device-found` with a [`DeviceInfo`] as devices appear and
+/// `device-lost` with a [`DeviceLost`] when they go away. A device seen
+/// again keeps its index, so indices stay valid for `pair_with_device`.
+/// Lost devices stay paired-able until the store fills up and forgets the
+/// ones seen least recently, which are reported as lost too.
+#[tauri::command]
+pub async fn start_scan(app: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
+    if let Some(previous) = state.scan.lock().await.take() {
+        previous.stop();
+    }
+    state.discovered_devices.lock().await.clear();
+
+    let browser = ServiceBrowser::new().map_err(|e| e.to_string())?;
+    let mut events = browser.browse().map_err(|e| e.to_string())?;
+
+    let task = tokio::spawn(async move {
+        let config = SshConfig::new().ok();
+        while let Some(event) = events.recv().await {
+            let state = app.state::<AppState>();
+            match event {
+                DiscoveryEvent::DeviceFound(device) => {
+                    let device = *device;
+                    // Follow paired devices that came back at a different address
+                    if let (Some(config), Some(identity), Some(address)) =
+                        (&config, &device.identity, device.primary_host())
+                    {
+                        let _ = config.update_address(identity, &address);
+                    }
+                    follow_renames(&app, std::slice::from_ref(&device));
+                    let mut info = DeviceInfo::from((0, &device));
+                    let stored = state.discovered_devices.lock().await.insert(device);
+                    if let Some(index) = stored.evicted {
+                        let _ = app.emit_all("device-lost", DeviceLost { index });
+                    }
+                    info.index = stored.index;
+                    let _ = app.emit_all("device-found", info);
+                }
+                DiscoveryEvent::DeviceLost(instance_name) => {
+                    let index = state
+                        .discovered_devices
+                        .lock()
+                        .await
+                        .index_of(&instance_name);
+                    if let Some(index) = index {
+                        let _ = app.emit_all("device-lost", DeviceLost { index });
+                    }
+                }
+                DiscoveryEvent::SearchStarted => {}
+                DiscoveryEvent::SearchStopped => break,
+            }
+        }
+    });
+
+    *state.scan.lock().await = Some(RunningScan { browser, task });
+    Ok(())
+}
+
+/// Stop the scan started by `start_scan`
+#[tauri::command]
+pub async fn stop_scan(state: State<'_, AppState>) -> Result<(), String> {
+    if let Some(scan) = state.scan.lock().await.take() {
+        scan.stop();
+    }
+    let stats = state.discovered_devices.lock().await.stats();
+    tracing::debug!(
+        "Scan kept {} of at most {} devices ({} forgotten, ~{} bytes)",
+        stats.devices,
+        stats.capacity,
+        stats.evicted,
+        stats.bytes
+    );
+    Ok(())
+}
+
 /// Pair with a device by index
 #[tauri::command]
 pub async fn pair_with_device(
     device_index: usize,
     use_rsa: bool,
     custom_comment: Option<String>,
+    add_to_ssh_config: Option<bool>,
+    app: AppHandle,
     state: State<'_, AppState>,
 ) -> Result<PairingInfo, String> {
     // Get the device from cache
@@ -133,7 +686,6 @@ pub async fn pair_with_device(
         let devices = state.discovered_devices.lock().await;
         devices
             .get(device_index)
-            .cloned()
             .ok_or_else(|| "Device not found. Please scan again.".to_string())?
     };
 
@@ -141,15 +693,21 @@ pub async fn pair_with_device(
         .connection_string()
         .ok_or_else(|| "Device has no IP address".to_string())?;
 
-    pair_with_address(address, use_rsa, custom_comment).await
+    pair_with_address(address, use_rsa, custom_comment, add_to_ssh_config, app).await
 }
 
 /// Pair with a device by address
+///
+/// Emits a `pin-requested` event when the device asks for the verification
+/// code it shows. Unless `add_to_ssh_config` is `false`, the device gets an
+/// entry in `~/.ssh/config`, as with `connecto pair`.
 #[tauri::command]
 pub async fn pair_with_address(
     address: String,
     use_rsa: bool,
     custom_comment: Option<String>,
+    add_to_ssh_config: Option<bool>,
+    app: AppHandle,
 ) -> Result<PairingInfo, String> {
     // Determine algorithm
     let algorithm = if use_rsa {
@@ -165,72 +723,355 @@ pub async fn pair_with_address(
         format!("{}@{}", user, hostname)
     });
 
-    // Generate key pair
-    let key_pair = SshKeyPair::generate(algorithm, &comment).map_err(|e| e.to_string())?;
+    // Refuse a second click while this device is still being paired with,
+    // and send the same key again on a quick retry
+    let attempt = match PairingAttempts::new().and_then(|attempts| attempts.begin(&address)) {
+        Ok(attempt) => Some(attempt),
+        Err(e @ ConnectoError::PairingInProgress(_)) => {
+            return Ok(PairingInfo::failed(e.to_string()))
+        }
+        Err(e) => {
+            tracing::warn!("Duplicate pairing attempts will not be detected: {}", e);
+            None
+        }
+    };
+    let recent_key = attempt
+        .as_ref()
+        .and_then(|attempt| attempt.recent_key(algorithm))
+        .filter(|key| key.comment == comment);
+    let key_pair = match recent_key {
+        Some(key_pair) => key_pair,
+        None => {
+            let key_pair = generate_reporting(algorithm, &comment, &app).await?;
+            if let Some(attempt) = &attempt {
+                if let Err(e) = attempt.remember_key(&key_pair) {
+                    tracing::warn!("A retry will need a new key: {}", e);
+                }
+            }
+            key_pair
+        }
+    };
 
     // Create client and pair
-    let client = HandshakeClient::new(&get_hostname());
+    let mut client = HandshakeClient::new(&discovery::get_device_name());
+    if let Ok(store) = TrustStore::new() {
+        client = client.with_trust_store(store);
+    }
+    if let Ok(pins) = SshConfig::new().and_then(|config| config.identity_pins()) {
+        client = client.with_address_pins(pins);
+    }
+    if let Ok(identity) = DeviceIdentity::load_or_create() {
+        client = client.with_identity(&identity);
+    }
+    let (pin_tx, pin_rx) = tokio::sync::mpsc::channel(4);
+    client = client.with_pin_prompt(pin_tx);
+    let prompter = tokio::spawn(forward_pin_prompts(pin_rx, app.clone()));
+
     let result = client.pair(&address, &key_pair).await;
+    prompter.abort();
+    app.state::<AppState>()
+        .pin_prompts
+        .lock()
+        .await
+        .remove(&address);
 
     match result {
         Ok(pairing_result) => {
             // Save the key locally
             let key_manager = KeyManager::new().map_err(|e| e.to_string())?;
-            let key_name = format!(
-                "connecto_{}",
-                pairing_result
-                    .server_name
-                    .chars()
-                    .map(|c| if c.is_alphanumeric() { c } else { '_' })
-                    .collect::<String>()
-                    .to_lowercase()
-            );
+            let alias = ssh_config::host_alias(pairing_result.peer_name());
+            let key_name = format!("connecto_{}", alias);
 
             let (private_path, public_path) = key_manager
                 .save_key_pair(&key_pair, &key_name)
                 .map_err(|e| e.to_string())?;
 
-            let ip = address.split(':').next().unwrap_or(&address);
-            let ssh_command = format!(
-                "ssh -i {} {}@{}",
-                private_path.display(),
-                pairing_result.ssh_user,
-                ip
+            let ip = net::host_of(&address);
+            let mut ssh_config_failed = false;
+            let host_alias = if add_to_ssh_config.unwrap_or(true) {
+                let entry = HostEntry {
+                    host: alias,
+                    hostname: ip.to_string(),
+                    user: pairing_result.ssh_user.clone(),
+                    port: Some(pairing_result.ssh_port),
+                    identity_file: private_path.display().to_string(),
+                    identity: pairing_result
+                        .server_identity
+                        .as_ref()
+                        .map(|identity| identity.fingerprint().to_string()),
+                    ..Default::default()
+                };
+                add_ssh_config_entry(&entry).unwrap_or_else(|e| {
+                    tracing::warn!("Failed to add {} to the SSH config: {}", entry.host, e);
+                    ssh_config_failed = true;
+                    None
+                })
+            } else {
+                None
+            };
+
+            record_pairing(
+                PairingRecord::new(
+                    pairing_result.peer_name(),
+                    &key_pair.public_key,
+                    ip,
+                    PairingDirection::Outgoing,
+                )
+                .map(|r| {
+                    let r = r
+                        .with_key_path(&private_path.to_string_lossy())
+                        .with_ssh_port(pairing_result.ssh_port)
+                        .with_peer_identity(
+                            pairing_result
+                                .server_identity
+                                .as_ref()
+                                .map(VerifiedIdentity::fingerprint),
+                        )
+                        .with_clock_skew(pairing_result.clock_skew);
+                    match &host_alias {
+                        Some(alias) => r.with_host(alias),
+                        None => r,
+                    }
+                }),
             );
+            emit_tray_status(&app).await;
+
+            // Trust the host keys the device sent, so the first `ssh` doesn't ask
+            if !pairing_result.host_keys.is_empty() {
+                let recorded = KnownHostsStore::new().and_then(|store| {
+                    store.record(ip, pairing_result.ssh_port, &pairing_result.host_keys)
+                });
+                if let Err(e) = recorded {
+                    tracing::warn!("Failed to add host keys of {}: {}", ip, e);
+                }
+            }
+
+            let ssh_command = match &host_alias {
+                Some(alias) => format!("ssh {}", alias),
+                None => format!(
+                    "ssh -i {}{} {}@{}",
+                    private_path.display(),
+                    match pairing_result.ssh_port {
+                        SSH_PORT => String::new(),
+                        port => format!(" -p {}", port),
+                    },
+                    pairing_result.ssh_user,
+                    ip
+                ),
+            };
+
+            let next_steps = next_steps::recommend(&Situation {
+                host: host_alias.clone(),
+                ssh_command: Some(ssh_command.clone()),
+                ssh_config_failed,
+                key_path: Some(private_path.display().to_string()),
+                key_in_agent: next_steps::key_in_agent(&key_pair),
+                ..Default::default()
+            });
 
             Ok(PairingInfo {
                 success: true,
-                server_name: pairing_result.server_name,
+                server_name: pairing_result.peer_name().to_string(),
+                clock_warning: clock_warning(pairing_result.peer_name(), pairing_result.clock_skew),
                 ssh_user: pairing_result.ssh_user,
                 ssh_command,
                 private_key_path: private_path.to_string_lossy().to_string(),
                 public_key_path: public_path.to_string_lossy().to_string(),
+                host_alias,
+                key_fingerprint: Some(pairing_result.key_fingerprint),
                 error: None,
+                next_steps,
             })
         }
-        Err(e) => Ok(PairingInfo {
-            success: false,
-            server_name: String::new(),
-            ssh_user: String::new(),
-            ssh_command: String::new(),
-            private_key_path: String::new(),
-            public_key_path: String::new(),
-            error: Some(e.to_string()),
-        }),
+        Err(e) => {
+            let mut info = PairingInfo::failed(e.to_string());
+            if matches!(e, ConnectoError::IdentityMismatch(_)) {
+                info.next_steps = next_steps::recommend(&Situation {
+                    address: Some(address),
+                    identity_changed: true,
+                    ..Default::default()
+                });
+            }
+            Ok(info)
+        }
+    }
+}
+
+/// Add a freshly paired device to `~/.ssh/config`, returning its alias
+///
+/// A Connecto entry already under the alias is kept, and bound to the
+/// device's identity if it had none. `None` if the alias is taken by a host
+/// Connecto did not write.
+fn add_ssh_config_entry(entry: &HostEntry) -> connecto_core::Result<Option<String>> {
+    let config = SshConfig::new()?;
+    if config.add_entry(entry)? {
+        return Ok(Some(entry.host.clone()));
+    }
+    let Some(existing) = config.entries()?.into_iter().find(|e| e.host == entry.host) else {
+        return Ok(None);
+    };
+    if let (None, Some(identity)) = (&existing.identity, &entry.identity) {
+        config.set_identity(&entry.host, identity, &entry.hostname)?;
+    }
+    Ok(Some(entry.host.clone()))
+}
+
+/// Pair with several devices at once, by index
+///
+/// Emits a `pairing-progress` event each time a device is queued, starts
+/// pairing, or finishes. Returns one result per device, in the order given.
+#[tauri::command]
+pub async fn pair_with_devices(
+    device_indices: Vec<usize>,
+    use_rsa: bool,
+    custom_comment: Option<String>,
+    add_to_ssh_config: Option<bool>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Vec<PairingInfo>, String> {
+    let addresses = {
+        let devices = state.discovered_devices.lock().await;
+        device_indices
+            .iter()
+            .map(|&index| {
+                let device = devices
+                    .get(index)
+                    .ok_or_else(|| "Device not found. Please scan again.".to_string())?;
+                device
+                    .connection_string()
+                    .ok_or_else(|| format!("{} has no IP address", device.name))
+            })
+            .collect::<Result<Vec<_>, String>>()?
+    };
+
+    // Forward progress to the frontend, keyed by the device index it knows
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel::<BatchProgress>(16);
+    let progress_app = app.clone();
+    let forwarder = tokio::spawn(async move {
+        while let Some(progress) = progress_rx.recv().await {
+            let _ = progress_app.emit_all(
+                "pairing-progress",
+                PairingProgress {
+                    device_index: device_indices[progress.target],
+                    status: progress.status,
+                },
+            );
+        }
+    });
+
+    let results = BatchPairing::new()
+        .with_progress(progress_tx)
+        .run(addresses, |address| {
+            let custom_comment = custom_comment.clone();
+            let app = app.clone();
+            async move {
+                let info =
+                    pair_with_address(address, use_rsa, custom_comment, add_to_ssh_config, app)
+                        .await?;
+                match &info.error {
+                    Some(error) => Err(error.clone()),
+                    None => Ok(info),
+                }
+            }
+        })
+        .await;
+    let _ = forwarder.await;
+
+    Ok(results
+        .into_iter()
+        .map(|result| result.unwrap_or_else(PairingInfo::failed))
+        .collect())
+}
+
+/// Hand each verification code prompt to the frontend
+///
+/// The prompt waits in the app state, keyed by address, until `enter_pin`
+/// answers it.
+async fn forward_pin_prompts(mut pin_rx: tokio::sync::mpsc::Receiver<PinPrompt>, app: AppHandle) {
+    while let Some(prompt) = pin_rx.recv().await {
+        let requested = PinRequested {
+            address: prompt.address.clone(),
+            server_name: prompt.server_name.clone(),
+            attempts_left: prompt.attempts_left,
+        };
+        app.state::<AppState>()
+            .pin_prompts
+            .lock()
+            .await
+            .insert(prompt.address.clone(), prompt);
+        let _ = app.emit_all("pin-requested", requested);
+    }
+}
+
+/// Answer a `pin-requested` event; no PIN gives up on the pairing
+#[tauri::command]
+pub async fn enter_pin(
+    address: String,
+    pin: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let prompt = state
+        .pin_prompts
+        .lock()
+        .await
+        .remove(&address)
+        .ok_or_else(|| format!("{} is not waiting for a verification code", address))?;
+    if let Some(pin) = pin {
+        prompt.respond(&pin);
+    }
+    Ok(())
+}
+
+/// Warning for the frontend if `peer_name`'s clock is too far from ours
+fn clock_warning(peer_name: &str, skew: Option<i64>) -> Option<String> {
+    skew.filter(|s| clock::is_large(*s)).map(|skew| {
+        format!(
+            "The clock of {} is {} compared to this computer's",
+            peer_name,
+            clock::describe(skew)
+        )
+    })
+}
+
+/// Add a pairing to the pairing database; failures only cost history
+fn record_pairing(record: connecto_core::Result<PairingRecord>) {
+    if let Err(e) = record.and_then(|r| PairingStore::new()?.record(r)) {
+        tracing::warn!("Failed to record pairing: {}", e);
     }
 }
 
 /// Start the listener server
+///
+/// The advertisement is withdrawn while the machine sleeps, emitting a
+/// `listener-power` event on every change. Pairing requests and results
+/// also show as desktop notifications, unless `notifications` or the
+/// settings turn them off. With `approve_pairings` in the settings, keys
+/// from unknown devices wait for `answer_listener_approval`. A taken port
+/// fails with [`ListenerError::PortInUse`].
 #[tauri::command]
 pub async fn start_listener(
     port: u16,
     device_name: Option<String>,
+    notifications: Option<bool>,
+    app: AppHandle,
     state: State<'_, AppState>,
-) -> Result<ServerStatus, String> {
-    let name = device_name.unwrap_or_else(get_hostname);
+) -> Result<ServerStatus, ListenerError> {
+    let name = device_name.unwrap_or_else(discovery::get_device_name);
+    let identity = DeviceIdentity::load_or_create().ok();
+
+    // A listener started again replaces the running one
+    if let Some(running) = state.server.lock().await.take() {
+        running.stop().await;
+    }
+
+    // Find a taken port before advertising it
+    ports::check_available(port)?;
 
     // Start mDNS advertiser
     let mut advertiser = ServiceAdvertiser::new().map_err(|e| e.to_string())?;
+    if let Some(identity) = &identity {
+        advertiser = advertiser.with_identity(identity.fingerprint());
+    }
     advertiser
         .advertise(&name, port)
         .map_err(|e| e.to_string())?;
@@ -244,7 +1085,83 @@ pub async fn start_listener(
     // Start handshake server
     let key_manager = KeyManager::new().map_err(|e| e.to_string())?;
     let mut server = HandshakeServer::new(key_manager, &name);
-    let addr = server.listen(port).await.map_err(|e| e.to_string())?;
+    if let Some(identity) = &identity {
+        server = server.with_identity(identity);
+    }
+    // Devices that are not trusted enter the code shown in a `verification_code` event
+    if let Ok(store) = TrustStore::new() {
+        server = server.with_trust_levels(store);
+    }
+    if let Ok(store) = PairingStore::new() {
+        server = server.with_pairing_store(store);
+    }
+    if let Ok(log) = DecisionLog::new() {
+        server = server.with_decision_log(log);
+    }
+    let settings = state.settings.lock().await.clone();
+    // Unknown devices also wait for the user to approve them
+    if settings.approve_pairings {
+        let (approval_tx, approval_rx) = tokio::sync::mpsc::channel(4);
+        server = server.with_approval(approval_tx);
+        tokio::spawn(forward_listener_approvals(approval_rx, app.clone()));
+    }
+    let addr = server.listen(port).await?;
+    *state.listener_session.lock().await = SessionLog::new();
+
+    // Accept pairings until stop_listener shuts the server down
+    let (event_tx, mut event_rx) = tokio::sync::mpsc::channel(10);
+    let events_app = app.clone();
+    let notifier = if notifications.unwrap_or(settings.notifications) {
+        Notifier::new()
+    } else {
+        Notifier::disabled()
+    };
+    tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            tracing::info!("Listener: {:?}", event);
+            notifier.notify_server_event(&event);
+            events_app
+                .state::<AppState>()
+                .listener_session
+                .lock()
+                .await
+                .record(&event);
+            // The tray counts pairings and requests waiting for approval
+            let changes_tray = matches!(
+                event,
+                ServerEvent::PairingComplete { .. }
+                    | ServerEvent::PairingRejected { .. }
+                    | ServerEvent::ApprovalTimedOut { .. }
+            );
+            if let Some(event) = ListenerEvent::from_server(event) {
+                let _ = events_app.emit_all("listener-event", event);
+            }
+            if changes_tray {
+                emit_tray_status(&events_app).await;
+            }
+        }
+    });
+    let shutdown = server.shutdown_handle();
+    let server_app = app.clone();
+    let task = tokio::spawn(async move {
+        if let Err(e) = server.run(event_tx).await {
+            tracing::warn!("Listener stopped: {}", e);
+            *server_app.state::<AppState>().is_listening.lock().await = false;
+            let _ = server_app.emit_all(
+                "listener-event",
+                ListenerEvent::Stopped {
+                    error: e.to_string(),
+                },
+            );
+            emit_tray_status(&server_app).await;
+        }
+    });
+    *state.server.lock().await = Some(RunningServer {
+        shutdown,
+        task,
+        port: addr.port(),
+        device_name: name.clone(),
+    });
 
     // Store listening state
     {
@@ -252,6 +1169,15 @@ pub async fn start_listener(
         *listening = true;
     }
 
+    // Follow sleep and wake
+    {
+        let mut power_watch = state.power_watch.lock().await;
+        if let Some(watch) = power_watch.replace(tokio::spawn(follow_power_events(app.clone()))) {
+            watch.abort();
+        }
+    }
+    emit_tray_status(&app).await;
+
     // Get addresses for display
     let addresses: Vec<String> = get_local_addresses()
         .iter()
@@ -269,7 +1195,7 @@ pub async fn start_listener(
 
 /// Stop the listener server
 #[tauri::command]
-pub async fn stop_listener(state: State<'_, AppState>) -> Result<(), String> {
+pub async fn stop_listener(app: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
     // Stop advertiser
     {
         let mut adv = state.advertiser.lock().await;
@@ -279,31 +1205,136 @@ pub async fn stop_listener(state: State<'_, AppState>) -> Result<(), String> {
         *adv = None;
     }
 
+    // Stop accepting pairings and release the port
+    if let Some(running) = state.server.lock().await.take() {
+        running.stop().await;
+    }
+
+    if let Some(watch) = state.power_watch.lock().await.take() {
+        watch.abort();
+    }
+    // Requests can no longer be answered once the server is gone
+    state.listener_approvals.lock().await.clear();
+
     // Update listening state
     {
         let mut listening = state.is_listening.lock().await;
         *listening = false;
     }
+    emit_tray_status(&app).await;
 
     Ok(())
 }
 
+/// Hand each pairing request waiting for approval to the frontend
+///
+/// Requests wait in the app state, by client address, until
+/// `answer_listener_approval` answers them or the listener stops waiting.
+async fn forward_listener_approvals(
+    mut approval_rx: tokio::sync::mpsc::Receiver<ApprovalRequest>,
+    app: AppHandle,
+) {
+    while let Some(request) = approval_rx.recv().await {
+        let requested = ApprovalRequested::from(&request);
+        app.state::<AppState>()
+            .listener_approvals
+            .lock()
+            .await
+            .insert(requested.address.clone(), request);
+        let _ = app.emit_all("listener-approval", requested);
+        emit_tray_status(&app).await;
+    }
+}
+
+/// Requests still waiting for `answer_listener_approval`, for a window
+/// opened after they were sent
+#[tauri::command]
+pub async fn get_listener_approvals(
+    state: State<'_, AppState>,
+) -> Result<Vec<ApprovalRequested>, String> {
+    let mut approvals = state.listener_approvals.lock().await;
+    approvals.retain(|_, request| !request.is_closed());
+    Ok(approvals.values().map(ApprovalRequested::from).collect())
+}
+
+/// Answer a `listener-approval` event; declining refuses the device's key
+#[tauri::command]
+pub async fn answer_listener_approval(
+    address: String,
+    approved: bool,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let request = state
+        .listener_approvals
+        .lock()
+        .await
+        .remove(&address)
+        .filter(|request| !request.is_closed())
+        .ok_or_else(|| format!("No pairing request from {} is waiting", address))?;
+    request.respond(approved);
+    emit_tray_status(&app).await;
+    Ok(())
+}
+
+/// Suspend the advertiser while the machine sleeps, telling the frontend
+async fn follow_power_events(app: AppHandle) {
+    let mut power_rx = PowerMonitor::new().watch();
+    while let Some(event) = power_rx.recv().await {
+        let state = app.state::<AppState>();
+        let suspended = {
+            let mut adv = state.advertiser.lock().await;
+            let Some(advertiser) = adv.as_mut() else {
+                continue;
+            };
+            let result = match event {
+                PowerEvent::Sleeping => advertiser.suspend(),
+                PowerEvent::Woke => advertiser.resume(),
+            };
+            if let Err(e) = result {
+                tracing::warn!("Failed to follow {:?}: {}", event, e);
+            }
+            advertiser.is_suspended()
+        };
+        let _ = app.emit_all("listener-power", ListenerPower { suspended });
+        emit_tray_status(&app).await;
+    }
+}
+
+/// Pairing requests the listener received since it last started, oldest
+/// first, with how each ended
+///
+/// The session stays readable after `stop_listener`, until the listener
+/// starts again.
+#[tauri::command]
+pub async fn get_listener_session(
+    state: State<'_, AppState>,
+) -> Result<Vec<PairingAttempt>, String> {
+    Ok(state
+        .listener_session
+        .lock()
+        .await
+        .attempts()
+        .cloned()
+        .collect())
+}
+
 /// Get listening status
 #[tauri::command]
 pub async fn get_listener_status(state: State<'_, AppState>) -> Result<bool, String> {
     Ok(*state.is_listening.lock().await)
 }
 
-/// List authorized keys
+/// List authorized keys, from every authorized_keys file
 #[tauri::command]
-pub fn list_authorized_keys() -> Result<Vec<String>, String> {
+pub fn list_authorized_keys() -> Result<Vec<AuthorizedKeyEntry>, String> {
     let key_manager = KeyManager::new().map_err(|e| e.to_string())?;
     key_manager
-        .list_authorized_keys()
+        .list_authorized_key_entries()
         .map_err(|e| e.to_string())
 }
 
-/// Remove an authorized key
+/// Remove an authorized key from every authorized_keys file
 #[tauri::command]
 pub fn remove_authorized_key(key: String) -> Result<bool, String> {
     let key_manager = KeyManager::new().map_err(|e| e.to_string())?;
@@ -314,16 +1345,15 @@ pub fn remove_authorized_key(key: String) -> Result<bool, String> {
 
 /// Generate a new SSH key pair
 #[tauri::command]
-pub fn generate_key_pair(
+pub async fn generate_key_pair(
     name: String,
     comment: Option<String>,
-    use_rsa: bool,
+    algorithm: String,
+    app: AppHandle,
 ) -> Result<(String, String), String> {
-    let algorithm = if use_rsa {
-        KeyAlgorithm::Rsa4096
-    } else {
-        KeyAlgorithm::Ed25519
-    };
+    let algorithm = algorithm
+        .parse::<KeyAlgorithm>()
+        .map_err(|e| e.to_string())?;
 
     let key_comment = comment.unwrap_or_else(|| {
         let user = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
@@ -331,11 +1361,11 @@ pub fn generate_key_pair(
         format!("{}@{}", user, hostname)
     });
 
-    let key_pair = SshKeyPair::generate(algorithm, &key_comment).map_err(|e| e.to_string())?;
+    let key_pair = generate_reporting(algorithm, &key_comment, &app).await?;
 
     let key_manager = KeyManager::new().map_err(|e| e.to_string())?;
     let (private_path, public_path) = key_manager
-        .save_key_pair(&key_pair, &name)
+        .save_new_key_pair(&key_pair, &name)
         .map_err(|e| e.to_string())?;
 
     Ok((
@@ -344,15 +1374,50 @@ pub fn generate_key_pair(
     ))
 }
 
+/// Generate a key pair off the async runtime, sending `keygen-progress`
+/// events to the frontend until it is done
+async fn generate_reporting(
+    algorithm: KeyAlgorithm,
+    comment: &str,
+    app: &AppHandle,
+) -> Result<SshKeyPair, String> {
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel::<KeygenProgress>(16);
+    let app = app.clone();
+    let forwarder = tokio::spawn(async move {
+        while let Some(progress) = progress_rx.recv().await {
+            let _ = app.emit_all("keygen-progress", progress);
+        }
+    });
+    let key_pair = SshKeyPair::generate_with_progress(algorithm, comment, progress_tx).await;
+    let _ = forwarder.await;
+    key_pair.map_err(|e| e.to_string())
+}
+
+/// Rename the SSH config alias of a paired host, e.g. to follow its device's
+/// new name
+#[tauri::command]
+pub fn rename_host(host: String, new_host: String) -> Result<(), String> {
+    if new_host.is_empty() || ssh_config::host_alias(&new_host) != new_host {
+        return Err(tr!("host.invalid-name", name = new_host));
+    }
+    let store = PairingStore::new().map_err(|e| e.to_string())?;
+    let config = SshConfig::new().map_err(|e| e.to_string())?;
+    if renames::rename_alias(&store, &config, &host, &new_host).map_err(|e| e.to_string())? {
+        Ok(())
+    } else {
+        Err(format!(
+            "Cannot rename '{}': '{}' is already taken",
+            host, new_host
+        ))
+    }
+}
+
 /// List paired hosts from SSH config
 #[tauri::command]
 pub fn list_paired_hosts() -> Result<Vec<PairedHost>, String> {
     use std::fs;
 
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| "HOME/USERPROFILE not set".to_string())?;
-    let config_path = std::path::PathBuf::from(&home).join(".ssh").join("config");
+    let config_path = connecto_core::paths::ssh_config_file().map_err(|e| e.to_string())?;
 
     if !config_path.exists() {
         return Ok(Vec::new());
@@ -389,15 +1454,20 @@ pub fn list_paired_hosts() -> Result<Vec<PairedHost>, String> {
                         hostname: hn,
                         user: u,
                         identity_file: id,
+                        keep_warm: None,
                     });
                 }
                 current_host = Some(trimmed.strip_prefix("Host ").unwrap().to_string());
             } else if trimmed.starts_with("HostName ") {
-                current_hostname = Some(trimmed.strip_prefix("HostName ").unwrap().to_string());
+                current_hostname = Some(ssh_config::parse_arg(
+                    trimmed.strip_prefix("HostName ").unwrap(),
+                ));
             } else if trimmed.starts_with("User ") {
                 current_user = Some(trimmed.strip_prefix("User ").unwrap().to_string());
             } else if trimmed.starts_with("IdentityFile ") {
-                current_identity = Some(trimmed.strip_prefix("IdentityFile ").unwrap().to_string());
+                current_identity = Some(ssh_config::parse_arg(
+                    trimmed.strip_prefix("IdentityFile ").unwrap(),
+                ));
                 // End of this host block
                 if let (Some(h), Some(hn), Some(u), Some(id)) = (
                     current_host.take(),
@@ -410,6 +1480,7 @@ pub fn list_paired_hosts() -> Result<Vec<PairedHost>, String> {
                         hostname: hn,
                         user: u,
                         identity_file: id,
+                        keep_warm: None,
                     });
                 }
                 in_connecto_block = false;
@@ -431,10 +1502,115 @@ pub fn list_paired_hosts() -> Result<Vec<PairedHost>, String> {
             hostname: hn,
             user: u,
             identity_file: id,
+            keep_warm: None,
         });
     }
 
-    Ok(hosts)
+    for entry in ssh_config::parse_entries(&content) {
+        if let Some(host) = hosts.iter_mut().find(|h| h.host == entry.host) {
+            host.keep_warm = entry.keep_warm.map(|s| s.to_string());
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Log in to a paired host with the built-in SSH client, which needs no
+/// `ssh` installed and says exactly which step failed
+#[tauri::command]
+pub async fn test_connection(host: String) -> Result<ConnectionTest, String> {
+    let entry = SshConfig::new()
+        .and_then(|config| config.entries())
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|entry| entry.host == host)
+        .ok_or_else(|| format!("Host '{}' not found in SSH config", host))?;
+    let check = SshCheck::for_entry(&entry).map_err(|e| e.to_string())?;
+    Ok(match check.run().await {
+        Ok(report) => report.into(),
+        Err(failure) => failure.into(),
+    })
+}
+
+// ============================================================================
+// Keep-warm connections
+// ============================================================================
+
+/// Set the keep-warm schedule of a paired host, e.g. `mon-fri 09:00-18:00`,
+/// or clear it with `None`
+#[tauri::command]
+pub fn set_keep_warm(host: String, schedule: Option<String>) -> Result<(), String> {
+    let schedule = schedule
+        .map(|s| s.parse::<Schedule>())
+        .transpose()
+        .map_err(|e| e.to_string())?;
+    let config = SshConfig::new().map_err(|e| e.to_string())?;
+    if !config
+        .entries()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .any(|e| e.host == host)
+    {
+        return Err(format!("Host '{}' not found in SSH config", host));
+    }
+    config
+        .set_keep_warm(&host, schedule)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Keep hosts warm on schedule in the background
+///
+/// Sends a `keep-warm-status` event after every check.
+#[tauri::command]
+pub async fn start_keep_warm(app: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
+    let config = SshConfig::new().map_err(|e| e.to_string())?;
+    let keeper = std::sync::Arc::new(KeepWarm::new(SshControlMaster::new(), config));
+    let task = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(keepwarm::CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let ticker = keeper.clone();
+            match tokio::task::spawn_blocking(move || ticker.tick(&keepwarm::local_time())).await {
+                Ok(Ok(hosts)) => {
+                    let _ = app.emit_all("keep-warm-status", hosts);
+                }
+                Ok(Err(e)) => tracing::warn!("Failed to keep hosts warm: {}", e),
+                Err(e) => tracing::warn!("Keep-warm check failed: {}", e),
+            }
+        }
+    });
+    if let Some(previous) = state.keep_warm.lock().await.replace(task) {
+        previous.abort();
+    }
+    Ok(())
+}
+
+/// Stop keeping hosts warm; open connections close once their sessions end
+#[tauri::command]
+pub async fn stop_keep_warm(state: State<'_, AppState>) -> Result<(), String> {
+    if let Some(task) = state.keep_warm.lock().await.take() {
+        task.abort();
+    }
+    let config = SshConfig::new().map_err(|e| e.to_string())?;
+    tokio::task::spawn_blocking(move || KeepWarm::new(SshControlMaster::new(), config).stop_all())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Get whether hosts are kept warm, and how each is doing
+#[tauri::command]
+pub async fn get_keep_warm_status(state: State<'_, AppState>) -> Result<KeepWarmStatus, String> {
+    let running = state.keep_warm.lock().await.is_some();
+    let config = SshConfig::new().map_err(|e| e.to_string())?;
+    let hosts = tokio::task::spawn_blocking(move || {
+        KeepWarm::new(SshControlMaster::new(), config).status(&keepwarm::local_time())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())?;
+    Ok(KeepWarmStatus { running, hosts })
 }
 
 // ============================================================================
@@ -454,172 +1630,20 @@ const IGNORED_FILES: &[&str] = &[
 
 /// Helper function to get SSH directory
 fn get_ssh_dir() -> Result<std::path::PathBuf, String> {
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .map_err(|_| "HOME/USERPROFILE not set".to_string())?;
-    Ok(std::path::PathBuf::from(&home).join(".ssh"))
-}
-
-/// Parse public key to extract algorithm, comment, and fingerprint
-fn parse_public_key_info(public_key_content: &str) -> (String, String, String) {
-    let parts: Vec<&str> = public_key_content.split_whitespace().collect();
-
-    let algorithm = parts.first().unwrap_or(&"unknown").to_string();
-    let comment = if parts.len() > 2 {
-        parts[2..].join(" ")
-    } else {
-        String::new()
-    };
-
-    // Calculate fingerprint from the key data
-    let fingerprint = if parts.len() >= 2 {
-        // Base64 decode the key data and hash it
-        if let Ok(key_bytes) = base64_decode(parts[1]) {
-            let hash = sha256_hash(&key_bytes);
-            format!("SHA256:{}", base64_encode_no_padding(&hash))
-        } else {
-            String::new()
-        }
-    } else {
-        String::new()
-    };
-
-    (algorithm, comment, fingerprint)
-}
-
-/// Simple base64 decode (for fingerprint calculation)
-fn base64_decode(input: &str) -> Result<Vec<u8>, ()> {
-    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
-
-    let input = input.trim_end_matches('=');
-    let mut output = Vec::with_capacity(input.len() * 3 / 4);
-    let mut buffer = 0u32;
-    let mut bits = 0;
-
-    for &byte in input.as_bytes() {
-        let val = ALPHABET.iter().position(|&c| c == byte).ok_or(())?;
-        buffer = (buffer << 6) | (val as u32);
-        bits += 6;
-        if bits >= 8 {
-            bits -= 8;
-            output.push((buffer >> bits) as u8);
-        }
-    }
-
-    Ok(output)
-}
-
-/// Simple SHA256 hash
-fn sha256_hash(data: &[u8]) -> [u8; 32] {
-    use std::num::Wrapping;
-
-    const K: [u32; 64] = [
-        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
-        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
-        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
-        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
-        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
-        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
-        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
-        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
-        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
-        0xc67178f2,
-    ];
-
-    let mut h: [Wrapping<u32>; 8] = [
-        Wrapping(0x6a09e667),
-        Wrapping(0xbb67ae85),
-        Wrapping(0x3c6ef372),
-        Wrapping(0xa54ff53a),
-        Wrapping(0x510e527f),
-        Wrapping(0x9b05688c),
-        Wrapping(0x1f83d9ab),
-        Wrapping(0x5be0cd19),
-    ];
-
-    // Padding
-    let ml = (data.len() as u64) * 8;
-    let mut padded = data.to_vec();
-    padded.push(0x80);
-    while (padded.len() % 64) != 56 {
-        padded.push(0);
-    }
-    padded.extend_from_slice(&ml.to_be_bytes());
-
-    // Process blocks
-    for chunk in padded.chunks(64) {
-        let mut w = [0u32; 64];
-        for (i, word) in chunk.chunks(4).enumerate() {
-            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
-        }
-        for i in 16..64 {
-            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
-            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
-            w[i] = w[i - 16]
-                .wrapping_add(s0)
-                .wrapping_add(w[i - 7])
-                .wrapping_add(s1);
-        }
-
-        let (mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh) =
-            (h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7]);
-
-        for i in 0..64 {
-            let s1 = Wrapping(e.0.rotate_right(6) ^ e.0.rotate_right(11) ^ e.0.rotate_right(25));
-            let ch = (e & f) ^ ((!e) & g);
-            let temp1 = hh + s1 + ch + Wrapping(K[i]) + Wrapping(w[i]);
-            let s0 = Wrapping(a.0.rotate_right(2) ^ a.0.rotate_right(13) ^ a.0.rotate_right(22));
-            let maj = (a & b) ^ (a & c) ^ (b & c);
-            let temp2 = s0 + maj;
-
-            hh = g;
-            g = f;
-            f = e;
-            e = d + temp1;
-            d = c;
-            c = b;
-            b = a;
-            a = temp1 + temp2;
-        }
-
-        h[0] += a;
-        h[1] += b;
-        h[2] += c;
-        h[3] += d;
-        h[4] += e;
-        h[5] += f;
-        h[6] += g;
-        h[7] += hh;
-    }
-
-    let mut result = [0u8; 32];
-    for (i, &val) in h.iter().enumerate() {
-        result[i * 4..(i + 1) * 4].copy_from_slice(&val.0.to_be_bytes());
-    }
-    result
+    connecto_core::paths::ssh_dir().map_err(|e| e.to_string())
 }
 
-/// Base64 encode without padding (for fingerprint display)
-fn base64_encode_no_padding(data: &[u8]) -> String {
-    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
-
-    let mut result = String::new();
-    for chunk in data.chunks(3) {
-        let b0 = chunk[0] as usize;
-        let b1 = chunk.get(1).copied().unwrap_or(0) as usize;
-        let b2 = chunk.get(2).copied().unwrap_or(0) as usize;
-
-        result.push(ALPHABET[b0 >> 2] as char);
-        result.push(ALPHABET[((b0 & 0x03) << 4) | (b1 >> 4)] as char);
-
-        if chunk.len() > 1 {
-            result.push(ALPHABET[((b1 & 0x0f) << 2) | (b2 >> 6)] as char);
+/// Describe a `.pub` file; one ssh-key cannot read keeps its type and comment
+fn public_key_info(public_key_content: &str) -> PublicKeyInfo {
+    keys::parse_public_key_info(public_key_content).unwrap_or_else(|_| {
+        let mut parts = public_key_content.split_whitespace();
+        PublicKeyInfo {
+            algorithm: parts.next().unwrap_or("unknown").to_string(),
+            comment: parts.skip(1).collect::<Vec<_>>().join(" "),
+            bits: None,
+            fingerprint: String::new(),
         }
-        if chunk.len() > 2 {
-            result.push(ALPHABET[b2 & 0x3f] as char);
-        }
-    }
-    result
+    })
 }
 
 /// List local SSH keys in the given directory (for testing)
@@ -654,7 +1678,7 @@ pub fn list_local_keys_in_dir(ssh_dir: &std::path::Path) -> Result<Vec<LocalKeyI
             Err(_) => continue,
         };
 
-        let (algorithm, comment, fingerprint) = parse_public_key_info(&public_key_content);
+        let info = public_key_info(&public_key_content);
 
         // Get file creation time if available
         let created = fs::metadata(&path)
@@ -671,11 +1695,12 @@ pub fn list_local_keys_in_dir(ssh_dir: &std::path::Path) -> Result<Vec<LocalKeyI
 
         keys.push(LocalKeyInfo {
             name: file_name.to_string(),
-            algorithm,
-            comment,
+            algorithm: info.algorithm,
+            bits: info.bits,
+            comment: info.comment,
             private_key_path: path.to_string_lossy().to_string(),
             public_key_path: pub_path.to_string_lossy().to_string(),
-            fingerprint,
+            fingerprint: info.fingerprint,
             created,
         });
     }
@@ -686,25 +1711,20 @@ pub fn list_local_keys_in_dir(ssh_dir: &std::path::Path) -> Result<Vec<LocalKeyI
     Ok(keys)
 }
 
-/// Delete a local SSH key pair (for testing)
-pub fn delete_local_key_in_dir(ssh_dir: &std::path::Path, name: &str) -> Result<(), String> {
-    use std::fs;
-
-    let private_path = ssh_dir.join(name);
-    let public_path = ssh_dir.join(format!("{}.pub", name));
+/// Delete a local SSH key pair, optionally shredding the private key (for testing)
+pub fn delete_local_key_in_dir(
+    ssh_dir: &std::path::Path,
+    name: &str,
+    shred: bool,
+) -> Result<(), String> {
+    let deleted = KeyManager::with_dir(ssh_dir.to_path_buf())
+        .delete_key_pair(name, shred)
+        .map_err(|e| e.to_string())?;
 
-    if !private_path.exists() && !public_path.exists() {
+    if !deleted {
         return Err(format!("Key '{}' not found", name));
     }
 
-    if private_path.exists() {
-        fs::remove_file(&private_path).map_err(|e| e.to_string())?;
-    }
-
-    if public_path.exists() {
-        fs::remove_file(&public_path).map_err(|e| e.to_string())?;
-    }
-
     Ok(())
 }
 
@@ -723,7 +1743,7 @@ pub fn get_key_details_in_dir(
     }
 
     let public_key_content = fs::read_to_string(&public_path).map_err(|e| e.to_string())?;
-    let (algorithm, comment, fingerprint) = parse_public_key_info(&public_key_content);
+    let info = public_key_info(&public_key_content);
 
     let created = fs::metadata(&private_path)
         .ok()
@@ -738,11 +1758,12 @@ pub fn get_key_details_in_dir(
 
     Ok(LocalKeyInfo {
         name: name.to_string(),
-        algorithm,
-        comment,
+        algorithm: info.algorithm,
+        bits: info.bits,
+        comment: info.comment,
         private_key_path: private_path.to_string_lossy().to_string(),
         public_key_path: public_path.to_string_lossy().to_string(),
-        fingerprint,
+        fingerprint: info.fingerprint,
         created,
     })
 }
@@ -790,9 +1811,9 @@ pub fn list_local_keys() -> Result<Vec<LocalKeyInfo>, String> {
 
 /// Delete a local SSH key pair
 #[tauri::command]
-pub fn delete_local_key(name: String) -> Result<(), String> {
+pub fn delete_local_key(name: String, shred: Option<bool>) -> Result<(), String> {
     let ssh_dir = get_ssh_dir()?;
-    delete_local_key_in_dir(&ssh_dir, &name)
+    delete_local_key_in_dir(&ssh_dir, &name, shred.unwrap_or(false))
 }
 
 /// Get detailed information about a specific key
@@ -809,6 +1830,58 @@ pub fn rename_local_key(old_name: String, new_name: String) -> Result<(), String
     rename_local_key_in_dir(&ssh_dir, &old_name, &new_name)
 }
 
+// ============================================================================
+// Audit log commands
+// ============================================================================
+
+/// An audit log entry for the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntryInfo {
+    pub seq: u64,
+    pub timestamp: u64,
+    /// Event name, e.g. `key_installed`
+    pub event: String,
+    /// What happened, e.g. the key's fingerprint and comment
+    pub details: String,
+}
+
+impl From<&AuditRecord> for AuditEntryInfo {
+    fn from(record: &AuditRecord) -> Self {
+        Self {
+            seq: record.seq,
+            timestamp: record.timestamp,
+            event: record.event.name().to_string(),
+            details: record.event.to_string(),
+        }
+    }
+}
+
+/// The audit log for the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditLogInfo {
+    pub path: String,
+    /// Newest first
+    pub entries: Vec<AuditEntryInfo>,
+    /// Why the log failed verification, if it did
+    pub tampered: Option<String>,
+}
+
+/// Show the log of key, pairing and sync events, and whether it verifies
+#[tauri::command]
+pub fn get_audit_log() -> Result<AuditLogInfo, String> {
+    let log = AuditLog::new().map_err(|e| e.to_string())?;
+    audit_log_info(&log)
+}
+
+fn audit_log_info(log: &AuditLog) -> Result<AuditLogInfo, String> {
+    let records = log.all().map_err(|e| e.to_string())?;
+    Ok(AuditLogInfo {
+        path: log.path().display().to_string(),
+        entries: records.iter().rev().map(AuditEntryInfo::from).collect(),
+        tampered: log.verify().err().map(|e| e.to_string()),
+    })
+}
+
 // ============================================================================
 // Sync commands
 // ============================================================================
@@ -821,7 +1894,11 @@ pub struct SyncResultInfo {
     pub peer_user: String,
     pub peer_address: String,
     pub ssh_command: String,
+    /// Set when the peer's clock is far from ours
+    pub clock_warning: Option<String>,
     pub error: Option<String>,
+    /// Suggestions shown as hint cards
+    pub next_steps: Vec<NextStep>,
 }
 
 /// Sync status for the frontend
@@ -832,35 +1909,115 @@ pub struct SyncStatusInfo {
     pub peer_name: Option<String>,
 }
 
-/// Start sync operation
-#[tauri::command]
-pub async fn start_sync(
-    port: u16,
-    device_name: Option<String>,
-    timeout_secs: u64,
-    use_rsa: bool,
-    state: State<'_, AppState>,
-) -> Result<SyncResultInfo, String> {
-    use tokio::sync::mpsc;
-
-    let name = device_name.unwrap_or_else(get_hostname);
+/// Progress of the running sync, sent as a `sync-event` event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SyncEventInfo {
+    Started {
+        address: String,
+    },
+    Searching,
+    PeerFound {
+        device_name: String,
+        address: String,
+    },
+    Connected {
+        device_name: String,
+    },
+    KeyReceived {
+        device_name: String,
+        key_comment: String,
+    },
+    KeyAccepted,
+    Completed {
+        peer_name: String,
+        peer_user: String,
+    },
+    Failed {
+        message: String,
+    },
+    KeysChanged {
+        device_name: String,
+        added: usize,
+        removed: usize,
+    },
+}
 
-    // Check if already syncing
-    {
-        let status = state.sync_status.lock().await;
-        if status.is_syncing {
-            return Err("Sync operation already in progress".to_string());
+impl From<SyncEvent> for SyncEventInfo {
+    fn from(event: SyncEvent) -> Self {
+        match event {
+            SyncEvent::Started { address } => Self::Started {
+                address: address.to_string(),
+            },
+            SyncEvent::Searching => Self::Searching,
+            SyncEvent::PeerFound {
+                device_name,
+                address,
+            } => Self::PeerFound {
+                device_name,
+                address: address.to_string(),
+            },
+            SyncEvent::Connected { device_name } => Self::Connected { device_name },
+            SyncEvent::KeyReceived {
+                device_name,
+                key_comment,
+            } => Self::KeyReceived {
+                device_name,
+                key_comment,
+            },
+            SyncEvent::KeyAccepted => Self::KeyAccepted,
+            SyncEvent::Completed {
+                peer_name,
+                peer_user,
+            } => Self::Completed {
+                peer_name,
+                peer_user,
+            },
+            SyncEvent::Failed { message } => Self::Failed { message },
+            SyncEvent::PeerSynced { result } => Self::Completed {
+                peer_name: result.peer_name,
+                peer_user: result.peer_user,
+            },
+            SyncEvent::KeysChanged {
+                device_name,
+                added,
+                removed,
+            } => Self::KeysChanged {
+                device_name,
+                added: added.len(),
+                removed: removed.len(),
+            },
         }
     }
+}
 
-    // Update status to syncing
-    {
-        let mut status = state.sync_status.lock().await;
-        status.is_syncing = true;
-        status.status_message = "Starting sync...".to_string();
-        status.peer_name = None;
+impl SyncEventInfo {
+    /// The status message shown while the sync is at this step
+    fn status_message(&self) -> String {
+        match self {
+            Self::Started { address } => format!("Waiting for a peer on {}...", address),
+            Self::Searching => "Searching for sync peer...".to_string(),
+            Self::PeerFound { device_name, .. } => format!("Found {}", device_name),
+            Self::Connected { device_name } => format!("Exchanging keys with {}...", device_name),
+            Self::KeyReceived { device_name, .. } => format!("Received key from {}", device_name),
+            Self::KeyAccepted => "Key accepted by peer".to_string(),
+            Self::Completed { peer_name, .. } => format!("Sync completed with {}!", peer_name),
+            Self::Failed { message } => format!("Sync failed: {}", message),
+            Self::KeysChanged { device_name, .. } => format!("Updated keys of {}", device_name),
+        }
     }
+}
 
+/// Generate and save the key for a sync as `name`, returning a handler
+/// offering it and the path of the private key
+///
+/// With `approval`, keys from devices that are not yet trusted are handed
+/// to it before they are installed.
+async fn prepare_sync(
+    name: &str,
+    use_rsa: bool,
+    approval: Option<tokio::sync::mpsc::Sender<ApprovalRequest>>,
+) -> Result<(SyncHandler, SshKeyPair, std::path::PathBuf), String> {
     // Generate key pair
     let algorithm = if use_rsa {
         KeyAlgorithm::Rsa4096
@@ -870,7 +2027,9 @@ pub async fn start_sync(
 
     let user = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
     let comment = format!("{}@{}", user, name);
-    let key_pair = SshKeyPair::generate(algorithm, &comment).map_err(|e| e.to_string())?;
+    let key_pair = SshKeyPair::generate_async(algorithm, &comment)
+        .await
+        .map_err(|e| e.to_string())?;
 
     // Save the key
     let key_manager = KeyManager::new().map_err(|e| e.to_string())?;
@@ -887,20 +2046,133 @@ pub async fn start_sync(
 
     // Create sync handler
     let sync_key_manager = KeyManager::new().map_err(|e| e.to_string())?;
-    let handler = SyncHandler::new(sync_key_manager, &name, key_pair.clone());
+    let mut handler = SyncHandler::new(sync_key_manager, name, key_pair.clone());
+    if let Ok(identity) = DeviceIdentity::load_or_create() {
+        handler = handler.with_identity(&identity);
+    }
+    if let Ok(store) = TrustStore::new() {
+        handler = handler.with_trust_store(store);
+    }
+    if let Ok(log) = DecisionLog::new() {
+        handler = handler.with_decision_log(log);
+    }
+    if let Some(approval_tx) = approval {
+        handler = handler.with_approval(approval_tx);
+    }
+    Ok((handler, key_pair, private_path))
+}
 
-    // Create event channel (events are logged but not stored in state to avoid lifetime issues)
-    let (event_tx, mut event_rx) = mpsc::channel(10);
+/// Hand each sync peer's key to the frontend for approval
+///
+/// The request waits in the app state until `answer_sync_approval` answers
+/// it; the sync handler asks about one key at a time.
+async fn forward_sync_approvals(
+    mut approval_rx: tokio::sync::mpsc::Receiver<ApprovalRequest>,
+    app: AppHandle,
+) {
+    while let Some(request) = approval_rx.recv().await {
+        let requested = ApprovalRequested::from(&request);
+        *app.state::<AppState>().sync_approval.lock().await = Some(request);
+        let _ = app.emit_all("sync-approval", requested);
+        emit_tray_status(&app).await;
+    }
+}
 
-    // Spawn a task to drain events (they're logged in the handler)
-    tokio::spawn(async move {
-        while event_rx.recv().await.is_some() {
-            // Events are handled in SyncHandler, we just drain them here
+/// Answer a `sync-approval` event; declining refuses the peer's key
+#[tauri::command]
+pub async fn answer_sync_approval(
+    approved: bool,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let request = state
+        .sync_approval
+        .lock()
+        .await
+        .take()
+        .ok_or_else(|| "No sync peer is waiting for approval".to_string())?;
+    request.respond(approved);
+    emit_tray_status(&app).await;
+    Ok(())
+}
+
+/// Start sync operation
+///
+/// Only one sync runs at a time. Progress is sent as `sync-event` events
+/// until the result is returned. With `approve`, the peer's keys wait for
+/// `answer_sync_approval` unless its device is already trusted.
+#[tauri::command]
+pub async fn start_sync(
+    port: u16,
+    device_name: Option<String>,
+    timeout_secs: u64,
+    use_rsa: bool,
+    approve: bool,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<SyncResultInfo, String> {
+    use tokio::sync::mpsc;
+
+    let name = device_name.unwrap_or_else(discovery::get_device_name);
+
+    // Claim the sync in one step, so two requests cannot both start one
+    {
+        let mut status = state.sync_status.lock().await;
+        if status.is_syncing {
+            return Err(tr!("sync.in-progress"));
+        }
+        status.is_syncing = true;
+        status.status_message = "Starting sync...".to_string();
+        status.peer_name = None;
+    }
+    emit_tray_status(&app).await;
+
+    let (approval_tx, approver) = if approve {
+        let (approval_tx, approval_rx) = mpsc::channel(1);
+        let approver = tokio::spawn(forward_sync_approvals(approval_rx, app.clone()));
+        (Some(approval_tx), Some(approver))
+    } else {
+        (None, None)
+    };
+    let (handler, key_pair, private_path) = match prepare_sync(&name, use_rsa, approval_tx).await {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            {
+                let mut status = state.sync_status.lock().await;
+                status.is_syncing = false;
+                status.status_message = format!("Sync failed: {}", e);
+            }
+            emit_tray_status(&app).await;
+            return Err(e);
+        }
+    };
+
+    // Forward progress to the frontend
+    let (event_tx, mut event_rx) = mpsc::channel(10);
+    let events_app = app.clone();
+    let forwarder = tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            let event = SyncEventInfo::from(event);
+            events_app
+                .state::<AppState>()
+                .sync_status
+                .lock()
+                .await
+                .status_message = event.status_message();
+            let _ = events_app.emit_all("sync-event", event);
         }
     });
 
-    // Run sync
+    // Run sync, until it ends or cancel_sync stops it
+    *state.sync_shutdown.lock().await = Some(handler.shutdown_handle());
     let result = handler.run(port, timeout_secs, event_tx).await;
+    state.sync_shutdown.lock().await.take();
+    // The channel closed with the handler; let the last events through first
+    let _ = forwarder.await;
+    if let Some(approver) = approver {
+        let _ = approver.await;
+    }
+    state.sync_approval.lock().await.take();
 
     // Update status
     {
@@ -908,8 +2180,27 @@ pub async fn start_sync(
         status.is_syncing = false;
     }
 
-    match result {
+    let info = match result {
         Ok(sync_result) => {
+            record_pairing(
+                PairingRecord::new(
+                    &sync_result.peer_name,
+                    &key_pair.public_key,
+                    &sync_result.peer_address.to_string(),
+                    PairingDirection::Sync,
+                )
+                .map(|r| {
+                    r.with_key_path(&private_path.to_string_lossy())
+                        .with_peer_identity(
+                            sync_result
+                                .peer_identity
+                                .as_ref()
+                                .map(VerifiedIdentity::fingerprint),
+                        )
+                        .with_clock_skew(sync_result.clock_skew)
+                }),
+            );
+
             let ssh_command = format!(
                 "ssh -i {} {}@{}",
                 private_path.display(),
@@ -924,14 +2215,26 @@ pub async fn start_sync(
                 status.peer_name = Some(sync_result.peer_name.clone());
             }
 
-            Ok(SyncResultInfo {
+            // The peer connects back, which needs our SSH server
+            let next_steps = next_steps::recommend(&Situation {
+                event: Event::Synced,
+                ssh_command: Some(ssh_command.clone()),
+                key_path: Some(private_path.display().to_string()),
+                key_in_agent: next_steps::key_in_agent(&key_pair),
+                sshd_running: Some(next_steps::ssh_server_running().await),
+                ..Default::default()
+            });
+
+            SyncResultInfo {
                 success: true,
+                clock_warning: clock_warning(&sync_result.peer_name, sync_result.clock_skew),
                 peer_name: sync_result.peer_name,
                 peer_user: sync_result.peer_user,
                 peer_address: sync_result.peer_address.to_string(),
                 ssh_command,
                 error: None,
-            })
+                next_steps,
+            }
         }
         Err(e) => {
             // Update status with failure
@@ -940,16 +2243,21 @@ pub async fn start_sync(
                 status.status_message = format!("Sync failed: {}", e);
             }
 
-            Ok(SyncResultInfo {
+            SyncResultInfo {
                 success: false,
                 peer_name: String::new(),
                 peer_user: String::new(),
                 peer_address: String::new(),
                 ssh_command: String::new(),
+                clock_warning: None,
                 error: Some(e.to_string()),
-            })
+                next_steps: Vec::new(),
+            }
         }
-    }
+    };
+    emit_tray_status(&app).await;
+
+    Ok(info)
 }
 
 /// Get sync status
@@ -965,19 +2273,164 @@ pub async fn get_sync_status(state: State<'_, AppState>) -> Result<SyncStatusInf
 
 /// Cancel sync operation
 #[tauri::command]
-pub async fn cancel_sync(state: State<'_, AppState>) -> Result<(), String> {
-    {
-        let mut cancel = state.sync_cancel.lock().await;
-        *cancel = true;
+pub async fn cancel_sync(app: AppHandle, state: State<'_, AppState>) -> Result<(), String> {
+    if let Some(shutdown) = state.sync_shutdown.lock().await.take() {
+        shutdown.shutdown();
     }
+    // Dropping an unanswered request refuses the key
+    state.sync_approval.lock().await.take();
     {
         let mut status = state.sync_status.lock().await;
         status.is_syncing = false;
         status.status_message = "Sync cancelled".to_string();
     }
+    emit_tray_status(&app).await;
     Ok(())
 }
 
+/// Number of pairings made at or after `since` (Unix seconds)
+fn count_recent_pairings(records: &[PairingRecord], since: u64) -> usize {
+    records.iter().filter(|r| r.paired_at >= since).count()
+}
+
+/// What the tray shows for the current state
+async fn tray_status(state: &AppState) -> TrayStatus {
+    let (device_name, port) = match &*state.server.lock().await {
+        Some(server) => (Some(server.device_name.clone()), Some(server.port)),
+        None => (None, None),
+    };
+    let suspended = state
+        .advertiser
+        .lock()
+        .await
+        .as_ref()
+        .is_some_and(|advertiser| advertiser.is_suspended());
+    let sync = state.sync_status.lock().await.clone();
+    let pending_approvals = {
+        let mut approvals = state.listener_approvals.lock().await;
+        // Requests the listener stopped waiting for drop out
+        approvals.retain(|_, request| !request.is_closed());
+        let sync_approval = state.sync_approval.lock().await;
+        approvals.len() + usize::from(sync_approval.as_ref().is_some_and(|r| !r.is_closed()))
+    };
+    let since = (clock::unix_now().max(0) as u64).saturating_sub(RECENT_PAIRINGS_SECS);
+    let recent_pairings = PairingStore::new()
+        .and_then(|store| store.all())
+        .map(|records| count_recent_pairings(&records, since))
+        .unwrap_or(0);
+
+    TrayStatus {
+        listening: *state.is_listening.lock().await,
+        suspended,
+        device_name,
+        port,
+        recent_pairings,
+        pending_approvals,
+        syncing: sync.is_syncing,
+        sync_message: sync.status_message,
+    }
+}
+
+/// Send the tray a `tray-status` event with the current state
+async fn emit_tray_status(app: &AppHandle) {
+    let status = tray_status(&app.state::<AppState>()).await;
+    // The tray icon in main.rs listens for it too
+    if let Ok(payload) = serde_json::to_string(&status) {
+        app.trigger_global("tray-status", Some(payload));
+    }
+    let _ = app.emit_all("tray-status", status);
+}
+
+/// Get what the tray shows; later changes arrive as `tray-status` events
+#[tauri::command]
+pub async fn get_tray_status(state: State<'_, AppState>) -> Result<TrayStatus, String> {
+    Ok(tray_status(&state).await)
+}
+
+/// Run a quick action from the tray
+///
+/// A sync window runs in the background; its result is sent as a
+/// `sync-result` event and its progress shows in `tray-status` events.
+#[tauri::command]
+pub async fn tray_action(
+    action: TrayAction,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<TrayStatus, String> {
+    match action {
+        TrayAction::StartListener => {
+            start_listener(DEFAULT_PORT, None, None, app, state.clone())
+                .await
+                .map_err(|e| e.message().to_string())?;
+        }
+        TrayAction::StopListener => stop_listener(app, state.clone()).await?,
+        TrayAction::OpenSyncWindow => {
+            if state.sync_status.lock().await.is_syncing {
+                return Err(tr!("sync.in-progress"));
+            }
+            let task = tokio::spawn(async move {
+                let state = app.state::<AppState>();
+                match start_sync(
+                    DEFAULT_PORT,
+                    None,
+                    SYNC_WINDOW_SECS,
+                    false,
+                    false,
+                    app.clone(),
+                    state,
+                )
+                .await
+                {
+                    Ok(result) => {
+                        let _ = app.emit_all("sync-result", result);
+                    }
+                    Err(e) => tracing::warn!("Sync window failed: {}", e),
+                }
+            });
+            if let Some(previous) = state.sync_task.lock().await.replace(task) {
+                previous.abort();
+            }
+        }
+        TrayAction::CloseSyncWindow => cancel_sync(app, state.clone()).await?,
+    }
+    Ok(tray_status(&state).await)
+}
+
+/// Get the GUI settings
+#[tauri::command]
+pub async fn get_settings(state: State<'_, AppState>) -> Result<Settings, String> {
+    Ok(state.settings.lock().await.clone())
+}
+
+/// Save the GUI settings, which apply from then on
+#[tauri::command]
+pub async fn set_settings(
+    settings: Settings,
+    state: State<'_, AppState>,
+) -> Result<Settings, String> {
+    SettingsStore::new()?.save(&settings)?;
+    *state.settings.lock().await = settings.clone();
+    Ok(settings)
+}
+
+/// Start the listener on the default port if the settings ask for it at
+/// launch; a failure is reported as a `listener-event` like any other stop
+pub async fn start_listener_on_launch(app: AppHandle) {
+    let state = app.state::<AppState>();
+    if !state.settings.lock().await.start_listener_on_launch {
+        return;
+    }
+    if let Err(e) = start_listener(DEFAULT_PORT, None, None, app.clone(), state).await {
+        tracing::warn!("Failed to start the listener on launch: {}", e.message());
+        let _ = app.emit_all(
+            "listener-event",
+            ListenerEvent::Stopped {
+                error: e.message().to_string(),
+            },
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -991,6 +2444,12 @@ mod tests {
             addresses: vec!["192.168.1.100".parse().unwrap()],
             port: 8099,
             instance_name: "test".to_string(),
+            identity: None,
+            scope: None,
+            external: None,
+            os: None,
+            version: None,
+            latency: None,
         };
 
         let info = DeviceInfo::from((0, &device));
@@ -998,6 +2457,128 @@ mod tests {
         assert_eq!(info.index, 0);
     }
 
+    #[test]
+    fn test_tray_status_counts_recent_pairings() {
+        let key = SshKeyPair::generate(KeyAlgorithm::Ed25519, "test@connecto").unwrap();
+        let pairing = |paired_at| {
+            let mut record = PairingRecord::new(
+                "desk",
+                &key.public_key,
+                "192.168.1.10",
+                PairingDirection::Incoming,
+            )
+            .unwrap();
+            record.paired_at = paired_at;
+            record
+        };
+        let records = [pairing(1_000), pairing(5_000), pairing(9_000)];
+        assert_eq!(count_recent_pairings(&records, 5_000), 2);
+        assert_eq!(count_recent_pairings(&records, 10_000), 0);
+
+        let action: TrayAction = serde_json::from_str(r#"{"action":"open_sync_window"}"#).unwrap();
+        assert_eq!(action, TrayAction::OpenSyncWindow);
+    }
+
+    #[test]
+    fn test_tray_status_labels() {
+        let mut status = TrayStatus {
+            listening: false,
+            suspended: false,
+            device_name: None,
+            port: None,
+            recent_pairings: 0,
+            pending_approvals: 0,
+            syncing: false,
+            sync_message: String::new(),
+        };
+        assert_eq!(status.listener_label(), "Not listening");
+        assert_eq!(status.pairings_label(), "No pairings in the last day");
+        assert_eq!(status.approvals_label(), "No requests waiting");
+
+        status.listening = true;
+        status.device_name = Some("desk".to_string());
+        status.port = Some(DEFAULT_PORT);
+        status.recent_pairings = 1;
+        status.pending_approvals = 2;
+        assert_eq!(status.listener_label(), "Listening as desk on port 8099");
+        assert_eq!(status.pairings_label(), "1 pairing in the last day");
+        assert_eq!(status.approvals_label(), "2 requests waiting for approval");
+        status.suspended = true;
+        assert_eq!(
+            status.listener_label(),
+            "Listening as desk on port 8099, paused while asleep"
+        );
+    }
+
+    #[test]
+    fn test_listener_error_for_taken_port() {
+        let taken = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let error = ListenerError::from(ports::check_available(port).unwrap_err());
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["kind"], "port_in_use");
+        assert_eq!(json["port"], port);
+        assert!(json["suggested_port"].as_u64().unwrap() > u64::from(port));
+
+        let error = ListenerError::from("No advertiser".to_string());
+        assert_eq!(serde_json::to_value(&error).unwrap()["kind"], "other");
+    }
+
+    #[test]
+    fn test_connection_test_from_failure() {
+        let test = ConnectionTest::from(CheckFailure::Rejected {
+            methods: vec!["publickey".to_string()],
+        });
+        let json = serde_json::to_value(&test).unwrap();
+        assert_eq!(json["result"], "failed");
+        assert_eq!(json["reason"], "rejected");
+        assert_eq!(
+            json["message"],
+            "The server refused the key (it accepts: publickey)"
+        );
+    }
+
+    #[test]
+    fn test_listener_event_from_server() {
+        let address = "192.168.1.7:50123".parse().unwrap();
+        assert!(ListenerEvent::from_server(ServerEvent::Started { address }).is_none());
+
+        let event = ListenerEvent::from_server(ServerEvent::PairingRequest {
+            device_name: "laptop".to_string(),
+            address,
+        })
+        .unwrap();
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "pairing_request");
+        assert_eq!(json["address"], "192.168.1.7:50123");
+
+        // Only clocks far enough apart are worth a warning
+        let skew = |skew_secs| {
+            ListenerEvent::from_server(ServerEvent::ClockSkew {
+                device_name: "laptop".to_string(),
+                skew_secs,
+            })
+        };
+        assert!(skew(5).is_none());
+        assert!(matches!(skew(-600), Some(ListenerEvent::ClockSkew { .. })));
+    }
+
+    #[test]
+    fn test_sync_event_info() {
+        let event = SyncEventInfo::from(SyncEvent::PeerFound {
+            device_name: "desk".to_string(),
+            address: "192.168.1.7:8099".parse().unwrap(),
+        });
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "peer_found");
+        assert_eq!(json["address"], "192.168.1.7:8099");
+        assert_eq!(event.status_message(), "Found desk");
+
+        let json = serde_json::to_value(SyncEventInfo::from(SyncEvent::KeyAccepted)).unwrap();
+        assert_eq!(json, serde_json::json!({ "event": "key_accepted" }));
+    }
+
     #[test]
     fn test_get_device_name() {
         let name = get_device_name();
@@ -1052,19 +2633,33 @@ mod tests {
         assert!(private_path.exists());
         assert!(public_path.exists());
 
-        let result = delete_local_key_in_dir(&ssh_dir, "test_key");
+        let result = delete_local_key_in_dir(&ssh_dir, "test_key", false);
         assert!(result.is_ok());
         assert!(!private_path.exists());
         assert!(!public_path.exists());
     }
 
+    #[test]
+    fn test_delete_local_key_shred() {
+        let temp_dir = TempDir::new().unwrap();
+        let ssh_dir = temp_dir.path().join(".ssh");
+        std::fs::create_dir_all(&ssh_dir).unwrap();
+
+        let private_path = ssh_dir.join("test_key");
+        std::fs::write(&private_path, "private").unwrap();
+        std::fs::write(ssh_dir.join("test_key.pub"), "public").unwrap();
+
+        assert!(delete_local_key_in_dir(&ssh_dir, "test_key", true).is_ok());
+        assert!(!private_path.exists());
+    }
+
     #[test]
     fn test_delete_local_key_nonexistent() {
         let temp_dir = TempDir::new().unwrap();
         let ssh_dir = temp_dir.path().join(".ssh");
         std::fs::create_dir_all(&ssh_dir).unwrap();
 
-        let result = delete_local_key_in_dir(&ssh_dir, "nonexistent");
+        let result = delete_local_key_in_dir(&ssh_dir, "nonexistent", false);
         assert!(result.is_err());
     }
 
@@ -1104,4 +2699,36 @@ mod tests {
         let result = rename_local_key_in_dir(&ssh_dir, "key1", "key2");
         assert!(result.is_err()); // Should fail, target exists
     }
+
+    #[test]
+    fn test_audit_log_info() {
+        use connecto_core::audit::AuditEvent;
+
+        let temp_dir = TempDir::new().unwrap();
+        let log = AuditLog::with_path(temp_dir.path().join("audit.jsonl"));
+        log.append(AuditEvent::sync_completed(
+            "Desk",
+            "10.0.0.1".parse().unwrap(),
+            1,
+        ))
+        .unwrap();
+        log.append(AuditEvent::sync_completed(
+            "Laptop",
+            "10.0.0.2".parse().unwrap(),
+            0,
+        ))
+        .unwrap();
+
+        let info = audit_log_info(&log).unwrap();
+        assert_eq!(info.entries.len(), 2);
+        assert_eq!(info.entries[0].seq, 2);
+        assert_eq!(info.entries[0].event, "sync_completed");
+        assert_eq!(info.entries[1].details, "Desk at 10.0.0.1, 1 key(s) added");
+        assert!(info.tampered.is_none());
+
+        let content = std::fs::read_to_string(log.path()).unwrap();
+        std::fs::write(log.path(), content.replacen("Desk", "Evil", 1)).unwrap();
+        let info = audit_log_info(&log).unwrap();
+        assert!(info.tampered.unwrap().contains("Entry 1 was modified"));
+    }
 }
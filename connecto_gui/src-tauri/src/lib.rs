@@ -1,9 +1,11 @@
 //! Connecto GUI Library - Tauri backend
 
 pub mod commands;
+pub mod settings;
 pub mod state;
 
 pub use commands::*;
+pub use settings::*;
 pub use state::*;
 
 #[cfg(test)]
@@ -8,15 +8,23 @@
 )]
 
 mod commands;
+mod settings;
 mod state;
+mod tray;
 
 use commands::{
-    cancel_sync, delete_local_key, generate_key_pair, get_addresses, get_device_name,
-    get_key_details, get_listener_status, get_sync_status, list_authorized_keys, list_local_keys,
-    list_paired_hosts, pair_with_address, pair_with_device, remove_authorized_key,
-    rename_local_key, scan_devices, start_listener, start_sync, stop_listener,
+    answer_listener_approval, answer_sync_approval, cancel_sync, delete_local_key, enter_pin,
+    generate_key_pair, get_addresses, get_audit_log, get_device_name, get_keep_warm_status,
+    get_key_details, get_listener_approvals, get_listener_status, get_settings, get_sync_status,
+    get_tray_status, list_authorized_keys, list_local_keys, list_paired_hosts, pair_with_address,
+    pair_with_device, pair_with_devices, remove_authorized_key, rename_host, rename_local_key,
+    scan_devices, set_keep_warm, set_settings, start_keep_warm, start_listener,
+    start_listener_on_launch, start_scan, start_sync, stop_keep_warm, stop_listener, stop_scan,
+    test_connection, tray_action, TrayStatus,
 };
+use settings::{Settings, SettingsStore};
 use state::AppState;
+use tauri::{Manager, SystemTray, WindowEvent};
 use tracing_subscriber::EnvFilter;
 
 fn main() {
@@ -25,14 +33,50 @@ fn main() {
         .with_env_filter(EnvFilter::new("info"))
         .init();
 
+    let settings = SettingsStore::new()
+        .and_then(|store| store.load())
+        .unwrap_or_else(|e| {
+            tracing::warn!("Using default settings: {}", e);
+            Settings::default()
+        });
+
     tauri::Builder::default()
-        .manage(AppState::new())
+        .manage(AppState::new().with_settings(settings))
+        .system_tray(SystemTray::new().with_menu(tray::menu()))
+        .on_system_tray_event(tray::on_event)
+        .on_window_event(|event| {
+            // Closing hides the window instead, and the listener keeps running
+            if let WindowEvent::CloseRequested { api, .. } = event.event() {
+                let state = event.window().state::<AppState>();
+                if state.settings.blocking_lock().minimize_to_tray {
+                    let _ = event.window().hide();
+                    api.prevent_close();
+                }
+            }
+        })
+        .setup(|app| {
+            let handle = app.handle();
+            app.listen_global("tray-status", move |event| {
+                let status = event
+                    .payload()
+                    .and_then(|payload| serde_json::from_str::<TrayStatus>(payload).ok());
+                if let Some(status) = status {
+                    tray::update(&handle, &status);
+                }
+            });
+            tauri::async_runtime::spawn(start_listener_on_launch(app.handle()));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             get_device_name,
             get_addresses,
             scan_devices,
+            start_scan,
+            stop_scan,
             pair_with_device,
             pair_with_address,
+            pair_with_devices,
+            enter_pin,
             start_listener,
             stop_listener,
             get_listener_status,
@@ -40,13 +84,28 @@ fn main() {
             remove_authorized_key,
             generate_key_pair,
             list_paired_hosts,
+            rename_host,
+            test_connection,
+            set_keep_warm,
+            start_keep_warm,
+            stop_keep_warm,
+            get_keep_warm_status,
             list_local_keys,
             delete_local_key,
             get_key_details,
             rename_local_key,
+            get_audit_log,
             start_sync,
             get_sync_status,
             cancel_sync,
+            answer_sync_approval,
+            get_listener_approvals,
+            answer_listener_approval,
+            get_listener_session,
+            get_tray_status,
+            tray_action,
+            get_settings,
+            set_settings,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,113 @@
+//! GUI settings, kept in the Connecto config directory
+
+use connecto_core::paths;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File the settings are kept in
+const SETTINGS_FILE: &str = "gui-settings.json";
+
+/// What the user chose in the settings dialog
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Start the listener when the app launches
+    pub start_listener_on_launch: bool,
+    /// Hide the window to the tray on close, keeping the listener running
+    pub minimize_to_tray: bool,
+    /// Show desktop notifications for listener events
+    pub notifications: bool,
+    /// Ask before accepting pairing requests from unknown devices
+    pub approve_pairings: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            start_listener_on_launch: false,
+            minimize_to_tray: false,
+            notifications: true,
+            approve_pairings: false,
+        }
+    }
+}
+
+/// Reads and writes [`Settings`]
+#[derive(Debug, Clone)]
+pub struct SettingsStore {
+    path: PathBuf,
+}
+
+impl SettingsStore {
+    /// Default location of the settings
+    pub fn default_path() -> Result<PathBuf, String> {
+        paths::config_file(SETTINGS_FILE).map_err(|e| e.to_string())
+    }
+
+    /// Use the settings in the Connecto config directory
+    pub fn new() -> Result<Self, String> {
+        Ok(Self::with_path(Self::default_path()?))
+    }
+
+    /// Use a specific settings file
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The saved settings, or the defaults if none were saved
+    pub fn load(&self) -> Result<Settings, String> {
+        if !self.path.exists() {
+            return Ok(Settings::default());
+        }
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("Failed to parse {}: {}", self.path.display(), e))
+    }
+
+    pub fn save(&self, settings: &Settings) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let content = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+        fs::write(&self.path, content)
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_settings_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = SettingsStore::with_path(temp_dir.path().join("gui").join(SETTINGS_FILE));
+        assert_eq!(store.load().unwrap(), Settings::default());
+
+        let settings = Settings {
+            start_listener_on_launch: true,
+            minimize_to_tray: true,
+            notifications: false,
+            approve_pairings: true,
+        };
+        store.save(&settings).unwrap();
+        assert_eq!(store.load().unwrap(), settings);
+    }
+
+    #[test]
+    fn test_missing_settings_take_defaults() {
+        // Settings saved by an older version lack newer fields
+        let settings: Settings = serde_json::from_str(r#"{"minimize_to_tray":true}"#).unwrap();
+        assert!(settings.minimize_to_tray);
+        assert!(!settings.start_listener_on_launch);
+        assert!(settings.notifications);
+    }
+}
@@ -1,7 +1,15 @@
 //! Application state management
 
-use connecto_core::discovery::{DiscoveredDevice, ServiceAdvertiser};
+use connecto_core::devices::DeviceStore;
+use connecto_core::discovery::{ServiceAdvertiser, ServiceBrowser};
+use connecto_core::protocol::{ApprovalRequest, PinPrompt};
+use connecto_core::session_log::SessionLog;
+use connecto_core::shutdown::ShutdownHandle;
+
+use crate::settings::Settings;
+use std::collections::HashMap;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
 /// Sync operation status
 #[derive(Debug, Clone, Default)]
@@ -11,30 +19,100 @@ pub struct SyncStatus {
     pub peer_name: Option<String>,
 }
 
+/// A handshake server accepting pairings in the background
+pub struct RunningServer {
+    pub shutdown: ShutdownHandle,
+    pub task: JoinHandle<()>,
+    /// Port the server listens on
+    pub port: u16,
+    /// Name the listener announces
+    pub device_name: String,
+}
+
+impl RunningServer {
+    /// Stop the server, waiting until its port is released
+    pub async fn stop(self) {
+        self.shutdown.shutdown();
+        let _ = self.task.await;
+    }
+}
+
+/// An mDNS browse reporting devices as they come and go
+pub struct RunningScan {
+    pub browser: ServiceBrowser,
+    pub task: JoinHandle<()>,
+}
+
+impl RunningScan {
+    /// Stop browsing and forwarding events
+    pub fn stop(self) {
+        if let Err(e) = self.browser.stop() {
+            tracing::warn!("Failed to stop scanning: {}", e);
+        }
+        self.task.abort();
+    }
+}
+
 /// Global application state
 pub struct AppState {
-    /// Currently discovered devices
-    pub discovered_devices: Mutex<Vec<DiscoveredDevice>>,
+    /// Currently discovered devices, by the index the frontend knows them by
+    pub discovered_devices: Mutex<DeviceStore>,
+    /// The live scan started by `start_scan`
+    pub scan: Mutex<Option<RunningScan>>,
     /// mDNS service advertiser
     pub advertiser: Mutex<Option<ServiceAdvertiser>>,
     /// Whether the listener is active
     pub is_listening: Mutex<bool>,
+    /// The listener's handshake server
+    pub server: Mutex<Option<RunningServer>>,
+    /// Task suspending the advertiser while the machine sleeps
+    pub power_watch: Mutex<Option<JoinHandle<()>>>,
     /// Sync operation status
     pub sync_status: Mutex<SyncStatus>,
-    /// Cancel flag for sync operation
-    pub sync_cancel: Mutex<bool>,
+    /// Cancels the running sync operation
+    pub sync_shutdown: Mutex<Option<ShutdownHandle>>,
+    /// Sync window opened from the tray, running in the background
+    pub sync_task: Mutex<Option<JoinHandle<()>>>,
+    /// Task keeping paired hosts warm on schedule
+    pub keep_warm: Mutex<Option<JoinHandle<()>>>,
+    /// Verification code prompts waiting for the user, by address
+    pub pin_prompts: Mutex<HashMap<String, PinPrompt>>,
+    /// Key of the sync peer waiting for the user's approval
+    pub sync_approval: Mutex<Option<ApprovalRequest>>,
+    /// Keys of listener clients waiting for the user's approval, by address
+    pub listener_approvals: Mutex<HashMap<String, ApprovalRequest>>,
+    /// Pairing requests the listener received since it last started
+    pub listener_session: Mutex<SessionLog>,
+    /// Settings as last saved
+    pub settings: Mutex<Settings>,
 }
 
 impl AppState {
     pub fn new() -> Self {
         Self {
-            discovered_devices: Mutex::new(Vec::new()),
+            discovered_devices: Mutex::new(DeviceStore::default()),
+            scan: Mutex::new(None),
             advertiser: Mutex::new(None),
             is_listening: Mutex::new(false),
+            server: Mutex::new(None),
+            power_watch: Mutex::new(None),
             sync_status: Mutex::new(SyncStatus::default()),
-            sync_cancel: Mutex::new(false),
+            sync_shutdown: Mutex::new(None),
+            sync_task: Mutex::new(None),
+            keep_warm: Mutex::new(None),
+            pin_prompts: Mutex::new(HashMap::new()),
+            sync_approval: Mutex::new(None),
+            listener_approvals: Mutex::new(HashMap::new()),
+            listener_session: Mutex::new(SessionLog::new()),
+            settings: Mutex::new(Settings::default()),
         }
     }
+
+    /// Start from the saved settings instead of the defaults
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = Mutex::new(settings);
+        self
+    }
 }
 
 impl Default for AppState {
@@ -51,8 +129,13 @@ mod tests {
     async fn test_app_state_creation() {
         let state = AppState::new();
         assert!(state.discovered_devices.lock().await.is_empty());
+        assert!(state.scan.lock().await.is_none());
         assert!(state.advertiser.lock().await.is_none());
         assert!(!*state.is_listening.lock().await);
+        assert!(state.server.lock().await.is_none());
+        assert!(state.power_watch.lock().await.is_none());
+        assert!(state.sync_task.lock().await.is_none());
+        assert!(state.keep_warm.lock().await.is_none());
     }
 
     #[tokio::test]
@@ -60,4 +143,14 @@ mod tests {
         let state = AppState::default();
         assert!(!*state.is_listening.lock().await);
     }
+
+    #[tokio::test]
+    async fn test_app_state_with_settings() {
+        let settings = Settings {
+            start_listener_on_launch: true,
+            ..Default::default()
+        };
+        let state = AppState::new().with_settings(settings.clone());
+        assert_eq!(*state.settings.lock().await, settings);
+    }
 }
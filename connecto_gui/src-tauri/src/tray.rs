@@ -0,0 +1,135 @@
+//! The tray icon and its menu
+//!
+//! The icon turns to color while the listener runs. The menu shows what the
+//! listener is doing and how many requests wait for approval, and offers the
+//! quick actions of [`TrayAction`]. Its labels follow the `tray-status`
+//! events the commands send.
+
+use connecto_core::notifications::{Notification, Notifier};
+use tauri::{
+    AppHandle, CustomMenuItem, Icon, Manager, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem,
+};
+
+use crate::commands::{tray_action, TrayAction, TrayStatus};
+use crate::state::AppState;
+
+/// Tray icons for a running and a stopped listener
+const LISTENING_ICON: &[u8] =
+    include_bytes!("../../icons/android/res/mipmap-xhdpi/ic_launcher.png");
+const IDLE_ICON: &[u8] =
+    include_bytes!("../../icons/android/res/mipmap-mdpi/ic_launcher_monochrome.png");
+
+/// The menu for a stopped listener; [`update`] keeps it current
+pub fn menu() -> SystemTrayMenu {
+    SystemTrayMenu::new()
+        .add_item(CustomMenuItem::new("status", "Not listening").disabled())
+        .add_item(CustomMenuItem::new("pairings", "No pairings in the last day").disabled())
+        .add_item(CustomMenuItem::new("approvals", "No requests waiting").disabled())
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new("show", "Open Connecto"))
+        .add_item(CustomMenuItem::new("listener", "Start listening"))
+        .add_item(CustomMenuItem::new("scan", "Scan for devices"))
+        .add_item(CustomMenuItem::new("sync", "Open sync window"))
+        .add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new("quit", "Quit"))
+}
+
+/// Show `status` in the icon, its tooltip and the menu
+///
+/// On macOS the number of requests waiting for approval shows next to the
+/// icon; elsewhere it is in the tooltip.
+pub fn update(app: &AppHandle, status: &TrayStatus) {
+    let tray = app.tray_handle();
+    let icon = if status.listening {
+        LISTENING_ICON
+    } else {
+        IDLE_ICON
+    };
+    let _ = tray.set_icon(Icon::Raw(icon.to_vec()));
+    #[cfg(target_os = "macos")]
+    {
+        let _ = tray.set_icon_as_template(!status.listening);
+        let badge = match status.pending_approvals {
+            0 => String::new(),
+            n => n.to_string(),
+        };
+        let _ = tray.set_title(&badge);
+    }
+
+    let mut tooltip = format!("Connecto: {}", status.listener_label());
+    if status.pending_approvals > 0 {
+        tooltip.push_str(&format!(" ({})", status.approvals_label()));
+    }
+    let _ = tray.set_tooltip(&tooltip);
+
+    let _ = tray.get_item("status").set_title(status.listener_label());
+    let _ = tray.get_item("pairings").set_title(status.pairings_label());
+    let approvals = tray.get_item("approvals");
+    let _ = approvals.set_title(status.approvals_label());
+    let _ = approvals.set_enabled(status.pending_approvals > 0);
+    let _ = tray.get_item("listener").set_title(if status.listening {
+        "Stop listening"
+    } else {
+        "Start listening"
+    });
+    let _ = tray.get_item("sync").set_title(if status.syncing {
+        "Close sync window"
+    } else {
+        "Open sync window"
+    });
+}
+
+fn show_window(app: &AppHandle) {
+    if let Some(window) = app.get_window("main") {
+        let _ = window.show();
+        let _ = window.unminimize();
+        let _ = window.set_focus();
+    }
+}
+
+pub fn on_event(app: &AppHandle, event: SystemTrayEvent) {
+    match event {
+        SystemTrayEvent::LeftClick { .. } => show_window(app),
+        SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
+            "show" => show_window(app),
+            // Requests are answered in the window
+            "approvals" => {
+                show_window(app);
+                let _ = app.emit_all("tray-approvals", ());
+            }
+            "scan" => {
+                show_window(app);
+                let _ = app.emit_all("tray-scan", ());
+            }
+            "listener" | "sync" => {
+                let app = app.clone();
+                let listener = id == "listener";
+                tauri::async_runtime::spawn(async move { toggle(listener, app).await });
+            }
+            "quit" => app.exit(0),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+/// Start or stop the listener, or else the sync window
+async fn toggle(listener: bool, app: AppHandle) {
+    let state = app.state::<AppState>();
+    let action = if listener {
+        if *state.is_listening.lock().await {
+            TrayAction::StopListener
+        } else {
+            TrayAction::StartListener
+        }
+    } else if state.sync_status.lock().await.is_syncing {
+        TrayAction::CloseSyncWindow
+    } else {
+        TrayAction::OpenSyncWindow
+    };
+    if let Err(e) = tray_action(action, app.clone(), state).await {
+        tracing::warn!("Tray action failed: {}", e);
+        // The window may be hidden, so the error would go unseen
+        Notifier::new().notify(&Notification::new("Connecto", &e));
+    }
+}
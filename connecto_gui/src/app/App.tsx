@@ -1,3 +1,5 @@
+import { useState, useEffect } from 'react';
+import { listen } from '@tauri-apps/api/event';
 import { Tabs, TabsContent, TabsList, TabsTrigger } from '@/app/components/ui/tabs';
 import { ScanAndPairTab } from '@/app/components/ScanAndPairTab';
 import { ListenTab } from '@/app/components/ListenTab';
@@ -5,13 +7,30 @@ import { KeysTab } from '@/app/components/KeysTab';
 import { Toaster } from '@/app/components/ui/sonner';
 
 export default function App() {
+  const [tab, setTab] = useState('scan');
+  // Set by the tray's "Scan for devices" until the scan tab starts one
+  const [scanRequested, setScanRequested] = useState(false);
+
+  useEffect(() => {
+    const unlisten = listen('tray-scan', () => {
+      setTab('scan');
+      setScanRequested(true);
+    });
+    // Pairing requests waiting for approval are answered on the listen tab
+    const unlistenApprovals = listen('tray-approvals', () => setTab('listen'));
+    return () => {
+      unlisten.then((stop) => stop());
+      unlistenApprovals.then((stop) => stop());
+    };
+  }, []);
+
   return (
     <div className="min-h-screen bg-gradient-to-br from-slate-50 to-slate-100 overflow-x-hidden max-w-full">
       <Toaster />
 
       {/* Main Content */}
       <div className="max-w-4xl mx-auto px-6 py-6">
-        <Tabs defaultValue="scan" className="space-y-6">
+        <Tabs value={tab} onValueChange={setTab} className="space-y-6">
           <TabsList className="grid w-full grid-cols-3 max-w-md">
             <TabsTrigger value="scan">Scan</TabsTrigger>
             <TabsTrigger value="listen">Listen</TabsTrigger>
@@ -19,7 +38,10 @@ export default function App() {
           </TabsList>
 
           <TabsContent value="scan" className="space-y-6">
-            <ScanAndPairTab />
+            <ScanAndPairTab
+              scanRequested={scanRequested}
+              onScanStarted={() => setScanRequested(false)}
+            />
           </TabsContent>
 
           <TabsContent value="listen" className="space-y-6">
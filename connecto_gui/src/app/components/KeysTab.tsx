@@ -1,11 +1,13 @@
 import { useState, useEffect } from 'react';
 import { invoke } from '@tauri-apps/api/tauri';
+import { listen } from '@tauri-apps/api/event';
 import { Card, CardContent, CardDescription, CardHeader, CardTitle } from '@/app/components/ui/card';
 import { Button } from '@/app/components/ui/button';
 import { Input } from '@/app/components/ui/input';
 import { Badge } from '@/app/components/ui/badge';
 import { Checkbox } from '@/app/components/ui/checkbox';
-import { Key, Trash2, RefreshCw, Loader2, Plus, CircleHelp, Pencil, Copy } from 'lucide-react';
+import { Select, SelectContent, SelectItem, SelectTrigger, SelectValue } from '@/app/components/ui/select';
+import { Key, Trash2, RefreshCw, Loader2, Plus, CircleHelp, Pencil, Copy, ShieldCheck, ShieldAlert } from 'lucide-react';
 import { toast } from 'sonner';
 import {
   AlertDialog,
@@ -29,16 +31,19 @@ import {
 import { TooltipProvider, TooltipTrigger } from '@radix-ui/react-tooltip';
 import { Tooltip, TooltipContent } from './ui/tooltip';
 
-interface ParsedKey {
-  type: string;
-  data: string;
+interface AuthorizedKey {
+  options: string;
+  key_type: string;
+  blob: string;
   comment: string;
-  raw: string;
+  /** The authorized_keys file holding the key */
+  source: string;
 }
 
 interface LocalKeyInfo {
   name: string;
   algorithm: string;
+  bits: number | null;
   comment: string;
   private_key_path: string;
   public_key_path: string;
@@ -46,13 +51,54 @@ interface LocalKeyInfo {
   created: string | null;
 }
 
+interface AuditEntry {
+  seq: number;
+  timestamp: number;
+  /** Event name, e.g. `key_installed` */
+  event: string;
+  details: string;
+}
+
+interface AuditLogInfo {
+  path: string;
+  /** Newest first */
+  entries: AuditEntry[];
+  /** Why the log failed verification, if it did */
+  tampered: string | null;
+}
+
+const AUDIT_EVENT_LABELS: Record<string, string> = {
+  key_generated: 'Key generated',
+  key_installed: 'Key installed',
+  key_removed: 'Key removed',
+  pairing_accepted: 'Pairing accepted',
+  pairing_rejected: 'Pairing rejected',
+  sync_completed: 'Sync completed',
+};
+
+interface KeygenProgress {
+  algorithm: string;
+  elapsed_ms: number;
+  done: boolean;
+}
+
+// Values match the names accepted by `connecto keygen --type`
+const KEY_ALGORITHMS = [
+  { value: 'ed25519', label: 'Ed25519 (recommended)' },
+  { value: 'rsa', label: 'RSA-4096' },
+  { value: 'ecdsa-p256', label: 'ECDSA P-256' },
+  { value: 'ecdsa-p384', label: 'ECDSA P-384' },
+  { value: 'ed25519-sk', label: 'Ed25519 on a security key' },
+];
+
 export function KeysTab() {
-  const [keys, setKeys] = useState<ParsedKey[]>([]);
+  const [keys, setKeys] = useState<AuthorizedKey[]>([]);
   const [isLoading, setIsLoading] = useState(true);
   const [keyName, setKeyName] = useState('');
   const [keyComment, setKeyComment] = useState('');
-  const [useRsa, setUseRsa] = useState(false);
+  const [algorithm, setAlgorithm] = useState('ed25519');
   const [isGenerating, setIsGenerating] = useState(false);
+  const [keygenSeconds, setKeygenSeconds] = useState(0);
   const [generatedKey, setGeneratedKey] = useState<{ privatePath: string; publicPath: string } | null>(null);
 
   // Local keys state
@@ -61,18 +107,33 @@ export function KeysTab() {
   const [renameDialogOpen, setRenameDialogOpen] = useState(false);
   const [keyToRename, setKeyToRename] = useState<LocalKeyInfo | null>(null);
   const [newKeyName, setNewKeyName] = useState('');
+  const [shredOnDelete, setShredOnDelete] = useState(false);
+
+  // Audit log state
+  const [auditLog, setAuditLog] = useState<AuditLogInfo | null>(null);
+  const [isLoadingAudit, setIsLoadingAudit] = useState(true);
 
   useEffect(() => {
     loadKeys();
     loadLocalKeys();
+    loadAuditLog();
   }, []);
 
+  const loadAuditLog = async () => {
+    setIsLoadingAudit(true);
+    try {
+      setAuditLog(await invoke<AuditLogInfo>('get_audit_log'));
+    } catch (error) {
+      toast.error(`Failed to load the audit log: ${error}`);
+    } finally {
+      setIsLoadingAudit(false);
+    }
+  };
+
   const loadKeys = async () => {
     setIsLoading(true);
     try {
-      const rawKeys = await invoke<string[]>('list_authorized_keys');
-      const parsed = rawKeys.map(parseKey);
-      setKeys(parsed);
+      setKeys(await invoke<AuthorizedKey[]>('list_authorized_keys'));
     } catch (error) {
       toast.error(`Failed to load keys: ${error}`);
     } finally {
@@ -80,19 +141,10 @@ export function KeysTab() {
     }
   };
 
-  const parseKey = (key: string): ParsedKey => {
-    const parts = key.split(/\s+/);
-    return {
-      type: parts[0] || 'unknown',
-      data: parts[1] || '',
-      comment: parts.slice(2).join(' ') || 'No comment',
-      raw: key
-    };
-  };
-
-  const handleRemoveKey = async (key: ParsedKey) => {
+  const handleRemoveKey = async (key: AuthorizedKey) => {
     try {
-      await invoke('remove_authorized_key', { key: key.raw });
+      // Every entry of the key goes, whatever its options and comment
+      await invoke('remove_authorized_key', { key: `${key.key_type} ${key.blob}` });
       toast.success('Key removed');
       loadKeys();
     } catch (error) {
@@ -109,22 +161,29 @@ export function KeysTab() {
     }
 
     setIsGenerating(true);
+    setKeygenSeconds(0);
+
+    // RSA keys take a few seconds; show how long it has been
+    const unlisten = await listen<KeygenProgress>('keygen-progress', (event) => {
+      setKeygenSeconds(Math.floor(event.payload.elapsed_ms / 1000));
+    });
 
     try {
       const [privatePath, publicPath] = await invoke<[string, string]>('generate_key_pair', {
         name,
         comment: keyComment || null,
-        useRsa
+        algorithm
       });
 
       setGeneratedKey({ privatePath, publicPath });
       toast.success('SSH key pair generated');
       setKeyName('');
       setKeyComment('');
-      setUseRsa(false);
+      setAlgorithm('ed25519');
     } catch (error) {
       toast.error(`Failed to generate key: ${error}`);
     } finally {
+      unlisten();
       setIsGenerating(false);
     }
   };
@@ -149,8 +208,8 @@ export function KeysTab() {
 
   const handleDeleteLocalKey = async (key: LocalKeyInfo) => {
     try {
-      await invoke('delete_local_key', { name: key.name });
-      toast.success(`Key "${key.name}" deleted`);
+      await invoke('delete_local_key', { name: key.name, shred: shredOnDelete });
+      toast.success(`Key "${key.name}" ${shredOnDelete ? 'shredded' : 'deleted'}`);
       loadLocalKeys();
     } catch (error) {
       toast.error(`Failed to delete key: ${error}`);
@@ -206,11 +265,13 @@ export function KeysTab() {
         </p>
       );
     }
+    // Windows administrators have keys in two files; say which holds each
+    const showSource = new Set(keys.map((key) => key.source)).size > 1;
     return (
       <div className="space-y-3">
         {keys.map((key) => (
           <div
-            key={key.data}
+            key={`${key.source}:${key.blob}`}
             className="flex items-start justify-between p-4 border rounded-lg hover:bg-gray-50 transition-colors"
           >
             <div className="flex items-start gap-4">
@@ -220,13 +281,23 @@ export function KeysTab() {
               <div className="min-w-0">
                 <div className="flex items-center gap-2 mb-1">
                   <Badge variant="secondary" className="font-mono text-xs">
-                    {key.type}
+                    {key.key_type}
                   </Badge>
+                  {key.options && (
+                    <Badge variant="outline" className="font-mono text-xs truncate max-w-xs" title={key.options}>
+                      {key.options}
+                    </Badge>
+                  )}
                 </div>
-                <p className="font-medium text-sm">{key.comment}</p>
+                <p className="font-medium text-sm">{key.comment || 'No comment'}</p>
                 <p className="text-xs text-gray-400 font-mono truncate max-w-md">
-                  {truncateKey(key.data)}
+                  {truncateKey(key.blob)}
                 </p>
+                {showSource && (
+                  <p className="text-xs text-gray-400 truncate max-w-md" title={key.source}>
+                    {key.source}
+                  </p>
+                )}
               </div>
             </div>
             <AlertDialog>
@@ -290,6 +361,7 @@ export function KeysTab() {
                   <span className="font-medium">{key.name}</span>
                   <Badge variant="secondary" className="font-mono text-xs">
                     {key.algorithm}
+                    {key.bits && ` · ${key.bits} bits`}
                   </Badge>
                 </div>
                 {key.comment && (
@@ -369,6 +441,17 @@ export function KeysTab() {
                       Any services using this key will lose access. This action cannot be undone.
                     </AlertDialogDescription>
                   </AlertDialogHeader>
+                  <div className="flex items-center space-x-2">
+                    <Checkbox
+                      id={`shred-${key.name}`}
+                      checked={shredOnDelete}
+                      onCheckedChange={(checked) => setShredOnDelete(checked as boolean)}
+                    />
+                    <label htmlFor={`shred-${key.name}`} className="text-sm text-gray-600 cursor-pointer">
+                      Overwrite the private key before deleting (best effort; copies may survive on SSDs,
+                      copy-on-write filesystems, and backups)
+                    </label>
+                  </div>
                   <AlertDialogFooter>
                     <AlertDialogCancel>Cancel</AlertDialogCancel>
                     <AlertDialogAction
@@ -387,6 +470,59 @@ export function KeysTab() {
     );
   };
 
+  const renderAuditLogContent = () => {
+    if (isLoadingAudit) {
+      return (
+        <div className="flex items-center justify-center py-8">
+          <Loader2 className="size-6 animate-spin text-gray-400" />
+        </div>
+      );
+    }
+    if (!auditLog || auditLog.entries.length === 0) {
+      return (
+        <p className="text-center text-gray-500 py-8">
+          No events recorded yet
+        </p>
+      );
+    }
+    return (
+      <div className="space-y-3">
+        {auditLog.tampered ? (
+          <div className="flex items-center gap-2 p-3 rounded-lg bg-red-50 text-red-700 text-sm">
+            <ShieldAlert className="size-4 shrink-0" />
+            <span>The log was modified: {auditLog.tampered}</span>
+          </div>
+        ) : (
+          <div className="flex items-center gap-2 text-sm text-green-700">
+            <ShieldCheck className="size-4" />
+            <span>Verified: no entry was modified or removed</span>
+          </div>
+        )}
+        <div className="max-h-80 overflow-y-auto divide-y border rounded-lg">
+          {auditLog.entries.map((entry) => (
+            <div key={entry.seq} className="flex items-start gap-3 p-3 text-sm">
+              <span className="text-xs text-gray-400 font-mono w-8 shrink-0">#{entry.seq}</span>
+              <div className="min-w-0">
+                <div className="flex items-center gap-2">
+                  <Badge variant="secondary" className="text-xs">
+                    {AUDIT_EVENT_LABELS[entry.event] ?? entry.event}
+                  </Badge>
+                  <span className="text-xs text-gray-400">
+                    {new Date(entry.timestamp * 1000).toLocaleString()}
+                  </span>
+                </div>
+                <p className="text-xs text-gray-600 font-mono mt-1 break-all">{entry.details}</p>
+              </div>
+            </div>
+          ))}
+        </div>
+        <p className="text-xs text-gray-400 truncate" title={auditLog.path}>
+          {auditLog.path}
+        </p>
+      </div>
+    );
+  };
+
   return (
     <div className="space-y-6">
       {/* Authorized keys */}
@@ -427,6 +563,25 @@ export function KeysTab() {
         </CardContent>
       </Card>
 
+      {/* Audit log */}
+      <Card>
+        <CardHeader>
+          <div className="flex items-center justify-between">
+            <div>
+              <CardTitle>Audit log</CardTitle>
+              <CardDescription>Keys generated, installed and removed, pairings, and syncs on this machine</CardDescription>
+            </div>
+            <Button variant="outline" size="sm" onClick={loadAuditLog} disabled={isLoadingAudit}>
+              <RefreshCw className={`mr-2 size-4 ${isLoadingAudit ? 'animate-spin' : ''}`} />
+              Refresh
+            </Button>
+          </div>
+        </CardHeader>
+        <CardContent>
+          {renderAuditLogContent()}
+        </CardContent>
+      </Card>
+
       {/* Rename dialog */}
       <Dialog open={renameDialogOpen} onOpenChange={setRenameDialogOpen}>
         <DialogContent>
@@ -483,13 +638,8 @@ export function KeysTab() {
           </div>
 
           <div className="flex items-center space-x-2">
-            <Checkbox
-              id="useRsa"
-              checked={useRsa}
-              onCheckedChange={(checked) => setUseRsa(checked as boolean)}
-            />
-            <label htmlFor="useRsa" className="text-sm text-gray-600 cursor-pointer">
-              Use RSA-4096 instead of Ed25519
+            <label htmlFor="keyAlgorithm" className="text-sm text-gray-600 flex items-center gap-1">
+              Key type
               <TooltipProvider>
               <Tooltip>
                 <TooltipTrigger asChild>
@@ -511,13 +661,23 @@ export function KeysTab() {
               </Tooltip>
             </TooltipProvider>
             </label>
+            <Select value={algorithm} onValueChange={setAlgorithm}>
+              <SelectTrigger id="keyAlgorithm" className="w-56">
+                <SelectValue />
+              </SelectTrigger>
+              <SelectContent>
+                {KEY_ALGORITHMS.map(({ value, label }) => (
+                  <SelectItem key={value} value={value}>{label}</SelectItem>
+                ))}
+              </SelectContent>
+            </Select>
           </div>
 
           <Button onClick={handleGenerateKey} disabled={isGenerating}>
             {isGenerating ? (
               <>
                 <Loader2 className="mr-2 size-4 animate-spin" />
-                Generating...
+                {keygenSeconds > 0 ? `Generating... ${keygenSeconds}s` : 'Generating...'}
               </>
             ) : (
               <>
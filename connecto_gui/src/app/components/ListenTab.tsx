@@ -1,9 +1,21 @@
 import { useState, useEffect } from 'react';
 import { invoke } from '@tauri-apps/api/tauri';
+import { listen } from '@tauri-apps/api/event';
 import { Card, CardContent, CardDescription, CardHeader, CardTitle } from '@/app/components/ui/card';
 import { Button } from '@/app/components/ui/button';
 import { Input } from '@/app/components/ui/input';
 import { Badge } from '@/app/components/ui/badge';
+import { Switch } from '@/app/components/ui/switch';
+import {
+  AlertDialog,
+  AlertDialogAction,
+  AlertDialogCancel,
+  AlertDialogContent,
+  AlertDialogDescription,
+  AlertDialogFooter,
+  AlertDialogHeader,
+  AlertDialogTitle,
+} from '@/app/components/ui/alert-dialog';
 import {
   Tooltip,
   TooltipContent,
@@ -18,32 +30,180 @@ interface ListenerStatus {
   port: number;
 }
 
+interface ListenerPower {
+  suspended: boolean;
+}
+
+interface Settings {
+  start_listener_on_launch: boolean;
+  minimize_to_tray: boolean;
+  notifications: boolean;
+  approve_pairings: boolean;
+}
+
+interface ApprovalRequested {
+  device_name: string;
+  address: string;
+  fingerprint: string;
+  comment: string;
+}
+
+interface PairingAttempt {
+  started_at: number;
+  device_name: string;
+  address: string;
+  fingerprint: string | null;
+  outcome:
+    | { status: 'pending' | 'accepted' }
+    | { status: 'rejected' | 'failed'; reason: string };
+}
+
+type ListenerError =
+  | {
+      kind: 'port_in_use';
+      message: string;
+      port: number;
+      owner: string | null;
+      owner_is_connecto: boolean;
+      suggested_port: number | null;
+    }
+  | { kind: 'other'; message: string };
+
+type PortConflict = Extract<ListenerError, { kind: 'port_in_use' }>;
+
+type ListenerEvent =
+  | { event: 'client_connected'; address: string }
+  | { event: 'pairing_request'; device_name: string; address: string }
+  | { event: 'verification_code'; device_name: string; code: string }
+  | { event: 'key_received'; device_name: string; comment: string; fingerprint: string }
+  | { event: 'pairing_complete'; device_name: string }
+  | { event: 'pairing_rejected'; device_name: string }
+  | { event: 'access_denied'; device_name: string; address: string; reason: string }
+  | { event: 'approval_timed_out'; device_name: string; accepted: boolean }
+  | { event: 'clock_skew'; device_name: string; warning: string }
+  | { event: 'error'; message: string }
+  | { event: 'stopped'; error: string };
+
 export function ListenTab() {
   const [isListening, setIsListening] = useState(false);
   const [isStarting, setIsStarting] = useState(false);
   const [deviceName, setDeviceName] = useState('');
   const [port, setPort] = useState('8099');
+  const [settings, setSettings] = useState<Settings | null>(null);
   const [addresses, setAddresses] = useState<string[]>([]);
   const [listenerInfo, setListenerInfo] = useState<ListenerStatus | null>(null);
+  const [isSuspended, setIsSuspended] = useState(false);
+  const [pairedDevices, setPairedDevices] = useState<string[]>([]);
+  const [portConflict, setPortConflict] = useState<PortConflict | null>(null);
+  // Pairing requests waiting for an answer, oldest first
+  const [approvals, setApprovals] = useState<ApprovalRequested[]>([]);
+  // Every pairing request since the listener started, oldest first
+  const [session, setSession] = useState<PairingAttempt[]>([]);
 
   useEffect(() => {
     loadInitialData();
     checkListenerStatus();
+    loadSession();
+
+    // The advertisement is withdrawn while the machine sleeps
+    const unlisten = listen<ListenerPower>('listener-power', (event) => {
+      setIsSuspended(event.payload.suspended);
+    });
+    const unlistenEvents = listen<ListenerEvent>('listener-event', (event) => {
+      handleListenerEvent(event.payload);
+      loadSession();
+    });
+    const unlistenApprovals = listen<ApprovalRequested>('listener-approval', (event) => {
+      const request = event.payload;
+      setApprovals(prev => [...prev.filter(a => a.address !== request.address), request]);
+    });
+    return () => {
+      unlisten.then((stop) => stop());
+      unlistenEvents.then((stop) => stop());
+      unlistenApprovals.then((stop) => stop());
+    };
   }, []);
 
   const loadInitialData = async () => {
     try {
-      const [name, addrs] = await Promise.all([
+      const [name, addrs, saved, waiting] = await Promise.all([
         invoke<string>('get_device_name'),
-        invoke<string[]>('get_addresses')
+        invoke<string[]>('get_addresses'),
+        invoke<Settings>('get_settings'),
+        invoke<ApprovalRequested[]>('get_listener_approvals')
       ]);
       setDeviceName(name);
       setAddresses(addrs);
+      setSettings(saved);
+      setApprovals(waiting);
     } catch (error) {
       console.error('Failed to load initial data:', error);
     }
   };
 
+  const updateSettings = async (changes: Partial<Settings>) => {
+    if (!settings) return;
+    try {
+      setSettings(await invoke<Settings>('set_settings', { settings: { ...settings, ...changes } }));
+    } catch (error) {
+      toast.error(`Failed to save settings: ${error}`);
+    }
+  };
+
+  const handleListenerEvent = (event: ListenerEvent) => {
+    switch (event.event) {
+      case 'pairing_request':
+        toast.info(`Pairing request from ${event.device_name} (${event.address})`);
+        break;
+      case 'verification_code':
+        toast.info(`Verification code for ${event.device_name}: ${event.code}`, { duration: 60000 });
+        break;
+      case 'key_received':
+        toast.info(`Received key ${event.comment}`, {
+          description: `Fingerprint ${event.fingerprint}; compare it with the one shown on the other device`,
+          duration: 30000,
+        });
+        break;
+      case 'pairing_complete':
+        setPairedDevices(prev => [...prev, event.device_name]);
+        toast.success(`Paired with ${event.device_name}! They can now SSH to this machine.`);
+        break;
+      case 'pairing_rejected':
+        toast.warning(`Rejected pairing request from ${event.device_name}`);
+        break;
+      case 'access_denied':
+        toast.warning(`Refused ${event.device_name} (${event.address})`, { description: event.reason });
+        break;
+      case 'approval_timed_out':
+        setApprovals(prev => prev.filter(a => a.device_name !== event.device_name));
+        toast.warning(`No answer to ${event.device_name} in time; ${event.accepted ? 'accepted its verified key' : 'rejected it'}`);
+        break;
+      case 'clock_skew':
+        toast.warning(event.warning);
+        break;
+      case 'error':
+        toast.error(event.message);
+        break;
+      case 'stopped':
+        setIsListening(false);
+        setIsSuspended(false);
+        setListenerInfo(null);
+        setApprovals([]);
+        toast.error(`Listener stopped: ${event.error}`);
+        break;
+      default:
+        break;
+    }
+  };
+
+  const loadSession = async () => {
+    try {
+      setSession(await invoke<PairingAttempt[]>('get_listener_session'));
+    } catch (error) {
+      console.error('Failed to load pairing requests:', error);
+    }
+  };
+
   const checkListenerStatus = async () => {
     try {
       const status = await invoke<boolean>('get_listener_status');
@@ -53,36 +213,64 @@ export function ListenTab() {
     }
   };
 
-  const handleStartListening = async () => {
+  const handleStartListening = async (listenPort: string = port) => {
     setIsStarting(true);
 
     try {
       const status = await invoke<ListenerStatus>('start_listener', {
-        port: Number.parseInt(port, 10),
+        port: Number.parseInt(listenPort, 10),
         deviceName: deviceName || null
       });
 
       setIsListening(true);
+      setIsSuspended(false);
+      setPairedDevices([]);
+      setSession([]);
       setListenerInfo(status);
       toast.success(`Now listening on port ${status.port}`);
     } catch (error) {
-      toast.error(`Failed to start listener: ${error}`);
+      const listenerError = error as ListenerError;
+      if (listenerError.kind === 'port_in_use') {
+        setPortConflict(listenerError);
+      } else {
+        toast.error(`Failed to start listener: ${listenerError.message ?? error}`);
+      }
     } finally {
       setIsStarting(false);
     }
   };
 
+  const handleUseSuggestedPort = () => {
+    if (portConflict?.suggested_port) {
+      const suggested = String(portConflict.suggested_port);
+      setPort(suggested);
+      handleStartListening(suggested);
+    }
+    setPortConflict(null);
+  };
+
   const handleStopListening = async () => {
     try {
       await invoke('stop_listener');
       setIsListening(false);
+      setIsSuspended(false);
       setListenerInfo(null);
+      setApprovals([]);
       toast.info('Stopped listening');
     } catch (error) {
       toast.error(`Failed to stop listener: ${error}`);
     }
   };
 
+  const handleAnswerApproval = async (request: ApprovalRequested, approved: boolean) => {
+    setApprovals(prev => prev.filter(a => a.address !== request.address));
+    try {
+      await invoke('answer_listener_approval', { address: request.address, approved });
+    } catch (error) {
+      toast.error(`Failed to answer ${request.device_name}: ${error}`);
+    }
+  };
+
   const copyToClipboard = (text: string) => {
     navigator.clipboard.writeText(text);
     toast.success('Copied to clipboard!');
@@ -101,10 +289,19 @@ export function ListenTab() {
                   <span className="absolute -top-1 -right-1 size-3 bg-green-500 rounded-full animate-pulse" />
                 </div>
                 <div>
-                  <CardTitle className="text-green-900">Listening for Connections</CardTitle>
+                  <CardTitle className="text-green-900 flex items-center gap-2">
+                    Listening for Connections
+                    {isSuspended && <Badge variant="secondary">Suspended</Badge>}
+                  </CardTitle>
                   <CardDescription className="text-green-700">
                     {listenerInfo ? `${listenerInfo.device_name} on port ${listenerInfo.port}` : `Port ${port}`}
+                    {isSuspended && ' · not advertised while this machine sleeps'}
                   </CardDescription>
+                  {pairedDevices.length > 0 && (
+                    <p className="text-sm text-green-800 mt-1">
+                      Paired: {pairedDevices.join(', ')}
+                    </p>
+                  )}
                 </div>
               </div>
               <Button variant="destructive" onClick={handleStopListening}>
@@ -116,6 +313,41 @@ export function ListenTab() {
         </Card>
       )}
 
+      {/* Pairing requests this session */}
+      {session.length > 0 && (
+        <Card>
+          <CardHeader>
+            <CardTitle>Pairing requests</CardTitle>
+            <CardDescription>Since the listener started, newest first</CardDescription>
+          </CardHeader>
+          <CardContent>
+            <ul className="space-y-2">
+              {[...session].reverse().map((attempt) => (
+                <li key={`${attempt.address}-${attempt.started_at}`} className="flex items-start justify-between gap-4 text-sm">
+                  <div className="min-w-0">
+                    <p className="font-medium">
+                      {attempt.device_name}{' '}
+                      <span className="text-gray-500 font-normal">
+                        {attempt.address} · {new Date(attempt.started_at * 1000).toLocaleTimeString()}
+                      </span>
+                    </p>
+                    {attempt.fingerprint && (
+                      <p className="font-mono text-xs text-gray-500 truncate">{attempt.fingerprint}</p>
+                    )}
+                    {'reason' in attempt.outcome && (
+                      <p className="text-xs text-gray-600">{attempt.outcome.reason}</p>
+                    )}
+                  </div>
+                  <Badge variant={attempt.outcome.status === 'accepted' ? 'default' : 'secondary'}>
+                    {attempt.outcome.status}
+                  </Badge>
+                </li>
+              ))}
+            </ul>
+          </CardContent>
+        </Card>
+      )}
+
       {/* Configuration */}
       <Card>
         <CardHeader>
@@ -169,8 +401,34 @@ export function ListenTab() {
             </div>
           </div>
 
+          <div className="flex items-center justify-between">
+            <label htmlFor="notifications" className="text-sm">
+              <span className="font-medium block">Desktop notifications</span>
+              <span className="text-muted-foreground">Notify me of pairing requests while Connecto is in the background</span>
+            </label>
+            <Switch
+              id="notifications"
+              checked={settings?.notifications ?? true}
+              onCheckedChange={(notifications) => updateSettings({ notifications })}
+              disabled={isListening || !settings}
+            />
+          </div>
+
+          <div className="flex items-center justify-between">
+            <label htmlFor="approvePairings" className="text-sm">
+              <span className="font-medium block">Ask before accepting new devices</span>
+              <span className="text-muted-foreground">Keys from devices this machine does not know wait for you to accept them</span>
+            </label>
+            <Switch
+              id="approvePairings"
+              checked={settings?.approve_pairings ?? false}
+              onCheckedChange={(approve_pairings) => updateSettings({ approve_pairings })}
+              disabled={isListening || !settings}
+            />
+          </div>
+
           {!isListening && (
-            <Button onClick={handleStartListening} disabled={isStarting} className="w-full">
+            <Button onClick={() => handleStartListening()} disabled={isStarting} className="w-full">
               {isStarting ? (
                 <>
                   <Loader2 className="mr-2 size-4 animate-spin" />
@@ -187,6 +445,86 @@ export function ListenTab() {
         </CardContent>
       </Card>
 
+      {/* Listening without the window open */}
+      <Card>
+        <CardHeader>
+          <CardTitle>In the background</CardTitle>
+          <CardDescription>Keep this machine pairable without starting the listener by hand</CardDescription>
+        </CardHeader>
+        <CardContent className="space-y-4">
+          <div className="flex items-center justify-between">
+            <label htmlFor="startOnLaunch" className="text-sm">
+              <span className="font-medium block">Start listening when Connecto opens</span>
+              <span className="text-muted-foreground">Uses the default port and device name</span>
+            </label>
+            <Switch
+              id="startOnLaunch"
+              checked={settings?.start_listener_on_launch ?? false}
+              onCheckedChange={(start_listener_on_launch) => updateSettings({ start_listener_on_launch })}
+              disabled={!settings}
+            />
+          </div>
+          <div className="flex items-center justify-between">
+            <label htmlFor="minimizeToTray" className="text-sm">
+              <span className="font-medium block">Keep running in the tray</span>
+              <span className="text-muted-foreground">Closing the window hides it, and the listener keeps running</span>
+            </label>
+            <Switch
+              id="minimizeToTray"
+              checked={settings?.minimize_to_tray ?? false}
+              onCheckedChange={(minimize_to_tray) => updateSettings({ minimize_to_tray })}
+              disabled={!settings}
+            />
+          </div>
+        </CardContent>
+      </Card>
+
+      {/* A taken port offers a free one instead */}
+      <AlertDialog open={portConflict !== null} onOpenChange={(open) => !open && setPortConflict(null)}>
+        <AlertDialogContent>
+          <AlertDialogHeader>
+            <AlertDialogTitle>Port {portConflict?.port} is already in use</AlertDialogTitle>
+            <AlertDialogDescription>
+              {portConflict?.owner ? `It is held by ${portConflict.owner}. ` : 'Another program is using it. '}
+              {portConflict?.owner_is_connecto
+                ? 'Another Connecto is still running; quit it, or listen on another port.'
+                : 'Listen on another port, or stop the program using it.'}
+            </AlertDialogDescription>
+          </AlertDialogHeader>
+          <AlertDialogFooter>
+            <AlertDialogCancel>Cancel</AlertDialogCancel>
+            {portConflict?.suggested_port && (
+              <AlertDialogAction onClick={handleUseSuggestedPort}>
+                Use port {portConflict.suggested_port}
+              </AlertDialogAction>
+            )}
+          </AlertDialogFooter>
+        </AlertDialogContent>
+      </AlertDialog>
+
+      {/* Pairing requests waiting for approval, one at a time */}
+      <AlertDialog open={approvals.length > 0}>
+        <AlertDialogContent>
+          <AlertDialogHeader>
+            <AlertDialogTitle>Accept {approvals[0]?.device_name}?</AlertDialogTitle>
+            <AlertDialogDescription>
+              {approvals[0]?.device_name} ({approvals[0]?.address}) wants to install the key{' '}
+              {approvals[0]?.comment} with fingerprint{' '}
+              <code className="font-mono text-xs">{approvals[0]?.fingerprint}</code>.
+              Accept it only if the fingerprint matches the one shown on that device.
+            </AlertDialogDescription>
+          </AlertDialogHeader>
+          <AlertDialogFooter>
+            <AlertDialogCancel onClick={() => handleAnswerApproval(approvals[0], false)}>
+              Reject
+            </AlertDialogCancel>
+            <AlertDialogAction onClick={() => handleAnswerApproval(approvals[0], true)}>
+              Accept
+            </AlertDialogAction>
+          </AlertDialogFooter>
+        </AlertDialogContent>
+      </AlertDialog>
+
       {/* Network information */}
       <Card>
         <CardHeader>
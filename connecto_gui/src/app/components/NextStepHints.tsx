@@ -0,0 +1,68 @@
+import { Button } from '@/app/components/ui/button';
+import { Copy, Terminal, KeyRound, Server, FileCog, Wrench, Search, Pencil, RefreshCw } from 'lucide-react';
+import { toast } from 'sonner';
+
+export interface NextStep {
+  action:
+    | 'connect'
+    | 'load_key'
+    | 'start_ssh_server'
+    | 'fix_ssh_config'
+    | 'repair'
+    | 'check_host'
+    | 'update_address'
+    | 'pair_again';
+  title: string;
+  command: string | null;
+}
+
+const icons = {
+  connect: Terminal,
+  load_key: KeyRound,
+  start_ssh_server: Server,
+  fix_ssh_config: FileCog,
+  repair: Wrench,
+  check_host: Search,
+  update_address: Pencil,
+  pair_again: RefreshCw,
+};
+
+const copyToClipboard = (text: string) => {
+  navigator.clipboard.writeText(text);
+  toast.success('Copied to clipboard!');
+};
+
+/** Hint cards for the suggestions returned with pairing and sync results */
+export function NextStepHints({ steps }: { steps: NextStep[] }) {
+  if (steps.length === 0) {
+    return null;
+  }
+
+  return (
+    <div className="space-y-2">
+      <p className="text-sm font-medium">Next steps</p>
+      {steps.map((step) => {
+        const Icon = icons[step.action];
+        return (
+          <div
+            key={`${step.action}-${step.title}`}
+            className="flex items-center gap-3 p-3 bg-white border rounded-lg"
+          >
+            <Icon className="size-4 shrink-0 text-muted-foreground" />
+            <div className="flex-1 min-w-0">
+              <p className="text-sm">{step.title}</p>
+              {step.command && (
+                <code className="block text-xs font-mono text-muted-foreground truncate">{step.command}</code>
+              )}
+            </div>
+            {step.command && (
+              <Button size="sm" variant="ghost" onClick={() => copyToClipboard(step.command!)}>
+                <Copy className="size-4" />
+              </Button>
+            )}
+          </div>
+        );
+      })}
+    </div>
+  );
+}
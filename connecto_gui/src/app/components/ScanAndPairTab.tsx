@@ -1,18 +1,29 @@
 import { useState, useEffect } from 'react';
 import { invoke } from '@tauri-apps/api/tauri';
+import { listen } from '@tauri-apps/api/event';
 import { Card, CardContent, CardDescription, CardHeader, CardTitle } from '@/app/components/ui/card';
 import { Button } from '@/app/components/ui/button';
 import { Input } from '@/app/components/ui/input';
 import { Badge } from '@/app/components/ui/badge';
 import { Checkbox } from '@/app/components/ui/checkbox';
+import { Progress } from '@/app/components/ui/progress';
 import {
   Accordion,
   AccordionContent,
   AccordionItem,
   AccordionTrigger,
 } from "@/app/components/ui/accordion";
-import { Wifi, Loader2, CheckCircle2, Monitor, Copy, Link2, RefreshCw, StopCircle, XCircle } from 'lucide-react';
+import {
+  Dialog,
+  DialogContent,
+  DialogDescription,
+  DialogFooter,
+  DialogHeader,
+  DialogTitle,
+} from '@/app/components/ui/dialog';
+import { Wifi, Loader2, CheckCircle2, Monitor, Copy, Link2, RefreshCw, StopCircle, XCircle, Flame, Activity } from 'lucide-react';
 import { toast } from 'sonner';
+import { NextStepHints, NextStep } from '@/app/components/NextStepHints';
 
 interface DeviceInfo {
   name: string;
@@ -22,6 +33,10 @@ interface DeviceInfo {
   index: number;
 }
 
+interface DeviceLost {
+  index: number;
+}
+
 interface PairingResult {
   success: boolean;
   server_name: string;
@@ -29,16 +44,52 @@ interface PairingResult {
   ssh_command: string;
   private_key_path: string;
   public_key_path: string;
+  host_alias?: string;
+  clock_warning?: string;
+  key_fingerprint?: string;
+  error?: string;
+  next_steps: NextStep[];
+}
+
+interface PairingProgress {
+  device_index: number;
+  status: 'queued' | 'pairing' | 'paired' | 'failed';
   error?: string;
 }
 
+interface PinRequested {
+  address: string;
+  server_name: string;
+  attempts_left: number;
+}
+
+interface ScanProgress {
+  scanned: number;
+  total: number;
+  found: number;
+}
+
 interface SyncResult {
   success: boolean;
   peer_name: string;
   peer_user: string;
   peer_address: string;
   ssh_command: string;
+  clock_warning: string | null;
   error: string | null;
+  next_steps: NextStep[];
+}
+
+interface AliasRename {
+  host: string;
+  new_host: string;
+}
+
+interface DeviceRenamed {
+  identity: string;
+  old_name: string;
+  new_name: string;
+  aliases: AliasRename[];
 }
 
 interface PairedHost {
@@ -46,16 +97,52 @@ interface PairedHost {
   hostname: string;
   user: string;
   identity_file: string;
+  keep_warm: string | null;
+}
+
+type ConnectionTest =
+  | { result: 'passed'; server_version: string; host_key: string }
+  | { result: 'failed'; reason: string; message: string };
+
+interface HostStatus {
+  host: string;
+  schedule: string;
+  in_hours: boolean;
+  warm: boolean;
+  error?: string;
+}
+
+interface KeepWarmStatus {
+  running: boolean;
+  hosts: HostStatus[];
 }
 
-export function ScanAndPairTab() {
+const WORK_HOURS = 'mon-fri 09:00-18:00';
+
+export function ScanAndPairTab({
+  scanRequested = false,
+  onScanStarted,
+}: {
+  scanRequested?: boolean;
+  onScanStarted?: () => void;
+}) {
   const [isScanning, setIsScanning] = useState(false);
+  const [isLiveScanning, setIsLiveScanning] = useState(false);
+  const [scanProgress, setScanProgress] = useState<ScanProgress | null>(null);
   const [manualIp, setManualIp] = useState('');
   const [devices, setDevices] = useState<DeviceInfo[]>([]);
   const [pairingIndex, setPairingIndex] = useState<number | null>(null);
   const [pairedIndices, setPairedIndices] = useState<Set<number>>(new Set());
   const [pairingResult, setPairingResult] = useState<PairingResult | null>(null);
+  const [selectedIndices, setSelectedIndices] = useState<Set<number>>(new Set());
+  const [batchProgress, setBatchProgress] = useState<Record<number, PairingProgress>>({});
+  const [isBatchPairing, setIsBatchPairing] = useState(false);
   const [pairedHosts, setPairedHosts] = useState<PairedHost[]>([]);
+  const [pinRequests, setPinRequests] = useState<PinRequested[]>([]);
+  const [keepWarmRunning, setKeepWarmRunning] = useState(false);
+  const [warmStatus, setWarmStatus] = useState<Record<string, HostStatus>>({});
+  const [testingHost, setTestingHost] = useState<string | null>(null);
+  const [pin, setPin] = useState('');
 
   // Sync state
   const [isSyncing, setIsSyncing] = useState(false);
@@ -66,8 +153,138 @@ export function ScanAndPairTab() {
   // Load paired hosts on mount
   useEffect(() => {
     loadPairedHosts();
+    loadKeepWarmStatus();
+
+    // Listeners started with --verify ask for the code on their screen
+    const unlisten = listen<PinRequested>('pin-requested', (event) => {
+      setPinRequests(prev => [...prev, event.payload]);
+    });
+
+    // Sent while a live scan runs; a device seen again keeps its index
+    const unlistenFound = listen<DeviceInfo>('device-found', (event) => {
+      const found = event.payload;
+      setDevices(prev =>
+        [...prev.filter(d => d.index !== found.index), found].sort((a, b) => a.index - b.index)
+      );
+    });
+    // A paired device announces a new name; its host alias follows only if asked
+    const unlistenRenamed = listen<DeviceRenamed>('device-renamed', (event) => {
+      const { old_name, new_name, aliases } = event.payload;
+      toast.info(`'${old_name}' is now called '${new_name}'`);
+      for (const alias of aliases) {
+        toast(`Rename host '${alias.host}' to '${alias.new_host}'?`, {
+          duration: Infinity,
+          action: {
+            label: 'Rename',
+            onClick: () => renameHost(alias),
+          },
+          cancel: {
+            label: 'Keep',
+            onClick: () => {},
+          },
+        });
+      }
+    });
+    // Sent after every keep-warm check
+    const unlistenWarm = listen<HostStatus[]>('keep-warm-status', (event) => {
+      setWarmStatus(Object.fromEntries(event.payload.map(s => [s.host, s])));
+    });
+    const unlistenLost = listen<DeviceLost>('device-lost', (event) => {
+      const { index } = event.payload;
+      setDevices(prev => prev.filter(d => d.index !== index));
+      setSelectedIndices(prev => {
+        const next = new Set(prev);
+        next.delete(index);
+        return next;
+      });
+    });
+
+    return () => {
+      unlisten.then((stop) => stop());
+      unlistenFound.then((stop) => stop());
+      unlistenLost.then((stop) => stop());
+      unlistenRenamed.then((stop) => stop());
+      unlistenWarm.then((stop) => stop());
+      invoke('stop_scan').catch(() => {});
+    };
   }, []);
 
+  const pinRequest = pinRequests[0];
+
+  const answerPin = async (answer: string | null) => {
+    if (!pinRequest) return;
+    setPinRequests(prev => prev.slice(1));
+    setPin('');
+    try {
+      await invoke('enter_pin', { address: pinRequest.address, pin: answer });
+    } catch (error) {
+      toast.error(`${error}`);
+    }
+  };
+
+  const renameHost = async (alias: AliasRename) => {
+    try {
+      await invoke('rename_host', { host: alias.host, newHost: alias.new_host });
+      toast.success(`Renamed host '${alias.host}' to '${alias.new_host}'`);
+      loadPairedHosts();
+    } catch (error) {
+      toast.error(`${error}`);
+    }
+  };
+
+  const loadKeepWarmStatus = async () => {
+    try {
+      const status = await invoke<KeepWarmStatus>('get_keep_warm_status');
+      setKeepWarmRunning(status.running);
+      setWarmStatus(Object.fromEntries(status.hosts.map(s => [s.host, s])));
+    } catch (error) {
+      console.error('Failed to load keep-warm status:', error);
+    }
+  };
+
+  const toggleKeepWarm = async (host: PairedHost) => {
+    try {
+      await invoke('set_keep_warm', {
+        host: host.host,
+        schedule: host.keep_warm ? null : WORK_HOURS,
+      });
+      await loadPairedHosts();
+      await loadKeepWarmStatus();
+    } catch (error) {
+      toast.error(`${error}`);
+    }
+  };
+
+  const testConnection = async (host: PairedHost) => {
+    setTestingHost(host.host);
+    try {
+      const test = await invoke<ConnectionTest>('test_connection', { host: host.host });
+      if (test.result === 'passed') {
+        toast.success(`Logged in to ${host.host}`, {
+          description: test.server_version,
+        });
+      } else {
+        toast.error(`Cannot log in to ${host.host}`, { description: test.message });
+      }
+    } catch (error) {
+      toast.error(`${error}`);
+    } finally {
+      setTestingHost(null);
+    }
+  };
+
+  const toggleKeepWarmRunning = async () => {
+    try {
+      await invoke(keepWarmRunning ? 'stop_keep_warm' : 'start_keep_warm');
+      setKeepWarmRunning(!keepWarmRunning);
+      if (keepWarmRunning) {
+        loadKeepWarmStatus();
+      }
+    } catch (error) {
+      toast.error(`${error}`);
+    }
+  };
+
   const loadPairedHosts = async () => {
     try {
       const hosts = await invoke<PairedHost[]>('list_paired_hosts');
@@ -79,11 +296,20 @@ export function ScanAndPairTab() {
 
   const handleScan = async () => {
     setIsScanning(true);
+    setScanProgress(null);
     toast.info('Scanning network for Connecto devices...');
 
+    // Only sent when mDNS finds nothing and the subnets are probed
+    const unlisten = await listen<ScanProgress>('scan-progress', (event) => {
+      setScanProgress(event.payload);
+    });
+
     try {
       const result = await invoke<DeviceInfo[]>('scan_devices', { timeoutSecs: 5 });
       setDevices(result);
+      // Indices refer to the new scan results
+      setSelectedIndices(new Set());
+      setBatchProgress({});
       if (result.length === 0) {
         toast.warning('No devices found on your network');
       } else {
@@ -92,7 +318,40 @@ export function ScanAndPairTab() {
     } catch (error) {
       toast.error(`Scan failed: ${error}`);
     } finally {
+      unlisten();
       setIsScanning(false);
+      setScanProgress(null);
+    }
+  };
+
+  // Asked for from the tray
+  useEffect(() => {
+    if (scanRequested && !isScanning) {
+      onScanStarted?.();
+      handleScan();
+    }
+  }, [scanRequested]);
+
+  const handleLiveScan = async () => {
+    if (isLiveScanning) {
+      try {
+        await invoke('stop_scan');
+      } catch (error) {
+        toast.error(`Failed to stop scanning: ${error}`);
+      }
+      setIsLiveScanning(false);
+      return;
+    }
+
+    // The live scan numbers devices afresh
+    setDevices([]);
+    setSelectedIndices(new Set());
+    setBatchProgress({});
+    try {
+      await invoke('start_scan');
+      setIsLiveScanning(true);
+    } catch (error) {
+      toast.error(`Scan failed: ${error}`);
     }
   };
 
@@ -112,7 +371,11 @@ export function ScanAndPairTab() {
         setPairingResult(result);
         loadPairedHosts();
         toast.success(`Successfully paired with ${result.server_name}!`, { id: 'pairing' });
+        if (result.clock_warning) {
+          toast.warning(result.clock_warning);
+        }
       } else {
+        setPairingResult(result.next_steps.length > 0 ? result : null);
         toast.error(`Pairing failed: ${result.error}`, { id: 'pairing' });
       }
     } catch (error) {
@@ -122,6 +385,63 @@ export function ScanAndPairTab() {
     }
   };
 
+  const toggleSelected = (index: number, selected: boolean) => {
+    setSelectedIndices(prev => {
+      const next = new Set(prev);
+      if (selected) {
+        next.add(index);
+      } else {
+        next.delete(index);
+      }
+      return next;
+    });
+  };
+
+  const handlePairSelected = async () => {
+    const indices = [...selectedIndices];
+    setIsBatchPairing(true);
+    setBatchProgress({});
+    toast.loading(`Pairing with ${indices.length} device(s)...`, { id: 'pairing' });
+
+    const unlisten = await listen<PairingProgress>('pairing-progress', (event) => {
+      setBatchProgress(prev => ({ ...prev, [event.payload.device_index]: event.payload }));
+    });
+
+    try {
+      const results = await invoke<PairingResult[]>('pair_with_devices', {
+        deviceIndices: indices,
+        useRsa: false,
+        customComment: null
+      });
+
+      const paired = indices.filter((_, i) => results[i].success);
+      setPairedIndices(prev => new Set([...prev, ...paired]));
+      setSelectedIndices(new Set());
+      const lastSuccess = results.filter(r => r.success).pop();
+      if (lastSuccess) {
+        setPairingResult(lastSuccess);
+        loadPairedHosts();
+      }
+
+      for (const result of results) {
+        if (result.clock_warning) {
+          toast.warning(result.clock_warning);
+        }
+      }
+      const failed = results.length - paired.length;
+      if (failed === 0) {
+        toast.success(`Paired with ${paired.length} device(s)`, { id: 'pairing' });
+      } else {
+        toast.warning(`Paired with ${paired.length} device(s), ${failed} failed`, { id: 'pairing' });
+      }
+    } catch (error) {
+      toast.error(`Pairing failed: ${error}`, { id: 'pairing' });
+    } finally {
+      unlisten();
+      setIsBatchPairing(false);
+    }
+  };
+
   const handleManualConnect = async () => {
     if (!manualIp) {
       toast.error('Please enter an IP address');
@@ -146,8 +466,12 @@ export function ScanAndPairTab() {
         setPairingResult(result);
         loadPairedHosts();
         toast.success(`Successfully paired!`, { id: 'manual' });
+        if (result.clock_warning) {
+          toast.warning(result.clock_warning);
+        }
         setManualIp('');
       } else {
+        setPairingResult(result.next_steps.length > 0 ? result : null);
         toast.error(`Connection failed: ${result.error}`, { id: 'manual' });
       }
     } catch (error) {
@@ -165,13 +489,17 @@ export function ScanAndPairTab() {
         port: 8099,
         deviceName: null,
         timeoutSecs: Number.parseInt(syncTimeout, 10),
-        useRsa: syncUseRsa
+        useRsa: syncUseRsa,
+        approve: false
       });
 
       setSyncResult(result);
 
       if (result.success) {
         toast.success(`Synced with ${result.peer_name}!`, { id: 'sync' });
+        if (result.clock_warning) {
+          toast.warning(result.clock_warning);
+        }
         loadPairedHosts();
       } else {
         toast.error(`Sync failed: ${result.error}`, { id: 'sync' });
@@ -212,26 +540,61 @@ export function ScanAndPairTab() {
               <CardTitle>Network discovery</CardTitle>
               <CardDescription>Find devices running Connecto on your local network</CardDescription>
             </div>
-            <Button onClick={handleScan} disabled={isScanning}>
-              {isScanning ? (
-                <>
-                  <Loader2 className="mr-2 size-4 animate-spin" />
-                  Scanning...
-                </>
-              ) : (
-                <>
-                  <Wifi className="mr-2 size-4" />
-                  Scan network
-                </>
+            <div className="flex items-center gap-2">
+              {selectedIndices.size > 0 && (
+                <Button onClick={handlePairSelected} disabled={isBatchPairing} variant="outline">
+                  {isBatchPairing && <Loader2 className="mr-2 size-4 animate-spin" />}
+                  Pair selected ({selectedIndices.size})
+                </Button>
               )}
-            </Button>
+              <Button
+                onClick={handleLiveScan}
+                disabled={isScanning || isBatchPairing}
+                variant={isLiveScanning ? 'secondary' : 'outline'}
+              >
+                {isLiveScanning ? (
+                  <>
+                    <StopCircle className="mr-2 size-4" />
+                    Stop watching
+                  </>
+                ) : (
+                  <>
+                    <RefreshCw className="mr-2 size-4" />
+                    Watch network
+                  </>
+                )}
+              </Button>
+              <Button onClick={handleScan} disabled={isScanning || isLiveScanning || isBatchPairing}>
+                {isScanning ? (
+                  <>
+                    <Loader2 className="mr-2 size-4 animate-spin" />
+                    Scanning...
+                  </>
+                ) : (
+                  <>
+                    <Wifi className="mr-2 size-4" />
+                    Scan network
+                  </>
+                )}
+              </Button>
+            </div>
           </div>
         </CardHeader>
         <CardContent>
+          {isScanning && scanProgress && (
+            <div className="mb-4 space-y-2">
+              <Progress value={scanProgress.total ? (scanProgress.scanned / scanProgress.total) * 100 : 0} />
+              <p className="text-sm text-gray-500">
+                Probing subnets: {scanProgress.scanned} of {scanProgress.total} hosts, {scanProgress.found} found
+              </p>
+            </div>
+          )}
           <div className="space-y-3">
             {devices.length === 0 ? (
               <p className="text-center text-gray-500 py-8">
-                No devices found. Click "Scan network" to search.
+                {isLiveScanning
+                  ? 'Watching for devices... they appear here as they are found.'
+                  : 'No devices found. Click "Scan network" to search.'}
               </p>
             ) : (
               devices.map((device) => (
@@ -240,6 +603,11 @@ export function ScanAndPairTab() {
                   className="flex items-center justify-between p-4 border rounded-lg hover:bg-gray-50 transition-colors"
                 >
                   <div className="flex items-center gap-4">
+                    <Checkbox
+                      checked={selectedIndices.has(device.index)}
+                      onCheckedChange={(checked) => toggleSelected(device.index, checked as boolean)}
+                      disabled={isBatchPairing || pairedIndices.has(device.index)}
+                    />
                     <div className="p-2 bg-blue-100 rounded-lg">
                       <Monitor className="size-5" />
                     </div>
@@ -252,6 +620,21 @@ export function ScanAndPairTab() {
                             Paired
                           </Badge>
                         )}
+                        {batchProgress[device.index]?.status === 'queued' && (
+                          <Badge variant="secondary">Queued</Badge>
+                        )}
+                        {batchProgress[device.index]?.status === 'pairing' && (
+                          <Badge variant="secondary">
+                            <Loader2 className="mr-1 size-3 animate-spin" />
+                            Pairing
+                          </Badge>
+                        )}
+                        {batchProgress[device.index]?.status === 'failed' && (
+                          <Badge variant="destructive" title={batchProgress[device.index].error}>
+                            <XCircle className="mr-1 size-3" />
+                            Failed
+                          </Badge>
+                        )}
                       </div>
                       <p className="text-sm text-gray-500">
                         {device.addresses[0] || 'Unknown'}:{device.port}
@@ -260,7 +643,7 @@ export function ScanAndPairTab() {
                   </div>
                   <Button
                     onClick={() => handlePair(device)}
-                    disabled={pairingIndex === device.index || pairedIndices.has(device.index)}
+                    disabled={isBatchPairing || pairingIndex === device.index || pairedIndices.has(device.index)}
                     variant={pairedIndices.has(device.index) ? 'outline' : 'default'}
                   >
                     {pairingIndex === device.index && (
@@ -284,7 +667,15 @@ export function ScanAndPairTab() {
                 <CardTitle>Paired hosts</CardTitle>
                 <CardDescription>Previously paired SSH connections</CardDescription>
               </div>
-              <Badge variant="secondary">{pairedHosts.length} host(s)</Badge>
+              <div className="flex items-center gap-2">
+                {pairedHosts.some(h => h.keep_warm) && (
+                  <Button variant="outline" size="sm" onClick={toggleKeepWarmRunning}>
+                    <Flame className="mr-2 size-3" />
+                    {keepWarmRunning ? 'Stop keeping warm' : 'Keep warm'}
+                  </Button>
+                )}
+                <Badge variant="secondary">{pairedHosts.length} host(s)</Badge>
+              </div>
             </div>
           </CardHeader>
           <CardContent>
@@ -305,20 +696,57 @@ export function ScanAndPairTab() {
                           <CheckCircle2 className="mr-1 size-3" />
                           Paired
                         </Badge>
+                        {host.keep_warm && warmStatus[host.host] && (
+                          <Badge
+                            variant="outline"
+                            className={warmStatus[host.host].warm ? 'text-orange-600 border-orange-300' : 'text-gray-500'}
+                            title={warmStatus[host.host].error}
+                          >
+                            {warmStatus[host.host].warm
+                              ? 'Warm'
+                              : warmStatus[host.host].in_hours
+                                ? 'Cold'
+                                : 'Off hours'}
+                          </Badge>
+                        )}
                       </div>
                       <p className="text-sm text-gray-500">
                         {host.user}@{host.hostname}
+                        {host.keep_warm && ` · kept warm ${host.keep_warm}`}
                       </p>
                     </div>
                   </div>
-                  <Button
-                    variant="outline"
-                    size="sm"
-                    onClick={() => copyToClipboard(`ssh ${host.host}`)}
-                  >
-                    <Copy className="mr-2 size-3" />
-                    Copy SSH
-                  </Button>
+                  <div className="flex items-center gap-2">
+                    <Button
+                      variant={host.keep_warm ? 'secondary' : 'outline'}
+                      size="sm"
+                      onClick={() => toggleKeepWarm(host)}
+                      title={host.keep_warm ? 'Stop keeping warm' : `Keep warm ${WORK_HOURS}`}
+                    >
+                      <Flame className="size-3" />
+                    </Button>
+                    <Button
+                      variant="outline"
+                      size="sm"
+                      onClick={() => testConnection(host)}
+                      disabled={testingHost === host.host}
+                      title="Test logging in"
+                    >
+                      {testingHost === host.host ? (
+                        <Loader2 className="size-3 animate-spin" />
+                      ) : (
+                        <Activity className="size-3" />
+                      )}
+                    </Button>
+                    <Button
+                      variant="outline"
+                      size="sm"
+                      onClick={() => copyToClipboard(`ssh ${host.host}`)}
+                    >
+                      <Copy className="mr-2 size-3" />
+                      Copy SSH
+                    </Button>
+                  </div>
                 </div>
               ))}
             </div>
@@ -420,6 +848,11 @@ export function ScanAndPairTab() {
                         </Button>
                       )}
                     </div>
+                    {syncResult.success && (
+                      <div className="mt-3">
+                        <NextStepHints steps={syncResult.next_steps} />
+                      </div>
+                    )}
                   </div>
                 )}
 
@@ -474,6 +907,7 @@ export function ScanAndPairTab() {
             <CardTitle className="text-green-900">Connection ready!</CardTitle>
             <CardDescription className="text-green-700">
               Successfully paired with {pairingResult.server_name}
+              {pairingResult.host_alias && ` · added to ~/.ssh/config as ${pairingResult.host_alias}`}
             </CardDescription>
           </CardHeader>
           <CardContent className="space-y-3">
@@ -503,9 +937,69 @@ export function ScanAndPairTab() {
                 </Button>
               </div>
             </div>
+            {pairingResult.key_fingerprint && (
+              <div>
+                <p className="text-sm font-medium text-green-800 mb-1">Key fingerprint</p>
+                <div className="p-3 bg-white border rounded-lg font-mono text-sm break-all">
+                  {pairingResult.key_fingerprint}
+                </div>
+                <p className="text-xs text-green-700 mt-1">
+                  Compare it with the one {pairingResult.server_name} shows
+                </p>
+              </div>
+            )}
+            <NextStepHints steps={pairingResult.next_steps} />
+          </CardContent>
+        </Card>
+      )}
+
+      {/* What to do about a failed pairing */}
+      {pairingResult && !pairingResult.success && (
+        <Card className="border-yellow-200 bg-yellow-50">
+          <CardHeader>
+            <CardTitle className="text-yellow-900">Pairing aborted</CardTitle>
+            <CardDescription className="text-yellow-700">{pairingResult.error}</CardDescription>
+          </CardHeader>
+          <CardContent>
+            <NextStepHints steps={pairingResult.next_steps} />
           </CardContent>
         </Card>
       )}
+
+      {/* Verification code */}
+      <Dialog open={!!pinRequest} onOpenChange={(open) => !open && answerPin(null)}>
+        <DialogContent>
+          <DialogHeader>
+            <DialogTitle>Enter verification code</DialogTitle>
+            <DialogDescription>
+              {pinRequest && (
+                <>
+                  Enter the code shown on {pinRequest.server_name} ({pinRequest.address}).
+                  {pinRequest.attempts_left < 3 && ` Wrong code, ${pinRequest.attempts_left} ${pinRequest.attempts_left === 1 ? 'try' : 'tries'} left.`}
+                </>
+              )}
+            </DialogDescription>
+          </DialogHeader>
+          <div className="py-4">
+            <Input
+              value={pin}
+              onChange={(e) => setPin(e.target.value)}
+              onKeyDown={(e) => e.key === 'Enter' && pin && answerPin(pin)}
+              placeholder="1234"
+              inputMode="numeric"
+              autoFocus
+            />
+          </div>
+          <DialogFooter>
+            <Button variant="outline" onClick={() => answerPin(null)}>
+              Cancel
+            </Button>
+            <Button onClick={() => answerPin(pin)} disabled={!pin}>
+              Continue
+            </Button>
+          </DialogFooter>
+        </DialogContent>
+      </Dialog>
     </div>
   );
 }
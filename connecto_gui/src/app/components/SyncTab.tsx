@@ -1,10 +1,19 @@
 import { useState, useEffect } from 'react';
 import { invoke } from '@tauri-apps/api/tauri';
+import { listen } from '@tauri-apps/api/event';
 import { Card, CardContent, CardDescription, CardHeader, CardTitle } from '@/app/components/ui/card';
 import { Button } from '@/app/components/ui/button';
 import { Input } from '@/app/components/ui/input';
 import { Badge } from '@/app/components/ui/badge';
 import { Checkbox } from '@/app/components/ui/checkbox';
+import {
+  Dialog,
+  DialogContent,
+  DialogDescription,
+  DialogFooter,
+  DialogHeader,
+  DialogTitle,
+} from '@/app/components/ui/dialog';
 import {
   Tooltip,
   TooltipContent,
@@ -13,6 +22,7 @@ import {
 } from "@/app/components/ui/tooltip";
 import { RefreshCw, Loader2, Copy, StopCircle, CircleHelp, CheckCircle2, XCircle } from 'lucide-react';
 import { toast } from 'sonner';
+import { NextStepHints, NextStep } from '@/app/components/NextStepHints';
 
 interface SyncResult {
   success: boolean;
@@ -20,7 +30,50 @@ interface SyncResult {
   peer_user: string;
   peer_address: string;
   ssh_command: string;
+  clock_warning: string | null;
   error: string | null;
+  next_steps: NextStep[];
+}
+
+type SyncEvent =
+  | { event: 'started'; address: string }
+  | { event: 'searching' }
+  | { event: 'peer_found'; device_name: string; address: string }
+  | { event: 'connected'; device_name: string }
+  | { event: 'key_received'; device_name: string; key_comment: string }
+  | { event: 'key_accepted' }
+  | { event: 'completed'; peer_name: string; peer_user: string }
+  | { event: 'failed'; message: string }
+  | { event: 'keys_changed'; device_name: string; added: number; removed: number };
+
+const describeSyncEvent = (event: SyncEvent): string => {
+  switch (event.event) {
+    case 'started':
+      return `Waiting for a peer on ${event.address}...`;
+    case 'searching':
+      return 'Searching for sync peer on the network';
+    case 'peer_found':
+      return `Found ${event.device_name} at ${event.address}`;
+    case 'connected':
+      return `Exchanging keys with ${event.device_name}...`;
+    case 'key_received':
+      return `Received key ${event.key_comment} from ${event.device_name}`;
+    case 'key_accepted':
+      return 'Our key was accepted';
+    case 'completed':
+      return `Sync completed with ${event.peer_name}`;
+    case 'failed':
+      return event.message;
+    case 'keys_changed':
+      return `Updated keys of ${event.device_name}: ${event.added} added, ${event.removed} removed`;
+  }
+};
+
+interface SyncApprovalRequested {
+  device_name: string;
+  address: string;
+  fingerprint: string;
+  comment: string;
 }
 
 interface SyncStatus {
@@ -35,12 +88,25 @@ export function SyncTab() {
   const [port, setPort] = useState('8099');
   const [timeout, setTimeout] = useState('60');
   const [useRsa, setUseRsa] = useState(false);
+  const [approve, setApprove] = useState(false);
+  const [approvalRequest, setApprovalRequest] = useState<SyncApprovalRequested | null>(null);
   const [addresses, setAddresses] = useState<string[]>([]);
   const [syncResult, setSyncResult] = useState<SyncResult | null>(null);
   const [statusMessage, setStatusMessage] = useState('');
 
   useEffect(() => {
     loadInitialData();
+
+    const unlisten = listen<SyncEvent>('sync-event', (event) => {
+      setStatusMessage(describeSyncEvent(event.payload));
+    });
+    const unlistenApproval = listen<SyncApprovalRequested>('sync-approval', (event) => {
+      setApprovalRequest(event.payload);
+    });
+    return () => {
+      unlisten.then((stop) => stop());
+      unlistenApproval.then((stop) => stop());
+    };
   }, []);
 
   const loadInitialData = async () => {
@@ -66,13 +132,17 @@ export function SyncTab() {
         port: Number.parseInt(port, 10),
         deviceName: deviceName || null,
         timeoutSecs: Number.parseInt(timeout, 10),
-        useRsa
+        useRsa,
+        approve
       });
 
       setSyncResult(result);
 
       if (result.success) {
         toast.success(`Synced with ${result.peer_name}!`);
+        if (result.clock_warning) {
+          toast.warning(result.clock_warning);
+        }
         setStatusMessage(`Sync completed with ${result.peer_name}`);
       } else {
         toast.error(`Sync failed: ${result.error}`);
@@ -83,6 +153,17 @@ export function SyncTab() {
       setStatusMessage(`Error: ${error}`);
     } finally {
       setIsSyncing(false);
+      setApprovalRequest(null);
+    }
+  };
+
+  const answerApproval = async (approved: boolean) => {
+    if (!approvalRequest) return;
+    setApprovalRequest(null);
+    try {
+      await invoke('answer_sync_approval', { approved });
+    } catch (error) {
+      toast.error(`${error}`);
     }
   };
 
@@ -163,6 +244,9 @@ export function SyncTab() {
                   <Copy className="size-4 text-green-600" />
                 </div>
               </div>
+              <div className="mt-4">
+                <NextStepHints steps={syncResult.next_steps} />
+              </div>
             </CardContent>
           )}
         </Card>
@@ -242,6 +326,21 @@ export function SyncTab() {
             </label>
           </div>
 
+          <div className="flex items-center space-x-2">
+            <Checkbox
+              id="syncApprove"
+              checked={approve}
+              onCheckedChange={(checked) => setApprove(checked as boolean)}
+              disabled={isSyncing}
+            />
+            <label
+              htmlFor="syncApprove"
+              className="text-sm font-medium leading-none peer-disabled:cursor-not-allowed peer-disabled:opacity-70"
+            >
+              Ask before accepting keys from unknown devices
+            </label>
+          </div>
+
           {!isSyncing && (
             <Button onClick={handleStartSync} className="w-full bg-purple-600 hover:bg-purple-700">
               <RefreshCw className="mr-2 size-4" />
@@ -293,6 +392,40 @@ export function SyncTab() {
           </ol>
         </CardContent>
       </Card>
+
+      {/* Key approval */}
+      <Dialog open={!!approvalRequest} onOpenChange={(open) => !open && answerApproval(false)}>
+        <DialogContent>
+          <DialogHeader>
+            <DialogTitle>Accept key from {approvalRequest?.device_name}?</DialogTitle>
+            <DialogDescription>
+              {approvalRequest && (
+                <>
+                  {approvalRequest.device_name} ({approvalRequest.address}) wants to SSH into this
+                  computer with this key. Declining its sync key ends the sync, and neither device
+                  keeps the other's key.
+                </>
+              )}
+            </DialogDescription>
+          </DialogHeader>
+          {approvalRequest && (
+            <div className="py-4 space-y-1 text-sm">
+              <p className="font-mono break-all">{approvalRequest.fingerprint}</p>
+              {approvalRequest.comment && (
+                <p className="text-muted-foreground">{approvalRequest.comment}</p>
+              )}
+            </div>
+          )}
+          <DialogFooter>
+            <Button variant="outline" onClick={() => answerApproval(false)}>
+              Decline
+            </Button>
+            <Button onClick={() => answerApproval(true)}>
+              Accept
+            </Button>
+          </DialogFooter>
+        </DialogContent>
+      </Dialog>
     </div>
   );
 }